@@ -138,6 +138,7 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
     let funcs = module_globs.iter();
     let exports = module_globs.iter();
     let imports = module_globs.iter();
+    let sections = module_globs.iter();
     let modules = module_globs.iter();
     let output = quote! {
         #( mod #mods; )*
@@ -170,7 +171,9 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
                 if let Some(ref md) = #imports::METADATA.state {
                     if let Some(name) = md.supports_export() {
                         if let Some(value) = v.data.remove(name) {
-                            md.import(chat, value).await?;
+                            if let Some(value) = md.upgrade_export(v.version, value)? {
+                                md.import(chat, value).await?;
+                            }
                         }
                     }
                 }
@@ -178,6 +181,25 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
             Ok(v)
         }
 
+        /// Import a single section of an export written with the given format version,
+        /// returning false if no module supports it
+        pub async fn import_section(chat: i64, name: &str, version: u32, value: ::serde_json::Value) -> crate::util::error::Result<bool> {
+            #(
+                if let Some(ref md) = #sections::METADATA.state {
+                    if md.supports_export() == Some(name) {
+                        return match md.upgrade_export(version, value)? {
+                            Some(value) => {
+                                md.import(chat, value).await?;
+                                Ok(true)
+                            }
+                            None => Ok(false),
+                        };
+                    }
+                }
+            )*
+            Ok(false)
+        }
+
         pub fn get_metadata() -> ::std::vec::Vec<crate::metadata::Metadata> {
             let mut metadata = Vec::new();
             #(
@@ -193,8 +215,17 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
                         priority: None,
                         description: crate::metadata::markdownify(std::include_str!(#doc_names)),
                         commands: ::std::collections::HashMap::new(),
+                        levels: ::std::collections::HashMap::new(),
+                        policies: ::std::collections::HashMap::new(),
                         sections: #vecs,
-                        state: None
+                        category: Some("Misc".to_owned()),
+                        long_help: None,
+                        updates: crate::metadata::UpdateKinds::ALL,
+                        state: None,
+                        version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+                        author: None,
+                        source: None,
+                        api_version: crate::metadata::API_VERSION
                     });
                 }
             )*
@@ -209,9 +240,17 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
             ) -> crate::util::error::Result<()> {
             match crate::tg::command::StaticContext::get_context(update).await.map(|v| v.yoke()) {
                 Ok(ctx) => {
-                    if let Err(err) = ctx.record_chat_member().await {
-                        log::warn!("failed to record chat member {}", err);
-                        err.record_stats();
+                    // commands copied from a connected private chat were never sent in the group
+                    if !crate::tg::connection::is_redirected() {
+                        if let Err(err) = ctx.record_chat_member().await {
+                            log::warn!("failed to record chat member {}", err);
+                            err.record_stats();
+                        }
+
+                        if let Err(err) = ctx.record_message_history().await {
+                            log::warn!("failed to record message history {}", err);
+                            err.record_stats();
+                        }
                     }
 
                     ctx.handle_gbans().await;
@@ -226,24 +265,49 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
                         err.record_stats();
                     }
 
+                    let command = ctx.cmd().and_then(|&crate::tg::command::Cmd{cmd, ..}| {
+                        helps.module_for_command(cmd).map(|module| (module.to_owned(), cmd))
+                    });
+                    if let Some((ref module, cmd)) = command {
+                        crate::persist::metrics::count_command(module, cmd);
+                    }
+
+                    match crate::tg::maintenance::maintenance_middleware(&ctx, &helps).await {
+                        Ok(true) => return Ok(()),
+                        Ok(false) => (),
+                        Err(err) => {
+                            log::warn!("failed to check maintenance mode {}", err);
+                            err.record_stats();
+                        }
+                    }
+
+                    match crate::tg::policy::policy_middleware(&ctx, &helps).await {
+                        Ok(true) => return Ok(()),
+                        Ok(false) => (),
+                        Err(err) => {
+                            log::warn!("failed to check command policy {}", err);
+                            err.record_stats();
+                        }
+                    }
+
                     let help = if let Some(&crate::tg::command::Cmd{cmd, ref args, message, lang, ..}) = ctx.cmd() {
                          match cmd {
-                            "help" => crate::tg::client::show_help(&ctx, message, helps, args).await,
+                            "help" => crate::tg::client::show_help(&ctx, message, helps, args, None).await,
                             "start" => match args.args.first().map(|a| a.get_text()) {
                                 Some(v) => {
-                                    let deep_args: Option<crate::tg::command::OwnedTextArgs> =
+                                    let deep_args: Option<crate::tg::client::OwnedHelpLink> =
                                         crate::tg::command::handle_deep_link(&ctx, crate::tg::client::help_key).await?;
-                                    let deep_args = deep_args.as_ref().map(|v| v.get_ref());
+                                    let deep_args = deep_args.as_ref().map(|v| (v.args.get_ref(), v.chat));
                                     if let (Some("help"), Some(s)) = (v.get(0..4), v.get(4..)) {
                                         let s = if s.len() > 0 {
                                             Some(s)
                                         } else {
                                             None
                                         };
-                                        crate::tg::client::show_help(&ctx, message, helps, args).await?;
+                                        crate::tg::client::show_help(&ctx, message, helps, args, None).await?;
                                         Ok(true)
-                                    } else if let Some(deep) = deep_args {
-                                        crate::tg::client::show_help(&ctx, message, helps, &deep).await?;
+                                    } else if let Some((deep, chat)) = deep_args {
+                                        crate::tg::client::show_help(&ctx, message, helps, &deep, Some(chat)).await?;
                                         Ok(true)
                                     } else {
                                         Ok(false)
@@ -264,9 +328,22 @@ pub fn autoimport<T: AsRef<str>>(input: T) -> TokenStream {
                     match help {
                         Ok(false) => {
                             handler.handle_update(&ctx).await;
+                            let kind = crate::metadata::UpdateKind::of(ctx.update());
                             #(
-                            if crate::statics::module_enabled(#module_names) {
-                                if let Err(err) = #updates::update_handler::handle_update(&ctx).await {
+                            if crate::statics::module_enabled(#module_names)
+                                && #updates::METADATA.updates.contains(kind)
+                                && crate::tg::client::receives_redirected(&ctx, &#updates::METADATA) {
+                                let start = ::std::time::Instant::now();
+                                let res = crate::persist::metrics::meter_module(
+                                    &#updates::METADATA.name,
+                                    #updates::update_handler::handle_update(&ctx),
+                                ).await;
+                                if let Some((ref module, cmd)) = command {
+                                    if *module == #updates::METADATA.name {
+                                        crate::persist::metrics::record_command_latency(module, cmd, start.elapsed());
+                                    }
+                                }
+                                if let Err(err) = res {
                                     err.record_stats();
                                     match err.get_message().await {
                                         Err(err) => {
@@ -204,6 +204,24 @@ pub fn get_langs(_: TokenStream) -> TokenStream {
 
     let ids: Vec<usize> = (0..STRINGS.len()).collect();
 
+    let locale = LOCALE.read().unwrap();
+    let string_arms = STRINGS.iter().map(|name| {
+        let v = format_ident!("{}", name.to_case(Case::UpperCamel));
+        let (keys, values): (Vec<&String>, Vec<&String>) = locale
+            .langs
+            .get(name.as_str())
+            .expect("invalid language")
+            .strings
+            .iter()
+            .unzip();
+        quote! {
+            Self::#v => match key {
+                #( #keys => Some(#values), )*
+                _ => None
+            }
+        }
+    });
+
     let res = quote! {
         #[doc = "Autogenerated language files, edit the files in ./strings to change these"]
         pub mod langs {
@@ -241,6 +259,19 @@ pub fn get_langs(_: TokenStream) -> TokenStream {
                     &self
                 }
 
+                /// Get the unformatted string for a key in this language, falling back
+                /// to english if this language is missing it
+                pub fn get_string(&self, key: &str) -> Option<&'static str> {
+                    let res = match self {
+                        #( #string_arms ),*,
+                        Self::Invalid => None
+                    };
+                    match res {
+                        None if *self != Self::En => Self::En.get_string(key),
+                        res => res,
+                    }
+                }
+
                 pub fn into_code(self) -> &'static str {
                     match self {
                         #( #into ),*,
@@ -378,10 +409,25 @@ fn get_entity_match(ctx: &Expr, key: LitStr, args: Punctuated<Expr, Comma>) -> i
         });
 
     let c = get_current_crate();
+    let key = key.value();
+    let idents = args.iter();
+    let parts = 0..args.len();
+    let last_part = args.len();
     quote! {
-        match #ctx.lang() {
-            #( #arms ),*,
-            #c ::langs::Lang::Invalid => ("invalid", &(* #c ::tg::markdown::EMPTY_ENTITIES))
+        {
+            use #c ::util::string::StringOverrides as _;
+            if let Some(template) = #ctx.string_override(#key) {
+                let parts = template.split("{}").collect::<Vec<&str>>();
+                builder.builder
+                    #( .text(parts.get(#parts).copied().unwrap_or("")).regular_fmt(#idents.into()) )*
+                    .text(parts.get(#last_part).copied().unwrap_or(""))
+                    .build()
+            } else {
+                match #ctx.lang() {
+                    #( #arms ),*,
+                    #c ::langs::Lang::Invalid => ("invalid", &(* #c ::tg::markdown::EMPTY_ENTITIES))
+                }
+            }
         }
     }
 }
@@ -416,11 +462,23 @@ fn get_match(language: &Expr, key: LitStr, args: Punctuated<Expr, Comma>) -> imp
         });
 
     let c = get_current_crate();
+    let key = key.value();
+    let idents = args.iter();
 
     quote! {
-        match #language.lang() {
-            #( #arms ),*,
-            #c ::langs::Lang::Invalid => "invalid".to_owned()
+        {
+            use #c ::util::string::StringOverrides as _;
+            if let Some(template) = #language.string_override(#key) {
+                #c ::util::string::format_override(
+                    template,
+                    &[ #( &(#idents) as &dyn ::std::fmt::Display ),* ]
+                )
+            } else {
+                match #language.lang() {
+                    #( #arms ),*,
+                    #c ::langs::Lang::Invalid => "invalid".to_owned()
+                }
+            }
         }
     }
 }
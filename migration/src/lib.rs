@@ -20,6 +20,39 @@ mod m20231029_015614_notes;
 mod m20231029_032907_notes_entity;
 mod m20231117_045213_taint;
 mod m20240220_230802_no_cycle;
+mod m20261015_000002_media_mirror;
+mod m20261015_000003_audit_log;
+mod m20261015_000004_log_channel;
+mod m20261015_000005_greet_flood;
+mod m20261015_000007_default_durations;
+mod m20261015_000008_ignored_bots;
+mod m20261015_000009_string_overrides;
+mod m20261015_000010_rules_gate;
+mod m20261015_000011_ban_signals;
+mod m20261015_000013_moderate_edits;
+mod m20261015_000015_response_ttl;
+mod m20261015_000016_nickname;
+mod m20261015_000018_chat_webhooks;
+mod m20261015_000019_bridge_bots;
+mod m20261015_000020_payments;
+mod m20261015_000023_user_optouts;
+mod m20261015_000024_ratelimit;
+mod m20261015_000025_connections;
+mod m20261015_000026_chat_templates;
+mod m20261015_000027_captcha_timeout;
+mod m20261015_000028_clean_greetings;
+mod m20261015_000029_rules_entity;
+mod m20261015_000030_private_notes;
+mod m20261015_000034_roles;
+mod m20261015_000036_flair;
+mod m20261015_000039_command_aliases;
+mod m20261015_000040_fed_media;
+mod m20261015_000042_member_joined;
+mod m20261015_000044_role_commands;
+mod m20261015_000046_warn_decay;
+mod m20261015_000049_gban_sources;
+mod m20261015_000057_report_optouts;
+mod m20261015_000058_pending_deletes;
 
 pub struct Migrator;
 
@@ -86,6 +119,39 @@ impl MigratorTrait for Migrator {
             Box::new(m20231029_015614_notes::Migration),
             Box::new(m20231029_032907_notes_entity::Migration),
             Box::new(m20240220_230802_no_cycle::Migration),
+            Box::new(m20261015_000002_media_mirror::Migration),
+            Box::new(m20261015_000003_audit_log::Migration),
+            Box::new(m20261015_000004_log_channel::Migration),
+            Box::new(m20261015_000005_greet_flood::Migration),
+            Box::new(m20261015_000007_default_durations::Migration),
+            Box::new(m20261015_000008_ignored_bots::Migration),
+            Box::new(m20261015_000009_string_overrides::Migration),
+            Box::new(m20261015_000010_rules_gate::Migration),
+            Box::new(m20261015_000011_ban_signals::Migration),
+            Box::new(m20261015_000013_moderate_edits::Migration),
+            Box::new(m20261015_000015_response_ttl::Migration),
+            Box::new(m20261015_000016_nickname::Migration),
+            Box::new(m20261015_000018_chat_webhooks::Migration),
+            Box::new(m20261015_000019_bridge_bots::Migration),
+            Box::new(m20261015_000020_payments::Migration),
+            Box::new(m20261015_000023_user_optouts::Migration),
+            Box::new(m20261015_000024_ratelimit::Migration),
+            Box::new(m20261015_000025_connections::Migration),
+            Box::new(m20261015_000026_chat_templates::Migration),
+            Box::new(m20261015_000027_captcha_timeout::Migration),
+            Box::new(m20261015_000028_clean_greetings::Migration),
+            Box::new(m20261015_000029_rules_entity::Migration),
+            Box::new(m20261015_000030_private_notes::Migration),
+            Box::new(m20261015_000034_roles::Migration),
+            Box::new(m20261015_000036_flair::Migration),
+            Box::new(m20261015_000039_command_aliases::Migration),
+            Box::new(m20261015_000040_fed_media::Migration),
+            Box::new(m20261015_000042_member_joined::Migration),
+            Box::new(m20261015_000044_role_commands::Migration),
+            Box::new(m20261015_000046_warn_decay::Migration),
+            Box::new(m20261015_000049_gban_sources::Migration),
+            Box::new(m20261015_000057_report_optouts::Migration),
+            Box::new(m20261015_000058_pending_deletes::Migration),
         ]);
         core_migrations
     }
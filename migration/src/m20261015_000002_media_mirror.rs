@@ -0,0 +1,71 @@
+use dijkstra::persist::{core::mirrored_media, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(mirrored_media::Entity)
+                    .col(
+                        ColumnDef::new(mirrored_media::Column::Key)
+                            .text()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(mirrored_media::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(mirrored_media::Column::Category)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(mirrored_media::Column::FileUniqueId).text())
+                    .col(
+                        ColumnDef::new(mirrored_media::Column::Size)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(mirrored_media::Column::Created)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(mirrored_media::Column::Expires).timestamp_with_time_zone())
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                IndexCreateStatement::new()
+                    .table(mirrored_media::Entity)
+                    .col(mirrored_media::Column::Chat)
+                    .col(mirrored_media::Column::Created)
+                    .name("mirrored_media_chat_index")
+                    .index_type(IndexType::BTree)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                IndexDropStatement::new()
+                    .table(mirrored_media::Entity)
+                    .name("mirrored_media_chat_index")
+                    .to_owned(),
+            )
+            .await?;
+        manager.drop_table_auto(mirrored_media::Entity).await?;
+        Ok(())
+    }
+}
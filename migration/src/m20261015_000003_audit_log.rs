@@ -0,0 +1,67 @@
+use dijkstra::persist::{core::audit_log, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(audit_log::Entity)
+                    .col(
+                        ColumnDef::new(audit_log::Column::Id)
+                            .big_integer()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    .col(
+                        ColumnDef::new(audit_log::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(audit_log::Column::Issuer)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(audit_log::Column::Command).text().not_null())
+                    .col(ColumnDef::new(audit_log::Column::Target).big_integer())
+                    .col(
+                        ColumnDef::new(audit_log::Column::Created)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                IndexCreateStatement::new()
+                    .table(audit_log::Entity)
+                    .col(audit_log::Column::Chat)
+                    .col(audit_log::Column::Created)
+                    .name("audit_log_chat_index")
+                    .index_type(IndexType::BTree)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                IndexDropStatement::new()
+                    .table(audit_log::Entity)
+                    .name("audit_log_chat_index")
+                    .to_owned(),
+            )
+            .await?;
+        manager.drop_table_auto(audit_log::Entity).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use dijkstra::persist::{core::ignored_bots, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(ignored_bots::Entity)
+                    .col(
+                        ColumnDef::new(ignored_bots::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(ignored_bots::Column::Bot).text().not_null())
+                    .col(
+                        ColumnDef::new(ignored_bots::Column::Ignored)
+                            .boolean()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(ignored_bots::Column::Chat)
+                            .col(ignored_bots::Column::Bot)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(ignored_bots::Entity).await?;
+        Ok(())
+    }
+}
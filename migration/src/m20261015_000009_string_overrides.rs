@@ -0,0 +1,45 @@
+use dijkstra::persist::{core::string_overrides, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(string_overrides::Entity)
+                    .col(
+                        ColumnDef::new(string_overrides::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(string_overrides::Column::Key)
+                            .text()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(string_overrides::Column::Text)
+                            .text()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(string_overrides::Column::Chat)
+                            .col(string_overrides::Column::Key)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(string_overrides::Entity).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use dijkstra::persist::{
+    core::{dialogs, rules_acks},
+    migrate::ManagerHelper,
+};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(dialogs::Entity)
+                    .add_column(
+                        ColumnDef::new(dialogs::Column::RulesGate)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(rules_acks::Entity)
+                    .col(
+                        ColumnDef::new(rules_acks::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(rules_acks::Column::User)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(rules_acks::Column::Acknowledged)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(rules_acks::Column::Chat)
+                            .col(rules_acks::Column::User)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(rules_acks::Entity).await?;
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(dialogs::Entity)
+                    .drop_column(dialogs::Column::RulesGate)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
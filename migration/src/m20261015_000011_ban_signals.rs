@@ -0,0 +1,88 @@
+use dijkstra::persist::{
+    admin::{ban_signals, fed_sharing},
+    migrate::ManagerHelper,
+};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(fed_sharing::Entity)
+                    .col(
+                        ColumnDef::new(fed_sharing::Column::FedId)
+                            .uuid()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(fed_sharing::Column::Publish)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .col(
+                        ColumnDef::new(fed_sharing::Column::Consume)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_table(
+                Table::create()
+                    .table(ban_signals::Entity)
+                    .col(
+                        ColumnDef::new(ban_signals::Column::Source)
+                            .text()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ban_signals::Column::User)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ban_signals::Column::Category)
+                            .integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(ban_signals::Column::Created)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(ban_signals::Column::Source)
+                            .col(ban_signals::Column::User)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        manager
+            .create_index(
+                IndexCreateStatement::new()
+                    .table(ban_signals::Entity)
+                    .col(ban_signals::Column::User)
+                    .name("ban_signals_user_index")
+                    .index_type(IndexType::BTree)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(ban_signals::Entity).await?;
+        manager.drop_table_auto(fed_sharing::Entity).await?;
+        Ok(())
+    }
+}
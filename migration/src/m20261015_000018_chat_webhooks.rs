@@ -0,0 +1,36 @@
+use dijkstra::persist::{core::chat_webhooks, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(chat_webhooks::Entity)
+                    .col(
+                        ColumnDef::new(chat_webhooks::Column::Chat)
+                            .big_integer()
+                            .not_null()
+                            .primary_key(),
+                    )
+                    .col(ColumnDef::new(chat_webhooks::Column::Url).text().not_null())
+                    .col(
+                        ColumnDef::new(chat_webhooks::Column::Secret)
+                            .text()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(chat_webhooks::Entity).await?;
+        Ok(())
+    }
+}
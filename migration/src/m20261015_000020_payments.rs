@@ -0,0 +1,69 @@
+use dijkstra::persist::{core::payments, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(payments::Entity)
+                    .col(
+                        ColumnDef::new(payments::Column::Id)
+                            .big_integer()
+                            .primary_key()
+                            .auto_increment(),
+                    )
+                    .col(
+                        ColumnDef::new(payments::Column::User)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(payments::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(payments::Column::Kind).text().not_null())
+                    .col(
+                        ColumnDef::new(payments::Column::Data)
+                            .json_binary()
+                            .not_null(),
+                    )
+                    .col(ColumnDef::new(payments::Column::Currency).text().not_null())
+                    .col(
+                        ColumnDef::new(payments::Column::Amount)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(payments::Column::ChargeId)
+                            .text()
+                            .not_null()
+                            .unique_key(),
+                    )
+                    .col(
+                        ColumnDef::new(payments::Column::Refunded)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .col(
+                        ColumnDef::new(payments::Column::Created)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(payments::Entity).await?;
+        Ok(())
+    }
+}
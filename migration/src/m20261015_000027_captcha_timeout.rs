@@ -0,0 +1,37 @@
+use dijkstra::persist::admin::captchastate;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(captchastate::Entity)
+                    .add_column(
+                        ColumnDef::new(captchastate::Column::TimeoutMute)
+                            .boolean()
+                            .not_null()
+                            .default(false),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(captchastate::Entity)
+                    .drop_column(captchastate::Column::TimeoutMute)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
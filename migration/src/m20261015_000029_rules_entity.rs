@@ -0,0 +1,32 @@
+use dijkstra::persist::core::rules;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(rules::Entity)
+                    .add_column(ColumnDef::new(rules::Column::EntityId).big_integer())
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(rules::Entity)
+                    .drop_column(rules::Column::EntityId)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use dijkstra::persist::{core::roles, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(roles::Entity)
+                    .col(ColumnDef::new(roles::Column::Chat).big_integer().not_null())
+                    .col(ColumnDef::new(roles::Column::Name).text().not_null())
+                    .col(ColumnDef::new(roles::Column::User).big_integer().not_null())
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(roles::Column::Chat)
+                            .col(roles::Column::Name)
+                            .col(roles::Column::User)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(roles::Entity).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,39 @@
+use dijkstra::persist::{core::flair, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(flair::Entity)
+                    .col(ColumnDef::new(flair::Column::Chat).big_integer().not_null())
+                    .col(ColumnDef::new(flair::Column::User).big_integer().not_null())
+                    .col(
+                        ColumnDef::new(flair::Column::Messages)
+                            .big_integer()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(ColumnDef::new(flair::Column::Title).text())
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(flair::Column::Chat)
+                            .col(flair::Column::User)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(flair::Entity).await?;
+        Ok(())
+    }
+}
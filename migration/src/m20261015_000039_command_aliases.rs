@@ -0,0 +1,45 @@
+use dijkstra::persist::{core::command_aliases, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(command_aliases::Entity)
+                    .col(
+                        ColumnDef::new(command_aliases::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(command_aliases::Column::Alias)
+                            .text()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(command_aliases::Column::Command)
+                            .text()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(command_aliases::Column::Chat)
+                            .col(command_aliases::Column::Alias)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(command_aliases::Entity).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,41 @@
+use dijkstra::persist::{admin::fed_media, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(fed_media::Entity)
+                    .col(ColumnDef::new(fed_media::Column::FedId).uuid().not_null())
+                    .col(
+                        ColumnDef::new(fed_media::Column::Fingerprint)
+                            .text()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(fed_media::Column::AddedBy)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(fed_media::Column::FedId)
+                            .col(fed_media::Column::Fingerprint)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(fed_media::Entity).await?;
+        Ok(())
+    }
+}
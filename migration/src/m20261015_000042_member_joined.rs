@@ -0,0 +1,34 @@
+use dijkstra::persist::core::chat_members;
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(chat_members::Entity)
+                    .add_column(
+                        ColumnDef::new(chat_members::Column::JoinedAt).timestamp_with_time_zone(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .alter_table(
+                Table::alter()
+                    .table(chat_members::Entity)
+                    .drop_column(chat_members::Column::JoinedAt)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}
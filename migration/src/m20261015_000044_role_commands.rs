@@ -0,0 +1,46 @@
+use dijkstra::persist::{core::role_commands, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(role_commands::Entity)
+                    .col(
+                        ColumnDef::new(role_commands::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(role_commands::Column::Role)
+                            .text()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(role_commands::Column::Command)
+                            .text()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(role_commands::Column::Chat)
+                            .col(role_commands::Column::Role)
+                            .col(role_commands::Column::Command)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(role_commands::Entity).await?;
+        Ok(())
+    }
+}
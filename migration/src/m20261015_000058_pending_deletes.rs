@@ -0,0 +1,64 @@
+use dijkstra::persist::{core::pending_deletes, migrate::ManagerHelper};
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(pending_deletes::Entity)
+                    .col(
+                        ColumnDef::new(pending_deletes::Column::Chat)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(pending_deletes::Column::MessageId)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(pending_deletes::Column::Due)
+                            .timestamp_with_time_zone()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        IndexCreateStatement::new()
+                            .col(pending_deletes::Column::Chat)
+                            .col(pending_deletes::Column::MessageId)
+                            .primary(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+
+        manager
+            .create_index(
+                IndexCreateStatement::new()
+                    .table(pending_deletes::Entity)
+                    .col(pending_deletes::Column::Due)
+                    .name("pending_deletes_due_index")
+                    .index_type(IndexType::BTree)
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_index(
+                IndexDropStatement::new()
+                    .table(pending_deletes::Entity)
+                    .name("pending_deletes_due_index")
+                    .to_owned(),
+            )
+            .await?;
+        manager.drop_table_auto(pending_deletes::Entity).await?;
+        Ok(())
+    }
+}
@@ -1,21 +1,20 @@
+use crate::persist::metrics::{count_module_db_query, DB_LATENCY};
 use crate::persist::redis::RedisPoolBuilder;
-use crate::statics;
-use crate::statics::{
-    Args, ARGS, CLIENT_BACKEND, CONFIG, CONFIG_BACKEND, DB_BACKEND, EXEC, REDIS_BACKEND,
-};
+use crate::statics::{spawn, Args, ARGS, CONFIG, EXEC, ME, TASKS, TG};
 use crate::tg::client::TgClient;
 use crate::util::error::{BotError, Result};
+use crate::util::tasks::RestartPolicy;
 use crate::{logger, DijkstraOpts};
 use clap::Parser;
 use confy::load_path;
-use nonblock_logger::JoinHandle;
+use futures::future::try_join_all;
 use prometheus::default_registry;
 use prometheus_hyper::Server;
 use sea_orm::{ConnectOptions, Database};
 use tokio::sync::Notify;
 
 fn prometheus_serve() -> tokio::task::JoinHandle<Result<()>> {
-    tokio::spawn(async move {
+    spawn(async move {
         Server::run(
             default_registry(),
             CONFIG.logging.prometheus_hook,
@@ -26,50 +25,174 @@ fn prometheus_serve() -> tokio::task::JoinHandle<Result<()>> {
     })
 }
 
+/// Register the builtin background tasks that keep state local to this process, like
+/// in memory caches and the bot client. These run in every process, including workers
+fn register_local_tasks() {
+    TASKS.register("cache_bus", RestartPolicy::on_failure(), || async {
+        crate::persist::redis::CACHE_BUS.run().await;
+        Ok(())
+    });
+    TASKS.register("token_check", RestartPolicy::on_failure(), || async {
+        crate::tg::client::run_token_check().await;
+        Ok(())
+    });
+    TASKS.register("ratelimit_cleanup", RestartPolicy::on_failure(), || async {
+        crate::tg::ratelimit::run_cleanup().await;
+        Ok(())
+    });
+}
+
+/// Register the builtin background tasks that work on shared state, like the database and
+/// the job scheduler. With sharding enabled these only run in the process routing updates,
+/// so each job runs once instead of once per worker. These are all expected to run forever
+/// unless disabled in the config, so they are only restarted if they fail
+fn register_shared_tasks() {
+    if CONFIG.mirror.enabled {
+        TASKS.register("mirror_lifecycle", RestartPolicy::on_failure(), || async {
+            crate::persist::mirror::run_lifecycle().await;
+            Ok(())
+        });
+    }
+    TASKS.register("audit_retention", RestartPolicy::on_failure(), || async {
+        crate::persist::audit::run_retention().await;
+        Ok(())
+    });
+    TASKS.register("table_stats", RestartPolicy::on_failure(), || async {
+        crate::persist::dbstats::run_table_stats().await;
+        Ok(())
+    });
+    TASKS.register("fed_cache_check", RestartPolicy::on_failure(), || async {
+        crate::tg::federations::run_cache_check().await;
+        Ok(())
+    });
+    TASKS.register("scheduler", RestartPolicy::on_failure(), || async {
+        crate::util::scheduler::run_scheduler().await;
+        Ok(())
+    });
+    TASKS.register("gban_sync", RestartPolicy::on_failure(), || async {
+        crate::tg::gbansync::run_sync().await;
+        Ok(())
+    });
+    TASKS.register("warn_sweep", RestartPolicy::on_failure(), || async {
+        crate::tg::admin_helpers::run_warn_sweep().await;
+        Ok(())
+    });
+    TASKS.register("delete_sweep", RestartPolicy::on_failure(), || async {
+        crate::tg::admin_helpers::run_delete_sweep().await;
+        Ok(())
+    });
+}
+
+/// Register the jobs and background tasks of the bot whose instance this runs in, then
+/// handle its updates until it stops
+async fn run_bot() -> Result<()> {
+    let worker = ARGS.get().unwrap().worker;
+    register_local_tasks();
+    if worker.is_none() {
+        register_shared_tasks();
+    }
+    crate::tg::admin_helpers::register_delete_job();
+    crate::tg::greetings::register_join_request_jobs();
+    crate::tg::import_export::register_import_job();
+    for state in TG.modules.iter().filter_map(|v| v.state.as_ref()) {
+        state.register_jobs();
+        state.register_inline();
+    }
+    TG.start_external_modules();
+    TASKS.start();
+    let me = TG.get_me_or_failover().await?;
+    ME.set(me)
+        .map_err(|_| BotError::generic("bot user already set"))?;
+    match worker {
+        Some(shard) => TG.run_worker(shard).await,
+        None => TG.run().await,
+    }
+}
+
 impl DijkstraOpts {
-    async fn init_real(self) -> Result<JoinHandle> {
-        ARGS.set(Args::parse()).unwrap();
-        let config = if let Some(config) = self.config {
+    /// Collect this bot and every bot hosted alongside it, this one first
+    fn flatten(mut self, bots: &mut Vec<DijkstraOpts>) {
+        let hosted = std::mem::take(&mut self.hosted);
+        bots.push(self);
+        for bot in hosted {
+            bot.flatten(bots);
+        }
+    }
+
+    /// Give this bot's instance its config, either the one passed to DijkstraOpts or the
+    /// one from the --config argument
+    fn load_config(&mut self) -> Result<()> {
+        let config = if let Some(config) = self.config.take() {
             config
         } else {
             load_path(&ARGS.get().unwrap().config).expect("failed to load config")
         };
-        CONFIG_BACKEND.set(config).unwrap();
+        self.instance.set_config(config)
+    }
 
-        let db = Database::connect(ConnectOptions::new(
-            CONFIG.persistence.database_connection.to_owned(),
-        ))
-        .await?;
-        DB_BACKEND.set(db).unwrap();
+    /// Connect to the database, telegram, and redis. Must run in this bot's instance so
+    /// the connections belong to it
+    async fn init_real(mut self) -> Result<()> {
+        let mut options = ConnectOptions::new(CONFIG.persistence.database_connection.to_owned());
+        if let Some(ref schema) = CONFIG.persistence.database_schema {
+            options.set_schema_search_path(schema.to_owned());
+        }
+        let mut db = Database::connect(options).await?;
+        db.set_metric_callback(|info| {
+            DB_LATENCY.record(info.elapsed);
+            count_module_db_query();
+        });
+        self.instance.set_db(db)?;
 
-        let log_handle = logger::setup_log();
+        #[cfg(feature = "dylib-plugins")]
+        if let Some(ref dir) = CONFIG.plugins.dir {
+            for plugin in crate::tg::plugin::load_plugins(dir) {
+                self.handler = self.handler.module(plugin);
+            }
+        }
+        self.handler.retain_compatible();
 
-        let client = if let Some(metadata) = self.modules {
-            TgClient::connect_mod(&CONFIG.bot_token, metadata, self.handler)
-        } else {
-            TgClient::connect(&CONFIG.bot_token)
-        };
-        CLIENT_BACKEND.set(client).unwrap();
+        let mut metadata = self.modules.unwrap_or_else(crate::modules::get_metadata);
+        metadata.extend(self.handler.get_metadata());
+        let client = TgClient::connect_mod(&CONFIG.bot_token, metadata, self.handler);
+        self.instance.set_client(client)?;
 
-        REDIS_BACKEND
-            .set(
-                RedisPoolBuilder::new(&CONFIG.persistence.redis_connection)
-                    .build()
-                    .await?,
-            )
-            .map_err(|_| BotError::generic("Failed to set RedisBackend"))?;
-        Ok(log_handle)
+        self.instance.set_redis(
+            RedisPoolBuilder::new(&CONFIG.persistence.redis_connection)
+                .build()
+                .await?,
+        )?;
+        Ok(())
     }
 
-    /// Initialize and run the bot
+    /// Initialize and run the bot, along with every bot hosted with it
     pub fn run(self) {
         EXEC.block_on(async move {
-            let mut log_handle = self.init_real().await.expect("failed to init state");
+            ARGS.set(Args::parse()).unwrap();
+            let mut bots = Vec::new();
+            self.flatten(&mut bots);
+            for bot in bots.iter_mut() {
+                bot.load_config().expect("failed to load config");
+            }
+
+            // logging and metrics are shared by every bot, the first bot's config sets
+            // them up
+            let first = bots[0].instance;
+            let mut log_handle = first.enter(logger::setup_log);
+            lazy_static::initialize(&crate::persist::metrics::START_TIME);
+            let handle = first.enter(prometheus_serve);
 
-            let handle = prometheus_serve();
-            let me = statics::TG.client.get_me().await.unwrap();
-            statics::ME.set(me).unwrap();
-            statics::TG.run().await.unwrap();
+            let mut running = Vec::with_capacity(bots.len());
+            for bot in bots {
+                let instance = bot.instance;
+                instance
+                    .scope(bot.init_real())
+                    .await
+                    .expect("failed to init state");
+                running.push(instance.scope(run_bot()));
+            }
+            // stop as soon as any of the bots fails, like a single bot would
+            try_join_all(running).await.unwrap();
             handle.await.unwrap().unwrap();
             log_handle.join();
         });
@@ -6,7 +6,7 @@
 //!
 //! Dijkstra is under heavy development and the API is not considered stable yet. Check back later for a future
 //! stable release.
-use metadata::Metadata;
+use metadata::{Metadata, Module};
 
 /// Utilities for keeping track of the module list and generating the help menu.
 pub mod metadata;
@@ -38,11 +38,14 @@ pub use once_cell;
 pub use redis;
 pub use sea_orm;
 pub use sea_orm_migration;
+use sea_orm_migration::MigrationTrait;
 pub use sea_query;
 pub use serde;
 pub use serde_json;
-use statics::Config;
+use statics::{Config, Instance};
 use tg::client::UpdateHandler;
+use tg::events::ModEvent;
+use tokio::sync::broadcast;
 pub use uuid;
 #[cfg(not(test))]
 pub mod init;
@@ -54,6 +57,8 @@ pub struct DijkstraOpts {
     config: Option<Config>,
     modules: Option<Vec<Metadata>>,
     handler: UpdateHandler,
+    instance: &'static Instance,
+    hosted: Vec<DijkstraOpts>,
 }
 
 impl Default for DijkstraOpts {
@@ -69,6 +74,8 @@ impl DijkstraOpts {
             config: None,
             modules: None,
             handler: UpdateHandler::new(),
+            instance: Instance::claim(),
+            hosted: Vec::new(),
         }
     }
 
@@ -79,6 +86,27 @@ impl DijkstraOpts {
         self
     }
 
+    /// Adds a module from an external crate. Registered modules are listed in help alongside
+    /// the built in ones, receive updates of the kinds in their metadata, and can be disabled
+    /// from the Module section of the Config type like any other module
+    pub fn register_module<T: Module>(mut self, module: T) -> Self {
+        self.handler = self.handler.module(module);
+        self
+    }
+
+    /// Adds a custom scorer to the antispam module. Its points are added to those of the
+    /// built in scorers for every join and every message from new members
+    pub fn spam_scorer<T: modules::antispam::SpamScorer + 'static>(self, scorer: T) -> Self {
+        self.instance.enter(|| modules::antispam::register_scorer(scorer));
+        self
+    }
+
+    /// Get the migrations of every registered module. Bots using external modules with
+    /// their own tables should add these to their migrator after the built in migrations
+    pub fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        self.handler.get_migrations()
+    }
+
     /// Add a custom configration to this bot, overriding the config parsed from config.toml via
     /// the --config argument.
     pub fn config(mut self, config: Config) -> Self {
@@ -91,4 +119,19 @@ impl DijkstraOpts {
         self.handler = update_handler;
         self
     }
+
+    /// Subscribe to moderation events like bans, warns, and fbans. Events are published
+    /// once the bot is running, see tg::events for delivery guarantees
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ModEvent> {
+        self.instance.enter(tg::events::subscribe)
+    }
+
+    /// Host another bot in this process alongside this one. The hosted bot has its own
+    /// token, modules, database schema, and redis from its config and shares no state with
+    /// this bot, see statics::Instance. Give the hosted bot its own config with
+    /// DijkstraOpts::config, otherwise it loads the same --config file as this one
+    pub fn host(mut self, bot: DijkstraOpts) -> Self {
+        self.hosted.push(bot);
+        self
+    }
 }
@@ -30,7 +30,11 @@ pub fn markdownify<T: AsRef<str>>(description: T) -> String {
 }
 
 /// Macro for registering a module. Generates a metadata getter out of a name, description, and
-/// command list
+/// command list. A leading `{ category = "Moderation" }` groups the module in the help menu,
+/// optionally with `long_help` shown on the module's help page after the description.
+/// Commands can declare `requires = [CanRestrict, CanDelete]` and `cooldown = 30` after their
+/// level, see CommandPolicy. A trailing `{ updates = [Message, ChatMember] }` limits the
+/// module's handler to those kinds of updates, see UpdateKind
 #[macro_export]
 macro_rules! metadata {
     ($name:expr, $description:expr) => {
@@ -40,14 +44,25 @@ macro_rules! metadata {
                 priority: None,
                 description: $description.into(),
                 commands: ::std::collections::HashMap::new(),
+                levels: ::std::collections::HashMap::new(),
+                policies: ::std::collections::HashMap::new(),
                 sections: ::std::collections::HashMap::new(),
-                state: None
+                category: None,
+                long_help: None,
+                updates: $crate::metadata::UpdateKinds::ALL,
+                state: None,
+                version: Some(env!("CARGO_PKG_VERSION").into()),
+                author: None,
+                source: None,
+                api_version: $crate::metadata::API_VERSION
             });
     };
 
     ($name:expr, $description:expr
+        $( , { category = $category:expr $(, long_help = $long_help:expr)? } )?
         $( , { sub = $sub:expr, content = $content:expr } )*
-        $( , { command = $command:expr, help = $help:expr } )*
+        $( , { command = $command:expr, help = $help:expr $(, level = $level:ident)? $(, requires = [$($right:ident),* $(,)?])? $(, cooldown = $cooldown:expr)? } )*
+        $( , { updates = [$($update:ident),* $(,)?] } )?
     ) => {
         #[allow(unused_mut)]
         pub static METADATA: $crate::once_cell::sync::Lazy<$crate::metadata::Metadata> =
@@ -59,10 +74,38 @@ macro_rules! metadata {
                     priority: None,
                     description,
                     commands: ::std::collections::HashMap::new(),
+                    levels: ::std::collections::HashMap::new(),
+                    policies: ::std::collections::HashMap::new(),
                     sections: ::std::collections::HashMap::new(),
-                    state: None
+                    category: None,
+                    long_help: None,
+                    updates: $crate::metadata::UpdateKinds::declared(&[$($($crate::metadata::UpdateKind::$update),*)?]),
+                    state: None,
+                    version: Some(env!("CARGO_PKG_VERSION").into()),
+                    author: None,
+                    source: None,
+                    api_version: $crate::metadata::API_VERSION
                 };
-                $(c.commands.insert($command.into(), $help.into());)*
+                $(
+                    c.commands.insert($command.into(), $help.into());
+                    c.levels.insert(
+                        $command.into(),
+                        $crate::metadata::CommandLevel::declared(&[$($crate::metadata::CommandLevel::$level),*])
+                    );
+                    c.policies.insert(
+                        $command.into(),
+                        $crate::metadata::CommandPolicy::declared(
+                            &[$($($crate::metadata::CommandRight::$right),*)?],
+                            &[$($cooldown),*]
+                        )
+                    );
+                )*
+                $(
+                    c.category = Some($category.into());
+                    $(
+                        c.long_help = Some($crate::metadata::markdownify($long_help));
+                    )?
+                )?
                 $(
                     let content = $crate::metadata::markdownify($content);
                     c.sections.insert($sub.into(), content.into());
@@ -72,8 +115,10 @@ macro_rules! metadata {
     };
 
     ($name:expr, $description:expr, $serialize:expr
+        $( , { category = $category:expr $(, long_help = $long_help:expr)? } )?
         $( , { sub = $sub:expr, content = $content:expr } )*
-        $( , { command = $command:expr, help = $help:expr } )*
+        $( , { command = $command:expr, help = $help:expr $(, level = $level:ident)? $(, requires = [$($right:ident),* $(,)?])? $(, cooldown = $cooldown:expr)? } )*
+        $( , { updates = [$($update:ident),* $(,)?] } )?
     ) => {
         #[allow(unused_mut)]
         pub static METADATA: $crate::once_cell::sync::Lazy<$crate::metadata::Metadata> =
@@ -85,10 +130,38 @@ macro_rules! metadata {
                     priority: None,
                     description,
                     commands: ::std::collections::HashMap::new(),
+                    levels: ::std::collections::HashMap::new(),
+                    policies: ::std::collections::HashMap::new(),
                     sections: ::std::collections::HashMap::new(),
-                    state: Some(::std::sync::Arc::new($serialize))
+                    category: None,
+                    long_help: None,
+                    updates: $crate::metadata::UpdateKinds::declared(&[$($($crate::metadata::UpdateKind::$update),*)?]),
+                    state: Some(::std::sync::Arc::new($serialize)),
+                    version: Some(env!("CARGO_PKG_VERSION").into()),
+                    author: None,
+                    source: None,
+                    api_version: $crate::metadata::API_VERSION
                 };
-                $(c.commands.insert($command.into(), $help.into());)*
+                $(
+                    c.commands.insert($command.into(), $help.into());
+                    c.levels.insert(
+                        $command.into(),
+                        $crate::metadata::CommandLevel::declared(&[$($crate::metadata::CommandLevel::$level),*])
+                    );
+                    c.policies.insert(
+                        $command.into(),
+                        $crate::metadata::CommandPolicy::declared(
+                            &[$($($crate::metadata::CommandRight::$right),*)?],
+                            &[$($cooldown),*]
+                        )
+                    );
+                )*
+                $(
+                    c.category = Some($category.into());
+                    $(
+                        c.long_help = Some($crate::metadata::markdownify($long_help));
+                    )?
+                )?
                 $(
                     let content = $crate::metadata::markdownify($content);
                     c.sections.insert($sub.into(), content.into());
@@ -98,8 +171,10 @@ macro_rules! metadata {
 
     };
     ($name:expr, $description:expr, $serialize:expr, $priority:expr
+        $( , { category = $category:expr $(, long_help = $long_help:expr)? } )?
         $( , { sub = $sub:expr, content = $content:expr } )*
-        $( , { command = $command:expr, help = $help:expr } )*
+        $( , { command = $command:expr, help = $help:expr $(, level = $level:ident)? $(, requires = [$($right:ident),* $(,)?])? $(, cooldown = $cooldown:expr)? } )*
+        $( , { updates = [$($update:ident),* $(,)?] } )?
     ) => {
         #[allow(unused_mut)]
         pub static METADATA: $crate::once_cell::sync::Lazy<$crate::metadata::Metadata> =
@@ -111,10 +186,38 @@ macro_rules! metadata {
                     priority: Some($priority),
                     description,
                     commands: ::std::collections::HashMap::new(),
+                    levels: ::std::collections::HashMap::new(),
+                    policies: ::std::collections::HashMap::new(),
                     sections: ::std::collections::HashMap::new(),
-                    state: Some(::std::sync::Arc::new($serialize))
+                    category: None,
+                    long_help: None,
+                    updates: $crate::metadata::UpdateKinds::declared(&[$($($crate::metadata::UpdateKind::$update),*)?]),
+                    state: Some(::std::sync::Arc::new($serialize)),
+                    version: Some(env!("CARGO_PKG_VERSION").into()),
+                    author: None,
+                    source: None,
+                    api_version: $crate::metadata::API_VERSION
                 };
-                $(c.commands.insert($command.into(), $help.into());)*
+                $(
+                    c.commands.insert($command.into(), $help.into());
+                    c.levels.insert(
+                        $command.into(),
+                        $crate::metadata::CommandLevel::declared(&[$($crate::metadata::CommandLevel::$level),*])
+                    );
+                    c.policies.insert(
+                        $command.into(),
+                        $crate::metadata::CommandPolicy::declared(
+                            &[$($($crate::metadata::CommandRight::$right),*)?],
+                            &[$($cooldown),*]
+                        )
+                    );
+                )*
+                $(
+                    c.category = Some($category.into());
+                    $(
+                        c.long_help = Some($crate::metadata::markdownify($long_help));
+                    )?
+                )?
                 $(
                     let content = $crate::metadata::markdownify($content);
                     c.sections.insert($sub.into(), content.into());
@@ -124,14 +227,149 @@ macro_rules! metadata {
     };
 }
 use async_trait::async_trait;
+use botapi::gen_types::{Chat, UpdateExt};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 pub use metadata;
 use regex::Regex;
 use sea_orm_migration::MigrationTrait;
 
+use crate::tg::command::Context;
+use crate::tg::template::ChatTemplate;
 use crate::util::error::Result;
 
+/// Help menu category of modules that don't declare one
+pub const OTHER_CATEGORY: &str = "Other";
+
+/// Version of the API external modules are built against. Bumped whenever Module or
+/// Metadata change in a way that breaks existing modules
+pub const API_VERSION: u32 = 1;
+
+/// Oldest API version external modules can be built against and still be loaded
+pub const MIN_API_VERSION: u32 = 1;
+
+/// Who a command is intended for. Privileged commands are hidden or marked in help
+/// depending on who is asking
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CommandLevel {
+    #[default]
+    User,
+    Admin,
+    Owner,
+}
+
+impl CommandLevel {
+    /// Get the level declared for a command in metadata!, or User if none was given
+    pub fn declared(levels: &[CommandLevel]) -> Self {
+        levels.first().copied().unwrap_or_default()
+    }
+}
+
+/// Chat rights a command can require in metadata! with `requires = [CanRestrict]`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandRight {
+    CanRestrict,
+    CanDelete,
+    CanPin,
+    CanChangeInfo,
+    CanPromote,
+    /// the user created the chat
+    Owner,
+    /// the user owns or is an admin of the chat's federation
+    FedAdmin,
+}
+
+/// Rights and cooldown enforced by the dispatcher before a command reaches its module,
+/// so modules don't each have to check them. Sudo users skip every check
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CommandPolicy {
+    /// every right the sender needs, see CommandRight
+    pub requires: Vec<CommandRight>,
+    /// seconds each user has to wait between uses of the command in a chat
+    pub cooldown: Option<u64>,
+}
+
+impl CommandPolicy {
+    /// Get the policy declared for a command in metadata!
+    pub fn declared(requires: &[CommandRight], cooldown: &[u64]) -> Self {
+        Self {
+            requires: requires.to_vec(),
+            cooldown: cooldown.first().copied().filter(|v| *v > 0),
+        }
+    }
+
+    /// Returns true if the policy doesn't restrict the command at all
+    pub fn is_empty(&self) -> bool {
+        self.requires.is_empty() && self.cooldown.is_none()
+    }
+}
+
+/// Kinds of updates a module can subscribe to in metadata!
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateKind {
+    /// messages and channel posts
+    Message,
+    /// edited messages and channel posts
+    EditedMessage,
+    Callback,
+    /// joins, leaves, and join requests
+    ChatMember,
+    Reaction,
+    /// inline queries and chosen inline results
+    Inline,
+    /// anything else, polls, payments, boosts, and business updates
+    Other,
+}
+
+impl UpdateKind {
+    /// Get the kind of an update for routing
+    pub fn of(update: &UpdateExt) -> Self {
+        match update {
+            UpdateExt::Message(_) | UpdateExt::ChannelPost(_) => Self::Message,
+            UpdateExt::EditedMessage(_) | UpdateExt::EditedChannelPost(_) => Self::EditedMessage,
+            UpdateExt::CallbackQuery(_) => Self::Callback,
+            UpdateExt::ChatMember(_)
+            | UpdateExt::MyChatMember(_)
+            | UpdateExt::ChatJoinRequest(_) => Self::ChatMember,
+            UpdateExt::MessageReaction(_) | UpdateExt::MessageReactionCount(_) => Self::Reaction,
+            UpdateExt::InlineQuery(_) | UpdateExt::ChosenInlineResult(_) => Self::Inline,
+            _ => Self::Other,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// Set of update kinds a module's handler is called for. Modules that don't declare any
+/// in metadata! are called for every update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UpdateKinds(u8);
+
+impl UpdateKinds {
+    pub const ALL: Self = Self(u8::MAX);
+
+    /// Get the kinds declared for a module in metadata!, or all kinds if none were given
+    pub fn declared(kinds: &[UpdateKind]) -> Self {
+        if kinds.is_empty() {
+            Self::ALL
+        } else {
+            Self(kinds.iter().fold(0, |acc, v| acc | v.bit()))
+        }
+    }
+
+    pub fn contains(&self, kind: UpdateKind) -> bool {
+        self.0 & kind.bit() != 0
+    }
+}
+
+impl Default for UpdateKinds {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
 /// metadata for a single module
 #[derive(Clone, Debug)]
 pub struct Metadata {
@@ -139,8 +377,24 @@ pub struct Metadata {
     pub priority: Option<i32>,
     pub description: String,
     pub commands: HashMap<String, String>,
+    pub levels: HashMap<String, CommandLevel>,
+    /// rights and cooldowns checked before a command reaches the module
+    pub policies: HashMap<String, CommandPolicy>,
     pub sections: HashMap<String, String>,
+    /// help menu category, modules without one are listed under OTHER_CATEGORY
+    pub category: Option<String>,
+    /// extra help shown on the module's own help page but not in listings
+    pub long_help: Option<String>,
+    /// kinds of updates the module's handler is called for
+    pub updates: UpdateKinds,
     pub state: Option<Arc<dyn ModuleHelpers + Send + Sync>>,
+    /// version of the module, built in modules use the bot's version
+    pub version: Option<String>,
+    pub author: Option<String>,
+    /// url of the module's source code
+    pub source: Option<String>,
+    /// API_VERSION the module was built against
+    pub api_version: u32,
 }
 
 impl Metadata {
@@ -150,8 +404,17 @@ impl Metadata {
             priority,
             description,
             commands: HashMap::new(),
+            levels: HashMap::new(),
+            policies: HashMap::new(),
             sections: HashMap::new(),
+            category: None,
+            long_help: None,
+            updates: UpdateKinds::ALL,
             state: None,
+            version: None,
+            author: None,
+            source: None,
+            api_version: API_VERSION,
         }
     }
 
@@ -160,10 +423,68 @@ impl Metadata {
         self
     }
 
+    /// Get who a command is intended for, defaulting to all users
+    pub fn command_level(&self, command: &str) -> CommandLevel {
+        self.levels.get(command).copied().unwrap_or_default()
+    }
+
+    /// Get the rights and cooldown required to use a command, if it declared any
+    pub fn command_policy(&self, command: &str) -> Option<&'_ CommandPolicy> {
+        self.policies.get(command).filter(|v| !v.is_empty())
+    }
+
+    /// Require chat rights or a cooldown for a command, see CommandPolicy
+    pub fn set_command_policy(mut self, command: String, policy: CommandPolicy) -> Self {
+        self.policies.insert(command, policy);
+        self
+    }
+
     pub fn add_section(mut self, sub: String, content: String) -> Self {
         self.sections.insert(sub, content);
         self
     }
+
+    pub fn set_category(mut self, category: String) -> Self {
+        self.category = Some(category);
+        self
+    }
+
+    pub fn set_long_help(mut self, long_help: String) -> Self {
+        self.long_help = Some(long_help);
+        self
+    }
+
+    pub fn set_version(mut self, version: String) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    pub fn set_author(mut self, author: String) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    pub fn set_source(mut self, source: String) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Declare the API_VERSION this module was written for. Modules that don't are
+    /// assumed to match the version they were compiled with
+    pub fn set_api_version(mut self, api_version: u32) -> Self {
+        self.api_version = api_version;
+        self
+    }
+
+    /// Returns true if this module's API version can be loaded by this version of the bot
+    pub fn is_compatible(&self) -> bool {
+        (MIN_API_VERSION..=API_VERSION).contains(&self.api_version)
+    }
+
+    /// Get the help menu category of this module
+    pub fn category(&self) -> &'_ str {
+        self.category.as_deref().unwrap_or(OTHER_CATEGORY)
+    }
 }
 
 #[async_trait]
@@ -172,4 +493,107 @@ pub trait ModuleHelpers: std::fmt::Debug {
     async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()>;
     fn supports_export(&self) -> Option<&'static str>;
     fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>>;
+
+    /// Convert this module's section of a backup written with an older format version, see
+    /// crate::tg::import_export::EXPORT_VERSION. Returns None if the section can't be
+    /// imported, for example if only this bot's own backups use this section name
+    fn upgrade_export(
+        &self,
+        _version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok(Some(value))
+    }
+
+    /// Register handlers for scheduled jobs owned by this module. Called once at startup
+    fn register_jobs(&self) {}
+
+    /// Register providers for inline queries owned by this module. Called once at startup
+    fn register_inline(&self) {}
+
+    /// Apply this module's section of the default settings template to a chat the bot
+    /// was just added to, see crate::tg::template
+    async fn apply_template(&self, _chat: &Chat, _template: &ChatTemplate) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A module shipped by an external crate. Unlike metadata passed to DijkstraOpts::modules,
+/// registered modules get the same treatment as the built in ones: they show up in help,
+/// their commands are routed and metered under their name, they can be disabled in the
+/// config, and their migrations can be added to a migrator with DijkstraOpts::get_migrations
+#[async_trait]
+pub trait Module: Send + Sync + 'static {
+    /// Name, help text, commands, and subscribed update kinds of this module
+    fn metadata(&self) -> Metadata;
+
+    /// Called for every update of a kind listed in the module's metadata
+    async fn handle_update(&self, ctx: &Context) -> Result<()>;
+
+    /// Database migrations for tables owned by this module
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        Vec::new()
+    }
+
+    /// Register handlers for scheduled jobs owned by this module. Called once at startup
+    fn register_jobs(&self) {}
+
+    /// Register providers for inline queries owned by this module. Called once at startup,
+    /// see crate::tg::inline
+    fn register_inline(&self) {}
+
+    /// Spawn long running background tasks. Called once at startup, after register_jobs
+    fn spawn_tasks(&self) {}
+
+    /// Apply this module's section of the default settings template to a chat the bot
+    /// was just added to, see crate::tg::template
+    async fn apply_template(&self, _chat: &Chat, _template: &ChatTemplate) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn declared_update_kinds() {
+        let all = UpdateKinds::declared(&[]);
+        assert!(all.contains(UpdateKind::Message));
+        assert!(all.contains(UpdateKind::Other));
+
+        let kinds = UpdateKinds::declared(&[UpdateKind::Message, UpdateKind::ChatMember]);
+        assert!(kinds.contains(UpdateKind::Message));
+        assert!(kinds.contains(UpdateKind::ChatMember));
+        assert!(!kinds.contains(UpdateKind::EditedMessage));
+        assert!(!kinds.contains(UpdateKind::Other));
+    }
+
+    #[test]
+    fn declared_policy() {
+        let policy = CommandPolicy::declared(&[CommandRight::CanRestrict], &[30]);
+        assert_eq!(policy.requires, vec![CommandRight::CanRestrict]);
+        assert_eq!(policy.cooldown, Some(30));
+        assert!(CommandPolicy::declared(&[], &[]).is_empty());
+        assert!(CommandPolicy::declared(&[], &[0]).is_empty());
+
+        let metadata = Metadata::new("test".to_owned(), "test".to_owned(), None)
+            .add_command("ban".to_owned(), "bans".to_owned())
+            .set_command_policy("ban".to_owned(), policy.clone());
+        assert_eq!(metadata.command_policy("ban"), Some(&policy));
+        assert_eq!(metadata.command_policy("kick"), None);
+    }
+
+    #[test]
+    fn api_compatibility() {
+        let metadata = Metadata::new("test".to_owned(), "test".to_owned(), None);
+        assert!(metadata.is_compatible());
+        assert!(!metadata
+            .clone()
+            .set_api_version(API_VERSION + 1)
+            .is_compatible());
+        assert!(!metadata
+            .set_api_version(MIN_API_VERSION - 1)
+            .is_compatible());
+    }
 }
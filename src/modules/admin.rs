@@ -27,10 +27,12 @@ metadata!("Admin",
     changed recently. This is to avoid spamming the telegram api. Use this command if the bot
     does not correctly recognize an admin
     "#,
-    { command = "admincache", help = "Refresh the cached list of admins" },
+    { category = "Moderation" },
+    { command = "admincache", help = "Refresh the cached list of admins", level = Admin },
     { command = "admins", help = "Get a list of admins" },
-    { command = "promote", help = "Promote a user to admin"},
-    { command = "demote", help = "Demote a user" }
+    { command = "promote", help = "Promote a user to admin", level = Admin},
+    { command = "demote", help = "Demote a user", level = Admin },
+    { updates = [Message] }
 );
 
 async fn promote(context: &Context) -> Result<()> {
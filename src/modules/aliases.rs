@@ -0,0 +1,108 @@
+use crate::metadata::metadata;
+use crate::statics::TG;
+use crate::tg::aliases::{get_aliases, parse_alias, remove_alias, set_alias};
+use crate::tg::command::{trim_prefix, Cmd, Context, TextArgs};
+use crate::tg::markdown::Escape;
+use crate::tg::quotas::{check_quota, Quota};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Aliases",
+    r#"
+    Give commands other names in this chat. An alias works exactly like the command it
+    stands for, including its arguments and permission checks, and can be used with any
+    command prefix. Aliases can't replace the name of an existing command.
+
+    [*Example:]
+    /alias warnban ban
+    /warnban @someone spam
+    "#,
+    { category = "Chat Management" },
+    { command = "alias", help = "Usage: alias \\<name\\> \\<command\\>. Makes name run command in this chat", level = Admin, requires = [CanChangeInfo] },
+    { command = "rmalias", help = "Usage: rmalias \\<name\\>. Removes an alias", level = Admin, requires = [CanChangeInfo] },
+    { command = "aliases", help = "Lists the command aliases in this chat" },
+    { updates = [Message] }
+);
+
+async fn alias_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let [alias, command] = args.args.as_slice() else {
+        return ctx.fail(lang_fmt!(ctx, "aliasusage"));
+    };
+    let Some(alias) = parse_alias(trim_prefix(alias.get_text())) else {
+        return ctx.fail(lang_fmt!(ctx, "aliasusage"));
+    };
+    let command = trim_prefix(command.get_text()).to_lowercase();
+    if TG.modules.command_level(&command).is_none() {
+        return ctx.fail(lang_fmt!(ctx, "aliasnocmd", command.escape(false)));
+    }
+    if TG.modules.command_level(&alias).is_some() {
+        return ctx.fail(lang_fmt!(ctx, "aliastaken", alias.escape(false)));
+    }
+    let aliases = get_aliases(chat).await?;
+    if !aliases.contains_key(&alias) {
+        check_quota(message, ctx.lang(), Quota::Aliases, aliases.len() as u64, 1)?;
+    }
+    set_alias(chat, &alias, &command).await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "aliasset",
+        alias.escape(false),
+        command.escape(false)
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn rmalias_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(alias) = args
+        .args
+        .first()
+        .and_then(|v| parse_alias(trim_prefix(v.get_text())))
+    else {
+        return ctx.fail(lang_fmt!(ctx, "rmaliasusage"));
+    };
+    if remove_alias(chat, &alias).await? {
+        ctx.reply(lang_fmt!(ctx, "aliasremoved", alias.escape(false)))
+            .await?;
+    } else {
+        ctx.fail(lang_fmt!(ctx, "aliasmissing", alias.escape(false)))?;
+    }
+    Ok(())
+}
+
+async fn list_aliases(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let aliases = get_aliases(chat).await?;
+    if aliases.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "noaliases")).await?;
+        return Ok(());
+    }
+    let list = aliases
+        .iter()
+        .sorted()
+        .map(|(alias, command)| format!("/{} -> /{}", alias.escape(false), command.escape(false)))
+        .join("\n");
+    ctx.reply(lang_fmt!(ctx, "listaliases", list)).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "alias" => alias_cmd(ctx, args).await?,
+            "rmalias" => rmalias_cmd(ctx, args).await?,
+            "aliases" => list_aliases(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
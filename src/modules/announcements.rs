@@ -0,0 +1,491 @@
+use self::entities::{announcement_settings, announcements};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::markdown::{Escape, MarkupBuilder};
+use crate::tg::quotas::check_note_size;
+use crate::util::error::{BotError, Fail, Result, SpeakErr};
+use crate::util::locale::Localize;
+use crate::util::scheduler::{cancel, register_job, schedule};
+use crate::util::string::Speak;
+use botapi::gen_types::EReplyMarkup;
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Announcements",
+    r#"
+    Rotate pinned announcements, like a weekly reminder of the rules. Announcements are
+    murkdown formatted \(see /help formatting\). Once rotation is started, the next
+    announcement is posted and pinned on a schedule, and the one pinned before it is unpinned.
+
+    [*Example:]
+    /addannouncement Please read the /rules before posting
+    /addannouncement Community call every friday!
+    /rotateannouncements 1w
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "addannouncement", help = "Usage: addannouncement \\<text\\>. Adds an announcement to the rotation", level = Admin, requires = [CanPin] },
+    { command = "rmannouncement", help = "Usage: rmannouncement \\<number\\>. Removes an announcement, see /announcements for numbers", level = Admin, requires = [CanPin] },
+    { command = "announcements", help = "Lists the announcements and how often they rotate", level = Admin },
+    { command = "rotateannouncements", help = "Usage: rotateannouncements \\<time/off\\>. Pins the next announcement every time, starting now", level = Admin, requires = [CanPin] },
+    { updates = [Message] }
+);
+
+/// Scheduler job kind for pinning the next announcement
+const ANNOUNCE_JOB: &str = "announce";
+
+/// Most announcements in a chat's rotation
+const MAX_ANNOUNCEMENTS: usize = 10;
+
+/// Shortest time between rotations, in seconds
+const MIN_INTERVAL: i64 = 60 * 60;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(announcements::Entity)
+                        .col(
+                            ColumnDef::new(announcements::Column::Id)
+                                .big_integer()
+                                .not_null()
+                                .primary_key()
+                                .auto_increment(),
+                        )
+                        .col(
+                            ColumnDef::new(announcements::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(announcements::Column::Text)
+                                .text()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_index(
+                    IndexCreateStatement::new()
+                        .table(announcements::Entity)
+                        .name("announcements_chat")
+                        .col(announcements::Column::Chat)
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_table(
+                    Table::create()
+                        .table(announcement_settings::Entity)
+                        .col(
+                            ColumnDef::new(announcement_settings::Column::Chat)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(announcement_settings::Column::Interval)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(announcement_settings::Column::Next)
+                                .integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .col(ColumnDef::new(announcement_settings::Column::Pinned).big_integer())
+                        .col(ColumnDef::new(announcement_settings::Column::Job).uuid())
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(announcements::Entity).await?;
+            manager
+                .drop_table_auto(announcement_settings::Entity)
+                .await?;
+            Ok(())
+        }
+    }
+
+    pub mod announcements {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// One announcement in a chat's rotation, stored as murkdown
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "announcements")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            pub chat: i64,
+            pub text: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod announcement_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// Rotation state of a chat with rotation started
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "announcement_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub chat: i64,
+            /// seconds between rotations
+            pub interval: i64,
+            /// position of the announcement pinned next
+            pub next: i32,
+            /// message id of the announcement currently pinned
+            pub pinned: Option<i64>,
+            pub job: Option<Uuid>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000047_create_announcements"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(ANNOUNCE_JOB, |payload| async move {
+            let chat: i64 = serde_json::from_value(payload)?;
+            rotate(chat).await
+        });
+    }
+}
+
+#[inline(always)]
+fn get_announcements_key(chat: i64) -> String {
+    format!("anns:{}", chat)
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("annset:{}", chat)
+}
+
+/// Get the announcements of a chat in the order they rotate
+async fn get_announcements(chat: i64) -> Result<Vec<announcements::Model>> {
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = announcements::Entity::find()
+                .filter(announcements::Column::Chat.eq(chat))
+                .order_by_asc(announcements::Column::Id)
+                .all(*DB)
+                .await?;
+            Ok(Some(res))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_announcements_key(chat), &())
+    .await?;
+    Ok(res.unwrap_or_default())
+}
+
+async fn get_settings(chat: i64) -> Result<Option<announcement_settings::Model>> {
+    let key = get_settings_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = announcement_settings::Entity::find_by_id(chat)
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+async fn save_settings(settings: announcement_settings::Model) -> Result<()> {
+    let chat = settings.chat;
+    let model = announcement_settings::Entity::insert(announcement_settings::ActiveModel {
+        chat: Set(settings.chat),
+        interval: Set(settings.interval),
+        next: Set(settings.next),
+        pinned: Set(settings.pinned),
+        job: Set(settings.job),
+    })
+    .on_conflict(
+        OnConflict::column(announcement_settings::Column::Chat)
+            .update_columns([
+                announcement_settings::Column::Interval,
+                announcement_settings::Column::Next,
+                announcement_settings::Column::Pinned,
+                announcement_settings::Column::Job,
+            ])
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+/// Unpin the current announcement and stop rotating
+async fn stop_rotation(chat: i64) -> Result<()> {
+    if let Some(settings) = get_settings(chat).await? {
+        if let Some(job) = settings.job {
+            cancel(&job).await?;
+        }
+        if let Some(pinned) = settings.pinned {
+            TG.client()
+                .build_unpin_chat_message(chat)
+                .message_id(pinned)
+                .build()
+                .await
+                .log();
+        }
+    }
+    announcement_settings::Entity::delete_by_id(chat)
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_settings_key(chat)]).await?;
+    Ok(())
+}
+
+/// Unpin the previous announcement, pin the next one, and schedule the rotation after
+async fn rotate(chat: i64) -> Result<()> {
+    let Some(mut settings) = announcement_settings::Entity::find_by_id(chat)
+        .one(*DB)
+        .await?
+    else {
+        return Ok(());
+    };
+    let announcements = get_announcements(chat).await?;
+    if announcements.is_empty() {
+        return stop_rotation(chat).await;
+    }
+    let next = settings.next as usize % announcements.len();
+
+    if let Some(pinned) = settings.pinned {
+        // the previous announcement may have been deleted or unpinned by hand
+        TG.client()
+            .build_unpin_chat_message(chat)
+            .message_id(pinned)
+            .build()
+            .await
+            .log();
+    }
+    let (text, entities, buttons) = MarkupBuilder::new(None)
+        .set_text(announcements[next].text.clone())
+        .filling(false)
+        .header(false)
+        .build_murkdown_nofail()
+        .await;
+    let message = TG
+        .client()
+        .build_send_message(chat, &text)
+        .entities(&entities)
+        .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(buttons.build()))
+        .build()
+        .await?;
+    TG.client()
+        .build_pin_chat_message(chat, message.get_message_id())
+        .disable_notification(true)
+        .build()
+        .await?;
+
+    let interval = Duration::try_seconds(settings.interval).unwrap_or_default();
+    settings.job = Some(schedule(ANNOUNCE_JOB, &chat, Utc::now() + interval).await?);
+    settings.pinned = Some(message.get_message_id());
+    settings.next = ((next + 1) % announcements.len()) as i32;
+    save_settings(settings).await?;
+    Ok(())
+}
+
+async fn add_announcement(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let text = args.text.trim();
+    if text.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "addannouncementusage"));
+    }
+    check_note_size(message, ctx.lang(), text)?;
+    if get_announcements(chat).await?.len() >= MAX_ANNOUNCEMENTS {
+        return ctx.fail(lang_fmt!(ctx, "announcementsfull", MAX_ANNOUNCEMENTS));
+    }
+    // check the murkdown now so a broken announcement isn't found when it is due
+    MarkupBuilder::new(None)
+        .set_text(text.to_owned())
+        .filling(false)
+        .header(false)
+        .build_murkdown()
+        .await
+        .speak_err_raw(ctx, |v| match v {
+            BotError::MurkdownError(err) => {
+                Some(lang_fmt!(ctx, "failmurkat", err.to_string().escape(false)))
+            }
+            _ => Some(lang_fmt!(ctx, "failmurk")),
+        })
+        .await?;
+    announcements::Entity::insert(announcements::ActiveModel {
+        id: NotSet,
+        chat: Set(chat),
+        text: Set(text.to_owned()),
+    })
+    .exec(*DB)
+    .await?;
+    REDIS.invalidate(&[&get_announcements_key(chat)]).await?;
+    let count = get_announcements(chat).await?.len();
+    ctx.reply(lang_fmt!(ctx, "announcementadded", count))
+        .await?;
+    Ok(())
+}
+
+async fn rm_announcement(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let announcements = get_announcements(chat).await?;
+    let Some(announcement) = args
+        .text
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|v| v.checked_sub(1))
+        .and_then(|v| announcements.get(v))
+    else {
+        return ctx.fail(lang_fmt!(ctx, "rmannouncementusage"));
+    };
+    announcements::Entity::delete_by_id(announcement.id)
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_announcements_key(chat)]).await?;
+    ctx.reply(lang_fmt!(ctx, "announcementremoved", args.text.trim()))
+        .await?;
+    Ok(())
+}
+
+async fn list_announcements(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let announcements = get_announcements(chat).await?;
+    if announcements.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "noannouncements")).await?;
+        return Ok(());
+    }
+    let rotation = match get_settings(chat).await? {
+        Some(settings) => lang_fmt!(
+            ctx,
+            "announcementsrotating",
+            Duration::try_seconds(settings.interval)
+                .unwrap_or_default()
+                .localize(ctx.lang())
+        ),
+        None => lang_fmt!(ctx, "announcementsstopped"),
+    };
+    let list = announcements
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("{}. {}", i + 1, v.text.escape(false)))
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(lang_fmt!(ctx, "listannouncements", rotation, list))
+        .await?;
+    Ok(())
+}
+
+async fn rotate_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    if args.text.trim() == "off" {
+        stop_rotation(chat).await?;
+        ctx.reply(lang_fmt!(ctx, "rotationoff")).await?;
+        return Ok(());
+    }
+    let Ok(Some(interval)) = ctx.parse_duration(&Some(args.as_slice())) else {
+        return ctx.fail(lang_fmt!(ctx, "rotationusage"));
+    };
+    if interval.num_seconds() < MIN_INTERVAL {
+        return ctx.fail(lang_fmt!(ctx, "rotationusage"));
+    }
+    if get_announcements(chat).await?.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "noannouncements"));
+    }
+    let previous = get_settings(chat).await?;
+    if let Some(job) = previous.as_ref().and_then(|v| v.job) {
+        cancel(&job).await?;
+    }
+    save_settings(announcement_settings::Model {
+        chat,
+        interval: interval.num_seconds(),
+        next: previous.as_ref().map(|v| v.next).unwrap_or(0),
+        pinned: previous.and_then(|v| v.pinned),
+        job: None,
+    })
+    .await?;
+    rotate(chat).await?;
+    ctx.reply(lang_fmt!(ctx, "rotationon", interval.localize(ctx.lang())))
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "addannouncement" => add_announcement(ctx, args).await?,
+            "rmannouncement" => rm_announcement(ctx, args).await?,
+            "announcements" => list_announcements(ctx).await?,
+            "rotateannouncements" => rotate_cmd(ctx, args).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,587 @@
+use self::entities::antispam_settings::{self, SpamAction};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{BotLocal, CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::{
+    is_dm, kick, post_log, DeleteAfterTime, UpdateHelpers, UserChanged,
+};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_joined_at;
+use crate::tg::events::{publish, ModEvent};
+use crate::tg::exemptions::get_exemption;
+use crate::tg::markdown::Escape;
+use crate::tg::user::{get_bio, Username};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use async_trait::async_trait;
+use botapi::gen_types::{Chat, Message, User};
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use std::sync::{Arc, RwLock};
+
+metadata!("Antispam",
+    r#"
+    Score new members for signs of being a spam account and act once they score too high.
+    Points are given for links or many emoji in a name, links in a bio, joining during a flood
+    of joins, and posting links soon after joining. Scores are forgotten after a day, and
+    admins and approved users are never scored. Every action is reported to the log channel.
+
+    [*Actions:]\n
+    [*flag]: only report it to the log channel\n
+    [*mute]: mute the member\n
+    [*kick]: remove the member, they can join again\n
+    [*ban]: ban the member
+
+    [*Example:]
+    /antispam mute 5
+    "#,
+    Helper,
+    { category = "Moderation" },
+    { command = "antispam", help = "Usage: antispam \\[off/flag/mute/kick/ban\\] \\[score\\]. Shows or sets what happens to members scoring at least score", level = Admin, requires = [CanRestrict] },
+    { updates = [Message, ChatMember] }
+);
+
+/// Score at which action is taken when none is given
+const DEFAULT_THRESHOLD: i32 = 5;
+
+/// Seconds a member's score is remembered
+const SCORE_TTL: i64 = 24 * 60 * 60;
+
+/// Seconds after joining that a member's messages are scored
+const NEW_MEMBER_SECONDS: i64 = 24 * 60 * 60;
+
+/// Seconds over which joins are counted to detect a flood
+const JOIN_WINDOW: i64 = 60;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(antispam_settings::Entity)
+                        .col(
+                            ColumnDef::new(antispam_settings::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(antispam_settings::Column::Action)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(antispam_settings::Column::Threshold)
+                                .integer()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(antispam_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod antispam_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// What happens to a member once their score reaches the threshold
+        #[derive(
+            EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+        )]
+        #[sea_orm(rs_type = "i32", db_type = "Integer")]
+        pub enum SpamAction {
+            /// only report it to the log channel
+            #[sea_orm(num_value = 1)]
+            Flag,
+            #[sea_orm(num_value = 2)]
+            Mute,
+            #[sea_orm(num_value = 3)]
+            Kick,
+            #[sea_orm(num_value = 4)]
+            Ban,
+        }
+
+        impl SpamAction {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    "flag" => Some(Self::Flag),
+                    "mute" => Some(Self::Mute),
+                    "kick" => Some(Self::Kick),
+                    "ban" => Some(Self::Ban),
+                    _ => None,
+                }
+            }
+
+            pub fn get_name(&self) -> &'static str {
+                match self {
+                    Self::Flag => "flag",
+                    Self::Mute => "mute",
+                    Self::Kick => "kick",
+                    Self::Ban => "ban",
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "antispam_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            pub action: SpamAction,
+            pub threshold: i32,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000050_create_antispam"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+/// Something a member did that can be scored
+pub enum SpamSignal<'a> {
+    /// the member just joined
+    Joined(&'a User),
+    /// the member sent a message shortly after joining
+    Message(&'a User, &'a Message),
+}
+
+/// Points given to a member and why, shown in the log channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpamScore {
+    pub points: i32,
+    pub reason: String,
+}
+
+impl SpamScore {
+    pub fn new<T: Into<String>>(points: i32, reason: T) -> Self {
+        Self {
+            points,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// A heuristic for spotting spam accounts. Every scorer sees every join and every message
+/// from new members in chats with antispam on, and the points from all of them add up
+/// to the member's score. Deployments can add their own with DijkstraOpts::spam_scorer
+#[async_trait]
+pub trait SpamScorer: Send + Sync {
+    /// Score a join or message, None if nothing about it looks like spam
+    async fn score(&self, chat: i64, signal: &SpamSignal<'_>) -> Result<Option<SpamScore>>;
+}
+
+static SCORERS: BotLocal<RwLock<Vec<Arc<dyn SpamScorer>>>> = BotLocal::new(|| {
+    RwLock::new(vec![
+        Arc::new(NameScorer),
+        Arc::new(BioScorer),
+        Arc::new(JoinFloodScorer),
+        Arc::new(LinkScorer),
+    ])
+});
+
+/// Add a scorer alongside the built in ones
+pub fn register_scorer<T: SpamScorer + 'static>(scorer: T) {
+    SCORERS.write().unwrap().push(Arc::new(scorer));
+}
+
+/// Does text look like it has a link or a telegram handle in it
+fn has_link(text: &str) -> bool {
+    let text = text.to_lowercase();
+    ["http://", "https://", "www.", "t.me/", "telegram.me/"]
+        .iter()
+        .any(|v| text.contains(v))
+}
+
+/// Count the emoji in text, going by the main emoji and symbol blocks
+fn count_emoji(text: &str) -> usize {
+    text.chars()
+        .filter(|c| matches!(*c as u32, 0x1F300..=0x1FAFF | 0x2600..=0x27BF))
+        .count()
+}
+
+/// Points for links or lots of emoji in a display name
+struct NameScorer;
+
+#[async_trait]
+impl SpamScorer for NameScorer {
+    async fn score(&self, _: i64, signal: &SpamSignal<'_>) -> Result<Option<SpamScore>> {
+        let SpamSignal::Joined(user) = signal else {
+            return Ok(None);
+        };
+        let mut name = user.get_first_name().to_owned();
+        if let Some(last) = user.get_last_name() {
+            name.push(' ');
+            name.push_str(last);
+        }
+        let res = if has_link(&name) {
+            Some(SpamScore::new(3, "link in name"))
+        } else if count_emoji(&name) >= 3 {
+            Some(SpamScore::new(1, "emoji in name"))
+        } else {
+            None
+        };
+        Ok(res)
+    }
+}
+
+/// Points for links in a bio
+struct BioScorer;
+
+#[async_trait]
+impl SpamScorer for BioScorer {
+    async fn score(&self, _: i64, signal: &SpamSignal<'_>) -> Result<Option<SpamScore>> {
+        let SpamSignal::Joined(user) = signal else {
+            return Ok(None);
+        };
+        let res = get_bio(user.get_id())
+            .await?
+            .filter(|v| has_link(v))
+            .map(|_| SpamScore::new(2, "link in bio"));
+        Ok(res)
+    }
+}
+
+/// Points for joining while many others are joining
+struct JoinFloodScorer;
+
+#[async_trait]
+impl SpamScorer for JoinFloodScorer {
+    async fn score(&self, chat: i64, signal: &SpamSignal<'_>) -> Result<Option<SpamScore>> {
+        let SpamSignal::Joined(_) = signal else {
+            return Ok(None);
+        };
+        let key = get_joins_key(chat);
+        let (joins,): (u64,) = REDIS
+            .pipe(|q| q.incr(&key, 1).expire(&key, JOIN_WINDOW).ignore())
+            .await?;
+        let res = (joins > CONFIG.moderation.antispam_flood_joins)
+            .then(|| SpamScore::new(2, "joined during a join flood"));
+        Ok(res)
+    }
+}
+
+/// Points for posting links right after joining
+struct LinkScorer;
+
+#[async_trait]
+impl SpamScorer for LinkScorer {
+    async fn score(&self, _: i64, signal: &SpamSignal<'_>) -> Result<Option<SpamScore>> {
+        let SpamSignal::Message(_, message) = signal else {
+            return Ok(None);
+        };
+        let links = message
+            .get_entities()
+            .into_iter()
+            .chain(message.get_caption_entities())
+            .flatten()
+            .any(|v| matches!(v.get_tg_type(), "url" | "text_link"));
+        Ok(links.then(|| SpamScore::new(3, "link soon after joining")))
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("asset:{}", chat)
+}
+
+#[inline(always)]
+fn get_score_key(chat: i64, user: i64) -> String {
+    format!("asscore:{}:{}", chat, user)
+}
+
+#[inline(always)]
+fn get_acted_key(chat: i64, user: i64) -> String {
+    format!("asacted:{}:{}", chat, user)
+}
+
+#[inline(always)]
+fn get_joins_key(chat: i64) -> String {
+    format!("asjoins:{}", chat)
+}
+
+async fn get_settings(chat: i64) -> Result<Option<antispam_settings::Model>> {
+    let key = get_settings_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = antispam_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+async fn set_settings(chat: i64, action: Option<(SpamAction, i32)>) -> Result<()> {
+    let key = get_settings_key(chat);
+    if let Some((action, threshold)) = action {
+        let model = antispam_settings::Entity::insert(antispam_settings::ActiveModel {
+            chat: Set(chat),
+            action: Set(action),
+            threshold: Set(threshold),
+        })
+        .on_conflict(
+            OnConflict::column(antispam_settings::Column::Chat)
+                .update_columns([
+                    antispam_settings::Column::Action,
+                    antispam_settings::Column::Threshold,
+                ])
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+        model.cache(key).await?;
+    } else {
+        antispam_settings::Entity::delete_by_id(chat)
+            .exec(*DB)
+            .await?;
+        REDIS.invalidate(&[&key]).await?;
+    }
+    Ok(())
+}
+
+/// Run every scorer on a signal, returning the member's new total score and the reasons
+/// for this signal's points
+async fn score(chat: i64, user: i64, signal: &SpamSignal<'_>) -> Result<(i64, Vec<String>)> {
+    let scorers = SCORERS.read().unwrap().clone();
+    let mut points = 0;
+    let mut reasons = Vec::new();
+    for scorer in scorers {
+        if let Some(score) = scorer.score(chat, signal).await? {
+            points += score.points as i64;
+            reasons.push(score.reason);
+        }
+    }
+    if points == 0 {
+        let key = get_score_key(chat, user);
+        let total: Option<i64> = REDIS.sq(|q| q.get(&key)).await?;
+        return Ok((total.unwrap_or(0), reasons));
+    }
+    let key = get_score_key(chat, user);
+    let (total,): (i64,) = REDIS
+        .pipe(|q| q.incr(&key, points).expire(&key, SCORE_TTL).ignore())
+        .await?;
+    Ok((total, reasons))
+}
+
+/// Act on a member whose score reached the threshold, once per day
+async fn act(
+    ctx: &Context,
+    settings: &antispam_settings::Model,
+    chat: &Chat,
+    user: &User,
+    total: i64,
+    reasons: &[String],
+) -> Result<()> {
+    let key = get_acted_key(chat.get_id(), user.get_id());
+    let (first,): (bool,) = REDIS
+        .pipe(|q| q.set_nx(&key, true).expire(&key, SCORE_TTL).ignore())
+        .await?;
+    if !first {
+        return Ok(());
+    }
+    match settings.action {
+        SpamAction::Flag => (),
+        SpamAction::Mute => ctx.mute(user.get_id(), chat, None).await?,
+        SpamAction::Kick => kick(user.get_id(), chat.get_id()).await?,
+        SpamAction::Ban => {
+            TG.client()
+                .build_ban_chat_member(chat.get_id(), user.get_id())
+                .build()
+                .await?;
+            publish(ModEvent::UserBanned {
+                chat: chat.get_id(),
+                user: user.get_id(),
+                until: None,
+            });
+        }
+    }
+    post_log(
+        chat,
+        lang_fmt!(
+            ctx,
+            "antispamlog",
+            user.name_humanreadable(),
+            user.get_id(),
+            total,
+            reasons.join(", ").escape(false),
+            settings.action.get_name()
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn check_join(ctx: &Context) -> Result<()> {
+    let Some(UserChanged::UserJoined(member)) = ctx.update().user_event() else {
+        return Ok(());
+    };
+    let chat = member.get_chat();
+    let user = member.get_new_chat_member().get_user();
+    let Some(settings) = get_settings(chat.get_id()).await? else {
+        return Ok(());
+    };
+    if user.get_is_bot() || get_exemption(chat, user.get_id()).await?.is_some() {
+        return Ok(());
+    }
+    let (total, reasons) = score(chat.get_id(), user.get_id(), &SpamSignal::Joined(user)).await?;
+    if total >= settings.threshold as i64 {
+        act(ctx, &settings, chat, user, total, &reasons).await?;
+    }
+    Ok(())
+}
+
+async fn check_message(ctx: &Context) -> Result<()> {
+    let Some(message) = ctx.moderated_message().await else {
+        return Ok(());
+    };
+    let chat = message.get_chat();
+    if is_dm(chat) || message.get_sender_chat().is_some() {
+        return Ok(());
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(settings) = get_settings(chat.get_id()).await? else {
+        return Ok(());
+    };
+    let Some(joined) = get_joined_at(user.get_id(), chat.get_id()).await? else {
+        return Ok(());
+    };
+    if (Utc::now() - joined).num_seconds() > NEW_MEMBER_SECONDS {
+        return Ok(());
+    }
+    let signal = SpamSignal::Message(user, message);
+    let (total, reasons) = score(chat.get_id(), user.get_id(), &signal).await?;
+    if !reasons.is_empty() && total >= settings.threshold as i64 {
+        if settings.action != SpamAction::Flag {
+            message.delete().await?;
+        }
+        act(ctx, &settings, chat, user, total, &reasons).await?;
+    }
+    Ok(())
+}
+
+async fn antispam_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let (action, threshold) = match args.args.as_slice() {
+        [] => {
+            let status = match get_settings(chat).await? {
+                Some(settings) => lang_fmt!(
+                    ctx,
+                    "antispamon",
+                    settings.action.get_name(),
+                    settings.threshold
+                ),
+                None => lang_fmt!(ctx, "antispamoff"),
+            };
+            ctx.reply(status).await?;
+            return Ok(());
+        }
+        [action] => (action.get_text(), None),
+        [action, threshold] => (action.get_text(), Some(threshold.get_text())),
+        _ => return ctx.fail(lang_fmt!(ctx, "antispamusage")),
+    };
+    if action == "off" {
+        set_settings(chat, None).await?;
+        ctx.reply(lang_fmt!(ctx, "antispamoff")).await?;
+        return Ok(());
+    }
+    let Some(action) = SpamAction::from_name(action) else {
+        return ctx.fail(lang_fmt!(ctx, "antispamusage"));
+    };
+    let threshold = match threshold {
+        Some(threshold) => match threshold.parse::<i32>() {
+            Ok(v) if v > 0 => v,
+            _ => return ctx.fail(lang_fmt!(ctx, "antispamusage")),
+        },
+        None => get_settings(chat)
+            .await?
+            .map(|v| v.threshold)
+            .unwrap_or(DEFAULT_THRESHOLD),
+    };
+    set_settings(chat, Some((action, threshold))).await?;
+    ctx.reply(lang_fmt!(ctx, "antispamon", action.get_name(), threshold))
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "antispam" {
+            antispam_cmd(ctx, args).await?;
+        }
+        return Ok(());
+    }
+    check_join(ctx).await?;
+    check_message(ctx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn name_patterns() {
+        assert!(has_link("Crypto signals t.me/somewhere"));
+        assert!(has_link("HTTPS://example.com"));
+        assert!(!has_link("Jane Doe"));
+        assert_eq!(count_emoji("Free 💰💰🚀 money"), 3);
+        assert_eq!(count_emoji("Jane Doe"), 0);
+    }
+}
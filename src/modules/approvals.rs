@@ -15,9 +15,11 @@ metadata!("Approvals",
     r#"
     Approvals are a tool to allow specific users to be ignored by automated admin actions
     "#,
-    { command = "approve", help = "Approves a user"},
-    { command = "unapprove", help = "Removals approval" },
-    { command = "listapprovals", help = "List all approvals for current chat"}
+    { category = "Moderation" },
+    { command = "approve", help = "Approves a user", level = Admin},
+    { command = "unapprove", help = "Removals approval", level = Admin },
+    { command = "listapprovals", help = "List all approvals for current chat", level = Admin},
+    { updates = [Message] }
 );
 
 async fn cmd_approve<'a>(ctx: &Context) -> Result<()> {
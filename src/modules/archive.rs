@@ -0,0 +1,693 @@
+use self::entities::{archive_settings, message_archive};
+use crate::metadata::ModuleHelpers;
+use crate::persist::core::mirrored_media::MirrorCategory;
+use crate::persist::mirror::{mirror_bytes, remove_created_before};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{ArchiveSinkType, BotLocal, CONFIG, DB, TASKS};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::optout::{is_opted_out, OptOut};
+use crate::tg::permissions::*;
+use crate::util::error::{BotError, Result};
+use crate::util::tasks::RestartPolicy;
+use crate::{metadata::metadata, util::string::Speak};
+use async_trait::async_trait;
+use botapi::gen_types::{Message, UpdateExt};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use macros::{lang_fmt, update_handler};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{ConnectionTrait, DbBackend, EntityTrait, Statement};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, Mutex};
+
+metadata!("Archive",
+    r#"
+    Opt-in archival of message metadata for compliance or analytics. By default only
+    metadata is stored (message type, length, and an anonymized sender), message text is
+    only stored if enabled separately. Archived messages are removed after the retention period
+    configured by the bot owner. Messages from users who opted out with /optout stats are
+    never archived.
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "archive", help = "Usage: archive \\<on/off\\>. Enables or disables message archival", level = Admin },
+    { command = "archivecontent", help = "Usage: archivecontent \\<on/off\\>. Include message text in the archive", level = Admin },
+    { updates = [Message] }
+);
+
+/// Maximum number of messages waiting to be written before new messages are dropped
+const ARCHIVE_QUEUE: usize = 10000;
+
+const ARCHIVE_TABLE: &str = "message_archive";
+
+pub mod entities {
+    use super::{Migration, ARCHIVE_TABLE};
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+    use sea_orm::ConnectionTrait;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(archive_settings::Entity)
+                        .col(
+                            ColumnDef::new(archive_settings::Column::Chat)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(archive_settings::Column::Enabled)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .col(
+                            ColumnDef::new(archive_settings::Column::Content)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            // sea-query can't express partitioned tables, partitions are created by month
+            // as messages are written
+            manager
+                .get_connection()
+                .execute_unprepared(&format!(
+                    "
+                    CREATE TABLE {table} (
+                        id BIGSERIAL NOT NULL,
+                        created TIMESTAMPTZ NOT NULL,
+                        chat BIGINT NOT NULL,
+                        message_id BIGINT NOT NULL,
+                        user_ref TEXT,
+                        kind TEXT NOT NULL,
+                        text_len INTEGER NOT NULL,
+                        content TEXT,
+                        PRIMARY KEY (id, created)
+                    ) PARTITION BY RANGE (created);
+                    CREATE INDEX {table}_chat_idx ON {table} (chat, created);
+                    ",
+                    table = ARCHIVE_TABLE
+                ))
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .get_connection()
+                .execute_unprepared(&format!("DROP TABLE IF EXISTS {} CASCADE;", ARCHIVE_TABLE))
+                .await?;
+            manager.drop_table_auto(archive_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod archive_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "archive_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub chat: i64,
+            pub enabled: bool,
+            pub content: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod message_archive {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "message_archive")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub created: DateTimeUtc,
+            pub chat: i64,
+            pub message_id: i64,
+            pub user_ref: Option<String>,
+            pub kind: String,
+            pub text_len: i32,
+            pub content: Option<String>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000001_create_archive"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+/// A single archived message as written to a sink
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchiveRecord {
+    pub created: DateTime<Utc>,
+    pub chat: i64,
+    pub message_id: i64,
+    pub user_ref: Option<String>,
+    pub kind: String,
+    pub text_len: i32,
+    pub content: Option<String>,
+}
+
+/// Destination for archived messages. Sinks receive messages in batches and are
+/// responsible for removing messages older than the retention period
+#[async_trait]
+pub trait ArchiveSink: Send + Sync {
+    /// write a batch of messages
+    async fn write(&self, records: Vec<ArchiveRecord>) -> Result<()>;
+
+    /// remove all messages created before the cutoff
+    async fn prune(&self, before: DateTime<Utc>) -> Result<()>;
+}
+
+/// Writes messages to a postgres table partitioned by month. Expired months are dropped
+/// as a whole
+struct PostgresSink;
+
+/// Writes messages to one jsonl file per day
+struct JsonlSink;
+
+/// Uploads each batch as jsonl objects, one per chat and day, to the mirror's object
+/// storage. Objects are tracked like other mirrored media
+struct S3Sink;
+
+/// Gets the partition name and bounds for the month containing a date
+fn partition_for(date: &DateTime<Utc>) -> (String, NaiveDate, NaiveDate) {
+    let start = NaiveDate::from_ymd_opt(date.year(), date.month(), 1).unwrap();
+    let end = if date.month() == 12 {
+        NaiveDate::from_ymd_opt(date.year() + 1, 1, 1).unwrap()
+    } else {
+        NaiveDate::from_ymd_opt(date.year(), date.month() + 1, 1).unwrap()
+    };
+    let name = format!("{}_p{:04}{:02}", ARCHIVE_TABLE, date.year(), date.month());
+    (name, start, end)
+}
+
+/// Parse the end of the range covered by a partition from its name
+fn partition_end(name: &str) -> Option<NaiveDate> {
+    let date = name.strip_prefix(ARCHIVE_TABLE)?.strip_prefix("_p")?;
+    let year = date.get(0..4)?.parse().ok()?;
+    let month = date.get(4..6)?.parse().ok()?;
+    let start = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let (_, _, end) = partition_for(&start.and_hms_opt(0, 0, 0)?.and_utc());
+    Some(end)
+}
+
+fn jsonl_name(date: &NaiveDate) -> String {
+    format!("archive-{}.jsonl", date.format("%Y-%m-%d"))
+}
+
+fn jsonl_date(name: &str) -> Option<NaiveDate> {
+    let date = name.strip_prefix("archive-")?.strip_suffix(".jsonl")?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+#[async_trait]
+impl ArchiveSink for PostgresSink {
+    async fn write(&self, records: Vec<ArchiveRecord>) -> Result<()> {
+        let mut partitions = records
+            .iter()
+            .map(|r| partition_for(&r.created))
+            .collect::<Vec<(String, NaiveDate, NaiveDate)>>();
+        partitions.sort_unstable();
+        partitions.dedup();
+        for (name, start, end) in partitions {
+            DB.execute_unprepared(&format!(
+                "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} FOR VALUES FROM ('{}') TO ('{}');",
+                name, ARCHIVE_TABLE, start, end
+            ))
+            .await?;
+        }
+
+        let models = records.into_iter().map(|r| message_archive::ActiveModel {
+            id: NotSet,
+            created: Set(r.created),
+            chat: Set(r.chat),
+            message_id: Set(r.message_id),
+            user_ref: Set(r.user_ref),
+            kind: Set(r.kind),
+            text_len: Set(r.text_len),
+            content: Set(r.content),
+        });
+        message_archive::Entity::insert_many(models)
+            .exec_without_returning(*DB)
+            .await?;
+        Ok(())
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> Result<()> {
+        let partitions = DB
+            .query_all(Statement::from_string(
+                DbBackend::Postgres,
+                format!(
+                    "
+                    SELECT c.relname AS name FROM pg_inherits i
+                    JOIN pg_class c ON i.inhrelid = c.oid
+                    JOIN pg_class p ON i.inhparent = p.oid
+                    WHERE p.relname = '{}';
+                    ",
+                    ARCHIVE_TABLE
+                ),
+            ))
+            .await?;
+        for partition in partitions {
+            let name: String = partition.try_get("", "name")?;
+            if let Some(end) = partition_end(&name) {
+                if end <= before.date_naive() {
+                    log::info!("dropping archive partition {}", name);
+                    DB.execute_unprepared(&format!("DROP TABLE IF EXISTS {};", name))
+                        .await?;
+                }
+            }
+        }
+        DB.execute(Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            format!("DELETE FROM {} WHERE created < $1;", ARCHIVE_TABLE),
+            [before.into()],
+        ))
+        .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArchiveSink for JsonlSink {
+    async fn write(&self, records: Vec<ArchiveRecord>) -> Result<()> {
+        let path = &CONFIG.archive.jsonl_path;
+        tokio::fs::create_dir_all(path).await?;
+        let mut days = HashMap::<NaiveDate, Vec<u8>>::new();
+        for record in records {
+            let buf = days.entry(record.created.date_naive()).or_default();
+            serde_json::to_writer(&mut *buf, &record)?;
+            buf.push(b'\n');
+        }
+
+        for (day, buf) in days {
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path.join(jsonl_name(&day)))
+                .await?;
+            file.write_all(&buf).await?;
+            file.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> Result<()> {
+        let path: &Path = &CONFIG.archive.jsonl_path;
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut dir = tokio::fs::read_dir(path).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let name = entry.file_name();
+            if let Some(date) = jsonl_date(&name.to_string_lossy()) {
+                if date < before.date_naive() {
+                    log::info!("removing archive file {:?}", name);
+                    tokio::fs::remove_file(entry.path()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArchiveSink for S3Sink {
+    async fn write(&self, records: Vec<ArchiveRecord>) -> Result<()> {
+        let mut objects = HashMap::<(i64, NaiveDate), Vec<u8>>::new();
+        for record in records {
+            let buf = objects
+                .entry((record.chat, record.created.date_naive()))
+                .or_default();
+            serde_json::to_writer(&mut *buf, &record)?;
+            buf.push(b'\n');
+        }
+
+        for ((chat, day), buf) in objects {
+            let key = mirror_bytes(
+                chat,
+                MirrorCategory::Archive,
+                &jsonl_name(&day),
+                buf.into(),
+                "application/x-ndjson",
+            )
+            .await?;
+            if key.is_none() {
+                return Err(BotError::Generic(
+                    "the s3 archive sink requires mirror to be enabled".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn prune(&self, before: DateTime<Utc>) -> Result<()> {
+        remove_created_before(MirrorCategory::Archive, before).await
+    }
+}
+
+fn get_sink() -> Box<dyn ArchiveSink> {
+    match CONFIG.archive.sink {
+        ArchiveSinkType::Postgres => Box::new(PostgresSink),
+        ArchiveSinkType::Jsonl => Box::new(JsonlSink),
+        ArchiveSinkType::S3 => Box::new(S3Sink),
+    }
+}
+
+async fn flush(sink: &dyn ArchiveSink, batch: &mut Vec<ArchiveRecord>) {
+    if batch.is_empty() {
+        return;
+    }
+    let records = std::mem::take(batch);
+    let len = records.len();
+    if let Err(err) = sink.write(records).await {
+        log::warn!("failed to archive {} messages: {}", len, err);
+        err.record_stats();
+    }
+}
+
+/// Background task receiving messages to archive, writing them in batches and
+/// periodically pruning expired messages
+async fn run_archiver(rx: Arc<Mutex<mpsc::Receiver<ArchiveRecord>>>) -> Result<()> {
+    // the receiver outlives a run of the task so a restarted archiver picks up the queue
+    let mut rx = rx.lock().await;
+    let sink = get_sink();
+    let batch_size = CONFIG.archive.batch_size.max(1);
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut flush_interval = tokio::time::interval(std::time::Duration::from_secs(10));
+    let mut prune_interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        tokio::select! {
+            record = rx.recv() => match record {
+                Some(record) => {
+                    batch.push(record);
+                    if batch.len() >= batch_size {
+                        flush(sink.as_ref(), &mut batch).await;
+                    }
+                }
+                None => break,
+            },
+            _ = flush_interval.tick() => flush(sink.as_ref(), &mut batch).await,
+            _ = prune_interval.tick() => {
+                let retention = Duration::try_days(CONFIG.archive.retention_days)
+                    .unwrap_or_else(|| Duration::try_days(30).unwrap());
+                if let Err(err) = sink.prune(Utc::now() - retention).await {
+                    log::warn!("failed to prune message archive: {}", err);
+                    err.record_stats();
+                }
+            }
+        }
+    }
+    flush(sink.as_ref(), &mut batch).await;
+    Ok(())
+}
+
+static ARCHIVER: BotLocal<mpsc::Sender<ArchiveRecord>> = BotLocal::new(|| {
+    let (tx, rx) = mpsc::channel(ARCHIVE_QUEUE);
+    let rx = Arc::new(Mutex::new(rx));
+    TASKS.register("archiver", RestartPolicy::on_failure(), move || {
+        run_archiver(Arc::clone(&rx))
+    });
+    tx
+});
+
+/// Replace a user id with a salted hash so archived messages from the same user can be
+/// correlated without storing the id
+fn anonymize(user: i64) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(CONFIG.archive.anonymize_salt.as_bytes());
+    hasher.update(user.to_le_bytes());
+    hex::encode(&hasher.finalize()[..16])
+}
+
+#[inline(always)]
+fn get_archive_key(chat: i64) -> String {
+    format!("archive:{}", chat)
+}
+
+async fn get_settings(chat: i64) -> Result<Option<archive_settings::Model>> {
+    let key = get_archive_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = archive_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+async fn set_settings(
+    chat: i64,
+    enabled: Option<bool>,
+    content: Option<bool>,
+) -> Result<archive_settings::Model> {
+    let model = archive_settings::ActiveModel {
+        chat: Set(chat),
+        enabled: enabled.map(Set).unwrap_or(NotSet),
+        content: content.map(Set).unwrap_or(NotSet),
+    };
+    let mut update = Vec::with_capacity(2);
+    if enabled.is_some() {
+        update.push(archive_settings::Column::Enabled);
+    }
+    if content.is_some() {
+        update.push(archive_settings::Column::Content);
+    }
+    let model = archive_settings::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(archive_settings::Column::Chat)
+                .update_columns(update)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+    model.cache(get_archive_key(chat)).await
+}
+
+/// Name the kind of content in a message. Kinds without a name, like service messages,
+/// are archived as "other"
+fn message_kind(message: &Message) -> &'static str {
+    if message.get_photo().is_some() {
+        "photo"
+    } else if message.get_sticker().is_some() {
+        "sticker"
+    } else if message.get_animation().is_some() {
+        // animations are also sent as documents, so check for them first
+        "animation"
+    } else if message.get_document().is_some() {
+        "document"
+    } else if message.get_video().is_some() {
+        "video"
+    } else if message.get_video_note().is_some() {
+        "video_note"
+    } else if message.get_audio().is_some() {
+        "audio"
+    } else if message.get_voice().is_some() {
+        "voice"
+    } else if message.get_poll().is_some() {
+        "poll"
+    } else if message.get_text().is_some() {
+        "text"
+    } else {
+        "other"
+    }
+}
+
+async fn archive_message(message: &Message) -> Result<()> {
+    let chat = message.get_chat().get_id();
+    if let Some(settings) = get_settings(chat).await? {
+        if !settings.enabled {
+            return Ok(());
+        }
+        if let Some(user) = message.get_from() {
+            if is_opted_out(user.get_id(), OptOut::Stats).await? {
+                return Ok(());
+            }
+        }
+        let text = message.get_text().or_else(|| message.get_caption());
+        let user_ref = message.get_from().map(|u| {
+            if CONFIG.archive.anonymize {
+                anonymize(u.get_id())
+            } else {
+                u.get_id().to_string()
+            }
+        });
+        let record = ArchiveRecord {
+            created: DateTime::from_timestamp(message.get_date(), 0).unwrap_or_else(Utc::now),
+            chat,
+            message_id: message.get_message_id(),
+            user_ref,
+            kind: message_kind(message).to_owned(),
+            text_len: text.map(|t| t.encode_utf16().count() as i32).unwrap_or(0),
+            content: if settings.content {
+                text.map(|t| t.to_owned())
+            } else {
+                None
+            },
+        };
+        if ARCHIVER.try_send(record).is_err() {
+            log::warn!("message archive queue full, dropping message");
+        }
+    }
+    Ok(())
+}
+
+fn parse_toggle(ctx: &Context, args: &TextArgs<'_>) -> Result<Option<bool>> {
+    match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => Ok(Some(true)),
+        Some("off") | Some("no") => Ok(Some(false)),
+        None => Ok(None),
+        Some(_) => Err(BotError::speak(
+            lang_fmt!(ctx, "invalidargument"),
+            ctx.message()?.get_chat().get_id(),
+            Some(ctx.message()?.message_id),
+        )),
+    }
+}
+
+async fn archive_cmd(ctx: &Context, args: &TextArgs<'_>, content: bool) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    if !CONFIG.archive.enabled {
+        ctx.reply(lang_fmt!(ctx, "archiveunavailable")).await?;
+        return Ok(());
+    }
+    let chat = ctx.message()?.get_chat().get_id();
+    match (parse_toggle(ctx, args)?, content) {
+        (Some(enabled), false) => {
+            set_settings(chat, Some(enabled), None).await?;
+            if enabled {
+                ctx.reply(lang_fmt!(ctx, "archiveon")).await?;
+            } else {
+                ctx.reply(lang_fmt!(ctx, "archiveoff")).await?;
+            }
+        }
+        (Some(enabled), true) => {
+            set_settings(chat, None, Some(enabled)).await?;
+            if enabled {
+                ctx.reply(lang_fmt!(ctx, "archivecontenton")).await?;
+            } else {
+                ctx.reply(lang_fmt!(ctx, "archivecontentoff")).await?;
+            }
+        }
+        (None, _) => {
+            let settings = get_settings(chat).await?;
+            let (enabled, content) = settings
+                .map(|s| (s.enabled, s.content))
+                .unwrap_or((false, false));
+            let fmt = |v: bool| if v { "on" } else { "off" };
+            ctx.reply(lang_fmt!(ctx, "archivestatus", fmt(enabled), fmt(content)))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_command(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "archive" => archive_cmd(ctx, args, false).await?,
+            "archivecontent" => archive_cmd(ctx, args, true).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    handle_command(ctx).await?;
+    if CONFIG.archive.enabled {
+        if let UpdateExt::Message(ref message) = ctx.update() {
+            archive_message(message).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn partition_bounds() {
+        let date = NaiveDate::from_ymd_opt(2024, 12, 17)
+            .unwrap()
+            .and_hms_opt(10, 0, 0)
+            .unwrap()
+            .and_utc();
+        let (name, start, end) = partition_for(&date);
+        assert_eq!(name, "message_archive_p202412");
+        assert_eq!(start, NaiveDate::from_ymd_opt(2024, 12, 1).unwrap());
+        assert_eq!(end, NaiveDate::from_ymd_opt(2025, 1, 1).unwrap());
+        assert_eq!(partition_end(&name), Some(end));
+        assert_eq!(partition_end("message_archive_chat_idx"), None);
+    }
+
+    #[test]
+    fn jsonl_names() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+        let name = jsonl_name(&date);
+        assert_eq!(name, "archive-2024-03-05.jsonl");
+        assert_eq!(jsonl_date(&name), Some(date));
+        assert_eq!(jsonl_date("notes.json"), None);
+    }
+}
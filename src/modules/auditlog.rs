@@ -0,0 +1,156 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use botapi::gen_types::{
+    EReplyMarkup, InlineKeyboardButtonBuilder, LinkPreviewOptionsBuilder, MaybeInaccessibleMessage,
+};
+use macros::{lang_fmt, update_handler};
+use uuid::Uuid;
+
+use crate::metadata::metadata;
+use crate::persist::audit::get_audit_page;
+use crate::statics::TG;
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{Cmd, Context};
+use crate::tg::markdown::EntityMessage;
+use crate::tg::permissions::{IsGroupAdmin, NamedBotPermissions};
+use crate::tg::user::GetUser;
+use crate::util::error::Result;
+use crate::util::locale::Localize;
+use crate::util::string::{get_chat_lang, Speak};
+
+metadata!("Audit Log",
+    r#"
+    Every admin command used in this chat is recorded along with who used it and who it
+    was used on. Entries are kept for a limited time set by the bot owner, even if the
+    messages or log channel are cleaned up.
+    "#,
+    { category = "Chat Management" },
+    { command = "auditlog", help = "Show recent admin commands used in this chat", level = Admin },
+    { updates = [Message] }
+);
+
+/// Render a single page of the audit log into a message
+async fn render_page(chat: i64, page: u64) -> Result<(EntityMessage, u64)> {
+    let (entries, pages) = get_audit_page(chat, page).await?;
+    let lang = get_chat_lang(chat).await?;
+    let mut message = EntityMessage::new(chat);
+    message
+        .builder
+        .bold(format!("Audit log {}/{}\n", page + 1, pages.max(1)));
+    for entry in entries {
+        message
+            .builder
+            .text(format!(
+                "{} /{} by ",
+                entry.created.localize(&lang),
+                entry.command
+            ))
+            .regular(entry.issuer.mention().await?);
+        if let Some(target) = entry.target {
+            message
+                .builder
+                .text(" on ")
+                .regular(target.mention().await?);
+        }
+        message.builder.text("\n");
+    }
+    Ok((message, pages))
+}
+
+async fn auditlog(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_manage_chat).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let (message, pages) = render_page(chat, 0).await?;
+    if pages <= 1 {
+        ctx.reply_fmt(message).await?;
+        return Ok(());
+    }
+
+    let lang = get_chat_lang(chat).await?;
+    let prev = InlineKeyboardButtonBuilder::new(lang_fmt!(lang, "auditprev"))
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+    let next = InlineKeyboardButtonBuilder::new(lang_fmt!(lang, "auditnext"))
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+
+    let mut buttons = InlineKeyboardBuilder::default();
+    buttons.button(prev.clone());
+    buttons.button(next.clone());
+    let markup = buttons.build();
+
+    let current = Arc::new(AtomicU64::new(0));
+    for (button, forward) in [(&prev, false), (&next, true)] {
+        let current = Arc::clone(&current);
+        let markup = markup.clone();
+        button.on_push_multi(move |callback| {
+            let current = Arc::clone(&current);
+            let markup = markup.clone();
+            async move {
+                let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message()
+                else {
+                    return Ok(true);
+                };
+                let permissions =
+                    NamedBotPermissions::from_chatuser(callback.get_from(), message.get_chat())
+                        .await?;
+                if !permissions.can_manage_chat.is_granted() && !permissions.is_sudo.is_granted() {
+                    TG.client()
+                        .build_answer_callback_query(callback.get_id())
+                        .text(&lang_fmt!(lang, "auditdenied"))
+                        .show_alert(true)
+                        .build()
+                        .await?;
+                    return Ok(false);
+                }
+
+                let page = current.load(Ordering::Relaxed);
+                let (_, pages) = get_audit_page(chat, page).await?;
+                let page = if forward {
+                    (page + 1).min(pages.saturating_sub(1))
+                } else {
+                    page.saturating_sub(1)
+                };
+                current.store(page, Ordering::Relaxed);
+
+                let (mut rendered, _) = render_page(chat, page).await?;
+                let (text, entities, _) = rendered.builder.build_murkdown_nofail_ref().await;
+                TG.client()
+                    .build_edit_message_text(text)
+                    .message_id(message.get_message_id())
+                    .chat_id(chat)
+                    .entities(entities)
+                    .reply_markup(&markup)
+                    .link_preview_options(
+                        &LinkPreviewOptionsBuilder::new()
+                            .set_is_disabled(true)
+                            .build(),
+                    )
+                    .build()
+                    .await?;
+                TG.client()
+                    .build_answer_callback_query(callback.get_id())
+                    .build()
+                    .await?;
+                Ok(false)
+            }
+        });
+    }
+
+    ctx.reply_fmt(message.reply_markup(EReplyMarkup::InlineKeyboardMarkup(markup)))
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+        if cmd == "auditlog" {
+            auditlog(ctx).await?;
+        }
+    }
+    Ok(())
+}
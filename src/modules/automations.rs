@@ -0,0 +1,830 @@
+use self::entities::automations::{self, Action, Actions, Condition, Conditions};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::core::media::SendMediaReply;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::{approve, parse_duration_str, UpdateHelpers, UserChanged};
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{Cmd, Context, PopSlice, TextArgs};
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
+use crate::tg::markdown::Escape;
+use crate::tg::notes::get_note_by_name;
+use crate::tg::permissions::{IsGroupAdmin, NamedBotPermissions};
+use crate::tg::quotas::{check_quota, Quota};
+use crate::util::error::{BotError, Fail, Result};
+use crate::util::string::{should_ignore_chat, Lang, Speak};
+use botapi::gen_types::{
+    CallbackQuery, EReplyMarkup, InlineKeyboardButtonBuilder, InlineKeyboardMarkup,
+    MaybeInaccessibleMessage, Message,
+};
+use chrono::{Duration, Timelike, Utc};
+use dashmap::DashMap;
+use futures::FutureExt;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use regex::{Regex, RegexBuilder};
+use sea_orm::ActiveValue::Set;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::OnConflict;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+metadata!("Automations",
+    r#"
+    Automate moderation with simple "if this, then that" rules, no code required. Each
+    automation has a name, one or more conditions, and one or more actions. When a message
+    meets every condition the actions are run.
+
+    [*Conditions:]\n
+    [*match] \<regex\>: the message text or caption matches a regular expression\n
+    [*new] \<time\>: the sender joined less than this long ago \(5m, 4h, 2d\)\n
+    [*between] \<HH:MM\>\-\<HH:MM\>: the message was sent in this window, in UTC
+
+    [*Actions:]\n
+    [*delete]: delete the message\n
+    [*warn] \[reason\]: warn the sender\n
+    [*mute]: mute the sender\n
+    [*ban]: ban the sender\n
+    [*note] \<name\>: reply with a note\n
+    [*approve]: approve the sender, exempting them from automated moderation
+
+    Separate conditions and actions with "and". Messages from admins and approved users are
+    never acted on. Joins are only tracked for chats with a [*new] condition, so members who
+    joined before it was saved don't count as new.
+
+    [*Example:]
+    /automate nolinks if new 1d and match https?:// then delete and warn no links yet
+    "#,
+    Helper,
+    { category = "Moderation" },
+    { command = "automate", help = "Usage: automate \\<name\\> \\<conditions\\> then \\<actions\\>. Saves an automation, replacing any with the same name", level = Admin },
+    { command = "rmautomation", help = "Usage: rmautomation \\<name\\>. Deletes an automation", level = Admin },
+    { command = "automations", help = "Lists the automations in this chat with buttons to pause or delete them", level = Admin },
+    { updates = [Message, EditedMessage, ChatMember] }
+);
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(automations::Entity)
+                        .col(
+                            ColumnDef::new(automations::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(automations::Column::Name).text().not_null())
+                        .col(
+                            ColumnDef::new(automations::Column::Enabled)
+                                .boolean()
+                                .not_null()
+                                .default(true),
+                        )
+                        .col(
+                            ColumnDef::new(automations::Column::Conditions)
+                                .json_binary()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(automations::Column::Actions)
+                                .json_binary()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(automations::Column::Chat)
+                                .col(automations::Column::Name)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(automations::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod automations {
+        use sea_orm::{entity::prelude::*, FromJsonQueryResult};
+        use serde::{Deserialize, Serialize};
+
+        /// Something a message has to meet for an automation to run
+        #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        pub enum Condition {
+            /// Regex matched against the text or caption
+            Match(String),
+            /// Sender joined less than this many seconds ago
+            New(i64),
+            /// Sent between these minutes past midnight UTC, wrapping around midnight
+            Between(u32, u32),
+        }
+
+        /// Something done to a message once every condition is met. Variants are in the order
+        /// actions are run, so replies go out before the message is deleted
+        #[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        pub enum Action {
+            Note(String),
+            Approve,
+            Warn(Option<String>),
+            Mute,
+            Ban,
+            Delete,
+        }
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromJsonQueryResult)]
+        pub struct Conditions(pub Vec<Condition>);
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromJsonQueryResult)]
+        pub struct Actions(pub Vec<Action>);
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "automations")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub name: String,
+            pub enabled: bool,
+            #[sea_orm(column_type = "JsonBinary")]
+            pub conditions: Conditions,
+            #[sea_orm(column_type = "JsonBinary")]
+            pub actions: Actions,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000033_create_automations"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// An automation as stored in backups, without the chat
+#[derive(Serialize, Deserialize)]
+struct ExportAutomation {
+    name: String,
+    enabled: bool,
+    conditions: Vec<Condition>,
+    actions: Vec<Action>,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let automations = get_automations(chat)
+            .await?
+            .into_iter()
+            .map(|v| ExportAutomation {
+                name: v.name,
+                enabled: v.enabled,
+                conditions: v.conditions.0,
+                actions: v.actions.0,
+            })
+            .collect::<Vec<ExportAutomation>>();
+        Ok(Some(serde_json::to_value(automations)?))
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let automations: Vec<ExportAutomation> = serde_json::from_value(value)?;
+        automations::Entity::delete_many()
+            .filter(automations::Column::Chat.eq(chat))
+            .exec(*DB)
+            .await?;
+        if !automations.is_empty() {
+            automations::Entity::insert_many(automations.into_iter().map(|v| {
+                automations::Model {
+                    chat,
+                    name: v.name,
+                    enabled: v.enabled,
+                    conditions: Conditions(v.conditions),
+                    actions: Actions(v.actions),
+                }
+                .into_active_model()
+            }))
+            .on_conflict(
+                OnConflict::columns([automations::Column::Chat, automations::Column::Name])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(*DB)
+            .await?;
+        }
+        let key = get_automations_key(chat);
+        REDIS.invalidate(&[&key]).await?;
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("automations")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+}
+
+lazy_static! {
+    static ref PATTERNS: DashMap<String, Regex> = DashMap::new();
+}
+
+/// Largest compiled size allowed for match conditions
+const MAX_PATTERN_SIZE: usize = 1 << 16;
+
+/// Longest name allowed for an automation
+const MAX_NAME_LEN: usize = 32;
+
+#[inline(always)]
+fn get_automations_key(chat: i64) -> String {
+    format!("autos:{}", chat)
+}
+
+#[inline(always)]
+fn get_join_key(chat: i64) -> String {
+    format!("autojoin:{}", chat)
+}
+
+/// Compile the regex for a match condition. Compiled regexes are kept for the life of the
+/// process so messages don't recompile them
+fn compile_pattern(pattern: &str) -> Result<Regex> {
+    if let Some(regex) = PATTERNS.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(MAX_PATTERN_SIZE)
+        .build()
+        .map_err(BotError::generic)?;
+    PATTERNS.insert(pattern.to_owned(), regex.clone());
+    Ok(regex)
+}
+
+/// Parse HH:MM into minutes past midnight
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.trim().split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+}
+
+/// Split the body of /automate into its conditions and actions
+fn split_rule(body: &str) -> Option<(Vec<&str>, Vec<&str>)> {
+    let body = body.trim();
+    let body = body.strip_prefix("if ").unwrap_or(body);
+    let (conditions, actions) = body.split_once(" then ")?;
+    let split = |v: &str| {
+        v.split(" and ")
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<&str>>()
+    };
+    let (conditions, actions) = (split(conditions), split(actions));
+    (!conditions.is_empty() && !actions.is_empty()).then_some((conditions, actions))
+}
+
+fn parse_condition(clause: &str, chat: i64, reply: i64) -> Result<Option<Condition>> {
+    let (kind, arg) = clause.split_once(' ').unwrap_or((clause, ""));
+    let arg = arg.trim();
+    let condition = match kind.to_lowercase().as_str() {
+        "match" if !arg.is_empty() => {
+            compile_pattern(arg)?;
+            Condition::Match(arg.to_owned())
+        }
+        "new" if !arg.is_empty() => match parse_duration_str(arg, chat, reply)? {
+            Some(time) => Condition::New(time.num_seconds()),
+            None => return Ok(None),
+        },
+        "between" => {
+            let Some((start, end)) = arg.split_once('-') else {
+                return Ok(None);
+            };
+            match (parse_time(start), parse_time(end)) {
+                (Some(start), Some(end)) if start != end => Condition::Between(start, end),
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(condition))
+}
+
+fn parse_action(clause: &str) -> Option<Action> {
+    let (kind, arg) = clause.split_once(' ').unwrap_or((clause, ""));
+    let arg = arg.trim();
+    let action = match kind.to_lowercase().as_str() {
+        "delete" | "del" => Action::Delete,
+        "warn" if arg.is_empty() => Action::Warn(None),
+        "warn" => Action::Warn(Some(arg.to_owned())),
+        "mute" => Action::Mute,
+        "ban" => Action::Ban,
+        "note" if !arg.is_empty() => Action::Note(arg.trim_start_matches('#').to_lowercase()),
+        "approve" => Action::Approve,
+        _ => return None,
+    };
+    Some(action)
+}
+
+impl Condition {
+    /// Rough cost of checking a condition, so cheap conditions can rule out a message
+    /// before expensive ones run
+    fn cost(&self) -> u8 {
+        match self {
+            Self::Between(_, _) => 0,
+            Self::Match(_) => 1,
+            Self::New(_) => 2,
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Match(pattern) => format!("match {}", pattern),
+            Self::New(seconds) => format!("new {}m", seconds / 60),
+            Self::Between(start, end) => format!(
+                "between {:02}:{:02}-{:02}:{:02}",
+                start / 60,
+                start % 60,
+                end / 60,
+                end % 60
+            ),
+        }
+    }
+}
+
+impl Action {
+    fn describe(&self) -> String {
+        match self {
+            Self::Note(name) => format!("note {}", name),
+            Self::Approve => "approve".to_owned(),
+            Self::Warn(Some(reason)) => format!("warn {}", reason),
+            Self::Warn(None) => "warn".to_owned(),
+            Self::Mute => "mute".to_owned(),
+            Self::Ban => "ban".to_owned(),
+            Self::Delete => "delete".to_owned(),
+        }
+    }
+}
+
+fn describe(automation: &automations::Model, lang: &Lang) -> String {
+    let rule = format!(
+        "- {}: if {} then {}",
+        automation.name,
+        automation
+            .conditions
+            .0
+            .iter()
+            .map(|v| v.describe())
+            .join(" and "),
+        automation
+            .actions
+            .0
+            .iter()
+            .map(|v| v.describe())
+            .join(" and ")
+    );
+    if automation.enabled {
+        rule
+    } else {
+        lang_fmt!(lang, "automationpaused", rule)
+    }
+}
+
+/// Get every automation saved in a chat
+async fn get_automations(chat: i64) -> Result<Vec<automations::Model>> {
+    let key = get_automations_key(chat);
+    let automations = default_cache_query(
+        |_, _| async move {
+            let res = automations::Entity::find()
+                .filter(automations::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?;
+            Ok(Some(res))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(automations.unwrap_or_default())
+}
+
+async fn automate(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let reply = message.get_message_id();
+    let usage = || ctx.fail_err(lang_fmt!(ctx, "automateusage"));
+    let (name, body) = args.pop_slice().ok_or_else(usage)?;
+    let name = name.get_text().to_lowercase();
+    if name.len() > MAX_NAME_LEN {
+        return Err(usage());
+    }
+    let (conditions, actions) = split_rule(body.text).ok_or_else(usage)?;
+    let mut conditions = conditions
+        .into_iter()
+        .map(|v| {
+            parse_condition(v, chat, reply)?
+                .ok_or_else(|| ctx.fail_err(lang_fmt!(ctx, "automationcondition", v.escape(false))))
+        })
+        .collect::<Result<Vec<Condition>>>()?;
+    conditions.sort_by_key(|v| v.cost());
+    let actions = actions
+        .into_iter()
+        .map(|v| {
+            parse_action(v)
+                .ok_or_else(|| ctx.fail_err(lang_fmt!(ctx, "automationaction", v.escape(false))))
+        })
+        .collect::<Result<Vec<Action>>>()?;
+
+    let existing = automations::Entity::find()
+        .filter(
+            automations::Column::Chat
+                .eq(chat)
+                .and(automations::Column::Name.ne(name.as_str())),
+        )
+        .count(*DB)
+        .await?;
+    check_quota(message, ctx.lang(), Quota::Automations, existing, 1)?;
+
+    let model = automations::Model {
+        chat,
+        name: name.clone(),
+        enabled: true,
+        conditions: Conditions(conditions),
+        actions: Actions(actions),
+    };
+    let description = describe(&model, ctx.lang());
+    automations::Entity::insert(model.into_active_model())
+        .on_conflict(
+            OnConflict::columns([automations::Column::Chat, automations::Column::Name])
+                .update_columns([
+                    automations::Column::Enabled,
+                    automations::Column::Conditions,
+                    automations::Column::Actions,
+                ])
+                .to_owned(),
+        )
+        .exec(*DB)
+        .await?;
+    let key = get_automations_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    ctx.reply(lang_fmt!(ctx, "automationsaved", description.escape(false)))
+        .await?;
+    Ok(())
+}
+
+async fn rm_automation(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let Some(name) = args.args.first().map(|v| v.get_text().to_lowercase()) else {
+        return ctx.fail(lang_fmt!(ctx, "rmautomationusage"));
+    };
+    let chat = ctx.message()?.get_chat().get_id();
+    if delete_automation(chat, &name).await? {
+        ctx.reply(lang_fmt!(ctx, "automationremoved", name.escape(false)))
+            .await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "automationnotfound", name.escape(false)))
+            .await?;
+    }
+    Ok(())
+}
+
+/// Delete an automation, returning false if there wasn't one with this name
+async fn delete_automation(chat: i64, name: &str) -> Result<bool> {
+    let res = automations::Entity::delete_by_id((chat, name.to_owned()))
+        .exec(*DB)
+        .await?;
+    let key = get_automations_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+/// Pause or resume an automation
+async fn toggle_automation(chat: i64, name: &str) -> Result<()> {
+    let Some(automation) = automations::Entity::find_by_id((chat, name.to_owned()))
+        .one(*DB)
+        .await?
+    else {
+        return Ok(());
+    };
+    let enabled = !automation.enabled;
+    let mut automation = automation.into_active_model();
+    automation.enabled = Set(enabled);
+    automations::Entity::update(automation).exec(*DB).await?;
+    let key = get_automations_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(())
+}
+
+/// Text of the settings menu, listing every automation
+fn menu_text(lang: &Lang, automations: &[automations::Model]) -> String {
+    if automations.is_empty() {
+        return lang_fmt!(lang, "noautomations");
+    }
+    [lang_fmt!(lang, "listautomations")]
+        .into_iter()
+        .chain(automations.iter().map(|v| describe(v, lang)))
+        .join("\n")
+}
+
+/// Build the settings menu with a pause/resume and a delete button for each automation.
+/// The whole menu is replaced each time a button is pressed
+fn menu_markup(lang: Lang, automations: &[automations::Model]) -> InlineKeyboardMarkup {
+    let mut markup = InlineKeyboardBuilder::default();
+    for automation in automations {
+        let toggle = if automation.enabled {
+            lang_fmt!(lang, "automationpause", automation.name)
+        } else {
+            lang_fmt!(lang, "automationresume", automation.name)
+        };
+        let toggle = InlineKeyboardButtonBuilder::new(toggle)
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        let delete = InlineKeyboardButtonBuilder::new(lang_fmt!(lang, "automationdelete"))
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        for (button, delete) in [(&toggle, false), (&delete, true)] {
+            let name = automation.name.clone();
+            button.on_push_multi(move |callback| menu_button(callback, name.clone(), delete, lang));
+        }
+        markup.button(toggle);
+        markup.button(delete);
+        markup.newline();
+    }
+    markup.build()
+}
+
+/// Handle a push on a settings menu button. Returns false to keep the button registered
+/// when pushed by someone without permission
+async fn menu_button(
+    callback: CallbackQuery,
+    name: String,
+    delete: bool,
+    lang: Lang,
+) -> Result<bool> {
+    let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() else {
+        return Ok(true);
+    };
+    let chat = message.get_chat();
+    let permissions = NamedBotPermissions::from_chatuser(callback.get_from(), chat).await?;
+    if !permissions.can_change_info.is_granted() && !permissions.is_sudo.is_granted() {
+        TG.client()
+            .build_answer_callback_query(callback.get_id())
+            .text(&lang_fmt!(lang, "automationdenied"))
+            .show_alert(true)
+            .build()
+            .await?;
+        return Ok(false);
+    }
+
+    if delete {
+        delete_automation(chat.get_id(), &name).await?;
+    } else {
+        toggle_automation(chat.get_id(), &name).await?;
+    }
+    let automations = get_automations(chat.get_id()).await?;
+    TG.client()
+        .build_edit_message_text(&menu_text(&lang, &automations))
+        .message_id(message.get_message_id())
+        .chat_id(chat.get_id())
+        .reply_markup(&menu_markup(lang, &automations))
+        .build()
+        .await?;
+    TG.client()
+        .build_answer_callback_query(callback.get_id())
+        .build()
+        .await?;
+    Ok(true)
+}
+
+async fn list_automations(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let lang = *ctx.lang();
+    let automations = get_automations(chat).await?;
+    if automations.is_empty() {
+        ctx.reply(lang_fmt!(lang, "noautomations")).await?;
+        return Ok(());
+    }
+    if !should_ignore_chat(chat).await? {
+        TG.client()
+            .build_send_message(chat, &menu_text(&lang, &automations))
+            .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(menu_markup(
+                lang,
+                &automations,
+            )))
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Check the conditions of an automation in order, stopping at the first one not met
+async fn conditions_met(automation: &automations::Model, message: &Message) -> Result<bool> {
+    let now = Utc::now();
+    for condition in automation.conditions.0.iter() {
+        let met = match condition {
+            Condition::Between(start, end) => {
+                let minute = now.num_seconds_from_midnight() / 60;
+                if start < end {
+                    *start <= minute && minute < *end
+                } else {
+                    minute >= *start || minute < *end
+                }
+            }
+            Condition::Match(pattern) => {
+                let text = message.get_text().or_else(|| message.get_caption());
+                match (text, compile_pattern(pattern)) {
+                    (Some(text), Ok(regex)) => regex.is_match(text),
+                    (_, Err(err)) => {
+                        log::warn!("automation {} has a bad pattern: {}", automation.name, err);
+                        false
+                    }
+                    (None, _) => false,
+                }
+            }
+            Condition::New(seconds) => match message.get_from() {
+                Some(user) => {
+                    let key = get_join_key(automation.chat);
+                    let joined: Option<i64> = REDIS.sq(|q| q.hget(&key, user.get_id())).await?;
+                    joined.is_some_and(|v| now.timestamp() - v < *seconds)
+                }
+                None => false,
+            },
+        };
+        if !met {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+async fn run_action(ctx: &Context, message: &Message, action: Action) -> Result<()> {
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let chat = message.get_chat();
+    match action {
+        Action::Note(name) => {
+            if let Some((note, entities, buttons)) = get_note_by_name(name, chat.get_id()).await? {
+                SendMediaReply::new(ctx, note.media_type)
+                    .button_callback(|_, _| async move { Ok(()) }.boxed())
+                    .text(note.text)
+                    .media_id(note.media_id)
+                    .extra_entities(entities)
+                    .buttons(buttons)
+                    .send_media_reply()
+                    .await?;
+            }
+        }
+        Action::Approve => {
+            approve(chat, user).await?;
+        }
+        Action::Warn(reason) => {
+            ctx.warn_with_action(user.get_id(), reason.as_deref(), None)
+                .await?;
+        }
+        Action::Mute => {
+            ctx.mute(user.get_id(), chat, None).await?;
+        }
+        Action::Ban => {
+            ctx.ban(user.get_id(), None, false).await?;
+        }
+        Action::Delete => {
+            message.delete().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Run the actions of every automation a message meets. Actions are merged so a message
+/// meeting several automations is only warned or deleted once, and run in a fixed order
+/// so replies are sent before the message is deleted
+async fn run_automations(ctx: &Context) -> Result<()> {
+    let Some(message) = ctx.moderated_message().await else {
+        return Ok(());
+    };
+    let automations = get_automations(message.get_chat().get_id()).await?;
+    let mut actions = Vec::new();
+    for automation in automations.iter().filter(|v| v.enabled) {
+        if conditions_met(automation, message).await? {
+            actions.extend(automation.actions.0.iter().cloned());
+        }
+    }
+    for action in actions.into_iter().sorted().dedup() {
+        run_action(ctx, message, action).await?;
+    }
+    Ok(())
+}
+
+/// Record when users join chats with automations for new members, keeping joins around
+/// for as long as the longest new condition
+async fn record_join(ctx: &Context) -> Result<()> {
+    let Some(UserChanged::UserJoined(member)) = ctx.user_event() else {
+        return Ok(());
+    };
+    let chat = member.get_chat().get_id();
+    let window = get_automations(chat)
+        .await?
+        .iter()
+        .filter(|v| v.enabled)
+        .flat_map(|v| v.conditions.0.iter())
+        .filter_map(|v| match v {
+            Condition::New(seconds) => Some(*seconds),
+            _ => None,
+        })
+        .max();
+    let Some(window) = window else {
+        return Ok(());
+    };
+    let key = get_join_key(chat);
+    let user = member.get_from().get_id();
+    REDIS
+        .pipe(|q| {
+            q.hset(&key, user, Utc::now().timestamp())
+                .ignore()
+                .expire(&key, window)
+                .ignore()
+        })
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "automate" => automate(ctx, args).await?,
+            "rmautomation" => rm_automation(ctx, args).await?,
+            "automations" => list_automations(ctx).await?,
+            _ => (),
+        };
+    }
+    run_automations(ctx).await?;
+    record_join(ctx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rules() {
+        let (conditions, actions) =
+            split_rule("if new 1d and match https?:// then delete and warn no links").unwrap();
+        assert_eq!(conditions, vec!["new 1d", "match https?://"]);
+        assert_eq!(actions, vec!["delete", "warn no links"]);
+        assert!(split_rule("match spam").is_none());
+        assert!(split_rule("match spam then ").is_none());
+
+        assert_eq!(
+            parse_condition("between 22:00-06:30", 0, 0).unwrap(),
+            Some(Condition::Between(22 * 60, 6 * 60 + 30))
+        );
+        assert_eq!(parse_condition("between 25:00-06:00", 0, 0).unwrap(), None);
+        assert_eq!(parse_condition("sometimes", 0, 0).unwrap(), None);
+        assert_eq!(
+            parse_action("warn no spam"),
+            Some(Action::Warn(Some("no spam".to_owned())))
+        );
+        assert_eq!(
+            parse_action("note #Rules"),
+            Some(Action::Note("rules".to_owned()))
+        );
+        assert_eq!(parse_action("note"), None);
+    }
+}
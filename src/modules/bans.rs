@@ -1,14 +1,18 @@
 use crate::{
     metadata::metadata,
+    persist::{
+        admin::actions::ActionType, core::mirrored_media::MirrorCategory, mirror::MirrorFile,
+    },
     statics::TG,
     tg::{
         admin_helpers::*,
-        command::{Cmd, Context},
+        command::{Cmd, Context, TextArgs},
         permissions::*,
         user::GetUser,
     },
     util::{
         error::{BotError, Result, SpeakErr},
+        locale::Localize,
         string::Speak,
     },
 };
@@ -32,13 +36,26 @@ metadata!("Bans",
 
     [_mutes a user forever]
     /mute @username
+
+    Admins can set a default time for mutes and bans with /setmutetime and /setbantime,
+    used whenever no time is given, including for warn limit actions.
+
+    /sban, /smute, and /skick act silently: nothing is announced, and the command along
+    with the user's recent messages is deleted.
     "#,
+    { category = "Moderation" },
     { command = "kickme", help = "Send a free course on termux hacking"},
-    { command = "mute", help = "Mute a user"},
-    { command = "unmute", help = "Unmute a user"},
-    { command = "ban", help = "Bans a user"},
-    { command = "unban", help = "Unbans a user"},
-    { command = "kick", help = "Kicks a user, they can join again"}
+    { command = "mute", help = "Mute a user", level = Admin},
+    { command = "unmute", help = "Unmute a user", level = Admin},
+    { command = "ban", help = "Bans a user", level = Admin},
+    { command = "unban", help = "Unbans a user", level = Admin},
+    { command = "kick", help = "Kicks a user, they can join again", level = Admin},
+    { command = "sban", help = "Silently bans a user, deleting their recent messages", level = Admin},
+    { command = "smute", help = "Silently mutes a user, deleting their recent messages", level = Admin},
+    { command = "skick", help = "Silently kicks a user, deleting their recent messages", level = Admin},
+    { command = "setmutetime", help = "Usage: setmutetime \\<time/clear\\>. Sets the default mute time", level = Admin},
+    { command = "setbantime", help = "Usage: setbantime \\<time/clear\\>. Sets the default ban time", level = Admin},
+    { updates = [Message] }
 );
 
 pub async fn unban_cmd(ctx: &Context) -> Result<()> {
@@ -62,7 +79,10 @@ pub async fn ban_cmd(ctx: &Context) -> Result<()> {
     ctx.check_permissions(|p| p.can_restrict_members).await?;
     let lang = ctx.try_get()?.lang;
     ctx.action_user(|ctx, user, args| async move {
-        let duration = ctx.parse_duration(&args)?;
+        let duration = match ctx.parse_duration(&args)? {
+            Some(duration) => Some(duration),
+            None => ctx.default_duration(ActionType::Ban).await?,
+        };
         ctx.ban(user, duration, true)
             .await
             .speak_err_code(ctx.message()?.get_chat(), 400, |_| {
@@ -70,6 +90,17 @@ pub async fn ban_cmd(ctx: &Context) -> Result<()> {
             })
             .await?;
 
+        let message = ctx.message()?;
+        if let Some(reply) = message.get_reply_to_message() {
+            if let Err(err) = reply
+                .mirror(message.get_chat().get_id(), MirrorCategory::Evidence)
+                .await
+            {
+                log::warn!("failed to mirror ban evidence: {}", err);
+                err.record_stats();
+            }
+        }
+
         Ok(())
     })
     .await
@@ -116,8 +147,9 @@ pub async fn mute_cmd<'a>(ctx: &Context) -> Result<()> {
         .set_can_send_other_messages(false)
         .build();
     let lang = ctx.try_get()?.lang;
+    let default = ctx.default_duration(ActionType::Mute).await?;
     let user = ctx
-        .change_permissions_message(permissions)
+        .change_permissions_message(permissions, default)
         .await
         .speak_err_code(ctx.message()?.get_chat(), 400, |_| {
             lang_fmt!(lang, "failmute")
@@ -156,7 +188,7 @@ pub async fn unmute_cmd<'a>(ctx: &Context) -> Result<()> {
 
     let lang = ctx.try_get()?.lang;
     let user = ctx
-        .change_permissions_message(permissions)
+        .change_permissions_message(permissions, None)
         .await
         .speak_err_code(message.get_chat(), 400, |_| lang_fmt!(lang, "failmute"))
         .await?;
@@ -172,6 +204,76 @@ pub async fn unmute_cmd<'a>(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+/// Actions of the silent commands
+#[derive(Clone, Copy)]
+enum SilentAction {
+    Ban,
+    Mute,
+    Kick,
+}
+
+/// Ban, mute, or kick a user without announcing it, deleting the command and the user's
+/// recent messages
+async fn silent_cmd(ctx: &Context, action: SilentAction) -> Result<()> {
+    ctx.check_permissions(|p| p.can_restrict_members.and(p.can_delete_messages))
+        .await?;
+    ctx.action_user(|ctx, user, args| async move {
+        match action {
+            SilentAction::Ban => {
+                let duration = match ctx.parse_duration(&args)? {
+                    Some(duration) => Some(duration),
+                    None => ctx.default_duration(ActionType::Ban).await?,
+                };
+                ctx.ban_silent(user, duration).await
+            }
+            SilentAction::Mute => {
+                let duration = match ctx.parse_duration(&args)? {
+                    Some(duration) => Some(duration),
+                    None => ctx.default_duration(ActionType::Mute).await?,
+                };
+                ctx.mute_silent(user, duration).await
+            }
+            SilentAction::Kick => ctx.kick_silent(user).await,
+        }
+    })
+    .await
+    .speak_err_raw(ctx, |v| match v {
+        BotError::UserNotFound => Some(lang_fmt!(ctx, "failuser", "remove")),
+        _ => None,
+    })
+    .await?;
+    Ok(())
+}
+
+/// Set or clear the default duration used for mutes or bans
+async fn set_default_time(ctx: &Context, args: &TextArgs<'_>, action: ActionType) -> Result<()> {
+    ctx.check_permissions(|p| p.can_restrict_members).await?;
+    let chat = ctx.message()?.get_chat();
+    let time = if args.text.trim() == "clear" {
+        None
+    } else if let Some(time) = ctx.parse_duration(&Some(args.as_slice()))? {
+        Some(time)
+    } else {
+        ctx.reply(lang_fmt!(ctx, "specifytime")).await?;
+        return Ok(());
+    };
+
+    let seconds = time.map(|v| v.num_seconds());
+    match action {
+        ActionType::Ban => set_ban_time(chat, seconds).await?,
+        _ => set_mute_time(chat, seconds).await?,
+    };
+
+    let text = match (action, time) {
+        (ActionType::Ban, Some(time)) => lang_fmt!(ctx, "bantimeset", time.localize(ctx.lang())),
+        (ActionType::Ban, None) => lang_fmt!(ctx, "bantimeclear"),
+        (_, Some(time)) => lang_fmt!(ctx, "mutetimeset", time.localize(ctx.lang())),
+        (_, None) => lang_fmt!(ctx, "mutetimeclear"),
+    };
+    ctx.reply(text).await?;
+    Ok(())
+}
+
 async fn kickme(ctx: &Context) -> Result<()> {
     ctx.is_group_or_die().await?;
     let message = ctx.message()?;
@@ -195,14 +297,19 @@ async fn kickme(ctx: &Context) -> Result<()> {
 }
 
 async fn handle_command<'a>(ctx: &Context) -> Result<()> {
-    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
         match cmd {
             "kickme" => kickme(ctx).await,
+            "setmutetime" => set_default_time(ctx, args, ActionType::Mute).await,
+            "setbantime" => set_default_time(ctx, args, ActionType::Ban).await,
             "mute" => mute_cmd(ctx).await,
             "unmute" => unmute_cmd(ctx).await,
             "ban" => ban_cmd(ctx).await,
             "unban" => unban_cmd(ctx).await,
             "kick" => kick_cmd(ctx).await,
+            "sban" => silent_cmd(ctx, SilentAction::Ban).await,
+            "smute" => silent_cmd(ctx, SilentAction::Mute).await,
+            "skick" => silent_cmd(ctx, SilentAction::Kick).await,
             _ => Ok(()),
         }?;
     }
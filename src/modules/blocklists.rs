@@ -1,8 +1,11 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
 
 use crate::metadata::ModuleHelpers;
 use crate::persist::admin::actions::ActionType;
 use crate::persist::admin::actions::FilterType;
+use crate::persist::core::media::MediaType;
+use crate::persist::core::media::SendMediaReply;
 use crate::persist::redis::default_cache_query;
 use crate::persist::redis::CachedQueryTrait;
 use crate::persist::redis::RedisCache;
@@ -11,22 +14,31 @@ use crate::persist::redis::ToRedisStr;
 use crate::statics::CONFIG;
 use crate::statics::DB;
 use crate::statics::REDIS;
+use crate::statics::TG;
 use crate::tg::admin_helpers::parse_duration_str;
+use crate::tg::admin_helpers::post_log;
 use crate::tg::admin_helpers::ActionMessage;
 use crate::tg::admin_helpers::DeleteAfterTime;
 use crate::tg::admin_helpers::UpdateHelpers;
+use crate::tg::admin_helpers::UserChanged;
 use crate::tg::command::Cmd;
 use crate::tg::command::Context;
 use crate::tg::command::PopSlice;
 use crate::tg::command::TextArgs;
+use crate::tg::events::{publish, ModEvent};
+use crate::tg::exemptions::get_exemption;
+use crate::tg::markdown::Escape;
 use crate::tg::markdown::Header;
 use crate::tg::markdown::MarkupBuilder;
 use crate::tg::markdown::MarkupType;
+use crate::tg::mdlint::KNOWN_FILLINGS;
+use crate::tg::ocr::image_text;
 use crate::tg::permissions::*;
+use crate::tg::quotas::{check_quota, Quota};
 
 use crate::tg::dialog::dialog_or_default;
 
-use crate::tg::user::GetUser;
+use crate::tg::user::{get_bio, GetUser, Username};
 use crate::util::error::BotError;
 use crate::util::error::Fail;
 use crate::util::error::Result;
@@ -35,15 +47,19 @@ use crate::metadata::metadata;
 
 use crate::util::error::SpeakErr;
 use crate::util::glob::WildMatch;
+use crate::util::locale::Localize;
 
 use crate::util::scripting::ModAction;
+use crate::util::string::Lang;
 use crate::util::string::Speak;
+use botapi::gen_types::Chat;
 use botapi::gen_types::Message;
 use botapi::gen_types::User;
 use chrono::Duration;
-use entities::{blocklists, triggers};
+use chrono::Utc;
+use dashmap::DashMap;
+use entities::{blocklists, profiles, triggers};
 use futures::FutureExt;
-use humantime::format_duration;
 use itertools::Itertools;
 use sea_orm::ModelTrait;
 
@@ -54,6 +70,7 @@ use macros::lang_fmt;
 use macros::update_handler;
 use redis::AsyncCommands;
 use regex::Regex;
+use regex::RegexBuilder;
 use rhai::Dynamic;
 use sea_orm::entity::ActiveValue;
 use sea_orm::sea_query::OnConflict;
@@ -61,14 +78,28 @@ use sea_orm::ActiveValue::{NotSet, Set};
 use sea_orm::ColumnTrait;
 use sea_orm::EntityTrait;
 use sea_orm::IntoActiveModel;
+use sea_orm::JoinType;
+use sea_orm::PaginatorTrait;
 use sea_orm::QueryFilter;
+use sea_orm::QuerySelect;
+use sea_orm::RelationTrait;
 use sea_orm::TransactionTrait;
 use sea_orm_migration::{MigrationName, MigrationTrait};
 use serde::{Deserialize, Serialize};
 
 metadata!("Blocklists",
-    r#"Censor specific words in your group!. Supports globbing to match partial words."#,
+    r#"Censor specific words in your group!. Supports globbing to match partial words. If the bot has image text recognition enabled, text inside photos is matched as well.
+
+    Triggers match with wildcards by default. Add \{exact\} to only match whole words or \{regex\} to match a regular expression. The action is set with \{delete\}, \{warn\}, \{mute\}, \{ban\}, or \{tmute 1h\}, \{tban 1h\}, \{twarn 1h\} for a limited time. Any other text is sent as a reply when the blocklist is triggered, and supports formatting and fillings like \{mention\}.
+
+    [*Example:]
+    /addblocklist "crypto\*" \[\*No spam\] here \{mention\}! \{mute\}
+    /addblocklist \(buy, sell\) \{exact\} \{warn\}
+    /addblocklist "t.me/.\*" \{regex\} \{ban\}
+
+    With /profileblocklist, names, usernames, and bios of users are matched against the blocklist too, when they join and again every so often while they chat. This catches accounts advertising in their name. Profiles have their own action, set with /profileblocklist ban or /profileblocklist tmute 1h."#,
     Helper,
+    { category = "Moderation" },
     { sub = "scripting", content = r#"
     Blocklists now have alpha-quality support for rhai scripting! Scripts allow
     taking arbitrary custom moderation action based on ANY value in telegram's updates.
@@ -120,16 +151,20 @@ metadata!("Blocklists",
     ]
         "#
     },
-    { command = "addblocklist", help = "\\<trigger\\> \\<reply\\> {action}: Add a blocklist" },
+    { command = "addblocklist", help = "\\<trigger\\> \\<reply\\> {mode} {action}: Add a blocklist", level = Admin },
     { command = "blocklist", help = "List all blocklists" },
-    { command = "rmblocklist", help = "Stop a blocklist by trigger" },
-    { command = "rmallblocklists", help = "Stop all blocklists" },
-    { command = "scriptblocklist", help = "Adds a rhai script as a blocklist with a provided name" },
-    { command = "rmscriptblocklist", help = "Moves a script blocklist by name"}
+    { command = "rmblocklist", help = "Stop a blocklist by trigger", level = Admin },
+    { command = "rmallblocklists", help = "Stop all blocklists", level = Admin },
+    { command = "scriptblocklist", help = "Adds a rhai script as a blocklist with a provided name", level = Admin },
+    { command = "rmscriptblocklist", help = "Moves a script blocklist by name", level = Admin},
+    { command = "profileblocklist", help = "Usage: profileblocklist \\<off/delete/warn/mute/ban/tmute/tban\\> \\[time\\]. Act on users whose name, username, or bio matches the blocklist", level = Admin, requires = [CanRestrict, CanChangeInfo] },
+    { updates = [Message, EditedMessage, ChatMember] }
 );
 
 struct Migration;
 struct MigrationScripting;
+struct MigrationReply;
+struct MigrationProfiles;
 
 impl MigrationName for Migration {
     fn name(&self) -> &str {
@@ -142,11 +177,24 @@ impl MigrationName for MigrationScripting {
         "m20240444_000001_create_scripting_blocklist"
     }
 }
+
+impl MigrationName for MigrationReply {
+    fn name(&self) -> &str {
+        "m20261015_000025_blocklist_reply"
+    }
+}
+
+impl MigrationName for MigrationProfiles {
+    fn name(&self) -> &str {
+        "m20261015_000041_blocklist_profiles"
+    }
+}
 #[derive(Serialize, Deserialize, Clone)]
 enum FilterConfig {
     Text,
     Glob,
     Script(String),
+    Regex,
 }
 
 impl FilterConfig {
@@ -155,6 +203,7 @@ impl FilterConfig {
             Self::Text => FilterType::Text,
             Self::Script(_) => FilterType::Script,
             Self::Glob => FilterType::Glob,
+            Self::Regex => FilterType::Regex,
         }
     }
     fn get_handle(self) -> Option<String> {
@@ -164,6 +213,24 @@ impl FilterConfig {
             None
         }
     }
+
+    fn from_trigger(filter_type: FilterType, handle: Option<String>) -> Self {
+        match (filter_type, handle) {
+            (FilterType::Text, _) => Self::Text,
+            (FilterType::Regex, _) => Self::Regex,
+            (FilterType::Script, Some(handle)) => Self::Script(handle),
+            _ => Self::Glob,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Text => "exact",
+            Self::Glob => "wildcard",
+            Self::Regex => "regex",
+            Self::Script(_) => "script",
+        }
+    }
 }
 
 pub mod entities {
@@ -229,6 +296,64 @@ pub mod entities {
         }
     }
 
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::MigrationReply {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .alter_table(
+                    TableAlterStatement::new()
+                        .table(blocklists::Entity)
+                        .add_column(ColumnDef::new(blocklists::Column::Reply).text().null())
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .alter_table(
+                    TableAlterStatement::new()
+                        .table(blocklists::Entity)
+                        .drop_column(blocklists::Column::Reply)
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::MigrationProfiles {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(profiles::Entity)
+                        .col(
+                            ColumnDef::new(profiles::Column::Chat)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(profiles::Column::Action)
+                                .integer()
+                                .not_null()
+                                .default(ActionType::Ban),
+                        )
+                        .col(ColumnDef::new(profiles::Column::Duration).big_integer())
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(profiles::Entity).await?;
+            Ok(())
+        }
+    }
+
     #[async_trait::async_trait]
     impl MigrationTrait for super::Migration {
         async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
@@ -353,6 +478,29 @@ pub mod entities {
         impl ActiveModelBehavior for ActiveModel {}
     }
 
+    /// Per chat action taken against users whose name, username, or bio matches the
+    /// blocklist. Chats without a row don't check profiles
+    pub mod profiles {
+        use crate::persist::admin::actions::ActionType;
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "blocklist_profiles")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            pub action: ActionType,
+            /// seconds a mute or ban lasts, forever if unset
+            pub duration: Option<i64>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
     pub mod blocklists {
 
         use crate::persist::admin::actions::ActionType;
@@ -371,6 +519,8 @@ pub mod entities {
             pub duration: Option<i64>,
             #[sea_orm(unique)]
             pub handle: Option<String>,
+            /// Murkdown sent when the blocklist is triggered, with fillings for the user
+            pub reply: Option<String>,
         }
 
         #[derive(Hash, Eq, PartialEq, Clone, DeriveIntoActiveModel, Debug)]
@@ -414,7 +564,12 @@ pub mod entities {
 }
 
 pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
-    vec![Box::new(Migration), Box::new(MigrationScripting)]
+    vec![
+        Box::new(Migration),
+        Box::new(MigrationScripting),
+        Box::new(MigrationReply),
+        Box::new(MigrationProfiles),
+    ]
 }
 
 #[derive(Serialize, Deserialize)]
@@ -541,6 +696,7 @@ impl ModuleHelpers for Helper {
                             reason: Set(v.reason),
                             duration: Set(v.duration),
                             handle: Set(v.handle),
+                            reply: Set(None),
                         }
                     }))
                     .on_empty_do_nothing()
@@ -585,8 +741,8 @@ impl ModuleHelpers for Helper {
     }
 }
 
-fn get_blocklist_key(message: &Message, id: i64) -> String {
-    format!("blockl:{}:{}", message.get_chat().get_id(), id)
+fn get_blocklist_key(chat: i64, id: i64) -> String {
+    format!("blockl:{}:{}", chat, id)
 }
 
 fn get_blocklist_hash_key(chat: i64) -> String {
@@ -668,7 +824,7 @@ async fn delete_trigger(ctx: &Context, trigger: String) -> Result<()> {
                 .query(|mut q| async move {
                     let id: Option<i64> = q.hdel(&hash_key, trigger).await?;
                     if let Some(id) = id {
-                        let key = get_blocklist_key(message, id);
+                        let key = get_blocklist_key(message.get_chat().get_id(), id);
                         q.del(&key).await?;
                         Ok(Some(id))
                     } else {
@@ -698,12 +854,76 @@ async fn get_blocklist(message: &Message, id: i64) -> Result<Option<blocklists::
         },
         Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
     )
-    .query(&get_blocklist_key(message, id), &())
+    .query(&get_blocklist_key(message.get_chat().get_id(), id), &())
     .await
 }
 
 lazy_static! {
     static ref WHITESPACE: Regex = Regex::new(r#"\s+|\S*"#).unwrap();
+    static ref PATTERNS: DashMap<String, Regex> = DashMap::new();
+}
+
+/// Largest compiled size allowed for regex triggers
+const MAX_PATTERN_SIZE: usize = 1 << 16;
+
+/// Fillings in /addblocklist that pick the action or how triggers match instead of being
+/// part of the reply
+const CONTROL_FILLINGS: [&str; 12] = [
+    "tmute", "tban", "twarn", "mute", "ban", "warn", "del", "delete", "exact", "wildcard", "glob",
+    "regex",
+];
+
+/// Compile a regex trigger, ignoring case like the other trigger types
+fn compile_pattern(pattern: &str) -> Result<Regex> {
+    if let Some(regex) = PATTERNS.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .size_limit(MAX_PATTERN_SIZE)
+        .build()
+        .map_err(BotError::generic)?;
+    PATTERNS.insert(pattern.to_owned(), regex.clone());
+    Ok(regex)
+}
+
+/// Returns true if key appears in text as whole words rather than inside another word
+fn contains_words(text: &str, key: &str) -> bool {
+    let is_word = |c: Option<char>| c.map(|c| c.is_alphanumeric()).unwrap_or(false);
+    text.match_indices(key).any(|(idx, _)| {
+        !is_word(text[..idx].chars().next_back())
+            && !is_word(text[idx + key.len()..].chars().next())
+    })
+}
+
+fn is_control_filling(filling: &str) -> bool {
+    filling
+        .split_whitespace()
+        .next()
+        .map(|v| CONTROL_FILLINGS.contains(&v))
+        .unwrap_or(false)
+}
+
+/// Get the murkdown for a blocklist's reply from the command text, without the triggers
+/// at the start or the fillings that set the action
+fn reply_source(text: &str) -> String {
+    let text = text.trim_start();
+    let rest = match text.chars().next() {
+        Some('(') => text.find(')').map(|v| &text[v + 1..]),
+        Some('"') => text[1..].find('"').map(|v| &text[v + 2..]),
+        _ => text.find(char::is_whitespace).map(|v| &text[v..]),
+    }
+    .unwrap_or("");
+    FILLER_REGEX
+        .replace_all(rest, |c: &regex::Captures| {
+            if is_control_filling(&c[1]) {
+                String::new()
+            } else {
+                c[0].to_owned()
+            }
+        })
+        .trim()
+        .to_owned()
 }
 
 async fn search_cache(
@@ -711,8 +931,11 @@ async fn search_cache(
     message: &Message,
     text: &str,
 ) -> Result<Option<blocklists::Model>> {
-    update_cache_from_db(message).await?;
+    update_cache_from_db(message.get_chat().get_id()).await?;
     let hash_key = get_blocklist_hash_key(message.get_chat().get_id());
+    // triggers other than regex and scripts are stored lowercase
+    let lower = text.to_lowercase();
+    let lower = lower.as_str();
     REDIS
         .query(|mut q| async move {
             let mut iter: redis::AsyncIter<(String, RedisStr)> = q.hscan(&hash_key).await?;
@@ -726,15 +949,22 @@ async fn search_cache(
                 match filtertype {
                     FilterConfig::Glob => {
                         let glob = WildMatch::new(&key);
-                        if glob.matches(text) {
+                        if glob.matches(lower) {
                             return get_blocklist(message, item).await;
                         }
                     }
                     FilterConfig::Text => {
-                        if text.contains(&key) {
+                        if contains_words(lower, &key) {
                             return get_blocklist(message, item).await;
                         }
                     }
+                    FilterConfig::Regex => match compile_pattern(&key) {
+                        Ok(regex) if regex.is_match(text) => {
+                            return get_blocklist(message, item).await;
+                        }
+                        Ok(_) => (),
+                        Err(err) => log::warn!("invalid regex blocklist {}: {}", key, err),
+                    },
                     FilterConfig::Script(_) => {
                         let res: Result<Dynamic> = ManagedRhai::new_mapper(
                             key,
@@ -816,12 +1046,12 @@ async fn search_cache(
         .await
 }
 
-async fn update_cache_from_db(message: &Message) -> Result<()> {
-    let hash_key = get_blocklist_hash_key(message.get_chat().get_id());
+async fn update_cache_from_db(chat: i64) -> Result<()> {
+    let hash_key = get_blocklist_hash_key(chat);
     let k: usize = REDIS.sq(|q| q.exists(&hash_key)).await?;
     if k == 0 {
         let res = blocklists::Entity::find()
-            .filter(blocklists::Column::Chat.eq(message.get_chat().get_id()))
+            .filter(blocklists::Column::Chat.eq(chat))
             .find_with_related(triggers::Entity)
             .all(*DB)
             .await?;
@@ -829,17 +1059,15 @@ async fn update_cache_from_db(message: &Message) -> Result<()> {
             .try_pipe(|p| {
                 p.hset(&hash_key, "", 0);
                 for (filter, triggers) in res.into_iter() {
-                    let key = get_blocklist_key(message, filter.id);
+                    let key = get_blocklist_key(chat, filter.id);
                     let filter_st = RedisStr::new(&filter)?;
                     p.set(&key, filter_st)
                         .expire(&key, CONFIG.timing.cache_timeout);
                     for trigger in triggers.into_iter() {
-                        p.hset(
-                            &hash_key,
-                            trigger.trigger,
-                            (filter.id, Some(filter.handle.as_ref())).to_redis()?,
-                        )
-                        .expire(&hash_key, CONFIG.timing.cache_timeout);
+                        let config =
+                            FilterConfig::from_trigger(trigger.filter_type, filter.handle.clone());
+                        p.hset(&hash_key, trigger.trigger, (filter.id, config).to_redis()?)
+                            .expire(&hash_key, CONFIG.timing.cache_timeout);
                     }
                 }
                 Ok(p)
@@ -849,25 +1077,46 @@ async fn update_cache_from_db(message: &Message) -> Result<()> {
     Ok(())
 }
 
+/// Number of triggers a chat has other than the given ones, for checking its quota
+async fn count_other_triggers(chat: i64, keep: &[String]) -> Result<u64> {
+    let count = blocklists::Entity::find()
+        .join(JoinType::InnerJoin, blocklists::Relation::Triggers.def())
+        .filter(
+            blocklists::Column::Chat
+                .eq(chat)
+                .and(triggers::Column::Trigger.is_not_in(keep)),
+        )
+        .count(*DB)
+        .await?;
+    Ok(count)
+}
+
 async fn insert_blocklist(
     message: &Message,
+    lang: &Lang,
     triggers: &[&str],
     action: ActionType,
     reason: Option<String>,
+    reply: Option<String>,
     duration: Option<Duration>,
     filter_type: FilterConfig,
 ) -> Result<()> {
     let ft = filter_type.get_type();
     let triggers = triggers
         .iter()
-        .map(|v| {
-            if let FilterConfig::Script(_) = filter_type {
-                (*v).to_owned()
-            } else {
-                v.to_lowercase()
-            }
+        .map(|v| match filter_type {
+            FilterConfig::Script(_) | FilterConfig::Regex => (*v).to_owned(),
+            _ => v.to_lowercase(),
         })
         .collect::<Vec<String>>();
+    let existing = count_other_triggers(message.get_chat().get_id(), &triggers).await?;
+    check_quota(
+        message,
+        lang,
+        Quota::Blocklists,
+        existing,
+        triggers.len() as u64,
+    )?;
 
     let model = blocklists::ActiveModel {
         id: ActiveValue::NotSet,
@@ -876,6 +1125,7 @@ async fn insert_blocklist(
         reason: ActiveValue::Set(reason),
         duration: ActiveValue::Set(duration.map(|v| v.num_seconds())),
         handle: ActiveValue::Set(filter_type.clone().get_handle()),
+        reply: ActiveValue::Set(reply),
     };
 
     let model = blocklists::Entity::insert(model);
@@ -893,7 +1143,7 @@ async fn insert_blocklist(
                 blocklists::Column::Action,
                 blocklists::Column::Reason,
             ])
-            .update_column(blocklists::Column::Duration)
+            .update_columns([blocklists::Column::Duration, blocklists::Column::Reply])
             .to_owned(),
         )
     }
@@ -931,7 +1181,9 @@ async fn insert_blocklist(
             p
         })
         .await?;
-    model.cache(get_blocklist_key(message, model_id)).await?;
+    model
+        .cache(get_blocklist_key(message.get_chat().get_id(), model_id))
+        .await?;
     Ok(())
 }
 
@@ -953,53 +1205,63 @@ async fn command_blocklist<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()>
     };
 
     let filters = filters.iter().map(|v| v.as_str()).collect::<Vec<&str>>();
-    let (action, duration) = if let Some(v) = footer.last() {
-        let mut args = v.split(' ');
+    let chat = message.get_chat().get_id();
+    let duration = |d: Option<&str>| {
+        d.and_then(|d| parse_duration_str(d, chat, message.message_id).ok())
+            .flatten()
+    };
+    let mut action = ActionType::Delete;
+    let mut time = None;
+    let mut filter_type = FilterConfig::Glob;
+    for filling in footer.iter() {
+        let mut args = filling.split_whitespace();
         match args.next() {
-            Some("tmute") => (
-                ActionType::Mute,
-                args.next().and_then(|d| {
-                    parse_duration_str(d, message.get_chat().get_id(), message.message_id).ok()
-                }),
-            ),
-
-            Some("tban") => (
-                ActionType::Ban,
-                args.next().and_then(|d| {
-                    parse_duration_str(d, message.get_chat().get_id(), message.message_id).ok()
-                }),
-            ),
-            Some("twarn") => (
-                ActionType::Warn,
-                args.next().and_then(|d| {
-                    parse_duration_str(d, message.get_chat().get_id(), message.message_id).ok()
-                }),
-            ),
-            None => (ActionType::Delete, None),
+            Some("tmute") => (action, time) = (ActionType::Mute, duration(args.next())),
+            Some("tban") => (action, time) = (ActionType::Ban, duration(args.next())),
+            Some("twarn") => (action, time) = (ActionType::Warn, duration(args.next())),
+            Some("mute") => action = ActionType::Mute,
+            Some("ban") => action = ActionType::Ban,
+            Some("warn") => action = ActionType::Warn,
+            Some("del") | Some("delete") => action = ActionType::Delete,
+            Some("exact") => filter_type = FilterConfig::Text,
+            Some("wildcard") | Some("glob") => filter_type = FilterConfig::Glob,
+            Some("regex") => filter_type = FilterConfig::Regex,
+            // other fillings are filled in when the reply is sent
+            Some(v) if KNOWN_FILLINGS.contains(&v) => (),
             _ => {
                 return Err(BotError::speak(
                     "Invalid action",
-                    message.get_chat().get_id(),
+                    chat,
                     Some(message.message_id),
                 ));
             }
         }
-    } else {
-        (ActionType::Delete, None)
-    };
+    }
+
+    if let FilterConfig::Regex = filter_type {
+        for filter in filters.iter() {
+            compile_pattern(filter)
+                .speak_err(ctx, |e| format!("Invalid regex {}: {}", filter, e))
+                .await?;
+        }
+    }
 
-    let (f, message) = if let Some(message) = message.get_reply_to_message() {
-        (message.get_text().map(|v| v.to_owned()), message)
+    let (f, reply, message) = if let Some(message) = message.get_reply_to_message() {
+        let text = message.get_text().map(|v| v.to_owned());
+        (text.clone(), text, message)
     } else {
-        (Some(body), message)
+        let reply = reply_source(args.text);
+        (Some(body), Some(reply).filter(|v| !v.is_empty()), message)
     };
     insert_blocklist(
         message,
+        ctx.lang(),
         filters.as_slice(),
         action,
         f,
-        duration.flatten(),
-        FilterConfig::Glob,
+        reply,
+        time,
+        filter_type,
     )
     .await?;
 
@@ -1066,10 +1328,12 @@ async fn script_blocklist<'a>(ctx: &Context) -> Result<()> {
 
             insert_blocklist(
                 message,
+                ctx.lang(),
                 &[text],
                 ActionType::Delete,
                 None,
                 None,
+                None,
                 FilterConfig::Script(name.get_text().to_owned()),
             )
             .await?;
@@ -1099,13 +1363,23 @@ async fn warn(ctx: &Context, user: &User, reason: Option<String>) -> Result<()>
 }
 
 async fn handle_trigger(ctx: &Context) -> Result<()> {
-    if let Some(message) = ctx.should_moderate().await {
+    if let Some(message) = ctx.moderated_message().await {
         if let Some(user) = message.get_from() {
-            if let Some(text) = message.get_text() {
-                if let Some(res) = search_cache(ctx, message, text).await? {
+            let text = match message.get_text() {
+                Some(text) => Some(Cow::Borrowed(text)),
+                None => match image_text(message).await {
+                    Ok(text) => text.map(Cow::Owned),
+                    Err(err) => {
+                        log::warn!("failed to read text in image: {}", err);
+                        None
+                    }
+                },
+            };
+            if let Some(text) = text {
+                if let Some(res) = search_cache(ctx, message, &text).await? {
                     let duration = res.duration.and_then(Duration::try_seconds);
                     let duration_str = if let Some(duration) = duration {
-                        lang_fmt!(ctx, "duration", format_duration(duration.to_std()?))
+                        lang_fmt!(ctx, "duration", duration.localize(ctx.lang()))
                     } else {
                         String::new()
                     };
@@ -1114,33 +1388,39 @@ async fn handle_trigger(ctx: &Context) -> Result<()> {
                         .as_ref()
                         .map(|v| lang_fmt!(ctx, "reason", v))
                         .unwrap_or_default();
+                    // a custom reply replaces the default notice
+                    let reply = res.reply.clone();
                     match res.action {
                         ActionType::Mute => {
                             ctx.mute(user.get_id(), ctx.try_get()?.chat, duration)
                                 .await?;
-                            let mention = user.mention().await?;
-                            message
-                                .reply_fmt(entity_fmt!(
-                                    ctx,
-                                    "blockmute",
-                                    mention,
-                                    duration_str,
-                                    reason_str
-                                ))
-                                .await?;
+                            if reply.is_none() {
+                                let mention = user.mention().await?;
+                                message
+                                    .reply_fmt(entity_fmt!(
+                                        ctx,
+                                        "blockmute",
+                                        mention,
+                                        duration_str,
+                                        reason_str
+                                    ))
+                                    .await?;
+                            }
                         }
                         ActionType::Ban => {
                             ctx.ban(user.get_id(), duration, true).await?;
-                            let mention = user.mention().await?;
-                            message
-                                .reply_fmt(entity_fmt!(
-                                    ctx,
-                                    "blockban",
-                                    mention,
-                                    duration_str,
-                                    reason_str
-                                ))
-                                .await?;
+                            if reply.is_none() {
+                                let mention = user.mention().await?;
+                                message
+                                    .reply_fmt(entity_fmt!(
+                                        ctx,
+                                        "blockban",
+                                        mention,
+                                        duration_str,
+                                        reason_str
+                                    ))
+                                    .await?;
+                            }
                         }
                         ActionType::Warn => {
                             warn(ctx, user, res.reason).await?;
@@ -1148,6 +1428,13 @@ async fn handle_trigger(ctx: &Context) -> Result<()> {
                         ActionType::Shame => (),
                         ActionType::Delete => (),
                     }
+                    if let Some(reply) = reply {
+                        SendMediaReply::new(ctx, MediaType::Text)
+                            .button_callback(|_, _| async move { Ok(()) }.boxed())
+                            .text(Some(reply))
+                            .send_media()
+                            .await?;
+                    }
                     message.delete().await?;
                 }
             }
@@ -1157,10 +1444,310 @@ async fn handle_trigger(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+fn get_profile_settings_key(chat: i64) -> String {
+    format!("bprofile:{}", chat)
+}
+
+fn get_profile_check_key(chat: i64, user: i64) -> String {
+    format!("bprofchk:{}:{}", chat, user)
+}
+
+fn profile_recheck_seconds() -> i64 {
+    CONFIG.moderation.profile_recheck_minutes.max(1) * 60
+}
+
+/// Get the action taken against users with blocklisted profiles, None if profiles aren't
+/// checked in this chat
+async fn get_profile_settings(chat: i64) -> Result<Option<profiles::Model>> {
+    default_cache_query(
+        |_, _| async move {
+            let res = profiles::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_profile_settings_key(chat), &())
+    .await
+}
+
+async fn set_profile_settings(
+    chat: i64,
+    action: Option<(ActionType, Option<Duration>)>,
+) -> Result<()> {
+    let key = get_profile_settings_key(chat);
+    if let Some((action, duration)) = action {
+        let model = profiles::Entity::insert(
+            profiles::Model {
+                chat,
+                action,
+                duration: duration.map(|v| v.num_seconds()),
+            }
+            .into_active_model(),
+        )
+        .on_conflict(
+            OnConflict::column(profiles::Column::Chat)
+                .update_columns([profiles::Column::Action, profiles::Column::Duration])
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+        model.cache(&key).await?;
+    } else {
+        profiles::Entity::delete_by_id(chat).exec(*DB).await?;
+        REDIS.invalidate(&[&key]).await?;
+    }
+    Ok(())
+}
+
+/// Display name and username of a user, compared to detect name changes
+fn profile_name(user: &User) -> String {
+    let mut name = user.get_first_name().to_owned();
+    if let Some(last) = user.get_last_name() {
+        name.push(' ');
+        name.push_str(last);
+    }
+    if let Some(username) = user.get_username() {
+        name.push_str(" @");
+        name.push_str(username);
+    }
+    name
+}
+
+/// Every part of a user's profile matched against the blocklist
+async fn profile_fields(user: &User) -> Result<Vec<String>> {
+    let mut fields = vec![user.get_first_name().to_owned()];
+    if let Some(last) = user.get_last_name() {
+        fields[0].push(' ');
+        fields[0].push_str(last);
+    }
+    if let Some(username) = user.get_username() {
+        fields.push(username.to_owned());
+    }
+    if let Some(bio) = get_bio(user.get_id()).await? {
+        fields.push(bio);
+    }
+    Ok(fields)
+}
+
+/// Find a trigger in a chat's blocklist matching any of the given profile fields. Script
+/// blocklists take a message, so they are skipped
+async fn search_profile(chat: i64, fields: &[String]) -> Result<Option<String>> {
+    update_cache_from_db(chat).await?;
+    let hash_key = get_blocklist_hash_key(chat);
+    let res: Option<HashMap<String, RedisStr>> = REDIS.sq(|q| q.hgetall(&hash_key)).await?;
+    let lower = fields.iter().map(|v| v.to_lowercase()).collect_vec();
+    for (key, rs) in res.iter().flatten() {
+        if key.is_empty() {
+            continue;
+        }
+        let Ok((_, filtertype)) = rs.get::<(i64, FilterConfig)>() else {
+            continue;
+        };
+        let matched = match filtertype {
+            FilterConfig::Glob => {
+                let glob = WildMatch::new(key);
+                lower.iter().any(|v| glob.matches(v))
+            }
+            FilterConfig::Text => lower.iter().any(|v| contains_words(v, key)),
+            FilterConfig::Regex => match compile_pattern(key) {
+                Ok(regex) => fields.iter().any(|v| regex.is_match(v)),
+                Err(err) => {
+                    log::warn!("invalid regex blocklist {}: {}", key, err);
+                    false
+                }
+            },
+            FilterConfig::Script(_) => false,
+        };
+        if matched {
+            return Ok(Some(key.clone()));
+        }
+    }
+    Ok(None)
+}
+
+fn profile_action_name(settings: &profiles::Model) -> &'static str {
+    match (&settings.action, settings.duration) {
+        (ActionType::Mute, Some(_)) => "tmute",
+        (ActionType::Mute, None) => "mute",
+        (ActionType::Ban, Some(_)) => "tban",
+        (ActionType::Ban, None) => "ban",
+        (ActionType::Warn, _) => "warn",
+        (ActionType::Shame, _) | (ActionType::Delete, _) => "delete",
+    }
+}
+
+/// Act on a user whose profile matched the blocklist. message is the message that caused
+/// the check, or None if the user just joined
+async fn profile_action(
+    ctx: &Context,
+    settings: &profiles::Model,
+    chat: &Chat,
+    user: &User,
+    message: Option<&Message>,
+    trigger: &str,
+) -> Result<()> {
+    let duration = settings.duration.and_then(Duration::try_seconds);
+    match settings.action {
+        ActionType::Mute => ctx.mute(user.get_id(), chat, duration).await?,
+        ActionType::Ban => {
+            let until = duration.and_then(|v| Utc::now().checked_add_signed(v));
+            if let Some(until) = until {
+                TG.client()
+                    .build_ban_chat_member(chat.get_id(), user.get_id())
+                    .until_date(until.timestamp())
+                    .build()
+                    .await?;
+            } else {
+                TG.client()
+                    .build_ban_chat_member(chat.get_id(), user.get_id())
+                    .build()
+                    .await?;
+            }
+            publish(ModEvent::UserBanned {
+                chat: chat.get_id(),
+                user: user.get_id(),
+                until,
+            });
+        }
+        ActionType::Warn => {
+            if message.is_some() {
+                let reason = lang_fmt!(ctx, "profileblockreason");
+                ctx.warn_with_action(user.get_id(), Some(&reason), None)
+                    .await?;
+            }
+        }
+        ActionType::Shame | ActionType::Delete => (),
+    }
+    if let Some(message) = message {
+        message.delete().await?;
+    }
+    post_log(
+        chat,
+        lang_fmt!(
+            ctx,
+            "profileblocklog",
+            user.name_humanreadable(),
+            user.get_id(),
+            trigger.escape(false),
+            profile_action_name(settings)
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Check the profile of a user who just joined. Warnings and deletions need a message, so
+/// with those actions the user is checked when they first speak instead
+async fn check_profile_join(ctx: &Context) -> Result<()> {
+    let Some(UserChanged::UserJoined(member)) = ctx.update().user_event() else {
+        return Ok(());
+    };
+    let chat = member.get_chat();
+    let user = member.get_new_chat_member().get_user();
+    let Some(settings) = get_profile_settings(chat.get_id()).await? else {
+        return Ok(());
+    };
+    if !matches!(settings.action, ActionType::Mute | ActionType::Ban) {
+        return Ok(());
+    }
+    if get_exemption(chat, user.get_id()).await?.is_some() {
+        return Ok(());
+    }
+    let fields = profile_fields(user).await?;
+    if let Some(trigger) = search_profile(chat.get_id(), &fields).await? {
+        profile_action(ctx, &settings, chat, user, None, &trigger).await?;
+    } else {
+        let key = get_profile_check_key(chat.get_id(), user.get_id());
+        let name = profile_name(user);
+        REDIS
+            .pipe(|q| {
+                q.set(&key, &name)
+                    .expire(&key, profile_recheck_seconds())
+                    .ignore()
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+/// Recheck the profile of a user sending a message if it changed or wasn't checked
+/// recently
+async fn check_profile_message(ctx: &Context) -> Result<()> {
+    let Some(message) = ctx.moderated_message().await else {
+        return Ok(());
+    };
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    if message.get_sender_chat().is_some() {
+        return Ok(());
+    }
+    let chat = message.get_chat();
+    let Some(settings) = get_profile_settings(chat.get_id()).await? else {
+        return Ok(());
+    };
+    let key = get_profile_check_key(chat.get_id(), user.get_id());
+    let name = profile_name(user);
+    let checked: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    if checked.as_deref() == Some(name.as_str()) {
+        return Ok(());
+    }
+    let fields = profile_fields(user).await?;
+    if let Some(trigger) = search_profile(chat.get_id(), &fields).await? {
+        // keep checking every message until the profile is cleaned up
+        REDIS.sq(|q| q.del(&key)).await?;
+        profile_action(ctx, &settings, chat, user, Some(message), &trigger).await?;
+    } else {
+        REDIS
+            .pipe(|q| {
+                q.set(&key, &name)
+                    .expire(&key, profile_recheck_seconds())
+                    .ignore()
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+async fn profile_blocklist(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let args = args.args.iter().map(|v| v.get_text()).collect_vec();
+    let Some(arg) = args.first() else {
+        let current = get_profile_settings(chat).await?;
+        let current = current.as_ref().map(profile_action_name).unwrap_or("off");
+        ctx.reply(lang_fmt!(ctx, "profileblockstatus", current))
+            .await?;
+        return Ok(());
+    };
+    let duration = match args.get(1) {
+        Some(time) => parse_duration_str(time, chat, message.get_message_id())?,
+        None => None,
+    };
+    let action = match (arg.to_lowercase().as_str(), duration) {
+        ("off" | "no", _) => None,
+        ("delete" | "del", _) => Some((ActionType::Delete, None)),
+        ("warn", _) => Some((ActionType::Warn, None)),
+        ("mute", _) => Some((ActionType::Mute, None)),
+        ("ban", _) => Some((ActionType::Ban, None)),
+        ("tmute", Some(duration)) => Some((ActionType::Mute, Some(duration))),
+        ("tban", Some(duration)) => Some((ActionType::Ban, Some(duration))),
+        _ => return ctx.fail(lang_fmt!(ctx, "profileblockinvalid")),
+    };
+    let enabled = action.is_some();
+    set_profile_settings(chat, action).await?;
+    if enabled {
+        ctx.reply(lang_fmt!(ctx, "profileblockon", arg)).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "profileblockoff")).await?;
+    }
+    Ok(())
+}
+
 async fn list_triggers(message: &Message) -> Result<()> {
     message.check_permissions(|p| p.can_manage_chat).await?;
     let hash_key = get_blocklist_hash_key(message.get_chat().get_id());
-    update_cache_from_db(message).await?;
+    update_cache_from_db(message.get_chat().get_id()).await?;
     let res: Option<HashMap<String, RedisStr>> = REDIS.sq(|q| q.hgetall(&hash_key)).await?;
     if let Some(map) = res {
         let iter = map
@@ -1183,12 +1770,10 @@ async fn list_triggers(message: &Message) -> Result<()> {
             .join("\n");
 
         let vals = iter
-            .filter_map(|(n, v)| {
-                if let FilterConfig::Glob = v.1 {
-                    Some(format!("\t- {}", n))
-                } else {
-                    None
-                }
+            .filter_map(|(n, v)| match v.1 {
+                FilterConfig::Glob => Some(format!("\t- {}", n)),
+                FilterConfig::Script(_) => None,
+                ref mode => Some(format!("\t- {} ({})", n, mode.name())),
             })
             .join("\n");
 
@@ -1211,7 +1796,7 @@ async fn delete_all(chat: i64) -> Result<()> {
         .await?;
 
     let key = get_blocklist_hash_key(chat);
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     Ok(())
 }
 
@@ -1237,11 +1822,14 @@ async fn handle_command<'a>(ctx: &Context) -> Result<()> {
             "rmscriptblocklist" => delete_script(ctx, args.text.to_owned()).await?,
             "blocklist" => list_triggers(message).await?,
             "rmallblocklists" => stopall(ctx, ctx.message()?.get_chat().get_id()).await?,
+            "profileblocklist" => profile_blocklist(ctx, args).await?,
             _ => handle_trigger(ctx).await?,
         };
     }
 
     handle_trigger(ctx).await?;
+    check_profile_message(ctx).await?;
+    check_profile_join(ctx).await?;
 
     Ok(())
 }
@@ -1255,7 +1843,7 @@ pub async fn handle_update<'a>(cmd: &Context) -> Result<()> {
 
 #[allow(unused_imports)]
 mod test {
-    use super::FILLER_REGEX;
+    use super::{contains_words, reply_source, FILLER_REGEX};
 
     #[test]
     fn regex_match() {
@@ -1263,4 +1851,22 @@ mod test {
         assert_eq!(m.next().map(|m| m.as_str()), Some("{filler}"));
         assert_eq!(m.next().map(|m| m.as_str()), Some("{filler2}"));
     }
+
+    #[test]
+    fn exact_words() {
+        assert!(contains_words("buy now", "buy"));
+        assert!(contains_words("i want to buy.", "buy"));
+        assert!(!contains_words("buyer", "buy"));
+        assert!(contains_words("buyer and buy", "buy"));
+    }
+
+    #[test]
+    fn reply_without_header() {
+        assert_eq!(
+            reply_source("crypto [*no] {mention} {tmute 1h}"),
+            "[*no] {mention}"
+        );
+        assert_eq!(reply_source("\"two words\" hi {regex}"), "hi");
+        assert_eq!(reply_source("(a, b) {ban}"), "");
+    }
 }
@@ -0,0 +1,74 @@
+use macros::{lang_fmt, update_handler};
+
+use crate::metadata::metadata;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::exemptions::{get_ignored_bots, set_ignored_bot};
+use crate::tg::permissions::IsGroupAdmin;
+use crate::util::error::{BotError, Result};
+use crate::util::string::Speak;
+
+metadata!("Bot Ignore",
+    r#"
+    Messages from other moderation bots are ignored by filters, blocklists, and locks so
+    bots don't end up fighting each other. The bot owner provides a list of well known
+    moderation bots, which can be changed for this chat.
+
+    [*Example:]
+    /ignorebot @somemoderationbot
+    "#,
+    { category = "Moderation" },
+    { command = "ignorebot", help = "\\<@bot\\>: Ignore messages from a bot in this chat", level = Admin },
+    { command = "unignorebot", help = "\\<@bot\\>: Stop ignoring messages from a bot in this chat", level = Admin },
+    { command = "ignoredbots", help = "List bots ignored in this chat" },
+    { updates = [Message] }
+);
+
+async fn set_ignored(ctx: &Context, args: &TextArgs<'_>, ignored: bool) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let Some(bot) = args.args.first().map(|v| v.get_text()) else {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "ignorebotinvalid"),
+            chat,
+            Some(message.get_message_id()),
+        ));
+    };
+    set_ignored_bot(chat, bot, ignored).await?;
+    let bot = bot.trim_start_matches('@');
+    if ignored {
+        ctx.reply(lang_fmt!(ctx, "ignorebot", bot)).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "unignorebot", bot)).await?;
+    }
+    Ok(())
+}
+
+async fn list_ignored(ctx: &Context) -> Result<()> {
+    let chat = ctx.message()?.get_chat().get_id();
+    let bots = get_ignored_bots(chat).await?;
+    if bots.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "ignoredbotsnone")).await?;
+    } else {
+        let bots = bots
+            .into_iter()
+            .map(|v| format!("\t- @{}", v))
+            .collect::<Vec<String>>()
+            .join("\n");
+        ctx.reply(lang_fmt!(ctx, "ignoredbots", bots)).await?;
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "ignorebot" => set_ignored(ctx, args, true).await?,
+            "unignorebot" => set_ignored(ctx, args, false).await?,
+            "ignoredbots" => list_ignored(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
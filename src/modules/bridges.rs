@@ -0,0 +1,108 @@
+use crate::metadata::metadata;
+use crate::tg::bridges::{
+    compile_pattern, delete_bridge, get_bridges, set_bridge, DEFAULT_PATTERN,
+};
+use crate::tg::command::{Cmd, Context, PopSlice, TextArgs};
+use crate::tg::permissions::*;
+use crate::tg::user::{get_user_username, GetUser, Username};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::User;
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Bridges",
+    r#"
+    Bridge bots relay messages from Matrix, Discord, or IRC into this chat, putting the real
+    author's name in front of each message. Register a bridge so warns and stats go to the
+    person who wrote a message instead of the bridge. Replying to a bridged message with /warn
+    warns its author. Bridged authors can't be muted or banned since they have no telegram account.
+
+    The pattern is a regex matching the start of relayed messages, with a group called name
+    for the author. Without a pattern, "\[name\] text" and "<name>: text" are recognized.
+    "#,
+    { category = "Content" },
+    { command = "bridge", help = "Usage: bridge \\<@bot\\> \\[pattern\\]. Treat bot as a bridge", level = Admin },
+    { command = "rmbridge", help = "Usage: rmbridge \\<@bot\\>. Stop treating bot as a bridge", level = Admin },
+    { command = "bridges", help = "List bridge bots in this chat", level = Admin },
+    { updates = [Message] }
+);
+
+async fn get_bot(ctx: &Context, username: &str) -> Result<User> {
+    match get_user_username(username.trim_start_matches('@')).await? {
+        Some(bot) if bot.get_is_bot() => Ok(bot),
+        _ => ctx.fail(lang_fmt!(ctx, "bridgenotbot", username)),
+    }
+}
+
+async fn bridge(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some((username, pattern)) = args.pop_slice() else {
+        return ctx.fail(lang_fmt!(ctx, "bridgeinvalid"));
+    };
+    let bot = get_bot(ctx, username.get_text()).await?;
+    let pattern = match pattern.text.trim() {
+        "" => DEFAULT_PATTERN,
+        pattern => pattern,
+    };
+    if compile_pattern(pattern).is_err() {
+        return ctx.fail(lang_fmt!(ctx, "bridgepattern"));
+    }
+    set_bridge(chat, bot.get_id(), pattern).await?;
+    ctx.reply(lang_fmt!(ctx, "bridgeset", bot.name_humanreadable()))
+        .await?;
+    Ok(())
+}
+
+async fn rmbridge(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(username) = args.args.first() else {
+        return ctx.fail(lang_fmt!(ctx, "bridgeinvalid"));
+    };
+    let bot = get_bot(ctx, username.get_text()).await?;
+    if delete_bridge(chat, bot.get_id()).await? {
+        ctx.reply(lang_fmt!(ctx, "bridgeremoved")).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "bridgemissing")).await?;
+    }
+    Ok(())
+}
+
+async fn bridges(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let bridges = get_bridges(chat).await?;
+    if bridges.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "bridgesnone")).await?;
+        return Ok(());
+    }
+    let mut list = Vec::with_capacity(bridges.len());
+    for bridge in bridges {
+        list.push(format!(
+            "{}: {}",
+            bridge.bot.cached_name().await?,
+            bridge.pattern
+        ));
+    }
+    ctx.reply(lang_fmt!(ctx, "bridges", list.iter().join("\n")))
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "bridge" => bridge(ctx, args).await?,
+            "rmbridge" => rmbridge(ctx, args).await?,
+            "bridges" => bridges(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -18,7 +18,9 @@ metadata!(
     r#"
     Nothing here yet
     "#,
-    Helper
+    Helper,
+    { category = "Misc" },
+    { updates = [Message] }
 );
 
 const BIG: &str = r#"
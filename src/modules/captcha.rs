@@ -4,12 +4,16 @@ use crate::persist::admin::captchastate::CaptchaType;
 use crate::persist::redis::RedisStr;
 use crate::statics::REDIS;
 
+use crate::tg::captcha_stats::{get_captcha_stats, get_global_captcha_stats};
 use crate::tg::command::{ArgSlice, Cmd, Context, TextArgs};
-use crate::tg::greetings::{get_callback_key, get_captcha_auth_key, send_captcha};
+use crate::tg::greetings::{
+    get_callback_key, get_captcha_auth_key, get_chat_captcha_config, send_captcha,
+};
 use crate::tg::permissions::*;
 use crate::tg::user::Username;
 use crate::util::error::Fail;
 use crate::util::error::Result;
+use crate::util::locale::Localize;
 use crate::util::string::Speak;
 use base64::engine::general_purpose;
 use base64::Engine;
@@ -21,11 +25,31 @@ use uuid::Uuid;
 
 metadata!("Captcha",
     r#"
-       Set a captcha in the group to keep bots out. Supports two security levels, text and button.
+       Set a captcha in the group to keep bots out. New members are muted until they solve it.
+       Supports three modes: button, where users just press a button, math, where users answer
+       an arithmetic question, and text, where users pick the text shown in an image. Math and
+       text captchas are solved in private with the bot.
+       If the chat approves new members through join requests, the captcha is sent to the user's
+       DM instead and the request is approved once it is solved, or declined if it isn't.
+       With /captchakick set, users who don't solve the captcha in time are kicked, or kept muted
+       if /captchaaction is set to mute.
+
+       [*Example:]
+       /captcha on
+       /captchamode math
+       /captchakick 5m
+       /captchaaction mute
+       /captchatext Prove you are human to start chatting
     "#,
-    { command = "captcha", help = "Enabled or disables captcha. Usage: /captcha \\<on/off\\>" },
-    { command = "captchamode", help = "Sets the captcha mode to either button or text"},
-    { command = "captchakick", help = "Sets the timeout for removing users who haven't solved the captcha. off to disable"}
+    { category = "Moderation" },
+    { command = "captcha", help = "Enabled or disables captcha. Usage: /captcha \\<on/off\\>", level = Admin },
+    { command = "captchamode", help = "Sets the captcha mode to button, math, or text", level = Admin},
+    { command = "captchakick", help = "Sets the timeout for removing users who haven't solved the captcha. off to disable", level = Admin},
+    { command = "captchaaction", help = "Sets whether users who time out are kicked or kept muted. Usage: /captchaaction \\<kick/mute\\>", level = Admin},
+    { command = "captchatext", help = "Sets the text sent to new members with the captcha. off for the default", level = Admin},
+    { command = "captchasettings", help = "Shows the captcha settings for this chat", level = Admin},
+    { command = "captchastats", help = "Shows how many captchas were solved, failed, or timed out", level = Admin},
+    { updates = [Message] }
 
 );
 
@@ -44,12 +68,12 @@ async fn captchakick_cmd<'a>(ctx: &Context, args: &'a TextArgs<'a>) -> Result<()
     match args.as_slice() {
         ArgSlice { text: "off", .. } => {
             ctx.captchakick(None).await?;
-            message.reply(lang_fmt!(ctx, "enablekick")).await?;
+            message.reply(lang_fmt!(ctx, "disablekick")).await?;
         }
         slice => {
             if let Some(time) = ctx.parse_duration(&Some(slice))? {
                 ctx.captchakick(Some(time.num_seconds())).await?;
-                message.reply(lang_fmt!(ctx, "disablekick")).await?;
+                message.reply(lang_fmt!(ctx, "enablekick")).await?;
             } else {
                 message.reply(lang_fmt!(ctx, "invalidargument")).await?;
             }
@@ -58,6 +82,59 @@ async fn captchakick_cmd<'a>(ctx: &Context, args: &'a TextArgs<'a>) -> Result<()
     Ok(())
 }
 
+async fn captchatext_cmd<'a>(ctx: &Context, args: &'a TextArgs<'a>) -> Result<()> {
+    match args.text.trim() {
+        "" => ctx.fail(lang_fmt!(ctx, "captchatextusage")),
+        "off" => ctx.captchatext(None).await,
+        text => ctx.captchatext(Some(text.to_owned())).await,
+    }
+}
+
+async fn captchasettings_cmd(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let Some(config) = get_chat_captcha_config(ctx.message()?.get_chat()).await? else {
+        return ctx.fail(lang_fmt!(ctx, "captchanotenabled"));
+    };
+    let timeout = config
+        .kick_time
+        .map(|v| std::time::Duration::from_secs(v.max(0) as u64).localize(ctx.lang()))
+        .unwrap_or_else(|| "off".to_owned());
+    let action = if config.timeout_mute { "mute" } else { "kick" };
+    let text = config
+        .captcha_text
+        .unwrap_or_else(|| lang_fmt!(ctx, "captchatextdefault"));
+    ctx.reply(lang_fmt!(
+        ctx,
+        "captchasettings",
+        config.captcha_type.get_name(),
+        timeout,
+        action,
+        text
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn captchastats_cmd(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = get_captcha_stats(ctx.message()?.get_chat().get_id()).await?;
+    let global = get_global_captcha_stats().await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "captchastats",
+        chat.presented,
+        chat.solved,
+        chat.failed,
+        chat.timeout,
+        global.presented,
+        global.solved,
+        global.failed,
+        global.timeout
+    ))
+    .await?;
+    Ok(())
+}
+
 async fn handle_command<'a>(ctx: &Context) -> Result<()> {
     if let Some(&Cmd {
         cmd,
@@ -70,6 +147,14 @@ async fn handle_command<'a>(ctx: &Context) -> Result<()> {
             "captchakick" => {
                 captchakick_cmd(ctx, args).await?;
             }
+            "captchastats" => captchastats_cmd(ctx).await?,
+            "captchasettings" => captchasettings_cmd(ctx).await?,
+            "captchatext" => captchatext_cmd(ctx, args).await?,
+            "captchaaction" => match args.args.first().map(|a| a.get_text()) {
+                Some("kick") => ctx.captchaaction(false).await?,
+                Some("mute") => ctx.captchaaction(true).await?,
+                _ => return ctx.fail(lang_fmt!(ctx, "captchaactionusage")),
+            },
             "captchamode" => {
                 let t = CaptchaType::from_str(
                     args.args.first().map(|a| a.get_text()).unwrap_or(""),
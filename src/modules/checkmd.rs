@@ -0,0 +1,59 @@
+use crate::metadata::metadata;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::markdown::Escape;
+use crate::tg::mdlint::lint;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Check Formatting",
+    r#"
+    Checks murkdown for mistakes before saving it as a note, filter, or welcome. Finds brackets
+    that are never closed, misspelled fillings, buttons that don't point to a url or #note, and
+    messages with more formatting than telegram allows. Problems are listed with the character
+    they start at.
+
+    Example: /checkmd Hi \{mention\}, see \<rules\>\(#rules\)
+    "#,
+    { category = "Content" },
+    { command = "checkmd", help = "Usage: checkmd \\<text\\>. Checks murkdown for mistakes. Reply to a message to check its text" },
+    { updates = [Message] }
+);
+
+async fn checkmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let message = ctx.message()?;
+    let text = message
+        .get_reply_to_message()
+        .and_then(|v| v.get_text().or_else(|| v.get_caption()))
+        .unwrap_or(args.text);
+    if text.trim().is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "checkmdinvalid"));
+    }
+    let lints = lint(text).await;
+    if lints.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "checkmdok")).await?;
+    } else {
+        let list = lints
+            .iter()
+            .map(|v| format!("- {}", v.to_string().escape(false)))
+            .join("\n");
+        ctx.reply(format!(
+            "{}\n{}",
+            lang_fmt!(ctx, "checkmdproblems", lints.len()),
+            list
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "checkmd" {
+            checkmd(ctx, args).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,416 @@
+use self::entities::clean_service;
+use crate::metadata::ModuleHelpers;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::set_response_ttl;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::permissions::*;
+use crate::util::error::{BotError, Result};
+use crate::util::locale::Localize;
+use crate::util::scheduler::{register_job, schedule};
+use crate::{metadata::metadata, util::string::Speak};
+use botapi::gen_types::{Message, UpdateExt};
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Clean Service",
+    r#"
+    Automatically delete telegram's service messages. Each kind can be turned on separately:
+    [*joins]: users joining or leaving
+    [*pins]: pinned message notices
+    [*videochats]: video chats starting, ending, or being scheduled
+    [*boosts]: users boosting the chat
+
+    Admin command responses, help messages, and errors can also be deleted after a delay
+    with /autodelete
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "cleanservice", help = "Usage: cleanservice \\<joins/pins/videochats/boosts/all\\> \\<on/off\\>. Without arguments shows which kinds are deleted", level = Admin },
+    { command = "autodelete", help = "Usage: autodelete \\<time/off\\>. Deletes bot responses after the given time", level = Admin },
+    { updates = [Message] }
+);
+
+/// Scheduler job kind for deleting queued service messages
+const CLEAN_JOB: &str = "cleanservice";
+
+/// Seconds service messages are queued before being deleted together, so a mass join
+/// doesn't turn into one delete call per user
+const BATCH_DELAY: i64 = 2;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(clean_service::Entity)
+                        .col(
+                            ColumnDef::new(clean_service::Column::Chat)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(clean_service::Column::Joins)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .col(
+                            ColumnDef::new(clean_service::Column::Pins)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .col(
+                            ColumnDef::new(clean_service::Column::VideoChats)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .col(
+                            ColumnDef::new(clean_service::Column::Boosts)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(clean_service::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod clean_service {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "clean_service")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(default = false)]
+            pub joins: bool,
+            #[sea_orm(default = false)]
+            pub pins: bool,
+            #[sea_orm(default = false)]
+            pub video_chats: bool,
+            #[sea_orm(default = false)]
+            pub boosts: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000014_create_clean_service"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(CLEAN_JOB, |payload| async move {
+            let chat: i64 = serde_json::from_value(payload)?;
+            flush_queue(chat).await
+        });
+    }
+}
+
+/// Kinds of service messages that can be deleted
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ServiceKind {
+    Joins,
+    Pins,
+    VideoChats,
+    Boosts,
+}
+
+impl ServiceKind {
+    fn from_name(kind: &str) -> Option<Self> {
+        match kind {
+            "joins" => Some(Self::Joins),
+            "pins" => Some(Self::Pins),
+            "videochats" => Some(Self::VideoChats),
+            "boosts" => Some(Self::Boosts),
+            _ => None,
+        }
+    }
+
+    /// Get the kind of service message, or None for regular messages
+    fn of(message: &Message) -> Option<Self> {
+        if message.get_new_chat_members().is_some() || message.get_left_chat_member().is_some() {
+            Some(Self::Joins)
+        } else if message.get_pinned_message().is_some() {
+            Some(Self::Pins)
+        } else if message.get_video_chat_started().is_some()
+            || message.get_video_chat_ended().is_some()
+            || message.get_video_chat_scheduled().is_some()
+            || message.get_video_chat_participants_invited().is_some()
+        {
+            Some(Self::VideoChats)
+        } else if message.get_boost_added().is_some() {
+            Some(Self::Boosts)
+        } else {
+            None
+        }
+    }
+
+    fn enabled(&self, settings: &clean_service::Model) -> bool {
+        match self {
+            Self::Joins => settings.joins,
+            Self::Pins => settings.pins,
+            Self::VideoChats => settings.video_chats,
+            Self::Boosts => settings.boosts,
+        }
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("csvc:{}", chat)
+}
+
+#[inline(always)]
+fn get_queue_key(chat: i64) -> String {
+    format!("csvcq:{}", chat)
+}
+
+#[inline(always)]
+fn get_pending_key(chat: i64) -> String {
+    format!("csvcp:{}", chat)
+}
+
+async fn get_settings(chat: i64) -> Result<Option<clean_service::Model>> {
+    let key = get_settings_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = clean_service::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+/// Turn deletion of one kind of service message on or off, None changes every kind
+async fn set_clean(chat: i64, kind: Option<ServiceKind>, enabled: bool) -> Result<()> {
+    let set = |k: ServiceKind| {
+        if kind.is_none() || kind == Some(k) {
+            Set(enabled)
+        } else {
+            NotSet
+        }
+    };
+    let model = clean_service::ActiveModel {
+        chat: Set(chat),
+        joins: set(ServiceKind::Joins),
+        pins: set(ServiceKind::Pins),
+        video_chats: set(ServiceKind::VideoChats),
+        boosts: set(ServiceKind::Boosts),
+    };
+    let columns = [
+        (ServiceKind::Joins, clean_service::Column::Joins),
+        (ServiceKind::Pins, clean_service::Column::Pins),
+        (ServiceKind::VideoChats, clean_service::Column::VideoChats),
+        (ServiceKind::Boosts, clean_service::Column::Boosts),
+    ]
+    .into_iter()
+    .filter(|(k, _)| kind.is_none() || kind == Some(*k))
+    .map(|(_, column)| column);
+    let model = clean_service::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(clean_service::Column::Chat)
+                .update_columns(columns)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+/// Delete every service message queued for a chat
+async fn flush_queue(chat: i64) -> Result<()> {
+    let key = get_queue_key(chat);
+    let (messages,): (Vec<i64>,) = REDIS
+        .pipe(|p| {
+            p.atomic();
+            p.lrange(&key, 0, -1).del(&key).ignore()
+        })
+        .await?;
+    TG.delete_messages_queued(chat, &messages, |_| async { Ok(()) })
+        .await
+}
+
+/// Queue a service message for deletion if the chat turned on cleaning for its kind
+async fn clean_service(ctx: &Context) -> Result<()> {
+    let UpdateExt::Message(ref message) = ctx.update() else {
+        return Ok(());
+    };
+    let Some(kind) = ServiceKind::of(message) else {
+        return Ok(());
+    };
+    let chat = message.get_chat().get_id();
+    if !get_settings(chat)
+        .await?
+        .map(|v| kind.enabled(&v))
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+    let (queue, pending) = (get_queue_key(chat), get_pending_key(chat));
+    let (first,): (bool,) = REDIS
+        .pipe(|q| {
+            q.rpush(&queue, message.get_message_id())
+                .ignore()
+                .set_nx(&pending, true)
+                .expire(&pending, BATCH_DELAY)
+                .ignore()
+        })
+        .await?;
+    if first {
+        let at = Utc::now() + Duration::try_seconds(BATCH_DELAY).unwrap();
+        schedule(CLEAN_JOB, &chat, at).await?;
+    }
+    Ok(())
+}
+
+async fn cleanservice_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_delete_messages.and(p.can_change_info))
+        .await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let args = args
+        .args
+        .iter()
+        .map(|v| v.get_text())
+        .collect::<Vec<&str>>();
+    if args.is_empty() {
+        let settings = get_settings(chat).await?;
+        let onoff = |kind: ServiceKind| {
+            if settings.as_ref().map(|v| kind.enabled(v)).unwrap_or(false) {
+                "on"
+            } else {
+                "off"
+            }
+        };
+        ctx.reply(lang_fmt!(
+            ctx,
+            "cleanservicestatus",
+            onoff(ServiceKind::Joins),
+            onoff(ServiceKind::Pins),
+            onoff(ServiceKind::VideoChats),
+            onoff(ServiceKind::Boosts)
+        ))
+        .await?;
+        return Ok(());
+    }
+    let kind = match args[0] {
+        "all" => None,
+        kind => Some(ServiceKind::from_name(kind)),
+    };
+    let enabled = match args.get(1) {
+        Some(&"on") | Some(&"yes") => Some(true),
+        Some(&"off") | Some(&"no") => Some(false),
+        _ => None,
+    };
+    match (kind, enabled) {
+        (Some(None), _) | (_, None) => Err(BotError::speak(
+            lang_fmt!(ctx, "cleanserviceinvalid"),
+            chat,
+            Some(message.get_message_id()),
+        )),
+        (kind, Some(enabled)) => {
+            set_clean(chat, kind.flatten(), enabled).await?;
+            let name = args[0];
+            if enabled {
+                ctx.reply(lang_fmt!(ctx, "cleanserviceon", name)).await?;
+            } else {
+                ctx.reply(lang_fmt!(ctx, "cleanserviceoff", name)).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn autodelete_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat();
+    let time = match args.text.trim() {
+        "off" | "clear" => None,
+        _ => match ctx.parse_duration(&Some(args.as_slice()))? {
+            Some(time) => Some(time),
+            None => {
+                ctx.reply(lang_fmt!(ctx, "specifytime")).await?;
+                return Ok(());
+            }
+        },
+    };
+    set_response_ttl(chat, time.map(|v| v.num_seconds())).await?;
+    let text = match time {
+        Some(time) => lang_fmt!(ctx, "autodeleteset", time.localize(ctx.lang())),
+        None => lang_fmt!(ctx, "autodeleteoff"),
+    };
+    ctx.reply(text).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "cleanservice" {
+            cleanservice_cmd(ctx, args).await?;
+        } else if cmd == "autodelete" {
+            autodelete_cmd(ctx, args).await?;
+        }
+    }
+    clean_service(ctx).await?;
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use crate::metadata::metadata;
+use crate::tg::admin_helpers::is_dm;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::connection::{connect, disconnect, get_connection};
+use crate::tg::user::{GetChat, Username};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Connections",
+    r#"
+    Manage a group from your private chat with the bot. After connecting, commands sent to me
+    in private are run in the connected group as if you sent them there, and my responses
+    are sent back to you in private. Only admins of a group can connect to it, and the
+    connection is dropped if you stop being an admin.
+
+    [*Example:]
+    /connect in the group, or /connect -1001234567890 in private
+    /disconnect
+    "#,
+    { category = "Chat Management" },
+    { command = "connect", help = "Usage: connect \\[chat id\\]. Connects your private chat to a group", level = Admin },
+    { command = "disconnect", help = "Disconnects your private chat from its group" },
+    { command = "connection", help = "Shows which group your private chat is connected to" },
+    { updates = [Message] }
+);
+
+async fn connect_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let message = ctx.message()?;
+    let user = ctx.get_real_from()?;
+    let chat = if is_dm(message.get_chat()) {
+        match args.args.first().map(|v| v.get_text().parse::<i64>()) {
+            Some(Ok(chat)) => chat,
+            _ => return ctx.fail(lang_fmt!(ctx, "connectusage")),
+        }
+    } else {
+        message.get_chat().get_id()
+    };
+    let group = connect(message, ctx.lang(), user, chat).await?;
+    let name = group.name_humanreadable();
+    if is_dm(message.get_chat()) {
+        ctx.reply(lang_fmt!(ctx, "connected", name)).await?;
+    } else {
+        // let the admin know where to continue
+        user.get_id()
+            .speak(lang_fmt!(ctx, "connected", name))
+            .await?;
+        ctx.reply(lang_fmt!(ctx, "connectedgroup")).await?;
+    }
+    Ok(())
+}
+
+async fn disconnect_cmd(ctx: &Context) -> Result<()> {
+    ctx.is_dm_or_die().await?;
+    let message = ctx.message()?;
+    match disconnect(message.get_chat()).await? {
+        Some(chat) => {
+            let name = chat
+                .get_chat()
+                .await?
+                .map(|v| v.name_humanreadable().into_owned())
+                .unwrap_or_else(|| chat.to_string());
+            ctx.reply(lang_fmt!(ctx, "disconnected", name)).await?;
+        }
+        None => {
+            ctx.reply(lang_fmt!(ctx, "notconnected")).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn connection_cmd(ctx: &Context) -> Result<()> {
+    ctx.is_dm_or_die().await?;
+    let message = ctx.message()?;
+    match get_connection(message.get_chat()).await? {
+        Some(chat) => {
+            let name = chat
+                .get_chat()
+                .await?
+                .map(|v| v.name_humanreadable().into_owned())
+                .unwrap_or_else(|| chat.to_string());
+            ctx.reply(lang_fmt!(ctx, "connection", name, chat)).await?;
+        }
+        None => {
+            ctx.reply(lang_fmt!(ctx, "notconnected")).await?;
+        }
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "connect" => connect_cmd(ctx, args).await?,
+            "disconnect" => disconnect_cmd(ctx).await?,
+            "connection" => connection_cmd(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
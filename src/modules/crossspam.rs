@@ -0,0 +1,528 @@
+use self::entities::cross_spam::{self, SpamAction};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::{is_dm, post_log, DeleteAfterTime};
+use crate::tg::bridges::is_bridged_id;
+use crate::tg::command::{is_command, Cmd, Context, TextArgs};
+use crate::tg::exemptions::get_message_exemption;
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
+use crate::tg::markdown::Escape;
+use crate::tg::permissions::*;
+use crate::tg::user::GetUser;
+use crate::util::error::{BotError, Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::{Message, UpdateExt};
+use chrono::{Duration, Utc};
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::{EntityTrait, IntoActiveModel};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::OnConflict;
+use serde::{Deserialize, Serialize};
+
+metadata!("Cross-chat Spam",
+    r#"
+    Catch spammers blasting the same message to many chats. The bot remembers a fingerprint
+    of recent messages from each user in every chat it is in, and when someone sends the
+    same or nearly the same message to several chats within a few minutes, chats with
+    detection on act on it. Slightly reworded copies still count.
+
+    Only fingerprints are kept, never the message text, and they are forgotten after a few
+    minutes. Short messages are ignored, and admins and approved users are never acted on.
+    The first time a user is caught, their earlier copies are also removed from other chats
+    that delete spam. Every catch is reported to the log channel.
+
+    [*Actions:]\n
+    [*flag]: only report it to the log channel\n
+    [*delete]: delete the message\n
+    [*warn]: delete the message and warn the sender\n
+    [*mute]: delete the message and mute the sender\n
+    [*ban]: delete the message and ban the sender
+
+    [*Example:]
+    /crossspam delete
+    "#,
+    Helper,
+    { category = "Moderation" },
+    { command = "crossspam", help = "Usage: crossspam \\[off/flag/delete/warn/mute/ban\\]. Shows or sets what happens to cross-chat spam", level = Admin },
+    { updates = [Message] }
+);
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(cross_spam::Entity)
+                        .col(
+                            ColumnDef::new(cross_spam::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(cross_spam::Column::Action)
+                                .integer()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(cross_spam::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod cross_spam {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// What happens to a message once its sender is caught sending it to several chats
+        #[derive(
+            EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+        )]
+        #[sea_orm(rs_type = "i32", db_type = "Integer")]
+        #[serde(rename_all = "snake_case")]
+        pub enum SpamAction {
+            /// only report it to the log channel
+            #[sea_orm(num_value = 1)]
+            Flag,
+            #[sea_orm(num_value = 2)]
+            Delete,
+            #[sea_orm(num_value = 3)]
+            Warn,
+            #[sea_orm(num_value = 4)]
+            Mute,
+            #[sea_orm(num_value = 5)]
+            Ban,
+        }
+
+        impl SpamAction {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    "flag" => Some(Self::Flag),
+                    "delete" => Some(Self::Delete),
+                    "warn" => Some(Self::Warn),
+                    "mute" => Some(Self::Mute),
+                    "ban" => Some(Self::Ban),
+                    _ => None,
+                }
+            }
+
+            pub fn get_name(&self) -> &'static str {
+                match self {
+                    Self::Flag => "flag",
+                    Self::Delete => "delete",
+                    Self::Warn => "warn",
+                    Self::Mute => "mute",
+                    Self::Ban => "ban",
+                }
+            }
+        }
+
+        /// Cross-chat spam settings for a chat. Detection is on in every chat with a row
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "cross_spam")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            pub action: SpamAction,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000038_create_cross_spam"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// Cross-chat spam settings as stored in backups, without the chat
+#[derive(Serialize, Deserialize)]
+struct ExportCrossSpam {
+    action: SpamAction,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        match get_settings(chat).await? {
+            Some(settings) => Ok(Some(serde_json::to_value(ExportCrossSpam {
+                action: settings.action,
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let settings: ExportCrossSpam = serde_json::from_value(value)?;
+        set_settings(chat, Some(settings.action)).await?;
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("crossspam")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+}
+
+/// Messages with fewer words than this are too generic to fingerprint
+const MIN_WORDS: usize = 6;
+
+/// Most bits two fingerprints can differ by and still count as the same message
+const MAX_DISTANCE: u32 = 8;
+
+/// Most recent messages remembered per user
+const MAX_SEEN: isize = 50;
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("xspamcfg:{}", chat)
+}
+
+#[inline(always)]
+fn get_seen_key(user: i64) -> String {
+    format!("xspam:{}", user)
+}
+
+#[inline(always)]
+fn get_caught_key(user: i64) -> String {
+    format!("xspamcaught:{}", user)
+}
+
+/// 64 bit FNV-1a, used instead of the std hasher so fingerprints stay the same between
+/// builds and across sharded workers
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Fingerprint a message so near-identical messages get fingerprints differing by only a
+/// few bits. Returns None for messages too short to fingerprint reliably
+fn simhash(text: &str) -> Option<u64> {
+    let text = text.to_lowercase();
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<&str>>();
+    if words.len() < MIN_WORDS {
+        return None;
+    }
+    let mut weights = [0i32; 64];
+    for word in words {
+        let hash = fnv1a(word.as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash & (1u64 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |hash, (bit, _)| hash | (1u64 << bit)),
+    )
+}
+
+fn is_near_duplicate(a: u64, b: u64) -> bool {
+    (a ^ b).count_ones() <= MAX_DISTANCE
+}
+
+/// A recent message from a user, as remembered in redis
+#[derive(Debug, PartialEq)]
+struct Seen {
+    hash: u64,
+    chat: i64,
+    message: i64,
+}
+
+impl Seen {
+    fn encode(&self) -> String {
+        format!("{:016x}:{}:{}", self.hash, self.chat, self.message)
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(3, ':');
+        Some(Self {
+            hash: u64::from_str_radix(parts.next()?, 16).ok()?,
+            chat: parts.next()?.parse().ok()?,
+            message: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+async fn get_settings(chat: i64) -> Result<Option<cross_spam::Model>> {
+    default_cache_query(
+        |_, _| async move {
+            let res = cross_spam::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_settings_key(chat), &())
+    .await
+}
+
+/// Turn detection on with an action, or off with None
+async fn set_settings(chat: i64, action: Option<SpamAction>) -> Result<()> {
+    let key = get_settings_key(chat);
+    match action {
+        Some(action) => {
+            let model =
+                cross_spam::Entity::insert(cross_spam::Model { chat, action }.into_active_model())
+                    .on_conflict(
+                        OnConflict::column(cross_spam::Column::Chat)
+                            .update_column(cross_spam::Column::Action)
+                            .to_owned(),
+                    )
+                    .exec_with_returning(*DB)
+                    .await?;
+            model.cache(key).await?;
+        }
+        None => {
+            cross_spam::Entity::delete_by_id(chat).exec(*DB).await?;
+            REDIS.invalidate(&[&key]).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn crossspam(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    match args.args.first().map(|v| v.get_text().to_lowercase()) {
+        None => (),
+        Some(v) if v == "off" || v == "no" => set_settings(chat, None).await?,
+        Some(v) => match SpamAction::from_name(&v) {
+            Some(action) => set_settings(chat, Some(action)).await?,
+            None => return ctx.fail(lang_fmt!(ctx, "crossspaminvalid")),
+        },
+    }
+    match get_settings(chat).await? {
+        Some(settings) => {
+            ctx.reply(lang_fmt!(
+                ctx,
+                "crossspamon",
+                settings.action.get_name(),
+                CONFIG.moderation.cross_spam_chats,
+                CONFIG.moderation.cross_spam_minutes
+            ))
+            .await?
+        }
+        None => ctx.reply(lang_fmt!(ctx, "crossspamoff")).await?,
+    };
+    Ok(())
+}
+
+/// Remember a message and get every recent message from its sender that is a copy of it,
+/// including the message itself
+async fn record(user: i64, seen: Seen) -> Result<Vec<Seen>> {
+    let window = CONFIG.moderation.cross_spam_minutes.max(1) * 60;
+    let now = Utc::now().timestamp();
+    let key = get_seen_key(user);
+    let hash = seen.hash;
+    let (recent,): (Vec<String>,) = REDIS
+        .pipe(|q| {
+            q.zadd(&key, seen.encode(), now)
+                .ignore()
+                .zrembyscore(&key, "-inf", now - window)
+                .ignore()
+                .zremrangebyrank(&key, 0, -MAX_SEEN - 1)
+                .ignore()
+                .expire(&key, window)
+                .ignore()
+                .zrange(&key, 0, -1)
+        })
+        .await?;
+    Ok(recent
+        .iter()
+        .filter_map(|v| Seen::decode(v))
+        .filter(|v| is_near_duplicate(v.hash, hash))
+        .collect())
+}
+
+/// Delete copies of a message sent before its sender was caught, in the chats that delete
+/// cross-chat spam
+async fn remove_earlier_copies(copies: &[Seen], current: i64) -> Result<()> {
+    for copy in copies.iter().filter(|v| v.chat != current) {
+        let deletes = get_settings(copy.chat)
+            .await?
+            .map(|v| v.action != SpamAction::Flag)
+            .unwrap_or(false);
+        if !deletes {
+            continue;
+        }
+        if let Err(err) = TG
+            .client()
+            .build_delete_message(copy.chat, copy.message)
+            .build()
+            .await
+        {
+            BotError::from(err).record_stats();
+        }
+    }
+    Ok(())
+}
+
+/// Fingerprint a message and act on it if its sender sent copies of it to too many chats
+async fn check_message(ctx: &Context, message: &Message) -> Result<()> {
+    let chat = message.get_chat();
+    let Some(sender) = message.get_from() else {
+        return Ok(());
+    };
+    if sender.get_is_bot() || is_bridged_id(sender.get_id()) || is_dm(chat) {
+        return Ok(());
+    }
+    let Some(text) = message.get_text().or_else(|| message.get_caption()) else {
+        return Ok(());
+    };
+    if is_command(text) {
+        return Ok(());
+    }
+    let Some(hash) = simhash(text) else {
+        return Ok(());
+    };
+    let user = sender.get_id();
+    let copies = record(
+        user,
+        Seen {
+            hash,
+            chat: chat.get_id(),
+            message: message.get_message_id(),
+        },
+    )
+    .await?;
+    let chats = copies.iter().map(|v| v.chat).unique().count();
+    if chats < CONFIG.moderation.cross_spam_chats.max(2) {
+        return Ok(());
+    }
+
+    let key = get_caught_key(user);
+    let window = CONFIG.moderation.cross_spam_minutes.max(1) * 60;
+    let (first,): (bool,) = REDIS
+        .pipe(|q| q.set_nx(&key, true).expire(&key, window).ignore())
+        .await?;
+    if first {
+        remove_earlier_copies(&copies, chat.get_id()).await?;
+    }
+
+    let Some(settings) = get_settings(chat.get_id()).await? else {
+        return Ok(());
+    };
+    if get_message_exemption(message).await?.is_some() {
+        return Ok(());
+    }
+    let reason = lang_fmt!(ctx, "crossspamreason", chats);
+    match settings.action {
+        SpamAction::Flag => (),
+        SpamAction::Delete => message.delete().await?,
+        SpamAction::Warn => {
+            message.delete().await?;
+            ctx.warn_with_action(user, Some(&reason), None).await?;
+        }
+        SpamAction::Mute => {
+            message.delete().await?;
+            ctx.mute(user, chat, None).await?;
+        }
+        SpamAction::Ban => {
+            message.delete().await?;
+            ctx.ban(user, None, false).await?;
+        }
+    }
+    let name = user.cached_name().await?;
+    post_log(
+        chat,
+        lang_fmt!(
+            ctx,
+            "crossspamlog",
+            name.escape(false),
+            user,
+            chats,
+            CONFIG.moderation.cross_spam_minutes,
+            settings.action.get_name()
+        ),
+    )
+    .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "crossspam" {
+            crossspam(ctx, args).await?;
+        }
+    }
+    if let UpdateExt::Message(ref message) = ctx.update() {
+        check_message(ctx, message).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        let spam = simhash("Earn 500 dollars a day from home with crypto trading, join our channel t.me/getrich now before it's too late").unwrap();
+        let reworded = simhash("earn 700 dollars a day from home with crypto trading join our channel t.me/getrich now before it's too late").unwrap();
+        let other = simhash("Does anyone know how to configure the welcome message so it shows the rules button under it?").unwrap();
+        assert!(is_near_duplicate(spam, reworded));
+        assert!(!is_near_duplicate(spam, other));
+        assert_eq!(simhash("hello there"), None);
+    }
+
+    #[test]
+    fn seen() {
+        let seen = Seen {
+            hash: u64::MAX,
+            chat: -1001234567890,
+            message: 42,
+        };
+        assert_eq!(Seen::decode(&seen.encode()), Some(seen));
+        assert_eq!(Seen::decode("nothex:1:2"), None);
+    }
+}
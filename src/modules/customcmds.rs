@@ -0,0 +1,648 @@
+use self::entities::{custom_command_aliases, custom_commands};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::core::entity;
+use crate::persist::core::media::{get_media_type, SendMediaReply};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::IntoChatUser;
+use crate::tg::button::InlineKeyboardBuilder;
+use crate::tg::command::{get_content, Cmd, Context, InputType, TextArgs};
+use crate::tg::markdown::{Escape, MarkupBuilder};
+use crate::tg::permissions::IsGroupAdmin;
+use crate::tg::quotas::{check_note_size, check_quota, Quota};
+use crate::tg::roles::{has_role, parse_role_name};
+use crate::util::error::{BotError, Fail, Result, SpeakErr};
+use crate::util::string::Speak;
+use botapi::gen_types::MessageEntity;
+use chrono::Duration;
+use futures::FutureExt;
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::{Expr, OnConflict};
+use std::collections::{BTreeMap, HashMap};
+
+metadata!("Custom Commands",
+    r#"
+    Create commands for your chat without writing any code. Custom commands work like notes,
+    but are triggered with /name or !name like any other command. They can be text, media, or
+    both, and support murkdown formatting, buttons, and fillings \(see /help formatting\).
+
+    Give a command several names by separating them with |. Custom commands can't use the
+    name of one of the bot's own commands. A command can be limited to the members of a role
+    with /cmdrole, other users are ignored unless they are admins.
+
+    [*Example:]
+    /addcmd !social|!links Follow us on [twitter](https://example.com)
+    !social
+    "#,
+    Helper,
+    { category = "Content" },
+    { command = "addcmd", help = "Usage: addcmd \\<name\\>\\[|alias\\] \\<content\\>. Creates a custom command, or replaces its content. Reply to a message to use it as the content", level = Admin },
+    { command = "rmcmd", help = "Usage: rmcmd \\<name\\>. Deletes a custom command along with its aliases, or just an alias", level = Admin },
+    { command = "cmdrole", help = "Usage: cmdrole \\<name\\> \\<role|off\\>. Only lets members of a role use a custom command", level = Admin },
+    { command = "cmds", help = "Lists the custom commands in this chat" },
+    { updates = [Message] }
+);
+
+pub mod entities {
+    use super::{Migration, MigrationRoles};
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(custom_commands::Entity)
+                        .col(
+                            ColumnDef::new(custom_commands::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(custom_commands::Column::Name)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(custom_commands::Column::Text).text())
+                        .col(ColumnDef::new(custom_commands::Column::MediaId).text())
+                        .col(
+                            ColumnDef::new(custom_commands::Column::MediaType)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(custom_commands::Column::EntityId).big_integer())
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(custom_commands::Column::Chat)
+                                .col(custom_commands::Column::Name)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_table(
+                    Table::create()
+                        .table(custom_command_aliases::Entity)
+                        .col(
+                            ColumnDef::new(custom_command_aliases::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(custom_command_aliases::Column::Alias)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(custom_command_aliases::Column::Command)
+                                .text()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(custom_command_aliases::Column::Chat)
+                                .col(custom_command_aliases::Column::Alias)
+                                .primary(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .name("fk_custom_command_alias")
+                                .from(
+                                    custom_command_aliases::Entity,
+                                    (
+                                        custom_command_aliases::Column::Chat,
+                                        custom_command_aliases::Column::Command,
+                                    ),
+                                )
+                                .to(
+                                    custom_commands::Entity,
+                                    (custom_commands::Column::Chat, custom_commands::Column::Name),
+                                )
+                                .on_delete(ForeignKeyAction::Cascade)
+                                .on_update(ForeignKeyAction::Cascade),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .drop_table_auto(custom_command_aliases::Entity)
+                .await?;
+            manager.drop_table_auto(custom_commands::Entity).await?;
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for MigrationRoles {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .alter_table(
+                    TableAlterStatement::new()
+                        .table(custom_commands::Entity)
+                        .add_column(ColumnDef::new(custom_commands::Column::Role).text())
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .alter_table(
+                    TableAlterStatement::new()
+                        .table(custom_commands::Entity)
+                        .drop_column(custom_commands::Column::Role)
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    pub mod custom_commands {
+        use crate::persist::core::media::MediaType;
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// A custom command, stored like a note
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "custom_commands")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub name: String,
+            #[sea_orm(column_type = "Text")]
+            pub text: Option<String>,
+            pub media_id: Option<String>,
+            pub media_type: MediaType,
+            pub entity_id: Option<i64>,
+            /// only members of this role can use the command
+            pub role: Option<String>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod custom_command_aliases {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// Another name for a custom command
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "custom_command_aliases")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub alias: String,
+            pub command: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000032_create_custom_commands"
+    }
+}
+
+pub struct MigrationRoles;
+
+impl MigrationName for MigrationRoles {
+    fn name(&self) -> &str {
+        "m20261015_000035_custom_command_roles"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration), Box::new(MigrationRoles)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+/// Longest name telegram allows for a command
+const MAX_NAME_LEN: usize = 32;
+
+#[inline(always)]
+fn get_names_key(chat: i64) -> String {
+    format!("ccmds:{}", chat)
+}
+
+#[inline(always)]
+fn get_command_key(chat: i64, name: &str) -> String {
+    format!("ccmd:{}:{}", chat, name)
+}
+
+/// Strip the command prefix from a name and check that telegram would accept it as a
+/// command
+fn parse_name(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches(['!', '/']).to_lowercase();
+    (!name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+    .then_some(name)
+}
+
+/// Get every custom command name and alias in a chat, mapped to the command it runs
+async fn get_names(chat: i64) -> Result<HashMap<String, String>> {
+    let key = get_names_key(chat);
+    let names = default_cache_query(
+        |_, _| async move {
+            let commands = custom_commands::Entity::find()
+                .filter(custom_commands::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?;
+            let aliases = custom_command_aliases::Entity::find()
+                .filter(custom_command_aliases::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?;
+            let names = commands
+                .into_iter()
+                .map(|v| (v.name.clone(), v.name))
+                .chain(aliases.into_iter().map(|v| (v.alias, v.command)))
+                .collect::<HashMap<String, String>>();
+            Ok(Some(names))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(names.unwrap_or_default())
+}
+
+/// Get a custom command along with its entities and buttons
+async fn get_command(
+    chat: i64,
+    name: &str,
+) -> Result<
+    Option<(
+        custom_commands::Model,
+        Vec<MessageEntity>,
+        Option<InlineKeyboardBuilder>,
+    )>,
+> {
+    let key = get_command_key(chat, name);
+    let name = name.to_owned();
+    let command = default_cache_query(
+        |_, _| async move {
+            let Some(command) = custom_commands::Entity::find_by_id((chat, name))
+                .one(*DB)
+                .await?
+            else {
+                return Ok(None);
+            };
+            let (entities, buttons) = match command.entity_id {
+                Some(id) => entity::get(*DB, id).await?,
+                None => (vec![], None),
+            };
+            Ok(Some((command, entities, buttons)))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(command)
+}
+
+async fn clear_cache(chat: i64, name: &str) -> Result<()> {
+    let names = get_names_key(chat);
+    let command = get_command_key(chat, name);
+    REDIS.invalidate(&[&names, &command]).await?;
+    Ok(())
+}
+
+/// Build a custom command from the /addcmd message or the message it replies to,
+/// returning the command along with its aliases
+async fn get_model(
+    ctx: &Context,
+    args: &TextArgs<'_>,
+) -> Result<(custom_commands::Model, Vec<String>)> {
+    let message = ctx.message()?;
+    let (names, content, source) = match get_content(message, args)? {
+        InputType::Reply(names, text, source) => (
+            names,
+            text.map(Some).unwrap_or_else(|| source.get_caption()),
+            source,
+        ),
+        InputType::Command(names, content, source) => (
+            names,
+            content.map(Some).unwrap_or_else(|| source.get_caption()),
+            source,
+        ),
+    };
+    let mut names = names
+        .split('|')
+        .map(|v| parse_name(v).ok_or_else(|| ctx.fail_err(lang_fmt!(ctx, "customcmdinvalid", v))))
+        .collect::<Result<Vec<String>>>()?
+        .into_iter()
+        .unique()
+        .collect::<Vec<String>>();
+    let name = names.remove(0);
+    let (media_id, media_type) = get_media_type(source)?;
+    if content.is_none() && media_id.is_none() {
+        return ctx.fail(lang_fmt!(ctx, "addcmdusage"));
+    }
+
+    let (text, entity_id) = if let Some(text) = content {
+        check_note_size(message, ctx.lang(), text)?;
+        let chatuser = source.get_chatuser();
+        let md = MarkupBuilder::new(source.get_entities().map(|v| v.to_owned()))
+            .chatuser(chatuser.as_ref())
+            .filling(false)
+            .header(false)
+            .set_text(text.to_owned());
+        let (text, entities, buttons) = md
+            .build_murkdown()
+            .await
+            .speak_err_raw(ctx, |v| match v {
+                BotError::MurkdownError(err) => {
+                    Some(lang_fmt!(ctx, "failmurkat", err.to_string().escape(false)))
+                }
+                _ => Some(lang_fmt!(ctx, "failmurk")),
+            })
+            .await?;
+        (Some(text), entity::insert(*DB, &entities, buttons).await?)
+    } else {
+        (None, None)
+    };
+
+    let model = custom_commands::Model {
+        chat: message.get_chat().get_id(),
+        name,
+        text,
+        media_id,
+        media_type,
+        entity_id,
+        role: None,
+    };
+    Ok((model, names))
+}
+
+async fn add_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let (model, aliases) = get_model(ctx, args).await?;
+    let existing = get_names(chat).await?;
+    for name in [&model.name].into_iter().chain(aliases.iter()) {
+        if TG.modules.command_level(name).is_some() {
+            return ctx.fail(lang_fmt!(ctx, "customcmdtaken", name));
+        }
+        if let Some(command) = existing.get(name).filter(|v| **v != model.name) {
+            return ctx.fail(lang_fmt!(ctx, "customcmdaliastaken", name, command));
+        }
+    }
+    let kept = existing
+        .keys()
+        .filter(|v| **v != model.name && !aliases.contains(v))
+        .count() as u64;
+    check_quota(
+        message,
+        ctx.lang(),
+        Quota::CustomCommands,
+        kept,
+        aliases.len() as u64 + 1,
+    )?;
+
+    let name = model.name.clone();
+    // replacing the content of a command keeps its role
+    custom_commands::Entity::insert(model.into_active_model())
+        .on_conflict(
+            OnConflict::columns([custom_commands::Column::Chat, custom_commands::Column::Name])
+                .update_columns([
+                    custom_commands::Column::Text,
+                    custom_commands::Column::MediaId,
+                    custom_commands::Column::MediaType,
+                    custom_commands::Column::EntityId,
+                ])
+                .to_owned(),
+        )
+        .exec(*DB)
+        .await?;
+    if !aliases.is_empty() {
+        custom_command_aliases::Entity::insert_many(aliases.iter().map(|alias| {
+            custom_command_aliases::Model {
+                chat,
+                alias: alias.clone(),
+                command: name.clone(),
+            }
+            .into_active_model()
+        }))
+        .on_conflict(
+            OnConflict::columns([
+                custom_command_aliases::Column::Chat,
+                custom_command_aliases::Column::Alias,
+            ])
+            .do_nothing()
+            .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+    }
+    clear_cache(chat, &name).await?;
+    ctx.reply(lang_fmt!(ctx, "customcmdsaved", name)).await?;
+    Ok(())
+}
+
+async fn rm_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let Some(name) = args.args.first().and_then(|v| parse_name(v.get_text())) else {
+        return ctx.fail(lang_fmt!(ctx, "rmcmdusage"));
+    };
+    let chat = ctx.message()?.get_chat().get_id();
+    let names = get_names(chat).await?;
+    match names.get(&name) {
+        Some(command) if *command == name => {
+            // aliases are removed along with the command by the foreign key
+            custom_commands::Entity::delete_by_id((chat, name.clone()))
+                .exec(*DB)
+                .await?;
+            clear_cache(chat, &name).await?;
+            ctx.reply(lang_fmt!(ctx, "customcmdremoved", name)).await?;
+        }
+        Some(command) => {
+            custom_command_aliases::Entity::delete_by_id((chat, name.clone()))
+                .exec(*DB)
+                .await?;
+            clear_cache(chat, command).await?;
+            ctx.reply(lang_fmt!(ctx, "customcmdaliasremoved", name, command))
+                .await?;
+        }
+        None => {
+            ctx.reply(lang_fmt!(ctx, "customcmdnotfound", name)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn cmd_role(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let (Some(name), Some(role)) = (
+        args.args.first().and_then(|v| parse_name(v.get_text())),
+        args.args.get(1).map(|v| v.get_text()),
+    ) else {
+        return ctx.fail(lang_fmt!(ctx, "cmdroleusage"));
+    };
+    let role = match role.to_lowercase().as_str() {
+        "off" | "none" => None,
+        role => Some(
+            parse_role_name(role).ok_or_else(|| ctx.fail_err(lang_fmt!(ctx, "cmdroleusage")))?,
+        ),
+    };
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(command) = get_names(chat).await?.remove(&name) else {
+        return ctx.fail(lang_fmt!(ctx, "customcmdnotfound", name));
+    };
+    custom_commands::Entity::update_many()
+        .filter(custom_commands::Column::Chat.eq(chat))
+        .filter(custom_commands::Column::Name.eq(command.as_str()))
+        .col_expr(custom_commands::Column::Role, Expr::value(role.clone()))
+        .exec(*DB)
+        .await?;
+    clear_cache(chat, &command).await?;
+    match role {
+        Some(role) => {
+            ctx.reply(lang_fmt!(ctx, "cmdroleset", command, role))
+                .await?
+        }
+        None => ctx.reply(lang_fmt!(ctx, "cmdroleoff", command)).await?,
+    };
+    Ok(())
+}
+
+async fn list_cmds(ctx: &Context) -> Result<()> {
+    let chat = ctx.message()?.get_chat().get_id();
+    let names = get_names(chat).await?;
+    if names.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "nocustomcmds")).await?;
+        return Ok(());
+    }
+    let mut commands = BTreeMap::<&str, Vec<&str>>::new();
+    for (name, command) in names.iter() {
+        let aliases = commands.entry(command.as_str()).or_default();
+        if name != command {
+            aliases.push(name.as_str());
+        }
+    }
+    let m = [lang_fmt!(ctx, "listcustomcmds")]
+        .into_iter()
+        .chain(commands.into_iter().map(|(command, mut aliases)| {
+            if aliases.is_empty() {
+                format!("- !{}", command.escape(false))
+            } else {
+                aliases.sort_unstable();
+                format!(
+                    "- !{} \\({}\\)",
+                    command.escape(false),
+                    aliases.join(", ").escape(false)
+                )
+            }
+        }))
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(m).await?;
+    Ok(())
+}
+
+/// Send a custom command if the current command isn't one of the bot's own
+async fn run_cmd(ctx: &Context, cmd: &str) -> Result<()> {
+    let cmd = cmd.to_lowercase();
+    if TG.modules.command_level(&cmd).is_some() {
+        return Ok(());
+    }
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(command) = get_names(chat).await?.remove(&cmd) else {
+        return Ok(());
+    };
+    let Some((command, entities, buttons)) = get_command(chat, &command).await? else {
+        return Ok(());
+    };
+    if let Some(ref role) = command.role {
+        let message = ctx.message()?;
+        let allowed = match message.get_from() {
+            Some(user) => {
+                has_role(chat, user.get_id(), role).await? || message.is_group_admin().await?
+            }
+            None => false,
+        };
+        if !allowed {
+            return Ok(());
+        }
+    }
+    SendMediaReply::new(ctx, command.media_type)
+        .button_callback(|_, _| async move { Ok(()) }.boxed())
+        .text(command.text)
+        .media_id(command.media_id)
+        .extra_entities(entities)
+        .buttons(buttons)
+        .send_media_reply()
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "addcmd" => add_cmd(ctx, args).await,
+            "rmcmd" => rm_cmd(ctx, args).await,
+            "cmdrole" => cmd_role(ctx, args).await,
+            "cmds" => list_cmds(ctx).await,
+            cmd => run_cmd(ctx, cmd).await,
+        }?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        assert_eq!(parse_name("!Social"), Some("social".to_owned()));
+        assert_eq!(parse_name("/links_2"), Some("links_2".to_owned()));
+        assert_eq!(parse_name("!"), None);
+        assert_eq!(parse_name("so cial"), None);
+        assert_eq!(parse_name(&"a".repeat(MAX_NAME_LEN + 1)), None);
+    }
+}
@@ -0,0 +1,110 @@
+use macros::{lang_fmt, update_handler};
+
+use crate::metadata::metadata;
+use crate::tg::command::{Cmd, Context, PopSlice, TextArg, TextArgs};
+use crate::tg::permissions::IsGroupAdmin;
+use crate::util::error::{BotError, Result};
+use crate::util::string::{
+    clear_string_override, count_placeholders, get_string_overrides, set_string_override, Speak,
+};
+
+metadata!("Custom Strings",
+    r#"
+    Replace any message the bot sends in this chat with your own text. Each {} in the
+    original message is filled in by the bot, for example with a user's name, so a
+    replacement must keep the same number of {}.
+
+    [*Example:]
+    /setstring setlang "Language changed, have fun"
+    "#,
+    { category = "Chat Management" },
+    { command = "setstring", help = "\\<name\\> \\<text\\>: Replace a bot message in this chat", level = Admin },
+    { command = "resetstring", help = "\\<name\\>: Go back to the default text for a message", level = Admin },
+    { command = "customstrings", help = "List the messages replaced in this chat" },
+    { updates = [Message] }
+);
+
+async fn setstring(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let fail = |text: String| BotError::speak(text, chat, Some(message.get_message_id()));
+    let Some((name, rest)) = args.pop_slice() else {
+        return Err(fail(lang_fmt!(ctx, "setstringusage")));
+    };
+    let name = name.get_text();
+    let text = match rest.args {
+        [TextArg::Quote(text)] => *text,
+        _ => rest.text,
+    };
+    if text.is_empty() {
+        return Err(fail(lang_fmt!(ctx, "setstringusage")));
+    }
+    let Some(original) = ctx.lang().get_string(name) else {
+        return Err(fail(lang_fmt!(ctx, "setstringnotfound", name)));
+    };
+    let expected = count_placeholders(original);
+    if count_placeholders(text) != expected {
+        return Err(fail(lang_fmt!(
+            ctx,
+            "setstringplaceholders",
+            expected,
+            original
+        )));
+    }
+    set_string_override(chat, name, text).await?;
+    ctx.reply(lang_fmt!(ctx, "setstring", name)).await?;
+    Ok(())
+}
+
+async fn resetstring(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let Some(name) = args.args.first().map(|v| v.get_text()) else {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "setstringusage"),
+            chat,
+            Some(message.get_message_id()),
+        ));
+    };
+    if clear_string_override(chat, name).await? {
+        ctx.reply(lang_fmt!(ctx, "resetstring", name)).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "resetstringnone", name)).await?;
+    }
+    Ok(())
+}
+
+async fn list_strings(ctx: &Context) -> Result<()> {
+    let chat = ctx.message()?.get_chat().get_id();
+    let mut names = get_string_overrides(chat)
+        .await?
+        .into_keys()
+        .collect::<Vec<String>>();
+    if names.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "customstringsnone")).await?;
+    } else {
+        names.sort();
+        let names = names
+            .into_iter()
+            .map(|v| format!("\t- {}", v))
+            .collect::<Vec<String>>()
+            .join("\n");
+        ctx.reply(lang_fmt!(ctx, "customstrings", names)).await?;
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "setstring" => setstring(ctx, args).await?,
+            "resetstring" => resetstring(ctx, args).await?,
+            "customstrings" => list_strings(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
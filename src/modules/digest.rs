@@ -0,0 +1,384 @@
+use self::entities::digest_settings::{self, DigestPeriod};
+use crate::metadata::ModuleHelpers;
+use crate::persist::audit::count_since;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::tg::admin_helpers::{post_log, UpdateHelpers, UserChanged};
+use crate::tg::bridges::bridged_author;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_dialog;
+use crate::tg::optout::{is_opted_out, OptOut};
+use crate::tg::permissions::*;
+use crate::tg::user::{GetChat, GetUser, Username};
+use crate::util::error::{BotError, Result};
+use crate::util::scheduler::{cancel, register_job, schedule};
+use crate::util::string::get_chat_lang;
+use crate::{metadata::metadata, util::string::Speak};
+use botapi::gen_types::UpdateExt;
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use std::collections::HashMap;
+
+metadata!("Digest",
+    r#"
+    Post a daily or weekly summary of this chat to the log channel, including how many users
+    joined or left, how many moderation actions were taken, and who posted the most.
+    A log channel must be set with /setlog first. Users who opted out with /optout stats
+    are left out of the top posters.
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "digest", help = "Usage: digest \\<daily/weekly/off\\>. Sets how often a digest is posted", level = Admin },
+    { updates = [Message, ChatMember] }
+);
+
+/// Scheduler job kind for posting a digest
+const DIGEST_JOB: &str = "digest";
+
+/// Number of top posters listed in a digest
+const TOP_POSTERS: isize = 5;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(digest_settings::Entity)
+                        .col(
+                            ColumnDef::new(digest_settings::Column::Chat)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(digest_settings::Column::Period)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(digest_settings::Column::Job).uuid())
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(digest_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod digest_settings {
+        use chrono::Duration;
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(
+            EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Debug,
+        )]
+        #[sea_orm(rs_type = "i32", db_type = "Integer")]
+        pub enum DigestPeriod {
+            #[sea_orm(num_value = 1)]
+            Daily,
+            #[sea_orm(num_value = 2)]
+            Weekly,
+        }
+
+        impl DigestPeriod {
+            pub fn get_name(&self) -> &'static str {
+                match self {
+                    Self::Daily => "daily",
+                    Self::Weekly => "weekly",
+                }
+            }
+
+            pub fn get_duration(&self) -> Duration {
+                match self {
+                    Self::Daily => Duration::try_days(1).unwrap(),
+                    Self::Weekly => Duration::try_weeks(1).unwrap(),
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "digest_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub chat: i64,
+            pub period: DigestPeriod,
+            pub job: Option<Uuid>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000006_create_digest"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(DIGEST_JOB, |payload| async move {
+            let chat: i64 = serde_json::from_value(payload)?;
+            send_digest(chat).await
+        });
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("dgset:{}", chat)
+}
+
+#[inline(always)]
+fn get_counts_key(chat: i64) -> String {
+    format!("dgcount:{}", chat)
+}
+
+#[inline(always)]
+fn get_posters_key(chat: i64) -> String {
+    format!("dgposters:{}", chat)
+}
+
+async fn get_settings(chat: i64) -> Result<Option<digest_settings::Model>> {
+    let key = get_settings_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = digest_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+/// Schedule the next digest for a chat and save the settings
+async fn enable_digest(chat: i64, period: DigestPeriod) -> Result<()> {
+    if let Some(job) = get_settings(chat).await?.and_then(|v| v.job) {
+        cancel(&job).await?;
+    }
+    let job = schedule(DIGEST_JOB, &chat, Utc::now() + period.get_duration()).await?;
+    let model = digest_settings::ActiveModel {
+        chat: Set(chat),
+        period: Set(period),
+        job: Set(Some(job)),
+    };
+    let model = digest_settings::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(digest_settings::Column::Chat)
+                .update_columns([
+                    digest_settings::Column::Period,
+                    digest_settings::Column::Job,
+                ])
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+async fn disable_digest(chat: i64) -> Result<()> {
+    if let Some(job) = get_settings(chat).await?.and_then(|v| v.job) {
+        cancel(&job).await?;
+    }
+    digest_settings::Entity::delete_by_id(chat)
+        .exec(*DB)
+        .await?;
+    let (settings, counts, posters) = (
+        get_settings_key(chat),
+        get_counts_key(chat),
+        get_posters_key(chat),
+    );
+    REDIS
+        .pipe(|q| q.del(&settings).del(&counts).del(&posters).ignore())
+        .await?;
+    Ok(())
+}
+
+/// Build and post the digest for a chat, then schedule the next one
+async fn send_digest(chat: i64) -> Result<()> {
+    let Some(settings) = digest_settings::Entity::find_by_id(chat).one(*DB).await? else {
+        return Ok(());
+    };
+    let period = settings.period;
+    let (counts_key, posters_key) = (get_counts_key(chat), get_posters_key(chat));
+    let (counts, posters): (HashMap<String, u64>, Vec<(i64, u64)>) = REDIS
+        .pipe(|q| {
+            q.hgetall(&counts_key)
+                .zrevrange_withscores(&posters_key, 0, TOP_POSTERS - 1)
+                .del(&counts_key)
+                .ignore()
+                .del(&posters_key)
+                .ignore()
+        })
+        .await?;
+    let actions = count_since(chat, Utc::now() - period.get_duration()).await?;
+
+    if let Some(c) = chat.get_chat().await? {
+        let lang = get_chat_lang(chat).await?;
+        let mut text = lang_fmt!(
+            lang,
+            "digest",
+            period.get_name(),
+            c.name_humanreadable(),
+            counts.get("joined").copied().unwrap_or(0),
+            counts.get("left").copied().unwrap_or(0),
+            actions
+        );
+        if posters.is_empty() {
+            text.push_str(&lang_fmt!(lang, "digestnoposters"));
+        }
+        for (user, count) in posters {
+            text.push_str(&format!("\n{}: {}", user.cached_name().await?, count));
+        }
+        post_log(&c, text).await?;
+    }
+
+    let job = schedule(DIGEST_JOB, &chat, Utc::now() + period.get_duration()).await?;
+    let model = digest_settings::Entity::update(digest_settings::ActiveModel {
+        chat: Set(chat),
+        period: Set(period),
+        job: Set(Some(job)),
+    })
+    .exec(*DB)
+    .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+/// Count joins, leaves, and messages for chats with digests enabled
+async fn count_activity(ctx: &Context) -> Result<()> {
+    let update = ctx.update();
+    if let Some(event) = update.user_event() {
+        let chat = event.get_chat().get_id();
+        if get_settings(chat).await?.is_none() {
+            return Ok(());
+        }
+        let field = match event {
+            UserChanged::UserJoined(_) => "joined",
+            UserChanged::UserLeft(_) => "left",
+        };
+        let key = get_counts_key(chat);
+        REDIS.sq(|q| q.hincr(&key, field, 1)).await?;
+    } else if let UpdateExt::Message(ref message) = update {
+        let chat = message.get_chat().get_id();
+        let Some(user) = message.get_from() else {
+            return Ok(());
+        };
+        if get_settings(chat).await?.is_none() {
+            return Ok(());
+        }
+        let user = match bridged_author(message).await? {
+            Some(author) => author.get_id(),
+            None => user.get_id(),
+        };
+        if is_opted_out(user, OptOut::Stats).await? {
+            return Ok(());
+        }
+        let key = get_posters_key(chat);
+        REDIS.sq(|q| q.zincr(&key, user, 1)).await?;
+    }
+    Ok(())
+}
+
+async fn digest_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat();
+    match args.args.first().map(|v| v.get_text()) {
+        Some("off") | Some("no") => {
+            disable_digest(chat.get_id()).await?;
+            ctx.reply(lang_fmt!(ctx, "digestoff")).await?;
+        }
+        Some(arg @ ("daily" | "weekly")) => {
+            if get_dialog(chat)
+                .await?
+                .and_then(|v| v.log_channel)
+                .is_none()
+            {
+                return Err(BotError::speak(
+                    lang_fmt!(ctx, "digestnolog"),
+                    chat.get_id(),
+                    Some(message.get_message_id()),
+                ));
+            }
+            let period = if arg == "daily" {
+                DigestPeriod::Daily
+            } else {
+                DigestPeriod::Weekly
+            };
+            enable_digest(chat.get_id(), period).await?;
+            ctx.reply(lang_fmt!(ctx, "digeston", period.get_name()))
+                .await?;
+        }
+        None => {
+            let period = get_settings(chat.get_id())
+                .await?
+                .map(|v| v.period.get_name())
+                .unwrap_or("off");
+            ctx.reply(lang_fmt!(ctx, "digeststatus", period)).await?;
+        }
+        Some(_) => {
+            return Err(BotError::speak(
+                lang_fmt!(ctx, "digestinvalid"),
+                chat.get_id(),
+                Some(message.get_message_id()),
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "digest" {
+            digest_cmd(ctx, args).await?;
+        }
+    }
+    count_activity(ctx).await?;
+    Ok(())
+}
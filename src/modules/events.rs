@@ -0,0 +1,522 @@
+use self::entities::event_settings::{self, Reminders};
+use self::entities::events;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::parse_duration_str;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::markdown::Escape;
+use crate::util::error::{Fail, Result};
+use crate::util::locale::{parse_utc_offset, utc_offset_timezone, Localize};
+use crate::util::scheduler::{register_job, schedule};
+use crate::util::string::{get_chat_lang, Speak};
+use chrono::{DateTime, Duration, NaiveDateTime, TimeZone, Utc};
+use macros::{lang_fmt, update_handler};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
+
+metadata!("Events",
+    r#"
+    Keep a calendar of upcoming events in this chat. Times are read and shown in the chat's
+    timezone, set with /timezone as an offset from UTC. Reminders are posted before each event,
+    by default a day and an hour before it starts. Changing the reminders only affects events
+    added afterwards.
+
+    [*Example:]
+    /timezone UTC+2
+    /addevent 2024-07-01 18:00 Community call
+    /eventreminders 2d 1h 10m
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "addevent", help = "Usage: addevent \\<yyyy-mm-dd\\> \\<hh:mm\\> \\<title\\>. Adds an event to the calendar", level = Admin, requires = [CanChangeInfo] },
+    { command = "rmevent", help = "Usage: rmevent \\<number\\>. Removes an event, see /events for numbers", level = Admin, requires = [CanChangeInfo] },
+    { command = "events", help = "Lists upcoming events" },
+    { command = "timezone", help = "Usage: timezone \\<UTC+hh:mm\\>. Sets the timezone event times are in", level = Admin, requires = [CanChangeInfo] },
+    { command = "eventreminders", help = "Usage: eventreminders \\<times/off\\>. Sets how long before an event reminders are posted", level = Admin, requires = [CanChangeInfo] },
+    { updates = [Message] }
+);
+
+/// Scheduler job kind for reminding a chat of an event
+const REMINDER_JOB: &str = "eventreminder";
+
+/// Most upcoming events in a chat
+const MAX_EVENTS: usize = 20;
+
+/// Longest event title
+const MAX_TITLE_LEN: usize = 200;
+
+/// Most reminders per event
+const MAX_REMINDERS: usize = 5;
+
+/// Format of event dates and times, both when added and when shown
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(events::Entity)
+                        .col(
+                            ColumnDef::new(events::Column::Id)
+                                .big_integer()
+                                .not_null()
+                                .primary_key()
+                                .auto_increment(),
+                        )
+                        .col(
+                            ColumnDef::new(events::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(events::Column::Starts)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(events::Column::Title).text().not_null())
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_index(
+                    IndexCreateStatement::new()
+                        .table(events::Entity)
+                        .name("events_chat_starts")
+                        .col(events::Column::Chat)
+                        .col(events::Column::Starts)
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_table(
+                    Table::create()
+                        .table(event_settings::Entity)
+                        .col(
+                            ColumnDef::new(event_settings::Column::Chat)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(event_settings::Column::UtcOffset)
+                                .integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .col(
+                            ColumnDef::new(event_settings::Column::Reminders)
+                                .json_binary()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(events::Entity).await?;
+            manager.drop_table_auto(event_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod events {
+        use chrono::Utc;
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "events")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            pub chat: i64,
+            pub starts: chrono::DateTime<Utc>,
+            pub title: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod event_settings {
+        use sea_orm::{entity::prelude::*, FromJsonQueryResult};
+        use serde::{Deserialize, Serialize};
+
+        /// Seconds before an event that reminders are posted
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromJsonQueryResult)]
+        pub struct Reminders(pub Vec<i64>);
+
+        impl Default for Reminders {
+            fn default() -> Self {
+                Self(vec![24 * 60 * 60, 60 * 60])
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "event_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub chat: i64,
+            /// minutes east of UTC
+            pub utc_offset: i32,
+            #[sea_orm(column_type = "JsonBinary")]
+            pub reminders: Reminders,
+        }
+
+        impl Model {
+            pub fn new(chat: i64) -> Self {
+                Self {
+                    chat,
+                    utc_offset: 0,
+                    reminders: Reminders::default(),
+                }
+            }
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000048_create_events"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// A reminder waiting in the scheduler
+#[derive(Serialize, Deserialize)]
+struct EventReminder {
+    event: i64,
+    before: i64,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(REMINDER_JOB, |payload| async move {
+            let reminder: EventReminder = serde_json::from_value(payload)?;
+            send_reminder(reminder).await
+        });
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("evset:{}", chat)
+}
+
+/// Parse a date and time in a chat's timezone
+fn parse_time(date: &str, time: &str, offset: i32) -> Option<DateTime<Utc>> {
+    let time = NaiveDateTime::parse_from_str(&format!("{} {}", date, time), TIME_FORMAT).ok()?;
+    utc_offset_timezone(offset)
+        .from_local_datetime(&time)
+        .single()
+        .map(|v| v.with_timezone(&Utc))
+}
+
+fn format_time(time: &DateTime<Utc>, offset: i32) -> String {
+    let timezone = utc_offset_timezone(offset);
+    format!(
+        "{} UTC{}",
+        time.with_timezone(&timezone).format(TIME_FORMAT),
+        timezone
+    )
+}
+
+async fn get_settings(chat: i64) -> Result<event_settings::Model> {
+    let key = get_settings_key(chat);
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = event_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.unwrap_or_else(|| event_settings::Model::new(chat)))
+}
+
+async fn save_settings(settings: event_settings::Model) -> Result<()> {
+    let chat = settings.chat;
+    let model = event_settings::Entity::insert(event_settings::ActiveModel {
+        chat: Set(settings.chat),
+        utc_offset: Set(settings.utc_offset),
+        reminders: Set(settings.reminders),
+    })
+    .on_conflict(
+        OnConflict::column(event_settings::Column::Chat)
+            .update_columns([
+                event_settings::Column::UtcOffset,
+                event_settings::Column::Reminders,
+            ])
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+/// Get the events in a chat that haven't started yet, soonest first
+async fn get_upcoming(chat: i64) -> Result<Vec<events::Model>> {
+    let res = events::Entity::find()
+        .filter(events::Column::Chat.eq(chat))
+        .filter(events::Column::Starts.gt(Utc::now()))
+        .order_by_asc(events::Column::Starts)
+        .all(*DB)
+        .await?;
+    Ok(res)
+}
+
+/// Post a reminder for an event unless it was removed since
+async fn send_reminder(reminder: EventReminder) -> Result<()> {
+    let Some(event) = events::Entity::find_by_id(reminder.event).one(*DB).await? else {
+        return Ok(());
+    };
+    let settings = get_settings(event.chat).await?;
+    let lang = get_chat_lang(event.chat).await?;
+    let before = Duration::try_seconds(reminder.before)
+        .unwrap_or_default()
+        .localize(&lang);
+    let text = lang_fmt!(
+        lang,
+        "eventreminder",
+        event.title,
+        before,
+        format_time(&event.starts, settings.utc_offset)
+    );
+    TG.client()
+        .build_send_message(event.chat, &text)
+        .build()
+        .await?;
+    Ok(())
+}
+
+async fn add_event(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let settings = get_settings(chat).await?;
+    let mut words = args.text.trim().splitn(3, char::is_whitespace);
+    let (Some(date), Some(time), Some(title)) = (words.next(), words.next(), words.next()) else {
+        return ctx.fail(lang_fmt!(ctx, "addeventusage"));
+    };
+    let title = title.trim();
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return ctx.fail(lang_fmt!(ctx, "addeventusage"));
+    }
+    let Some(starts) = parse_time(date, time, settings.utc_offset) else {
+        return ctx.fail(lang_fmt!(ctx, "addeventusage"));
+    };
+    if starts <= Utc::now() {
+        return ctx.fail(lang_fmt!(ctx, "eventpast"));
+    }
+    if get_upcoming(chat).await?.len() >= MAX_EVENTS {
+        return ctx.fail(lang_fmt!(ctx, "eventsfull", MAX_EVENTS));
+    }
+    let event = events::Entity::insert(events::ActiveModel {
+        id: NotSet,
+        chat: Set(chat),
+        starts: Set(starts),
+        title: Set(title.to_owned()),
+    })
+    .exec_with_returning(*DB)
+    .await?;
+    for before in settings.reminders.0.iter().copied() {
+        let at = starts - Duration::try_seconds(before).unwrap_or_default();
+        if at > Utc::now() {
+            let reminder = EventReminder {
+                event: event.id,
+                before,
+            };
+            schedule(REMINDER_JOB, &reminder, at).await?;
+        }
+    }
+    ctx.reply(lang_fmt!(
+        ctx,
+        "eventadded",
+        title.escape(false),
+        format_time(&starts, settings.utc_offset)
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn rm_event(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let upcoming = get_upcoming(chat).await?;
+    let Some(event) = args
+        .text
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|v| v.checked_sub(1))
+        .and_then(|v| upcoming.get(v))
+    else {
+        return ctx.fail(lang_fmt!(ctx, "rmeventusage"));
+    };
+    // reminders already scheduled find the event gone and do nothing
+    events::Entity::delete_by_id(event.id).exec(*DB).await?;
+    ctx.reply(lang_fmt!(ctx, "eventremoved", event.title.escape(false)))
+        .await?;
+    Ok(())
+}
+
+async fn list_events(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    events::Entity::delete_many()
+        .filter(events::Column::Chat.eq(chat))
+        .filter(events::Column::Starts.lte(Utc::now()))
+        .exec(*DB)
+        .await?;
+    let upcoming = get_upcoming(chat).await?;
+    if upcoming.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "noevents")).await?;
+        return Ok(());
+    }
+    let settings = get_settings(chat).await?;
+    let list = upcoming
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            format!(
+                "{}. {}: {}",
+                i + 1,
+                format_time(&v.starts, settings.utc_offset).escape(false),
+                v.title.escape(false)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(lang_fmt!(ctx, "listevents", list)).await?;
+    Ok(())
+}
+
+async fn timezone_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let mut settings = get_settings(chat).await?;
+    if args.text.trim().is_empty() {
+        let timezone = format!("UTC{}", utc_offset_timezone(settings.utc_offset));
+        ctx.reply(lang_fmt!(ctx, "timezonestatus", timezone))
+            .await?;
+        return Ok(());
+    }
+    let Some(offset) = parse_utc_offset(args.text) else {
+        return ctx.fail(lang_fmt!(ctx, "timezoneusage"));
+    };
+    settings.utc_offset = offset;
+    save_settings(settings).await?;
+    let timezone = format!("UTC{}", utc_offset_timezone(offset));
+    ctx.reply(lang_fmt!(ctx, "timezoneset", timezone)).await?;
+    Ok(())
+}
+
+async fn reminders_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let mut settings = get_settings(chat).await?;
+    if args.text.trim() == "off" {
+        settings.reminders = Reminders(vec![]);
+        save_settings(settings).await?;
+        ctx.reply(lang_fmt!(ctx, "eventremindersoff")).await?;
+        return Ok(());
+    }
+    if args.args.is_empty() || args.args.len() > MAX_REMINDERS {
+        return ctx.fail(lang_fmt!(ctx, "eventremindersusage", MAX_REMINDERS));
+    }
+    let mut reminders = Vec::with_capacity(args.args.len());
+    for arg in args.args.iter() {
+        let Some(before) = parse_duration_str(arg.get_text(), chat, message.get_message_id())?
+        else {
+            return ctx.fail(lang_fmt!(ctx, "eventremindersusage", MAX_REMINDERS));
+        };
+        reminders.push(before.num_seconds());
+    }
+    reminders.sort_unstable_by(|a, b| b.cmp(a));
+    reminders.dedup();
+    let list = reminders
+        .iter()
+        .map(|v| {
+            Duration::try_seconds(*v)
+                .unwrap_or_default()
+                .localize(ctx.lang())
+        })
+        .collect::<Vec<String>>()
+        .join(", ");
+    settings.reminders = Reminders(reminders);
+    save_settings(settings).await?;
+    ctx.reply(lang_fmt!(ctx, "eventremindersset", list)).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "addevent" => add_event(ctx, args).await?,
+            "rmevent" => rm_event(ctx, args).await?,
+            "events" => list_events(ctx).await?,
+            "timezone" => timezone_cmd(ctx, args).await?,
+            "eventreminders" => reminders_cmd(ctx, args).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn local_times() {
+        let time = parse_time("2024-07-01", "18:00", 120).unwrap();
+        assert_eq!(time, Utc.with_ymd_and_hms(2024, 7, 1, 16, 0, 0).unwrap());
+        assert_eq!(format_time(&time, 120), "2024-07-01 18:00 UTC+02:00");
+        assert!(parse_time("2024-13-01", "18:00", 0).is_none());
+    }
+}
@@ -1,25 +1,33 @@
 use crate::persist::admin::{fbans, federations};
 use crate::persist::core::users;
 use crate::statics::{DB, TG};
-use crate::tg::admin_helpers::{FileGetter, StrOption};
+use crate::tg::admin_helpers::{post_log, DeleteAfterTime, FileGetter, StrOption, UpdateHelpers};
+use crate::tg::ban_signals::{get_risk_score, get_sharing, set_sharing, try_risk_lookup};
 use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::fed_media::{
+    add_fed_media, blocked_media, get_fed_media, is_valid_fingerprint, message_fingerprints,
+    remove_fed_media,
+};
 use crate::tg::federations::{
     create_federation, fban_user, fstat, get_fed, get_feds, is_fedadmin, is_fedmember, join_fed,
     subfed, try_update_fban_cache, update_fed,
 };
+use crate::tg::import_export::{ChunkedUpload, ListFormat, ListImport, ListItem, ListRow};
+use crate::tg::markdown::Escape;
 use crate::tg::permissions::IsGroupAdmin;
+use crate::tg::quotas::{check_quota, Quota};
 use crate::tg::user::{GetUser, Username};
 use crate::util::error::{BotError, Fail, Result, SpeakErr};
 use crate::util::string::should_ignore_chat;
 use crate::{metadata::metadata, util::string::Speak};
-use botapi::bot::Part;
-use botapi::gen_types::{FileData, Message};
+use botapi::gen_types::{Message, UserBuilder};
 use itertools::Itertools;
 use macros::{entity_fmt, lang_fmt, update_handler};
 use sea_orm::ActiveValue::{NotSet, Set};
-use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
 use sea_query::OnConflict;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use uuid::Uuid;
 
 metadata!("Federations",
@@ -31,7 +39,12 @@ metadata!("Federations",
     Each federation has an owner, and a number of admins, all of which are cable of issuing fbans
     in that federation. Federations can subscribe to other federations to receive their bans \(but not
     their actual ban list \)
+
+    Fedadmins can also block media across the federation with /fblockmedia. Blocked media is
+    deleted in every chat of the federation, matching either the exact file or, when the bot
+    supports it, similar looking copies of an image
     "#,
+    { category = "Moderation" },
     { command = "fban", help = "Bans a user in the current chat's federation" },
     { command = "joinfed", help = "Joins a chat to a federation. Only one fed per chat" },
     { command = "newfed", help = "Create a new federation with yourself as the owner" },
@@ -40,10 +53,35 @@ metadata!("Federations",
     { command = "unfban", help = "Unban a user in the current chat's federation" },
     { command = "renamefed", help = "Rename your federation" },
     { command = "subfed", help = "Usage: subfed \\<uuid\\>: subscribes your federation to a new fed's id" },
-    { command = "fedimport", help = "Import a list of fbans to your current federation using Rose bot's json format" },
-    { command = "fedexport", help = "Export your federation's fbans in Rose bot's json format" }
+    { command = "fimport", help = "Reply to a file to import its fbans to your federation. Accepts Rose bot's json or csv fban exports, skipping users already fbanned" },
+    { command = "fexport", help = "Usage: fexport \\<json/csv\\>: Export your federation's fbans in Rose bot's format. Large federations are split across several files" },
+    { command = "multifban", help = "Fban every user in an attached csv or json file of user ids and optional reasons in the current chat's federation" },
+    { command = "fedshare", help = "Usage: fedshare \\<publish/consume\\> \\<on/off\\>. Opt your federation in or out of sharing anonymized ban signals with other federations. Tag fban reasons with \\#spam, \\#scam, \\#raid, or \\#nsfw to categorize them" },
+    { command = "fedrisk", help = "Show how many other federations flagged a user in shared ban signals. Advisory only, requires the chat's federation to consume signals" },
+    { command = "fblockmedia", help = "Reply to a photo, video, gif, sticker, or file to delete it in every chat of the current chat's federation. Also accepts fingerprints from /fblockedmedia as arguments" },
+    { command = "funblockmedia", help = "Reply to media or give fingerprints to unblock them in the current chat's federation" },
+    { command = "fblockedmedia", help = "List the fingerprints of media blocked in the current chat's federation" },
+    { updates = [Message] }
 );
 
+/// Number of fbans applied between progress updates in /multifban
+const MULTIFBAN_BATCH: usize = 100;
+
+/// Maximum number of invalid lines listed in the /multifban and /fimport summaries
+const MULTIFBAN_INVALID_SHOWN: usize = 20;
+
+/// Number of rows read from the database at a time in /fexport
+const FBAN_EXPORT_PAGE: u64 = 1000;
+
+/// Number of fbans in each document sent by /fexport
+const FBAN_EXPORT_CHUNK: usize = 50000;
+
+/// Number of fbans inserted at a time in /fimport
+const FBAN_IMPORT_BATCH: usize = 1000;
+
+/// Maximum number of fingerprints listed by /fblockedmedia
+const FED_MEDIA_SHOWN: usize = 100;
+
 async fn fban(ctx: &Context) -> Result<()> {
     if ctx.message()?.get_sender_chat().is_some() {
         return ctx.fail(lang_fmt!(ctx, "anonban"));
@@ -178,7 +216,7 @@ pub async fn unfban(ctx: &Context) -> Result<()> {
             if is_fedadmin(user, &fed).await?
                 || ctx.check_permissions(|p| p.is_support).await.is_ok()
             {
-                ctx.unfban(user, &fed).await?;
+                ctx.unfban(user).await?;
             } else {
                 ctx.reply(lang_fmt!(ctx, "unfbanperm")).await?;
             }
@@ -261,129 +299,666 @@ async fn fstat_cmd(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+/// A single fban in Rose bot's export format
 #[derive(Serialize, Deserialize)]
 struct FbanExportItem {
     pub user_id: i64,
+    #[serde(default)]
     pub first_name: String,
+    #[serde(default)]
     pub last_name: String,
+    #[serde(default)]
     pub reason: String,
 }
 
-async fn get_fban_list(fed: &Uuid) -> Result<Vec<FbanExportItem>> {
-    let res = fbans::Entity::find()
-        .filter(crate::persist::admin::fbans::Column::Federation.eq(*fed))
-        .find_also_related(users::Entity)
-        .all(*DB)
-        .await?;
-    Ok(res
-        .into_iter()
-        .filter_map(|(fban, user)| user.map(|user| (fban, user)))
-        .map(|(fban, user)| FbanExportItem {
-            user_id: user.user_id,
-            first_name: user.first_name,
-            last_name: user.last_name.unwrap_or_else(|| "".to_owned()),
-            reason: fban.reason.unwrap_or_else(|| "".to_owned()),
+impl FbanExportItem {
+    fn new(fban: fbans::Model, user: Option<users::Model>) -> Self {
+        let (first_name, last_name) = user
+            .map(|v| (v.first_name, v.last_name.unwrap_or_default()))
+            .unwrap_or_else(|| (fban.user_name.unwrap_or_default(), String::new()));
+        Self {
+            user_id: fban.user,
+            first_name,
+            last_name,
+            reason: fban.reason.unwrap_or_default(),
+        }
+    }
+}
+
+impl ListRow for FbanExportItem {
+    const CSV_HEADER: &'static str = "user_id,first_name,last_name,reason";
+
+    fn to_csv(&self) -> Vec<String> {
+        vec![
+            self.user_id.to_string(),
+            self.first_name.clone(),
+            self.last_name.clone(),
+            self.reason.clone(),
+        ]
+    }
+
+    fn from_csv(fields: Vec<String>) -> Option<Self> {
+        let mut fields = fields.into_iter();
+        let user_id = fields.next()?.trim().parse().ok()?;
+        let mut next = || fields.next().unwrap_or_default();
+        Some(Self {
+            user_id,
+            first_name: next(),
+            last_name: next(),
+            reason: next(),
         })
-        .collect())
+    }
 }
 
-async fn set_fban_list(ctx: &Context, fed: &Uuid, message: &Message) -> Result<u64> {
-    if let Some(document) = message.get_document() {
-        let text = document.get_text().await?;
-        let fb = serde_json::Deserializer::from_str(&text).into_iter::<FbanExportItem>();
-        let res = fb.map_ok(|fb| {
+/// Counts of what happened to each row of a /fimport file
+#[derive(Default)]
+struct FbanImportReport {
+    imported: usize,
+    existing: usize,
+    duplicates: usize,
+    invalid: Vec<usize>,
+}
+
+/// Import a batch of fbans read from a /fimport file, skipping users already seen in the
+/// file or already fbanned in the federation
+async fn import_fban_batch(
+    fed: &Uuid,
+    batch: Vec<ListItem<FbanExportItem>>,
+    seen: &mut HashSet<i64>,
+    report: &mut FbanImportReport,
+) -> Result<()> {
+    let mut items = Vec::with_capacity(batch.len());
+    for (line, item) in batch {
+        match item {
+            // user ids are always positive
+            Some(item) if item.user_id > 0 => {
+                if seen.insert(item.user_id) {
+                    items.push(item);
+                } else {
+                    report.duplicates += 1;
+                }
+            }
+            _ => report.invalid.push(line),
+        }
+    }
+    let existing = fbans::Entity::find()
+        .filter(fbans::Column::Federation.eq(*fed))
+        .filter(fbans::Column::User.is_in(items.iter().map(|v| v.user_id)))
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| v.user)
+        .collect::<HashSet<i64>>();
+    report.existing += existing.len();
+    items.retain(|v| !existing.contains(&v.user_id));
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let (users, fbs): (Vec<_>, Vec<_>) = items
+        .iter()
+        .map(|fb| {
             (
                 users::ActiveModel {
                     user_id: Set(fb.user_id),
-                    first_name: Set(fb.first_name),
-                    last_name: Set(fb.last_name.none_if_empty()),
+                    first_name: Set(fb.first_name.clone()),
+                    last_name: Set(fb.last_name.clone().none_if_empty()),
                     username: NotSet,
                     is_bot: NotSet,
+                    optout_mentions: NotSet,
+                    optout_stats: NotSet,
                 },
                 fbans::ActiveModel {
                     fban_id: Set(Uuid::new_v4()),
                     federation: Set(*fed),
                     user: Set(fb.user_id),
                     user_name: NotSet,
-                    reason: Set(fb.reason.none_if_empty()),
+                    reason: Set(fb.reason.trim().to_owned().none_if_empty()),
                 },
-                fb.user_id,
             )
-        });
+        })
+        .unzip();
+    users::Entity::insert_many(users)
+        .on_conflict(
+            OnConflict::column(users::Column::UserId) //SECURITY ALERT don't modify existing users
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+    report.imported += fbans::Entity::insert_many(fbs)
+        .on_conflict(
+            OnConflict::columns([fbans::Column::User, fbans::Column::Federation])
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await? as usize;
+    for item in items {
+        try_update_fban_cache(item.user_id).await?;
+    }
+    Ok(())
+}
 
-        let (user, fbs, ids): (Vec<_>, Vec<_>, Vec<_>) =
-            itertools::process_results(res, |i| itertools::multiunzip(i))
-                .speak_err(ctx, |e| format!("Failed to parse fban json: {}", e))
-                .await?;
-        users::Entity::insert_many(user)
-            .on_conflict(
-                OnConflict::column(users::Column::UserId) //SECURITY ALERT don't modify existing users
-                    .do_nothing()
-                    .to_owned(),
-            )
-            .exec_without_returning(*DB)
-            .await?;
+async fn import_fbans(ctx: &Context) -> Result<()> {
+    let message = ctx.message()?;
+    if message.get_sender_chat().is_some() {
+        return ctx.fail(lang_fmt!(ctx, "anonfed"));
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(fed) = get_fed(user.get_id()).await? else {
+        return ctx.fail(lang_fmt!(ctx, "nofed"));
+    };
+    ctx.action_message(|ctx, message, _| async move {
+        let Some(document) = message.message().get_document() else {
+            return ctx.fail(lang_fmt!(ctx, "fimportnofile"));
+        };
+        let progress = ctx.reply(lang_fmt!(ctx, "fimportprogress", 0)).await?;
+        let mut import =
+            ListImport::<FbanExportItem>::start(document.get_file_id(), FBAN_IMPORT_BATCH).await?;
+        let mut report = FbanImportReport::default();
+        let mut seen = HashSet::new();
+        while let Some(batch) = import.next().await {
+            import_fban_batch(&fed.fed_id, batch, &mut seen, &mut report).await?;
+            let done = report.imported + report.existing + report.duplicates;
+            edit_progress(&progress, &lang_fmt!(ctx, "fimportprogress", done)).await?;
+        }
+        let parse_error = import.finish().await.err();
 
-        let res = fbans::Entity::insert_many(fbs)
-            .on_conflict(
-                OnConflict::column(fbans::Column::User)
-                    .update_columns([fbans::Column::UserName, fbans::Column::Reason])
-                    .to_owned(),
-            )
-            .exec_without_returning(*DB)
-            .await?;
-        for id in ids {
-            try_update_fban_cache(id).await?;
+        let mut summary = lang_fmt!(
+            ctx,
+            "fimportsummary",
+            fed.fed_name,
+            report.imported,
+            report.existing,
+            report.duplicates,
+            report.invalid.len()
+        );
+        if !report.invalid.is_empty() {
+            let lines = report
+                .invalid
+                .iter()
+                .take(MULTIFBAN_INVALID_SHOWN)
+                .join(", ");
+            summary.push_str(&lang_fmt!(ctx, "multifbaninvalid", lines));
         }
+        if let Some(err) = parse_error {
+            summary.push_str(&lang_fmt!(ctx, "fimportparseerror", err.to_string()));
+        }
+        if progress.is_some() {
+            edit_progress(&progress, &summary).await?;
+        } else {
+            ctx.reply(summary).await?;
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(())
+}
+
+async fn export_fbans(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let message = ctx.message()?;
+    if message.get_sender_chat().is_some() {
+        return ctx.fail(lang_fmt!(ctx, "anonfed"));
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(fed) = get_fed(user.get_id()).await? else {
+        return ctx.fail(lang_fmt!(ctx, "nofed"));
+    };
+    let format = match args.args.first().map(|v| v.get_text()) {
+        None => ListFormat::Json,
+        Some(arg) => ListFormat::from_arg(arg)
+            .ok_or_else(|| ctx.fail_err(lang_fmt!(ctx, "fexportformat")))?,
+    };
+    let chat = message.get_chat().get_id();
+    if should_ignore_chat(chat).await? {
+        return Ok(());
+    }
+
+    let mut upload = ChunkedUpload::new(chat, "fbans", format, FBAN_EXPORT_CHUNK);
+    let mut pages = fbans::Entity::find()
+        .filter(fbans::Column::Federation.eq(fed.fed_id))
+        .find_also_related(users::Entity)
+        .order_by_asc(fbans::Column::User)
+        .paginate(*DB, FBAN_EXPORT_PAGE);
+    while let Some(page) = pages.fetch_and_next().await? {
+        for (fban, user) in page {
+            upload.push(&FbanExportItem::new(fban, user)).await?;
+        }
+    }
+    if upload.finish().await? == 0 {
+        return ctx.fail(lang_fmt!(ctx, "fexportempty"));
+    }
+    Ok(())
+}
+
+/// A single fban read from a /multifban file
+#[derive(Deserialize, Debug, PartialEq)]
+struct MultiFbanItem {
+    user_id: i64,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Entries read from a /multifban file, along with the line or item numbers of entries
+/// that could not be used
+#[derive(Debug, Default)]
+struct MultiFbanList {
+    items: Vec<MultiFbanItem>,
+    invalid: Vec<usize>,
+    duplicates: usize,
+}
+
+impl MultiFbanList {
+    fn push(&mut self, line: usize, item: Option<MultiFbanItem>) {
+        match item {
+            // user ids are always positive
+            Some(item) if item.user_id > 0 => self.items.push(MultiFbanItem {
+                user_id: item.user_id,
+                reason: item
+                    .reason
+                    .map(|v| v.trim().to_owned())
+                    .and_then(|v| v.none_if_empty()),
+            }),
+            _ => self.invalid.push(line),
+        }
+    }
+
+    /// Drop repeated user ids, keeping the first entry for each
+    fn dedup(mut self) -> Self {
+        let mut seen = HashSet::new();
+        let len = self.items.len();
+        self.items.retain(|v| seen.insert(v.user_id));
+        self.duplicates = len - self.items.len();
+        self
+    }
+}
 
-        Ok(res)
+/// Parse a /multifban file. Json can be an array or a stream of objects like Rose bot's
+/// exports, anything else is read as csv with a user id and optional reason per line
+fn parse_fban_file(text: &str) -> Result<MultiFbanList> {
+    let text = text.trim();
+    let mut list = MultiFbanList::default();
+    if text.starts_with('[') || text.starts_with('{') {
+        let values = if text.starts_with('[') {
+            serde_json::from_str::<Vec<serde_json::Value>>(text)?
+        } else {
+            serde_json::Deserializer::from_str(text)
+                .into_iter::<serde_json::Value>()
+                .collect::<serde_json::Result<Vec<serde_json::Value>>>()?
+        };
+        for (i, value) in values.into_iter().enumerate() {
+            list.push(i + 1, serde_json::from_value(value).ok());
+        }
     } else {
-        ctx.fail("Message is not a file")
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (user, reason) = line
+                .split_once(',')
+                .map(|(user, reason)| (user, Some(reason.trim().trim_matches('"').to_owned())))
+                .unwrap_or((line, None));
+            match user.trim().parse::<i64>() {
+                Ok(user_id) => list.push(i + 1, Some(MultiFbanItem { user_id, reason })),
+                // allow a header row
+                Err(_) if i == 0 => (),
+                Err(_) => list.push(i + 1, None),
+            }
+        }
     }
+    Ok(list.dedup())
 }
 
-async fn import_fbans(ctx: &Context) -> Result<()> {
+async fn edit_progress(progress: &Option<Message>, text: &str) -> Result<()> {
+    if let Some(progress) = progress {
+        TG.client()
+            .build_edit_message_text(text)
+            .message_id(progress.get_message_id())
+            .chat_id(progress.get_chat().get_id())
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Fban a batch of users, returning the number fbanned, the number already fbanned, and
+/// the number that failed
+async fn apply_fban_batch(fed: &Uuid, batch: &[MultiFbanItem]) -> Result<(usize, usize, usize)> {
+    let existing = fbans::Entity::find()
+        .filter(fbans::Column::Federation.eq(*fed))
+        .filter(fbans::Column::User.is_in(batch.iter().map(|v| v.user_id)))
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| v.user)
+        .collect::<HashSet<i64>>();
+    let (mut applied, mut failed) = (0, 0);
+    for item in batch.iter().filter(|v| !existing.contains(&v.user_id)) {
+        let user = match item.user_id.get_cached_user().await? {
+            Some(user) => user,
+            None => UserBuilder::new(item.user_id, false, item.user_id.to_string()).build(),
+        };
+        let mut model = fbans::Model::new(&user, *fed);
+        model.reason = item.reason.clone();
+        match fban_user(model, &user).await {
+            Ok(()) => applied += 1,
+            Err(err) => {
+                log::warn!("multifban failed for {}: {}", item.user_id, err);
+                err.record_stats();
+                failed += 1;
+            }
+        }
+    }
+    Ok((applied, existing.len(), failed))
+}
+
+async fn multifban(ctx: &Context) -> Result<()> {
     let message = ctx.message()?;
     if message.get_sender_chat().is_some() {
-        return ctx.fail(lang_fmt!(ctx, "anonfed"));
+        return ctx.fail(lang_fmt!(ctx, "anonban"));
     }
-    if let Some(user) = message.get_from() {
-        let user = user.get_id();
-        ctx.action_message(|ctx, message, _| async move {
-            if let Some(fed) = get_fed(user).await? {
-                let res = set_fban_list(ctx, &fed.fed_id, message.message()).await?;
-                ctx.reply(format!("Successfully imported {} fbans", res))
-                    .await?;
+    let Some(issuer) = message.get_from() else {
+        return Ok(());
+    };
+    let chat = ctx.try_get()?.chat;
+    let Some(fed) = is_fedmember(chat.get_id()).await? else {
+        return ctx.fail(lang_fmt!(ctx, "notinfed", chat.name_humanreadable()));
+    };
+    let owner = get_fed(issuer.get_id()).await?.map(|v| v.fed_id) == Some(fed);
+    if !owner
+        && !is_fedadmin(issuer.get_id(), &fed).await?
+        && ctx.check_permissions(|p| p.is_support).await.is_err()
+    {
+        return ctx.fail(lang_fmt!(ctx, "notfedadmin", fed.to_string()));
+    }
+
+    ctx.action_message(|ctx, message, _| async move {
+        let Some(document) = message.message().get_document() else {
+            return ctx.fail(lang_fmt!(ctx, "multifbannofile"));
+        };
+        let text = document.get_text().await?;
+        let list = parse_fban_file(&text)
+            .speak_err(ctx, |e| lang_fmt!(ctx, "multifbanparse", e))
+            .await?;
+        if list.items.is_empty() {
+            return ctx.fail(lang_fmt!(ctx, "multifbanempty"));
+        }
+
+        let total = list.items.len();
+        let progress = ctx
+            .reply(lang_fmt!(ctx, "multifbanprogress", 0, total))
+            .await?;
+        let (mut applied, mut existing, mut failed) = (0, 0, 0);
+        for (i, batch) in list.items.chunks(MULTIFBAN_BATCH).enumerate() {
+            let (a, e, f) = apply_fban_batch(&fed, batch).await?;
+            applied += a;
+            existing += e;
+            failed += f;
+            let done = (i * MULTIFBAN_BATCH + batch.len()).min(total);
+            if done < total {
+                edit_progress(&progress, &lang_fmt!(ctx, "multifbanprogress", done, total)).await?;
             }
+        }
 
-            Ok(())
-        })
-        .await?;
-    }
+        let mut summary = lang_fmt!(
+            ctx,
+            "multifbansummary",
+            fed.to_string(),
+            applied,
+            existing,
+            list.duplicates,
+            list.invalid.len(),
+            failed
+        );
+        if !list.invalid.is_empty() {
+            let lines = list.invalid.iter().take(MULTIFBAN_INVALID_SHOWN).join(", ");
+            summary.push_str(&lang_fmt!(ctx, "multifbaninvalid", lines));
+        }
+        if progress.is_some() {
+            edit_progress(&progress, &summary).await?;
+        } else {
+            ctx.reply(summary).await?;
+        }
+        Ok(())
+    })
+    .await?;
     Ok(())
 }
 
-async fn export_fbans(ctx: &Context) -> Result<()> {
+async fn fedshare_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
     let message = ctx.message()?;
     if message.get_sender_chat().is_some() {
         return ctx.fail(lang_fmt!(ctx, "anonfed"));
     }
+    let Some(owner) = message.get_from() else {
+        return Ok(());
+    };
+    let fed = get_fed(owner.get_id())
+        .await?
+        .ok_or_else(|| ctx.fail_err(lang_fmt!(ctx, "nofed")))?;
+    let args = args
+        .args
+        .iter()
+        .map(|v| v.get_text())
+        .collect::<Vec<&str>>();
+    let enabled = match args.get(1) {
+        Some(&"on") | Some(&"yes") => Some(true),
+        Some(&"off") | Some(&"no") => Some(false),
+        _ => None,
+    };
+    let sharing = match (args.first(), enabled) {
+        (None, _) => get_sharing(&fed.fed_id).await?,
+        (Some(&"publish"), Some(enabled)) => {
+            Some(set_sharing(&fed.fed_id, Some(enabled), None).await?)
+        }
+        (Some(&"consume"), Some(enabled)) => {
+            Some(set_sharing(&fed.fed_id, None, Some(enabled)).await?)
+        }
+        _ => return ctx.fail(lang_fmt!(ctx, "fedshareinvalid")),
+    };
+    let (publish, consume) = sharing
+        .map(|v| (v.publish, v.consume))
+        .unwrap_or((false, false));
+    let onoff = |v: bool| if v { "on" } else { "off" };
+    ctx.reply(lang_fmt!(
+        ctx,
+        "fedsharestatus",
+        fed.fed_id,
+        onoff(publish),
+        onoff(consume)
+    ))
+    .await?;
+    Ok(())
+}
+
+/// Get the current chat's federation, failing unless the sender is its owner, one of its
+/// admins, or support
+async fn chat_fed_as_admin(ctx: &Context) -> Result<Uuid> {
+    let message = ctx.message()?;
+    let Some(issuer) = message
+        .get_from()
+        .filter(|_| message.get_sender_chat().is_none())
+    else {
+        return ctx.fail(lang_fmt!(ctx, "anonfed"));
+    };
+    let chat = ctx.try_get()?.chat;
+    let Some(fed) = is_fedmember(chat.get_id()).await? else {
+        return ctx.fail(lang_fmt!(ctx, "notinfed", chat.name_humanreadable()));
+    };
+    let owner = get_fed(issuer.get_id()).await?.map(|v| v.fed_id) == Some(fed);
+    if !owner
+        && !is_fedadmin(issuer.get_id(), &fed).await?
+        && ctx.check_permissions(|p| p.is_support).await.is_err()
+    {
+        return ctx.fail(lang_fmt!(ctx, "notfedadmin", fed.to_string()));
+    }
+    Ok(fed)
+}
 
-    if let Some(user) = message.get_from() {
-        if let Some(fed) = get_fed(user.get_id()).await? {
-            let export = get_fban_list(&fed.fed_id).await?;
-            let export = serde_json::to_string(&export)?;
-            let bytes = FileData::Part(Part::text(export).file_name("export.json"));
-            if !should_ignore_chat(message.get_chat().get_id()).await? {
-                TG.client
-                    .build_send_document(message.get_chat().get_id(), bytes)
-                    .build()
-                    .await?;
-            }
+async fn fedrisk_cmd(ctx: &Context) -> Result<()> {
+    let fed = chat_fed_as_admin(ctx).await?;
+    if !get_sharing(&fed).await?.map(|v| v.consume).unwrap_or(false) {
+        return ctx.fail(lang_fmt!(ctx, "fedriskoff"));
+    }
+
+    ctx.action_user(|ctx, user, _| async move {
+        if !try_risk_lookup(&fed).await? {
+            return ctx.fail(lang_fmt!(ctx, "fedriskrate"));
+        }
+        let score = get_risk_score(&fed, user).await?;
+        if score.sources == 0 {
+            ctx.reply_fmt(entity_fmt!(ctx, "fedrisknone", user.mention().await?))
+                .await?;
         } else {
-            return ctx.fail(lang_fmt!(ctx, "nofed"));
+            ctx.reply_fmt(entity_fmt!(
+                ctx,
+                "fedrisk",
+                user.mention().await?,
+                score.sources.to_string(),
+                score.describe()
+            ))
+            .await?;
+        }
+        Ok(())
+    })
+    .await
+    .speak_err_raw(ctx, |v| match v {
+        BotError::UserNotFound => Some(lang_fmt!(ctx, "failuser", "check risk for")),
+        _ => None,
+    })
+    .await?;
+    Ok(())
+}
+
+/// Get the fingerprints a fed media command applies to, from the replied media or the
+/// command's arguments
+async fn media_fingerprints(ctx: &Context, args: &TextArgs<'_>) -> Result<Vec<String>> {
+    let message = ctx.message()?;
+    let fingerprints = if let Some(reply) = message.get_reply_to_message() {
+        message_fingerprints(reply).await?
+    } else {
+        let fingerprints = args
+            .args
+            .iter()
+            .map(|v| v.get_text().to_owned())
+            .collect::<Vec<String>>();
+        if let Some(invalid) = fingerprints.iter().find(|v| !is_valid_fingerprint(v)) {
+            return ctx.fail(lang_fmt!(ctx, "fedmediainvalid", invalid.escape(false)));
         }
+        fingerprints
+    };
+    if fingerprints.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "fedmediausage"));
+    }
+    Ok(fingerprints)
+}
+
+async fn fblockmedia_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let fed = chat_fed_as_admin(ctx).await?;
+    let message = ctx.message()?;
+    let fingerprints = media_fingerprints(ctx, args).await?;
+    let blocked = get_fed_media(&fed).await?;
+    let adding = fingerprints.iter().filter(|v| !blocked.contains(v)).count();
+    check_quota(
+        message,
+        ctx.lang(),
+        Quota::FedMedia,
+        blocked.len() as u64,
+        adding as u64,
+    )?;
+    let issuer = message.get_from().map(|v| v.get_id()).unwrap_or_default();
+    add_fed_media(&fed, &fingerprints, issuer).await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "fedmediablocked",
+        fingerprints.len(),
+        fed.to_string()
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn funblockmedia_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let fed = chat_fed_as_admin(ctx).await?;
+    let fingerprints = media_fingerprints(ctx, args).await?;
+    let removed = remove_fed_media(&fed, &fingerprints).await?;
+    if removed == 0 {
+        return ctx.fail(lang_fmt!(ctx, "fedmedianotblocked"));
+    }
+    ctx.reply(lang_fmt!(
+        ctx,
+        "fedmediaunblocked",
+        removed,
+        fed.to_string()
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn fblockedmedia_cmd(ctx: &Context) -> Result<()> {
+    let fed = chat_fed_as_admin(ctx).await?;
+    let blocked = get_fed_media(&fed).await?;
+    if blocked.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "fedmedianone", fed.to_string()))
+            .await?;
+        return Ok(());
+    }
+    let mut list = blocked
+        .iter()
+        .sorted()
+        .take(FED_MEDIA_SHOWN)
+        .map(|v| format!("- {}", v.escape(false)))
+        .join("\n");
+    if blocked.len() > FED_MEDIA_SHOWN {
+        list.push_str(&lang_fmt!(
+            ctx,
+            "fedmediamore",
+            blocked.len() - FED_MEDIA_SHOWN
+        ));
+    }
+    ctx.reply(lang_fmt!(ctx, "fedmedialist", fed.to_string(), list))
+        .await?;
+    Ok(())
+}
+
+/// Delete media blocked by the current chat's federation
+async fn check_blocked_media(ctx: &Context) -> Result<()> {
+    if ctx.cmd().is_some() {
+        return Ok(());
     }
+    let Some(message) = ctx.moderated_message().await else {
+        return Ok(());
+    };
+    let chat = message.get_chat();
+    let Some(fed) = is_fedmember(chat.get_id()).await? else {
+        return Ok(());
+    };
+    let Some(fingerprint) = blocked_media(&fed, message).await? else {
+        return Ok(());
+    };
+    message.delete().await?;
+    let sender = match (message.get_from(), message.get_sender_chat()) {
+        (_, Some(sender)) => sender.name_humanreadable().into_owned(),
+        (Some(user), None) => user.name_humanreadable().into_owned(),
+        (None, None) => chat.name_humanreadable().into_owned(),
+    };
+    post_log(
+        chat,
+        lang_fmt!(
+            ctx,
+            "fedmedialog",
+            sender,
+            fed.to_string(),
+            fingerprint.escape(false)
+        ),
+    )
+    .await?;
     Ok(())
 }
 
@@ -400,11 +975,81 @@ pub async fn handle_update(ctx: &Context) -> Result<()> {
             "renamefed" => rename_fed(ctx, args).await,
             "subfed" => subfed_cmd(ctx, args).await,
             "fstat" => fstat_cmd(ctx).await,
-            "fedexport" => export_fbans(ctx).await,
-            "fedimport" => import_fbans(ctx).await,
+            "fexport" | "fedexport" => export_fbans(ctx, args).await,
+            "fimport" | "fedimport" => import_fbans(ctx).await,
+            "multifban" => multifban(ctx).await,
+            "fedshare" => fedshare_cmd(ctx, args).await,
+            "fedrisk" => fedrisk_cmd(ctx).await,
+            "fblockmedia" => fblockmedia_cmd(ctx, args).await,
+            "funblockmedia" => funblockmedia_cmd(ctx, args).await,
+            "fblockedmedia" => fblockedmedia_cmd(ctx).await,
             _ => Ok(()),
         }?;
     }
+    check_blocked_media(ctx).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_csv() {
+        let list = parse_fban_file("user_id,reason\n1,spam\n2\nbad,line\n1,again\n-5,x\n").unwrap();
+        assert_eq!(
+            list.items,
+            vec![
+                MultiFbanItem {
+                    user_id: 1,
+                    reason: Some("spam".to_owned())
+                },
+                MultiFbanItem {
+                    user_id: 2,
+                    reason: None
+                },
+            ]
+        );
+        assert_eq!(list.invalid, vec![4, 6]);
+        assert_eq!(list.duplicates, 1);
+    }
+
+    #[test]
+    fn parse_json() {
+        let list = parse_fban_file(r#"[{"user_id": 3, "reason": ""}, {"id": 4}]"#).unwrap();
+        assert_eq!(
+            list.items,
+            vec![MultiFbanItem {
+                user_id: 3,
+                reason: None
+            }]
+        );
+        assert_eq!(list.invalid, vec![2]);
+
+        let list = parse_fban_file(
+            r#"{"user_id": 5, "first_name": "a", "last_name": "", "reason": "raid"}
+            {"user_id": 6, "first_name": "b", "last_name": "", "reason": ""}"#,
+        )
+        .unwrap();
+        assert_eq!(list.items.len(), 2);
+        assert_eq!(list.items[0].reason.as_deref(), Some("raid"));
+    }
+
+    #[test]
+    fn export_row() {
+        let row = FbanExportItem::from_csv(vec![
+            "7".to_owned(),
+            "first".to_owned(),
+            "".to_owned(),
+            "spam, raid".to_owned(),
+        ])
+        .unwrap();
+        assert_eq!(row.user_id, 7);
+        assert_eq!(row.reason, "spam, raid");
+        assert_eq!(row.to_csv()[3], "spam, raid");
+        assert!(FbanExportItem::from_csv(vec!["user_id".to_owned()]).is_none());
+        let row: FbanExportItem = serde_json::from_str(r#"{"user_id": 8}"#).unwrap();
+        assert_eq!(row.first_name, "");
+    }
+}
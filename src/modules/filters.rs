@@ -5,6 +5,7 @@ use crate::metadata::ModuleHelpers;
 
 use crate::persist::core::entity;
 use crate::persist::core::media::get_media_type;
+use crate::persist::core::media::MediaType;
 use crate::persist::core::media::SendMediaReply;
 use crate::persist::redis::RedisStr;
 use crate::persist::redis::ToRedisStr;
@@ -13,14 +14,20 @@ use crate::statics::DB;
 use crate::statics::REDIS;
 use crate::tg::button::InlineKeyboardBuilder;
 use crate::tg::command::*;
+use crate::tg::exemptions::is_from_ignored_bot;
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
 use crate::tg::markdown::get_markup_for_buttons;
+use crate::tg::markdown::Escape;
 use crate::tg::markdown::Header;
 use crate::tg::markdown::MarkupBuilder;
 use crate::tg::markdown::MarkupType;
 use crate::tg::permissions::*;
+use crate::tg::quotas::{check_quota, Quota};
+use crate::tg::rosemd::{RoseMdDecompiler, RoseMdParser};
 use crate::util::error::BotError;
 use crate::util::error::Fail;
 use crate::util::error::Result;
+use crate::util::error::SpeakErr;
 use crate::util::string::AlignCharBoundry;
 use crate::util::string::Speak;
 use botapi::gen_types::Message;
@@ -38,10 +45,12 @@ use sea_orm::sea_query::OnConflict;
 use sea_orm::ColumnTrait;
 use sea_orm::EntityTrait;
 use sea_orm::IntoActiveModel;
+use sea_orm::PaginatorTrait;
 use sea_orm::QueryFilter;
 use sea_orm::QuerySelect;
 use sea_orm::RelationTrait;
 use sea_orm::TransactionTrait;
+use serde::{Deserialize, Serialize};
 
 use sea_orm_migration::{MigrationName, MigrationTrait};
 
@@ -51,10 +60,12 @@ metadata!("Filters",
     about how the bot is "alive" or an "AI"
     "#,
     Helper,
-    { command = "filter", help = "\\<trigger\\> \\<reply\\>: Trigger a reply when soemone says something" },
+    { category = "Content" },
+    { command = "filter", help = "\\<trigger\\> \\<reply\\>: Trigger a reply when soemone says something", level = Admin },
     { command = "filters", help = "List all filters" },
-    { command = "stop", help = "Stop a filter" },
-    { command = "stopall", help = "Stop all filters" }
+    { command = "stop", help = "Stop a filter", level = Admin },
+    { command = "stopall", help = "Stop all filters", level = Admin },
+    { updates = [Message] }
 );
 
 struct Migration;
@@ -521,31 +532,120 @@ pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
 #[derive(Debug)]
 struct Helper;
 
+/// A filter and its triggers, with formatting and buttons written as rose markdown
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportFilter {
+    triggers: Vec<String>,
+    text: Option<String>,
+    media_id: Option<String>,
+    media_type: MediaType,
+}
+
 #[async_trait::async_trait]
 impl ModuleHelpers for Helper {
-    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
-        Ok(None)
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let res = filters::get_filters_join(filters::Column::Chat.eq(chat)).await?;
+        let filters = res
+            .into_iter()
+            .map(|(filter, (entities, buttons, triggers))| {
+                let entities = entities
+                    .into_iter()
+                    .map(|v| v.get())
+                    .map(|(k, v)| k.to_entity(v))
+                    .collect_vec();
+                let buttons = get_markup_for_buttons(buttons.into_iter().collect())
+                    .unwrap_or_default()
+                    .build();
+                let text = filter.text.map(|text| {
+                    RoseMdDecompiler::new(&text, &entities, buttons.get_inline_keyboard())
+                        .decompile()
+                });
+                ExportFilter {
+                    triggers: triggers.into_iter().map(|v| v.trigger).sorted().collect(),
+                    text,
+                    media_id: filter.media_id,
+                    media_type: filter.media_type,
+                }
+            })
+            .collect_vec();
+        Ok(Some(serde_json::to_value(filters)?))
     }
 
-    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let filters: Vec<ExportFilter> = serde_json::from_value(value)?;
+        filters::Entity::delete_many()
+            .filter(filters::Column::Chat.eq(chat))
+            .exec(*DB)
+            .await?;
+        for filter in filters {
+            if filter.triggers.is_empty() {
+                continue;
+            }
+            let (text, entity_id) = if let Some(text) = filter.text {
+                let (text, entities, buttons) = RoseMdParser::new(&text, true).parse();
+                (Some(text), entity::insert(*DB, &entities, buttons).await?)
+            } else {
+                (None, None)
+            };
+            let model = filters::ActiveModel {
+                id: ActiveValue::NotSet,
+                chat: ActiveValue::Set(chat),
+                text: ActiveValue::Set(text),
+                media_id: ActiveValue::Set(filter.media_id),
+                media_type: ActiveValue::Set(filter.media_type),
+                entity_id: ActiveValue::Set(entity_id),
+            };
+            let model = filters::Entity::insert(model)
+                .exec_with_returning(*DB)
+                .await?;
+            triggers::Entity::insert_many(filter.triggers.into_iter().map(|trigger| {
+                triggers::Model {
+                    trigger: trigger.to_lowercase(),
+                    filter_id: model.id,
+                }
+                .into_active_model()
+            }))
+            .on_conflict(
+                OnConflict::columns([triggers::Column::Trigger, triggers::Column::FilterId])
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(*DB)
+            .await?;
+        }
+        let key = filter_hash_key(chat);
+        REDIS.invalidate(&[&key]).await?;
         Ok(())
     }
 
     fn supports_export(&self) -> Option<&'static str> {
-        None
+        Some("filters")
     }
 
     fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
         get_migrations()
     }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        // rose bot's filters section has a different layout
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
 }
 
 fn get_filter_key(message: &Message, id: i64) -> String {
     format!("filter:{}:{}", message.get_chat().get_id(), id)
 }
 
+fn filter_hash_key(chat: i64) -> String {
+    format!("fcache:{}", chat)
+}
+
 fn get_filter_hash_key(message: &Message) -> String {
-    format!("fcache:{}", message.get_chat().get_id())
+    filter_hash_key(message.get_chat().get_id())
 }
 
 async fn delete_trigger(ctx: &Context, trigger: &str) -> Result<()> {
@@ -745,8 +845,15 @@ async fn command_filter<'a>(c: &Context, args: &TextArgs<'a>) -> Result<()> {
                     .set_text(text)
                     .filling(false)
                     .header(true)
-                    .build_filter()
-                    .await;
+                    .try_build_filter()
+                    .await
+                    .speak_err_raw(&ctx, |v| match v {
+                        BotError::MurkdownError(err) => {
+                            Some(lang_fmt!(ctx, "failmurkat", err.to_string().escape(false)))
+                        }
+                        _ => None,
+                    })
+                    .await?;
 
                 let filters = match header
                     .ok_or_else(|| ctx.fail_err("Header missing from filter command"))?
@@ -769,6 +876,26 @@ async fn command_filter<'a>(c: &Context, args: &TextArgs<'a>) -> Result<()> {
                     return ctx.fail(lang_fmt!(ctx, "emptynotallowed"));
                 }
 
+                let existing = triggers::Entity::find()
+                    .join(
+                        sea_query::JoinType::InnerJoin,
+                        triggers::Relation::Filters.def(),
+                    )
+                    .filter(
+                        filters::Column::Chat
+                            .eq(message.get_chat().get_id())
+                            .and(triggers::Column::Trigger.is_not_in(triggers.as_slice())),
+                    )
+                    .count(tx)
+                    .await?;
+                check_quota(
+                    message,
+                    ctx.lang(),
+                    Quota::Filters,
+                    existing,
+                    triggers.len() as u64,
+                )?;
+
                 let (f, message) = if let Some(message) = message.get_reply_to_message() {
                     (message.get_text().map(|v| v.to_owned()), message)
                 } else {
@@ -878,6 +1005,9 @@ async fn command_filter<'a>(c: &Context, args: &TextArgs<'a>) -> Result<()> {
 
 async fn handle_trigger(ctx: &Context) -> Result<()> {
     let message = ctx.message()?;
+    if is_from_ignored_bot(message).await? {
+        return Ok(());
+    }
     if let Some(text) = message.get_text() {
         if let Some((res, extra_entities, extra_buttons)) = search_cache(message, text).await? {
             SendMediaReply::new(ctx, res.media_type)
@@ -920,7 +1050,7 @@ async fn stopall(ctx: &Context) -> Result<()> {
         .await?;
 
     let key = get_filter_hash_key(message);
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     ctx.reply("Stopped all filters").await?;
     Ok(())
 }
@@ -0,0 +1,539 @@
+use self::entities::flair_tiers;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::tg::bridges::bridged_author;
+use crate::tg::command::{Cmd, Context, PopSlice, TextArgs};
+use crate::tg::flair::{delete_chat_flair, get_flair, get_flair_model, set_flair};
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
+use crate::tg::markdown::Escape;
+use crate::tg::optout::{is_opted_out, OptOut};
+use crate::tg::permissions::*;
+use crate::tg::user::{GetUser, Username};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::{Message, UpdateExt};
+use chrono::Duration;
+use macros::{entity_fmt, lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::OnConflict;
+use serde::{Deserialize, Serialize};
+
+metadata!("Flair",
+    r#"
+    Give active members a cosmetic title as they post. Once flair is turned on, members
+    earn a new flair each time their message count in this chat passes a threshold. Flair is
+    shown in /info and can be added to welcomes, notes, and other messages with the
+    \{flair\} filling.
+
+    Thresholds start out as a few defaults and can be changed with /flairtier. Changed
+    thresholds apply the next time a member passes one. Members who opted out with
+    /optout stats aren't counted.
+
+    [*Example:]
+    /flair on
+    /flairtier 1000 Night owl
+    "#,
+    Helper,
+    { category = "Fun" },
+    { command = "flair", help = "Usage: flair \\[on/off\\]. Shows your flair and message count, or the flair of the user you reply to. Admins can turn flair on or off" },
+    { command = "flairtier", help = "Usage: flairtier \\<messages\\> \\<title\\>. Sets the flair earned after a number of messages", level = Admin },
+    { command = "rmflairtier", help = "Usage: rmflairtier \\<messages\\>. Removes a flair threshold", level = Admin },
+    { command = "flairtiers", help = "Lists the flair that can be earned in this chat" },
+    { command = "flairtop", help = "Shows the most active members of this chat" },
+    { updates = [Message] }
+);
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(flair_tiers::Entity)
+                        .col(
+                            ColumnDef::new(flair_tiers::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(flair_tiers::Column::Messages)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(flair_tiers::Column::Title).text().not_null())
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(flair_tiers::Column::Chat)
+                                .col(flair_tiers::Column::Messages)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(flair_tiers::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod flair_tiers {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// A flair earned after posting a number of messages. Flair is on in every chat
+        /// with at least one tier
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "flair_tiers")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub messages: i64,
+            pub title: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000037_create_flair_tiers"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// A flair tier as stored in backups, without the chat
+#[derive(Serialize, Deserialize)]
+struct ExportTier {
+    messages: i64,
+    title: String,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let tiers = get_tiers(chat)
+            .await?
+            .into_iter()
+            .map(|v| ExportTier {
+                messages: v.messages,
+                title: v.title,
+            })
+            .collect::<Vec<ExportTier>>();
+        Ok(Some(serde_json::to_value(tiers)?))
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let tiers: Vec<ExportTier> = serde_json::from_value(value)?;
+        flair_tiers::Entity::delete_many()
+            .filter(flair_tiers::Column::Chat.eq(chat))
+            .exec(*DB)
+            .await?;
+        let tiers = tiers
+            .into_iter()
+            .filter(|v| v.messages > 0)
+            .take(MAX_TIERS)
+            .map(|v| {
+                flair_tiers::Model {
+                    chat,
+                    messages: v.messages,
+                    title: v.title,
+                }
+                .into_active_model()
+            })
+            .collect::<Vec<_>>();
+        if !tiers.is_empty() {
+            flair_tiers::Entity::insert_many(tiers)
+                .on_conflict(
+                    OnConflict::columns([flair_tiers::Column::Chat, flair_tiers::Column::Messages])
+                        .do_nothing()
+                        .to_owned(),
+                )
+                .exec_without_returning(*DB)
+                .await?;
+        }
+        let key = get_tiers_key(chat);
+        REDIS.invalidate(&[&key]).await?;
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("flair")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+}
+
+/// Tiers added when flair is turned on
+const DEFAULT_TIERS: [(i64, &str); 4] = [
+    (100, "Regular"),
+    (500, "Veteran"),
+    (2000, "Elder"),
+    (10000, "Legend"),
+];
+
+/// Most tiers a chat can have
+const MAX_TIERS: usize = 20;
+
+/// Longest title allowed for a tier
+const MAX_TITLE_LEN: usize = 32;
+
+/// Members shown by /flairtop
+const TOP_MEMBERS: isize = 10;
+
+#[inline(always)]
+fn get_tiers_key(chat: i64) -> String {
+    format!("flairtiers:{}", chat)
+}
+
+#[inline(always)]
+fn get_counts_key(chat: i64) -> String {
+    format!("flaircount:{}", chat)
+}
+
+/// Get the flair tiers of a chat, lowest first. Empty if flair is off
+async fn get_tiers(chat: i64) -> Result<Vec<flair_tiers::Model>> {
+    let tiers = default_cache_query(
+        |_, _| async move {
+            let res = flair_tiers::Entity::find()
+                .filter(flair_tiers::Column::Chat.eq(chat))
+                .order_by_asc(flair_tiers::Column::Messages)
+                .all(*DB)
+                .await?;
+            Ok(Some(res))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_tiers_key(chat), &())
+    .await?;
+    Ok(tiers.unwrap_or_default())
+}
+
+/// Get the highest tier reached with a message count
+fn tier_for(tiers: &[flair_tiers::Model], count: i64) -> Option<&flair_tiers::Model> {
+    tiers.iter().rev().find(|v| v.messages <= count)
+}
+
+async fn save_tier(chat: i64, messages: i64, title: String) -> Result<()> {
+    flair_tiers::Entity::insert(
+        flair_tiers::Model {
+            chat,
+            messages,
+            title,
+        }
+        .into_active_model(),
+    )
+    .on_conflict(
+        OnConflict::columns([flair_tiers::Column::Chat, flair_tiers::Column::Messages])
+            .update_column(flair_tiers::Column::Title)
+            .to_owned(),
+    )
+    .exec_without_returning(*DB)
+    .await?;
+    let key = get_tiers_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(())
+}
+
+/// Turn flair on with the default tiers, keeping any tiers already set
+async fn enable_flair(chat: i64) -> Result<()> {
+    flair_tiers::Entity::insert_many(DEFAULT_TIERS.iter().map(|(messages, title)| {
+        flair_tiers::Model {
+            chat,
+            messages: *messages,
+            title: (*title).to_owned(),
+        }
+        .into_active_model()
+    }))
+    .on_conflict(
+        OnConflict::columns([flair_tiers::Column::Chat, flair_tiers::Column::Messages])
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(*DB)
+    .await?;
+    let key = get_tiers_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(())
+}
+
+/// Turn flair off, removing the tiers along with every member's flair and count
+async fn disable_flair(chat: i64) -> Result<()> {
+    flair_tiers::Entity::delete_many()
+        .filter(flair_tiers::Column::Chat.eq(chat))
+        .exec(*DB)
+        .await?;
+    delete_chat_flair(chat).await?;
+    let (tiers, counts) = (get_tiers_key(chat), get_counts_key(chat));
+    REDIS.invalidate(&[&tiers, &counts]).await?;
+    Ok(())
+}
+
+/// Count a message towards its sender's flair, awarding a new flair when they pass a tier.
+/// Counts live in redis and are saved whenever a flair is earned, so a cleared redis
+/// picks up from the last flair instead of starting over
+async fn count_message(ctx: &Context, message: &Message) -> Result<()> {
+    let chat = message.get_chat().get_id();
+    let Some(sender) = message.get_from() else {
+        return Ok(());
+    };
+    if sender.get_is_bot() {
+        return Ok(());
+    }
+    let tiers = get_tiers(chat).await?;
+    if tiers.is_empty() {
+        return Ok(());
+    }
+    let user = match bridged_author(message).await? {
+        Some(author) => author.get_id(),
+        None => sender.get_id(),
+    };
+    if is_opted_out(user, OptOut::Stats).await? {
+        return Ok(());
+    }
+    let key = get_counts_key(chat);
+    let mut count: i64 = REDIS.sq(|q| q.zincr(&key, user, 1)).await?;
+    if count == 1 {
+        if let Some(saved) = get_flair_model(chat, user)
+            .await?
+            .filter(|v| v.messages > 0)
+        {
+            count = REDIS.sq(|q| q.zincr(&key, user, saved.messages)).await?;
+        }
+    }
+    let Some(tier) = tier_for(&tiers, count) else {
+        return Ok(());
+    };
+    if tier_for(&tiers, count - 1).map(|v| v.messages) == Some(tier.messages) {
+        return Ok(());
+    }
+    set_flair(chat, user, count, Some(tier.title.clone())).await?;
+    let mention = user.mention().await?;
+    ctx.reply_fmt(entity_fmt!(ctx, "flairearned", mention, tier.title.clone()))
+        .await?;
+    Ok(())
+}
+
+async fn flair_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => {
+            ctx.check_permissions(|p| p.can_change_info).await?;
+            enable_flair(chat).await?;
+            ctx.reply(lang_fmt!(ctx, "flairon")).await?;
+        }
+        Some("off") | Some("no") => {
+            ctx.check_permissions(|p| p.can_change_info).await?;
+            disable_flair(chat).await?;
+            ctx.reply(lang_fmt!(ctx, "flairoff")).await?;
+        }
+        Some(_) => {
+            return ctx.fail(lang_fmt!(ctx, "flairinvalid"));
+        }
+        None => {
+            if get_tiers(chat).await?.is_empty() {
+                return ctx.fail(lang_fmt!(ctx, "flairdisabled"));
+            }
+            let Some(user) = message
+                .get_reply_to_message()
+                .and_then(|v| v.get_from())
+                .or_else(|| message.get_from())
+            else {
+                return Ok(());
+            };
+            let user = user.get_id();
+            let key = get_counts_key(chat);
+            let count: Option<i64> = REDIS.sq(|q| q.zscore(&key, user)).await?;
+            let flair = match get_flair(chat, user).await? {
+                Some(flair) => flair.escape(false).into_owned(),
+                None => lang_fmt!(ctx, "flairnone"),
+            };
+            let name = user.cached_name().await?;
+            ctx.reply(lang_fmt!(
+                ctx,
+                "flairshow",
+                name.escape(false),
+                count.unwrap_or(0),
+                flair
+            ))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn flair_tier(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some((messages, title)) = args.pop_slice() else {
+        return ctx.fail(lang_fmt!(ctx, "flairtierusage", MAX_TITLE_LEN));
+    };
+    let title = title.text.trim();
+    let Some(messages) = messages.get_text().parse::<i64>().ok().filter(|v| *v > 0) else {
+        return ctx.fail(lang_fmt!(ctx, "flairtierusage", MAX_TITLE_LEN));
+    };
+    if title.is_empty() || title.chars().count() > MAX_TITLE_LEN {
+        return ctx.fail(lang_fmt!(ctx, "flairtierusage", MAX_TITLE_LEN));
+    }
+    let tiers = get_tiers(chat).await?;
+    if tiers.len() >= MAX_TIERS && !tiers.iter().any(|v| v.messages == messages) {
+        return ctx.fail(lang_fmt!(ctx, "flairtierlimit", MAX_TIERS));
+    }
+    save_tier(chat, messages, title.to_owned()).await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "flairtiersaved",
+        title.escape(false),
+        messages
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn rm_flair_tier(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let Some(messages) = args
+        .args
+        .first()
+        .and_then(|v| v.get_text().parse::<i64>().ok())
+    else {
+        return ctx.fail(lang_fmt!(ctx, "rmflairtierusage"));
+    };
+    let chat = ctx.message()?.get_chat().get_id();
+    let res = flair_tiers::Entity::delete_by_id((chat, messages))
+        .exec(*DB)
+        .await?;
+    let key = get_tiers_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    if res.rows_affected > 0 {
+        ctx.reply(lang_fmt!(ctx, "flairtierremoved", messages))
+            .await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "flairtiernotfound", messages))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn list_tiers(ctx: &Context) -> Result<()> {
+    let chat = ctx.message()?.get_chat().get_id();
+    let tiers = get_tiers(chat).await?;
+    if tiers.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "flairdisabled"));
+    }
+    let m = [lang_fmt!(ctx, "flairtiers")]
+        .into_iter()
+        .chain(
+            tiers
+                .iter()
+                .map(|v| format!("- {}: {}", v.messages, v.title.escape(false))),
+        )
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(m).await?;
+    Ok(())
+}
+
+async fn flair_top(ctx: &Context) -> Result<()> {
+    let chat = ctx.message()?.get_chat().get_id();
+    if get_tiers(chat).await?.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "flairdisabled"));
+    }
+    let key = get_counts_key(chat);
+    let top: Vec<(i64, i64)> = REDIS
+        .sq(|q| q.zrevrange_withscores(&key, 0, TOP_MEMBERS - 1))
+        .await?;
+    if top.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "flairtopempty"));
+    }
+    let mut lines = vec![lang_fmt!(ctx, "flairtop")];
+    for (i, (user, count)) in top.into_iter().enumerate() {
+        let name = user.cached_name().await?;
+        let line = match get_flair(chat, user).await? {
+            Some(flair) => format!(
+                "{}\\. {}: {} \\({}\\)",
+                i + 1,
+                name.escape(false),
+                count,
+                flair.escape(false)
+            ),
+            None => format!("{}\\. {}: {}", i + 1, name.escape(false), count),
+        };
+        lines.push(line);
+    }
+    ctx.reply(lines.join("\n")).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "flair" => flair_cmd(ctx, args).await?,
+            "flairtier" => flair_tier(ctx, args).await?,
+            "rmflairtier" => rm_flair_tier(ctx, args).await?,
+            "flairtiers" => list_tiers(ctx).await?,
+            "flairtop" => flair_top(ctx).await?,
+            _ => (),
+        };
+    }
+    if let UpdateExt::Message(ref message) = ctx.update() {
+        count_message(ctx, message).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tiers() {
+        let tiers = DEFAULT_TIERS
+            .iter()
+            .map(|(messages, title)| flair_tiers::Model {
+                chat: 1,
+                messages: *messages,
+                title: (*title).to_owned(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tier_for(&tiers, 99), None);
+        assert_eq!(tier_for(&tiers, 100).map(|v| v.messages), Some(100));
+        assert_eq!(tier_for(&tiers, 1999).map(|v| v.messages), Some(500));
+        assert_eq!(tier_for(&tiers, 50000).map(|v| v.messages), Some(10000));
+    }
+}
@@ -1,20 +1,28 @@
 use macros::{lang_fmt, update_handler};
 
 use crate::persist::admin::gbans;
-use crate::tg::command::{Cmd, Context};
+use crate::tg::admin_helpers::set_enforce_gbans;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_dialog;
 use crate::tg::federations::gban_user;
 use crate::tg::permissions::IsGroupAdmin;
 use crate::tg::user::GetUser;
-use crate::util::error::{BotError, Result, SpeakErr};
+use crate::util::error::{BotError, Fail, Result, SpeakErr};
 use crate::{metadata::metadata, util::string::Speak};
 
 metadata!("Global Bans",
     r#"
     Global bans \(gbans\) ban a user across every chat the bot is in. This is a drastic action
     and therefore can only be taken by support users or the owner of the bot.
+
+    The bot can also be configured to sync gbans from remote ban lists. Admins who would rather
+    not have gbanned users banned in their chat can turn this off with /gbanstat off.
     "#,
-    { command = "gban", help = "Ban a user in all chats" },
-    { command = "ungban", help = "Unban a user in all chats" }
+    { category = "Moderation" },
+    { command = "gban", help = "Ban a user in all chats", level = Owner },
+    { command = "ungban", help = "Unban a user in all chats", level = Owner },
+    { command = "gbanstat", help = "Usage: gbanstat \\<on/off\\>. Sets whether gbanned users are banned in this chat", level = Admin, requires = [CanChangeInfo] },
+    { updates = [Message] }
 );
 
 async fn ungban(ctx: &Context) -> Result<()> {
@@ -63,12 +71,41 @@ async fn gban(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+async fn gbanstat(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat();
+    match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => {
+            set_enforce_gbans(chat, true).await?;
+            ctx.reply(lang_fmt!(ctx, "gbanstaton")).await?;
+        }
+        Some("off") | Some("no") => {
+            set_enforce_gbans(chat, false).await?;
+            ctx.reply(lang_fmt!(ctx, "gbanstatoff")).await?;
+        }
+        None => {
+            let enforced = get_dialog(chat)
+                .await?
+                .map(|v| v.enforce_gbans)
+                .unwrap_or(true);
+            if enforced {
+                ctx.reply(lang_fmt!(ctx, "gbanstaton")).await?;
+            } else {
+                ctx.reply(lang_fmt!(ctx, "gbanstatoff")).await?;
+            }
+        }
+        Some(_) => return ctx.fail(lang_fmt!(ctx, "gbanstatusage")),
+    }
+    Ok(())
+}
+
 #[update_handler]
 pub async fn handle_update(ctx: &Context) -> Result<()> {
-    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
         match cmd {
             "gban" => gban(ctx).await,
             "ungban" => ungban(ctx).await,
+            "gbanstat" => gbanstat(ctx, args).await,
             _ => Ok(()),
         }?;
     }
@@ -0,0 +1,543 @@
+use self::entities::{giveaway_entries, giveaways};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::statics::{spawn, CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::{UpdateHelpers, UserChanged};
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{Cmd, Context, PopSlice, TextArgs};
+use crate::tg::markdown::EntityMessage;
+use crate::tg::permissions::*;
+use crate::tg::user::Username;
+use crate::util::error::{BotError, Fail, Result};
+use crate::util::scheduler::{register_job, schedule};
+use crate::util::string::{get_chat_lang, Speak};
+use botapi::gen_types::{
+    CallbackQuery, EReplyMarkup, InlineKeyboardButton, InlineKeyboardButtonBuilder,
+};
+use chrono::{DateTime, Duration, Utc};
+use futures::FutureExt;
+use macros::{lang_fmt, update_handler};
+use rand::seq::SliceRandom;
+use redis::AsyncCommands;
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{
+    ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder, TransactionTrait,
+};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use uuid::Uuid;
+
+metadata!("Giveaways",
+    r#"
+    Run giveaways in this chat. Members enter by pressing a button under the giveaway
+    message, and winners are drawn at random when it ends. To keep alt accounts out, very new
+    accounts and users who only just joined the chat can't enter.
+
+    Example: /giveaway 2d 3 a signed copy of TAOCP
+    "#,
+    Helper,
+    { category = "Fun" },
+    { command = "giveaway", help = "Usage: giveaway \\<time\\> \\[winners\\] \\<prize\\>. Starts a giveaway", level = Admin },
+    { command = "endgiveaway", help = "Draw the winners of the latest giveaway now", level = Admin },
+    { updates = [Message, ChatMember] }
+);
+
+/// Scheduler job kind for drawing a giveaway
+const GIVEAWAY_JOB: &str = "giveaway";
+
+/// How long join times are remembered for the membership check
+const JOIN_RECORD_SECONDS: i64 = 60 * 60 * 24 * 90;
+
+/// Approximate creation dates of telegram user ids. Ids are assigned roughly in order, so
+/// this is enough to tell a week old account from a years old one, nothing more
+const ID_DATES: [(i64, i64); 8] = [
+    (100_000_000, 1420070400),   // 2015-01
+    (500_000_000, 1514764800),   // 2018-01
+    (1_000_000_000, 1572566400), // 2019-11
+    (1_500_000_000, 1606780800), // 2020-12
+    (2_000_000_000, 1622505600), // 2021-06
+    (5_000_000_000, 1638316800), // 2021-12
+    (6_000_000_000, 1672531200), // 2023-01
+    (7_000_000_000, 1709251200), // 2024-03
+];
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(giveaways::Entity)
+                        .col(
+                            ColumnDef::new(giveaways::Column::Id)
+                                .big_integer()
+                                .primary_key()
+                                .auto_increment(),
+                        )
+                        .col(
+                            ColumnDef::new(giveaways::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(giveaways::Column::Prize).text().not_null())
+                        .col(
+                            ColumnDef::new(giveaways::Column::Winners)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(giveaways::Column::Ends)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(giveaways::Column::Button).text().not_null())
+                        .col(ColumnDef::new(giveaways::Column::Message).big_integer())
+                        .col(
+                            ColumnDef::new(giveaways::Column::Open)
+                                .boolean()
+                                .not_null()
+                                .default(true),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_table(
+                    Table::create()
+                        .table(giveaway_entries::Entity)
+                        .col(
+                            ColumnDef::new(giveaway_entries::Column::Giveaway)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(giveaway_entries::Column::User)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(giveaway_entries::Column::Giveaway)
+                                .col(giveaway_entries::Column::User)
+                                .primary(),
+                        )
+                        .foreign_key(
+                            ForeignKey::create()
+                                .from(giveaway_entries::Entity, giveaway_entries::Column::Giveaway)
+                                .to(giveaways::Entity, giveaways::Column::Id)
+                                .on_delete(ForeignKeyAction::Cascade),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(giveaway_entries::Entity).await?;
+            manager.drop_table_auto(giveaways::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod giveaways {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "giveaways")]
+        pub struct Model {
+            #[sea_orm(primary_key, autoincrement = true)]
+            pub id: i64,
+            pub chat: i64,
+            pub prize: String,
+            pub winners: i32,
+            pub ends: DateTimeUtc,
+            /// callback data of the entry button, so it can be registered again on restart
+            pub button: String,
+            pub message: Option<i64>,
+            pub open: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod giveaway_entries {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "giveaway_entries")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub giveaway: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub user: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000021_create_giveaways"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(GIVEAWAY_JOB, |payload| async move {
+            let id: i64 = serde_json::from_value(payload)?;
+            draw(id).await
+        });
+        // buttons only live in memory, open giveaways need theirs back after a restart
+        spawn(async move {
+            if let Err(err) = restore_buttons().await {
+                log::warn!("failed to restore giveaway buttons: {}", err);
+            }
+        });
+    }
+}
+
+#[inline(always)]
+fn get_join_key(chat: i64) -> String {
+    format!("gajoin:{}", chat)
+}
+
+/// Estimate when a telegram account was created from its id. Ids newer than the table
+/// are extrapolated from its last two entries, never past the current time
+fn estimate_created(user: i64) -> DateTime<Utc> {
+    let (first_id, first_time) = ID_DATES[0];
+    let (prev_id, prev_time) = ID_DATES[ID_DATES.len() - 2];
+    let (last_id, last_time) = ID_DATES[ID_DATES.len() - 1];
+    let time = if user <= first_id {
+        first_time
+    } else if user >= last_id {
+        let extrapolated = last_time.saturating_add(
+            (user - last_id).saturating_mul(last_time - prev_time) / (last_id - prev_id),
+        );
+        extrapolated.min(Utc::now().timestamp())
+    } else {
+        ID_DATES
+            .windows(2)
+            .find(|v| user < v[1].0)
+            .map(|v| {
+                let ((id_a, time_a), (id_b, time_b)) = (v[0], v[1]);
+                time_a + (user - id_a) * (time_b - time_a) / (id_b - id_a)
+            })
+            .unwrap_or(last_time)
+    };
+    DateTime::from_timestamp(time, 0).unwrap_or_else(Utc::now)
+}
+
+/// Remember when users join so giveaways can turn away users who joined just to enter
+async fn record_join(ctx: &Context) -> Result<()> {
+    let Some(UserChanged::UserJoined(member)) = ctx.update().user_event() else {
+        return Ok(());
+    };
+    let key = get_join_key(member.get_chat().get_id());
+    let user = member.get_from().get_id();
+    REDIS
+        .pipe(|q| {
+            q.hset(&key, user, Utc::now().timestamp())
+                .ignore()
+                .expire(&key, JOIN_RECORD_SECONDS)
+                .ignore()
+        })
+        .await?;
+    Ok(())
+}
+
+fn register_entry(id: i64, button: &InlineKeyboardButton) {
+    button.on_push_multi(move |callback| enter(id, callback));
+}
+
+async fn restore_buttons() -> Result<()> {
+    let open = giveaways::Entity::find()
+        .filter(giveaways::Column::Open.eq(true))
+        .all(*DB)
+        .await?;
+    for giveaway in open {
+        let button = InlineKeyboardButtonBuilder::new(String::new())
+            .set_callback_data(giveaway.button)
+            .build();
+        register_entry(giveaway.id, &button);
+    }
+    Ok(())
+}
+
+/// Handle a press of the entry button. Returns true once the giveaway is over so the
+/// button is dropped
+async fn enter(id: i64, callback: CallbackQuery) -> Result<bool> {
+    let Some(giveaway) = giveaways::Entity::find_by_id(id).one(*DB).await? else {
+        return Ok(true);
+    };
+    let lang = get_chat_lang(giveaway.chat).await?;
+    let user = callback.get_from().get_id();
+    let key = get_join_key(giveaway.chat);
+    let joined: Option<i64> = REDIS.sq(|q| q.hget(&key, user)).await?;
+    let min_age = Duration::try_days(CONFIG.giveaways.min_account_days).unwrap();
+    let min_member = Duration::try_hours(CONFIG.giveaways.min_member_hours).unwrap();
+    let now = Utc::now();
+
+    // users who joined before the bot started recording joins count as old members
+    let (text, done) = if !giveaway.open {
+        (lang_fmt!(lang, "giveawayclosed"), true)
+    } else if now - estimate_created(user) < min_age {
+        (lang_fmt!(lang, "giveawaynewaccount"), false)
+    } else if joined.is_some_and(|v| now.timestamp() - v < min_member.num_seconds()) {
+        (lang_fmt!(lang, "giveawaynewmember"), false)
+    } else {
+        let inserted = giveaway_entries::Entity::insert(giveaway_entries::ActiveModel {
+            giveaway: Set(id),
+            user: Set(user),
+        })
+        .on_conflict(
+            OnConflict::columns([
+                giveaway_entries::Column::Giveaway,
+                giveaway_entries::Column::User,
+            ])
+            .do_nothing()
+            .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+        if inserted > 0 {
+            (lang_fmt!(lang, "giveawayentered", giveaway.prize), false)
+        } else {
+            (lang_fmt!(lang, "giveawayalready"), false)
+        }
+    };
+    TG.client()
+        .build_answer_callback_query(callback.get_id())
+        .text(&text)
+        .show_alert(true)
+        .build()
+        .await?;
+    Ok(done)
+}
+
+/// Close a giveaway and announce its winners. Does nothing if it was already drawn
+async fn draw(id: i64) -> Result<()> {
+    // closing only commits once the winners are announced, so a failed announcement leaves
+    // the giveaway open to be drawn again. The closed row stays locked until then, so only
+    // one of the scheduled job and /endgiveaway gets to close it
+    DB.transaction::<_, (), BotError>(|tx| {
+        async move {
+            let closed = giveaways::Entity::update_many()
+                .col_expr(giveaways::Column::Open, Expr::value(false))
+                .filter(giveaways::Column::Id.eq(id))
+                .filter(giveaways::Column::Open.eq(true))
+                .exec(tx)
+                .await?;
+            if closed.rows_affected == 0 {
+                return Ok(());
+            }
+            let Some(giveaway) = giveaways::Entity::find_by_id(id).one(tx).await? else {
+                return Ok(());
+            };
+            let entries = giveaway_entries::Entity::find()
+                .filter(giveaway_entries::Column::Giveaway.eq(id))
+                .all(tx)
+                .await?
+                .into_iter()
+                .map(|v| v.user)
+                .collect::<Vec<i64>>();
+            let winners = {
+                let mut rng = rand::thread_rng();
+                entries
+                    .choose_multiple(&mut rng, giveaway.winners.max(1) as usize)
+                    .copied()
+                    .collect::<Vec<i64>>()
+            };
+
+            let lang = get_chat_lang(giveaway.chat).await?;
+            let mut message = EntityMessage::new(giveaway.chat);
+            if winners.is_empty() {
+                message
+                    .builder
+                    .text(lang_fmt!(lang, "giveawaynoentries", giveaway.prize));
+            } else {
+                message
+                    .builder
+                    .bold(lang_fmt!(lang, "giveawaywinners", giveaway.prize));
+                for winner in winners {
+                    let mention = winner.mention().await?;
+                    message.builder.text("\n").regular(mention);
+                }
+            }
+
+            if let Some(message) = giveaway.message {
+                if let Err(err) = TG
+                    .client()
+                    .build_edit_message_reply_markup()
+                    .message_id(message)
+                    .chat_id(giveaway.chat)
+                    .build()
+                    .await
+                {
+                    log::warn!("failed to remove giveaway button: {}", err);
+                }
+            }
+            giveaway.chat.speak_fmt(message).await?;
+            giveaway_entries::Entity::delete_many()
+                .filter(giveaway_entries::Column::Giveaway.eq(id))
+                .exec(tx)
+                .await?;
+            Ok(())
+        }
+        .boxed()
+    })
+    .await?;
+    Ok(())
+}
+
+async fn giveaway(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(duration) = ctx.parse_duration(&Some(args.as_slice()))? else {
+        return ctx.fail(lang_fmt!(ctx, "giveawayinvalid"));
+    };
+    let Some((_, rest)) = args.pop_slice() else {
+        return ctx.fail(lang_fmt!(ctx, "giveawayinvalid"));
+    };
+    let (winners, prize) = match rest
+        .pop_slice()
+        .and_then(|(first, tail)| first.get_text().parse::<usize>().ok().map(|v| (v, tail)))
+    {
+        Some((winners, tail)) => (winners, tail.text),
+        None => (1, rest.text),
+    };
+    let winners = winners.clamp(1, CONFIG.giveaways.max_winners.max(1));
+    let prize = prize.trim();
+    if prize.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "giveawayinvalid"));
+    }
+
+    let ends = Utc::now() + duration;
+    let button = InlineKeyboardButtonBuilder::new(lang_fmt!(ctx, "giveawayenter"))
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+    let model = giveaways::Entity::insert(giveaways::ActiveModel {
+        id: NotSet,
+        chat: Set(chat),
+        prize: Set(prize.to_owned()),
+        winners: Set(winners as i32),
+        ends: Set(ends),
+        button: Set(button.get_callback_data().unwrap_or_default().to_owned()),
+        message: Set(None),
+        open: Set(true),
+    })
+    .exec_with_returning(*DB)
+    .await?;
+    register_entry(model.id, &button);
+
+    let mut markup = InlineKeyboardBuilder::default();
+    markup.button(button);
+    let mut message =
+        EntityMessage::new(chat).reply_markup(EReplyMarkup::InlineKeyboardMarkup(markup.build()));
+    message
+        .builder
+        .bold(lang_fmt!(ctx, "giveawaystarted", prize))
+        .text(lang_fmt!(
+            ctx,
+            "giveawaydetails",
+            winners,
+            ends.format("%Y-%m-%d %H:%M UTC")
+        ));
+    let sent = ctx.reply_fmt(message).await?;
+
+    let id = model.id;
+    let mut model = model.into_active_model();
+    model.message = Set(sent.map(|v| v.get_message_id()));
+    giveaways::Entity::update(model).exec(*DB).await?;
+    schedule(GIVEAWAY_JOB, &id, ends).await?;
+    Ok(())
+}
+
+async fn endgiveaway(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(giveaway) = giveaways::Entity::find()
+        .filter(giveaways::Column::Chat.eq(chat))
+        .filter(giveaways::Column::Open.eq(true))
+        .order_by_desc(giveaways::Column::Id)
+        .one(*DB)
+        .await?
+    else {
+        return ctx.fail(lang_fmt!(ctx, "giveawaynone"));
+    };
+    // the scheduled draw finds it closed and does nothing
+    draw(giveaway.id).await
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "giveaway" => giveaway(ctx, args).await?,
+            "endgiveaway" => endgiveaway(ctx).await?,
+            _ => (),
+        }
+    }
+    record_join(ctx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn account_age_estimate() {
+        let old = estimate_created(10_000_000);
+        let mid = estimate_created(1_250_000_000);
+        let last = estimate_created(7_000_000_000);
+        let new = estimate_created(7_500_000_000);
+        let future = estimate_created(i64::MAX);
+        assert!(old < mid && mid < last && last < new);
+        assert_eq!(old.timestamp(), ID_DATES[0].1);
+        assert_eq!(last.timestamp(), ID_DATES[ID_DATES.len() - 1].1);
+        assert!(future <= Utc::now());
+        assert!(Utc::now() - future < Duration::try_minutes(1).unwrap());
+        assert!(mid.timestamp() > ID_DATES[2].1 && mid.timestamp() < ID_DATES[3].1);
+    }
+}
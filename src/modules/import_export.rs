@@ -1,35 +1,125 @@
 use botapi::gen_types::{EReplyMarkup, FileData};
+use bytes::Bytes;
 use convert_case::{Case, Casing};
 use itertools::Itertools;
-use macros::{lang_fmt, update_handler};
+use macros::update_handler;
 use reqwest::multipart::Part;
-use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use sea_orm_migration::MigrationTrait;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use uuid::Uuid;
 
-use crate::metadata::metadata;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::core::mirrored_media::MirrorCategory;
 use crate::persist::core::taint;
+use crate::persist::mirror::mirror_bytes;
 use crate::statics::{DB, TG};
-use crate::tg::admin_helpers::FileGetter;
+use crate::tg::admin_helpers::{
+    set_ban_time, set_greet_flood_limit, set_moderate_edits, set_mute_time, set_rate_limits,
+    set_response_ttl, set_rules_gate,
+};
 use crate::tg::command::{Cmd, Context, TextArgs};
-use crate::tg::dialog::ConversationState;
+use crate::tg::dialog::{get_dialog, ConversationState};
+use crate::tg::import_export::{start_import, ROSE_EXPORT_VERSION};
 use crate::tg::markdown::EntityMessage;
 use crate::tg::permissions::IsGroupAdmin;
-use crate::tg::user::Username;
+use crate::tg::user::{GetChat, Username};
 use crate::util::error::{Fail, Result};
-use crate::util::string::{should_ignore_chat, Speak};
+use crate::util::string::{set_chat_lang, should_ignore_chat, Lang, Speak};
 
-use super::{all_export, all_import};
+use super::all_export;
 
 metadata!("Import/Export",
     r#"
     Import and export data from select modules in a format compatible with a certain feminine
     flower-based bot on telegram.
+
+    Exports are versioned backups covering chat settings, warns, filters, notes, rules, and
+    welcomes. Backups made by older versions of the bot are upgraded as they are imported, and
+    sections only this bot understands are skipped when importing another bot's backup.
     "#,
-    { command = "import", help = "Import data for the current chat" },
-    { command = "export", help = "Export data for the current chat"}
+    Helper,
+    { category = "Chat Management" },
+    { command = "import", help = "Import data for the current chat", level = Admin },
+    { command = "export", help = "Export data for the current chat", level = Admin},
+    { updates = [Message] }
 );
 
+/// Chat settings stored with the chat itself rather than by a single module
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportSettings {
+    language: Lang,
+    greet_flood_limit: i32,
+    mute_time: Option<i64>,
+    ban_time: Option<i64>,
+    rules_gate: bool,
+    moderate_edits: bool,
+    response_ttl: Option<i64>,
+    ratelimit_chat: Option<i32>,
+    ratelimit_user: Option<i32>,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let Some(chat) = chat.get_chat().await? else {
+            return Ok(None);
+        };
+        let Some(dialog) = get_dialog(&chat).await? else {
+            return Ok(None);
+        };
+        let settings = ExportSettings {
+            language: dialog.language,
+            greet_flood_limit: dialog.greet_flood_limit,
+            mute_time: dialog.mute_time,
+            ban_time: dialog.ban_time,
+            rules_gate: dialog.rules_gate,
+            moderate_edits: dialog.moderate_edits,
+            response_ttl: dialog.response_ttl,
+            ratelimit_chat: dialog.ratelimit_chat,
+            ratelimit_user: dialog.ratelimit_user,
+        };
+        Ok(Some(serde_json::to_value(settings)?))
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let settings: ExportSettings = serde_json::from_value(value)?;
+        let Some(chat) = chat.get_chat().await? else {
+            return Ok(());
+        };
+        set_chat_lang(&chat, settings.language).await?;
+        set_greet_flood_limit(&chat, settings.greet_flood_limit).await?;
+        set_mute_time(&chat, settings.mute_time).await?;
+        set_ban_time(&chat, settings.ban_time).await?;
+        set_rules_gate(&chat, settings.rules_gate).await?;
+        set_moderate_edits(&chat, settings.moderate_edits).await?;
+        set_response_ttl(&chat, settings.response_ttl).await?;
+        set_rate_limits(&chat, settings.ratelimit_chat, settings.ratelimit_user).await?;
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("settings")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        vec![]
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        // only this bot's own backups have chat settings
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+}
+
 #[allow(dead_code)]
 async fn get_taint<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     ctx.check_permissions(|p| p.can_manage_chat).await?;
@@ -160,9 +250,21 @@ pub async fn handle_update(ctx: &Context) -> Result<()> {
                 if !should_ignore_chat(message.get_chat().get_id()).await? {
                     let v = all_export(message.get_chat().get_id()).await?;
                     let out = serde_json::to_string_pretty(&v)?;
+                    if let Err(err) = mirror_bytes(
+                        message.get_chat().get_id(),
+                        MirrorCategory::Backup,
+                        "export.json",
+                        Bytes::from(out.clone()),
+                        "application/json",
+                    )
+                    .await
+                    {
+                        log::warn!("failed to mirror export: {}", err);
+                        err.record_stats();
+                    }
 
                     let bytes = FileData::Part(Part::text(out).file_name("export.txt"));
-                    TG.client
+                    TG.client()
                         .build_send_document(message.get_chat().get_id(), bytes)
                         .build()
                         .await?;
@@ -174,28 +276,8 @@ pub async fn handle_update(ctx: &Context) -> Result<()> {
                 ctx.action_message(|ctx, message, _| async move {
                     let message = message.message();
                     if let Some(file) = message.get_document() {
-                        let text = file.get_text().await?;
-                        all_import(message.get_chat().get_id(), &text).await?;
-                        let taint = taint::Entity::find()
-                            .filter(taint::Column::Chat.eq(message.get_chat().get_id()))
-                            .count(*DB)
-                            .await?;
-
-                        if taint == 0 {
-                            ctx.reply(lang_fmt!(
-                                ctx,
-                                "imported",
-                                message.get_chat().name_humanreadable()
-                            ))
-                            .await?;
-                        } else {
-                            ctx.reply(lang_fmt!(
-                                ctx,
-                                "taintdetected",
-                                message.get_chat().name_humanreadable()
-                            ))
+                        start_import(message.get_chat().get_id(), file.get_file_id().to_owned())
                             .await?;
-                        }
                     } else {
                         ctx.reply("Please select a json file").await?;
                     }
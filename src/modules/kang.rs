@@ -0,0 +1,378 @@
+use self::entities::kang_packs;
+use crate::metadata::ModuleHelpers;
+use crate::statics::{DB, TG, USERNAME};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::permissions::IsAdmin;
+use crate::tg::user::Username;
+use crate::util::error::{Fail, Result, SpeakErr};
+use crate::{metadata::metadata, util::string::Speak};
+use botapi::gen_types::{FileData, InputSticker, InputStickerBuilder, Message};
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Kang",
+    r#"
+    Copy stickers into your own sticker packs. Each user gets their own packs for every chat,
+    and a new pack is started when one fills up. Stickers keep their format, images must be
+    PNG or WEBP files with one side of 512 pixels and videos must be WEBM.
+    "#,
+    Helper,
+    { category = "Fun" },
+    { command = "kang", help = "Usage: kang \\[emoji\\]. Reply to a sticker or image to add it to your pack" },
+    { command = "delsticker", help = "Reply to a sticker from one of your packs to remove it" },
+    { command = "kangpacks", help = "List your packs for this chat" },
+    { updates = [Message] }
+);
+
+/// Most stickers telegram allows in a single pack
+const PACK_SIZE: i32 = 120;
+
+/// Emoji attached to kanged stickers if none was given
+const DEFAULT_EMOJI: &str = "⭐";
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(kang_packs::Entity)
+                        .col(
+                            ColumnDef::new(kang_packs::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(kang_packs::Column::User)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(kang_packs::Column::Number)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(kang_packs::Column::Name)
+                                .text()
+                                .not_null()
+                                .unique_key(),
+                        )
+                        .col(ColumnDef::new(kang_packs::Column::Title).text().not_null())
+                        .col(
+                            ColumnDef::new(kang_packs::Column::Count)
+                                .integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(kang_packs::Column::Chat)
+                                .col(kang_packs::Column::User)
+                                .col(kang_packs::Column::Number)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(kang_packs::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod kang_packs {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "kang_packs")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub user: i64,
+            /// packs are numbered from 0, a new one is created when the last is full
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub number: i32,
+            /// sticker set name used with the bot api
+            #[sea_orm(unique)]
+            pub name: String,
+            pub title: String,
+            pub count: i32,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000017_create_kang_packs"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+/// Sticker set names may only use letters, digits, and single underscores, and have to
+/// end with the bot's username
+fn pack_name(chat: i64, user: i64, number: i32, bot: &str) -> String {
+    format!("c{}u{}n{}_by_{}", chat.unsigned_abs(), user, number, bot)
+}
+
+fn pack_link(name: &str) -> String {
+    format!("https://t.me/addstickers/{}", name)
+}
+
+/// Get the file and sticker format for a message that can be kanged. Stickers are reused
+/// by file id so their format never needs converting
+fn sticker_source(message: &Message) -> Option<(String, &'static str, Option<&str>)> {
+    if let Some(sticker) = message.get_sticker() {
+        let format = if sticker.get_is_animated() {
+            "animated"
+        } else if sticker.get_is_video() {
+            "video"
+        } else {
+            "static"
+        };
+        Some((
+            sticker.get_file_id().to_owned(),
+            format,
+            sticker.get_emoji(),
+        ))
+    } else if let Some(document) = message.get_document() {
+        let format = match document.get_mime_type() {
+            Some("image/png") | Some("image/webp") => "static",
+            Some("video/webm") => "video",
+            _ => return None,
+        };
+        Some((document.get_file_id().to_owned(), format, None))
+    } else {
+        None
+    }
+}
+
+async fn last_pack(chat: i64, user: i64) -> Result<Option<kang_packs::Model>> {
+    let pack = kang_packs::Entity::find()
+        .filter(
+            kang_packs::Column::Chat
+                .eq(chat)
+                .and(kang_packs::Column::User.eq(user)),
+        )
+        .order_by_desc(kang_packs::Column::Number)
+        .one(*DB)
+        .await?;
+    Ok(pack)
+}
+
+async fn set_count(pack: &kang_packs::Model, count: i32) -> Result<()> {
+    kang_packs::Entity::update(kang_packs::ActiveModel {
+        chat: Set(pack.chat),
+        user: Set(pack.user),
+        number: Set(pack.number),
+        name: NotSet,
+        title: NotSet,
+        count: Set(count),
+    })
+    .exec(*DB)
+    .await?;
+    Ok(())
+}
+
+async fn kang(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let message = ctx.message()?;
+    let user = ctx.get_real_from()?;
+    let Some((file, format, emoji)) = message.get_reply_to_message().and_then(sticker_source)
+    else {
+        return ctx.fail(lang_fmt!(ctx, "kangreply"));
+    };
+    let emoji = args
+        .args
+        .first()
+        .map(|v| v.get_text())
+        .or(emoji)
+        .unwrap_or(DEFAULT_EMOJI);
+    let sticker: InputSticker = InputStickerBuilder::new(
+        FileData::String(file),
+        format.to_owned(),
+        vec![emoji.to_owned()],
+    )
+    .build();
+    let chat = message.get_chat();
+    let lang = ctx.lang();
+
+    let pack = match last_pack(chat.get_id(), user.get_id()).await? {
+        Some(pack) if pack.count < PACK_SIZE => {
+            TG.client()
+                .build_add_sticker_to_set(user.get_id(), &pack.name, &sticker)
+                .build()
+                .await
+                .speak_err_code(chat, 400, |_| lang_fmt!(lang, "kangfailed"))
+                .await?;
+            set_count(&pack, pack.count + 1).await?;
+            pack
+        }
+        last => {
+            let number = last.map(|v| v.number + 1).unwrap_or(0);
+            let name = pack_name(chat.get_id(), user.get_id(), number, *USERNAME);
+            let title: String = lang_fmt!(
+                ctx,
+                "kangtitle",
+                user.name_humanreadable(),
+                chat.name_humanreadable(),
+                number + 1
+            )
+            .chars()
+            .take(64)
+            .collect();
+            TG.client()
+                .build_create_new_sticker_set(user.get_id(), &name, &title, &vec![sticker])
+                .build()
+                .await
+                .speak_err_code(chat, 400, |_| lang_fmt!(lang, "kangfailed"))
+                .await?;
+            let pack = kang_packs::Model {
+                chat: chat.get_id(),
+                user: user.get_id(),
+                number,
+                name,
+                title,
+                count: 1,
+            };
+            kang_packs::Entity::insert(pack.clone().into_active_model())
+                .exec(*DB)
+                .await?;
+            pack
+        }
+    };
+    ctx.reply(lang_fmt!(ctx, "kanged", pack_link(&pack.name)))
+        .await?;
+    Ok(())
+}
+
+async fn delsticker(ctx: &Context) -> Result<()> {
+    let message = ctx.message()?;
+    let user = ctx.get_real_from()?;
+    let Some(sticker) = message.get_reply_to_message().and_then(|v| v.get_sticker()) else {
+        return ctx.fail(lang_fmt!(ctx, "delstickerreply"));
+    };
+    let pack = match sticker.get_set_name() {
+        Some(name) => {
+            kang_packs::Entity::find()
+                .filter(kang_packs::Column::Name.eq(name))
+                .one(*DB)
+                .await?
+        }
+        None => None,
+    };
+    let Some(pack) = pack else {
+        return ctx.fail(lang_fmt!(ctx, "delstickernotmine"));
+    };
+    if pack.user != user.get_id() && !user.is_admin(message.get_chat()).await? {
+        return ctx.fail(lang_fmt!(ctx, "delstickernotmine"));
+    }
+    TG.client()
+        .build_delete_sticker_from_set(sticker.get_file_id())
+        .build()
+        .await?;
+    set_count(&pack, (pack.count - 1).max(0)).await?;
+    ctx.reply(lang_fmt!(ctx, "delsticker")).await?;
+    Ok(())
+}
+
+async fn kangpacks(ctx: &Context) -> Result<()> {
+    let message = ctx.message()?;
+    let user = ctx.get_real_from()?;
+    let packs = kang_packs::Entity::find()
+        .filter(
+            kang_packs::Column::Chat
+                .eq(message.get_chat().get_id())
+                .and(kang_packs::Column::User.eq(user.get_id())),
+        )
+        .order_by_asc(kang_packs::Column::Number)
+        .all(*DB)
+        .await?;
+    if packs.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "kangpacksnone")).await?;
+    } else {
+        let list = packs
+            .iter()
+            .map(|v| {
+                format!(
+                    "{} ({}/{}): {}",
+                    v.title,
+                    v.count,
+                    PACK_SIZE,
+                    pack_link(&v.name)
+                )
+            })
+            .join("\n");
+        ctx.reply(lang_fmt!(ctx, "kangpacks", list)).await?;
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "kang" => kang(ctx, args).await?,
+            "delsticker" => delsticker(ctx).await?,
+            "kangpacks" => kangpacks(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn valid_pack_name() {
+        let name = pack_name(-1001234, 42, 0, "dijkstrabot");
+        assert_eq!(name, "c1001234u42n0_by_dijkstrabot");
+        assert!(!name.contains("__"));
+        assert!(name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+    }
+}
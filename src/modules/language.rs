@@ -1,4 +1,4 @@
-use crate::statics::TG;
+use crate::statics::{spawn, TG};
 use crate::tg::command::{Cmd, Context};
 
 use crate::tg::user::{GetChat, RecordChat};
@@ -20,7 +20,9 @@ metadata! {
     r#"This bot supports automatic translations! Set the language for the current chat
     using this module
     "#,
-    { command = "setlang", help = "Set languge" }
+    { category = "Chat Management" },
+    { command = "setlang", help = "Set languge", level = Admin },
+    { updates = [Message] }
 }
 
 inline_lang! {
@@ -83,7 +85,7 @@ async fn get_lang_conversation(message: &Message, current: &Lang) -> Result<Conv
     state.state_callback(move |uuid, conv| {
         log::info!("conversation state {}", uuid);
         if uuid != start {
-            tokio::spawn(async move {
+            spawn(async move {
                 if let Err(err) = handle_terminal_state(uuid, conv, id, message_id).await {
                     log::warn!("terminal state error {}", err);
                     err.record_stats();
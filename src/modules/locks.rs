@@ -1,39 +1,58 @@
 use self::entities::{default_locks, locks};
 use crate::metadata::ModuleHelpers;
 use crate::persist::admin::actions::ActionType;
+use crate::persist::admin::captchastate::CaptchaType;
 use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
 use crate::statics::{CONFIG, DB, REDIS};
-use crate::tg::admin_helpers::{ban_message, is_approved, UpdateHelpers};
+use crate::tg::admin_helpers::{
+    ban_message, change_chat_permissions, set_greet_flood_limit, set_moderate_edits, UpdateHelpers,
+};
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
 use crate::tg::command::{Cmd, Context, TextArg, TextArgs};
-use crate::tg::dialog::is_chat_member;
+use crate::tg::dialog::{dialog_or_default, is_chat_member};
+use crate::tg::greetings::{get_chat_captcha_config, set_chat_captcha};
+use crate::tg::markdown::EntityMessage;
 use crate::tg::permissions::*;
+use crate::tg::template::ChatTemplate;
 use crate::tg::user::{get_user_username, Username};
 use crate::util::error::{BotError, Result};
+use crate::util::locale::Localize;
 use crate::util::string::{get_chat_lang, Lang};
 use crate::{metadata::metadata, statics::TG, util::string::Speak};
-use botapi::gen_types::{Chat, Message, UpdateExt};
+use botapi::gen_types::{
+    CallbackQuery, Chat, ChatPermissions, ChatPermissionsBuilder, EReplyMarkup,
+    InlineKeyboardButtonBuilder, MaybeInaccessibleMessage, Message, UpdateExt,
+};
 use chrono::Duration;
 use entities::locks::LockType;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use macros::{lang_fmt, update_handler};
-use redis::AsyncCommands;
 use sea_orm::prelude::*;
 use sea_orm::sea_query::OnConflict;
 use sea_orm::ActiveValue::{NotSet, Set};
 use sea_orm::EntityTrait;
 use sea_orm_migration::{MigrationName, MigrationTrait};
+use serde::Deserialize;
+use uuid::Uuid;
 
 metadata!("Locks",
     r#"
     Are blue star check mark users ruining your group with their endless pop-psychobabble and
     coin scams? Lock the group to keep the premiums out.
+
+    Messages matching a lock are deleted. Give a lock an action to also warn, mute, or ban the
+    sender, like /lock sticker warn. See /available for every lock.
     "#,
     Helper,
-    { command = "lock", help = "Engage a lock" },
-    { command = "unlock", help = "Disable a lock"},
+    { category = "Moderation" },
+    { command = "lock", help = "Engage a lock", level = Admin },
+    { command = "unlock", help = "Disable a lock", level = Admin},
     { command = "locks", help = "Get a list of active locks"},
-    { command = "lockaction", help = "Set the action when a user sends a locked item"}
+    { command = "lockaction", help = "Set the action when a user sends a locked item", level = Admin},
+    { command = "preset", help = "Usage: preset \\<strict/standard/open\\>. Shows the changes a bundle of permissions, locks, captcha, and join flood settings would make, with a button to apply them", level = Admin},
+    { command = "moderateedits", help = "Usage: moderateedits \\<on/off\\>. Check edited messages against locks and blocklists, so spam can't be edited into an old message. Only recent messages are checked", level = Admin},
+    { updates = [Message, EditedMessage] }
 );
 
 pub mod entities {
@@ -219,6 +238,16 @@ pub mod entities {
             InviteLink,
             #[sea_orm(num_value = 11)]
             ExtUsers,
+            #[sea_orm(num_value = 12)]
+            Gif,
+            #[sea_orm(num_value = 13)]
+            ViaBot,
+            #[sea_orm(num_value = 14)]
+            Game,
+            #[sea_orm(num_value = 15)]
+            Contact,
+            #[sea_orm(num_value = 16)]
+            Voice,
         }
 
         impl LockType {
@@ -235,8 +264,18 @@ pub mod entities {
                     Self::Sticker => "Stickers",
                     Self::InviteLink => "Links to groups or channels",
                     Self::ExtUsers => "Users not participating in this chat",
+                    Self::Gif => "Gifs",
+                    Self::ViaBot => "Messages sent through inline bots",
+                    Self::Game => "Games",
+                    Self::Contact => "Contacts",
+                    Self::Voice => "Voice messages",
                 }
             }
+
+            /// The bit for this lock in a chat's cached set of enabled locks
+            pub fn get_bit(&self) -> u64 {
+                1 << self.to_value()
+            }
         }
 
         #[derive(EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, PartialEq, Debug)]
@@ -313,10 +352,15 @@ macro_rules! locks {
             let mut locks = Vec::<LockType>::new();
             match update {
                 UpdateExt::Message(ref message) | UpdateExt::EditedMessage(ref message) => {
+                    let bits = get_lock_bits(message.get_chat().get_id()).await?;
+                    if bits == 0 {
+                        return Ok((action, locks));
+                    }
                     $(
                     $(
                     update_action(
                         message,
+                        bits,
                         $lock,
                         &mut action,
                         &mut locks,
@@ -329,6 +373,7 @@ macro_rules! locks {
                     $(
                     update_action_async(
                         message,
+                        bits,
                         $async_lock,
                         &mut action,
                         &mut locks,
@@ -356,10 +401,7 @@ macro_rules! locks {
                     .map(|v| ActionType::from_str(v.get_text(), chat, message.message_id).ok())
                     .flatten();
                 let arg = match args.args.first() {
-                    $(
-                    $( Some(TextArg::Arg($name)) => Some($lock))?
-                    $( Some(TextArg::Arg($async_name)) => Some($async_lock))?
-                    ),+
+                    Some(TextArg::Arg(name)) => locktype_from_name(name),
                     _ => None,
                 };
 
@@ -368,6 +410,16 @@ macro_rules! locks {
                 (None, None)
             }
         }
+
+        fn locktype_from_name(name: &str) -> Option<LockType> {
+            match name {
+                $(
+                $( $name => Some($lock))?
+                $( $async_name => Some($async_lock))?
+                ),+,
+                _ => None,
+            }
+        }
     };
 }
 
@@ -427,6 +479,13 @@ locks! {
         message.get_forward_origin().is_some()
     });
     lock!("sticker", "Stickers", LockType::Sticker, |message| message.get_sticker().is_some());
+    lock!("gif", "Gifs", LockType::Gif, |message| message.get_animation().is_some());
+    lock!("via_bot", "Messages sent through inline bots", LockType::ViaBot, |message| {
+        message.get_via_bot().is_some()
+    });
+    lock!("game", "Games", LockType::Game, |message| message.get_game().is_some());
+    lock!("contact", "Contacts", LockType::Contact, |message| message.get_contact().is_some());
+    lock!("voice", "Voice messages", LockType::Voice, |message| message.get_voice().is_some());
     async_lock!("invitelink", "Invite Links", LockType::InviteLink, |message| is_invite(message));
     async_lock!("external_users", "External Users", LockType::ExtUsers, |message| is_out_of_chat_user(message));
 
@@ -437,6 +496,11 @@ pub(crate) fn get_lock_key(chat: i64, locktype: &LockType) -> String {
     format!("lock:{}:{}", chat, locktype.get_name())
 }
 
+#[inline(always)]
+fn get_lock_bits_key(chat: i64) -> String {
+    format!("lockbits:{}", chat)
+}
+
 pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
     vec![Box::new(Migration), Box::new(MigrationActionType)]
 }
@@ -504,7 +568,7 @@ fn is_invite(message: &Message) -> BoxFuture<'_, Result<bool>> {
                     "mention" => {
                         if let Some(user) = message.get_text() {
                             //TODO: cache this manybe?
-                            return Ok(TG.client.get_chat(user.to_owned()).await.is_ok());
+                            return Ok(TG.client().get_chat(user.to_owned()).await.is_ok());
                         }
                     }
                     "url" => {
@@ -541,6 +605,40 @@ impl ModuleHelpers for Helper {
     fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
         get_migrations()
     }
+
+    async fn apply_template(&self, chat: &Chat, template: &ChatTemplate) -> Result<()> {
+        let Some(template) = template.section::<LockTemplate>("locks")? else {
+            return Ok(());
+        };
+        if let Some(name) = template.preset {
+            let Some(preset) = PRESETS.iter().find(|v| v.name == name) else {
+                return Err(BotError::generic(format!("unknown preset {}", name)));
+            };
+            apply_preset(chat, preset).await?;
+        }
+        for name in template.locked {
+            let Some(lock) = locktype_from_name(&name) else {
+                return Err(BotError::generic(format!("unknown lock {}", name)));
+            };
+            set_chat_lock(chat.get_id(), lock).await?;
+        }
+        if let Some(action) = template.action {
+            let action = ActionType::from_str_err(&action, || {
+                BotError::generic(format!("unknown action {}", action))
+            })?;
+            set_default_action(chat, action).await?;
+        }
+        Ok(())
+    }
+}
+
+/// The locks section of a chat template. The preset is applied first, then the locks
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LockTemplate {
+    preset: Option<String>,
+    locked: Vec<String>,
+    action: Option<String>,
 }
 
 async fn get_lock(message: &Message, locktype: LockType) -> Result<Option<locks::Model>> {
@@ -557,20 +655,46 @@ async fn get_lock(message: &Message, locktype: LockType) -> Result<Option<locks:
     .await
 }
 
+/// Get the locks enabled in a chat as a bitset, so messages in chats without a matching
+/// lock are passed without looking up each lock
+async fn get_lock_bits(chat: i64) -> Result<u64> {
+    let key = get_lock_bits_key(chat);
+    let res = default_cache_query(
+        |_, _| async move {
+            let bits = get_chat_locks(chat)
+                .await?
+                .iter()
+                .fold(0, |bits, lock| bits | lock.get_bit());
+            Ok(Some(bits))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.unwrap_or_default())
+}
+
 async fn clear_lock(message: &Message, locktype: LockType) -> Result<()> {
-    let chat = message.get_chat().get_id();
+    clear_chat_lock(message.get_chat().get_id(), locktype).await
+}
+
+async fn clear_chat_lock(chat: i64, locktype: LockType) -> Result<()> {
     let key = get_lock_key(chat, &locktype);
     locks::Entity::delete_by_id((chat, locktype))
         .exec(*DB)
         .await?;
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key, &get_lock_bits_key(chat)]).await?;
     Ok(())
 }
 
 async fn set_lock(message: &Message, locktype: LockType) -> Result<()> {
-    let key = get_lock_key(message.get_chat().get_id(), &locktype);
+    set_chat_lock(message.get_chat().get_id(), locktype).await
+}
+
+async fn set_chat_lock(chat: i64, locktype: LockType) -> Result<()> {
+    let key = get_lock_key(chat, &locktype);
     let model = locks::ActiveModel {
-        chat: Set(message.get_chat().get_id()),
+        chat: Set(chat),
         lock_type: Set(locktype),
         lock_action: NotSet,
         reason: NotSet,
@@ -584,6 +708,7 @@ async fn set_lock(message: &Message, locktype: LockType) -> Result<()> {
         .exec_with_returning(*DB)
         .await?;
     res.cache(key).await?;
+    REDIS.invalidate(&[&get_lock_bits_key(chat)]).await?;
     Ok(())
 }
 
@@ -638,9 +763,10 @@ async fn set_lock_action(
     locktype: LockType,
     lockaction: ActionType,
 ) -> Result<()> {
-    let key = get_lock_key(message.get_chat().get_id(), &locktype);
+    let chat = message.get_chat().get_id();
+    let key = get_lock_key(chat, &locktype);
     let model = locks::Model {
-        chat: message.get_chat().get_id(),
+        chat,
         lock_type: locktype,
         lock_action: Some(lockaction),
         reason: None,
@@ -653,6 +779,7 @@ async fn set_lock_action(
         )
         .exec(*DB)
         .await?;
+    REDIS.invalidate(&[&get_lock_bits_key(chat)]).await?;
     Ok(())
 }
 
@@ -741,6 +868,28 @@ async fn lock_action<'a>(message: &Message, args: &TextArgs<'a>) -> Result<()> {
     Ok(())
 }
 
+async fn moderate_edits<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
+    message
+        .check_permissions(|p| p.can_change_info.and(p.can_restrict_members))
+        .await?;
+    let enabled = match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => Ok(true),
+        Some("off") | Some("no") => Ok(false),
+        _ => Err(BotError::speak(
+            lang_fmt!(lang, "moderateeditsinvalid"),
+            message.get_chat().get_id(),
+            Some(message.message_id),
+        )),
+    }?;
+    set_moderate_edits(message.get_chat(), enabled).await?;
+    if enabled {
+        message.reply(lang_fmt!(lang, "moderateeditson")).await?;
+    } else {
+        message.reply(lang_fmt!(lang, "moderateeditsoff")).await?;
+    }
+    Ok(())
+}
+
 async fn cmd_available(ctx: &Context) -> Result<()> {
     let available = ["[*Available locks]:".to_owned()]
         .into_iter()
@@ -755,6 +904,320 @@ async fn cmd_available(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+/// A bundle of permissions, locks, captcha, and join flood settings applied with /preset
+struct Preset {
+    name: &'static str,
+    send_messages: bool,
+    /// photos, videos, audio, documents, and voice or video notes
+    send_media: bool,
+    send_polls: bool,
+    /// stickers, gifs, games, and inline bots
+    send_other: bool,
+    link_previews: bool,
+    invite_users: bool,
+    locks: &'static [LockType],
+    /// captcha mode and kick timeout in seconds, None to disable captcha
+    captcha: Option<(CaptchaType, Option<i64>)>,
+    greet_flood_limit: i32,
+}
+
+static PRESETS: &[Preset] = &[
+    Preset {
+        name: "strict",
+        send_messages: true,
+        send_media: false,
+        send_polls: false,
+        send_other: false,
+        link_previews: false,
+        invite_users: false,
+        locks: &[
+            LockType::Link,
+            LockType::InviteLink,
+            LockType::Forward,
+            LockType::AnonChannel,
+            LockType::ExtUsers,
+        ],
+        captcha: Some((CaptchaType::Text, Some(300))),
+        greet_flood_limit: 5,
+    },
+    Preset {
+        name: "standard",
+        send_messages: true,
+        send_media: true,
+        send_polls: true,
+        send_other: true,
+        link_previews: true,
+        invite_users: false,
+        locks: &[LockType::InviteLink, LockType::AnonChannel],
+        captcha: Some((CaptchaType::Button, Some(600))),
+        greet_flood_limit: 10,
+    },
+    Preset {
+        name: "open",
+        send_messages: true,
+        send_media: true,
+        send_polls: true,
+        send_other: true,
+        link_previews: true,
+        invite_users: true,
+        locks: &[],
+        captcha: None,
+        greet_flood_limit: 20,
+    },
+];
+
+impl Preset {
+    fn get_permissions(&self) -> ChatPermissions {
+        ChatPermissionsBuilder::new()
+            .set_can_send_messages(self.send_messages)
+            .set_can_send_audios(self.send_media)
+            .set_can_send_documents(self.send_media)
+            .set_can_send_photos(self.send_media)
+            .set_can_send_videos(self.send_media)
+            .set_can_send_video_notes(self.send_media)
+            .set_can_send_voice_notes(self.send_media)
+            .set_can_send_polls(self.send_polls)
+            .set_can_send_other_messages(self.send_other)
+            .set_can_add_web_page_previews(self.link_previews)
+            .set_can_invite_users(self.invite_users)
+            .build()
+    }
+}
+
+/// The permissions shown in a preset preview, with all media types grouped together
+fn summarize_permissions(permissions: &ChatPermissions) -> [(&'static str, bool); 6] {
+    let media = [
+        permissions.get_can_send_audios(),
+        permissions.get_can_send_documents(),
+        permissions.get_can_send_photos(),
+        permissions.get_can_send_videos(),
+        permissions.get_can_send_video_notes(),
+        permissions.get_can_send_voice_notes(),
+    ]
+    .into_iter()
+    .all(|v| v.unwrap_or(false));
+    [
+        (
+            "messages",
+            permissions.get_can_send_messages().unwrap_or(false),
+        ),
+        ("media", media),
+        ("polls", permissions.get_can_send_polls().unwrap_or(false)),
+        (
+            "stickers and gifs",
+            permissions.get_can_send_other_messages().unwrap_or(false),
+        ),
+        (
+            "link previews",
+            permissions.get_can_add_web_page_previews().unwrap_or(false),
+        ),
+        (
+            "invite users",
+            permissions.get_can_invite_users().unwrap_or(false),
+        ),
+    ]
+}
+
+#[inline(always)]
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn describe_captcha(captcha: Option<&(CaptchaType, Option<i64>)>, lang: &Lang) -> String {
+    match captcha {
+        None => "off".to_owned(),
+        Some((captcha_type, None)) => captcha_type.get_name().to_owned(),
+        Some((captcha_type, Some(kick))) => lang_fmt!(
+            lang,
+            "presetcaptchakick",
+            captcha_type.get_name(),
+            std::time::Duration::from_secs((*kick).max(0) as u64).localize(lang)
+        ),
+    }
+}
+
+async fn get_chat_locks(chat: i64) -> Result<Vec<LockType>> {
+    let locks = locks::Entity::find()
+        .filter(locks::Column::Chat.eq(chat))
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| v.lock_type)
+        .collect();
+    Ok(locks)
+}
+
+/// List every setting a preset would change in a chat, empty if the chat already matches
+async fn preset_diff(chat: &Chat, preset: &Preset, lang: &Lang) -> Result<Vec<String>> {
+    let mut changes = Vec::new();
+    let info = TG.client().get_chat(chat.get_id()).await?;
+    let current = info
+        .get_permissions()
+        .map(summarize_permissions)
+        .unwrap_or_default();
+    let target = summarize_permissions(&preset.get_permissions());
+    for ((_, old), (name, new)) in current.iter().zip(target.iter()) {
+        if old != new {
+            changes.push(lang_fmt!(
+                lang,
+                "presetchange",
+                name,
+                on_off(*old),
+                on_off(*new)
+            ));
+        }
+    }
+
+    let locks = get_chat_locks(chat.get_id()).await?;
+    for lock in preset.locks.iter().filter(|v| !locks.contains(v)) {
+        changes.push(lang_fmt!(lang, "presetlockadd", lock.get_name()));
+    }
+    for lock in locks.iter().filter(|v| !preset.locks.contains(v)) {
+        changes.push(lang_fmt!(lang, "presetlockremove", lock.get_name()));
+    }
+
+    let captcha = get_chat_captcha_config(chat)
+        .await?
+        .map(|v| (v.captcha_type, v.kick_time));
+    if captcha != preset.captcha {
+        changes.push(lang_fmt!(
+            lang,
+            "presetchange",
+            "captcha",
+            describe_captcha(captcha.as_ref(), lang),
+            describe_captcha(preset.captcha.as_ref(), lang)
+        ));
+    }
+
+    let limit = dialog_or_default(chat).await?.greet_flood_limit;
+    if limit != preset.greet_flood_limit {
+        changes.push(lang_fmt!(
+            lang,
+            "presetchange",
+            "join flood limit",
+            limit,
+            preset.greet_flood_limit
+        ));
+    }
+    Ok(changes)
+}
+
+async fn apply_preset(chat: &Chat, preset: &Preset) -> Result<()> {
+    change_chat_permissions(chat, &preset.get_permissions()).await?;
+    let locks = get_chat_locks(chat.get_id()).await?;
+    for lock in locks.iter().filter(|v| !preset.locks.contains(v)) {
+        clear_chat_lock(chat.get_id(), lock.clone()).await?;
+    }
+    for lock in preset.locks.iter().filter(|v| !locks.contains(v)) {
+        set_chat_lock(chat.get_id(), lock.clone()).await?;
+    }
+    set_chat_captcha(chat, preset.captcha.clone()).await?;
+    set_greet_flood_limit(chat, preset.greet_flood_limit).await?;
+    Ok(())
+}
+
+/// Handle a push on the preset confirm or cancel button. Returns false to keep the button
+/// registered when pushed by someone without permission
+async fn preset_button(
+    callback: CallbackQuery,
+    preset: &'static Preset,
+    apply: bool,
+    lang: Lang,
+) -> Result<bool> {
+    let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() else {
+        return Ok(true);
+    };
+    let chat = message.get_chat();
+    let permissions = NamedBotPermissions::from_chatuser(callback.get_from(), chat).await?;
+    let allowed = permissions
+        .can_change_info
+        .and(permissions.can_restrict_members)
+        .is_granted();
+    if !allowed && !permissions.is_sudo.is_granted() {
+        TG.client()
+            .build_answer_callback_query(callback.get_id())
+            .text(&lang_fmt!(lang, "presetdenied"))
+            .show_alert(true)
+            .build()
+            .await?;
+        return Ok(false);
+    }
+
+    let text = if apply {
+        apply_preset(chat, preset).await?;
+        lang_fmt!(lang, "presetapplied", preset.name)
+    } else {
+        lang_fmt!(lang, "presetcancelled")
+    };
+    TG.client()
+        .build_edit_message_text(&text)
+        .message_id(message.get_message_id())
+        .chat_id(chat.get_id())
+        .build()
+        .await?;
+    TG.client()
+        .build_answer_callback_query(callback.get_id())
+        .build()
+        .await?;
+    Ok(true)
+}
+
+async fn preset_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info.and(p.can_restrict_members))
+        .await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat();
+    let lang = *ctx.lang();
+    let name = args.args.first().map(|v| v.get_text());
+    let Some(preset) = PRESETS.iter().find(|v| Some(v.name) == name) else {
+        let names = PRESETS
+            .iter()
+            .map(|v| v.name)
+            .collect::<Vec<&str>>()
+            .join("/");
+        return Err(BotError::speak(
+            lang_fmt!(lang, "presetinvalid", names),
+            chat.get_id(),
+            Some(message.get_message_id()),
+        ));
+    };
+
+    let changes = preset_diff(chat, preset, &lang).await?;
+    if changes.is_empty() {
+        ctx.reply(lang_fmt!(lang, "presetnochange", preset.name))
+            .await?;
+        return Ok(());
+    }
+
+    let confirm = InlineKeyboardButtonBuilder::new(lang_fmt!(lang, "presetconfirm"))
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+    let cancel = InlineKeyboardButtonBuilder::new(lang_fmt!(lang, "presetcancel"))
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+    for (button, apply) in [(&confirm, true), (&cancel, false)] {
+        button.on_push_multi(move |callback| preset_button(callback, preset, apply, lang));
+    }
+    let mut buttons = InlineKeyboardBuilder::default();
+    buttons.button(confirm);
+    buttons.button(cancel);
+
+    let mut reply = EntityMessage::new(chat.get_id())
+        .reply_markup(EReplyMarkup::InlineKeyboardMarkup(buttons.build()));
+    reply.builder.text(lang_fmt!(
+        lang,
+        "presetpreview",
+        preset.name,
+        changes.join("\n")
+    ));
+    ctx.reply_fmt(reply).await?;
+    Ok(())
+}
+
 async fn handle_command(ctx: &Context) -> Result<()> {
     if let Some(&Cmd {
         cmd,
@@ -771,6 +1234,8 @@ async fn handle_command(ctx: &Context) -> Result<()> {
             "locks" => handle_list(message).await?,
             "lockaction" => lock_action(message, args).await?,
             "available" => cmd_available(ctx).await?,
+            "preset" => preset_cmd(ctx, args).await?,
+            "moderateedits" => moderate_edits(message, args, lang).await?,
             _ => (),
         };
     }
@@ -780,6 +1245,7 @@ async fn handle_command(ctx: &Context) -> Result<()> {
 #[inline(always)]
 async fn update_action<F>(
     message: &Message,
+    bits: u64,
     locktype: LockType,
     action: &mut Option<ActionType>,
     locks: &mut Vec<LockType>,
@@ -788,7 +1254,7 @@ async fn update_action<F>(
 where
     F: for<'b> FnOnce(&'b Message) -> bool,
 {
-    if p(message) {
+    if bits & locktype.get_bit() != 0 && p(message) {
         if let Some(newaction) = get_lock(message, locktype.clone()).await? {
             let newaction = if let Some(action) = newaction.lock_action {
                 Some(action)
@@ -809,6 +1275,7 @@ where
 #[inline(always)]
 async fn update_action_async<F>(
     message: &Message,
+    bits: u64,
     locktype: LockType,
     action: &mut Option<ActionType>,
     locks: &mut Vec<LockType>,
@@ -817,6 +1284,9 @@ async fn update_action_async<F>(
 where
     F: for<'b> FnOnce(&'b Message) -> BoxFuture<'b, Result<bool>>,
 {
+    if bits & locktype.get_bit() == 0 {
+        return Ok(());
+    }
     match p(message).await {
         Ok(true) => {
             if let Some(newaction) = get_lock(message, locktype.clone()).await? {
@@ -849,14 +1319,6 @@ async fn handle_message_event(
     action: ActionType,
     locks: &[LockType],
 ) -> Result<()> {
-    if let Some(user) = message.get_from() {
-        if is_approved(message.get_chat(), user.id).await? {
-            return Ok(());
-        }
-    }
-    if message.get_from().is_admin(message.get_chat()).await? {
-        return Ok(());
-    }
     let default = get_default_settings(message.get_chat()).await?;
     let lang = ctx.try_get()?.lang;
     let reasons = locks
@@ -873,7 +1335,7 @@ async fn handle_message_event(
         }
         ActionType::Warn => {
             if let Some(chat) = message.get_sender_chat() {
-                TG.client
+                TG.client()
                     .build_ban_chat_sender_chat(message.get_chat().get_id(), chat.get_id())
                     .build()
                     .await?;
@@ -889,7 +1351,7 @@ async fn handle_message_event(
         _ => (),
     }
 
-    TG.client
+    TG.client()
         .build_delete_message(message.get_chat().get_id(), message.get_message_id())
         .build()
         .await?;
@@ -898,7 +1360,7 @@ async fn handle_message_event(
 
 async fn handle_user_event(update: &UpdateExt, ctx: &Context) -> Result<()> {
     if let (Some(action), locks) = action_from_update(update).await? {
-        if let Some(message) = update.should_moderate().await {
+        if let Some(message) = update.moderated_message().await {
             handle_message_event(message, ctx, action, &locks).await?;
         }
     }
@@ -0,0 +1,110 @@
+use botapi::gen_types::ChatMember;
+use macros::{lang_fmt, update_handler};
+
+use crate::metadata::metadata;
+use crate::statics::TG;
+use crate::tg::admin_helpers::set_log_channel;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_dialog;
+use crate::tg::permissions::IsGroupAdmin;
+use crate::tg::user::Username;
+use crate::util::error::{BotError, Result};
+use crate::util::string::Speak;
+
+metadata!("Log Channel",
+    r#"
+    Post moderation events and alerts for this chat to a separate channel. Add the bot to
+    the channel as an admin that can post messages, then use /setlog with the channel's id.
+    You must also be an admin in the log channel.
+    "#,
+    { category = "Chat Management" },
+    { command = "setlog", help = "\\<channel id\\>: Send logs for this chat to a channel", level = Admin },
+    { command = "unsetlog", help = "Stop sending logs for this chat", level = Admin },
+    { command = "logchannel", help = "Show the current log channel", level = Admin },
+    { updates = [Message] }
+);
+
+async fn setlog(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat();
+    let Some(channel) = args
+        .args
+        .first()
+        .and_then(|v| str::parse::<i64>(v.get_text()).ok())
+    else {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "logchannelinvalid"),
+            chat.get_id(),
+            Some(message.get_message_id()),
+        ));
+    };
+    let user = message
+        .get_from()
+        .ok_or_else(|| BotError::Generic("user not found".to_owned()))?;
+    let member = TG
+        .client()
+        .build_get_chat_member(channel, user.get_id())
+        .build()
+        .await
+        .ok();
+    if !matches!(
+        member,
+        Some(ChatMember::ChatMemberAdministrator(_)) | Some(ChatMember::ChatMemberOwner(_))
+    ) {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "logchannelnotadmin"),
+            chat.get_id(),
+            Some(message.get_message_id()),
+        ));
+    }
+
+    if channel
+        .speak(lang_fmt!(ctx, "logchannelhello", chat.name_humanreadable()))
+        .await
+        .is_err()
+    {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "logchannelcantpost"),
+            chat.get_id(),
+            Some(message.get_message_id()),
+        ));
+    }
+
+    set_log_channel(chat, Some(channel)).await?;
+    ctx.reply(lang_fmt!(ctx, "logchannelset", channel)).await?;
+    Ok(())
+}
+
+async fn unsetlog(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    set_log_channel(ctx.message()?.get_chat(), None).await?;
+    ctx.reply(lang_fmt!(ctx, "logchannelunset")).await?;
+    Ok(())
+}
+
+async fn logchannel(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat();
+    match get_dialog(chat).await?.and_then(|v| v.log_channel) {
+        Some(channel) => {
+            ctx.reply(lang_fmt!(ctx, "logchannelcurrent", channel))
+                .await?
+        }
+        None => ctx.reply(lang_fmt!(ctx, "logchannelnone")).await?,
+    };
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "setlog" => setlog(ctx, args).await?,
+            "unsetlog" => unsetlog(ctx).await?,
+            "logchannel" => logchannel(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+use crate::statics::TG;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::maintenance::{is_maintenance, set_maintenance};
+use crate::tg::permissions::IsGroupAdmin;
+use crate::util::error::{Fail, Result};
+use crate::{metadata::metadata, util::string::Speak};
+use macros::{lang_fmt, update_handler};
+
+metadata!("Maintenance",
+    r#"
+    Put the bot in maintenance mode, for example while migrating its database. All commands
+    except owner commands are answered with a maintenance notice until it is turned off.
+
+    After regenerating the bot token with BotFather, the new token can be swapped in without
+    restarting using /rotatetoken in a private chat with the bot. When running sharded workers
+    or several instances, enable the cache bus so every process switches to the new token.
+    "#,
+    { category = "Chat Management" },
+    { command = "maintenance", help = "Usage: maintenance \\<on/off\\>. Toggles maintenance mode for every chat", level = Owner },
+    { command = "rotatetoken", help = "Usage: rotatetoken \\<token\\>. Switches to a new bot token without restarting. Only works in DM", level = Owner },
+    { updates = [Message] }
+);
+
+async fn maintenance(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => {
+            set_maintenance(true).await?;
+            ctx.reply(lang_fmt!(ctx, "maintenanceon")).await?;
+        }
+        Some("off") | Some("no") => {
+            set_maintenance(false).await?;
+            ctx.reply(lang_fmt!(ctx, "maintenanceoff")).await?;
+        }
+        None => {
+            let status = if is_maintenance().await? { "on" } else { "off" };
+            ctx.reply(lang_fmt!(ctx, "maintenancestatus", status))
+                .await?;
+        }
+        Some(_) => ctx.fail(lang_fmt!(ctx, "maintenanceinvalid"))?,
+    }
+    Ok(())
+}
+
+async fn rotate_token(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    if !ctx.is_dm() {
+        return ctx.fail(lang_fmt!(ctx, "rotatetokendm"));
+    }
+    let token = args.text.trim();
+    if token.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "rotatetokeninvalid"));
+    }
+    let message = ctx.message()?;
+    if let Err(err) = TG
+        .client()
+        .build_delete_message(message.get_chat().get_id(), message.get_message_id())
+        .build()
+        .await
+    {
+        log::warn!("failed to delete token message: {}", err);
+    }
+    match TG.rotate_token(token).await {
+        Ok(me) => {
+            let name = me.get_username().unwrap_or("bot").to_owned();
+            message.speak(lang_fmt!(ctx, "rotatetoken", name)).await?;
+        }
+        Err(err) => {
+            log::warn!("failed to rotate token: {}", err);
+            message.speak(lang_fmt!(ctx, "rotatetokenfailed")).await?;
+        }
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "maintenance" {
+            maintenance(ctx, args).await?;
+        } else if cmd == "rotatetoken" {
+            rotate_token(ctx, args).await?;
+        }
+    }
+    Ok(())
+}
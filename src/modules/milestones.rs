@@ -0,0 +1,433 @@
+use self::entities::milestones;
+use crate::metadata::ModuleHelpers;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::{UpdateHelpers, UserChanged};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::util::error::{BotError, Result};
+use crate::util::locale::Localize;
+use crate::util::scheduler::{register_job, schedule};
+use crate::util::string::get_chat_lang;
+use crate::{metadata::metadata, util::string::Speak};
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QueryOrder};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Milestones",
+    r#"
+    Celebrate when this chat grows past a member count. Milestones are checked shortly after
+    users join, and each one is only posted the first time it is reached.
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "membercount", help = "Show how many members this chat has" },
+    { command = "milestone", help = "Usage: milestone \\<count\\> \\[message\\]. Posts a message when the chat reaches count members. Without a message a default celebration is posted", level = Admin },
+    { command = "rmmilestone", help = "Usage: rmmilestone \\<count\\>. Removes a milestone", level = Admin },
+    { command = "milestones", help = "List the milestones set in this chat" },
+    { updates = [Message, ChatMember] }
+);
+
+/// Scheduler job kind for checking milestones after users join
+const MILESTONE_JOB: &str = "milestone";
+
+/// Delay before checking milestones after a join, so a burst of joins only fetches the
+/// member count once
+const CHECK_DELAY: i64 = 30;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(milestones::Entity)
+                        .col(
+                            ColumnDef::new(milestones::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(milestones::Column::Count)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(milestones::Column::Text).text())
+                        .col(
+                            ColumnDef::new(milestones::Column::Reached)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(milestones::Column::Chat)
+                                .col(milestones::Column::Count)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(milestones::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod milestones {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "milestones")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub count: i64,
+            /// custom message, the default celebration is posted if unset
+            pub text: Option<String>,
+            pub reached: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000012_create_milestones"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(MILESTONE_JOB, |payload| async move {
+            let chat: i64 = serde_json::from_value(payload)?;
+            check_milestones(chat).await
+        });
+    }
+}
+
+#[inline(always)]
+fn get_count_key(chat: i64) -> String {
+    format!("mcount:{}", chat)
+}
+
+#[inline(always)]
+fn get_milestones_key(chat: i64) -> String {
+    format!("mstones:{}", chat)
+}
+
+#[inline(always)]
+fn get_pending_key(chat: i64) -> String {
+    format!("mpend:{}", chat)
+}
+
+/// Fetch the member count of a chat from telegram, refreshing the cached value
+async fn refresh_member_count(chat: i64) -> Result<i64> {
+    let count = TG
+        .client()
+        .build_get_chat_member_count(chat)
+        .build()
+        .await?;
+    let key = get_count_key(chat);
+    REDIS
+        .pipe(|q| {
+            q.set(&key, count)
+                .expire(&key, CONFIG.timing.cache_timeout)
+                .ignore()
+        })
+        .await?;
+    Ok(count)
+}
+
+/// Get the member count of a chat, using the cached value if there is one
+async fn get_member_count(chat: i64) -> Result<i64> {
+    let key = get_count_key(chat);
+    let count: Option<i64> = REDIS.sq(|q| q.get(&key)).await?;
+    match count {
+        Some(count) => Ok(count),
+        None => refresh_member_count(chat).await,
+    }
+}
+
+async fn get_milestones(chat: i64) -> Result<Vec<milestones::Model>> {
+    let key = get_milestones_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = milestones::Entity::find()
+                .filter(milestones::Column::Chat.eq(chat))
+                .order_by_asc(milestones::Column::Count)
+                .all(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+async fn invalidate_milestones(chat: i64) -> Result<()> {
+    let key = get_milestones_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(())
+}
+
+/// Pick the milestones newly crossed at a member count. Only the largest is celebrated,
+/// the rest are just marked as reached
+fn crossed(milestones: &[milestones::Model], count: i64) -> Vec<&milestones::Model> {
+    milestones
+        .iter()
+        .filter(|v| !v.reached && v.count <= count)
+        .collect()
+}
+
+/// Post the celebration for any milestones reached since the last check
+async fn check_milestones(chat: i64) -> Result<()> {
+    let milestones = get_milestones(chat).await?;
+    if milestones.iter().all(|v| v.reached) {
+        return Ok(());
+    }
+    let count = refresh_member_count(chat).await?;
+    let crossed = crossed(&milestones, count);
+    let Some(top) = crossed.last() else {
+        return Ok(());
+    };
+    milestones::Entity::update_many()
+        .col_expr(milestones::Column::Reached, true.into())
+        .filter(milestones::Column::Chat.eq(chat))
+        .filter(milestones::Column::Count.lte(count))
+        .exec(*DB)
+        .await?;
+    invalidate_milestones(chat).await?;
+
+    let lang = get_chat_lang(chat).await?;
+    let text = match top.text {
+        Some(ref text) => text.to_owned(),
+        None => lang_fmt!(lang, "milestonereached", top.count.localize(&lang)),
+    };
+    chat.speak(text).await?;
+    Ok(())
+}
+
+/// Schedule a milestone check after a user joins, unless one is already pending
+async fn on_join(ctx: &Context) -> Result<()> {
+    let Some(UserChanged::UserJoined(member)) = ctx.update().user_event() else {
+        return Ok(());
+    };
+    let chat = member.get_chat().get_id();
+    if get_milestones(chat).await?.iter().all(|v| v.reached) {
+        return Ok(());
+    }
+    let key = get_pending_key(chat);
+    let (first,): (bool,) = REDIS
+        .pipe(|q| q.set_nx(&key, true).expire(&key, CHECK_DELAY).ignore())
+        .await?;
+    if first {
+        let at = Utc::now() + Duration::try_seconds(CHECK_DELAY).unwrap();
+        schedule(MILESTONE_JOB, &chat, at).await?;
+    }
+    Ok(())
+}
+
+async fn membercount(ctx: &Context) -> Result<()> {
+    let chat = ctx.try_get()?.chat.get_id();
+    let count = get_member_count(chat).await?;
+    ctx.reply(lang_fmt!(ctx, "membercount", count.localize(ctx.lang())))
+        .await?;
+    Ok(())
+}
+
+async fn add_milestone(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let Some(Ok(count)) = args.args.first().map(|v| v.get_text().parse::<i64>()) else {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "milestoneinvalid"),
+            chat,
+            Some(message.get_message_id()),
+        ));
+    };
+    if count <= 0 {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "milestoneinvalid"),
+            chat,
+            Some(message.get_message_id()),
+        ));
+    }
+    let text = args
+        .text
+        .trim_start()
+        .strip_prefix(args.args[0].get_text())
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .map(|v| v.to_owned());
+
+    // milestones the chat is already past would fire on the next join otherwise
+    let reached = get_member_count(chat).await? >= count;
+    let model = milestones::ActiveModel {
+        chat: Set(chat),
+        count: Set(count),
+        text: Set(text),
+        reached: Set(reached),
+    };
+    milestones::Entity::insert(model)
+        .on_conflict(
+            OnConflict::columns([milestones::Column::Chat, milestones::Column::Count])
+                .update_columns([milestones::Column::Text, milestones::Column::Reached])
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+    invalidate_milestones(chat).await?;
+    let count = count.localize(ctx.lang());
+    if reached {
+        ctx.reply(lang_fmt!(ctx, "milestonepast", count)).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "milestoneset", count)).await?;
+    }
+    Ok(())
+}
+
+async fn remove_milestone(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let Ok(count) = args.text.trim().parse::<i64>() else {
+        return Err(BotError::speak(
+            lang_fmt!(ctx, "milestoneinvalid"),
+            chat,
+            Some(message.get_message_id()),
+        ));
+    };
+    let res = milestones::Entity::delete_by_id((chat, count))
+        .exec(*DB)
+        .await?;
+    invalidate_milestones(chat).await?;
+    if res.rows_affected > 0 {
+        ctx.reply(lang_fmt!(
+            ctx,
+            "milestoneremoved",
+            count.localize(ctx.lang())
+        ))
+        .await?;
+    } else {
+        ctx.reply(lang_fmt!(
+            ctx,
+            "milestonenotfound",
+            count.localize(ctx.lang())
+        ))
+        .await?;
+    }
+    Ok(())
+}
+
+async fn list_milestones(ctx: &Context) -> Result<()> {
+    let chat = ctx.try_get()?.chat.get_id();
+    let milestones = get_milestones(chat).await?;
+    if milestones.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "milestonesnone")).await?;
+        return Ok(());
+    }
+    let mut text = lang_fmt!(ctx, "milestoneslist");
+    for milestone in milestones {
+        let count = milestone.count.localize(ctx.lang());
+        if milestone.reached {
+            text.push_str(&lang_fmt!(ctx, "milestoneslinereached", count));
+        } else {
+            text.push_str(&lang_fmt!(ctx, "milestonesline", count));
+        }
+    }
+    ctx.reply(text).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "membercount" => membercount(ctx).await?,
+            "milestone" => add_milestone(ctx, args).await?,
+            "rmmilestone" => remove_milestone(ctx, args).await?,
+            "milestones" => list_milestones(ctx).await?,
+            _ => (),
+        };
+    }
+    on_join(ctx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn milestone(count: i64, reached: bool) -> milestones::Model {
+        milestones::Model {
+            chat: 1,
+            count,
+            text: None,
+            reached,
+        }
+    }
+
+    #[test]
+    fn crossed_milestones() {
+        let milestones = vec![
+            milestone(100, true),
+            milestone(500, false),
+            milestone(1000, false),
+            milestone(5000, false),
+        ];
+        let counts = |count| {
+            crossed(&milestones, count)
+                .into_iter()
+                .map(|v| v.count)
+                .collect::<Vec<i64>>()
+        };
+        assert_eq!(counts(499), Vec::<i64>::new());
+        assert_eq!(counts(500), vec![500]);
+        assert_eq!(counts(1200), vec![500, 1000]);
+    }
+}
@@ -0,0 +1,75 @@
+use macros::{lang_fmt, update_handler};
+
+use crate::metadata::metadata;
+use crate::persist::core::mirrored_media::MirrorCategory;
+use crate::persist::mirror::{get_mirrored, get_signed_url};
+use crate::statics::CONFIG;
+use crate::tg::command::{Cmd, Context};
+use crate::tg::markdown::EntityMessage;
+use crate::tg::permissions::IsGroupAdmin;
+use crate::util::error::Result;
+use crate::util::locale::Localize;
+use crate::util::string::Speak;
+
+metadata!("Media Mirror",
+    r#"
+    If the bot owner has configured object storage, evidence from bans and chat exports
+    are mirrored there so they survive telegram deleting the original file.
+    Links are only valid for a limited time.
+    "#,
+    { category = "Fun" },
+    { command = "evidence", help = "List recently mirrored ban evidence", level = Admin },
+    { command = "backups", help = "List recently mirrored chat exports", level = Admin },
+    { updates = [Message] }
+);
+
+/// Number of mirrored objects listed per command
+const LIST_LIMIT: u64 = 10;
+
+async fn list_mirrored(ctx: &Context, category: MirrorCategory) -> Result<()> {
+    if !CONFIG.mirror.enabled {
+        ctx.reply(lang_fmt!(ctx, "mirrorunavailable")).await?;
+        return Ok(());
+    }
+    let chat = ctx.message()?.get_chat().get_id();
+    let mirrored = get_mirrored(chat, category, LIST_LIMIT).await?;
+    if mirrored.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "mirrorempty")).await?;
+        return Ok(());
+    }
+
+    let mut message = EntityMessage::new(chat);
+    for object in mirrored {
+        let name = object
+            .key
+            .rsplit('/')
+            .next()
+            .unwrap_or(&object.key)
+            .to_owned();
+        message
+            .builder
+            .text(format!("{} ", object.created.localize(ctx.lang())))
+            .text_link(name, get_signed_url(&object.key), None)
+            .text("\n");
+    }
+    ctx.reply_fmt(message).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+        match cmd {
+            "evidence" => {
+                ctx.check_permissions(|p| p.can_restrict_members).await?;
+                list_mirrored(ctx, MirrorCategory::Evidence).await?;
+            }
+            "backups" => {
+                ctx.check_permissions(|p| p.can_manage_chat).await?;
+                list_mirrored(ctx, MirrorCategory::Backup).await?;
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
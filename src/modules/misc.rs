@@ -1,18 +1,35 @@
+use std::time::Duration;
+
 use macros::{lang_fmt, update_handler};
+use sea_orm::{EntityTrait, PaginatorTrait};
 
+use crate::metadata::{Metadata, API_VERSION};
+use crate::persist::core::{dialogs, users};
+use crate::persist::dbstats::{fmt_bytes, get_table_stats, last_sample};
+use crate::persist::metrics::{
+    cache_hit_rate, get_command_count, handler_error_rate, module_usage, slowest_commands,
+    DB_LATENCY, HANDLER_ERRORS, REDIS_LATENCY, START_TIME, UPDATES_RECEIVED, UPDATE_RATE,
+};
+use crate::statics::{DB, TG};
 use crate::tg::command::{Cmd, Context};
 use crate::tg::dialog::get_user_chats;
-use crate::tg::markdown::EntityMessage;
+use crate::tg::flair::get_flair;
+use crate::tg::greetings::get_rules_ack;
+use crate::tg::markdown::{EntityMessage, Escape};
 use crate::tg::permissions::IsGroupAdmin;
 use crate::tg::user::GetUser;
 use crate::util::error::{BotError, Result, SpeakErr};
+use crate::util::locale::Localize;
 use crate::{metadata::metadata, util::string::Speak};
 
 metadata!("Misc",
    r#"
     Random helper functions to make your life easier.
     "#,
-   { command = "id", help = "Gets the id for a user" }
+   { category = "Misc" },
+   { command = "id", help = "Gets the id for a user" },
+   { command = "info", help = "Shows a user's name, id, flair, and when they agreed to this chat's rules" },
+   { updates = [Message] }
 );
 
 async fn get_id(ctx: &Context) -> Result<()> {
@@ -33,6 +50,31 @@ async fn get_id(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+async fn get_info(ctx: &Context) -> Result<()> {
+    ctx.action_user(|ctx, user, _| async move {
+        if let Some(chat) = ctx.chat() {
+            let mut rules = match get_rules_ack(chat.get_id(), user).await? {
+                Some(time) => lang_fmt!(ctx, "infoagreed", time.localize(ctx.lang())),
+                None => lang_fmt!(ctx, "infonotagreed"),
+            };
+            if let Some(flair) = get_flair(chat.get_id(), user).await? {
+                rules.push('\n');
+                rules.push_str(&lang_fmt!(ctx, "infoflair", flair.escape(false)));
+            }
+            let name = user.cached_name().await?;
+            ctx.reply(lang_fmt!(ctx, "info", name, user, rules)).await?;
+        }
+        Ok(())
+    })
+    .await
+    .speak_err_raw(ctx, |v| match v {
+        BotError::UserNotFound => Some(lang_fmt!(ctx, "failuser", "get info for")),
+        _ => None,
+    })
+    .await?;
+    Ok(())
+}
+
 pub async fn allchats(ctx: &Context) -> Result<()> {
     ctx.check_permissions(|p| p.is_support).await?;
     ctx.action_user(|ctx, user, _| async move {
@@ -57,12 +99,245 @@ pub async fn allchats(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+fn fmt_percent(rate: Option<f64>) -> String {
+    rate.map(|v| format!("{:.2}%", v * 100.0))
+        .unwrap_or_else(|| "n/a".to_owned())
+}
+
+fn fmt_latency(latency: Option<Duration>) -> String {
+    latency
+        .map(|v| format!("{:.2}ms", v.as_secs_f64() * 1000.0))
+        .unwrap_or_else(|| "n/a".to_owned())
+}
+
+/// Owner-only dashboard with usage and performance stats for the whole bot
+pub async fn botstats(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    let chats = dialogs::Entity::find().count(*DB).await?;
+    let users = users::Entity::find().count(*DB).await?;
+    let uptime = Duration::from_secs(START_TIME.elapsed().as_secs());
+
+    let lang = ctx.lang();
+
+    let mut message = EntityMessage::new(ctx.message()?.get_chat().get_id());
+    message
+        .builder
+        .bold("Uptime: ")
+        .text(format!("{}\n", uptime.localize(lang)))
+        .bold("Chats: ")
+        .text(format!("{}\n", chats.localize(lang)))
+        .bold("Users: ")
+        .text(format!("{}\n", users.localize(lang)))
+        .bold("Updates: ")
+        .text(format!(
+            "{} total, {}/min\n",
+            UPDATES_RECEIVED.get(),
+            UPDATE_RATE.per_minute()
+        ))
+        .bold("Handler errors: ")
+        .text(format!(
+            "{} ({})\n",
+            HANDLER_ERRORS.get(),
+            fmt_percent(handler_error_rate())
+        ))
+        .bold("Cache hit rate: ")
+        .text(format!("{}\n", fmt_percent(cache_hit_rate())))
+        .bold("DB latency: ")
+        .text(format!(
+            "p50 {} p95 {} p99 {}\n",
+            fmt_latency(DB_LATENCY.percentile(50.0)),
+            fmt_latency(DB_LATENCY.percentile(95.0)),
+            fmt_latency(DB_LATENCY.percentile(99.0))
+        ))
+        .bold("Redis latency: ")
+        .text(format!(
+            "p50 {} p95 {} p99 {}\n",
+            fmt_latency(REDIS_LATENCY.percentile(50.0)),
+            fmt_latency(REDIS_LATENCY.percentile(95.0)),
+            fmt_latency(REDIS_LATENCY.percentile(99.0))
+        ))
+        .bold("\nCommand usage:\n");
+
+    let mut modules = TG
+        .modules
+        .iter()
+        .map(|m| {
+            let counts = m
+                .commands
+                .keys()
+                .map(|c| (c.as_str(), get_command_count(&m.name, c)))
+                .filter(|(_, c)| *c > 0)
+                .collect::<Vec<(&str, u64)>>();
+            (
+                m.name.as_str(),
+                counts.iter().map(|(_, c)| c).sum::<u64>(),
+                counts,
+            )
+        })
+        .filter(|(_, total, _)| *total > 0)
+        .collect::<Vec<(&str, u64, Vec<(&str, u64)>)>>();
+    modules.sort_by(|(_, a, _), (_, b, _)| b.cmp(a));
+
+    for (module, total, mut commands) in modules {
+        commands.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let commands = commands
+            .into_iter()
+            .map(|(c, n)| format!("/{} {}", c, n))
+            .collect::<Vec<String>>()
+            .join(", ");
+        message
+            .builder
+            .bold(module)
+            .text(format!(": {} ({})\n", total, commands));
+    }
+
+    ctx.reply_fmt(message).await?;
+    Ok(())
+}
+
+/// Number of commands listed by /slowcmds
+const SLOW_COMMANDS: usize = 10;
+
+/// Owner-only report of the commands with the slowest handlers
+pub async fn slowcmds(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    let commands = slowest_commands(SLOW_COMMANDS);
+    let mut message = EntityMessage::new(ctx.message()?.get_chat().get_id());
+    message.builder.bold("Slowest commands by p95 latency:\n");
+    if commands.is_empty() {
+        message.builder.text("n/a\n");
+    }
+    for (module, command, p95) in commands {
+        message.builder.text(format!(
+            "/{} ({}): {}, {} calls\n",
+            command,
+            module,
+            fmt_latency(Some(p95)),
+            get_command_count(&module, &command)
+        ));
+    }
+    ctx.reply_fmt(message).await?;
+    Ok(())
+}
+
+/// Owner-only report of the time, queries, and messages used by each module, to find
+/// modules, including ones added by the bot owner, that are misbehaving
+pub async fn modusage(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    let lang = ctx.lang();
+    let mut message = EntityMessage::new(ctx.message()?.get_chat().get_id());
+    message
+        .builder
+        .bold("Module usage, last minute (since startup):\n");
+    let usage = module_usage();
+    if usage.is_empty() {
+        message.builder.text("n/a\n");
+    }
+    for module in usage {
+        message.builder.bold(format!("{}\n", module.module));
+        message.builder.text(format!(
+            "  cpu {} ({}), db {} ({}), redis {} ({}), sent {} ({})\n",
+            fmt_latency(Some(module.cpu_minute)),
+            fmt_latency(Some(module.cpu_total)),
+            module.db_minute.localize(lang),
+            module.db_total.localize(lang),
+            module.redis_minute.localize(lang),
+            module.redis_total.localize(lang),
+            module.messages_minute.localize(lang),
+            module.messages_total.localize(lang)
+        ));
+    }
+    ctx.reply_fmt(message).await?;
+    Ok(())
+}
+
+/// Number of tables listed by /dbstats
+const DB_TABLES: usize = 15;
+
+/// Show the change since the last sample, if there was one
+fn fmt_growth(growth: Option<String>) -> String {
+    growth.map(|v| format!(" ({})", v)).unwrap_or_default()
+}
+
+/// Owner-only report of the largest database tables and how much they grew since the
+/// last periodic sample, to spot tables growing out of control
+pub async fn dbstats(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    let lang = ctx.lang();
+    let tables = get_table_stats().await?;
+    let total = tables.iter().map(|v| v.bytes).sum::<i64>();
+    let mut message = EntityMessage::new(ctx.message()?.get_chat().get_id());
+    message.builder.bold(format!(
+        "Largest tables, {} total (change since last sample):\n",
+        fmt_bytes(total)
+    ));
+    if tables.is_empty() {
+        message.builder.text("n/a\n");
+    }
+    for table in tables.into_iter().take(DB_TABLES) {
+        let last = last_sample(&table.name);
+        let rows = last.as_ref().map(|v| format!("{:+}", table.rows - v.rows));
+        let bytes = last.as_ref().map(|v| {
+            let growth = fmt_bytes(table.bytes - v.bytes);
+            if growth.starts_with('-') {
+                growth
+            } else {
+                format!("+{}", growth)
+            }
+        });
+        message.builder.bold(table.name);
+        message.builder.text(format!(
+            ": {} rows{}, {}{}\n",
+            table.rows.localize(lang),
+            fmt_growth(rows),
+            fmt_bytes(table.bytes),
+            fmt_growth(bytes)
+        ));
+    }
+    ctx.reply_fmt(message).await?;
+    Ok(())
+}
+
+/// Owner-only list of loaded modules with their versions, authors, and sources
+pub async fn modules(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.is_sudo).await?;
+    let mut modules = TG.modules.iter().collect::<Vec<&Metadata>>();
+    modules.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut message = EntityMessage::new(ctx.message()?.get_chat().get_id());
+    message.builder.bold(format!(
+        "{} modules, api version {}:\n",
+        modules.len(),
+        API_VERSION
+    ));
+    for module in modules {
+        message.builder.bold(module.name.as_str());
+        if let Some(ref version) = module.version {
+            message.builder.text(format!(" {}", version));
+        }
+        if let Some(ref author) = module.author {
+            message.builder.text(format!(" by {}", author));
+        }
+        if let Some(ref source) = module.source {
+            message.builder.text(format!(" ({})", source));
+        }
+        message.builder.text("\n");
+    }
+    ctx.reply_fmt(message).await?;
+    Ok(())
+}
+
 #[update_handler]
 pub async fn handle_update(ctx: &Context) -> Result<()> {
     if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
         match cmd {
             "id" => get_id(ctx).await?,
+            "info" => get_info(ctx).await?,
             "allchats" => allchats(ctx).await?,
+            "botstats" => botstats(ctx).await?,
+            "slowcmds" => slowcmds(ctx).await?,
+            "modusage" => modusage(ctx).await?,
+            "dbstats" => dbstats(ctx).await?,
+            "modules" => modules(ctx).await?,
             _ => (),
         }
     }
@@ -0,0 +1,115 @@
+use crate::metadata::metadata;
+use crate::statics::TG;
+use crate::tg::admin_helpers::{get_nickname, set_nickname};
+use crate::tg::command::{trim_prefix, Cmd, Context, Nickname, TextArgs};
+use crate::tg::permissions::*;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Nickname",
+    r#"
+    Give the bot a nickname in this chat and choose trigger words that run commands after it.
+    For example after /nickname Dij and /nicktrigger yeet ban, "Dij, yeet him" works like /ban.
+    Commands run this way check permissions the same as normal commands.
+    "#,
+    { category = "Moderation" },
+    { command = "nickname", help = "Usage: nickname \\<name/off\\>. Without arguments shows the current nickname and triggers", level = Admin },
+    { command = "nicktrigger", help = "Usage: nicktrigger \\<word\\> \\<command\\>. Runs command when word follows the nickname", level = Admin },
+    { command = "rmnicktrigger", help = "Usage: rmnicktrigger \\<word\\>. Removes a trigger word", level = Admin },
+    { updates = [Message] }
+);
+
+async fn nickname_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat();
+    let nickname = get_nickname(chat).await?;
+    match args.args.as_slice() {
+        [] => match nickname {
+            Some(nickname) => {
+                let triggers = nickname
+                    .triggers
+                    .iter()
+                    .sorted()
+                    .map(|(word, cmd)| format!("{} -> /{}", word, cmd))
+                    .join("\n");
+                ctx.reply(lang_fmt!(ctx, "nicknamestatus", nickname.name, triggers))
+                    .await?;
+            }
+            None => {
+                ctx.reply(lang_fmt!(ctx, "nicknamenone")).await?;
+            }
+        },
+        [name] if name.get_text() == "off" => {
+            set_nickname(chat, None).await?;
+            ctx.reply(lang_fmt!(ctx, "nicknameoff")).await?;
+        }
+        [name] => {
+            let name = name.get_text();
+            let nickname = Nickname {
+                name: name.to_owned(),
+                triggers: nickname.map(|v| v.triggers).unwrap_or_default(),
+            };
+            set_nickname(chat, Some(&nickname)).await?;
+            ctx.reply(lang_fmt!(ctx, "nicknameset", name)).await?;
+        }
+        _ => ctx.fail(lang_fmt!(ctx, "nicknameinvalid"))?,
+    }
+    Ok(())
+}
+
+async fn nicktrigger_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat();
+    let [word, cmd] = args.args.as_slice() else {
+        return ctx.fail(lang_fmt!(ctx, "nicktriggerinvalid"));
+    };
+    let cmd = trim_prefix(cmd.get_text());
+    if TG.modules.command_level(cmd).is_none() {
+        return ctx.fail(lang_fmt!(ctx, "nicktriggernocmd", cmd));
+    }
+    let Some(mut nickname) = get_nickname(chat).await? else {
+        return ctx.fail(lang_fmt!(ctx, "nicknamenone"));
+    };
+    let word = word.get_text().to_lowercase();
+    nickname.triggers.insert(word.clone(), cmd.to_owned());
+    set_nickname(chat, Some(&nickname)).await?;
+    ctx.reply(lang_fmt!(ctx, "nicktriggerset", nickname.name, word, cmd))
+        .await?;
+    Ok(())
+}
+
+async fn rmnicktrigger_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat();
+    let Some(word) = args.args.first().map(|v| v.get_text().to_lowercase()) else {
+        return ctx.fail(lang_fmt!(ctx, "nicktriggerinvalid"));
+    };
+    let Some(mut nickname) = get_nickname(chat).await? else {
+        return ctx.fail(lang_fmt!(ctx, "nicknamenone"));
+    };
+    if nickname.triggers.remove(&word).is_none() {
+        return ctx.fail(lang_fmt!(ctx, "nicktriggermissing", word));
+    }
+    set_nickname(chat, Some(&nickname)).await?;
+    ctx.reply(lang_fmt!(ctx, "nicktriggerremoved", word))
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "nickname" => nickname_cmd(ctx, args).await?,
+            "nicktrigger" => nicktrigger_cmd(ctx, args).await?,
+            "rmnicktrigger" => rmnicktrigger_cmd(ctx, args).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,489 @@
+use self::entities::nightmode_settings::{self, NightProfile};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::util::error::{BotError, Fail, Result};
+use crate::util::locale::{parse_utc_offset, utc_offset_timezone};
+use crate::util::scheduler::{cancel, register_job, schedule};
+use crate::util::string::Speak;
+use botapi::gen_types::{ChatPermissions, ChatPermissionsBuilder};
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Timelike, Utc};
+use macros::{lang_fmt, update_handler};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
+
+metadata!("Night Mode",
+    r#"
+    Restrict the chat every night, or during any other hours of the day. When night mode
+    starts the chat's permissions are saved and replaced, and when it ends the saved
+    permissions are put back. Times are in the timezone given to /nightmode, UTC if none is.
+
+    [*Profiles:]\n
+    [*mute]: nobody can send messages\n
+    [*media]: only text messages are allowed
+
+    [*Example:]
+    /nightmode 22:00-07:00 mute UTC+2
+    /nightmode off
+    "#,
+    Helper,
+    { category = "Moderation" },
+    { command = "nightmode", help = "Usage: nightmode \\<hh:mm-hh:mm\\> \\[mute/media\\] \\[utc offset\\]. Restrict the chat during these hours, or nightmode off", level = Admin, requires = [CanRestrict] },
+    { updates = [Message] }
+);
+
+/// Scheduler job kind starting or ending night mode
+const NIGHT_JOB: &str = "nightmode";
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(nightmode_settings::Entity)
+                        .col(
+                            ColumnDef::new(nightmode_settings::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(nightmode_settings::Column::Starts)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(nightmode_settings::Column::Ends)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(nightmode_settings::Column::UtcOffset)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(nightmode_settings::Column::Profile)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(nightmode_settings::Column::Saved).text())
+                        .col(ColumnDef::new(nightmode_settings::Column::Job).uuid())
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(nightmode_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod nightmode_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// The permissions a chat has during night mode
+        #[derive(
+            EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+        )]
+        #[sea_orm(rs_type = "i32", db_type = "Integer")]
+        pub enum NightProfile {
+            #[sea_orm(num_value = 1)]
+            Mute,
+            /// only text messages
+            #[sea_orm(num_value = 2)]
+            Media,
+        }
+
+        impl NightProfile {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    "mute" => Some(Self::Mute),
+                    "media" => Some(Self::Media),
+                    _ => None,
+                }
+            }
+
+            pub fn get_name(&self) -> &'static str {
+                match self {
+                    Self::Mute => "mute",
+                    Self::Media => "media",
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "nightmode_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            /// minute of the day night mode starts, in the chat's timezone
+            pub starts: i32,
+            /// minute of the day night mode ends, in the chat's timezone
+            pub ends: i32,
+            /// minutes east of UTC
+            pub utc_offset: i32,
+            pub profile: NightProfile,
+            /// json of the permissions to restore, set while night mode is on
+            pub saved: Option<String>,
+            /// the next start or end
+            pub job: Option<Uuid>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000053_create_nightmode"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(NIGHT_JOB, |payload| async move {
+            let job: NightJob = serde_json::from_value(payload)?;
+            if job.start {
+                start_night(job.chat).await
+            } else {
+                end_night(job.chat).await
+            }
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct NightJob {
+    chat: i64,
+    /// true to start night mode, false to end it
+    start: bool,
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("nightset:{}", chat)
+}
+
+/// Parse a time of day like "22:00" into minutes since midnight
+fn parse_minute(text: &str) -> Option<i32> {
+    let time = NaiveTime::parse_from_str(text, "%H:%M").ok()?;
+    Some((time.hour() * 60 + time.minute()) as i32)
+}
+
+fn format_minute(minute: i32) -> String {
+    format!("{:02}:{:02}", minute / 60, minute % 60)
+}
+
+/// Minute of the day at a time in a timezone
+fn local_minute(now: DateTime<Utc>, offset: i32) -> i32 {
+    let local = now.with_timezone(&utc_offset_timezone(offset));
+    (local.hour() * 60 + local.minute()) as i32
+}
+
+/// Is a time between the start and end minutes, which can wrap past midnight
+fn in_window(starts: i32, ends: i32, offset: i32, now: DateTime<Utc>) -> bool {
+    let minute = local_minute(now, offset);
+    if starts < ends {
+        (starts..ends).contains(&minute)
+    } else {
+        minute >= starts || minute < ends
+    }
+}
+
+/// The next time after now that a minute of the day comes around in a timezone
+fn next_at(minute: i32, offset: i32, now: DateTime<Utc>) -> DateTime<Utc> {
+    let timezone = utc_offset_timezone(offset);
+    let time =
+        NaiveTime::from_hms_opt((minute / 60) as u32, (minute % 60) as u32, 0).unwrap_or_default();
+    let date = now.with_timezone(&timezone).date_naive();
+    let at = timezone
+        .from_local_datetime(&date.and_time(time))
+        .single()
+        .map(|v| v.with_timezone(&Utc))
+        .unwrap_or(now);
+    if at <= now {
+        at + Duration::try_days(1).unwrap()
+    } else {
+        at
+    }
+}
+
+fn profile_permissions(profile: NightProfile) -> ChatPermissions {
+    let text = profile == NightProfile::Media;
+    ChatPermissionsBuilder::new()
+        .set_can_send_messages(text)
+        .set_can_send_audios(false)
+        .set_can_send_documents(false)
+        .set_can_send_photos(false)
+        .set_can_send_videos(false)
+        .set_can_send_video_notes(false)
+        .set_can_send_voice_notes(false)
+        .set_can_send_polls(false)
+        .set_can_send_other_messages(false)
+        .set_can_add_web_page_previews(false)
+        .build()
+}
+
+async fn set_permissions(chat: i64, permissions: &ChatPermissions) -> Result<()> {
+    TG.client()
+        .build_set_chat_permissions(chat, permissions)
+        .use_independent_chat_permissions(true)
+        .build()
+        .await?;
+    Ok(())
+}
+
+async fn get_settings(chat: i64) -> Result<Option<nightmode_settings::Model>> {
+    let key = get_settings_key(chat);
+    default_cache_query(
+        |_, _| async move {
+            let res = nightmode_settings::Entity::find_by_id(chat)
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+async fn save_settings(settings: nightmode_settings::Model) -> Result<()> {
+    let chat = settings.chat;
+    let model = nightmode_settings::Entity::insert(nightmode_settings::ActiveModel {
+        chat: Set(settings.chat),
+        starts: Set(settings.starts),
+        ends: Set(settings.ends),
+        utc_offset: Set(settings.utc_offset),
+        profile: Set(settings.profile),
+        saved: Set(settings.saved),
+        job: Set(settings.job),
+    })
+    .on_conflict(
+        OnConflict::column(nightmode_settings::Column::Chat)
+            .update_columns([
+                nightmode_settings::Column::Starts,
+                nightmode_settings::Column::Ends,
+                nightmode_settings::Column::UtcOffset,
+                nightmode_settings::Column::Profile,
+                nightmode_settings::Column::Saved,
+                nightmode_settings::Column::Job,
+            ])
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+/// Save the chat's permissions, restrict it, and schedule the end of night mode
+async fn start_night(chat: i64) -> Result<()> {
+    let Some(mut settings) = nightmode_settings::Entity::find_by_id(chat)
+        .one(*DB)
+        .await?
+    else {
+        return Ok(());
+    };
+    if settings.saved.is_none() {
+        let info = TG.client().get_chat(chat).await?;
+        let current = info
+            .get_permissions()
+            .ok_or_else(|| BotError::generic("failed to get chat permissions"))?;
+        settings.saved = Some(serde_json::to_string(current)?);
+        set_permissions(chat, &profile_permissions(settings.profile)).await?;
+    }
+    let ends = next_at(settings.ends, settings.utc_offset, Utc::now());
+    let job = NightJob { chat, start: false };
+    settings.job = Some(schedule(NIGHT_JOB, &job, ends).await?);
+    save_settings(settings).await
+}
+
+/// Put back the permissions saved when night mode started
+async fn restore(settings: &mut nightmode_settings::Model) -> Result<()> {
+    if let Some(saved) = settings.saved.take() {
+        let saved: ChatPermissions = serde_json::from_str(&saved)?;
+        set_permissions(settings.chat, &saved).await?;
+    }
+    Ok(())
+}
+
+/// Restore the chat's permissions and schedule the next start of night mode
+async fn end_night(chat: i64) -> Result<()> {
+    let Some(mut settings) = nightmode_settings::Entity::find_by_id(chat)
+        .one(*DB)
+        .await?
+    else {
+        return Ok(());
+    };
+    restore(&mut settings).await?;
+    let starts = next_at(settings.starts, settings.utc_offset, Utc::now());
+    let job = NightJob { chat, start: true };
+    settings.job = Some(schedule(NIGHT_JOB, &job, starts).await?);
+    save_settings(settings).await
+}
+
+/// Stop night mode, restoring permissions if it is on right now
+async fn stop_night(chat: i64) -> Result<()> {
+    if let Some(mut settings) = get_settings(chat).await? {
+        if let Some(job) = settings.job {
+            cancel(&job).await?;
+        }
+        restore(&mut settings).await?;
+    }
+    nightmode_settings::Entity::delete_by_id(chat)
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_settings_key(chat)]).await?;
+    Ok(())
+}
+
+async fn nightmode(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let current = get_settings(chat).await?;
+    let (range, rest) = match args.args.as_slice() {
+        [] => {
+            let status = match current {
+                Some(settings) => lang_fmt!(
+                    ctx,
+                    "nightmodestatus",
+                    format_minute(settings.starts),
+                    format_minute(settings.ends),
+                    utc_offset_timezone(settings.utc_offset),
+                    settings.profile.get_name()
+                ),
+                None => lang_fmt!(ctx, "nightmodeoff"),
+            };
+            ctx.reply(status).await?;
+            return Ok(());
+        }
+        [off] if off.get_text() == "off" => {
+            stop_night(chat).await?;
+            ctx.reply(lang_fmt!(ctx, "nightmodeoff")).await?;
+            return Ok(());
+        }
+        [range, rest @ ..] if rest.len() <= 2 => (range.get_text(), rest),
+        _ => return ctx.fail(lang_fmt!(ctx, "nightmodeusage")),
+    };
+    let Some((starts, ends)) = range
+        .split_once('-')
+        .and_then(|(s, e)| Some((parse_minute(s)?, parse_minute(e)?)))
+        .filter(|(s, e)| s != e)
+    else {
+        return ctx.fail(lang_fmt!(ctx, "nightmodeusage"));
+    };
+    let mut profile = NightProfile::Mute;
+    let mut offset = current.as_ref().map(|v| v.utc_offset).unwrap_or(0);
+    for arg in rest.iter().map(|v| v.get_text()) {
+        if let Some(p) = NightProfile::from_name(arg) {
+            profile = p;
+        } else if let Some(o) = parse_utc_offset(arg) {
+            offset = o;
+        } else {
+            return ctx.fail(lang_fmt!(ctx, "nightmodeusage"));
+        }
+    }
+
+    // start over so a changed profile or window applies right away
+    stop_night(chat).await?;
+    save_settings(nightmode_settings::Model {
+        chat,
+        starts,
+        ends,
+        utc_offset: offset,
+        profile,
+        saved: None,
+        job: None,
+    })
+    .await?;
+    if in_window(starts, ends, offset, Utc::now()) {
+        start_night(chat).await?;
+    } else {
+        end_night(chat).await?;
+    }
+    ctx.reply(lang_fmt!(
+        ctx,
+        "nightmodeset",
+        format_minute(starts),
+        format_minute(ends),
+        utc_offset_timezone(offset),
+        profile.get_name()
+    ))
+    .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "nightmode" {
+            nightmode(ctx, args).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let now = Utc.with_ymd_and_hms(2024, 7, 1, 21, 30, 0).unwrap();
+        // 23:30 at UTC+2
+        assert!(in_window(22 * 60, 7 * 60, 120, now));
+        assert!(!in_window(22 * 60, 7 * 60, 0, now));
+        assert!(in_window(9 * 60, 22 * 60, 0, now));
+        assert_eq!(
+            next_at(7 * 60, 120, now),
+            Utc.with_ymd_and_hms(2024, 7, 2, 5, 0, 0).unwrap()
+        );
+        assert_eq!(
+            next_at(22 * 60, 0, now),
+            Utc.with_ymd_and_hms(2024, 7, 1, 22, 0, 0).unwrap()
+        );
+    }
+}
@@ -2,43 +2,68 @@ use crate::metadata::{metadata, ModuleHelpers};
 use crate::persist::redis::RedisCache;
 use crate::statics::{DB, REDIS, TG};
 
-use crate::tg::admin_helpers::IntoChatUser;
+use crate::tg::admin_helpers::{is_dm, set_private_notes, IntoChatUser};
 use crate::tg::button::{InlineKeyboardBuilder, OnPush};
 use crate::tg::command::{
-    get_content, handle_deep_link, Cmd, Context, InputType, TextArg, TextArgs,
+    get_content, handle_deep_link, post_deep_link, Cmd, Context, InputType, TextArg, TextArgs,
 };
 
+use crate::tg::dialog::{dialog_or_default, get_user_chats};
 use crate::tg::import_export::{is_tainted, set_taint_vec};
-use crate::tg::markdown::{button_deeplink_key, MarkupBuilder};
+use crate::tg::inline::{register_inline_provider, InlineContext, MAX_RESULTS};
+use crate::tg::markdown::{button_deeplink_key, EntityMessage, Escape, MarkupBuilder};
 use crate::tg::notes::{
     clear_notes, get_hash_key, get_note_by_name, handle_transition, refresh_notes,
 };
 use crate::tg::permissions::IsGroupAdmin;
+use crate::tg::quotas::{check_note_size, check_quota, Quota};
 use crate::tg::rosemd::{RoseMdDecompiler, RoseMdParser};
-use crate::tg::user::Username;
+use crate::tg::user::{GetChat, Username};
+use crate::tg::webhooks::{post_event, ChatEvent};
 use crate::util::error::{BotError, Fail, Result, SpeakErr};
 use crate::util::string::Speak;
 use ::sea_orm_migration::prelude::*;
-use botapi::gen_types::MessageEntity;
+use botapi::gen_types::{
+    EReplyMarkup, InlineKeyboardButtonBuilder, InlineQueryResult, LinkPreviewOptionsBuilder,
+    MaybeInaccessibleMessage, MessageEntity,
+};
 use futures::FutureExt;
 use macros::{lang_fmt, update_handler};
 use redis::AsyncCommands;
 use sea_orm::ActiveValue::{NotSet, Set};
-use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use uuid::Uuid;
 
-use crate::persist::core::{entity, media::*, notes};
+use crate::persist::core::{dialogs, entity, media::*, notes};
 
 metadata!("Notes",
     r#"
     Easily store and retrive text, media, and other content by keywords.
     Useful for storing answers to often asked questions or searching uploaded media.
+    Text notes from your chats can also be searched and sent from any chat in inline mode.
+
+    Send #notename or /get notename to get a note. Notes can be text, media, or both, can
+    have buttons, and support fillings like \{mention\} or \{rules\} \(see /help formatting\).
+    Chats that don't want notes filling up the chat can use /privatenotes to have notes sent
+    in dm instead.
+
+    [*Example:]
+    /save hello Hi there \{mention\}
+    #hello
+    @botname notes rules
     "#,
     Helper,
-    { command = "save", help = "Saves a note" },
+    { category = "Content" },
+    { command = "save", help = "Saves a note", level = Admin },
     { command = "get", help = "Get a note" },
-    { command = "delete", help = "Delete a note" },
-    { command = "notes", help = "List all notes for the current chat"}
+    { command = "delete", help = "Delete a note", level = Admin },
+    { command = "notes", help = "List all notes for the current chat"},
+    { command = "privatenotes", help = "Usage: privatenotes \\<on/off\\>. Send notes in dm with a button in the chat instead of in the chat itself", level = Admin },
+    { command = "previewnote", help = "Usage: previewnote \\<name\\>. Shows how a note renders for you without triggering it", level = Admin },
+    { updates = [Message] }
 );
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -89,8 +114,13 @@ impl ModuleHelpers for Helper {
             })
             .collect();
 
+        let private_notes = dialogs::Entity::find_by_id(chat)
+            .one(*DB)
+            .await?
+            .map(|v| v.private_notes)
+            .unwrap_or(false);
         let out = ExportNotes {
-            private_notes: false,
+            private_notes,
             notes: items,
         };
 
@@ -127,6 +157,9 @@ impl ModuleHelpers for Helper {
         set_taint_vec(taint.collect()).await?;
         let res = res.into_iter().map(|v| v.into_active_model());
         notes::Entity::insert_many(res).exec(*DB).await?;
+        if let Some(c) = chat.get_chat().await? {
+            set_private_notes(&c, notes.private_notes).await?;
+        }
 
         refresh_notes(chat).await?;
         Ok(())
@@ -139,6 +172,47 @@ impl ModuleHelpers for Helper {
     fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
         vec![]
     }
+
+    fn register_inline(&self) {
+        register_inline_provider(&METADATA.name, Some("notes"), inline_notes);
+    }
+}
+
+/// Most chats searched for notes in a single inline query
+const INLINE_CHATS: usize = 10;
+
+/// Search text notes by name in the chats the user is in. Media notes and buttons are
+/// left out since they can't be sent from another chat
+async fn inline_notes(ctx: Arc<InlineContext>) -> Result<Vec<InlineQueryResult>> {
+    let search = ctx.text().to_lowercase();
+    let mut results = Vec::new();
+    for chat in get_user_chats(ctx.user().get_id())
+        .await?
+        .take(INLINE_CHATS)
+    {
+        let chat_name = match chat.get_chat().await? {
+            Some(c) => c.name_humanreadable().into_owned(),
+            None => continue,
+        };
+        for (name, (note, entities, _)) in refresh_notes(chat).await? {
+            if note.media_type != MediaType::Text || !name.to_lowercase().contains(&search) {
+                continue;
+            }
+            let mut message = EntityMessage::new(chat);
+            message.builder = MarkupBuilder::new(Some(entities))
+                .set_text(note.text.unwrap_or_default())
+                .filling(false);
+            results.push(
+                message
+                    .inline_article(format!("#{}", name), Some(chat_name.clone()))
+                    .await,
+            );
+            if results.len() >= MAX_RESULTS {
+                return Ok(results);
+            }
+        }
+    }
+    Ok(results)
 }
 
 async fn get_model<'a>(ctx: &'a Context, args: &'a TextArgs<'a>) -> Result<notes::Model> {
@@ -160,7 +234,12 @@ async fn get_model<'a>(ctx: &'a Context, args: &'a TextArgs<'a>) -> Result<notes
                 let (text, entities, buttons) = md
                     .build_murkdown()
                     .await
-                    .speak(ctx, lang_fmt!(ctx, "failmurk"))
+                    .speak_err_raw(ctx, |v| match v {
+                        BotError::MurkdownError(err) => {
+                            Some(lang_fmt!(ctx, "failmurkat", err.to_string().escape(false)))
+                        }
+                        _ => Some(lang_fmt!(ctx, "failmurk")),
+                    })
                     .await?;
                 let entity_id = entity::insert(*DB, &entities, buttons).await?;
                 (Some(text), entity_id)
@@ -196,7 +275,12 @@ async fn get_model<'a>(ctx: &'a Context, args: &'a TextArgs<'a>) -> Result<notes
                 let (text, entities, buttons) = md
                     .build_murkdown()
                     .await
-                    .speak(ctx, lang_fmt!(ctx, "failmurk"))
+                    .speak_err_raw(ctx, |v| match v {
+                        BotError::MurkdownError(err) => {
+                            Some(lang_fmt!(ctx, "failmurkat", err.to_string().escape(false)))
+                        }
+                        _ => Some(lang_fmt!(ctx, "failmurk")),
+                    })
                     .await?;
                 let entity_id = entity::insert(*DB, &entities, buttons).await?;
                 (Some(text), entity_id)
@@ -225,6 +309,8 @@ async fn handle_command<'a>(ctx: &Context) -> Result<()> {
             "get" => get(ctx).await,
             "delete" => delete(ctx, args).await,
             "notes" => list_notes(ctx).await,
+            "privatenotes" => private_notes(ctx, args).await,
+            "previewnote" => preview(ctx, args).await,
             "clearnotes" => clear_notes_cmd(ctx).await,
             "start" => {
                 let note: Option<(i64, String)> =
@@ -248,7 +334,6 @@ async fn print_note(
     buttons: Option<InlineKeyboardBuilder>,
     note_chat: i64,
 ) -> Result<()> {
-    let c = ctx.clone();
     if let Some(media_id) = note.media_id.as_ref() {
         if is_tainted(media_id, crate::tg::notes::MODULE_NAME, note_chat).await? {
             return ctx
@@ -264,12 +349,34 @@ async fn print_note(
                 .await;
         }
     }
+    if let Ok(message) = ctx.message() {
+        post_event(
+            message.get_chat().get_id(),
+            ChatEvent::NoteTrigger {
+                name: note.name.clone(),
+                user: message.get_from().map(|v| v.get_id()),
+            },
+        )
+        .await?;
+    }
+    send_note(ctx, note, entities, buttons, note_chat).await
+}
+
+/// Send a note without checking for stale media or posting a trigger event
+async fn send_note(
+    ctx: &Context,
+    note: notes::Model,
+    entities: Vec<MessageEntity>,
+    buttons: Option<InlineKeyboardBuilder>,
+    note_chat: i64,
+) -> Result<()> {
+    let c = ctx.clone();
     SendMediaReply::new(ctx, note.media_type)
         .button_callback(move |note, button| {
             let c = c.clone();
             async move {
                 button.on_push(move |b| async move {
-                    TG.client
+                    TG.client()
                         .build_answer_callback_query(b.get_id())
                         .build()
                         .await?;
@@ -290,8 +397,50 @@ async fn print_note(
     Ok(())
 }
 
-async fn print(message: &Context, name: String) -> Result<()> {
-    print_chat(message, name, message.message()?.get_chat().get_id()).await
+async fn print(ctx: &Context, name: String) -> Result<()> {
+    let chat = ctx.message()?.get_chat();
+    if !is_dm(chat) && dialog_or_default(chat).await?.private_notes {
+        send_note_link(ctx, name, chat.get_id()).await
+    } else {
+        print_chat(ctx, name, chat.get_id()).await
+    }
+}
+
+/// Reply with a button deep linking to the note in dm, for chats with private notes
+async fn send_note_link(ctx: &Context, name: String, chat: i64) -> Result<()> {
+    if get_note_by_name(name.clone(), chat).await?.is_none() {
+        return ctx.fail("Note not found");
+    }
+    let url = post_deep_link((chat, name.clone()), button_deeplink_key).await?;
+    let mut buttons = InlineKeyboardBuilder::default();
+    buttons.button(
+        InlineKeyboardButtonBuilder::new(lang_fmt!(ctx, "privatenotebutton"))
+            .set_url(url)
+            .build(),
+    );
+    let mut message = EntityMessage::new(chat);
+    message.builder.text(lang_fmt!(ctx, "privatenote", name));
+    ctx.reply_fmt(message.reply_markup(EReplyMarkup::InlineKeyboardMarkup(buttons.build())))
+        .await?;
+    Ok(())
+}
+
+async fn private_notes<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat();
+    match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => {
+            set_private_notes(chat, true).await?;
+            ctx.reply(lang_fmt!(ctx, "privatenoteson")).await?;
+        }
+        Some("off") | Some("no") => {
+            set_private_notes(chat, false).await?;
+            ctx.reply(lang_fmt!(ctx, "privatenotesoff")).await?;
+        }
+        _ => return ctx.fail(lang_fmt!(ctx, "privatenotesinvalid")),
+    }
+    Ok(())
 }
 
 async fn clear_notes_cmd(ctx: &Context) -> Result<()> {
@@ -338,6 +487,21 @@ async fn get<'a>(ctx: &Context) -> Result<()> {
     }
 }
 
+async fn preview<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let name = match args.args.first() {
+        Some(TextArg::Arg(name)) | Some(TextArg::Quote(name)) => (*name).to_owned(),
+        _ => return ctx.fail(lang_fmt!(ctx, "previewnoteinvalid")),
+    };
+    if let Some((note, entities, buttons)) = get_note_by_name(name, chat).await? {
+        send_note(ctx, note, entities, buttons, chat).await
+    } else {
+        ctx.fail("Note not found")
+    }
+}
+
 async fn delete_by_id(name: String, chat: i64) -> Result<()> {
     let hash_key = get_hash_key(chat);
     REDIS.sq(|q| q.hdel(&hash_key, &name)).await?;
@@ -354,19 +518,117 @@ async fn delete<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     Ok(())
 }
 
+/// Notes listed per page of /notes
+const NOTES_PAGE_SIZE: usize = 20;
+
+/// Render a single page of the note list
+fn render_notes_page(header: &str, names: &[String], page: usize) -> String {
+    let pages = names.len().div_ceil(NOTES_PAGE_SIZE).max(1);
+    let mut text = [header.to_owned()]
+        .into_iter()
+        .chain(
+            names
+                .iter()
+                .skip(page * NOTES_PAGE_SIZE)
+                .take(NOTES_PAGE_SIZE)
+                .map(|n| format!("- {}", n)),
+        )
+        .collect::<Vec<String>>()
+        .join("\n");
+    if pages > 1 {
+        text.push_str(&format!("\n\n{}/{}", page + 1, pages));
+    }
+    text
+}
+
 async fn list_notes(ctx: &Context) -> Result<()> {
     let message = ctx.message()?;
-    let notes = refresh_notes(message.get_chat().get_id()).await?;
-    let m = [lang_fmt!(
-        ctx,
-        "listnotes",
-        message.get_chat().name_humanreadable()
-    )]
-    .into_iter()
-    .chain(notes.keys().map(|n| format!("- {}", n)))
-    .collect::<Vec<String>>()
-    .join("\n");
-    message.reply(m).await?;
+    let chat = message.get_chat().get_id();
+    let names = refresh_notes(chat)
+        .await?
+        .into_keys()
+        .collect::<Vec<String>>();
+    let header = lang_fmt!(ctx, "listnotes", message.get_chat().name_humanreadable());
+    let m = render_notes_page(&header, &names, 0);
+    if names.len() <= NOTES_PAGE_SIZE {
+        message.reply(m).await?;
+        return Ok(());
+    }
+
+    let prev = InlineKeyboardButtonBuilder::new("<".to_owned())
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+    let next = InlineKeyboardButtonBuilder::new(">".to_owned())
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+
+    let mut buttons = InlineKeyboardBuilder::default();
+    buttons.button(prev.clone());
+    buttons.button(next.clone());
+    let markup = buttons.build();
+
+    let names = Arc::new(names);
+    let header = Arc::new(header);
+    let current = Arc::new(AtomicUsize::new(0));
+    for (button, forward) in [(&prev, false), (&next, true)] {
+        let names = Arc::clone(&names);
+        let header = Arc::clone(&header);
+        let current = Arc::clone(&current);
+        let markup = markup.clone();
+        button.on_push_multi(move |callback| {
+            let names = Arc::clone(&names);
+            let header = Arc::clone(&header);
+            let current = Arc::clone(&current);
+            let markup = markup.clone();
+            async move {
+                let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message()
+                else {
+                    return Ok(true);
+                };
+                let pages = names.len().div_ceil(NOTES_PAGE_SIZE);
+                let page = current.load(Ordering::Relaxed);
+                let page = if forward {
+                    (page + 1).min(pages.saturating_sub(1))
+                } else {
+                    page.saturating_sub(1)
+                };
+                current.store(page, Ordering::Relaxed);
+
+                let (text, entities, _) = MarkupBuilder::new(None)
+                    .set_text(render_notes_page(&header, &names, page))
+                    .filling(false)
+                    .header(false)
+                    .build_murkdown_nofail()
+                    .await;
+                TG.client()
+                    .build_edit_message_text(&text)
+                    .message_id(message.get_message_id())
+                    .chat_id(chat)
+                    .entities(&entities)
+                    .reply_markup(&markup)
+                    .link_preview_options(
+                        &LinkPreviewOptionsBuilder::new()
+                            .set_is_disabled(true)
+                            .build(),
+                    )
+                    .build()
+                    .await?;
+                TG.client()
+                    .build_answer_callback_query(callback.get_id())
+                    .build()
+                    .await?;
+                Ok(false)
+            }
+        });
+    }
+
+    let mut reply = EntityMessage::new(chat);
+    reply.builder = MarkupBuilder::new(None)
+        .set_text(m)
+        .filling(false)
+        .header(false);
+    ctx.reply_fmt(reply.reply_markup(EReplyMarkup::InlineKeyboardMarkup(markup)))
+        .await?;
     Ok(())
 }
 
@@ -375,10 +637,22 @@ async fn save<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     let message = ctx.message()?;
     let chat = message.get_chat().name_humanreadable();
     let model = get_model(ctx, args).await?;
+    if let Some(text) = model.text.as_deref() {
+        check_note_size(message, ctx.lang(), text)?;
+    }
+    let existing = notes::Entity::find()
+        .filter(
+            notes::Column::Chat
+                .eq(message.get_chat().get_id())
+                .and(notes::Column::Name.ne(model.name.as_str())),
+        )
+        .count(*DB)
+        .await?;
+    check_quota(message, ctx.lang(), Quota::Notes, existing, 1)?;
     let key = format!("note:{}:{}", message.get_chat().get_id(), model.name);
     log::info!("save key: {}", key);
     let hash_key = get_hash_key(message.get_chat().get_id());
-    REDIS.sq(|q| q.del(&hash_key)).await?;
+    REDIS.invalidate(&[&hash_key]).await?;
     let name = model.name.clone();
     notes::Entity::insert(model.cache(key).await?)
         .on_conflict(
@@ -429,7 +703,7 @@ pub async fn handle_update<'a>(cmd: &Context) -> Result<()> {
 
                 let key = get_hash_key(taint.chat);
 
-                REDIS.sq(|q| q.del(&key)).await?;
+                REDIS.invalidate(&[&key]).await?;
 
                 c.reply(lang_fmt!(c, "taintupdatednote", note.len()))
                     .await?;
@@ -0,0 +1,164 @@
+use crate::metadata::metadata;
+use crate::persist::user_data::{export_user_data, forget_user_keys};
+use crate::statics::TG;
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::optout::{get_optouts, set_optout, OptOut, OptOuts};
+use crate::util::error::{BotError, Fail, Result};
+use crate::util::string::{should_ignore_chat, Lang, Speak};
+use botapi::bot::Part;
+use botapi::gen_types::{
+    EReplyMarkup, FileData, InlineKeyboardButtonBuilder, InlineKeyboardMarkup,
+    MaybeInaccessibleMessage,
+};
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+use uuid::Uuid;
+
+metadata!("Privacy",
+    r#"
+    Request a copy of everything this bot has stored about you. Use /mydata in a private
+    chat with the bot to receive it as a json file. /forgetme deletes scores and other
+    activity kept outside the database, moderation records like bans and warns are kept.
+
+    You can also opt out of being pinged by /tagall (mentions) or having your activity counted
+    in digests and archives (stats). Opt-outs apply in every chat. Use /settings in a private
+    chat to toggle them with buttons.
+
+    Example: /optout mentions stats
+    "#,
+    { category = "Chat Management" },
+    { command = "mydata", help = "Export everything stored about you. Only works in dm" },
+    { command = "forgetme", help = "Delete your scores and other stored activity. Only works in dm" },
+    { command = "optout", help = "Usage: optout \\<mentions/stats\\>... Opt out of mass pings or statistics" },
+    { command = "optin", help = "Usage: optin \\<mentions/stats\\>... Undo an opt-out" },
+    { command = "settings", help = "Toggle your opt-outs. Only works in dm" },
+    { updates = [Message] }
+);
+
+async fn mydata(ctx: &Context) -> Result<()> {
+    ctx.is_dm_or_die().await?;
+    let message = ctx.message()?;
+    let user = message
+        .get_from()
+        .ok_or_else(|| BotError::Generic("user not found".to_owned()))?;
+    let export = export_user_data(user.get_id()).await?;
+    let export = serde_json::to_string_pretty(&export)?;
+    let bytes = FileData::Part(Part::text(export).file_name("mydata.json"));
+    if !should_ignore_chat(message.get_chat().get_id()).await? {
+        TG.client()
+            .build_send_document(message.get_chat().get_id(), bytes)
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn forgetme(ctx: &Context) -> Result<()> {
+    ctx.is_dm_or_die().await?;
+    let user = ctx.get_real_from()?;
+    forget_user_keys(user.get_id()).await?;
+    ctx.reply(lang_fmt!(ctx, "forgetme")).await?;
+    Ok(())
+}
+
+fn optout_list(optouts: &OptOuts) -> String {
+    OptOut::ALL
+        .iter()
+        .filter(|v| optouts.get(**v))
+        .map(|v| v.get_name())
+        .join(", ")
+}
+
+async fn optout(ctx: &Context, args: &TextArgs<'_>, value: bool) -> Result<()> {
+    let user = ctx.get_real_from()?;
+    let kinds = args
+        .args
+        .iter()
+        .map(|v| OptOut::from_name(&v.get_text().to_lowercase()))
+        .collect::<Option<Vec<OptOut>>>();
+    let kinds = match kinds {
+        Some(kinds) if !kinds.is_empty() => kinds,
+        _ => return ctx.fail(lang_fmt!(ctx, "optoutinvalid")),
+    };
+    let mut optouts = OptOuts::default();
+    for kind in kinds {
+        optouts = set_optout(user, kind, value).await?;
+    }
+    let list = optout_list(&optouts);
+    if list.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "optoutsnone")).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "optouts", list)).await?;
+    }
+    Ok(())
+}
+
+/// Build the settings menu with a toggle button for each opt-out. Buttons are replaced
+/// with a fresh menu each time one is pressed
+fn settings_markup(lang: Lang, optouts: OptOuts) -> InlineKeyboardMarkup {
+    let mut markup = InlineKeyboardBuilder::default();
+    for kind in OptOut::ALL {
+        let text = if optouts.get(kind) {
+            lang_fmt!(lang, "settingsoptedout", kind.get_name())
+        } else {
+            lang_fmt!(lang, "settingsoptedin", kind.get_name())
+        };
+        let button = InlineKeyboardButtonBuilder::new(text)
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        button.on_push_multi(move |callback| async move {
+            let user = callback.get_from();
+            let current = get_optouts(user.get_id()).await?;
+            let optouts = set_optout(user, kind, !current.get(kind)).await?;
+            if let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() {
+                TG.client()
+                    .build_edit_message_reply_markup()
+                    .message_id(message.get_message_id())
+                    .chat_id(message.get_chat().get_id())
+                    .reply_markup(&settings_markup(lang, optouts))
+                    .build()
+                    .await?;
+            }
+            TG.client()
+                .build_answer_callback_query(callback.get_id())
+                .build()
+                .await?;
+            Ok(true)
+        });
+        markup.button(button);
+        markup.newline();
+    }
+    markup.build()
+}
+
+async fn settings(ctx: &Context) -> Result<()> {
+    ctx.is_dm_or_die().await?;
+    let user = ctx.get_real_from()?;
+    let optouts = get_optouts(user.get_id()).await?;
+    let markup = settings_markup(*ctx.lang(), optouts);
+    let chat = ctx.message()?.get_chat().get_id();
+    if !should_ignore_chat(chat).await? {
+        TG.client()
+            .build_send_message(chat, &lang_fmt!(ctx, "settings"))
+            .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(markup))
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "mydata" => mydata(ctx).await?,
+            "forgetme" => forgetme(ctx).await?,
+            "optout" => optout(ctx, args, true).await?,
+            "optin" => optout(ctx, args, false).await?,
+            "settings" => settings(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use crate::metadata::metadata;
+use crate::statics::TG;
+use crate::tg::admin_helpers::DeleteAfterTime;
+use crate::tg::command::{Cmd, Context};
+use crate::tg::permissions::*;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use chrono::Duration;
+use macros::{lang_fmt, update_handler};
+use std::sync::Mutex;
+use std::time::Instant;
+
+metadata!("Purge",
+    r#"
+    Delete messages in bulk. Reply to a message with /purge to delete it and every message
+    sent after it, or use /spurge to do the same without any confirmation. /del deletes only
+    the message replied to. Telegram only lets bots delete messages from the last 48 hours,
+    older messages are skipped.
+    "#,
+    { category = "Moderation" },
+    { command = "purge", help = "Reply to a message to delete it and every message after it", level = Admin, requires = [CanDelete] },
+    { command = "spurge", help = "Like purge, but without a confirmation", level = Admin, requires = [CanDelete] },
+    { command = "del", help = "Reply to a message to delete it", level = Admin, requires = [CanDelete] },
+    { updates = [Message] }
+);
+
+/// Most messages a single purge deletes
+const MAX_PURGE: i64 = 5000;
+
+/// Purges of more messages than this report progress
+const PROGRESS_THRESHOLD: usize = 100;
+
+/// Seconds between progress updates, edits count towards telegram's limits too
+const PROGRESS_INTERVAL: u64 = 5;
+
+/// Seconds the confirmation of a finished purge stays up
+const DONE_SECONDS: i64 = 5;
+
+/// Get the ids of every message from the one replied to up to the command
+fn purge_range(ctx: &Context) -> Result<Option<Vec<i64>>> {
+    let message = ctx.message()?;
+    let Some(reply) = message.get_reply_to_message() else {
+        return Ok(None);
+    };
+    let (start, end) = (reply.get_message_id(), message.get_message_id());
+    if start > end || end - start >= MAX_PURGE {
+        return Ok(None);
+    }
+    Ok(Some((start..=end).collect()))
+}
+
+async fn purge(ctx: &Context, silent: bool) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some(messages) = purge_range(ctx)? else {
+        return ctx.fail(lang_fmt!(ctx, "purgeusage", MAX_PURGE));
+    };
+    let total = messages.len();
+    if silent {
+        return TG
+            .delete_messages_queued(chat, &messages, |_| async { Ok(()) })
+            .await;
+    }
+
+    let status = if total > PROGRESS_THRESHOLD {
+        ctx.speak(lang_fmt!(ctx, "purgeprogress", 0, total)).await?
+    } else {
+        None
+    };
+    let last = Mutex::new(Instant::now());
+    TG.delete_messages_queued(chat, &messages, |done| {
+        let update = done < total && {
+            let mut last = last.lock().unwrap();
+            let due = last.elapsed().as_secs() >= PROGRESS_INTERVAL;
+            if due {
+                *last = Instant::now();
+            }
+            due
+        };
+        let status = status.as_ref().filter(|_| update);
+        async move {
+            if let Some(status) = status {
+                TG.client()
+                    .build_edit_message_text(&lang_fmt!(ctx, "purgeprogress", done, total))
+                    .chat_id(chat)
+                    .message_id(status.get_message_id())
+                    .build()
+                    .await?;
+            }
+            Ok(())
+        }
+    })
+    .await?;
+
+    let done = lang_fmt!(ctx, "purged", total);
+    let done = match status {
+        Some(status) => {
+            TG.client()
+                .build_edit_message_text(&done)
+                .chat_id(chat)
+                .message_id(status.get_message_id())
+                .build()
+                .await?;
+            Some(status)
+        }
+        None => ctx.speak(done).await?,
+    };
+    if let Some(done) = done {
+        done.delete_after_time(Duration::try_seconds(DONE_SECONDS).unwrap());
+    }
+    Ok(())
+}
+
+async fn del(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let Some(reply) = message.get_reply_to_message() else {
+        return ctx.fail(lang_fmt!(ctx, "delusage"));
+    };
+    TG.delete_messages_queued(
+        message.get_chat().get_id(),
+        &[reply.get_message_id(), message.get_message_id()],
+        |_| async { Ok(()) },
+    )
+    .await
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+        match cmd {
+            "purge" => purge(ctx, false).await?,
+            "spurge" => purge(ctx, true).await?,
+            "del" => del(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
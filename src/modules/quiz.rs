@@ -0,0 +1,530 @@
+use self::entities::quiz_questions::{self, Answers};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::statics::{DB, REDIS, TG};
+use crate::tg::admin_helpers::parse_duration_str;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::markdown::Escape;
+use crate::tg::optout::{is_opted_out, OptOut};
+use crate::tg::user::GetUser;
+use crate::util::error::{Fail, Result};
+use crate::util::scheduler::{cancel, register_job, schedule};
+use crate::util::string::Speak;
+use botapi::gen_types::{InputPollOptionBuilder, PollAnswer, UpdateExt};
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use rand::seq::SliceRandom;
+use redis::AsyncCommands;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+metadata!("Quiz",
+    r#"
+    Run trivia in this chat using telegram's quiz polls. Admins add questions with /addquiz,
+    separating the question, the right answer, and the wrong answers with |. Every correct
+    answer earns a point on the chat's leaderboard. Users who opted out with /optout stats
+    are not scored.
+
+    Start a session with /quizsession to post several questions on a schedule.
+
+    [*Example:]
+    /addquiz What is the capital of France? | Paris | Lyon | Marseille
+    /quizsession 10 5m
+    /quizboard
+    "#,
+    Helper,
+    { category = "Fun" },
+    { command = "addquiz", help = "Usage: addquiz \\<question\\> | \\<right answer\\> | \\<wrong answers...\\>. Adds a quiz question", level = Admin, requires = [CanChangeInfo] },
+    { command = "rmquiz", help = "Usage: rmquiz \\<number\\>. Removes a question, see /quizzes for numbers", level = Admin, requires = [CanChangeInfo] },
+    { command = "quizzes", help = "Lists this chat's quiz questions", level = Admin },
+    { command = "quiz", help = "Posts a random quiz question", level = Admin },
+    { command = "quizsession", help = "Usage: quizsession \\<count\\> \\<interval\\> or quizsession off. Posts questions on a schedule", level = Admin, requires = [CanChangeInfo] },
+    { command = "quizboard", help = "Shows the quiz leaderboard" },
+    { command = "quizreset", help = "Clears the quiz leaderboard", level = Admin, requires = [CanChangeInfo] },
+    { updates = [Message, Other] }
+);
+
+/// Scheduler job kind for posting the next question of a session
+const QUIZ_JOB: &str = "quiz";
+
+/// Most questions a chat can have
+const MAX_QUESTIONS: u64 = 200;
+
+/// Most questions in one session
+const MAX_SESSION: u32 = 50;
+
+/// Telegram's limits on quiz polls
+const MAX_QUESTION_LEN: usize = 300;
+const MAX_ANSWER_LEN: usize = 100;
+const MAX_ANSWERS: usize = 10;
+
+/// Seconds each quiz poll stays open
+const QUIZ_SECONDS: i64 = 60;
+
+/// Users shown on the leaderboard
+const LEADERBOARD_SIZE: isize = 10;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(quiz_questions::Entity)
+                        .col(
+                            ColumnDef::new(quiz_questions::Column::Id)
+                                .big_integer()
+                                .not_null()
+                                .primary_key()
+                                .auto_increment(),
+                        )
+                        .col(
+                            ColumnDef::new(quiz_questions::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(quiz_questions::Column::Question)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(quiz_questions::Column::Answers)
+                                .json_binary()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(quiz_questions::Column::Correct)
+                                .integer()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_index(
+                    IndexCreateStatement::new()
+                        .table(quiz_questions::Entity)
+                        .name("quiz_questions_chat")
+                        .col(quiz_questions::Column::Chat)
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(quiz_questions::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod quiz_questions {
+        use sea_orm::{entity::prelude::*, FromJsonQueryResult};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, FromJsonQueryResult)]
+        pub struct Answers(pub Vec<String>);
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "quiz_questions")]
+        pub struct Model {
+            #[sea_orm(primary_key)]
+            pub id: i64,
+            pub chat: i64,
+            pub question: String,
+            #[sea_orm(column_type = "JsonBinary")]
+            pub answers: Answers,
+            /// index of the right answer
+            pub correct: i32,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000055_create_quiz"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// The next question of a session waiting in the scheduler
+#[derive(Serialize, Deserialize)]
+struct QuizSession {
+    chat: i64,
+    remaining: u32,
+    interval: i64,
+}
+
+/// A posted quiz poll, remembered until it closes so answers can be scored
+#[derive(Serialize, Deserialize)]
+struct QuizPoll {
+    chat: i64,
+    correct: i64,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn register_jobs(&self) {
+        register_job(QUIZ_JOB, |payload| async move {
+            let session: QuizSession = serde_json::from_value(payload)?;
+            run_session(session).await
+        });
+    }
+}
+
+#[inline(always)]
+fn get_scores_key(chat: i64) -> String {
+    format!("quizscore:{}", chat)
+}
+
+#[inline(always)]
+fn get_poll_key(poll: &str) -> String {
+    format!("quizpoll:{}", poll)
+}
+
+#[inline(always)]
+fn get_session_key(chat: i64) -> String {
+    format!("quizjob:{}", chat)
+}
+
+/// Split /addquiz arguments into the question and its answers, the first answer being the
+/// right one
+fn parse_question(text: &str) -> Option<(&str, Vec<&str>)> {
+    let mut parts = text.split('|').map(|v| v.trim());
+    let question = parts.next().filter(|v| !v.is_empty())?;
+    let answers = parts.collect::<Vec<&str>>();
+    if question.chars().count() > MAX_QUESTION_LEN
+        || !(2..=MAX_ANSWERS).contains(&answers.len())
+        || answers
+            .iter()
+            .any(|v| v.is_empty() || v.chars().count() > MAX_ANSWER_LEN)
+    {
+        return None;
+    }
+    Some((question, answers))
+}
+
+async fn get_questions(chat: i64) -> Result<Vec<quiz_questions::Model>> {
+    let res = quiz_questions::Entity::find()
+        .filter(quiz_questions::Column::Chat.eq(chat))
+        .order_by_asc(quiz_questions::Column::Id)
+        .all(*DB)
+        .await?;
+    Ok(res)
+}
+
+/// Post a random question as a quiz poll, returning false if the chat has none
+async fn post_question(chat: i64) -> Result<bool> {
+    let questions = get_questions(chat).await?;
+    let Some(question) = questions.choose(&mut rand::thread_rng()) else {
+        return Ok(false);
+    };
+    // shuffle so the right answer isn't always first
+    let mut order = (0..question.answers.0.len()).collect::<Vec<usize>>();
+    order.shuffle(&mut rand::thread_rng());
+    let Some(correct) = order.iter().position(|v| *v == question.correct as usize) else {
+        return Ok(false);
+    };
+    let options = order
+        .iter()
+        .map(|v| InputPollOptionBuilder::new(question.answers.0[*v].clone()).build())
+        .collect();
+    let message = TG
+        .client()
+        .build_send_poll(chat, &question.question, &options)
+        .tg_type("quiz")
+        .is_anonymous(false)
+        .correct_option_id(correct as i64)
+        .open_period(QUIZ_SECONDS)
+        .build()
+        .await?;
+    if let Some(poll) = message.get_poll() {
+        let key = get_poll_key(poll.get_id());
+        let quiz = serde_json::to_string(&QuizPoll {
+            chat,
+            correct: correct as i64,
+        })?;
+        REDIS
+            .pipe(|q| q.set(&key, quiz).expire(&key, QUIZ_SECONDS * 2).ignore())
+            .await?;
+    }
+    Ok(true)
+}
+
+/// Post the next question of a session and schedule the one after
+async fn run_session(session: QuizSession) -> Result<()> {
+    let key = get_session_key(session.chat);
+    if !post_question(session.chat).await? || session.remaining <= 1 {
+        REDIS.sq(|q| q.del(&key)).await?;
+        return Ok(());
+    }
+    let next = QuizSession {
+        remaining: session.remaining - 1,
+        ..session
+    };
+    let at = Utc::now() + Duration::try_seconds(next.interval).unwrap_or_default();
+    let job = schedule(QUIZ_JOB, &next, at).await?;
+    REDIS
+        .pipe(|q| {
+            q.set(&key, job.to_string())
+                .expire(&key, next.interval * 2)
+                .ignore()
+        })
+        .await?;
+    Ok(())
+}
+
+/// Cancel a chat's running session, returning false if there wasn't one
+async fn stop_session(chat: i64) -> Result<bool> {
+    let key = get_session_key(chat);
+    let job: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    let Some(job) = job.and_then(|v| Uuid::parse_str(&v).ok()) else {
+        return Ok(false);
+    };
+    cancel(&job).await?;
+    REDIS.sq(|q| q.del(&key)).await?;
+    Ok(true)
+}
+
+/// Give a point for a right answer to a quiz this module posted
+async fn score_answer(answer: &PollAnswer) -> Result<()> {
+    let Some(user) = answer.get_user() else {
+        return Ok(());
+    };
+    let key = get_poll_key(answer.get_poll_id());
+    let quiz: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    let Some(quiz) = quiz.and_then(|v| serde_json::from_str::<QuizPoll>(&v).ok()) else {
+        return Ok(());
+    };
+    if answer.get_option_ids().as_slice() != [quiz.correct]
+        || is_opted_out(user.get_id(), OptOut::Stats).await?
+    {
+        return Ok(());
+    }
+    let key = get_scores_key(quiz.chat);
+    REDIS.sq(|q| q.zincr(&key, user.get_id(), 1)).await?;
+    Ok(())
+}
+
+async fn add_quiz(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Some((question, answers)) = parse_question(args.text) else {
+        return ctx.fail(lang_fmt!(ctx, "addquizusage", MAX_ANSWERS));
+    };
+    let count = quiz_questions::Entity::find()
+        .filter(quiz_questions::Column::Chat.eq(chat))
+        .count(*DB)
+        .await?;
+    if count >= MAX_QUESTIONS {
+        return ctx.fail(lang_fmt!(ctx, "quizfull", MAX_QUESTIONS));
+    }
+    quiz_questions::Entity::insert(quiz_questions::ActiveModel {
+        id: NotSet,
+        chat: Set(chat),
+        question: Set(question.to_owned()),
+        answers: Set(Answers(answers.iter().map(|v| (*v).to_owned()).collect())),
+        correct: Set(0),
+    })
+    .exec(*DB)
+    .await?;
+    ctx.reply(lang_fmt!(ctx, "quizadded", question.escape(false)))
+        .await?;
+    Ok(())
+}
+
+async fn rm_quiz(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let questions = get_questions(chat).await?;
+    let Some(question) = args
+        .text
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|v| v.checked_sub(1))
+        .and_then(|v| questions.get(v))
+    else {
+        return ctx.fail(lang_fmt!(ctx, "rmquizusage"));
+    };
+    quiz_questions::Entity::delete_by_id(question.id)
+        .exec(*DB)
+        .await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "quizremoved",
+        question.question.escape(false)
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn list_quizzes(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let questions = get_questions(chat).await?;
+    if questions.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "noquizzes")).await?;
+        return Ok(());
+    }
+    let list = questions
+        .iter()
+        .enumerate()
+        .map(|(i, v)| format!("{}. {}", i + 1, v.question.escape(false)))
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(lang_fmt!(ctx, "listquizzes", list)).await?;
+    Ok(())
+}
+
+async fn quiz(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    if !post_question(chat).await? {
+        return ctx.fail(lang_fmt!(ctx, "noquizzes"));
+    }
+    Ok(())
+}
+
+async fn quiz_session(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    if let "off" | "stop" = args.text.trim() {
+        if stop_session(chat).await? {
+            ctx.reply(lang_fmt!(ctx, "quizsessionstopped")).await?;
+        } else {
+            ctx.reply(lang_fmt!(ctx, "quiznosession")).await?;
+        }
+        return Ok(());
+    }
+    let (Some(count), Some(interval)) = (args.args.first(), args.args.get(1)) else {
+        return ctx.fail(lang_fmt!(ctx, "quizsessionusage", MAX_SESSION));
+    };
+    let count = match count.get_text().parse::<u32>() {
+        Ok(count) if (1..=MAX_SESSION).contains(&count) => count,
+        _ => return ctx.fail(lang_fmt!(ctx, "quizsessionusage", MAX_SESSION)),
+    };
+    let Some(interval) = parse_duration_str(interval.get_text(), chat, message.get_message_id())?
+    else {
+        return ctx.fail(lang_fmt!(ctx, "quizsessionusage", MAX_SESSION));
+    };
+    // polls stay open for a minute, don't post the next one before the last closes
+    let interval = interval.num_seconds().max(QUIZ_SECONDS);
+    if get_questions(chat).await?.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "noquizzes"));
+    }
+    stop_session(chat).await?;
+    ctx.reply(lang_fmt!(ctx, "quizsessionstarted", count, interval))
+        .await?;
+    run_session(QuizSession {
+        chat,
+        remaining: count,
+        interval,
+    })
+    .await
+}
+
+async fn quiz_board(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let key = get_scores_key(chat);
+    let scores: Vec<(i64, u64)> = REDIS
+        .sq(|q| q.zrevrange_withscores(&key, 0, LEADERBOARD_SIZE - 1))
+        .await?;
+    if scores.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "quizboardempty")).await?;
+        return Ok(());
+    }
+    let mut list = Vec::with_capacity(scores.len());
+    for (i, (user, score)) in scores.into_iter().enumerate() {
+        list.push(format!(
+            "{}. {}: {}",
+            i + 1,
+            user.cached_name().await?.escape(false),
+            score
+        ));
+    }
+    ctx.reply(lang_fmt!(ctx, "quizboard", list.join("\n")))
+        .await?;
+    Ok(())
+}
+
+async fn quiz_reset(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let key = get_scores_key(ctx.message()?.get_chat().get_id());
+    REDIS.sq(|q| q.del(&key)).await?;
+    ctx.reply(lang_fmt!(ctx, "quizboardreset")).await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "addquiz" => add_quiz(ctx, args).await?,
+            "rmquiz" => rm_quiz(ctx, args).await?,
+            "quizzes" => list_quizzes(ctx).await?,
+            "quiz" => quiz(ctx).await?,
+            "quizsession" => quiz_session(ctx, args).await?,
+            "quizboard" => quiz_board(ctx).await?,
+            "quizreset" => quiz_reset(ctx).await?,
+            _ => (),
+        }
+    } else if let UpdateExt::PollAnswer(ref answer) = ctx.update() {
+        score_answer(answer).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn questions() {
+        assert_eq!(
+            parse_question("Capital of France? | Paris | Lyon "),
+            Some(("Capital of France?", vec!["Paris", "Lyon"]))
+        );
+        assert_eq!(parse_question("No answers | Paris"), None);
+        assert_eq!(parse_question(" | Paris | Lyon"), None);
+        assert_eq!(parse_question("Empty | Paris | "), None);
+    }
+}
@@ -0,0 +1,84 @@
+use crate::metadata::metadata;
+use crate::tg::command::{Cmd, Context};
+use crate::util::error::{Fail, Result};
+use macros::{lang_fmt, update_handler};
+
+metadata!("Quote",
+    r#"
+    Turn messages into stickers. Reply to a message with /quote and the bot draws it as a chat
+    bubble with the sender's name and the message formatting.
+    "#,
+    { category = "Fun" },
+    { command = "quote", help = "Reply to a message to turn it into a sticker" },
+    { updates = [Message] }
+);
+
+#[cfg(feature = "quote")]
+async fn quote(ctx: &Context) -> Result<()> {
+    use crate::statics::{CONFIG, TG};
+    use crate::tg::quote::{render_quote, Quote, Style};
+    use crate::tg::user::Username;
+    use botapi::gen_types::{FileData, ReplyParametersBuilder};
+
+    let message = ctx.message()?;
+    let Some(reply) = message.get_reply_to_message() else {
+        return ctx.fail(lang_fmt!(ctx, "quotereply"));
+    };
+    let (text, entities) = match (reply.get_text(), reply.get_caption()) {
+        (Some(text), _) => (text, reply.get_entities()),
+        (None, Some(caption)) => (caption, reply.get_caption_entities()),
+        (None, None) => return ctx.fail(lang_fmt!(ctx, "quotenotext")),
+    };
+    let name = if let Some(chat) = reply.get_sender_chat() {
+        chat.name_humanreadable().into_owned()
+    } else if let Some(user) = reply.get_from() {
+        user.name_humanreadable().into_owned()
+    } else {
+        String::new()
+    };
+    let entities = entities
+        .map(|entities| {
+            entities
+                .iter()
+                .filter_map(|e| {
+                    Style::from_entity(e.get_tg_type())
+                        .map(|s| (e.get_offset() as usize, e.get_length() as usize, s))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let quote = Quote {
+        name,
+        text: text.chars().take(CONFIG.quote.max_chars).collect(),
+        entities,
+    };
+
+    let bytes = match render_quote(quote).await {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            log::warn!("failed to render quote: {}", err);
+            return ctx.fail(lang_fmt!(ctx, "quotefailed"));
+        }
+    };
+    TG.client()
+        .build_send_sticker(message.get_chat().get_id(), FileData::Bytes(bytes))
+        .reply_parameters(&ReplyParametersBuilder::new(reply.get_message_id()).build())
+        .build()
+        .await?;
+    Ok(())
+}
+
+#[cfg(not(feature = "quote"))]
+async fn quote(ctx: &Context) -> Result<()> {
+    ctx.fail(lang_fmt!(ctx, "quotedisabled"))
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+        if cmd == "quote" {
+            quote(ctx).await?;
+        }
+    }
+    Ok(())
+}
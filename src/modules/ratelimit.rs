@@ -0,0 +1,91 @@
+use crate::metadata::metadata;
+use crate::tg::admin_helpers::set_rate_limits;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_dialog;
+use crate::tg::permissions::*;
+use crate::tg::ratelimit::get_rate_limits;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Rate Limits",
+    r#"
+    Limits how many commands the bot answers in this chat each minute, both for the whole chat
+    and for each user. Commands over the limit are ignored. Admins are never limited and
+    regular messages are always checked by locks and blocklists.
+
+    [*Example:]
+    /ratelimit user 5
+    /ratelimit chat off
+    /ratelimit user default
+    "#,
+    { category = "Moderation" },
+    { command = "ratelimit", help = "Usage: ratelimit \\[chat/user\\] \\[count/off/default\\]. Shows or sets the commands allowed per minute", level = Admin },
+    { updates = [Message] }
+);
+
+fn show_limit(limit: u32) -> String {
+    if limit == 0 {
+        "off".to_owned()
+    } else {
+        limit.to_string()
+    }
+}
+
+async fn ratelimit(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let mut args = args.args.iter().map(|v| v.get_text());
+    let (kind, value) = match (args.next(), args.next()) {
+        (None, _) => {
+            let (chat, user) = get_rate_limits(message).await?;
+            ctx.reply(lang_fmt!(
+                ctx,
+                "ratelimit",
+                show_limit(chat),
+                show_limit(user)
+            ))
+            .await?;
+            return Ok(());
+        }
+        (Some(kind), Some(value)) if kind == "chat" || kind == "user" => (kind, value),
+        _ => return ctx.fail(lang_fmt!(ctx, "ratelimitinvalid")),
+    };
+    let limit = match value {
+        "default" => None,
+        "off" => Some(0),
+        v => match v.parse::<i32>() {
+            Ok(v) if v > 0 => Some(v),
+            _ => return ctx.fail(lang_fmt!(ctx, "ratelimitinvalid")),
+        },
+    };
+    let dialog = get_dialog(message.get_chat()).await?;
+    let (chat_limit, user_limit) = dialog
+        .map(|v| (v.ratelimit_chat, v.ratelimit_user))
+        .unwrap_or_default();
+    if kind == "chat" {
+        set_rate_limits(message.get_chat(), limit, user_limit).await?;
+    } else {
+        set_rate_limits(message.get_chat(), chat_limit, limit).await?;
+    }
+    let (chat, user) = get_rate_limits(message).await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "ratelimit",
+        show_limit(chat),
+        show_limit(user)
+    ))
+    .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "ratelimit" {
+            ratelimit(ctx, args).await?;
+        }
+    }
+    Ok(())
+}
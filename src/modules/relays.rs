@@ -0,0 +1,507 @@
+use self::entities::relays::{self, RelayFilter};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::core::media::{GetMediaId, MediaType};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, ME, REDIS, TG};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::markdown::{normalize_entities, Escape};
+use crate::tg::permissions::*;
+use crate::tg::user::{get_chat, Username};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::{
+    Chat, MaybeInaccessibleMessage, Message, MessageEntity, MessageEntityBuilder, UpdateExt,
+};
+use chrono::Duration;
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::{ColumnTrait, Condition, EntityTrait, QueryFilter};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Relays",
+    r#"
+    Mirror messages from one chat or channel into another. Relayed messages start with a
+    header naming the chat and author they came from and keep their formatting. Text and
+    photos, videos, and documents are relayed, other messages are skipped.
+    You have to be an admin in both chats, and the bot has to be able to post in the
+    receiving one.
+
+    A relay can be limited to messages from admins, or to messages admins pin. Messages sent
+    by the bot itself are never relayed, so relays in both directions can't loop. Each relay
+    passes on at most a few messages a minute, the rest are dropped.
+
+    [*Example:]
+    Mirror pinned messages from this chat to another:
+    /relay to -1001234567890 pins
+    Mirror a channel into this chat:
+    /relay from -1009876543210
+    "#,
+    Helper,
+    { category = "Content" },
+    { command = "relay", help = "Usage: relay \\<to/from\\> \\<chat id\\> \\[all/admins/pins\\]. Mirror messages between this chat and another", level = Admin, requires = [CanChangeInfo] },
+    { command = "rmrelay", help = "Usage: rmrelay \\<chat id\\>. Stop relaying between this chat and another", level = Admin, requires = [CanChangeInfo] },
+    { command = "relays", help = "List relays to and from this chat", level = Admin },
+    { updates = [Message] }
+);
+
+/// Most messages passed on by a single relay each minute, matching telegram's limit on
+/// messages sent to a group
+const RELAY_PER_MINUTE: i64 = 20;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(relays::Entity)
+                        .col(
+                            ColumnDef::new(relays::Column::Source)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(relays::Column::Target)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(relays::Column::Filter).integer().not_null())
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(relays::Column::Source)
+                                .col(relays::Column::Target)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            manager
+                .create_index(
+                    IndexCreateStatement::new()
+                        .name("relays_target")
+                        .table(relays::Entity)
+                        .col(relays::Column::Target)
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(relays::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod relays {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// Which messages a relay passes on
+        #[derive(
+            EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq,
+        )]
+        #[sea_orm(rs_type = "i32", db_type = "Integer")]
+        pub enum RelayFilter {
+            #[sea_orm(num_value = 1)]
+            All,
+            /// messages from admins, anonymous admins, and channel posts
+            #[sea_orm(num_value = 2)]
+            Admins,
+            /// messages when they are pinned
+            #[sea_orm(num_value = 3)]
+            Pins,
+        }
+
+        impl RelayFilter {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    "all" => Some(Self::All),
+                    "admins" => Some(Self::Admins),
+                    "pins" => Some(Self::Pins),
+                    _ => None,
+                }
+            }
+
+            pub fn get_name(&self) -> &'static str {
+                match self {
+                    Self::All => "all",
+                    Self::Admins => "admins",
+                    Self::Pins => "pins",
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "relays")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub source: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub target: i64,
+            pub filter: RelayFilter,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000051_create_relays"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+#[inline(always)]
+fn get_relays_key(source: i64) -> String {
+    format!("relays:{}", source)
+}
+
+#[inline(always)]
+fn get_rate_key(source: i64, target: i64) -> String {
+    format!("relayrate:{}:{}", source, target)
+}
+
+/// Get the relays passing on messages from a chat
+async fn get_relays(source: i64) -> Result<Vec<relays::Model>> {
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = relays::Entity::find()
+                .filter(relays::Column::Source.eq(source))
+                .all(*DB)
+                .await?;
+            Ok(Some(res))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_relays_key(source), &())
+    .await?;
+    Ok(res.unwrap_or_default())
+}
+
+/// Prefix text with a bold header, moving its entities to still cover the same text
+fn with_header(
+    header: &str,
+    text: &str,
+    entities: Option<&Vec<MessageEntity>>,
+) -> (String, Vec<MessageEntity>) {
+    let header_len = header.encode_utf16().count() as i64;
+    let text = format!("{}\n{}", header, text);
+    let mut res = vec![MessageEntityBuilder::new(0, header_len)
+        .set_type("bold".to_owned())
+        .build()];
+    res.extend(entities.into_iter().flatten().map(|v| {
+        let mut v = v.clone();
+        v.set_offset(v.get_offset() + header_len + 1);
+        v
+    }));
+    normalize_entities(&text, &mut res);
+    (text, res)
+}
+
+/// Does a relay with this filter pass on this message
+async fn passes(filter: RelayFilter, message: &Message) -> Result<bool> {
+    let chat = message.get_chat();
+    let res = match filter {
+        RelayFilter::All => true,
+        RelayFilter::Admins => {
+            chat.get_tg_type() == "channel"
+                || message.get_sender_chat().map(|v| v.get_id()) == Some(chat.get_id())
+                || message.get_from().is_admin(chat).await?
+        }
+        RelayFilter::Pins => false,
+    };
+    Ok(res)
+}
+
+/// Send a copy of a message to a relay's target, returning false if the message can't be
+/// relayed
+async fn relay_message(source: &Chat, target: i64, message: &Message) -> Result<bool> {
+    let author = if let Some(chat) = message.get_sender_chat() {
+        chat.name_humanreadable().into_owned()
+    } else if let Some(user) = message.get_from() {
+        user.name_humanreadable_unescape().into_owned()
+    } else {
+        source.name_humanreadable().into_owned()
+    };
+    let header = if author == source.name_humanreadable() {
+        author
+    } else {
+        format!("{} in {}", author, source.name_humanreadable())
+    };
+
+    if let Some(text) = message.get_text() {
+        let (text, entities) = with_header(&header, text, message.get_entities());
+        TG.client()
+            .build_send_message(target, &text)
+            .entities(&entities)
+            .build()
+            .await?;
+        return Ok(true);
+    }
+    if let Some((_, MediaType::Photo | MediaType::Video | MediaType::Document)) =
+        message.get_media_id()
+    {
+        let (caption, entities) = with_header(
+            &header,
+            message.get_caption().unwrap_or_default(),
+            message.get_caption_entities(),
+        );
+        TG.client()
+            .build_copy_message(target, source.get_id(), message.get_message_id())
+            .caption(&caption)
+            .caption_entities(&entities)
+            .build()
+            .await?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Pass a message on to every relay from its chat
+async fn handle_relays(message: &Message) -> Result<()> {
+    let me = ME.get().unwrap().get_id();
+    if message.get_from().map(|v| v.get_id()) == Some(me) {
+        return Ok(());
+    }
+    let source = message.get_chat();
+    let relays = get_relays(source.get_id()).await?;
+    if relays.is_empty() {
+        return Ok(());
+    }
+    let pinned = message.get_pinned_message();
+    for relay in relays {
+        let relayed: &Message = match (relay.filter, pinned) {
+            (RelayFilter::Pins, Some(MaybeInaccessibleMessage::Message(pinned))) => pinned,
+            (_, Some(_)) => continue,
+            (filter, None) if passes(filter, message).await? => message,
+            _ => continue,
+        };
+        let key = get_rate_key(relay.source, relay.target);
+        let (sent,): (i64,) = REDIS
+            .pipe(|q| q.incr(&key, 1).expire(&key, 60).ignore())
+            .await?;
+        if sent > RELAY_PER_MINUTE {
+            continue;
+        }
+        if let Err(err) = relay_message(source, relay.target, relayed).await {
+            log::warn!(
+                "failed to relay message from {} to {}: {}",
+                relay.source,
+                relay.target,
+                err
+            );
+            err.record_stats();
+        }
+    }
+    Ok(())
+}
+
+/// Parse a chat id argument, checking the bot knows the chat and the caller is an admin in it
+async fn get_other_chat(ctx: &Context, arg: &str) -> Result<Chat> {
+    let message = ctx.message()?;
+    let Ok(id) = arg.parse::<i64>() else {
+        return ctx.fail(lang_fmt!(ctx, "relayusage"));
+    };
+    if id == message.get_chat().get_id() {
+        return ctx.fail(lang_fmt!(ctx, "relayself"));
+    }
+    let Some(chat) = get_chat(id).await? else {
+        return ctx.fail(lang_fmt!(ctx, "relaychatunknown", id));
+    };
+    message.get_from().admin_or_die(&chat).await?;
+    Ok(chat)
+}
+
+async fn relay(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat();
+    let (direction, other, filter) = match args.args.as_slice() {
+        [direction, other] => (direction.get_text(), other.get_text(), RelayFilter::All),
+        [direction, other, filter] => match RelayFilter::from_name(filter.get_text()) {
+            Some(filter) => (direction.get_text(), other.get_text(), filter),
+            None => return ctx.fail(lang_fmt!(ctx, "relayusage")),
+        },
+        _ => return ctx.fail(lang_fmt!(ctx, "relayusage")),
+    };
+    let other = get_other_chat(ctx, other).await?;
+    let (source, target) = match direction {
+        "to" => (chat, &other),
+        "from" => (&other, chat),
+        _ => return ctx.fail(lang_fmt!(ctx, "relayusage")),
+    };
+    relays::Entity::insert(relays::ActiveModel {
+        source: Set(source.get_id()),
+        target: Set(target.get_id()),
+        filter: Set(filter),
+    })
+    .on_conflict(
+        OnConflict::columns([relays::Column::Source, relays::Column::Target])
+            .update_column(relays::Column::Filter)
+            .to_owned(),
+    )
+    .exec(*DB)
+    .await?;
+    REDIS
+        .invalidate(&[&get_relays_key(source.get_id())])
+        .await?;
+    ctx.reply(lang_fmt!(
+        ctx,
+        "relayadded",
+        filter.get_name(),
+        source.name_humanreadable().escape(false),
+        target.name_humanreadable().escape(false)
+    ))
+    .await?;
+    Ok(())
+}
+
+async fn rmrelay(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let Ok(other) = args.text.trim().parse::<i64>() else {
+        return ctx.fail(lang_fmt!(ctx, "rmrelayusage"));
+    };
+    let res = relays::Entity::delete_many()
+        .filter(
+            Condition::any()
+                .add(
+                    relays::Column::Source
+                        .eq(chat)
+                        .and(relays::Column::Target.eq(other)),
+                )
+                .add(
+                    relays::Column::Source
+                        .eq(other)
+                        .and(relays::Column::Target.eq(chat)),
+                ),
+        )
+        .exec(*DB)
+        .await?;
+    REDIS
+        .invalidate(&[&get_relays_key(chat), &get_relays_key(other)])
+        .await?;
+    if res.rows_affected == 0 {
+        return ctx.fail(lang_fmt!(ctx, "relaymissing", other));
+    }
+    ctx.reply(lang_fmt!(ctx, "relayremoved", other)).await?;
+    Ok(())
+}
+
+async fn list_relays(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let relays = relays::Entity::find()
+        .filter(
+            Condition::any()
+                .add(relays::Column::Source.eq(chat))
+                .add(relays::Column::Target.eq(chat)),
+        )
+        .all(*DB)
+        .await?;
+    if relays.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "norelays")).await?;
+        return Ok(());
+    }
+    let mut list = Vec::with_capacity(relays.len());
+    for relay in relays {
+        let (direction, other) = if relay.source == chat {
+            ("to", relay.target)
+        } else {
+            ("from", relay.source)
+        };
+        let name = get_chat(other)
+            .await?
+            .map(|v| v.name_humanreadable().escape(false).into_owned())
+            .unwrap_or_else(|| other.to_string());
+        list.push(format!(
+            "{} {} ({}), {}",
+            direction,
+            name,
+            other,
+            relay.filter.get_name()
+        ));
+    }
+    ctx.reply(lang_fmt!(ctx, "listrelays", list.join("\n")))
+        .await?;
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "relay" => relay(ctx, args).await?,
+            "rmrelay" => rmrelay(ctx, args).await?,
+            "relays" => {
+                ctx.check_permissions(|p| p.can_change_info).await?;
+                list_relays(ctx).await?;
+            }
+            _ => (),
+        }
+        return Ok(());
+    }
+    match ctx.update() {
+        UpdateExt::Message(message) | UpdateExt::ChannelPost(message) => {
+            handle_relays(message).await?
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_keeps_entities() {
+        let entities = vec![MessageEntityBuilder::new(6, 5)
+            .set_type("italic".to_owned())
+            .build()];
+        let (text, entities) = with_header("Néws", "hello world", Some(&entities));
+        assert_eq!(text, "Néws\nhello world");
+        assert_eq!(entities.len(), 2);
+        assert_eq!(entities[0].get_tg_type(), "bold");
+        assert_eq!(entities[0].get_length(), 4);
+        assert_eq!(entities[1].get_offset(), 11);
+        assert_eq!(entities[1].get_length(), 5);
+    }
+}
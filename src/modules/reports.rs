@@ -1,23 +1,167 @@
-use crate::statics::TG;
-use crate::tg::command::{Cmd, Context};
+use crate::statics::{REDIS, TG};
+use crate::tg::admin_helpers::{
+    ban_message, get_report_optouts, kick, kick_message, set_report_optouts, ActionMessage,
+    DeleteAfterTime,
+};
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{Cmd, Context, TextArgs};
 
+use crate::tg::events::{publish, ModEvent};
 use crate::tg::permissions::*;
-use crate::tg::user::GetUser;
+use crate::tg::user::{GetUser, Username};
 use crate::util::error::{BotError, Fail};
-use crate::util::string::{should_ignore_chat, Speak};
+use crate::util::string::{should_ignore_chat, Lang, Speak};
 use crate::{metadata::metadata, util::error::Result};
-use botapi::gen_types::{MessageEntity, MessageEntityBuilder, ReplyParametersBuilder};
+use botapi::gen_types::{
+    CallbackQuery, Chat, EReplyMarkup, InlineKeyboardButtonBuilder, MaybeInaccessibleMessage,
+    Message, MessageEntity, MessageEntityBuilder, ReplyParametersBuilder,
+};
+use chrono::Duration;
 
 use macros::{lang_fmt, textentity_fmt, update_handler};
+use redis::AsyncCommands;
+use uuid::Uuid;
 
 metadata!("Reports",
     r#"
-    Allow users to report wrongdoers to admins. Each report notifies up to 4 admins.
+    Allow users to report wrongdoers to admins, by replying to a message with /report or
+    @admins. Admins are pinged along with buttons to ban or kick the reported user, delete the
+    reported message, or ignore the report. Admins who don't want to be pinged can opt out with
+    /reports off.
+
+    Each user can only send a few reports every 10 minutes.
     "#,
-    { command = "report", help = "Reports a user"}
+    { category = "Moderation" },
+    { command = "report", help = "Reports a user"},
+    { command = "reports", help = "Usage: reports \\<on/off\\>. Choose whether reports in this chat ping you", level = Admin },
+    { updates = [Message] }
 
 );
 
+/// Most reports a user can send in a window
+const REPORT_LIMIT: u64 = 3;
+
+/// Seconds in a user's report window
+const REPORT_WINDOW: i64 = 10 * 60;
+
+/// Seconds a notice about a rate limited report stays up
+const NOTICE_SECONDS: i64 = 30;
+
+/// What an admin chose to do about a report
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReportAction {
+    Ban,
+    Kick,
+    Delete,
+    Ignore,
+}
+
+#[inline(always)]
+fn get_limit_key(chat: i64, user: i64) -> String {
+    format!("rplimit:{}:{}", chat, user)
+}
+
+/// Count a report against the reporter's limit, returning false if it is used up
+async fn take_report(chat: i64, user: i64) -> Result<bool> {
+    let key = get_limit_key(chat, user);
+    let (count,): (u64,) = REDIS
+        .pipe(|q| q.incr(&key, 1).expire(&key, REPORT_WINDOW).ignore())
+        .await?;
+    Ok(count <= REPORT_LIMIT)
+}
+
+/// Carry out an admin's choice from the buttons under a report
+async fn report_button(
+    callback: CallbackQuery,
+    action: ReportAction,
+    user: i64,
+    reported: Option<Message>,
+    lang: Lang,
+) -> Result<bool> {
+    let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() else {
+        return Ok(true);
+    };
+    let chat = message.get_chat();
+    let permissions = NamedBotPermissions::from_chatuser(callback.get_from(), chat).await?;
+    let permission = match action {
+        ReportAction::Ban | ReportAction::Kick => permissions.can_restrict_members,
+        ReportAction::Delete => permissions.can_delete_messages,
+        ReportAction::Ignore => permissions.can_manage_chat,
+    };
+    if !permission.is_granted() && !permissions.is_sudo.is_granted() {
+        TG.client()
+            .build_answer_callback_query(callback.get_id())
+            .text(&lang_fmt!(lang, "reportdenied"))
+            .show_alert(true)
+            .build()
+            .await?;
+        return Ok(false);
+    }
+
+    match (action, reported.as_ref()) {
+        (ReportAction::Ban, Some(reported)) => ban_message(reported, None).await?,
+        (ReportAction::Ban, None) => {
+            TG.client()
+                .build_ban_chat_member(chat.get_id(), user)
+                .build()
+                .await?;
+            publish(ModEvent::UserBanned {
+                chat: chat.get_id(),
+                user,
+                until: None,
+            });
+        }
+        (ReportAction::Kick, Some(reported)) => kick_message(reported).await?,
+        (ReportAction::Kick, None) => kick(user, chat.get_id()).await?,
+        (ReportAction::Delete, Some(reported)) => reported.delete().await?,
+        (ReportAction::Delete, None) | (ReportAction::Ignore, _) => (),
+    }
+
+    let admin = callback.get_from().name_humanreadable_unescape();
+    let text = match action {
+        ReportAction::Ban => lang_fmt!(lang, "reportban", admin),
+        ReportAction::Kick => lang_fmt!(lang, "reportkick", admin),
+        ReportAction::Delete => lang_fmt!(lang, "reportdelete", admin),
+        ReportAction::Ignore => lang_fmt!(lang, "reportignore", admin),
+    };
+    TG.client()
+        .build_edit_message_text(&text)
+        .message_id(message.get_message_id())
+        .chat_id(chat.get_id())
+        .build()
+        .await?;
+    TG.client()
+        .build_answer_callback_query(callback.get_id())
+        .build()
+        .await?;
+    Ok(true)
+}
+
+/// Buttons for acting on a report. Deleting is only offered when a message was reported
+fn report_buttons(lang: Lang, user: i64, reported: Option<&Message>) -> InlineKeyboardBuilder {
+    let mut buttons = InlineKeyboardBuilder::default();
+    let actions = [
+        (ReportAction::Ban, lang_fmt!(lang, "reportbanbutton")),
+        (ReportAction::Kick, lang_fmt!(lang, "reportkickbutton")),
+        (ReportAction::Delete, lang_fmt!(lang, "reportdeletebutton")),
+        (ReportAction::Ignore, lang_fmt!(lang, "reportignorebutton")),
+    ];
+    for (action, name) in actions {
+        if action == ReportAction::Delete && reported.is_none() {
+            continue;
+        }
+        let button = InlineKeyboardButtonBuilder::new(name)
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        let reported = reported.cloned();
+        button.on_push_multi(move |callback| {
+            report_button(callback, action, user, reported.clone(), lang)
+        });
+        buttons.button(button);
+    }
+    buttons
+}
+
 pub async fn report(ctx: &Context) -> Result<()> {
     if let Some(chat) = ctx.chat() {
         if should_ignore_chat(chat.get_id()).await? {
@@ -25,21 +169,29 @@ pub async fn report(ctx: &Context) -> Result<()> {
         }
 
         ctx.is_group_or_die().await?;
-        if ctx
-            .message()?
-            .get_from()
-            .is_admin(ctx.message()?.get_chat())
-            .await?
-        {
+        let message = ctx.message()?;
+        if message.get_from().is_admin(message.get_chat()).await? {
             return Err(BotError::Generic("Admins can't warn".into()));
         }
+        if let Some(reporter) = message.get_from() {
+            if !take_report(chat.get_id(), reporter.get_id()).await? {
+                if let Some(notice) = ctx
+                    .reply(lang_fmt!(ctx, "reportlimit", REPORT_WINDOW / 60))
+                    .await?
+                {
+                    notice.delete_after_time(Duration::try_seconds(NOTICE_SECONDS).unwrap());
+                }
+                return Ok(());
+            }
+        }
 
-        ctx.action_message_some(|ctx, user, _, _| async move {
+        ctx.action_message_some(|ctx, user, _, action| async move {
             if let Some(chat) = ctx.chat() {
                 if let Some(user) = user {
                     if user.is_admin(chat).await? {
                         return ctx.fail("I am not going to report an admin, what the FLOOP");
                     }
+                    let optouts = get_report_optouts(chat).await?;
                     let mut admins = ctx
                         .message()?
                         .get_chat()
@@ -47,6 +199,8 @@ pub async fn report(ctx: &Context) -> Result<()> {
                         .await?
                         .values()
                         .filter(|v| !v.is_anon_admin())
+                        .filter(|v| !v.get_user().get_is_bot())
+                        .filter(|v| !optouts.contains(&v.get_user().get_id()))
                         .map(|a| {
                             MessageEntityBuilder::new(0, 0)
                                 .set_type("text_mention".to_owned())
@@ -55,6 +209,11 @@ pub async fn report(ctx: &Context) -> Result<()> {
                         })
                         .collect::<Vec<MessageEntity>>();
 
+                    let reported = match action {
+                        ActionMessage::Reply(reported) => Some(reported),
+                        ActionMessage::Me(_) => None,
+                    };
+                    let mut buttons = report_buttons(*ctx.lang(), user, reported);
                     let mention = user.mention().await?;
                     let te = textentity_fmt!(ctx, "reported", mention);
                     let (text, entities) = (&te.builder.text, &te.builder.entities);
@@ -65,6 +224,7 @@ pub async fn report(ctx: &Context) -> Result<()> {
                             &ReplyParametersBuilder::new(ctx.message()?.get_message_id()).build(),
                         )
                         .entities(&admins)
+                        .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(buttons.build_owned()))
                         .build()
                         .await?;
                 } else {
@@ -78,10 +238,42 @@ pub async fn report(ctx: &Context) -> Result<()> {
     Ok(())
 }
 
+/// Let an admin choose whether reports in a chat ping them
+async fn reports_optout(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let chat: &Chat = message.get_chat();
+    let Some(admin) = message.get_from() else {
+        return Ok(());
+    };
+    let mut optouts = get_report_optouts(chat).await?;
+    match args.text.trim() {
+        "on" | "yes" => {
+            optouts.remove(&admin.get_id());
+            set_report_optouts(chat, &optouts).await?;
+            ctx.reply(lang_fmt!(ctx, "reportson")).await?;
+        }
+        "off" | "no" => {
+            optouts.insert(admin.get_id());
+            set_report_optouts(chat, &optouts).await?;
+            ctx.reply(lang_fmt!(ctx, "reportsoff")).await?;
+        }
+        "" if optouts.contains(&admin.get_id()) => {
+            ctx.reply(lang_fmt!(ctx, "reportsoff")).await?;
+        }
+        "" => {
+            ctx.reply(lang_fmt!(ctx, "reportson")).await?;
+        }
+        _ => return ctx.fail(lang_fmt!(ctx, "reportsusage")),
+    }
+    Ok(())
+}
+
 async fn handle_command(ctx: &Context) -> Result<()> {
-    if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
         match cmd {
             "report" => report(ctx).await,
+            "reports" => reports_optout(ctx, args).await,
             _ => Ok(()),
         }?;
     }
@@ -0,0 +1,385 @@
+use crate::metadata::metadata;
+use crate::statics::{spawn, REDIS, TG};
+use crate::tg::bridges::is_bridged_id;
+use crate::tg::command::{trim_prefix, Cmd, Context, TextArgs};
+use crate::tg::markdown::{EntityMessage, Escape};
+use crate::tg::optout::{is_opted_out, OptOut};
+use crate::tg::permissions::*;
+use crate::tg::quotas::{check_quota, Quota};
+use crate::tg::roles::{
+    add_role_member, delete_role, get_role_commands, get_role_members, get_roles, grant_command,
+    parse_role_name, remove_role_member, revoke_command, UNGRANTABLE,
+};
+use crate::tg::user::{GetUser, Username};
+use crate::util::error::{BotError, Fail, Result, SpeakErr};
+use crate::util::string::Speak;
+use botapi::gen_types::{Message, UpdateExt, User};
+use itertools::Itertools;
+use macros::{entity_fmt, lang_fmt, update_handler};
+use std::time::Duration;
+
+metadata!("Roles",
+    r#"
+    Group members into roles that can be mentioned all at once. Adding a member to a role
+    that doesn't exist yet creates it, and a role is gone once its last member is removed.
+
+    Mention a role with @name in any message to ping everyone in it. Each role can only be
+    mentioned once a minute, and members who opted out with /optout mentions aren't pinged.
+    Roles can also limit who can use a custom command, see /help custom commands.
+
+    Roles can be granted bot commands that normally need admin rights, so their members
+    can use them without being made admins. A role keeps its commands when its last member
+    is removed, and loses them when it is deleted with /rmrole. Commands for managing
+    roles can't be granted.
+
+    [*Example:]
+    /role add @someone moderator
+    /role grant moderator warn
+    @helpers can someone take a look at this?
+    "#,
+    { category = "Chat Management" },
+    { command = "roleadd", help = "Usage: roleadd \\<user\\> \\<role\\>. Adds a user to a role, creating it if needed", level = Admin },
+    { command = "roledel", help = "Usage: roledel \\<user\\> \\<role\\>. Removes a user from a role", level = Admin },
+    { command = "rmrole", help = "Usage: rmrole \\<role\\>. Deletes a role along with its members", level = Admin },
+    { command = "role", help = "Usage: role \\<add/del\\> \\<user\\> \\<role\\>, or role \\<grant/revoke\\> \\<role\\> \\<command\\>. Manages role members and the commands a role can use", level = Admin },
+    { command = "roles", help = "Lists the roles in this chat, or the members and commands of a role" },
+    { updates = [Message] }
+);
+
+/// Most mentions in a single message
+const BATCH_SIZE: usize = 5;
+
+/// Delay between mention messages
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// Seconds before a role can be mentioned again
+const COOLDOWN: i64 = 60;
+
+/// Most roles expanded from a single message
+const MAX_ROLES_PER_MESSAGE: usize = 3;
+
+#[inline(always)]
+fn get_cooldown_key(chat: i64, role: &str) -> String {
+    format!("rolemention:{}:{}", chat, role)
+}
+
+/// Find the roles mentioned in a message, in the order they appear
+fn mentioned_roles(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter(|v| v.starts_with('@'))
+        .filter_map(|v| parse_role_name(v.trim_end_matches(|c: char| !c.is_alphanumeric())))
+        .unique()
+        .take(MAX_ROLES_PER_MESSAGE)
+        .collect()
+}
+
+async fn role_add(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    ctx.action_user(|ctx, user, args| async move {
+        let message = ctx.message()?;
+        let chat = message.get_chat().get_id();
+        // the role is last so /role add works the same as /roleadd
+        let Some(role) = args
+            .and_then(|a| a.args.last())
+            .and_then(|v| parse_role_name(v.get_text()))
+        else {
+            return ctx.fail(lang_fmt!(ctx, "roleaddusage"));
+        };
+        let roles = role_names(chat).await?;
+        if !roles.contains(&role) {
+            check_quota(message, ctx.lang(), Quota::Roles, roles.len() as u64, 1)?;
+        }
+        add_role_member(chat, user, &role).await?;
+        let name = user.mention().await?;
+        ctx.reply_fmt(entity_fmt!(ctx, "roleadded", name, role))
+            .await?;
+        Ok(())
+    })
+    .await
+    .speak_err_raw(ctx, |v| match v {
+        BotError::UserNotFound => Some(lang_fmt!(ctx, "failuser", "add to a role")),
+        _ => None,
+    })
+    .await?;
+    Ok(())
+}
+
+async fn role_del(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    ctx.action_user(|ctx, user, args| async move {
+        let chat = ctx.message()?.get_chat().get_id();
+        let Some(role) = args
+            .and_then(|a| a.args.last())
+            .and_then(|v| parse_role_name(v.get_text()))
+        else {
+            return ctx.fail(lang_fmt!(ctx, "roledelusage"));
+        };
+        let name = user.mention().await?;
+        if remove_role_member(chat, user, &role).await? {
+            ctx.reply_fmt(entity_fmt!(ctx, "roleremoved", name, role))
+                .await?;
+        } else {
+            ctx.reply_fmt(entity_fmt!(ctx, "rolenotmember", name, role))
+                .await?;
+        }
+        Ok(())
+    })
+    .await
+    .speak_err_raw(ctx, |v| match v {
+        BotError::UserNotFound => Some(lang_fmt!(ctx, "failuser", "remove from a role")),
+        _ => None,
+    })
+    .await?;
+    Ok(())
+}
+
+/// Names of every role in a chat, whether it has members, commands, or both
+async fn role_names(chat: i64) -> Result<Vec<String>> {
+    let members = get_roles(chat).await?;
+    let commands = get_role_commands(chat).await?;
+    Ok(members
+        .into_keys()
+        .chain(commands.into_keys())
+        .sorted()
+        .dedup()
+        .collect())
+}
+
+/// Grant or revoke a command for a role
+async fn role_command(ctx: &Context, args: &TextArgs<'_>, grant: bool) -> Result<()> {
+    ctx.check_permissions(|p| p.can_promote_members).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let [_, role, command] = args.args.as_slice() else {
+        return ctx.fail(lang_fmt!(ctx, "roleusage"));
+    };
+    let Some(role) = parse_role_name(role.get_text()) else {
+        return ctx.fail(lang_fmt!(ctx, "roleusage"));
+    };
+    let command = trim_prefix(command.get_text()).to_lowercase();
+    if TG.modules.command_level(&command).is_none() {
+        return ctx.fail(lang_fmt!(ctx, "rolenocmd", command.escape(false)));
+    }
+    if grant {
+        if UNGRANTABLE.contains(&command.as_str()) {
+            return ctx.fail(lang_fmt!(ctx, "roleungrantable", command));
+        }
+        let roles = role_names(chat).await?;
+        if !roles.contains(&role) {
+            check_quota(message, ctx.lang(), Quota::Roles, roles.len() as u64, 1)?;
+        }
+        grant_command(chat, &role, &command).await?;
+        ctx.reply(lang_fmt!(ctx, "rolegranted", role, command))
+            .await?;
+    } else if revoke_command(chat, &role, &command).await? {
+        ctx.reply(lang_fmt!(ctx, "rolerevoked", role, command))
+            .await?;
+    } else {
+        ctx.fail(lang_fmt!(ctx, "rolenotgranted", role, command))?;
+    }
+    Ok(())
+}
+
+async fn role_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    match args
+        .args
+        .first()
+        .map(|v| v.get_text().to_lowercase())
+        .as_deref()
+    {
+        Some("add") => role_add(ctx).await,
+        Some("del") | Some("remove") => role_del(ctx).await,
+        Some("grant") => role_command(ctx, args, true).await,
+        Some("revoke") => role_command(ctx, args, false).await,
+        _ => ctx.fail(lang_fmt!(ctx, "roleusage")),
+    }
+}
+
+async fn rm_role(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let Some(role) = args
+        .args
+        .first()
+        .and_then(|v| parse_role_name(v.get_text()))
+    else {
+        return ctx.fail(lang_fmt!(ctx, "rmroleusage"));
+    };
+    let chat = ctx.message()?.get_chat().get_id();
+    if delete_role(chat, &role).await? {
+        ctx.reply(lang_fmt!(ctx, "roledeleted", role)).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "rolenotfound", role)).await?;
+    }
+    Ok(())
+}
+
+async fn list_roles(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let m = match args.args.first() {
+        Some(role) => {
+            let Some(role) = parse_role_name(role.get_text()) else {
+                return ctx.fail(lang_fmt!(ctx, "rolenotfound", role.get_text()));
+            };
+            let members = get_role_members(chat, &role).await?;
+            let commands = get_role_commands(chat)
+                .await?
+                .remove(&role)
+                .unwrap_or_default();
+            if members.is_empty() && commands.is_empty() {
+                return ctx.fail(lang_fmt!(ctx, "rolenotfound", role));
+            }
+            let mut lines = vec![lang_fmt!(ctx, "listrolemembers", role)];
+            for member in members {
+                lines.push(format!("- {}", member.cached_name().await?.escape(false)));
+            }
+            if !commands.is_empty() {
+                let commands = commands.iter().map(|v| format!("/{}", v)).join(" ");
+                lines.push(lang_fmt!(ctx, "listrolecommands", commands.escape(false)));
+            }
+            lines.join("\n")
+        }
+        None => {
+            let roles = get_roles(chat).await?;
+            let commands = get_role_commands(chat).await?;
+            let names = role_names(chat).await?;
+            if names.is_empty() {
+                ctx.reply(lang_fmt!(ctx, "noroles")).await?;
+                return Ok(());
+            }
+            [lang_fmt!(ctx, "listroles")]
+                .into_iter()
+                .chain(names.iter().map(|role| {
+                    let members = roles.get(role).map(|v| v.len()).unwrap_or(0);
+                    match commands.get(role) {
+                        Some(commands) => format!(
+                            "- @{} \\({}\\): {}",
+                            role.escape(false),
+                            members,
+                            commands
+                                .iter()
+                                .map(|v| format!("/{}", v))
+                                .join(" ")
+                                .escape(false)
+                        ),
+                        None => format!("- @{} \\({}\\)", role.escape(false), members),
+                    }
+                }))
+                .collect::<Vec<String>>()
+                .join("\n")
+        }
+    };
+    ctx.reply(m).await?;
+    Ok(())
+}
+
+/// Get the members of a role that can be pinged, leaving out the user mentioning the
+/// role, users who opted out, bots, and anyone the bot has no cached user for
+async fn get_mentionable(chat: i64, role: &str, sender: i64) -> Result<Vec<User>> {
+    let mut users = Vec::new();
+    for member in get_role_members(chat, role).await? {
+        if member == sender || is_bridged_id(member) {
+            continue;
+        }
+        if is_opted_out(member, OptOut::Mentions).await? {
+            continue;
+        }
+        if let Some(user) = member.get_cached_user().await? {
+            if !user.get_is_bot() {
+                users.push(user);
+            }
+        }
+    }
+    Ok(users)
+}
+
+/// Send the mentions for a role in batches, with the role name in front of the first one
+async fn send_mentions(chat: i64, role: String, users: Vec<User>) -> Result<()> {
+    let header = format!("@{}\n", role);
+    for (i, batch) in users.chunks(BATCH_SIZE).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(BATCH_DELAY).await;
+        }
+        let mut message = EntityMessage::new(chat);
+        if i == 0 {
+            message.builder.text(&header);
+        }
+        for (j, user) in batch.iter().enumerate() {
+            if j > 0 {
+                message.builder.text(" ");
+            }
+            message
+                .builder
+                .text_mention(user.get_first_name(), user.clone(), None);
+        }
+        chat.speak_fmt(message).await?;
+    }
+    Ok(())
+}
+
+/// Ping the members of every role mentioned in a message
+async fn expand_mentions(message: &Message) -> Result<()> {
+    let Some(sender) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(text) = message.get_text().or_else(|| message.get_caption()) else {
+        return Ok(());
+    };
+    let chat = message.get_chat().get_id();
+    let roles = mentioned_roles(text);
+    if roles.is_empty() {
+        return Ok(());
+    }
+    let existing = get_roles(chat).await?;
+    for role in roles.into_iter().filter(|v| existing.contains_key(v)) {
+        let key = get_cooldown_key(chat, &role);
+        let (first,): (bool,) = REDIS
+            .pipe(|q| q.set_nx(&key, true).expire(&key, COOLDOWN).ignore())
+            .await?;
+        if !first {
+            continue;
+        }
+        let users = get_mentionable(chat, &role, sender.get_id()).await?;
+        if users.is_empty() {
+            continue;
+        }
+        spawn(async move {
+            if let Err(err) = send_mentions(chat, role, users).await {
+                log::warn!("role mention in {} stopped: {}", chat, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "role" => role_cmd(ctx, args).await?,
+            "roleadd" => role_add(ctx).await?,
+            "roledel" => role_del(ctx).await?,
+            "rmrole" => rm_role(ctx, args).await?,
+            "roles" => list_roles(ctx, args).await?,
+            _ => (),
+        };
+    } else if let UpdateExt::Message(ref message) = ctx.update() {
+        expand_mentions(message).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mentions() {
+        assert_eq!(
+            mentioned_roles("@Helpers, can @mods or @helpers look? email@example.com"),
+            vec!["helpers".to_owned(), "mods".to_owned()]
+        );
+        assert!(mentioned_roles("no roles here @").is_empty());
+        assert_eq!(mentioned_roles("@a @b @c @d").len(), MAX_ROLES_PER_MESSAGE);
+    }
+}
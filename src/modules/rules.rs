@@ -1,46 +1,72 @@
-use crate::metadata::metadata;
+use crate::metadata::{metadata, ModuleHelpers};
 use crate::persist::core::media::{get_media_type, MediaType, SendMediaReply};
-use crate::persist::core::rules;
-use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
-use crate::statics::{CONFIG, DB};
+use crate::persist::core::{entity, rules};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS};
 
+use crate::tg::admin_helpers::is_dm;
+use crate::tg::button::InlineKeyboardBuilder;
 use crate::tg::command::{handle_deep_link, Cmd, Context};
-use crate::tg::markdown::rules_deeplink_key;
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
+use crate::tg::markdown::{get_markup_for_buttons, rules_deeplink_key, MarkupBuilder};
 use crate::tg::permissions::IsGroupAdmin;
+use crate::tg::rosemd::{RoseMdDecompiler, RoseMdParser};
+use crate::tg::template::ChatTemplate;
 use crate::util::error::Result;
 use crate::util::string::{Lang, Speak};
+use botapi::gen_types::{Chat, MessageEntity};
 use chrono::Duration;
 use futures::FutureExt;
 use macros::{lang_fmt, update_handler};
-use sea_orm::EntityTrait;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel};
+use sea_orm_migration::MigrationTrait;
 use sea_query::OnConflict;
+use serde::{Deserialize, Serialize};
 
 metadata!("Rules",
     r#"
     Set rules for your chat. Rules can be murkdown formatted text \(see /help formatting\)
-    or images, video, stickers, etc. Rules can be accessed via formfilling using the \{rules\}
-    tag in filters or notes. This will create a button attached to the message linking to the rules
-    in dm.
+    or images, video, stickers, etc. Replying to a message with /setrules keeps its formatting.
+    Rules can be accessed via formfilling using the \{rules\} tag in welcomes, filters, or notes.
+    This will create a button attached to the message linking to the rules in dm.
+
+    [*Example:]
+    /setrules No spam, no NSFW, be nice
+    /rules
     "#,
-    { command = "setrules", help = "Sets the current rules for this chat" },
-    { command = "rules", help = "Gets the rules in dm"}
+    Helper,
+    { category = "Chat Management" },
+    { command = "setrules", help = "Sets the current rules for this chat", level = Admin },
+    { command = "rules", help = "Replies with a button to read the rules in dm"},
+    { updates = [Message] }
 );
 
-fn rules_model(ctx: &Context) -> Result<rules::Model> {
+async fn rules_model(ctx: &Context) -> Result<rules::Model> {
     let message = ctx.message()?;
-    let (text, media_id, media_type) = if let Some(message) = message.get_reply_to_message() {
-        let (media_id, media_type) = get_media_type(message)?;
-
+    let (media, text, extra) = if let Some(message) = message.get_reply_to_message() {
         (
+            message,
             message.get_text().map(|t| t.to_owned()),
-            media_id,
-            media_type,
+            message.get_entities().map(|v| v.to_owned()),
         )
     } else {
-        let (media_id, media_type) = get_media_type(message)?;
         let text = ctx.cmd().map(|Cmd { ref args, .. }| args.text.to_owned());
-        (text, media_id, media_type)
+        (message, text, None)
+    };
+
+    let (text, entity_id) = if let Some(text) = text {
+        let (text, entities, buttons) = MarkupBuilder::new(extra)
+            .set_text(text)
+            .filling(false)
+            .header(false)
+            .build_murkdown_nofail()
+            .await;
+        let entity_id = entity::insert(*DB, &entities, buttons).await?;
+        (Some(text), entity_id)
+    } else {
+        (None, None)
     };
+    let (media_id, media_type) = get_media_type(media)?;
 
     let model = rules::Model {
         chat_id: message.get_chat().get_id(),
@@ -49,6 +75,7 @@ fn rules_model(ctx: &Context) -> Result<rules::Model> {
         media_id,
         media_type,
         button_name: "Rules".to_owned(),
+        entity_id,
     };
     Ok(model)
 }
@@ -58,12 +85,135 @@ fn get_rules_key(chat: i64) -> String {
     format!("rules:{}", chat)
 }
 
-async fn save_rule<'a>(ctx: &Context) -> Result<()> {
+#[derive(Debug)]
+struct Helper;
+
+/// A chat's rules, with formatting and buttons written as rose markdown
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportRules {
+    text: Option<String>,
+    media_id: Option<String>,
+    media_type: MediaType,
+    private: bool,
+    button_name: String,
+}
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let Some((rules, entities, buttons)) = get_rule(chat).await? else {
+            return Ok(None);
+        };
+        let buttons = buttons.unwrap_or_default().build();
+        let text = rules.text.map(|text| {
+            RoseMdDecompiler::new(&text, &entities, buttons.get_inline_keyboard()).decompile()
+        });
+        let rules = ExportRules {
+            text,
+            media_id: rules.media_id,
+            media_type: rules.media_type,
+            private: rules.private,
+            button_name: rules.button_name,
+        };
+        Ok(Some(serde_json::to_value(rules)?))
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let rules: ExportRules = serde_json::from_value(value)?;
+        let (text, entity_id) = if let Some(text) = rules.text {
+            let (text, entities, buttons) = RoseMdParser::new(&text, true).parse();
+            (Some(text), entity::insert(*DB, &entities, buttons).await?)
+        } else {
+            (None, None)
+        };
+        let model = rules::Model {
+            chat_id: chat,
+            text,
+            media_id: rules.media_id,
+            media_type: rules.media_type,
+            private: rules.private,
+            button_name: rules.button_name,
+            entity_id,
+        };
+        rules::Entity::insert(model.into_active_model())
+            .on_conflict(
+                OnConflict::column(rules::Column::ChatId)
+                    .update_columns([
+                        rules::Column::Text,
+                        rules::Column::MediaId,
+                        rules::Column::MediaType,
+                        rules::Column::Private,
+                        rules::Column::ButtonName,
+                        rules::Column::EntityId,
+                    ])
+                    .to_owned(),
+            )
+            .exec(*DB)
+            .await?;
+        let key = get_rules_key(chat);
+        REDIS.invalidate(&[&key]).await?;
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("rules")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        Vec::new()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        // rose bot's rules section has a different layout
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+
+    async fn apply_template(&self, chat: &Chat, template: &ChatTemplate) -> Result<()> {
+        let Some(RulesTemplate { text: Some(text) }) =
+            template.section::<RulesTemplate>("rules")?
+        else {
+            return Ok(());
+        };
+        let model = rules::Model {
+            chat_id: chat.get_id(),
+            media_id: None,
+            media_type: MediaType::Text,
+            private: false,
+            text: Some(text),
+            button_name: "Rules".to_owned(),
+            entity_id: None,
+        };
+        // never clobber rules the chat already has
+        rules::Entity::insert(model.into_active_model())
+            .on_conflict(
+                OnConflict::column(rules::Column::ChatId)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(*DB)
+            .await?;
+        let key = get_rules_key(chat.get_id());
+        REDIS.invalidate(&[&key]).await?;
+        Ok(())
+    }
+}
+
+/// The rules section of a chat template, a skeleton for admins to fill in with /setrules
+#[derive(Deserialize)]
+struct RulesTemplate {
+    text: Option<String>,
+}
+
+async fn save_rule(ctx: &Context) -> Result<()> {
     ctx.check_permissions(|p| p.can_change_info).await?;
     let message = ctx.message()?;
     let key = get_rules_key(message.get_chat().get_id());
-    let model = rules_model(ctx)?;
-    rules::Entity::insert(model.cache(&key).await?)
+    let model = rules_model(ctx).await?;
+    rules::Entity::insert(model.into_active_model())
         .on_conflict(
             OnConflict::column(rules::Column::ChatId)
                 .update_columns([
@@ -71,11 +221,13 @@ async fn save_rule<'a>(ctx: &Context) -> Result<()> {
                     rules::Column::MediaId,
                     rules::Column::MediaType,
                     rules::Column::Private,
+                    rules::Column::EntityId,
                 ])
                 .to_owned(),
         )
         .exec(*DB)
         .await?;
+    REDIS.invalidate(&[&key]).await?;
 
     ctx.reply(lang_fmt!(ctx.try_get()?.lang, "saverules"))
         .await?;
@@ -91,21 +243,51 @@ fn default_rules(chat_id: i64, lang: &Lang) -> rules::Model {
         private: false,
         text: Some(lang_fmt!(lang, "norules")),
         button_name: "Rules".to_owned(),
+        entity_id: None,
     }
 }
 
+/// In groups reply with a button deep linking to the rules in dm. In dm there is nowhere
+/// else to go, so send the rules right away
 async fn rules(ctx: &Context) -> Result<()> {
-    ctx.reply(lang_fmt!(ctx.try_get()?.lang, "getrules"))
-        .await?;
-    Ok(())
+    let message = ctx.message()?;
+    if is_dm(message.get_chat()) {
+        send_rules(ctx, message.get_chat().get_id()).await
+    } else {
+        ctx.reply(lang_fmt!(ctx.try_get()?.lang, "getrules"))
+            .await?;
+        Ok(())
+    }
 }
 
-async fn get_rule(chat_id: i64) -> Result<Option<rules::Model>> {
+/// Get a chat's rules along with their entities and buttons
+async fn get_rule(
+    chat_id: i64,
+) -> Result<
+    Option<(
+        rules::Model,
+        Vec<MessageEntity>,
+        Option<InlineKeyboardBuilder>,
+    )>,
+> {
     let key = get_rules_key(chat_id);
     let rules = default_cache_query(
         |_, _| async move {
-            let r = rules::Entity::find_by_id(chat_id).one(*DB).await?;
-            Ok(r)
+            let res = rules::get_rules_join(rules::Column::ChatId.eq(chat_id)).await?;
+            Ok(res
+                .into_iter()
+                .map(|(rules, (entity, button))| {
+                    (
+                        rules,
+                        entity
+                            .into_iter()
+                            .map(|e| e.get())
+                            .map(|(e, u)| e.to_entity(u))
+                            .collect(),
+                        get_markup_for_buttons(button.into_iter().collect()),
+                    )
+                })
+                .next())
         },
         Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
     )
@@ -114,6 +296,26 @@ async fn get_rule(chat_id: i64) -> Result<Option<rules::Model>> {
     Ok(rules)
 }
 
+/// Send the rules for chat_id in reply to the current message
+async fn send_rules(ctx: &Context, chat_id: i64) -> Result<()> {
+    let (rules, entities, buttons) = match get_rule(chat_id).await? {
+        Some((rules, entities, buttons)) => (rules, Some(entities), buttons),
+        None => (default_rules(chat_id, ctx.try_get()?.lang), None, None),
+    };
+    let reply = SendMediaReply::new(ctx, rules.media_type)
+        .button_callback(|_, _| async move { Ok(()) }.boxed())
+        .text(rules.text)
+        .media_id(rules.media_id)
+        .buttons(buttons);
+    // the default rules are murkdown, saved rules are already formatted
+    let reply = if let Some(entities) = entities {
+        reply.extra_entities(entities)
+    } else {
+        reply
+    };
+    reply.send_media_reply().await
+}
+
 #[update_handler]
 pub async fn handle_update(ctx: &Context) -> Result<()> {
     if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
@@ -123,18 +325,7 @@ pub async fn handle_update(ctx: &Context) -> Result<()> {
             "start" => {
                 let key: Option<i64> = handle_deep_link(ctx, rules_deeplink_key).await?;
                 if let Some(chat_id) = key {
-                    let rules = if let Some(rules) = get_rule(chat_id).await? {
-                        rules
-                    } else {
-                        default_rules(chat_id, ctx.try_get()?.lang)
-                    };
-
-                    SendMediaReply::new(ctx, rules.media_type)
-                        .button_callback(|_, _| async move { Ok(()) }.boxed())
-                        .text(rules.text)
-                        .media_id(rules.media_id)
-                        .send_media_reply()
-                        .await?;
+                    send_rules(ctx, chat_id).await?;
                 }
                 Ok(())
             }
@@ -1,12 +1,22 @@
-use crate::metadata::metadata;
-use crate::tg::admin_helpers::ActionMessage;
-use crate::tg::command::{Cmd, Context};
+use self::entities::chat_scripts::{self, ScriptEvent};
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::{ActionMessage, UpdateHelpers, UserChanged};
+use crate::tg::command::{Cmd, Context, PopSlice};
+use crate::tg::events::{publish, ModEvent};
 use crate::tg::markdown::MarkupType;
+use crate::tg::permissions::IsGroupAdmin;
+use crate::tg::quotas::{check_quota, check_script_size, Quota};
 use crate::util::error::{Fail, Result, SpeakErr};
-use crate::util::scripting::{ManagedRhai, RHAI_ENGINE};
+use crate::util::scripting::{ManagedRhai, ModAction, HOOK_ENGINE, RHAI_ENGINE};
 use crate::util::string::Speak;
-use macros::{entity_fmt, update_handler};
-use rhai::Dynamic;
+use chrono::Duration;
+use macros::{entity_fmt, lang_fmt, update_handler};
+use rhai::{Dynamic, FuncArgs};
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::OnConflict;
 
 metadata!(
     "Scripting",
@@ -61,18 +71,418 @@ metadata!(
     ]
 
     [__helper functions:]\n
-    Text processing functionality is provided via helper functions. Current there is just [`glob]
-    which allows text globbing similar to how the blocklist module works.
+    Text processing functionality is provided via helper functions. [`glob] allows text globbing
+    similar to how the blocklist module works, and [`regex] matches a regular expression anywhere in the text.
     [`rust`
-    |m| glob("*coin*", m.from.value.username) // ban some coin scammers. Returns true if username has "coin"
+    |m| glob("*coin*", m.from.value.username) // ban some coin scammers. Returns true if username has "coin"\n
+    |m| regex("c[o0]in", m.text.value)
     ]
 
     [__supported modules:]\n
     Currently the [*blocklists] module has alpha quality support for scripting in blocklists.
     for more information please see /help blocklists"#,
-    {command = "eval", help = "Evaluates a test script using the current message as a parameter"}
+    Helper,
+    { category = "Content" },
+    { sub = "hooks", content = r#"
+    Admins can save scripts that run automatically in their chat. Each script is an
+    anonymous function like the ones used with /eval, and runs on one kind of event:\n
+    [*message]: every message, called with the message. Messages from admins are skipped\n
+    [*join]: every new member, called with the chat member update\n
+    [*command]: the /name command, where name is the script name, called with the message
+
+    Scripts return a ModAction to act on the event, or nothing to do nothing. Returning
+    text is the same as ModAction::Reply. On joins only replies, mutes, and bans are
+    supported.
+    [`rust`
+    /addscript message nocoin |m| if regex("c[o0]in", m.text.value) { ModAction::Warn("no coins") }\n
+    /addscript join hello |u| "welcome " + u.from.first_name\n
+    /addscript command ping |m| "pong"
+    ]
+
+    Scripts are limited in size and in how long they can run, scripts that run too long are
+    stopped without doing anything.
+    "# },
+    {command = "eval", help = "Evaluates a test script using the current message as a parameter"},
+    { command = "addscript", help = "Usage: addscript \\<message/join/command\\> \\<name\\> \\<script\\>. Saves a script run on every message, join, or /name command", level = Admin },
+    { command = "rmscript", help = "Usage: rmscript \\<name\\>. Deletes a saved script", level = Admin },
+    { command = "scripts", help = "Lists the scripts saved in this chat", level = Admin },
+    { updates = [Message, ChatMember] }
 );
 
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(chat_scripts::Entity)
+                        .col(
+                            ColumnDef::new(chat_scripts::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(chat_scripts::Column::Name).text().not_null())
+                        .col(
+                            ColumnDef::new(chat_scripts::Column::Event)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(chat_scripts::Column::Script)
+                                .text()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(chat_scripts::Column::Chat)
+                                .col(chat_scripts::Column::Name)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(chat_scripts::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod chat_scripts {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// What a saved script is run on
+        #[derive(EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, PartialEq, Debug)]
+        #[sea_orm(rs_type = "i32", db_type = "Integer")]
+        pub enum ScriptEvent {
+            #[sea_orm(num_value = 1)]
+            Message,
+            #[sea_orm(num_value = 2)]
+            Join,
+            #[sea_orm(num_value = 3)]
+            Command,
+        }
+
+        impl ScriptEvent {
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    "message" => Some(Self::Message),
+                    "join" => Some(Self::Join),
+                    "command" => Some(Self::Command),
+                    _ => None,
+                }
+            }
+
+            pub fn get_name(&self) -> &'static str {
+                match self {
+                    Self::Message => "message",
+                    Self::Join => "join",
+                    Self::Command => "command",
+                }
+            }
+        }
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "chat_scripts")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub name: String,
+            pub event: ScriptEvent,
+            #[sea_orm(column_type = "Text")]
+            pub script: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000031_create_chat_scripts"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+#[inline(always)]
+fn get_scripts_key(chat: i64) -> String {
+    format!("scripts:{}", chat)
+}
+
+/// Get every script saved in a chat
+async fn get_scripts(chat: i64) -> Result<Vec<chat_scripts::Model>> {
+    let key = get_scripts_key(chat);
+    let scripts = default_cache_query(
+        |_, _| async move {
+            let res = chat_scripts::Entity::find()
+                .filter(chat_scripts::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?;
+            Ok(Some(res))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(scripts.unwrap_or_default())
+}
+
+async fn add_script(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    ctx.action_message(|ctx, am, args| async move {
+        let usage = || ctx.fail_err(lang_fmt!(ctx, "addscriptusage"));
+        let (event, args) = args.and_then(|a| a.pop_slice()).ok_or_else(usage)?;
+        let event = ScriptEvent::from_name(event.get_text()).ok_or_else(usage)?;
+        let (name, args) = args.pop_slice().ok_or_else(usage)?;
+        let name = name.get_text().to_lowercase();
+        let (message, script) = match am {
+            ActionMessage::Me(message) => (message, args.text),
+            ActionMessage::Reply(message) => (message, message.get_text().ok_or_else(usage)?),
+        };
+        if script.trim().is_empty() {
+            return Err(usage());
+        }
+        if event == ScriptEvent::Command && TG.modules.command_level(&name).is_some() {
+            return ctx.fail(lang_fmt!(ctx, "scriptcommandtaken", name));
+        }
+        check_script_size(message, ctx.lang(), script)?;
+        let chat = message.get_chat().get_id();
+        let existing = chat_scripts::Entity::find()
+            .filter(
+                chat_scripts::Column::Chat
+                    .eq(chat)
+                    .and(chat_scripts::Column::Name.ne(name.as_str())),
+            )
+            .count(*DB)
+            .await?;
+        check_quota(message, ctx.lang(), Quota::Scripts, existing, 1)?;
+        ManagedRhai::new(script.to_owned(), &HOOK_ENGINE)
+            .compile()
+            .speak_err(ctx, |e| lang_fmt!(ctx, "scriptcompile", e))
+            .await?;
+
+        let model = chat_scripts::Model {
+            chat,
+            name: name.clone(),
+            event: event.clone(),
+            script: script.to_owned(),
+        };
+        chat_scripts::Entity::insert(model.into_active_model())
+            .on_conflict(
+                OnConflict::columns([chat_scripts::Column::Chat, chat_scripts::Column::Name])
+                    .update_columns([chat_scripts::Column::Event, chat_scripts::Column::Script])
+                    .to_owned(),
+            )
+            .exec(*DB)
+            .await?;
+        let key = get_scripts_key(chat);
+        REDIS.invalidate(&[&key]).await?;
+        ctx.reply(lang_fmt!(ctx, "scriptsaved", name, event.get_name()))
+            .await?;
+        Ok(())
+    })
+    .await
+}
+
+async fn rm_script(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let Some(Cmd { args, .. }) = ctx.cmd() else {
+        return Ok(());
+    };
+    let Some(name) = args.args.first().map(|v| v.get_text().to_lowercase()) else {
+        return ctx.fail(lang_fmt!(ctx, "rmscriptusage"));
+    };
+    let chat = ctx.message()?.get_chat().get_id();
+    let res = chat_scripts::Entity::delete_by_id((chat, name.clone()))
+        .exec(*DB)
+        .await?;
+    let key = get_scripts_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    if res.rows_affected > 0 {
+        ctx.reply(lang_fmt!(ctx, "scriptremoved", name)).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "scriptnotfound", name)).await?;
+    }
+    Ok(())
+}
+
+async fn list_scripts(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let scripts = get_scripts(chat).await?;
+    if scripts.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "noscripts")).await?;
+        return Ok(());
+    }
+    let m = [lang_fmt!(ctx, "listscripts")]
+        .into_iter()
+        .chain(
+            scripts
+                .iter()
+                .map(|v| format!("- {} \\({}\\)", v.name, v.event.get_name())),
+        )
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(m).await?;
+    Ok(())
+}
+
+/// Run a saved script, returning what it asked for. Errors and timeouts are logged and
+/// treated as doing nothing, since nobody is around to see them
+async fn run_script<A>(script: &chat_scripts::Model, args: A) -> Option<ModAction>
+where
+    A: FuncArgs + Send + Sync + 'static,
+{
+    let timeout = std::time::Duration::from_millis(CONFIG.scripting.timeout_ms);
+    let run = ManagedRhai::new_mapper(script.script.clone(), &HOOK_ENGINE, args).post();
+    let res: Dynamic = match tokio::time::timeout(timeout, run).await {
+        Ok(Ok(res)) => res,
+        Ok(Err(err)) => {
+            log::warn!("script {} in {} failed: {}", script.name, script.chat, err);
+            return None;
+        }
+        Err(_) => {
+            log::warn!("script {} in {} timed out", script.name, script.chat);
+            return None;
+        }
+    };
+    if res.is_string() {
+        res.into_string().ok().map(ModAction::Reply)
+    } else {
+        res.try_cast::<ModAction>()
+    }
+}
+
+/// Act on the result of a script run on a message
+async fn message_action(ctx: &Context, action: ModAction) -> Result<()> {
+    let message = ctx.message()?;
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    match action {
+        ModAction::Ignore => (),
+        ModAction::Reply(text) => {
+            ctx.reply(text).await?;
+        }
+        ModAction::Delete => {
+            message.delete().await?;
+        }
+        ModAction::Warn(reason) => {
+            ctx.warn_with_action(user.get_id(), reason.as_deref(), None)
+                .await?;
+        }
+        ModAction::Mute(_) => {
+            ctx.mute(user.get_id(), message.get_chat(), None).await?;
+        }
+        ModAction::Ban(_) => {
+            ctx.ban(user.get_id(), None, false).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_message_scripts(ctx: &Context) -> Result<()> {
+    let Ok(message) = ctx.message() else {
+        return Ok(());
+    };
+    let command = ctx.cmd().map(|v| v.cmd.to_lowercase());
+    let moderated = ctx.moderated_message().await.is_some();
+    for script in get_scripts(message.get_chat().get_id()).await? {
+        let run = match script.event {
+            ScriptEvent::Message => moderated,
+            ScriptEvent::Command => command.as_deref() == Some(script.name.as_str()),
+            ScriptEvent::Join => false,
+        };
+        if !run {
+            continue;
+        }
+        if let Some(action) = run_script(&script, (message.clone(),)).await {
+            message_action(ctx, action).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_join_scripts(ctx: &Context) -> Result<()> {
+    let Some(UserChanged::UserJoined(member)) = ctx.user_event() else {
+        return Ok(());
+    };
+    let chat = member.get_chat();
+    let user = member.get_from().get_id();
+    for script in get_scripts(chat.get_id()).await? {
+        if script.event != ScriptEvent::Join {
+            continue;
+        }
+        match run_script(&script, (member.clone(),)).await {
+            Some(ModAction::Reply(text)) => {
+                chat.get_id().speak(text).await?;
+            }
+            Some(ModAction::Mute(_)) => {
+                ctx.mute(user, chat, None).await?;
+            }
+            Some(ModAction::Ban(_)) => {
+                TG.client()
+                    .build_ban_chat_member(chat.get_id(), user)
+                    .build()
+                    .await?;
+                publish(ModEvent::UserBanned {
+                    chat: chat.get_id(),
+                    user,
+                    until: None,
+                });
+            }
+            Some(action) => {
+                log::info!(
+                    "script {} returned {:?} on join, ignoring",
+                    script.name,
+                    action
+                );
+            }
+            None => (),
+        }
+    }
+    Ok(())
+}
+
 async fn map_script(ctx: &Context) -> Result<()> {
     ctx.action_message(|ctx, am, args| async move {
         let args = args.ok_or_else(|| ctx.fail_err("missing arg"))?;
@@ -103,9 +513,15 @@ async fn map_script(ctx: &Context) -> Result<()> {
 #[update_handler]
 pub async fn handle_update(ctx: &Context) -> Result<()> {
     if let Some(&Cmd { cmd, .. }) = ctx.cmd() {
-        if cmd == "eval" {
-            map_script(ctx).await?;
+        match cmd {
+            "eval" => map_script(ctx).await?,
+            "addscript" => add_script(ctx).await?,
+            "rmscript" => rm_script(ctx).await?,
+            "scripts" => list_scripts(ctx).await?,
+            _ => (),
         };
     }
+    run_message_scripts(ctx).await?;
+    run_join_scripts(ctx).await?;
     Ok(())
 }
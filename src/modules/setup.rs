@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::metadata::metadata;
+use crate::persist::admin::captchastate::CaptchaType;
+use crate::statics::{spawn, TG};
+use crate::tg::admin_helpers::{set_warn_limit, UpdateHelpers};
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{Cmd, Context};
+use crate::tg::dialog::{get_dialog, Conversation, ConversationState};
+use crate::tg::greetings::{get_chat_captcha_config, set_chat_captcha};
+use crate::tg::permissions::*;
+use crate::tg::user::Username;
+use crate::util::error::Result;
+use crate::util::string::{get_chat_lang, get_langs, set_chat_lang, Lang, Speak};
+use botapi::gen_types::{
+    CallbackQuery, Chat, EReplyMarkup, InlineKeyboardButtonBuilder, InlineKeyboardMarkup,
+    MaybeInaccessibleMessage, User,
+};
+use macros::{lang_fmt, update_handler};
+use uuid::Uuid;
+
+metadata!("Setup",
+    r#"
+    Walks admins through the basic settings of a chat: language, captcha, warn limit, and
+    log channel. /setup shows buttons to change them right in the chat, and /setup private
+    sends you a step by step wizard in private instead. The wizard is also sent to whoever
+    adds me to a chat
+    "#,
+    { category = "Chat Management" },
+    { command = "setup", help = "Usage: setup \\[private\\]. Shows quick setup buttons for this chat, or sends you the setup wizard in private", level = Admin },
+    { updates = [Message, ChatMember] }
+);
+
+/// Warn limits offered by the wizard and cycled through by the quick setup menu
+const WARN_LIMITS: [i32; 3] = [3, 5, 10];
+
+/// Setting picked by pushing a button in the wizard, applied when its state is entered
+#[derive(Clone)]
+enum Choice {
+    Lang(Lang),
+    Captcha(bool),
+    WarnLimit(i32),
+    Keep,
+    Finish,
+}
+
+/// Add a wizard step with an option per choice. Every option gets its own state so the
+/// choice is known from the state alone, and all of them lead on with the same text
+fn add_step(
+    state: &mut ConversationState,
+    choices: &mut HashMap<Uuid, Choice>,
+    from: &[Uuid],
+    options: Vec<(String, String, Choice)>,
+    next: &str,
+) -> Vec<Uuid> {
+    options
+        .into_iter()
+        .map(|(trigger, name, choice)| {
+            let id = state.add_state(next);
+            for start in from {
+                state.add_transition(*start, id, trigger.clone(), name.clone());
+            }
+            choices.insert(id, choice);
+            id
+        })
+        .collect()
+}
+
+/// The settings covered by setup, as currently set in a chat
+struct Settings {
+    lang: Lang,
+    captcha: bool,
+    warn_limit: i32,
+    log_channel: Option<i64>,
+}
+
+async fn get_settings(chat: &Chat) -> Result<Settings> {
+    let dialog = get_dialog(chat).await?;
+    Ok(Settings {
+        lang: get_chat_lang(chat.get_id()).await?,
+        captcha: get_chat_captcha_config(chat).await?.is_some(),
+        warn_limit: dialog.as_ref().map(|v| v.warn_limit).unwrap_or(3),
+        log_channel: dialog.and_then(|v| v.log_channel),
+    })
+}
+
+async fn summary(chat: &Chat, lang: &Lang) -> Result<String> {
+    let settings = get_settings(chat).await?;
+    let log_channel = settings
+        .log_channel
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "none".to_owned());
+    Ok(lang_fmt!(
+        lang,
+        "setupsummary",
+        chat.name_humanreadable(),
+        settings.lang.into_code(),
+        if settings.captcha { "on" } else { "off" },
+        settings.warn_limit,
+        log_channel
+    ))
+}
+
+/// Apply a setting picked in the wizard or the quick setup menu
+async fn apply(choice: Choice, chat: &Chat) -> Result<()> {
+    match choice {
+        Choice::Lang(l) => set_chat_lang(chat, l).await?,
+        Choice::Captcha(true) => set_chat_captcha(chat, Some((CaptchaType::Button, None))).await?,
+        Choice::Captcha(false) => set_chat_captcha(chat, None).await?,
+        Choice::WarnLimit(limit) => set_warn_limit(chat, limit).await?,
+        Choice::Keep | Choice::Finish => (),
+    }
+    Ok(())
+}
+
+/// Apply a wizard choice to the chat, as long as the user pushing the buttons is still
+/// an admin
+async fn apply_choice(choice: Choice, chat: &Chat, user: i64, lang: &Lang) -> Result<()> {
+    if chat.is_user_admin(user).await?.is_none() {
+        user.speak(lang_fmt!(lang, "setupnotadmin", chat.name_humanreadable()))
+            .await?;
+        return Ok(());
+    }
+    if let Choice::Finish = choice {
+        user.speak(summary(chat, lang).await?).await?;
+    } else {
+        apply(choice, chat).await?;
+    }
+    Ok(())
+}
+
+fn get_setup_conversation(chat: &Chat, user: &User, lang: &Lang) -> Result<Conversation> {
+    let mut state = ConversationState::new_prefix(
+        "setup".to_owned(),
+        lang_fmt!(lang, "setupstart", chat.name_humanreadable()),
+        user.get_id(),
+        user.get_id(),
+        "setup",
+    )?;
+    let start = state.get_start()?.state_id;
+    let mut choices = HashMap::new();
+    let keep = || ("keep".to_owned(), "Skip".to_owned(), Choice::Keep);
+
+    let langs = get_langs()
+        .into_iter()
+        .map(|v| {
+            (
+                v.into_code().to_owned(),
+                v.into_code().to_owned(),
+                Choice::Lang(v),
+            )
+        })
+        .chain([keep()])
+        .collect();
+    let captcha = lang_fmt!(lang, "setupcaptcha");
+    let from = add_step(&mut state, &mut choices, &[start], langs, &captcha);
+
+    let options = vec![
+        ("on".to_owned(), "On".to_owned(), Choice::Captcha(true)),
+        ("off".to_owned(), "Off".to_owned(), Choice::Captcha(false)),
+        keep(),
+    ];
+    let warns = lang_fmt!(lang, "setupwarns");
+    let from = add_step(&mut state, &mut choices, &from, options, &warns);
+
+    let options = WARN_LIMITS
+        .into_iter()
+        .map(|v| (v.to_string(), v.to_string(), Choice::WarnLimit(v)))
+        .chain([keep()])
+        .collect();
+    let log = lang_fmt!(lang, "setuplog");
+    let from = add_step(&mut state, &mut choices, &from, options, &log);
+
+    let options = vec![("done".to_owned(), "Finish".to_owned(), Choice::Finish)];
+    let done = lang_fmt!(lang, "setupdone");
+    add_step(&mut state, &mut choices, &from, options, &done);
+
+    let choices = Arc::new(choices);
+    let chat = chat.clone();
+    let user = user.get_id();
+    let lang = *lang;
+    state.state_callback(move |uuid, _| {
+        let Some(choice) = choices.get(&uuid).cloned() else {
+            return;
+        };
+        let chat = chat.clone();
+        spawn(async move {
+            if let Err(err) = apply_choice(choice, &chat, user, &lang).await {
+                log::warn!("failed to apply setup choice: {}", err);
+                err.record_stats();
+            }
+        });
+    });
+    Ok(state.build())
+}
+
+/// Send the setup wizard for a chat to a user in private, returning false if the user
+/// hasn't started the bot
+async fn send_wizard(chat: &Chat, user: &User) -> Result<bool> {
+    let lang = get_chat_lang(chat.get_id()).await?;
+    let conv = get_setup_conversation(chat, user, &lang)?;
+    conv.write_self().await?;
+    let sent = TG
+        .client()
+        .build_send_message(user.get_id(), &conv.get_current().await?.content)
+        .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(
+            conv.get_current_markup(3).await?,
+        ))
+        .build()
+        .await;
+    Ok(sent.is_ok())
+}
+
+/// Setting changed by a button in the quick setup menu
+#[derive(Clone, Copy)]
+enum Toggle {
+    Lang,
+    Captcha,
+    WarnLimit,
+}
+
+fn next_lang(current: Lang) -> Lang {
+    let langs = get_langs();
+    let next = langs
+        .iter()
+        .position(|v| *v == current)
+        .map_or(0, |v| v + 1);
+    langs
+        .get(next)
+        .or(langs.first())
+        .copied()
+        .unwrap_or(current)
+}
+
+fn next_warn_limit(current: i32) -> i32 {
+    WARN_LIMITS
+        .into_iter()
+        .find(|v| *v > current)
+        .unwrap_or(WARN_LIMITS[0])
+}
+
+impl Toggle {
+    /// Get the choice moving this setting on to its next value
+    fn next(self, settings: &Settings) -> Choice {
+        match self {
+            Toggle::Lang => Choice::Lang(next_lang(settings.lang)),
+            Toggle::Captcha => Choice::Captcha(!settings.captcha),
+            Toggle::WarnLimit => Choice::WarnLimit(next_warn_limit(settings.warn_limit)),
+        }
+    }
+}
+
+/// Build the quick setup menu with a button per setting showing its current value.
+/// Pushing one moves the setting to its next value and redraws the menu
+fn quick_markup(settings: &Settings) -> InlineKeyboardMarkup {
+    let lang = settings.lang;
+    let captcha = if settings.captcha { "on" } else { "off" };
+    let buttons = [
+        (
+            lang_fmt!(lang, "setuplangbutton", lang.into_code()),
+            Some(Toggle::Lang),
+        ),
+        (
+            lang_fmt!(lang, "setupcaptchabutton", captcha),
+            Some(Toggle::Captcha),
+        ),
+        (
+            lang_fmt!(lang, "setupwarnbutton", settings.warn_limit),
+            Some(Toggle::WarnLimit),
+        ),
+        (lang_fmt!(lang, "setupfinish"), None),
+    ];
+    let mut markup = InlineKeyboardBuilder::default();
+    for (text, toggle) in buttons {
+        let button = InlineKeyboardButtonBuilder::new(text)
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        button.on_push_multi(move |callback| quick_button(callback, toggle));
+        markup.button(button);
+        markup.newline();
+    }
+    markup.build()
+}
+
+/// Handle a push on the quick setup menu, finishing with a summary if no setting was
+/// pushed. Returns false to keep the button registered when pushed by a non admin
+async fn quick_button(callback: CallbackQuery, toggle: Option<Toggle>) -> Result<bool> {
+    let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() else {
+        return Ok(true);
+    };
+    let chat = message.get_chat();
+    let settings = get_settings(chat).await?;
+    let permissions = NamedBotPermissions::from_chatuser(callback.get_from(), chat).await?;
+    if !permissions.can_change_info.is_granted() && !permissions.is_sudo.is_granted() {
+        TG.client()
+            .build_answer_callback_query(callback.get_id())
+            .text(&lang_fmt!(settings.lang, "setupdenied"))
+            .show_alert(true)
+            .build()
+            .await?;
+        return Ok(false);
+    }
+    if let Some(toggle) = toggle {
+        apply(toggle.next(&settings), chat).await?;
+        let settings = get_settings(chat).await?;
+        TG.client()
+            .build_edit_message_reply_markup()
+            .message_id(message.get_message_id())
+            .chat_id(chat.get_id())
+            .reply_markup(&quick_markup(&settings))
+            .build()
+            .await?;
+    } else {
+        TG.client()
+            .build_edit_message_text(&summary(chat, &settings.lang).await?)
+            .message_id(message.get_message_id())
+            .chat_id(chat.get_id())
+            .build()
+            .await?;
+    }
+    TG.client()
+        .build_answer_callback_query(callback.get_id())
+        .build()
+        .await?;
+    Ok(true)
+}
+
+async fn setup_cmd(ctx: &Context, private: bool) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.try_get()?.chat;
+    if !private {
+        let settings = get_settings(chat).await?;
+        let text = lang_fmt!(settings.lang, "setupquick", chat.name_humanreadable());
+        TG.client()
+            .build_send_message(chat.get_id(), &text)
+            .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(quick_markup(&settings)))
+            .build()
+            .await?;
+        return Ok(());
+    }
+    let user = ctx.get_real_from()?;
+    if send_wizard(chat, user).await? {
+        ctx.reply(lang_fmt!(ctx, "setupsent")).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "setupstartfirst")).await?;
+    }
+    Ok(())
+}
+
+/// Greet whoever added the bot to a chat with the wizard, if they are an admin there
+async fn on_added(ctx: &Context) -> Result<()> {
+    let Some(member) = ctx.update().bot_added() else {
+        return Ok(());
+    };
+    let chat = member.get_chat();
+    let user = member.get_from();
+    if chat.is_user_admin(user.get_id()).await?.is_none() {
+        return Ok(());
+    }
+    if !send_wizard(chat, user).await? {
+        log::info!("{} added me but hasn't started me", user.get_id());
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd {
+        cmd: "setup",
+        ref args,
+        ..
+    }) = ctx.cmd()
+    {
+        let private = args.args.first().map(|v| v.get_text()) == Some("private");
+        setup_cmd(ctx, private).await?;
+    }
+    on_added(ctx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn warn_limit_cycles() {
+        assert_eq!(next_warn_limit(3), 5);
+        assert_eq!(next_warn_limit(5), 10);
+        assert_eq!(next_warn_limit(10), 3);
+        assert_eq!(next_warn_limit(4), 5);
+        assert_eq!(next_warn_limit(20), 3);
+    }
+}
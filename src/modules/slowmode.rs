@@ -0,0 +1,315 @@
+use self::entities::slowmode_settings;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::tg::admin_helpers::{is_dm, DeleteAfterTime, GetChat};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::exemptions::get_exemption;
+use crate::tg::user::Username;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use chrono::Duration;
+use macros::{lang_fmt, update_handler};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Slowmode",
+    r#"
+    Limit how often each member can send messages, down to the second. Messages sent before
+    a member's interval is up are deleted, with a short notice telling them how long to wait.
+    Admins and approved users are never slowed down.
+
+    Bots can't change telegram's own slow mode, which only allows a few fixed intervals. It
+    is shown alongside the bot's interval and can still be set from the chat settings.
+
+    [*Example:]
+    /slowmode 5s
+    /slowmode off
+    "#,
+    Helper,
+    { category = "Chat Management" },
+    { command = "slowmode", help = "Usage: slowmode \\<interval/off\\>. Sets how long members wait between messages, like 5s or 2m", level = Admin, requires = [CanRestrict] },
+    { updates = [Message] }
+);
+
+/// Longest interval between messages
+const MAX_INTERVAL: i64 = 60 * 60;
+
+/// Seconds a notice about a deleted message stays up
+const NOTICE_SECONDS: i64 = 10;
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(slowmode_settings::Entity)
+                        .col(
+                            ColumnDef::new(slowmode_settings::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(slowmode_settings::Column::Interval)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(slowmode_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod slowmode_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "slowmode_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            /// seconds each member waits between messages
+            pub interval: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000056_create_slowmode"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("slowset:{}", chat)
+}
+
+#[inline(always)]
+fn get_user_key(chat: i64, user: i64) -> String {
+    format!("slow:{}:{}", chat, user)
+}
+
+#[inline(always)]
+fn get_notice_key(chat: i64, user: i64) -> String {
+    format!("slownotice:{}:{}", chat, user)
+}
+
+/// Parse an interval like 5s, 2m, or 1h into seconds. Plain numbers are seconds
+fn parse_interval(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (number, unit) = match text.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => text.split_at(i),
+        None => (text, "s"),
+    };
+    let number = number.parse::<i64>().ok()?;
+    let seconds = match unit {
+        "s" => number,
+        "m" => number.checked_mul(60)?,
+        "h" => number.checked_mul(60 * 60)?,
+        _ => return None,
+    };
+    Some(seconds).filter(|v| (1..=MAX_INTERVAL).contains(v))
+}
+
+async fn get_interval(chat: i64) -> Result<Option<i64>> {
+    let key = get_settings_key(chat);
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = slowmode_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.map(|v| v.interval))
+}
+
+async fn set_interval(chat: i64, interval: Option<i64>) -> Result<()> {
+    let key = get_settings_key(chat);
+    match interval {
+        Some(interval) => {
+            let model = slowmode_settings::Entity::insert(slowmode_settings::ActiveModel {
+                chat: Set(chat),
+                interval: Set(interval),
+            })
+            .on_conflict(
+                OnConflict::column(slowmode_settings::Column::Chat)
+                    .update_column(slowmode_settings::Column::Interval)
+                    .to_owned(),
+            )
+            .exec_with_returning(*DB)
+            .await?;
+            model.cache(key).await?;
+        }
+        None => {
+            slowmode_settings::Entity::delete_by_id(chat)
+                .exec(*DB)
+                .await?;
+            REDIS.sq(|q| q.del(&key)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete messages sent before the sender's interval is up. Each allowed message starts a
+/// new interval through a redis key that expires when the sender can talk again
+async fn enforce(ctx: &Context) -> Result<()> {
+    let Some(message) = ctx.moderated_message().await else {
+        return Ok(());
+    };
+    let chat = message.get_chat();
+    if is_dm(chat) || message.get_sender_chat().is_some() {
+        return Ok(());
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(interval) = get_interval(chat.get_id()).await? else {
+        return Ok(());
+    };
+    if get_exemption(chat, user.get_id()).await?.is_some() {
+        return Ok(());
+    }
+    let key = get_user_key(chat.get_id(), user.get_id());
+    let options = SetOptions::default()
+        .conditional_set(ExistenceCheck::NX)
+        .with_expiration(SetExpiry::EX(interval as usize));
+    let set: Option<String> = REDIS.sq(|q| q.set_options(&key, true, options)).await?;
+    if set.is_some() {
+        return Ok(());
+    }
+    message.delete().await?;
+    let notice_key = get_notice_key(chat.get_id(), user.get_id());
+    let (first, wait): (bool, i64) = REDIS
+        .pipe(|q| {
+            q.set_nx(&notice_key, true)
+                .expire(&notice_key, interval)
+                .ignore()
+                .ttl(&key)
+        })
+        .await?;
+    if first {
+        let notice = ctx
+            .speak(lang_fmt!(
+                ctx,
+                "slowmodenotice",
+                user.name_humanreadable(),
+                wait.max(1)
+            ))
+            .await?;
+        if let Some(notice) = notice {
+            notice.delete_after_time(Duration::try_seconds(NOTICE_SECONDS).unwrap());
+        }
+    }
+    Ok(())
+}
+
+async fn slowmode(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat();
+    match args.text.trim() {
+        "" => {
+            let native = chat
+                .get_chat_cached()
+                .await?
+                .get_slow_mode_delay()
+                .unwrap_or(0);
+            let status = match get_interval(chat.get_id()).await? {
+                Some(interval) => lang_fmt!(ctx, "slowmodestatus", interval, native),
+                None => lang_fmt!(ctx, "slowmodeoffstatus", native),
+            };
+            ctx.reply(status).await?;
+        }
+        "off" | "no" => {
+            set_interval(chat.get_id(), None).await?;
+            ctx.reply(lang_fmt!(ctx, "slowmodeoff")).await?;
+        }
+        text => {
+            let Some(interval) = parse_interval(text) else {
+                return ctx.fail(lang_fmt!(ctx, "slowmodeusage", MAX_INTERVAL / 60));
+            };
+            set_interval(chat.get_id(), Some(interval)).await?;
+            ctx.reply(lang_fmt!(ctx, "slowmodeset", interval)).await?;
+        }
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "slowmode" {
+            slowmode(ctx, args).await?;
+        }
+    }
+    enforce(ctx).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn intervals() {
+        assert_eq!(parse_interval("5s"), Some(5));
+        assert_eq!(parse_interval("5"), Some(5));
+        assert_eq!(parse_interval("2m"), Some(120));
+        assert_eq!(parse_interval("1h"), Some(3600));
+        assert_eq!(parse_interval("2h"), None);
+        assert_eq!(parse_interval("0s"), None);
+        assert_eq!(parse_interval("5d"), None);
+        assert_eq!(parse_interval("s"), None);
+    }
+}
@@ -1,9 +1,10 @@
 use std::str::FromStr;
+use std::sync::Arc;
 
 use self::entities::tags::ModelRedis;
 use crate::metadata::{metadata, ModuleHelpers};
 use crate::persist::redis as r;
-use crate::statics::{DB, REDIS, TG};
+use crate::statics::{DB, REDIS};
 use crate::tg::admin_helpers::is_dm;
 use crate::tg::admin_helpers::is_dm_or_die;
 use crate::tg::command::TextArg;
@@ -11,6 +12,7 @@ use crate::tg::command::{Cmd, Context};
 use crate::tg::dialog::ConversationState;
 use crate::tg::dialog::{drop_converstaion, Conversation};
 use crate::tg::dialog::{get_conversation, replace_conversation};
+use crate::tg::inline::{register_inline_provider, InlineContext};
 use crate::tg::user::GetUser;
 use crate::util::error::BotError;
 use crate::util::string::Speak;
@@ -21,9 +23,7 @@ use ::sea_orm_migration::prelude::*;
 use macros::update_handler;
 
 use crate::util::error::Result;
-use botapi::gen_types::{
-    InlineQuery, InlineQueryResult, InlineQueryResultCachedSticker, Message, UpdateExt,
-};
+use botapi::gen_types::{InlineQueryResult, InlineQueryResultCachedSticker, Message, UpdateExt};
 use log::info;
 use r::{scope_key_by_chatuser, RedisStr};
 // redis keys
@@ -49,9 +49,11 @@ metadata!("Sticker Organizer",
     Use this bot in inline mode to organize your stickers
     "#,
     Helper,
+    { category = "Fun" },
     { command = "upload", help = "Uploads a sticker" },
     { command = "list", help = "Lists available stickers"},
-    { command = "deletesticker", help = "Deletes a sticker by uuid"}
+    { command = "deletesticker", help = "Deletes a sticker by uuid"},
+    { updates = [Message] }
 );
 
 fn upload_sticker_conversation(message: &Message) -> Result<Conversation> {
@@ -273,19 +275,22 @@ impl ModuleHelpers for Helper {
     fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
         get_migrations()
     }
+
+    fn register_inline(&self) {
+        register_inline_provider(&METADATA.name, None, inline_stickers);
+    }
 }
 
-async fn handle_inline(query: &InlineQuery) -> Result<()> {
-    let id = query.get_from().get_id();
-    let key = query.get_query().to_owned();
+async fn inline_stickers(ctx: Arc<InlineContext>) -> Result<Vec<InlineQueryResult>> {
+    let id = ctx.user().get_id();
 
-    let cached = query.get_from().get_id().get_cached_user().await?;
+    let cached = id.get_cached_user().await?;
     if let Some(cached) = cached {
         if let Some(owner) = cached.get_username() {
-            log::info!("query! owner: {} tag: {}", owner, query.get_query());
+            log::info!("query! owner: {} tag: {}", owner, ctx.text());
         }
     }
-    let key = format!("%{}%", key);
+    let key = format!("%{}%", ctx.text());
     let stickers = entities::stickers::Entity::find()
         .join(
             sea_orm::JoinType::InnerJoin,
@@ -306,13 +311,8 @@ async fn handle_inline(query: &InlineQuery) -> Result<()> {
             ))
         })
         .collect::<Vec<InlineQueryResult>>();
-    TG.client
-        .build_answer_inline_query(query.get_id(), &stickers)
-        .is_personal(true)
-        .build()
-        .await?;
 
-    Ok(())
+    Ok(stickers)
 }
 
 async fn handle_message(ctx: &Context) -> Result<()> {
@@ -333,11 +333,6 @@ pub async fn handle_update(cmd: &Context) -> Result<()> {
             let id = message.get_chat().get_id();
             Some(id)
         }
-        UpdateExt::InlineQuery(ref query) => {
-            handle_inline(query).await?;
-            let id = query.get_from().get_id();
-            Some(id)
-        }
         _ => None,
     };
     Ok(())
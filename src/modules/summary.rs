@@ -0,0 +1,456 @@
+use self::entities::llm_settings;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::llm::complete;
+use crate::tg::optout::{is_opted_out, OptOut};
+use crate::tg::quotas::is_exempt;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::{Message, MessageEntity, ReplyParametersBuilder, UpdateExt};
+use chrono::Duration;
+use lazy_static::lazy_static;
+use macros::{lang_fmt, update_handler};
+use redis::{AsyncCommands, ExistenceCheck, SetExpiry, SetOptions};
+use regex::Regex;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
+
+metadata!("Summary",
+    r#"
+    Summarize recent conversation or ask questions using a language model configured by the
+    bot owner. Admins have to opt a chat in with /llm first, messages are only remembered
+    for summaries after that. User ids, @usernames and mentions are removed before anything
+    is sent to the model, and users who opted out with /optout stats are left out entirely.
+
+    Requests are strictly rate limited per chat and per user.
+
+    [*Example:]
+    /llm on
+    /summary 50
+    /ask what is the capital of France?
+    "#,
+    Helper,
+    { category = "Fun" },
+    { command = "summary", help = "Usage: summary \\[count\\]. Summarize the most recent messages in this chat" },
+    { command = "ask", help = "Usage: ask \\<question\\>. Ask the language model a question, replying to a message includes it" },
+    { command = "llm", help = "Usage: llm \\<on/off\\>. Allow /summary and /ask in this chat", level = Admin, requires = [CanChangeInfo] },
+    { updates = [Message] }
+);
+
+/// Messages summarized when no count is given
+const DEFAULT_SUMMARY: usize = 50;
+
+/// Seconds recent messages are remembered for summaries
+const RECENT_SECONDS: i64 = 48 * 60 * 60;
+
+/// Longest response sent back to the chat
+const MAX_RESPONSE: usize = 4096;
+
+/// Replaces @usernames and mentions in text sent to the model
+const REDACTED: &str = "[user]";
+
+lazy_static! {
+    static ref USERNAME: Regex = Regex::new(r#"@[A-Za-z0-9_]{3,32}"#).unwrap();
+}
+
+const SUMMARY_PROMPT: &str = "You summarize group chat conversations. Each line of the \
+    conversation is a message, prefixed with the sender's first name. Write a short, neutral \
+    summary of the main topics and who said what. Respond in plain text without markdown.";
+
+const ASK_PROMPT: &str = "You are a helpful assistant in a telegram group chat. Answer the \
+    question briefly and accurately. Respond in plain text without markdown.";
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(llm_settings::Entity)
+                        .col(
+                            ColumnDef::new(llm_settings::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(llm_settings::Column::Enabled)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(llm_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod llm_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "llm_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            pub enabled: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000054_create_llm_settings"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+/// A message remembered for summaries. Only the sender's first name is sent to the model,
+/// the id is kept so /mydata and /forgetme can find the user's messages
+#[derive(Serialize, Deserialize)]
+struct RecentMessage {
+    #[serde(default)]
+    user: i64,
+    name: String,
+    text: String,
+}
+
+/// Replace mentions and anything that looks like an @username with a placeholder.
+/// Entity offsets are in utf16 code units, so spans are cut out before decoding
+fn redact(text: &str, entities: Option<&Vec<MessageEntity>>) -> String {
+    let mut units = text.encode_utf16().collect::<Vec<u16>>();
+    let mut spans = entities
+        .into_iter()
+        .flatten()
+        .filter(|v| matches!(v.get_tg_type(), "mention" | "text_mention"))
+        .map(|v| {
+            (
+                v.get_offset() as usize,
+                (v.get_offset() + v.get_length()) as usize,
+            )
+        })
+        .filter(|(start, end)| start < end && *end <= units.len())
+        .collect::<Vec<(usize, usize)>>();
+    // back to front so earlier offsets stay valid
+    spans.sort_unstable_by(|a, b| b.cmp(a));
+    let mut last = units.len();
+    for (start, end) in spans {
+        if end > last {
+            continue;
+        }
+        units.splice(start..end, REDACTED.encode_utf16());
+        last = start;
+    }
+    let text = String::from_utf16_lossy(&units);
+    USERNAME.replace_all(&text, REDACTED).into_owned()
+}
+
+/// Text or caption of a message with mentions redacted
+fn redacted_text(message: &Message) -> Option<String> {
+    if let Some(text) = message.get_text() {
+        Some(redact(text, message.get_entities()))
+    } else {
+        message
+            .get_caption()
+            .map(|v| redact(v, message.get_caption_entities()))
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("llmset:{}", chat)
+}
+
+#[inline(always)]
+fn get_recent_key(chat: i64) -> String {
+    format!("llmrecent:{}", chat)
+}
+
+#[inline(always)]
+fn get_chat_limit_key(chat: i64) -> String {
+    format!("llmchat:{}", chat)
+}
+
+#[inline(always)]
+fn get_user_limit_key(chat: i64, user: i64) -> String {
+    format!("llmuser:{}:{}", chat, user)
+}
+
+async fn is_enabled(chat: i64) -> Result<bool> {
+    let key = get_settings_key(chat);
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = llm_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.map(|v| v.enabled).unwrap_or(false))
+}
+
+async fn set_enabled(chat: i64, enabled: bool) -> Result<()> {
+    let model = llm_settings::Entity::insert(llm_settings::ActiveModel {
+        chat: Set(chat),
+        enabled: Set(enabled),
+    })
+    .on_conflict(
+        OnConflict::column(llm_settings::Column::Chat)
+            .update_column(llm_settings::Column::Enabled)
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_settings_key(chat)).await?;
+    if !enabled {
+        REDIS.sq(|q| q.del(&get_recent_key(chat))).await?;
+    }
+    Ok(())
+}
+
+/// Remember a message for summaries if the chat opted in and the sender didn't opt out
+async fn record_message(message: &Message) -> Result<()> {
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(text) = redacted_text(message) else {
+        return Ok(());
+    };
+    let chat = message.get_chat().get_id();
+    if CONFIG.llm.max_messages == 0
+        || !is_enabled(chat).await?
+        || is_opted_out(user.get_id(), OptOut::Stats).await?
+    {
+        return Ok(());
+    }
+    let recent = serde_json::to_string(&RecentMessage {
+        user: user.get_id(),
+        name: user.get_first_name().to_owned(),
+        text,
+    })?;
+    let key = get_recent_key(chat);
+    REDIS
+        .pipe(|q| {
+            q.lpush(&key, recent)
+                .ignore()
+                .ltrim(&key, 0, CONFIG.llm.max_messages as isize - 1)
+                .ignore()
+                .expire(&key, RECENT_SECONDS)
+                .ignore()
+        })
+        .await?;
+    Ok(())
+}
+
+/// The most recent messages in a chat, oldest first
+async fn get_recent(chat: i64, count: usize) -> Result<Vec<RecentMessage>> {
+    let key = get_recent_key(chat);
+    let recent: Vec<String> = REDIS.sq(|q| q.lrange(&key, 0, count as isize - 1)).await?;
+    Ok(recent
+        .iter()
+        .rev()
+        .filter_map(|v| serde_json::from_str(v).ok())
+        .collect())
+}
+
+/// Count a request against the chat's hourly limit and the user's cooldown, returning
+/// false if either is used up
+async fn take_limit(ctx: &Context, chat: i64, user: i64) -> Result<bool> {
+    if is_exempt(chat) {
+        return Ok(true);
+    }
+    let key = get_user_limit_key(chat, user);
+    let cooldown = CONFIG.llm.user_cooldown_seconds;
+    if cooldown > 0 {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(cooldown as usize));
+        let set: Option<String> = REDIS.sq(|q| q.set_options(&key, true, options)).await?;
+        if set.is_none() {
+            return ctx.fail(lang_fmt!(ctx, "llmcooldown", cooldown));
+        }
+    }
+    if CONFIG.llm.chat_per_hour == 0 {
+        return Ok(true);
+    }
+    let key = get_chat_limit_key(chat);
+    let (used,): (u64,) = REDIS
+        .pipe(|q| q.incr(&key, 1).expire(&key, 60 * 60).ignore())
+        .await?;
+    Ok(used <= CONFIG.llm.chat_per_hour)
+}
+
+/// Check that the bot owner configured an endpoint, the chat opted in, and the rate limits
+/// allow another request
+async fn check_allowed(ctx: &Context) -> Result<()> {
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    if !CONFIG.llm.enabled {
+        return ctx.fail(lang_fmt!(ctx, "llmunavailable"));
+    }
+    if !is_enabled(chat).await? {
+        return ctx.fail(lang_fmt!(ctx, "llmdisabled"));
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    if !take_limit(ctx, chat, user.get_id()).await? {
+        return ctx.fail(lang_fmt!(ctx, "llmlimit", CONFIG.llm.chat_per_hour));
+    }
+    Ok(())
+}
+
+async fn send_response(message: &Message, response: &str) -> Result<()> {
+    let response = response.chars().take(MAX_RESPONSE).collect::<String>();
+    TG.client()
+        .build_send_message(message.get_chat().get_id(), &response)
+        .reply_parameters(&ReplyParametersBuilder::new(message.get_message_id()).build())
+        .build()
+        .await?;
+    Ok(())
+}
+
+async fn summary(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let count = match args.text.trim() {
+        "" => DEFAULT_SUMMARY,
+        count => match count.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => return ctx.fail(lang_fmt!(ctx, "summaryusage", CONFIG.llm.max_messages)),
+        },
+    }
+    .min(CONFIG.llm.max_messages);
+    check_allowed(ctx).await?;
+    let recent = get_recent(message.get_chat().get_id(), count).await?;
+    if recent.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "summaryempty"));
+    }
+    let conversation = recent
+        .iter()
+        .map(|v| format!("{}: {}", v.name, v.text.replace('\n', " ")))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let response = complete(SUMMARY_PROMPT, &conversation).await?;
+    send_response(message, &response).await
+}
+
+async fn ask(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let message = ctx.message()?;
+    let question = args.text.trim();
+    if question.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "askusage"));
+    }
+    // redact the whole message so entity offsets line up, then drop the command
+    let question = redacted_text(message).unwrap_or_default();
+    let question = question
+        .split_once(char::is_whitespace)
+        .map(|(_, v)| v.trim())
+        .unwrap_or_default();
+    let quoted = match message.get_reply_to_message() {
+        Some(reply) => {
+            if let Some(user) = reply.get_from() {
+                if is_opted_out(user.get_id(), OptOut::Stats).await? {
+                    return ctx.fail(lang_fmt!(ctx, "askoptedout"));
+                }
+            }
+            redacted_text(&reply)
+        }
+        None => None,
+    };
+    check_allowed(ctx).await?;
+    let prompt = match quoted {
+        Some(quoted) => format!("Message:\n{}\n\nQuestion:\n{}", quoted, question),
+        None => question.to_owned(),
+    };
+    let response = complete(ASK_PROMPT, &prompt).await?;
+    send_response(message, &response).await
+}
+
+async fn llm(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    match args.text.trim() {
+        "on" | "yes" => {
+            set_enabled(chat, true).await?;
+            ctx.reply(lang_fmt!(ctx, "llmon")).await?;
+        }
+        "off" | "no" => {
+            set_enabled(chat, false).await?;
+            ctx.reply(lang_fmt!(ctx, "llmoff")).await?;
+        }
+        "" if is_enabled(chat).await? => {
+            ctx.reply(lang_fmt!(ctx, "llmon")).await?;
+        }
+        "" => {
+            ctx.reply(lang_fmt!(ctx, "llmoff")).await?;
+        }
+        _ => return ctx.fail(lang_fmt!(ctx, "llmusage")),
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "summary" => summary(ctx, args).await?,
+            "ask" => ask(ctx, args).await?,
+            "llm" => llm(ctx, args).await?,
+            _ => (),
+        }
+    } else if let UpdateExt::Message(ref message) = ctx.update() {
+        record_message(message).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use crate::metadata::metadata;
+use crate::persist::core::{chat_members, users};
+use crate::statics::{spawn, DB, REDIS};
+use crate::tg::bridges::is_bridged_id;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::markdown::EntityMessage;
+use crate::tg::permissions::*;
+use crate::tg::user::GetUser;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::User;
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter, QuerySelect};
+use std::collections::HashSet;
+use std::time::Duration;
+
+metadata!("Tagall",
+    r#"
+    Mention every known member of this chat, for announcements that can't be missed. Mentions
+    are sent a few at a time with a delay between messages, and each chat can only tag everyone
+    once every few minutes. Only members the bot has seen talking or joining are mentioned.
+
+    Anyone who doesn't want to be pinged can opt out with /optout mentions, in every chat at once.
+    "#,
+    { category = "Fun" },
+    { command = "tagall", help = "Usage: tagall \\<message\\>. Mentions every member of this chat", level = Admin },
+    { updates = [Message] }
+);
+
+/// Most mentions in a single message
+const BATCH_SIZE: usize = 5;
+
+/// Delay between mention messages
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// Seconds before a chat can tag everyone again
+const COOLDOWN: i64 = 60 * 10;
+
+/// Most members mentioned by one tagall
+const MAX_MENTIONS: u64 = 500;
+
+#[inline(always)]
+fn get_cooldown_key(chat: i64) -> String {
+    format!("tagall:{}", chat)
+}
+
+/// Get the members of a chat that can be mentioned, leaving out users who opted out,
+/// bots, and anyone the bot has no cached user for
+async fn get_taggable(chat: i64) -> Result<Vec<User>> {
+    let members = chat_members::Entity::find()
+        .filter(chat_members::Column::ChatId.eq(chat))
+        .filter(chat_members::Column::BannedByMe.eq(false))
+        .limit(MAX_MENTIONS)
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| v.user_id)
+        .filter(|v| !is_bridged_id(*v))
+        .collect::<Vec<i64>>();
+    let optouts = users::Entity::find()
+        .filter(users::Column::UserId.is_in(members.iter().copied()))
+        .filter(users::Column::OptoutMentions.eq(true))
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| v.user_id)
+        .collect::<HashSet<i64>>();
+    let mut users = Vec::with_capacity(members.len());
+    for member in members.into_iter().filter(|v| !optouts.contains(v)) {
+        if let Some(user) = member.get_cached_user().await? {
+            if !user.get_is_bot() {
+                users.push(user);
+            }
+        }
+    }
+    Ok(users)
+}
+
+/// Send the mentions in batches, with the admin's message in front of the first one
+async fn send_mentions(chat: i64, text: String, users: Vec<User>) -> Result<()> {
+    for (i, batch) in users.chunks(BATCH_SIZE).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(BATCH_DELAY).await;
+        }
+        let mut message = EntityMessage::new(chat);
+        if i == 0 {
+            message.builder.text(&text).text("\n");
+        }
+        for (j, user) in batch.iter().enumerate() {
+            if j > 0 {
+                message.builder.text(" ");
+            }
+            message
+                .builder
+                .text_mention(user.get_first_name(), user.clone(), None);
+        }
+        chat.speak_fmt(message).await?;
+    }
+    Ok(())
+}
+
+async fn tagall(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let text = args.text.trim();
+    if text.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "tagallinvalid"));
+    }
+    let key = get_cooldown_key(chat);
+    let (first,): (bool,) = REDIS
+        .pipe(|q| q.set_nx(&key, true).expire(&key, COOLDOWN).ignore())
+        .await?;
+    if !first {
+        return ctx.fail(lang_fmt!(ctx, "tagallcooldown", COOLDOWN / 60));
+    }
+    let users = get_taggable(chat).await?;
+    if users.is_empty() {
+        return ctx.fail(lang_fmt!(ctx, "tagallnone"));
+    }
+    let text = text.to_owned();
+    // a few hundred mentions take minutes to send, don't hold up the update handler
+    spawn(async move {
+        if let Err(err) = send_mentions(chat, text, users).await {
+            log::warn!("tagall in {} stopped: {}", chat, err);
+        }
+    });
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "tagall" {
+            tagall(ctx, args).await?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,270 @@
+use self::entities::translation_settings;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::is_dm;
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::quotas::is_exempt;
+use crate::tg::translate::{strip_html, to_html, translate};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use botapi::gen_types::ReplyParametersBuilder;
+use chrono::{Duration, Utc};
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use sea_orm_migration::{MigrationName, MigrationTrait};
+
+metadata!("Translate",
+    r#"
+    Translate messages into another language. Reply to a message with /tl and a two letter
+    language code to get a translation, with bold, italic, links, and other formatting kept.
+    Admins have to turn translation on for a chat first, and each chat can only translate a
+    limited number of messages a day.
+
+    [*Example:]
+    /translation on
+    /tl en
+    "#,
+    Helper,
+    { category = "Fun" },
+    { command = "tl", help = "Usage: tl \\<language\\>. Reply to a message to translate it" },
+    { command = "translation", help = "Usage: translation \\<on/off\\>. Allow translating messages in this chat", level = Admin, requires = [CanChangeInfo] },
+    { updates = [Message] }
+);
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(translation_settings::Entity)
+                        .col(
+                            ColumnDef::new(translation_settings::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(translation_settings::Column::Enabled)
+                                .boolean()
+                                .not_null()
+                                .default(false),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .drop_table_auto(translation_settings::Entity)
+                .await?;
+            Ok(())
+        }
+    }
+
+    pub mod translation_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "translation_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            pub enabled: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000052_create_translation"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, _: i64) -> Result<Option<serde_json::Value>> {
+        Ok(None)
+    }
+
+    async fn import(&self, _: i64, _: serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        None
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+}
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("tlset:{}", chat)
+}
+
+#[inline(always)]
+fn get_quota_key(chat: i64) -> String {
+    format!("tlquota:{}:{}", chat, Utc::now().format("%Y%m%d"))
+}
+
+async fn is_enabled(chat: i64) -> Result<bool> {
+    let key = get_settings_key(chat);
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = translation_settings::Entity::find_by_id(chat)
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.map(|v| v.enabled).unwrap_or(false))
+}
+
+async fn set_enabled(chat: i64, enabled: bool) -> Result<()> {
+    let model = translation_settings::Entity::insert(translation_settings::ActiveModel {
+        chat: Set(chat),
+        enabled: Set(enabled),
+    })
+    .on_conflict(
+        OnConflict::column(translation_settings::Column::Chat)
+            .update_column(translation_settings::Column::Enabled)
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_settings_key(chat)).await?;
+    Ok(())
+}
+
+/// Count a translation against a chat's daily quota, returning false if it is used up
+async fn take_quota(chat: i64) -> Result<bool> {
+    if CONFIG.translate.chat_per_day == 0 || is_exempt(chat) {
+        return Ok(true);
+    }
+    let key = get_quota_key(chat);
+    let (used,): (u64,) = REDIS
+        .pipe(|q| q.incr(&key, 1).expire(&key, 24 * 60 * 60).ignore())
+        .await?;
+    Ok(used <= CONFIG.translate.chat_per_day)
+}
+
+fn is_lang_code(lang: &str) -> bool {
+    (2..=7).contains(&lang.len()) && lang.chars().all(|c| c.is_ascii_alphabetic() || c == '-')
+}
+
+async fn tl(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    if !CONFIG.translate.enabled {
+        return ctx.fail(lang_fmt!(ctx, "tlunavailable"));
+    }
+    if !is_dm(message.get_chat()) && !is_enabled(chat).await? {
+        return ctx.fail(lang_fmt!(ctx, "tldisabled"));
+    }
+    let lang = args.text.trim().to_lowercase();
+    if !is_lang_code(&lang) {
+        return ctx.fail(lang_fmt!(ctx, "tlusage"));
+    }
+    let Some(reply) = message.get_reply_to_message() else {
+        return ctx.fail(lang_fmt!(ctx, "tlusage"));
+    };
+    let (text, entities) = if let Some(text) = reply.get_text() {
+        (text, reply.get_entities())
+    } else if let Some(caption) = reply.get_caption() {
+        (caption, reply.get_caption_entities())
+    } else {
+        return ctx.fail(lang_fmt!(ctx, "tlnotext"));
+    };
+    if text.chars().count() > CONFIG.translate.max_chars {
+        return ctx.fail(lang_fmt!(ctx, "tltoolong", CONFIG.translate.max_chars));
+    }
+    let html = to_html(text, entities);
+    if !take_quota(chat).await? {
+        return ctx.fail(lang_fmt!(ctx, "tlquota", CONFIG.translate.chat_per_day));
+    }
+
+    let translated = translate(&lang, &html).await?;
+    let reply_to = ReplyParametersBuilder::new(reply.get_message_id()).build();
+    let sent = TG
+        .client()
+        .build_send_message(chat, &translated)
+        .parse_mode("HTML")
+        .reply_parameters(&reply_to)
+        .build()
+        .await;
+    if sent.is_err() {
+        // the backend mangled the html, the translation is still worth sending
+        TG.client()
+            .build_send_message(chat, &strip_html(&translated))
+            .reply_parameters(&reply_to)
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn translation(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    match args.text.trim() {
+        "on" | "yes" => {
+            set_enabled(chat, true).await?;
+            ctx.reply(lang_fmt!(ctx, "translationon")).await?;
+        }
+        "off" | "no" => {
+            set_enabled(chat, false).await?;
+            ctx.reply(lang_fmt!(ctx, "translationoff")).await?;
+        }
+        "" if is_enabled(chat).await? => {
+            ctx.reply(lang_fmt!(ctx, "translationon")).await?;
+        }
+        "" => {
+            ctx.reply(lang_fmt!(ctx, "translationoff")).await?;
+        }
+        _ => return ctx.fail(lang_fmt!(ctx, "translationusage")),
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "tl" => tl(ctx, args).await?,
+            "translation" => translation(ctx, args).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,446 @@
+use self::entities::trust_settings;
+use crate::metadata::{metadata, ModuleHelpers};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::tg::admin_helpers::{is_dm, DeleteAfterTime, UpdateHelpers};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_joined_at;
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
+use crate::tg::user::Username;
+use crate::util::error::{Fail, Result};
+use crate::util::string::{Lang, Speak};
+use botapi::gen_types::Message;
+use chrono::{Duration, Utc};
+use itertools::Itertools;
+use macros::{lang_fmt, update_handler};
+use redis::AsyncCommands;
+use sea_orm::{EntityTrait, IntoActiveModel};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::OnConflict;
+use serde::{Deserialize, Serialize};
+
+metadata!("Trust",
+    r#"
+    Make new members earn the right to post links, media, and forwards. Until a member has
+    sent enough messages or spent enough days in this chat, anything they send of the
+    restricted kinds is deleted, so spam accounts that join and immediately post a link or
+    forward get nowhere. Either threshold is enough, and 0 turns a threshold off.
+
+    Members are counted from when they joined, or from when the bot first saw them.
+    Members the bot knew before trust was added are trusted already. Admins and approved
+    users are never restricted.
+
+    [*Example:]
+    /trust 10 3 links forwards
+    Members need 10 messages or 3 days in the chat before sending links or forwards.
+    Media is still allowed
+    "#,
+    Helper,
+    { category = "Moderation" },
+    { command = "trust", help = "Usage: trust \\[off/\\<messages\\> \\<days\\> \\[links\\] \\[media\\] \\[forwards\\]\\]. Shows or sets what new members need before posting links, media, or forwards", level = Admin, requires = [CanChangeInfo] },
+    { updates = [Message] }
+);
+
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(trust_settings::Entity)
+                        .col(
+                            ColumnDef::new(trust_settings::Column::Chat)
+                                .big_integer()
+                                .not_null()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(trust_settings::Column::Messages)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(trust_settings::Column::Days)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(trust_settings::Column::Links)
+                                .boolean()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(trust_settings::Column::Media)
+                                .boolean()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(trust_settings::Column::Forwards)
+                                .boolean()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(trust_settings::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod trust_settings {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// What new members of a chat need before they can post restricted content.
+        /// Members are restricted in every chat with a row
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "trust_settings")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            /// messages sent in the chat to be trusted, 0 if messages don't count
+            pub messages: i64,
+            /// days in the chat to be trusted, 0 if time doesn't count
+            pub days: i64,
+            pub links: bool,
+            pub media: bool,
+            pub forwards: bool,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000043_create_trust_settings"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// Trust settings as stored in backups, without the chat
+#[derive(Serialize, Deserialize)]
+struct ExportTrust {
+    messages: i64,
+    days: i64,
+    links: bool,
+    media: bool,
+    forwards: bool,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        match get_settings(chat).await? {
+            Some(settings) => Ok(Some(serde_json::to_value(ExportTrust {
+                messages: settings.messages,
+                days: settings.days,
+                links: settings.links,
+                media: settings.media,
+                forwards: settings.forwards,
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let settings: ExportTrust = serde_json::from_value(value)?;
+        set_settings(
+            chat,
+            Some(trust_settings::Model {
+                chat,
+                messages: settings.messages,
+                days: settings.days,
+                links: settings.links,
+                media: settings.media,
+                forwards: settings.forwards,
+            }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("trust")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+}
+
+/// Seconds between notices to the same member that their message was removed
+const NOTICE_COOLDOWN: i64 = 300;
+
+#[inline(always)]
+fn get_settings_key(chat: i64) -> String {
+    format!("trustcfg:{}", chat)
+}
+
+#[inline(always)]
+fn get_counts_key(chat: i64) -> String {
+    format!("trustcount:{}", chat)
+}
+
+#[inline(always)]
+fn get_notice_key(chat: i64, user: i64) -> String {
+    format!("trustnotice:{}:{}", chat, user)
+}
+
+async fn get_settings(chat: i64) -> Result<Option<trust_settings::Model>> {
+    default_cache_query(
+        |_, _| async move {
+            let res = trust_settings::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_settings_key(chat), &())
+    .await
+}
+
+/// Save the trust settings of a chat, or turn trust off and forget message counts with None
+async fn set_settings(chat: i64, settings: Option<trust_settings::Model>) -> Result<()> {
+    let key = get_settings_key(chat);
+    match settings {
+        Some(settings) => {
+            let model = trust_settings::Entity::insert(settings.into_active_model())
+                .on_conflict(
+                    OnConflict::column(trust_settings::Column::Chat)
+                        .update_columns([
+                            trust_settings::Column::Messages,
+                            trust_settings::Column::Days,
+                            trust_settings::Column::Links,
+                            trust_settings::Column::Media,
+                            trust_settings::Column::Forwards,
+                        ])
+                        .to_owned(),
+                )
+                .exec_with_returning(*DB)
+                .await?;
+            model.cache(key).await?;
+        }
+        None => {
+            trust_settings::Entity::delete_by_id(chat).exec(*DB).await?;
+            REDIS.invalidate(&[&key, &get_counts_key(chat)]).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the kind of restricted content in a message, if any
+fn restricted_content(message: &Message, settings: &trust_settings::Model) -> Option<&'static str> {
+    let links = message
+        .get_entities()
+        .into_iter()
+        .chain(message.get_caption_entities())
+        .flatten()
+        .any(|v| matches!(v.get_tg_type(), "url" | "text_link"));
+    let media = message.get_photo().is_some()
+        || message.get_video().is_some()
+        || message.get_animation().is_some()
+        || message.get_document().is_some()
+        || message.get_audio().is_some()
+        || message.get_voice().is_some()
+        || message.get_video_note().is_some();
+    if settings.forwards && message.get_forward_origin().is_some() {
+        Some("forwards")
+    } else if settings.links && links {
+        Some("links")
+    } else if settings.media && media {
+        Some("media")
+    } else {
+        None
+    }
+}
+
+/// What a member needs to be trusted, in words
+fn describe_requirement(lang: &Lang, settings: &trust_settings::Model) -> String {
+    let mut parts = Vec::new();
+    if settings.messages > 0 {
+        parts.push(lang_fmt!(lang, "trustmessages", settings.messages));
+    }
+    if settings.days > 0 {
+        parts.push(lang_fmt!(lang, "trustdays", settings.days));
+    }
+    parts.join(&lang_fmt!(lang, "trustor"))
+}
+
+/// Kinds of content restricted by the settings, in words
+fn describe_restricted(settings: &trust_settings::Model) -> String {
+    [
+        (settings.links, "links"),
+        (settings.media, "media"),
+        (settings.forwards, "forwards"),
+    ]
+    .into_iter()
+    .filter(|(on, _)| *on)
+    .map(|(_, name)| name)
+    .join(", ")
+}
+
+/// Returns true if a member sent enough messages or has been in the chat long enough
+async fn is_trusted(settings: &trust_settings::Model, user: i64) -> Result<bool> {
+    if settings.messages > 0 {
+        let key = get_counts_key(settings.chat);
+        let count: Option<i64> = REDIS.sq(|q| q.zscore(&key, user)).await?;
+        if count.unwrap_or(0) >= settings.messages {
+            return Ok(true);
+        }
+    }
+    if settings.days > 0 {
+        // members recorded before join times were tracked have been here all along
+        let trusted = match get_joined_at(user, settings.chat).await? {
+            Some(joined) => Duration::try_days(settings.days)
+                .map(|days| joined + days <= Utc::now())
+                .unwrap_or(false),
+            None => true,
+        };
+        if trusted {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+async fn trust_cmd(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let args = args
+        .args
+        .iter()
+        .map(|v| v.get_text().to_lowercase())
+        .collect_vec();
+    match args.as_slice() {
+        [] => (),
+        [off] if off == "off" || off == "no" => set_settings(chat, None).await?,
+        [messages, days, kinds @ ..] => {
+            let (Ok(messages), Ok(days)) = (messages.parse::<i64>(), days.parse::<i64>()) else {
+                return ctx.fail(lang_fmt!(ctx, "trustinvalid"));
+            };
+            if messages < 0 || days < 0 || (messages == 0 && days == 0) {
+                return ctx.fail(lang_fmt!(ctx, "trustinvalid"));
+            }
+            if kinds
+                .iter()
+                .any(|v| !matches!(v.as_str(), "links" | "media" | "forwards"))
+            {
+                return ctx.fail(lang_fmt!(ctx, "trustinvalid"));
+            }
+            // with no kinds given, restrict all of them
+            let restricts = |kind: &str| kinds.is_empty() || kinds.iter().any(|v| v == kind);
+            set_settings(
+                chat,
+                Some(trust_settings::Model {
+                    chat,
+                    messages,
+                    days,
+                    links: restricts("links"),
+                    media: restricts("media"),
+                    forwards: restricts("forwards"),
+                }),
+            )
+            .await?;
+        }
+        _ => return ctx.fail(lang_fmt!(ctx, "trustinvalid")),
+    }
+    match get_settings(chat).await? {
+        Some(settings) => {
+            ctx.reply(lang_fmt!(
+                ctx,
+                "truston",
+                describe_requirement(ctx.lang(), &settings),
+                describe_restricted(&settings)
+            ))
+            .await?
+        }
+        None => ctx.reply(lang_fmt!(ctx, "trustoff")).await?,
+    };
+    Ok(())
+}
+
+/// Count a message towards its sender's trust, or delete it if the sender isn't trusted
+/// yet and it has restricted content
+async fn check_message(ctx: &Context) -> Result<()> {
+    let Some(message) = ctx.moderated_message().await else {
+        return Ok(());
+    };
+    let chat = message.get_chat();
+    if is_dm(chat) || message.get_sender_chat().is_some() {
+        return Ok(());
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let Some(settings) = get_settings(chat.get_id()).await? else {
+        return Ok(());
+    };
+    if let Some(kind) = restricted_content(message, &settings) {
+        if !is_trusted(&settings, user.get_id()).await? {
+            message.delete().await?;
+            let key = get_notice_key(chat.get_id(), user.get_id());
+            let (first,): (bool,) = REDIS
+                .pipe(|q| q.set_nx(&key, true).expire(&key, NOTICE_COOLDOWN).ignore())
+                .await?;
+            if first {
+                let notice = ctx
+                    .speak(lang_fmt!(
+                        ctx,
+                        "trustnotice",
+                        user.name_humanreadable(),
+                        kind,
+                        describe_requirement(ctx.lang(), &settings)
+                    ))
+                    .await?;
+                if let Some(notice) = notice {
+                    notice.delete_after_time(Duration::try_minutes(1).unwrap());
+                }
+            }
+            return Ok(());
+        }
+    }
+    if settings.messages > 0 {
+        let key = get_counts_key(chat.get_id());
+        let _: i64 = REDIS.sq(|q| q.zincr(&key, user.get_id(), 1)).await?;
+    }
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        if cmd == "trust" {
+            trust_cmd(ctx, args).await?;
+        }
+        return Ok(());
+    }
+    check_message(ctx).await?;
+    Ok(())
+}
@@ -1,15 +1,31 @@
-use crate::tg::command::{Cmd, Context};
-use crate::tg::markdown::remove_fillings;
-use crate::tg::user::{GetUser, Username};
+use self::entities::warn_reasons;
+use crate::metadata::ModuleHelpers;
+use crate::persist::admin::warns;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::button::{InlineKeyboardBuilder, OnPush};
+use crate::tg::command::{ArgSlice, Cmd, Context, PopSlice, TextArg};
+use crate::tg::dialog::get_dialog;
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
+use crate::tg::markdown::{remove_fillings, EntityMessage, Escape};
+use crate::tg::user::{GetChat, GetUser, Username};
 use crate::util::error::{BotError, Fail, SpeakErr};
+use crate::util::locale::Localize;
 
 use crate::{
     metadata::metadata, tg::admin_helpers::*, tg::command::TextArgs, tg::permissions::*,
     util::error::Result, util::string::Speak,
 };
 
-use humantime::format_duration;
+use botapi::gen_types::{EReplyMarkup, InlineKeyboardButtonBuilder, MaybeInaccessibleMessage};
+use chrono::{Duration, Utc};
 use macros::{entity_fmt, lang_fmt, update_handler};
+use sea_orm::sea_query::{Alias, Condition, Expr};
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, QueryOrder, QuerySelect};
+use sea_orm_migration::{MigrationName, MigrationTrait};
+use sea_query::OnConflict;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 metadata!("Warns",
     r#"
@@ -19,16 +35,285 @@ metadata!("Warns",
     After a user gets a set amount of warnings \(default 3\) the action specified by the /warnmode will
     be applied. The default action is to mute the user.
 
+    With /warnmode decay, a user's warns all reset once they go the given time without a new
+    one. Expired warns are cleaned up in the background.
+
+    Admins can save canned reasons with /addwarnreason. When a chat has canned reasons,
+    /warn without a reason shows them as buttons so warnings are given for the same few
+    reasons, and /warnstats counts the active warnings for each reason.
+
     "#,
-    { command = "warn", help = "Warns a user"},
+    Helper,
+    { category = "Moderation" },
+    { command = "warn", help = "Warns a user", level = Admin},
     { command = "warns", help = "Get warn count of a user"},
-    { command = "clearwarns", help = "Delete all warns for a user"},
+    { command = "clearwarns", help = "Delete all warns for a user", level = Admin},
     { command = "warntime", help = "Sets time before warns expire. Usage: /warntime 6m for 6 minutes.
-        Use /warntime clear to never expire"},
-    { command = "warnmode", help = "Set the action when max warns are reached. Can be 'mute', 'ban' or 'shame'"},
-    { command = "warnlimit", help = "Sets the number of warns before an action is taken." }
+        Use /warntime clear to never expire", level = Admin},
+    { command = "warnmode", help = "Set the action when max warns are reached. Can be 'mute', 'ban' or 'shame'.
+        Use /warnmode decay 1w to reset a user's warns after a week without new ones, or /warnmode decay off", level = Admin},
+    { command = "warnlimit", help = "Sets the number of warns before an action is taken.", level = Admin },
+    { command = "addwarnreason", help = "Usage: addwarnreason \\<reason\\>. Saves a canned reason offered when warning without one", level = Admin },
+    { command = "rmwarnreason", help = "Usage: rmwarnreason \\<reason\\>. Removes a canned warn reason", level = Admin },
+    { command = "warnreasons", help = "Lists the canned warn reasons of this chat" },
+    { command = "warnstats", help = "Counts the active warnings in this chat by reason", level = Admin },
+    { updates = [Message] }
 );
 
+pub mod entities {
+    use super::Migration;
+    use crate::persist::migrate::ManagerHelper;
+    use ::sea_orm_migration::prelude::*;
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for Migration {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(warn_reasons::Entity)
+                        .col(
+                            ColumnDef::new(warn_reasons::Column::Chat)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(warn_reasons::Column::Reason)
+                                .text()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            IndexCreateStatement::new()
+                                .col(warn_reasons::Column::Chat)
+                                .col(warn_reasons::Column::Reason)
+                                .primary(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager.drop_table_auto(warn_reasons::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod warn_reasons {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        /// A canned reason admins can pick when warning without giving one
+        #[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "warn_reasons")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub reason: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20261015_000045_create_warn_reasons"
+    }
+}
+
+pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
+    vec![Box::new(Migration)]
+}
+
+/// Most canned reasons a chat can have, each one is a button
+const MAX_REASONS: usize = 10;
+
+/// Longest canned reason, so it fits on a button
+const MAX_REASON_LEN: usize = 64;
+
+/// Warn settings of a chat
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportWarns {
+    warn_limit: i32,
+    warn_time: Option<i64>,
+    mode: String,
+    #[serde(default)]
+    warn_decay: Option<i64>,
+    #[serde(default)]
+    reasons: Vec<String>,
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let Some(chat) = chat.get_chat().await? else {
+            return Ok(None);
+        };
+        let Some(dialog) = get_dialog(&chat).await? else {
+            return Ok(None);
+        };
+        let warns = ExportWarns {
+            warn_limit: dialog.warn_limit,
+            warn_time: dialog.warn_time,
+            mode: dialog.action_type.get_name().to_owned(),
+            warn_decay: dialog.warn_decay,
+            reasons: get_warn_reasons(chat.get_id()).await?,
+        };
+        Ok(Some(serde_json::to_value(warns)?))
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let warns: ExportWarns = serde_json::from_value(value)?;
+        let Some(chat) = chat.get_chat().await? else {
+            return Ok(());
+        };
+        set_warn_limit(&chat, warns.warn_limit).await?;
+        set_warn_time(&chat, warns.warn_time).await?;
+        set_warn_mode(&chat, &warns.mode).await?;
+        set_warn_decay(&chat, warns.warn_decay).await?;
+        for reason in warns.reasons.iter().take(MAX_REASONS) {
+            add_warn_reason(chat.get_id(), reason).await?;
+        }
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("warns")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        get_migrations()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+}
+
+#[inline(always)]
+fn get_reasons_key(chat: i64) -> String {
+    format!("wreasons:{}", chat)
+}
+
+/// Get the canned warn reasons of a chat in alphabetical order
+async fn get_warn_reasons(chat: i64) -> Result<Vec<String>> {
+    let reasons = default_cache_query(
+        |_, _| async move {
+            let res = warn_reasons::Entity::find()
+                .filter(warn_reasons::Column::Chat.eq(chat))
+                .order_by_asc(warn_reasons::Column::Reason)
+                .all(*DB)
+                .await?
+                .into_iter()
+                .map(|v| v.reason)
+                .collect::<Vec<String>>();
+            Ok(Some(res))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_reasons_key(chat), &())
+    .await?;
+    Ok(reasons.unwrap_or_default())
+}
+
+async fn add_warn_reason(chat: i64, reason: &str) -> Result<()> {
+    warn_reasons::Entity::insert(
+        warn_reasons::Model {
+            chat,
+            reason: reason.to_owned(),
+        }
+        .into_active_model(),
+    )
+    .on_conflict(
+        OnConflict::columns([warn_reasons::Column::Chat, warn_reasons::Column::Reason])
+            .do_nothing()
+            .to_owned(),
+    )
+    .exec_without_returning(*DB)
+    .await?;
+    REDIS.invalidate(&[&get_reasons_key(chat)]).await?;
+    Ok(())
+}
+
+/// Remove a canned warn reason, returning false if it didn't exist
+async fn remove_warn_reason(chat: i64, reason: &str) -> Result<bool> {
+    let res = warn_reasons::Entity::delete_by_id((chat, reason.to_owned()))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_reasons_key(chat)]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+/// Ask the admin warning a user to pick one of the chat's canned reasons. The warning is
+/// given once they push a button
+async fn pick_reason(ctx: &Context, user: i64, reasons: Vec<String>) -> Result<()> {
+    let message = ctx.message()?;
+    let admin = message.get_from().map(|v| v.get_id());
+    let mut buttons = InlineKeyboardBuilder::default();
+    for reason in reasons.into_iter().map(Some).chain([None]) {
+        let text = reason.clone().unwrap_or_else(|| lang_fmt!(ctx, "noreason"));
+        let button = InlineKeyboardButtonBuilder::new(text)
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        let ctx = ctx.clone();
+        button.on_push_multi(move |callback| {
+            let ctx = ctx.clone();
+            let reason = reason.clone();
+            async move {
+                if Some(callback.get_from().get_id()) != admin {
+                    TG.client()
+                        .build_answer_callback_query(callback.get_id())
+                        .text(&lang_fmt!(ctx, "warnpicknotyou"))
+                        .show_alert(true)
+                        .build()
+                        .await?;
+                    return Ok(false);
+                }
+                TG.client()
+                    .build_answer_callback_query(callback.get_id())
+                    .build()
+                    .await?;
+                if let Some(MaybeInaccessibleMessage::Message(prompt)) = callback.get_message() {
+                    TG.client()
+                        .build_delete_message(prompt.get_chat().get_id(), prompt.get_message_id())
+                        .build()
+                        .await?;
+                }
+                ctx.warn_with_action(user, reason.as_deref(), None).await?;
+                Ok(true)
+            }
+        });
+        buttons.button(button);
+        buttons.newline();
+    }
+    let name = user
+        .get_cached_user()
+        .await?
+        .map(|v| v.name_humanreadable().into_owned())
+        .unwrap_or_else(|| user.to_string());
+    let mut reply = EntityMessage::new(message.get_chat().get_id())
+        .reply_markup(EReplyMarkup::InlineKeyboardMarkup(buttons.build()));
+    reply
+        .builder
+        .text(lang_fmt!(ctx, "warnpick", name.escape(false)));
+    ctx.reply_fmt(reply).await?;
+    Ok(())
+}
+
 pub async fn warn(context: &Context) -> Result<()> {
     context
         .check_permissions(|p| p.can_restrict_members)
@@ -48,6 +333,13 @@ pub async fn warn(context: &Context) -> Result<()> {
                 }
             });
 
+            if reason.is_none() {
+                let reasons = get_warn_reasons(ctx.message()?.get_chat().get_id()).await?;
+                if !reasons.is_empty() {
+                    return pick_reason(ctx, user, reasons).await;
+                }
+            }
+
             ctx.warn_with_action(user, reason, None).await?;
             Ok(())
         })
@@ -125,7 +417,7 @@ async fn set_time<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     let chat = ctx.try_get()?.chat.name_humanreadable();
     if let Ok(Some(time)) = ctx.parse_duration(&Some(args.as_slice())) {
         set_warn_time(message.get_chat(), Some(time.num_seconds())).await?;
-        let time = format_duration(time.to_std()?);
+        let time = time.localize(ctx.lang());
         message.reply(format!("Set warn time to {}", time)).await?;
     } else if args.text.trim() == "clear" {
         set_warn_time(message.get_chat(), None).await?;
@@ -138,10 +430,29 @@ async fn set_time<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     Ok(())
 }
 
+/// Set or clear the time after which a user's warns reset if they aren't warned again
+async fn warn_decay(ctx: &Context, args: ArgSlice<'_>) -> Result<()> {
+    let chat = ctx.message()?.get_chat();
+    if args.text.trim() == "off" {
+        set_warn_decay(chat, None).await?;
+        ctx.reply(lang_fmt!(ctx, "warndecayoff")).await?;
+    } else if let Ok(Some(time)) = ctx.parse_duration(&Some(args)) {
+        set_warn_decay(chat, Some(time.num_seconds())).await?;
+        ctx.reply(lang_fmt!(ctx, "warndecay", time.localize(ctx.lang())))
+            .await?;
+    } else {
+        ctx.fail(lang_fmt!(ctx, "warndecayusage"))?;
+    }
+    Ok(())
+}
+
 async fn cmd_warn_mode<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     ctx.check_permissions(|p| p.can_restrict_members).await?;
     let message = ctx.message()?;
     let chat = ctx.try_get()?.chat.name_humanreadable();
+    if let Some((TextArg::Arg("decay"), rest)) = args.pop_slice() {
+        return warn_decay(ctx, rest).await;
+    }
     set_warn_mode(message.get_chat(), args.text).await?;
     message
         .reply(lang_fmt!(ctx.lang(), "warnmode", args.text, chat))
@@ -171,6 +482,87 @@ async fn cmd_warn_limit<'a>(ctx: &Context, args: &TextArgs<'a>) -> Result<()> {
     Ok(())
 }
 
+async fn cmd_add_reason(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_restrict_members).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let reason = args.text.trim();
+    if reason.is_empty() || reason.chars().count() > MAX_REASON_LEN {
+        return ctx.fail(lang_fmt!(ctx, "addwarnreasonusage", MAX_REASON_LEN));
+    }
+    let reasons = get_warn_reasons(chat).await?;
+    if !reasons.iter().any(|v| v == reason) && reasons.len() >= MAX_REASONS {
+        return ctx.fail(lang_fmt!(ctx, "warnreasonsfull", MAX_REASONS));
+    }
+    add_warn_reason(chat, reason).await?;
+    ctx.reply(lang_fmt!(ctx, "warnreasonadded", reason.escape(false)))
+        .await?;
+    Ok(())
+}
+
+async fn cmd_rm_reason(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.check_permissions(|p| p.can_restrict_members).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let reason = args.text.trim();
+    if remove_warn_reason(chat, reason).await? {
+        ctx.reply(lang_fmt!(ctx, "warnreasonremoved", reason.escape(false)))
+            .await?;
+    } else {
+        ctx.fail(lang_fmt!(ctx, "warnreasonmissing", reason.escape(false)))?;
+    }
+    Ok(())
+}
+
+async fn cmd_list_reasons(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    let reasons = get_warn_reasons(ctx.message()?.get_chat().get_id()).await?;
+    if reasons.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "nowarnreasons")).await?;
+        return Ok(());
+    }
+    let list = reasons
+        .iter()
+        .map(|v| format!("- {}", v.escape(false)))
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(lang_fmt!(ctx, "listwarnreasons", list)).await?;
+    Ok(())
+}
+
+/// Count the active warnings in a chat by reason, most common first
+async fn cmd_warn_stats(ctx: &Context) -> Result<()> {
+    ctx.check_permissions(|p| p.can_restrict_members).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    let counts: Vec<(Option<String>, i64)> = warns::Entity::find()
+        .select_only()
+        .column(warns::Column::Reason)
+        .column_as(warns::Column::Id.count(), "count")
+        .filter(warns::Column::ChatId.eq(chat))
+        .filter(
+            Condition::any()
+                .add(warns::Column::Expires.is_null())
+                .add(warns::Column::Expires.gt(Utc::now())),
+        )
+        .group_by(warns::Column::Reason)
+        .order_by_desc(Expr::col(Alias::new("count")))
+        .into_tuple()
+        .all(*DB)
+        .await?;
+    if counts.is_empty() {
+        ctx.reply(lang_fmt!(ctx, "warnstatsnone")).await?;
+        return Ok(());
+    }
+    let list = counts
+        .into_iter()
+        .map(|(reason, count)| {
+            let reason = reason.unwrap_or_else(|| lang_fmt!(ctx, "noreason"));
+            format!("- {}: {}", reason.escape(false), count)
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    ctx.reply(lang_fmt!(ctx, "warnstats", list)).await?;
+    Ok(())
+}
+
 async fn handle_command<'a>(ctx: &Context) -> Result<()> {
     if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
         match cmd {
@@ -180,6 +572,10 @@ async fn handle_command<'a>(ctx: &Context) -> Result<()> {
             "warntime" => set_time(ctx, args).await,
             "warnmode" => cmd_warn_mode(ctx, args).await,
             "warnlimit" => cmd_warn_limit(ctx, args).await,
+            "addwarnreason" => cmd_add_reason(ctx, args).await,
+            "rmwarnreason" => cmd_rm_reason(ctx, args).await,
+            "warnreasons" => cmd_list_reasons(ctx).await,
+            "warnstats" => cmd_warn_stats(ctx).await,
             _ => Ok(()),
         }?;
     }
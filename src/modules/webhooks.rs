@@ -0,0 +1,104 @@
+use crate::metadata::metadata;
+use crate::tg::admin_helpers::{UpdateHelpers, UserChanged};
+use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::permissions::*;
+use crate::tg::user::Username;
+use crate::tg::webhooks::{
+    delete_webhook, get_webhook, new_secret, post_event, set_webhook, validate_url, ChatEvent,
+    SIGNATURE_HEADER,
+};
+use crate::util::error::{Fail, Result};
+use crate::util::string::Speak;
+use macros::{lang_fmt, update_handler};
+
+metadata!("Webhooks",
+    r#"
+    Send this chat's events to a url of your choice, for bridges, ticket systems, or other
+    automations. New members, warns, and notes being triggered are POSTed as json.
+    Every request is signed with a secret sent to you in private when the webhook is set,
+    the X-Dijkstra-Signature header holds sha256= followed by the hex hmac of the body.
+    "#,
+    { category = "Content" },
+    { command = "setwebhook", help = "Usage: setwebhook \\<https url\\>. Sends chat events to url", level = Admin },
+    { command = "delwebhook", help = "Stop sending chat events", level = Admin },
+    { command = "webhook", help = "Show where chat events are sent", level = Admin },
+    { updates = [Message, ChatMember] }
+);
+
+async fn setwebhook(ctx: &Context, args: &TextArgs<'_>) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let message = ctx.message()?;
+    let chat = message.get_chat();
+    let user = ctx.get_real_from()?;
+    let url = args.text.trim();
+    if validate_url(url).is_err() {
+        return ctx.fail(lang_fmt!(ctx, "webhookinvalid"));
+    }
+    let secret = new_secret();
+    // the secret only goes to the admin, so the webhook isn't saved unless they got it
+    if user
+        .get_id()
+        .speak(lang_fmt!(
+            ctx,
+            "webhooksecret",
+            chat.name_humanreadable(),
+            SIGNATURE_HEADER,
+            secret
+        ))
+        .await
+        .is_err()
+    {
+        return ctx.fail(lang_fmt!(ctx, "webhooknodm"));
+    }
+    set_webhook(chat.get_id(), url.to_owned(), secret).await?;
+    ctx.reply(lang_fmt!(ctx, "webhookset", url)).await?;
+    Ok(())
+}
+
+async fn delwebhook(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    if delete_webhook(chat).await? {
+        ctx.reply(lang_fmt!(ctx, "webhookdeleted")).await?;
+    } else {
+        ctx.reply(lang_fmt!(ctx, "webhooknone")).await?;
+    }
+    Ok(())
+}
+
+async fn webhook(ctx: &Context) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let chat = ctx.message()?.get_chat().get_id();
+    match get_webhook(chat).await? {
+        Some(hook) => ctx.reply(lang_fmt!(ctx, "webhookstatus", hook.url)).await?,
+        None => ctx.reply(lang_fmt!(ctx, "webhooknone")).await?,
+    };
+    Ok(())
+}
+
+#[update_handler]
+pub async fn handle_update(ctx: &Context) -> Result<()> {
+    if let Some(UserChanged::UserJoined(member)) = ctx.update().user_event() {
+        let user = member.get_from();
+        post_event(
+            member.get_chat().get_id(),
+            ChatEvent::NewMember {
+                user: user.get_id(),
+                name: user.name_humanreadable().into_owned(),
+            },
+        )
+        .await?;
+    }
+    if let Some(&Cmd { cmd, ref args, .. }) = ctx.cmd() {
+        match cmd {
+            "setwebhook" => setwebhook(ctx, args).await?,
+            "delwebhook" => delwebhook(ctx).await?,
+            "webhook" => webhook(ctx).await?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
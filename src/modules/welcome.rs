@@ -1,17 +1,27 @@
-use crate::persist::core::media::get_media_type;
+use crate::metadata::ModuleHelpers;
+use crate::persist::core::media::{get_media_type, MediaType};
 use crate::persist::core::{entity, welcomes};
 use crate::statics::{DB, REDIS};
+use crate::tg::admin_helpers::{set_clean_greeting, set_greet_flood_limit, set_rules_gate};
 use crate::tg::command::{Cmd, Context, TextArgs};
+use crate::tg::dialog::get_dialog;
+use crate::tg::greetings::preview_welcome;
+use crate::tg::import_export::ROSE_EXPORT_VERSION;
 use crate::tg::markdown::MarkupBuilder;
 use crate::tg::permissions::*;
-use crate::util::error::{BotError, Result};
+use crate::tg::quotas::check_welcome_media;
+use crate::tg::rosemd::{RoseMdDecompiler, RoseMdParser};
+use crate::tg::template::ChatTemplate;
+use crate::tg::user::GetChat;
+use crate::util::error::{BotError, Fail, Result};
 use crate::util::string::Lang;
 use crate::{metadata::metadata, util::string::Speak};
-use botapi::gen_types::Message;
+use botapi::gen_types::{Chat, Message};
 use macros::{lang_fmt, update_handler};
-use redis::AsyncCommands;
 use sea_orm::entity::ActiveValue::{NotSet, Set};
-use sea_orm::EntityTrait;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+use sea_orm_migration::MigrationTrait;
+use serde::{Deserialize, Serialize};
 
 use sea_query::OnConflict;
 
@@ -24,20 +34,39 @@ metadata!("Welcome",
     [*Example:]  
     /welcome on  
     /setwelcome Hi there \{mention\}, welcome to \{chatname\}
+
+    Use /previewwelcome to check how the welcome looks without waiting for someone to join.
+    Add a language code to preview the default welcome in that language.
+
+    Reply to a photo, sticker, or document with /setwelcome to greet new members with it.
+    Welcomes and goodbyes support formatting, buttons, and the fillings \{mention\}, \{first\},
+    \{chatname\}, \{id\}, and \{rules\} for a button linking to the chat's rules.
+    Use /cleanwelcome and /cleangoodbye to delete the previous greeting when a new one is
+    sent, so they don't pile up in busy chats.
     
     "#,
-    { command = "welcome", help = "Usage: welcome \\<on/off\\>. Enables or disables welcome" },
-    { command = "setwelcome", help = "Sets the welcome text. Reply to a message or media to set"},
-    { command = "setgoodbye", help = "Sets the goodbye message for when a user leaves"},
-    { command = "resetwelcome", help = "Resets welcome and goodbye messages to default" }
+    Helper,
+    { category = "Chat Management" },
+    { command = "welcome", help = "Usage: welcome \\<on/off\\>. Enables or disables welcome", level = Admin },
+    { command = "setwelcome", help = "Sets the welcome text. Reply to a message or media to set", level = Admin},
+    { command = "setgoodbye", help = "Sets the goodbye message for when a user leaves", level = Admin},
+    { command = "resetwelcome", help = "Resets the welcome message to default", level = Admin },
+    { command = "resetgoodbye", help = "Resets the goodbye message to default", level = Admin },
+    { command = "cleanwelcome", help = "Usage: cleanwelcome \\<on/off\\>. Deletes the previous welcome when a new member is welcomed", level = Admin },
+    { command = "cleangoodbye", help = "Usage: cleangoodbye \\<on/off\\>. Deletes the previous goodbye when a member leaves", level = Admin },
+    { command = "welcomeflood", help = "Usage: welcomeflood \\<count/off\\>. Replace greetings with a summary when more than count users join or leave in a minute", level = Admin },
+    { command = "rulesgate", help = "Usage: rulesgate \\<on/off\\>. Keep new members muted until they press the button on the welcome message to agree to the rules", level = Admin },
+    { command = "previewwelcome", help = "Usage: previewwelcome \\[lang\\]. Shows the welcome message as if you had just joined", level = Admin },
+    { updates = [Message] }
 );
 
 async fn get_model<'a>(
     message: &'a Message,
     args: &'a TextArgs<'a>,
     goodbye: bool,
+    lang: &Lang,
 ) -> Result<welcomes::ActiveModel> {
-    let (message, text, extra) = if let Some(message) = message.get_reply_to_message() {
+    let (media, text, extra) = if let Some(message) = message.get_reply_to_message() {
         (
             message,
             message.get_text(),
@@ -46,6 +75,8 @@ async fn get_model<'a>(
     } else {
         (message, Some(args.text), None)
     };
+    check_welcome_media(message, media, lang)?;
+    let message = media;
 
     let (text, entity_id) = if let Some(text) = text {
         let (text, entities, buttons) = MarkupBuilder::new(extra)
@@ -92,6 +123,212 @@ async fn get_model<'a>(
     Ok(res)
 }
 
+/// A welcome or goodbye, with formatting and buttons written as rose markdown
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportGreeting {
+    text: Option<String>,
+    media_id: Option<String>,
+    media_type: Option<MediaType>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ExportWelcome {
+    enabled: bool,
+    clean_welcome: bool,
+    clean_goodbye: bool,
+    welcome: ExportGreeting,
+    goodbye: ExportGreeting,
+}
+
+impl ExportGreeting {
+    async fn new(
+        text: Option<String>,
+        media_id: Option<String>,
+        media_type: Option<MediaType>,
+        entity_id: Option<i64>,
+    ) -> Result<Self> {
+        let text = match text {
+            Some(text) => {
+                let (entities, buttons) = match entity_id {
+                    Some(entity_id) => entity::get(*DB, entity_id).await?,
+                    None => (vec![], None),
+                };
+                let buttons = buttons.unwrap_or_default().build();
+                Some(
+                    RoseMdDecompiler::new(&text, &entities, buttons.get_inline_keyboard())
+                        .decompile(),
+                )
+            }
+            None => None,
+        };
+        Ok(Self {
+            text,
+            media_id,
+            media_type,
+        })
+    }
+
+    /// Get the text and entity id to store for this greeting
+    async fn insert(&self) -> Result<(Option<String>, Option<i64>)> {
+        let Some(text) = self.text.as_deref() else {
+            return Ok((None, None));
+        };
+        let (text, entities, buttons) = RoseMdParser::new(text, true).parse();
+        let entity_id = entity::insert(*DB, &entities, buttons).await?;
+        Ok((Some(text), entity_id))
+    }
+}
+
+#[derive(Debug)]
+struct Helper;
+
+#[async_trait::async_trait]
+impl ModuleHelpers for Helper {
+    async fn export(&self, chat: i64) -> Result<Option<serde_json::Value>> {
+        let Some(model) = welcomes::Entity::find_by_id(chat).one(*DB).await? else {
+            return Ok(None);
+        };
+        let dialog = match chat.get_chat().await? {
+            Some(chat) => get_dialog(&chat).await?,
+            None => None,
+        };
+        let welcome = ExportWelcome {
+            enabled: model.enabled,
+            clean_welcome: dialog.as_ref().map(|v| v.clean_welcome).unwrap_or(false),
+            clean_goodbye: dialog.as_ref().map(|v| v.clean_goodbye).unwrap_or(false),
+            welcome: ExportGreeting::new(
+                model.text,
+                model.media_id,
+                model.media_type,
+                model.welcome_entity_id,
+            )
+            .await?,
+            goodbye: ExportGreeting::new(
+                model.goodbye_text,
+                model.goodbye_media_id,
+                model.goodbye_media_type,
+                model.goodbye_entity_id,
+            )
+            .await?,
+        };
+        Ok(Some(serde_json::to_value(welcome)?))
+    }
+
+    async fn import(&self, chat: i64, value: serde_json::Value) -> Result<()> {
+        let welcome: ExportWelcome = serde_json::from_value(value)?;
+        let (text, welcome_entity_id) = welcome.welcome.insert().await?;
+        let (goodbye_text, goodbye_entity_id) = welcome.goodbye.insert().await?;
+        let model = welcomes::Model {
+            chat,
+            text,
+            media_id: welcome.welcome.media_id,
+            media_type: welcome.welcome.media_type,
+            goodbye_text,
+            goodbye_media_id: welcome.goodbye.media_id,
+            goodbye_media_type: welcome.goodbye.media_type,
+            enabled: welcome.enabled,
+            welcome_entity_id,
+            goodbye_entity_id,
+        };
+        welcomes::Entity::insert(model.into_active_model())
+            .on_conflict(
+                OnConflict::column(welcomes::Column::Chat)
+                    .update_columns([
+                        welcomes::Column::Text,
+                        welcomes::Column::MediaId,
+                        welcomes::Column::MediaType,
+                        welcomes::Column::GoodbyeText,
+                        welcomes::Column::GoodbyeMediaId,
+                        welcomes::Column::GoodbyeMediaType,
+                        welcomes::Column::Enabled,
+                        welcomes::Column::WelcomeEntityId,
+                        welcomes::Column::GoodbyeEntityId,
+                    ])
+                    .to_owned(),
+            )
+            .exec_without_returning(*DB)
+            .await?;
+        let key = format!("welcome:{}", chat);
+        REDIS.invalidate(&[&key]).await?;
+        if let Some(chat) = chat.get_chat().await? {
+            set_clean_greeting(&chat, false, welcome.clean_welcome).await?;
+            set_clean_greeting(&chat, true, welcome.clean_goodbye).await?;
+        }
+        Ok(())
+    }
+
+    fn supports_export(&self) -> Option<&'static str> {
+        Some("welcome")
+    }
+
+    fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        Vec::new()
+    }
+
+    fn upgrade_export(
+        &self,
+        version: u32,
+        value: serde_json::Value,
+    ) -> Result<Option<serde_json::Value>> {
+        Ok((version != ROSE_EXPORT_VERSION).then_some(value))
+    }
+
+    async fn apply_template(&self, chat: &Chat, template: &ChatTemplate) -> Result<()> {
+        let Some(template) = template.section::<WelcomeTemplate>("welcome")? else {
+            return Ok(());
+        };
+        let welcome = template_text(template.text).await?;
+        let goodbye = template_text(template.goodbye).await?;
+        let model = welcomes::ActiveModel {
+            chat: Set(chat.get_id()),
+            media_type: Set(welcome.0.as_ref().map(|_| MediaType::Text)),
+            text: Set(welcome.0),
+            media_id: Set(None),
+            goodbye_media_type: Set(goodbye.0.as_ref().map(|_| MediaType::Text)),
+            goodbye_text: Set(goodbye.0),
+            goodbye_media_id: Set(None),
+            enabled: Set(template.enabled),
+            welcome_entity_id: Set(welcome.1),
+            goodbye_entity_id: Set(goodbye.1),
+        };
+        // never clobber a welcome the chat already has
+        welcomes::Entity::insert(model)
+            .on_conflict(
+                OnConflict::column(welcomes::Column::Chat)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(*DB)
+            .await?;
+        let key = format!("welcome:{}", chat.get_id());
+        REDIS.invalidate(&[&key]).await?;
+        Ok(())
+    }
+}
+
+/// The welcome section of a chat template. Texts use the same formatting as /setwelcome
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct WelcomeTemplate {
+    enabled: bool,
+    text: Option<String>,
+    goodbye: Option<String>,
+}
+
+async fn template_text(text: Option<String>) -> Result<(Option<String>, Option<i64>)> {
+    let Some(text) = text else {
+        return Ok((None, None));
+    };
+    let (text, entities, buttons) = MarkupBuilder::new(None)
+        .set_text(text)
+        .filling(false)
+        .header(false)
+        .build_murkdown_nofail()
+        .await;
+    let entity_id = entity::insert(*DB, &entities, buttons).await?;
+    Ok((Some(text), entity_id))
+}
+
 async fn enable_welcome<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
     message.check_permissions(|p| p.can_change_info).await?;
     let key = format!("welcome:{}", message.get_chat().get_id());
@@ -127,14 +364,14 @@ async fn enable_welcome<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang)
         )
         .exec_with_returning(*DB)
         .await?;
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     message.reply("Enabled welcome").await?;
     Ok(())
 }
 
 async fn set_goodbye<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
     message.check_permissions(|p| p.can_change_info).await?;
-    let model = get_model(message, args, true).await?;
+    let model = get_model(message, args, true, lang).await?;
     let key = format!("welcome:{}", message.get_chat().get_id());
     log::info!("save goodbye: {}", key);
     let model = welcomes::Entity::insert(model)
@@ -144,19 +381,18 @@ async fn set_goodbye<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) ->
                     welcomes::Column::GoodbyeText,
                     welcomes::Column::GoodbyeMediaId,
                     welcomes::Column::GoodbyeMediaType,
-                    welcomes::Column::WelcomeEntityId,
                     welcomes::Column::GoodbyeEntityId,
                 ])
                 .to_owned(),
         )
         .exec_with_returning(*DB)
         .await?;
-    let text = if let Some(text) = model.text.as_ref() {
+    let text = if let Some(text) = model.goodbye_text.as_ref() {
         lang_fmt!(lang, "setgoodbye", text)
     } else {
         lang_fmt!(lang, "setgoodbye", "*media*")
     };
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
 
     message.reply(text).await?;
     Ok(())
@@ -165,7 +401,7 @@ async fn set_goodbye<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) ->
 async fn set_welcome<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
     message.check_permissions(|p| p.can_change_info).await?;
 
-    let model = get_model(message, args, false).await?;
+    let model = get_model(message, args, false, lang).await?;
     let key = format!("welcome:{}", message.get_chat().get_id());
     log::info!("save welcome: {}", key);
     let model = welcomes::Entity::insert(model)
@@ -176,7 +412,6 @@ async fn set_welcome<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) ->
                     welcomes::Column::MediaId,
                     welcomes::Column::MediaType,
                     welcomes::Column::WelcomeEntityId,
-                    welcomes::Column::GoodbyeEntityId,
                 ])
                 .to_owned(),
         )
@@ -188,7 +423,7 @@ async fn set_welcome<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) ->
     } else {
         lang_fmt!(lang, "setwelcome", "*media*")
     };
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     message.reply(text).await?;
     Ok(())
 }
@@ -206,21 +441,150 @@ async fn handle_command(ctx: &Context) -> Result<()> {
             "setwelcome" => set_welcome(message, args, lang).await?,
             "setgoodbye" => set_goodbye(message, args, lang).await?,
             "welcome" => enable_welcome(message, args, lang).await?,
-            "resetwelcome" => reset_welcome(message, lang).await?,
+            "resetwelcome" => reset_welcome(message, false, lang).await?,
+            "resetgoodbye" => reset_welcome(message, true, lang).await?,
+            "cleanwelcome" => enable_clean(message, args, false, lang).await?,
+            "cleangoodbye" => enable_clean(message, args, true, lang).await?,
+            "welcomeflood" => set_flood_limit(message, args, lang).await?,
+            "rulesgate" => enable_rules_gate(message, args, lang).await?,
+            "previewwelcome" => preview(ctx, args, lang).await?,
             _ => (),
         };
     }
     Ok(())
 }
 
-async fn reset_welcome(message: &Message, lang: &Lang) -> Result<()> {
+async fn set_flood_limit<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
+    message.check_permissions(|p| p.can_change_info).await?;
+    let limit = match args.args.first().map(|v| v.get_text()) {
+        Some("off") => Some(0),
+        Some(v) => v.parse::<i32>().ok().filter(|v| *v > 0),
+        None => None,
+    }
+    .ok_or_else(|| {
+        BotError::speak(
+            lang_fmt!(lang, "welcomefloodinvalid"),
+            message.get_chat().get_id(),
+            Some(message.message_id),
+        )
+    })?;
+    set_greet_flood_limit(message.get_chat(), limit).await?;
+    if limit == 0 {
+        message.reply(lang_fmt!(lang, "welcomefloodoff")).await?;
+    } else {
+        message
+            .reply(lang_fmt!(lang, "welcomeflood", limit))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn enable_rules_gate<'a>(message: &Message, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
+    message
+        .check_permissions(|p| p.can_change_info.and(p.can_restrict_members))
+        .await?;
+    let enabled = match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => Ok(true),
+        Some("off") | Some("no") => Ok(false),
+        _ => Err(BotError::speak(
+            lang_fmt!(lang, "rulesgateinvalid"),
+            message.get_chat().get_id(),
+            Some(message.message_id),
+        )),
+    }?;
+    set_rules_gate(message.get_chat(), enabled).await?;
+    if enabled {
+        message.reply(lang_fmt!(lang, "rulesgateon")).await?;
+    } else {
+        message.reply(lang_fmt!(lang, "rulesgateoff")).await?;
+    }
+    Ok(())
+}
+
+async fn preview<'a>(ctx: &Context, args: &TextArgs<'a>, lang: &Lang) -> Result<()> {
+    ctx.is_group_or_die().await?;
+    ctx.check_permissions(|p| p.can_change_info).await?;
+    let lang = match args.args.first().map(|v| Lang::from_code(v.get_text())) {
+        Some(Lang::Invalid) => return ctx.fail(lang_fmt!(lang, "invalidlang")),
+        Some(lang) => lang,
+        None => *lang,
+    };
+    preview_welcome(ctx, &lang).await
+}
+
+/// Clear the welcome, or the goodbye if goodbye is true, so the default is used again
+async fn reset_welcome(message: &Message, goodbye: bool, lang: &Lang) -> Result<()> {
     message.check_permissions(|p| p.can_change_info).await?;
     let chat = message.get_chat().get_id();
     let key = format!("welcome:{}", chat);
 
-    welcomes::Entity::delete_by_id(chat).exec(*DB).await?;
-    REDIS.sq(|q| q.del(&key)).await?;
-    message.reply(lang_fmt!(lang, "resetwelcome")).await?;
+    let model = if goodbye {
+        welcomes::ActiveModel {
+            chat: NotSet,
+            text: NotSet,
+            media_id: NotSet,
+            media_type: NotSet,
+            goodbye_text: Set(None),
+            goodbye_media_id: Set(None),
+            goodbye_media_type: Set(None),
+            enabled: NotSet,
+            welcome_entity_id: NotSet,
+            goodbye_entity_id: Set(None),
+        }
+    } else {
+        welcomes::ActiveModel {
+            chat: NotSet,
+            text: Set(None),
+            media_id: Set(None),
+            media_type: Set(None),
+            goodbye_text: NotSet,
+            goodbye_media_id: NotSet,
+            goodbye_media_type: NotSet,
+            enabled: NotSet,
+            welcome_entity_id: Set(None),
+            goodbye_entity_id: NotSet,
+        }
+    };
+    welcomes::Entity::update_many()
+        .set(model)
+        .filter(welcomes::Column::Chat.eq(chat))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&key]).await?;
+    if goodbye {
+        message.reply(lang_fmt!(lang, "resetgoodbye")).await?;
+    } else {
+        message.reply(lang_fmt!(lang, "resetwelcome")).await?;
+    }
+    Ok(())
+}
+
+async fn enable_clean<'a>(
+    message: &Message,
+    args: &TextArgs<'a>,
+    goodbye: bool,
+    lang: &Lang,
+) -> Result<()> {
+    message
+        .check_permissions(|p| p.can_change_info.and(p.can_delete_messages))
+        .await?;
+    let enabled = match args.args.first().map(|v| v.get_text()) {
+        Some("on") | Some("yes") => Ok(true),
+        Some("off") | Some("no") => Ok(false),
+        _ => Err(BotError::speak(
+            lang_fmt!(lang, "welcomeinvalid"),
+            message.get_chat().get_id(),
+            Some(message.message_id),
+        )),
+    }?;
+    set_clean_greeting(message.get_chat(), goodbye, enabled).await?;
+    let reply = match (goodbye, enabled) {
+        (false, true) => lang_fmt!(lang, "cleanwelcomeon"),
+        (false, false) => lang_fmt!(lang, "cleanwelcomeoff"),
+        (true, true) => lang_fmt!(lang, "cleangoodbyeon"),
+        (true, false) => lang_fmt!(lang, "cleangoodbyeoff"),
+    };
+    message.reply(reply).await?;
     Ok(())
 }
 
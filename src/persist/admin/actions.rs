@@ -35,6 +35,8 @@ pub enum FilterType {
     Glob,
     #[sea_orm(num_value = 3)]
     Script,
+    #[sea_orm(num_value = 4)]
+    Regex,
 }
 
 impl IntoActiveValue<ActionType> for ActionType {
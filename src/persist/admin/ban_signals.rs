@@ -0,0 +1,70 @@
+//! ORM type for anonymized ban signals published by federations. Signals only record who
+//! was banned and a broad category, the publishing federation is stored as a salted hash
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(
+    EnumIter,
+    DeriveActiveEnum,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Debug,
+)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+pub enum SignalCategory {
+    #[sea_orm(num_value = 1)]
+    Spam,
+    #[sea_orm(num_value = 2)]
+    Scam,
+    #[sea_orm(num_value = 3)]
+    Raid,
+    #[sea_orm(num_value = 4)]
+    Nsfw,
+    #[sea_orm(num_value = 5)]
+    Other,
+}
+
+impl SignalCategory {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Spam => "spam",
+            Self::Scam => "scam",
+            Self::Raid => "raid",
+            Self::Nsfw => "nsfw",
+            Self::Other => "other",
+        }
+    }
+
+    /// Pick a category from a #tag in an fban reason, defaulting to Other
+    pub fn from_reason(reason: Option<&str>) -> Self {
+        let reason = reason.unwrap_or("").to_lowercase();
+        [Self::Spam, Self::Scam, Self::Raid, Self::Nsfw]
+            .into_iter()
+            .find(|v| reason.contains(&format!("#{}", v.get_name())))
+            .unwrap_or(Self::Other)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "ban_signals")]
+pub struct Model {
+    /// salted hash of the publishing federation's id
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub source: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub user: i64,
+    pub category: SignalCategory,
+    pub created: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -12,6 +12,8 @@ pub enum CaptchaType {
     Button,
     #[sea_orm(num_value = 2)]
     Text,
+    #[sea_orm(num_value = 3)]
+    Math,
 }
 
 impl CaptchaType {
@@ -19,6 +21,7 @@ impl CaptchaType {
         match text {
             "button" => Ok(CaptchaType::Button),
             "text" => Ok(CaptchaType::Text),
+            "math" => Ok(CaptchaType::Math),
             _ => Err(BotError::speak("Invalid button type", chat, Some(reply))),
         }
     }
@@ -27,6 +30,7 @@ impl CaptchaType {
         match self {
             Self::Button => "Button",
             Self::Text => "Text",
+            Self::Math => "Math",
         }
     }
 }
@@ -40,6 +44,9 @@ pub struct Model {
     pub captcha_type: CaptchaType,
     pub kick_time: Option<i64>,
     pub captcha_text: Option<String>,
+    /// keep users who time out muted instead of kicking them
+    #[sea_orm(default = false)]
+    pub timeout_mute: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
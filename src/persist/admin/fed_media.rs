@@ -0,0 +1,22 @@
+//! ORM type for media blocked across a federation, matched either by a file's
+//! file_unique_id or by a perceptual hash of the image
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "fed_media")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub fed_id: Uuid,
+    /// a file_unique_id, or phash: followed by a perceptual hash in hex
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub fingerprint: String,
+    /// fedadmin who blocked the media
+    pub added_by: i64,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,20 @@
+//! ORM type for a federation's opt-in to the shared pool of ban signals
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+#[sea_orm(table_name = "fed_sharing")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub fed_id: Uuid,
+    /// fbans in this federation are published as signals
+    pub publish: bool,
+    /// admins of this federation can look up risk scores
+    pub consume: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -8,6 +8,8 @@ pub struct Model {
     pub user: i64,
     pub id: Uuid,
     pub reason: Option<String>,
+    /// name of the remote ban list this gban was synced from, None if it was issued here
+    pub source: Option<String>,
 }
 
 impl Model {
@@ -15,6 +17,7 @@ impl Model {
         Model {
             id: Uuid::new_v4(),
             reason: None,
+            source: None,
             user,
         }
     }
@@ -1,8 +1,11 @@
 pub mod actions;
 pub mod approvals;
 pub mod authorized;
+pub mod ban_signals;
 pub mod captchastate;
 pub mod fbans;
+pub mod fed_media;
+pub mod fed_sharing;
 pub mod fedadmin;
 pub mod federations;
 pub mod gbans;
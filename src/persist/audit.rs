@@ -0,0 +1,101 @@
+//! Audit log of admin command invocations. Every command that passes an admin permission
+//! check is recorded here along with who issued it and who it targeted. Unlike the log
+//! channel this is kept in the database, so history survives channel cleanup.
+
+use crate::persist::core::audit_log;
+use crate::statics::{CONFIG, DB};
+use crate::tg::command::{Cmd, Context, EntityArg};
+use crate::tg::user::get_user_username;
+use crate::util::error::Result;
+use chrono::{DateTime, Duration, Utc};
+use sea_orm::{
+    ActiveValue::{NotSet, Set},
+    ColumnTrait, EntityTrait, PaginatorTrait, QueryFilter, QueryOrder,
+};
+
+/// Try to find the user targeted by a command, using the same rules as action_user:
+/// the sender of a replied message, a mention, or a numeric user id
+async fn get_target(cmd: &Cmd<'_>) -> Result<Option<i64>> {
+    if let Some(user) = cmd
+        .message
+        .get_reply_to_message()
+        .and_then(|v| v.get_from())
+    {
+        return Ok(Some(user.get_id()));
+    }
+    let target = match cmd.entities.front() {
+        Some(EntityArg::Mention(name)) => get_user_username(name).await?.map(|u| u.get_id()),
+        Some(EntityArg::TextMention(user)) => Some(user.get_id()),
+        _ => cmd
+            .args
+            .args
+            .first()
+            .and_then(|v| str::parse(v.get_text()).ok()),
+    };
+    Ok(target)
+}
+
+/// Record a command invocation in the audit log. Does nothing if the update is not a command
+pub async fn record_command(ctx: &Context) -> Result<()> {
+    let Some(cmd) = ctx.cmd() else {
+        return Ok(());
+    };
+    let Some(issuer) = cmd.message.get_from() else {
+        return Ok(());
+    };
+    let model = audit_log::ActiveModel {
+        id: NotSet,
+        chat: Set(cmd.message.get_chat().get_id()),
+        issuer: Set(issuer.get_id()),
+        command: Set(cmd.cmd.to_owned()),
+        target: Set(get_target(cmd).await?),
+        created: Set(Utc::now()),
+    };
+    audit_log::Entity::insert(model).exec(*DB).await?;
+    Ok(())
+}
+
+/// Get a single page of audit entries for a chat, newest first, along with the total
+/// number of pages
+pub async fn get_audit_page(chat: i64, page: u64) -> Result<(Vec<audit_log::Model>, u64)> {
+    let paginator = audit_log::Entity::find()
+        .filter(audit_log::Column::Chat.eq(chat))
+        .order_by_desc(audit_log::Column::Created)
+        .paginate(*DB, CONFIG.audit.page_size);
+    let pages = paginator.num_pages().await?;
+    let entries = paginator.fetch_page(page).await?;
+    Ok((entries, pages))
+}
+
+/// Count the audit entries recorded for a chat since a point in time
+pub async fn count_since(chat: i64, since: DateTime<Utc>) -> Result<u64> {
+    let count = audit_log::Entity::find()
+        .filter(audit_log::Column::Chat.eq(chat))
+        .filter(audit_log::Column::Created.gte(since))
+        .count(*DB)
+        .await?;
+    Ok(count)
+}
+
+/// Delete audit entries older than the configured retention period
+pub async fn prune() -> Result<()> {
+    let cutoff = Utc::now() - Duration::try_days(CONFIG.audit.retention_days).unwrap_or_default();
+    let res = audit_log::Entity::delete_many()
+        .filter(audit_log::Column::Created.lt(cutoff))
+        .exec(*DB)
+        .await?;
+    log::info!("pruned {} audit log entries", res.rows_affected);
+    Ok(())
+}
+
+/// Periodically prune old audit entries. Runs forever
+pub async fn run_retention() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        if let Err(err) = prune().await {
+            log::warn!("failed to prune audit log: {}", err);
+            err.record_stats();
+        }
+    }
+}
@@ -0,0 +1,20 @@
+//! ORM type for relay bots bridging another chat service into a chat
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "bridge_bots")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub chat: i64,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub bot: i64,
+    /// regex with a "name" group matching the real author at the start of relayed messages
+    pub pattern: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,3 +1,4 @@
+use chrono::Utc;
 use sea_orm::entity::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -9,6 +10,9 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub user_id: i64,
     pub banned_by_me: bool,
+    /// when the user was first seen in or last joined the chat, None for members
+    /// recorded before join times were tracked
+    pub joined_at: Option<chrono::DateTime<Utc>>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -0,0 +1,22 @@
+//! ORM type for per-chat aliases of commands, so /alias warnban ban makes /warnban work
+//! like /ban
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "command_aliases")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub chat: i64,
+    /// lowercase name of the alias, without a prefix
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub alias: String,
+    /// name of the command the alias runs
+    pub command: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -38,6 +38,46 @@ pub struct Model {
     pub warn_time: Option<i64>,
     pub action_type: ActionType,
     pub federation: Option<Uuid>,
+    pub log_channel: Option<i64>,
+    #[sea_orm(default = 10)]
+    pub greet_flood_limit: i32,
+    pub mute_time: Option<i64>,
+    pub ban_time: Option<i64>,
+    /// new members stay muted until they agree to the rules from the welcome message
+    #[sea_orm(default = false)]
+    pub rules_gate: bool,
+    /// edited messages go through locks and blocklists like new messages
+    #[sea_orm(default = true)]
+    pub moderate_edits: bool,
+    /// seconds before responses to admin commands, help, and errors are deleted
+    pub response_ttl: Option<i64>,
+    /// name the bot answers to in this chat, with trigger words mapped to commands
+    pub nickname: Option<Json>,
+    /// commands per minute accepted from the whole chat, None uses the config default
+    pub ratelimit_chat: Option<i32>,
+    /// commands per minute accepted from each user in the chat, None uses the config default
+    pub ratelimit_user: Option<i32>,
+    /// for private chats, the group commands are currently sent to, see tg::connection
+    pub connected_chat: Option<i64>,
+    /// the default chat template was applied, see tg::template
+    #[sea_orm(default = false)]
+    pub template_applied: bool,
+    /// delete the previous welcome message when a new one is sent
+    #[sea_orm(default = false)]
+    pub clean_welcome: bool,
+    /// delete the previous goodbye message when a new one is sent
+    #[sea_orm(default = false)]
+    pub clean_goodbye: bool,
+    /// send notes in dm instead of the chat they were requested in
+    #[sea_orm(default = false)]
+    pub private_notes: bool,
+    /// seconds without a new warn after which a user's warns reset, see /warnmode decay
+    pub warn_decay: Option<i64>,
+    /// ban globally banned users in this chat, see /gbanstat
+    #[sea_orm(default = true)]
+    pub enforce_gbans: bool,
+    /// admins who don't want to be pinged by /report in this chat, see /reports
+    pub report_optouts: Option<Json>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -74,7 +114,7 @@ impl Related<crate::persist::admin::fbans::Entity> for Entity {
 
 impl Model {
     pub async fn from_chat(chat: &Chat) -> crate::util::error::Result<ActiveModel> {
-        let chat = TG.client.get_chat(chat.get_id()).await?;
+        let chat = TG.client().get_chat(chat.get_id()).await?;
         let def = &ChatPermissionsBuilder::new().build();
         let permissions = if is_dm_info(&chat) {
             def
@@ -99,6 +139,24 @@ impl Model {
             can_send_poll: Set(permissions.get_can_send_polls().unwrap_or(true)),
             can_send_other: Set(permissions.get_can_send_other_messages().unwrap_or(true)),
             federation: NotSet,
+            log_channel: NotSet,
+            greet_flood_limit: NotSet,
+            mute_time: NotSet,
+            ban_time: NotSet,
+            rules_gate: NotSet,
+            moderate_edits: NotSet,
+            response_ttl: NotSet,
+            nickname: NotSet,
+            ratelimit_chat: NotSet,
+            ratelimit_user: NotSet,
+            connected_chat: NotSet,
+            template_applied: NotSet,
+            clean_welcome: NotSet,
+            clean_goodbye: NotSet,
+            private_notes: NotSet,
+            warn_decay: NotSet,
+            enforce_gbans: NotSet,
+            report_optouts: NotSet,
         };
         Ok(res)
     }
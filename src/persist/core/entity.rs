@@ -1,12 +1,12 @@
 use botapi::gen_types::MessageEntity;
 use futures::{stream, StreamExt, TryStreamExt};
-use sea_orm::{entity::prelude::*, ActiveValue, IntoActiveModel};
+use sea_orm::{entity::prelude::*, ActiveValue, IntoActiveModel, QueryOrder};
 use sea_query::OnConflict;
 use serde::{Deserialize, Serialize};
 
-use crate::tg::button::InlineKeyboardBuilder;
+use crate::tg::{button::InlineKeyboardBuilder, markdown::get_markup_for_buttons};
 
-use super::{button, messageentity};
+use super::{button, messageentity, users};
 #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
 #[sea_orm(table_name = "entitylist")]
 pub struct Model {
@@ -142,4 +142,30 @@ where
     }
 }
 
+/// Get the entities and buttons stored under an entity id, for tables that store their
+/// formatting by entity id without a join query of their own
+pub async fn get<T>(
+    conn: &T,
+    entity_id: i64,
+) -> crate::util::error::Result<(Vec<MessageEntity>, Option<InlineKeyboardBuilder>)>
+where
+    T: ConnectionTrait,
+{
+    let entities = messageentity::Entity::find()
+        .filter(messageentity::Column::OwnerId.eq(entity_id))
+        .find_also_related(users::Entity)
+        .all(conn)
+        .await?
+        .into_iter()
+        .map(|(entity, user)| entity.to_entity(user))
+        .collect();
+    let buttons = button::Entity::find()
+        .filter(button::Column::OwnerId.eq(entity_id))
+        .order_by_asc(button::Column::PosX)
+        .order_by_asc(button::Column::PosY)
+        .all(conn)
+        .await?;
+    Ok((entities, get_markup_for_buttons(buttons)))
+}
+
 impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,21 @@
+//! ORM type for per-chat overrides of the bot owner's list of known moderation bots
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "ignored_bots")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub chat: i64,
+    /// lowercase username of the bot, without the @
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub bot: String,
+    /// false if a bot from the default list should be moderated in this chat
+    pub ignored: bool,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
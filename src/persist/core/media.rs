@@ -256,7 +256,7 @@ where
 
     pub async fn edit_media_reply_chatuser(mut self, current_message: &Message) -> Result<()> {
         if current_message.get_text().is_some() != (self.media_type == MediaType::Text) {
-            TG.client
+            TG.client()
                 .build_delete_message(
                     current_message.get_chat().get_id(),
                     current_message.get_message_id(),
@@ -311,7 +311,7 @@ where
 
             let input_media = match self.media_type {
                 MediaType::Sticker => {
-                    TG.client
+                    TG.client()
                         .build_delete_message(chat, current_message.get_message_id())
                         .build()
                         .await?;
@@ -355,7 +355,7 @@ where
                     .build(),
                 )),
                 MediaType::Text => {
-                    TG.client
+                    TG.client()
                         .build_edit_message_text(&text)
                         .message_id(current_message.get_message_id())
                         .chat_id(current_message.get_chat().get_id())
@@ -377,7 +377,7 @@ where
             };
 
             if let Some(input_media) = input_media {
-                TG.client
+                TG.client()
                     .build_edit_message_media(&input_media)
                     .media(&input_media)
                     .message_id(current_message.get_message_id())
@@ -386,7 +386,7 @@ where
                     .build()
                     .await?;
 
-                // TG.client
+                // TG.client()
                 //     .build_edit_message_caption()
                 //     .message_id(current_message.get_message_id())
                 //     .chat_id(current_message.get_chat().get_id())
@@ -400,7 +400,9 @@ where
         Ok(())
     }
 
-    pub async fn send_media(mut self) -> Result<()> {
+    /// Send the media to the chat of the update, returning the sent message unless the
+    /// chat is ignored
+    pub async fn send_media(mut self) -> Result<Option<Message>> {
         self.note_button().await?;
         if let Some(chat) = self.context.chat() {
             let chat = chat.get_id();
@@ -409,7 +411,7 @@ where
                 .ok_or_else(|| BotError::Generic("callback not set".to_owned()))?;
             let mut buttons = self.buttons.unwrap_or_default();
             if should_ignore_chat(chat).await? {
-                return Ok(());
+                return Ok(None);
             }
 
             let text = self.text.unwrap_or_else(|| "".to_owned());
@@ -444,7 +446,7 @@ where
 
             let buttons = EReplyMarkup::InlineKeyboardMarkup(buttons.build());
 
-            match self.media_type {
+            let sent = match self.media_type {
                 MediaType::Sticker => {
                     TG.client()
                         .build_send_sticker(
@@ -503,7 +505,7 @@ where
                         .await
                 }
                 MediaType::Audio => {
-                    TG.client
+                    TG.client()
                         .build_send_audio(
                             chat,
                             FileData::String(
@@ -531,8 +533,9 @@ where
                         .await
                 }
             }?;
+            return Ok(Some(sent));
         }
-        Ok(())
+        Ok(None)
     }
 
     pub async fn send_media_reply(mut self) -> Result<()> {
@@ -651,7 +654,7 @@ where
             }
 
             MediaType::Audio => {
-                TG.client
+                TG.client()
                     .build_send_audio(
                         chat,
                         FileData::String(
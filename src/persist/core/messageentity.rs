@@ -66,6 +66,8 @@ impl EntityWithUser {
                     last_name: self.last_name,
                     username: self.username,
                     is_bot,
+                    optout_mentions: false,
+                    optout_stats: false,
                 })
             } else {
                 None
@@ -0,0 +1,44 @@
+//! ORM type for tracking media mirrored to object storage along with when it expires
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Why a piece of media was mirrored. Each category has its own retention period
+#[derive(EnumIter, DeriveActiveEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[sea_orm(rs_type = "i32", db_type = "Integer")]
+pub enum MirrorCategory {
+    #[sea_orm(num_value = 1)]
+    Evidence,
+    #[sea_orm(num_value = 2)]
+    Backup,
+    #[sea_orm(num_value = 3)]
+    Archive,
+}
+
+impl MirrorCategory {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Evidence => "evidence",
+            Self::Backup => "backup",
+            Self::Archive => "archive",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "mirrored_media")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub key: String,
+    pub chat: i64,
+    pub category: MirrorCategory,
+    pub file_unique_id: Option<String>,
+    pub size: i64,
+    pub created: DateTimeUtc,
+    pub expires: Option<DateTimeUtc>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,17 +1,30 @@
+pub mod audit_log;
+pub mod bridge_bots;
 pub mod button;
 pub mod chat_members;
 pub mod chat_type;
+pub mod chat_webhooks;
+pub mod command_aliases;
 pub mod conversation_states;
 pub mod conversation_transitions;
 pub mod conversations;
 pub mod dialogs;
 pub mod entity;
+pub mod flair;
+pub mod ignored_bots;
 pub mod media;
 pub mod messageentity;
+pub mod mirrored_media;
 pub mod module_schemas;
 pub mod notes;
+pub mod payments;
+pub mod pending_deletes;
 pub mod prelude;
+pub mod role_commands;
+pub mod roles;
 pub mod rules;
+pub mod rules_acks;
+pub mod string_overrides;
 pub mod taint;
 pub mod users;
 pub mod welcomes;
@@ -0,0 +1,30 @@
+//! ORM type for the audit log of payments received by the bot
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "payments")]
+pub struct Model {
+    #[sea_orm(primary_key, autoincrement = true)]
+    pub id: i64,
+    pub user: i64,
+    /// chat the invoice was sent to
+    pub chat: i64,
+    /// product kind the payment was dispatched to
+    pub kind: String,
+    pub data: Json,
+    pub currency: String,
+    /// total in the smallest units of the currency, stars for XTR
+    pub amount: i64,
+    /// telegram's id for the charge, needed for refunds
+    #[sea_orm(unique)]
+    pub charge_id: String,
+    pub refunded: bool,
+    pub created: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
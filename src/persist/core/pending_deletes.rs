@@ -0,0 +1,21 @@
+//! ORM type for messages waiting to be deleted after a delay. Rows outlive restarts and
+//! redis being flushed, so the deletion still happens once the bot is back
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "pending_deletes")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub chat: i64,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub message_id: i64,
+    /// when the message should be deleted
+    pub due: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
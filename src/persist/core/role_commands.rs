@@ -0,0 +1,23 @@
+//! ORM type for commands granted to a chat-local role. Members of the role can use the
+//! command as if they had the admin rights it normally needs
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "role_commands")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub chat: i64,
+    /// lowercase name of the role
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub role: String,
+    /// command name without a prefix
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub command: String,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
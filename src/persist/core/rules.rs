@@ -1,12 +1,22 @@
-//! ORM type for storing user information. Since redis is used for this ephemerally
-//! in most cases this is very simple
+//! ORM type for chat rules. Formatting and buttons are stored in the entity tables the
+//! same way as notes, linked by entity_id
 
-use sea_orm::entity::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+use sea_orm::{entity::prelude::*, FromQueryResult, QueryOrder, QuerySelect};
+use sea_query::{IntoCondition, JoinType};
 use serde::{Deserialize, Serialize};
 
-use super::media::MediaType;
+use crate::statics::DB;
+
+use super::{
+    button, entity,
+    media::MediaType,
+    messageentity::{self, DbMarkupType, EntityWithUser},
+    users,
+};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, DeriveEntityModel)]
 #[sea_orm(table_name = "rules")]
 pub struct Model {
     #[sea_orm(primary_key)]
@@ -19,9 +29,199 @@ pub struct Model {
     pub private: bool,
     #[sea_orm(column_type = "Text", default_value = "Rules")]
     pub button_name: String,
+    pub entity_id: Option<i64>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-pub enum Relation {}
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "crate::persist::core::entity::Entity",
+        from = "Column::EntityId",
+        to = "crate::persist::core::entity::Column::Id"
+    )]
+    Entities,
+}
+
+impl Related<crate::persist::core::entity::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Entities.def()
+    }
+}
 
 impl ActiveModelBehavior for ActiveModel {}
+
+#[derive(FromQueryResult)]
+struct RulesWithEntities {
+    // rules fields
+    pub chat_id: Option<i64>,
+    pub text: Option<String>,
+    pub media_id: Option<String>,
+    pub media_type: Option<MediaType>,
+    pub private: Option<bool>,
+    pub button_name: Option<String>,
+    pub entity_id: Option<i64>,
+
+    // button fields
+    pub button_text: Option<String>,
+    pub callback_data: Option<String>,
+    pub button_url: Option<String>,
+    pub pos_x: Option<i32>,
+    pub pos_y: Option<i32>,
+    pub raw_text: Option<String>,
+
+    // entity fields
+    pub tg_type: Option<DbMarkupType>,
+    pub offset: Option<i64>,
+    pub length: Option<i64>,
+    pub url: Option<String>,
+    pub user: Option<i64>,
+    pub language: Option<String>,
+    pub emoji_id: Option<String>,
+
+    // user fields
+    pub user_id: Option<i64>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub username: Option<String>,
+    pub is_bot: Option<bool>,
+}
+
+impl RulesWithEntities {
+    fn get(self) -> (Option<Model>, Option<button::Model>, Option<EntityWithUser>) {
+        let button = if let (Some(button_text), Some(owner_id), Some(pos_x), Some(pos_y)) =
+            (self.button_text, self.entity_id, self.pos_x, self.pos_y)
+        {
+            Some(button::Model {
+                button_text,
+                owner_id: Some(owner_id),
+                callback_data: self.callback_data,
+                button_url: self.button_url,
+                pos_x,
+                pos_y,
+                raw_text: self.raw_text,
+            })
+        } else {
+            None
+        };
+
+        let rules = if let (Some(chat_id), Some(media_type), Some(private), Some(button_name)) = (
+            self.chat_id,
+            self.media_type,
+            self.private,
+            self.button_name,
+        ) {
+            Some(Model {
+                chat_id,
+                text: self.text,
+                media_id: self.media_id,
+                media_type,
+                private,
+                button_name,
+                entity_id: self.entity_id,
+            })
+        } else {
+            None
+        };
+
+        let entity = if let (Some(tg_type), Some(offset), Some(length), Some(owner_id)) =
+            (self.tg_type, self.offset, self.length, self.entity_id)
+        {
+            Some(EntityWithUser {
+                tg_type,
+                offset,
+                length,
+                url: self.url,
+                language: self.language,
+                emoji_id: self.emoji_id,
+                user: self.user,
+                owner_id,
+                user_id: self.user_id,
+                first_name: self.first_name,
+                last_name: self.last_name,
+                username: self.username,
+                is_bot: self.is_bot,
+            })
+        } else {
+            None
+        };
+
+        (rules, button, entity)
+    }
+}
+
+pub type RulesMap = HashMap<Model, (HashSet<EntityWithUser>, HashSet<button::Model>)>;
+
+/// Get rules along with their entities and buttons
+pub async fn get_rules_join<F>(filter: F) -> crate::util::error::Result<RulesMap>
+where
+    F: IntoCondition,
+{
+    let res = Entity::find()
+        .select_only()
+        .columns([
+            Column::ChatId,
+            Column::Text,
+            Column::MediaId,
+            Column::MediaType,
+            Column::Private,
+            Column::ButtonName,
+            Column::EntityId,
+        ])
+        .columns([
+            messageentity::Column::TgType,
+            messageentity::Column::Offset,
+            messageentity::Column::Length,
+            messageentity::Column::Url,
+            messageentity::Column::User,
+            messageentity::Column::Language,
+            messageentity::Column::EmojiId,
+            messageentity::Column::OwnerId,
+        ])
+        .columns([
+            button::Column::ButtonText,
+            button::Column::CallbackData,
+            button::Column::ButtonUrl,
+            button::Column::PosX,
+            button::Column::PosY,
+            button::Column::RawText,
+        ])
+        .column_as(button::Column::OwnerId, "b_owner_id")
+        .columns([
+            users::Column::UserId,
+            users::Column::FirstName,
+            users::Column::LastName,
+            users::Column::Username,
+            users::Column::IsBot,
+        ])
+        .join(JoinType::LeftJoin, Relation::Entities.def())
+        .join(JoinType::LeftJoin, entity::Relation::EntitiesRev.def())
+        .join(JoinType::LeftJoin, entity::Relation::ButtonsRev.def())
+        .join(JoinType::LeftJoin, messageentity::Relation::Users.def())
+        .filter(filter)
+        .order_by_asc(button::Column::PosX)
+        .order_by_asc(button::Column::PosY)
+        .into_model::<RulesWithEntities>()
+        .all(*DB)
+        .await?;
+
+    let res = res.into_iter().map(|v| v.get()).fold(
+        RulesMap::new(),
+        |mut acc, (rules, button, entity)| {
+            if let Some(rules) = rules {
+                let (entitylist, buttonlist) = acc
+                    .entry(rules)
+                    .or_insert_with(|| (HashSet::new(), HashSet::new()));
+
+                if let Some(button) = button {
+                    buttonlist.insert(button);
+                }
+
+                if let Some(entity) = entity {
+                    entitylist.insert(entity);
+                }
+            }
+            acc
+        },
+    );
+    Ok(res)
+}
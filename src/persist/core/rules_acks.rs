@@ -0,0 +1,19 @@
+//! ORM type for recording when a user agreed to a chat's rules
+
+use sea_orm::entity::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, DeriveEntityModel)]
+#[sea_orm(table_name = "rules_acks")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub chat: i64,
+    #[sea_orm(primary_key)]
+    pub user: i64,
+    pub acknowledged: DateTimeUtc,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -14,6 +14,12 @@ pub struct Model {
     pub last_name: Option<String>,
     pub username: Option<String>,
     pub is_bot: bool,
+    /// the user doesn't want to be pinged by tagall and other mass mentions
+    #[serde(default)]
+    pub optout_mentions: bool,
+    /// the user doesn't want their activity counted in digests or archived
+    #[serde(default)]
+    pub optout_stats: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -43,6 +49,8 @@ impl Model {
             last_name: value.get_last_name().map(|v| v.to_owned()),
             username: value.get_username().map(|v| v.to_owned()),
             is_bot: value.get_is_bot(),
+            optout_mentions: false,
+            optout_stats: false,
         }
     }
 }
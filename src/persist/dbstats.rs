@@ -0,0 +1,108 @@
+//! Row counts and sizes of every database table, sampled periodically into prometheus
+//! gauges so runaway tables like warns or the audit log are noticed before they fill the
+//! disk. Row counts are postgres' estimates, since counting large tables is slow
+
+use std::time::Duration;
+
+use dashmap::DashMap;
+use sea_orm::{ConnectionTrait, DbBackend, Statement};
+
+use crate::persist::metrics::{TABLE_BYTES, TABLE_ROWS};
+use crate::statics::{BotLocal, DB};
+use crate::util::error::Result;
+
+/// Time between samples
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Size of a single table
+#[derive(Clone, Debug)]
+pub struct TableStats {
+    pub name: String,
+    /// estimated number of live rows
+    pub rows: i64,
+    /// bytes used by the table, its indexes, and toast data
+    pub bytes: i64,
+}
+
+/// the most recent sample of each table, for showing growth between samples
+static LAST_SAMPLE: BotLocal<DashMap<String, TableStats>> = BotLocal::new(DashMap::new);
+
+/// Get the size of every table, largest first
+pub async fn get_table_stats() -> Result<Vec<TableStats>> {
+    let rows = DB
+        .query_all(Statement::from_string(
+            DbBackend::Postgres,
+            "
+            SELECT relname::text AS name, n_live_tup AS rows,
+                pg_total_relation_size(relid) AS bytes
+            FROM pg_stat_user_tables ORDER BY bytes DESC;
+            ",
+        ))
+        .await?;
+    let mut stats = Vec::with_capacity(rows.len());
+    for row in rows {
+        stats.push(TableStats {
+            name: row.try_get("", "name")?,
+            rows: row.try_get("", "rows")?,
+            bytes: row.try_get("", "bytes")?,
+        });
+    }
+    Ok(stats)
+}
+
+/// Get the last periodic sample of a table
+pub fn last_sample(table: &str) -> Option<TableStats> {
+    LAST_SAMPLE.get(table).map(|v| v.value().clone())
+}
+
+/// Format a number of bytes with the largest unit that keeps it above one
+pub fn fmt_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes.unsigned_abs() as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let sign = if bytes < 0 { "-" } else { "" };
+    if unit == 0 {
+        format!("{}{} {}", sign, size, UNITS[unit])
+    } else {
+        format!("{}{:.1} {}", sign, size, UNITS[unit])
+    }
+}
+
+async fn sample() -> Result<()> {
+    for table in get_table_stats().await? {
+        TABLE_ROWS.with_label_values(&[&table.name]).set(table.rows);
+        TABLE_BYTES
+            .with_label_values(&[&table.name])
+            .set(table.bytes);
+        LAST_SAMPLE.insert(table.name.clone(), table);
+    }
+    Ok(())
+}
+
+/// Periodically sample table sizes. Runs forever
+pub async fn run_table_stats() {
+    let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(err) = sample().await {
+            log::warn!("failed to sample table sizes: {}", err);
+            err.record_stats();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bytes_format() {
+        assert_eq!(fmt_bytes(512), "512 B");
+        assert_eq!(fmt_bytes(1536), "1.5 KB");
+        assert_eq!(fmt_bytes(-3 * 1024 * 1024), "-3.0 MB");
+    }
+}
@@ -1,13 +1,184 @@
 //! Counters and functions for collecting usage metrics and error reporting
 //! mainly used with prometheus
 
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use dashmap::DashMap;
 use lazy_static::lazy_static;
-use prometheus::{register_int_counter, IntCounter};
+use prometheus::{
+    register_counter_vec, register_gauge_vec, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec, Counter, CounterVec, GaugeVec, HistogramVec,
+    IntCounter, IntCounterVec, IntGaugeVec,
+};
+
+/// Number of latency samples retained for percentile calculations
+const LATENCY_SAMPLES: usize = 1024;
+
+/// Number of latency samples retained per command, smaller since there is one per command
+const COMMAND_LATENCY_SAMPLES: usize = 128;
+
 //counters
 lazy_static! {
     /// map of counters for telegram error codes, lazy initialized, one per http error code
     pub static ref ERROR_CODES_MAP: DashMap<i64, IntCounter> = DashMap::new();
+
+    /// time the bot was started, used for reporting uptime
+    pub static ref START_TIME: Instant = Instant::now();
+
+    /// total number of updates received from telegram
+    pub static ref UPDATES_RECEIVED: IntCounter =
+        register_int_counter!("updates_received", "Updates received from telegram").unwrap();
+
+    /// total number of errors returned from update handlers
+    pub static ref HANDLER_ERRORS: IntCounter =
+        register_int_counter!("handler_errors", "Errors returned by update handlers").unwrap();
+
+    /// number of cached queries served from redis
+    pub static ref CACHE_HITS: IntCounter =
+        register_int_counter!("cache_hits", "Cached queries served from redis").unwrap();
+
+    /// number of cached queries that fell through to the database
+    pub static ref CACHE_MISSES: IntCounter =
+        register_int_counter!("cache_misses", "Cached queries that missed redis").unwrap();
+
+    /// command invocations labeled by module and command name
+    pub static ref COMMAND_USAGE: IntCounterVec = register_int_counter_vec!(
+        "command_usage",
+        "Command invocations by module",
+        &["module", "command"]
+    )
+    .unwrap();
+
+    /// time spent in the handler of the module owning a command
+    pub static ref COMMAND_LATENCY: HistogramVec = register_histogram_vec!(
+        "command_latency_seconds",
+        "Time spent handling commands",
+        &["module", "command"]
+    )
+    .unwrap();
+
+    /// recent latencies for each command, keyed by module and command name
+    pub static ref COMMAND_SAMPLES: DashMap<(String, String), LatencySamples> = DashMap::new();
+
+    /// time spent polling each module's update handler
+    pub static ref MODULE_CPU: CounterVec = register_counter_vec!(
+        "module_cpu_seconds",
+        "Time spent polling module update handlers",
+        &["module"]
+    )
+    .unwrap();
+
+    /// database queries made while a module's update handler was running
+    pub static ref MODULE_DB_QUERIES: IntCounterVec = register_int_counter_vec!(
+        "module_db_queries",
+        "Database queries made by module update handlers",
+        &["module"]
+    )
+    .unwrap();
+
+    /// redis round trips made while a module's update handler was running
+    pub static ref MODULE_REDIS_QUERIES: IntCounterVec = register_int_counter_vec!(
+        "module_redis_queries",
+        "Redis queries and pipelines run by module update handlers",
+        &["module"]
+    )
+    .unwrap();
+
+    /// messages sent while a module's update handler was running
+    pub static ref MODULE_MESSAGES: IntCounterVec = register_int_counter_vec!(
+        "module_messages_sent",
+        "Messages sent by module update handlers",
+        &["module"]
+    )
+    .unwrap();
+
+    /// resource usage for each module, keyed by module name
+    pub static ref MODULE_USAGE: DashMap<String, Arc<ModuleUsage>> = DashMap::new();
+
+    /// captcha outcomes across all chats, labeled by event
+    pub static ref CAPTCHA_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "captcha_events",
+        "Captchas presented, solved, failed, or timed out",
+        &["event"]
+    )
+    .unwrap();
+
+    /// federation cache entries compared against the database, labeled by cache
+    pub static ref FED_CACHE_CHECKED: IntCounterVec = register_int_counter_vec!(
+        "fed_cache_checked",
+        "Federation cache entries validated against the database",
+        &["cache"]
+    )
+    .unwrap();
+
+    /// federation cache entries that disagreed with the database and were dropped
+    pub static ref FED_CACHE_DRIFT: IntCounterVec = register_int_counter_vec!(
+        "fed_cache_drift",
+        "Federation cache entries found inconsistent with the database",
+        &["cache"]
+    )
+    .unwrap();
+
+    /// fraction of checked entries that were inconsistent in the most recent check
+    pub static ref FED_CACHE_DRIFT_RATE: GaugeVec = register_gauge_vec!(
+        "fed_cache_drift_rate",
+        "Inconsistent fraction of federation cache entries in the last check",
+        &["cache"]
+    )
+    .unwrap();
+
+    /// estimated live rows in each database table, from the last sample
+    pub static ref TABLE_ROWS: IntGaugeVec = register_int_gauge_vec!(
+        "db_table_rows",
+        "Estimated rows in each database table",
+        &["table"]
+    )
+    .unwrap();
+
+    /// bytes used by each database table including indexes, from the last sample
+    pub static ref TABLE_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "db_table_bytes",
+        "Bytes used by each database table and its indexes",
+        &["table"]
+    )
+    .unwrap();
+
+    /// whether each supervised background task is currently running
+    pub static ref TASK_RUNNING: IntGaugeVec = register_int_gauge_vec!(
+        "task_running",
+        "Whether each background task is running",
+        &["task"]
+    )
+    .unwrap();
+
+    /// background task runs that returned an error or panicked
+    pub static ref TASK_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "task_failures",
+        "Failed runs of each background task",
+        &["task"]
+    )
+    .unwrap();
+
+    /// times each background task was restarted by its supervisor
+    pub static ref TASK_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "task_restarts",
+        "Restarts of each background task",
+        &["task"]
+    )
+    .unwrap();
+
+    /// recent database query latencies
+    pub static ref DB_LATENCY: LatencySamples = LatencySamples::new(LATENCY_SAMPLES);
+
+    /// recent redis query latencies
+    pub static ref REDIS_LATENCY: LatencySamples = LatencySamples::new(LATENCY_SAMPLES);
+
+    /// updates received per minute
+    pub static ref UPDATE_RATE: MinuteRate = MinuteRate::default();
 }
 
 /// register a http error code returned from telegra, lazy-initializing a prometheus counter
@@ -18,3 +189,350 @@ pub fn count_error_code(err: i64) {
     });
     counter.value().inc();
 }
+
+/// record a single update received from telegram
+pub fn count_update() {
+    UPDATES_RECEIVED.inc();
+    UPDATE_RATE.tick();
+}
+
+/// record a command invocation for a module
+pub fn count_command(module: &str, command: &str) {
+    COMMAND_USAGE.with_label_values(&[module, command]).inc();
+}
+
+/// get the number of times a command from a module was invoked since startup
+pub fn get_command_count(module: &str, command: &str) -> u64 {
+    COMMAND_USAGE.with_label_values(&[module, command]).get()
+}
+
+/// record how long the module owning a command took to handle it
+pub fn record_command_latency(module: &str, command: &str, latency: Duration) {
+    COMMAND_LATENCY
+        .with_label_values(&[module, command])
+        .observe(latency.as_secs_f64());
+    COMMAND_SAMPLES
+        .entry((module.to_owned(), command.to_owned()))
+        .or_insert_with(|| LatencySamples::new(COMMAND_LATENCY_SAMPLES))
+        .record(latency);
+}
+
+/// Get the commands with the highest p95 latency as (module, command, p95), slowest first
+pub fn slowest_commands(limit: usize) -> Vec<(String, String, Duration)> {
+    let mut commands = COMMAND_SAMPLES
+        .iter()
+        .filter_map(|v| {
+            let (module, command) = v.key();
+            v.value()
+                .percentile(95.0)
+                .map(|p95| (module.to_owned(), command.to_owned(), p95))
+        })
+        .collect::<Vec<(String, String, Duration)>>();
+    commands.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+    commands.truncate(limit);
+    commands
+}
+
+tokio::task_local! {
+    /// usage counters for the module whose update handler is running in this task
+    static CURRENT_MODULE: Arc<ModuleUsage>;
+}
+
+/// Resource usage attributed to a single module, both since startup for prometheus and
+/// over the last complete minute for the owner report
+pub struct ModuleUsage {
+    cpu: Counter,
+    db_queries: IntCounter,
+    redis_queries: IntCounter,
+    messages: IntCounter,
+    /// microseconds of polling time
+    cpu_rate: MinuteRate,
+    db_rate: MinuteRate,
+    redis_rate: MinuteRate,
+    messages_rate: MinuteRate,
+}
+
+/// Snapshot of a module's resource usage for reporting
+pub struct ModuleUsageReport {
+    pub module: String,
+    pub cpu_total: Duration,
+    pub cpu_minute: Duration,
+    pub db_total: u64,
+    pub db_minute: u64,
+    pub redis_total: u64,
+    pub redis_minute: u64,
+    pub messages_total: u64,
+    pub messages_minute: u64,
+}
+
+impl ModuleUsage {
+    fn new(module: &str) -> Self {
+        Self {
+            cpu: MODULE_CPU.with_label_values(&[module]),
+            db_queries: MODULE_DB_QUERIES.with_label_values(&[module]),
+            redis_queries: MODULE_REDIS_QUERIES.with_label_values(&[module]),
+            messages: MODULE_MESSAGES.with_label_values(&[module]),
+            cpu_rate: MinuteRate::default(),
+            db_rate: MinuteRate::default(),
+            redis_rate: MinuteRate::default(),
+            messages_rate: MinuteRate::default(),
+        }
+    }
+
+    fn record_cpu(&self, time: Duration) {
+        self.cpu.inc_by(time.as_secs_f64());
+        self.cpu_rate.add(time.as_micros() as u64);
+    }
+
+    fn report(&self, module: &str) -> ModuleUsageReport {
+        ModuleUsageReport {
+            module: module.to_owned(),
+            cpu_total: Duration::from_secs_f64(self.cpu.get()),
+            cpu_minute: Duration::from_micros(self.cpu_rate.per_minute()),
+            db_total: self.db_queries.get(),
+            db_minute: self.db_rate.per_minute(),
+            redis_total: self.redis_queries.get(),
+            redis_minute: self.redis_rate.per_minute(),
+            messages_total: self.messages.get(),
+            messages_minute: self.messages_rate.per_minute(),
+        }
+    }
+}
+
+/// Run a module's update handler, attributing the time spent polling it and any database
+/// queries, redis queries, or messages it makes to the module. Work the handler moves to
+/// a spawned task is not counted
+pub async fn meter_module<F: Future>(module: &str, handler: F) -> F::Output {
+    let usage = Arc::clone(
+        MODULE_USAGE
+            .entry(module.to_owned())
+            .or_insert_with(|| Arc::new(ModuleUsage::new(module)))
+            .value(),
+    );
+    let cpu = Arc::clone(&usage);
+    let mut handler = std::pin::pin!(handler);
+    CURRENT_MODULE
+        .scope(
+            usage,
+            futures::future::poll_fn(move |cx| {
+                let start = Instant::now();
+                let res = handler.as_mut().poll(cx);
+                cpu.record_cpu(start.elapsed());
+                res
+            }),
+        )
+        .await
+}
+
+/// record a database query against the module running in this task, if any
+pub fn count_module_db_query() {
+    let _ = CURRENT_MODULE.try_with(|v| {
+        v.db_queries.inc();
+        v.db_rate.tick();
+    });
+}
+
+/// record a redis query or pipeline against the module running in this task, if any
+pub fn count_module_redis_query() {
+    let _ = CURRENT_MODULE.try_with(|v| {
+        v.redis_queries.inc();
+        v.redis_rate.tick();
+    });
+}
+
+/// record a sent message against the module running in this task, if any
+pub fn count_module_message() {
+    let _ = CURRENT_MODULE.try_with(|v| {
+        v.messages.inc();
+        v.messages_rate.tick();
+    });
+}
+
+/// Get the resource usage of every module that has handled an update, busiest first by
+/// time spent in the last minute
+pub fn module_usage() -> Vec<ModuleUsageReport> {
+    let mut reports = MODULE_USAGE
+        .iter()
+        .map(|v| v.value().report(v.key()))
+        .collect::<Vec<ModuleUsageReport>>();
+    reports.sort_by(|a, b| {
+        b.cpu_minute
+            .cmp(&a.cpu_minute)
+            .then(b.cpu_total.cmp(&a.cpu_total))
+    });
+    reports
+}
+
+/// record the result of validating a sample of one of the federation caches
+pub fn record_fed_cache_check(cache: &str, checked: u64, drifted: u64) {
+    FED_CACHE_CHECKED
+        .with_label_values(&[cache])
+        .inc_by(checked);
+    FED_CACHE_DRIFT.with_label_values(&[cache]).inc_by(drifted);
+    if checked > 0 {
+        FED_CACHE_DRIFT_RATE
+            .with_label_values(&[cache])
+            .set(drifted as f64 / checked as f64);
+    }
+}
+
+/// Get the ratio of cache hits to total cached queries, None if no queries were made
+pub fn cache_hit_rate() -> Option<f64> {
+    let hits = CACHE_HITS.get();
+    let total = hits + CACHE_MISSES.get();
+    if total == 0 {
+        None
+    } else {
+        Some(hits as f64 / total as f64)
+    }
+}
+
+/// Get the ratio of handler errors to updates received, None if no updates were received
+pub fn handler_error_rate() -> Option<f64> {
+    let updates = UPDATES_RECEIVED.get();
+    if updates == 0 {
+        None
+    } else {
+        Some(HANDLER_ERRORS.get() as f64 / updates as f64)
+    }
+}
+
+/// Ring buffer of recent latency samples for calculating percentiles
+pub struct LatencySamples {
+    size: usize,
+    samples: Mutex<VecDeque<u64>>,
+}
+
+impl LatencySamples {
+    /// Construct a new sample buffer retaining at most `size` samples
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            samples: Mutex::new(VecDeque::with_capacity(size)),
+        }
+    }
+
+    /// record a single latency sample, dropping the oldest sample if full
+    pub fn record(&self, latency: Duration) {
+        if let Ok(mut samples) = self.samples.lock() {
+            if samples.len() >= self.size {
+                samples.pop_front();
+            }
+            samples.push_back(latency.as_micros() as u64);
+        }
+    }
+
+    /// get the given percentile (0-100) of the recorded samples
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let mut samples = self
+            .samples
+            .lock()
+            .ok()?
+            .iter()
+            .copied()
+            .collect::<Vec<u64>>();
+        nearest_rank(&mut samples, percentile).map(Duration::from_micros)
+    }
+}
+
+/// Nearest-rank percentile of a list of samples
+fn nearest_rank(samples: &mut [u64], percentile: f64) -> Option<u64> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let rank = ((percentile / 100.0) * samples.len() as f64).ceil() as usize;
+    let idx = rank.clamp(1, samples.len()) - 1;
+    Some(samples[idx])
+}
+
+/// Counter for events, or any other amount, over the last complete minute
+#[derive(Default)]
+pub struct MinuteRate {
+    minute: AtomicU64,
+    current: AtomicU64,
+    last: AtomicU64,
+}
+
+fn current_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs() / 60)
+        .unwrap_or(0)
+}
+
+impl MinuteRate {
+    /// record a single event
+    pub fn tick(&self) {
+        self.add(1);
+    }
+
+    /// record an amount, such as a number of events or a duration in some unit
+    pub fn add(&self, amount: u64) {
+        self.rotate(current_minute());
+        self.current.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// get the number of events in the last complete minute
+    pub fn per_minute(&self) -> u64 {
+        self.rotate(current_minute());
+        self.last.load(Ordering::Relaxed)
+    }
+
+    fn rotate(&self, now: u64) {
+        let minute = self.minute.load(Ordering::Relaxed);
+        if minute != now
+            && self
+                .minute
+                .compare_exchange(minute, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+        {
+            let count = self.current.swap(0, Ordering::Relaxed);
+            let last = if now == minute + 1 { count } else { 0 };
+            self.last.store(last, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentile_nearest_rank() {
+        let mut samples = (1..=100).rev().collect::<Vec<u64>>();
+        assert_eq!(nearest_rank(&mut samples, 50.0), Some(50));
+        assert_eq!(nearest_rank(&mut samples, 95.0), Some(95));
+        assert_eq!(nearest_rank(&mut samples, 100.0), Some(100));
+        assert_eq!(nearest_rank(&mut samples, 0.0), Some(1));
+        assert_eq!(nearest_rank(&mut [], 50.0), None);
+    }
+
+    #[tokio::test]
+    async fn module_usage_attribution() {
+        meter_module("metered", async {
+            count_module_db_query();
+            count_module_redis_query();
+            count_module_redis_query();
+            count_module_message();
+        })
+        .await;
+        count_module_db_query();
+        let usage = MODULE_USAGE.get("metered").unwrap().report("metered");
+        assert_eq!(usage.db_total, 1);
+        assert_eq!(usage.redis_total, 2);
+        assert_eq!(usage.messages_total, 1);
+    }
+
+    #[test]
+    fn minute_rate_rotates() {
+        let rate = MinuteRate::default();
+        rate.rotate(10);
+        rate.current.fetch_add(5, Ordering::Relaxed);
+        rate.rotate(11);
+        assert_eq!(rate.last.load(Ordering::Relaxed), 5);
+        rate.current.fetch_add(3, Ordering::Relaxed);
+        rate.rotate(20);
+        assert_eq!(rate.last.load(Ordering::Relaxed), 0);
+    }
+}
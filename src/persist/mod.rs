@@ -1,5 +1,9 @@
 pub mod admin;
+pub mod audit;
 pub mod core;
+pub mod dbstats;
 pub mod metrics;
 pub mod migrate;
+pub mod mirror;
 pub mod redis;
+pub mod user_data;
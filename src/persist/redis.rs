@@ -10,7 +10,8 @@
 //! which makes serializing keys with msgpack hard. This crate contains a workaround for this that
 
 use crate::{
-    statics::CONFIG,
+    persist::metrics::{count_module_redis_query, CACHE_HITS, CACHE_MISSES, REDIS_LATENCY},
+    statics::{spawn, BotLocal, Instance, CONFIG},
     util::{
         callback::{CacheCallback, CacheMissCallback},
         error::{BotError, Result},
@@ -21,21 +22,23 @@ use redis::aio::{ConnectionLike, MultiplexedConnection};
 use redis_test::MockRedisConnection;
 use sea_orm::{ActiveModelTrait, IntoActiveModel};
 
-use std::{marker::PhantomData, ops::DerefMut};
+use std::{marker::PhantomData, ops::DerefMut, time::Instant};
 
 use bb8::{Pool, PooledConnection};
 use bb8_redis::RedisConnectionManager;
 
 use async_trait::async_trait;
-use futures::Future;
+use futures::{Future, StreamExt};
 
-use crate::statics::REDIS;
+use crate::statics::{REDIS, TG};
 use ::redis::{
     AsyncCommands, ErrorKind, FromRedisValue, Pipeline, RedisError, RedisFuture, ToRedisArgs,
 };
 use botapi::gen_types::Message;
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::OnceCell;
 use tokio::task::JoinHandle;
+use uuid::Uuid;
 
 // write cache redis keys
 pub const KEY_WRITE_CACHE: &str = "writecache";
@@ -180,8 +183,10 @@ where
     async fn query(self, key: &'r str, param: &'r P) -> Result<T> {
         let (hit, val) = self.redis_query.cb(key, param).await?;
         if hit {
+            CACHE_HITS.inc();
             Ok(val)
         } else {
+            CACHE_MISSES.inc();
             let val = self.sql_query.cb(key, param).await?;
             Ok(self.miss_query.cb(key, val).await?)
         }
@@ -402,7 +407,10 @@ where
         let mut pipe = redis::pipe();
         let pipe = func(&mut pipe);
         let mut conn = self.pool.get().await?;
+        let start = Instant::now();
         let res: R = pipe.query_async(conn.deref_mut()).await?;
+        REDIS_LATENCY.record(start.elapsed());
+        count_module_redis_query();
         Ok(res)
     }
 
@@ -416,7 +424,10 @@ where
         let mut pipe = redis::pipe();
         let pipe = func(&mut pipe)?;
         let mut conn = self.pool.get().await?;
+        let start = Instant::now();
         let res: R = pipe.query_async(conn.deref_mut()).await?;
+        REDIS_LATENCY.record(start.elapsed());
+        count_module_redis_query();
         Ok(res)
     }
 
@@ -426,7 +437,12 @@ where
         T: for<'b> FnOnce(&'b mut PooledConnection<'a, C>) -> RedisFuture<'b, R> + Send,
         R: FromRedisValue + Send + 'a,
     {
-        Ok(func(&mut self.pool.get().await?).await?)
+        let mut conn = self.pool.get().await?;
+        let start = Instant::now();
+        let res = func(&mut conn).await?;
+        REDIS_LATENCY.record(start.elapsed());
+        count_module_redis_query();
+        Ok(res)
     }
 
     /// Run one or more redis queries using the connection provided to the
@@ -437,6 +453,7 @@ where
         Fut: Future<Output = Result<R>> + Send,
         R: Send,
     {
+        count_module_redis_query();
         func(self.pool.get().await?).await
     }
 
@@ -449,13 +466,28 @@ where
         R: Send + 'static,
     {
         let r = self.clone();
-        tokio::spawn(async move {
+        spawn(async move {
             let res = func(r.pool.get().await?).await?;
             let res: Result<R> = Ok(res);
             res
         })
     }
 
+    /// Delete cached keys, both here and on every other instance listening on the cache
+    /// bus. Use this rather than deleting directly when dropping a cache after a database
+    /// write
+    pub async fn invalidate<K: AsRef<str>>(&self, keys: &[K]) -> Result<()> {
+        self.pipe(|p| {
+            for key in keys {
+                p.del(key.as_ref()).ignore();
+            }
+            p
+        })
+        .await?;
+        CACHE_BUS.publish(keys).await;
+        Ok(())
+    }
+
     /// Gets a single connection from the connection pool.
     /// NOTE: this connection will not be returned to the pool until it is dropped
     pub async fn conn(&self) -> Result<PooledConnection<'_, C>> {
@@ -464,6 +496,170 @@ where
     }
 }
 
+pub static CACHE_BUS: BotLocal<CacheBus> = BotLocal::new(CacheBus::new);
+
+/// Seconds to wait before resubscribing after losing the cache bus connection
+const CACHE_BUS_RETRY: u64 = 5;
+
+/// Message sent over the cache bus
+#[derive(Serialize, Deserialize)]
+struct BusMessage {
+    /// instance the message came from, so instances skip their own messages
+    from: Uuid,
+    #[serde(flatten)]
+    event: BusEvent,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum BusEvent {
+    /// keys invalidated by a write on the sending instance
+    Invalidate { keys: Vec<String> },
+    /// bot token swapped in by /rotatetoken or failover on the sending instance
+    RotateToken { token: String },
+}
+
+/// Keeps caches consistent between bot instances that each have their own redis in front
+/// of a shared database. Writes publish the keys they invalidate over redis pub/sub, and
+/// every other instance deletes those keys from its own redis. Keys invalidated while an
+/// instance is disconnected stay stale until they expire. Rotated bot tokens are published
+/// the same way so sharded workers and other instances switch clients too. Does nothing
+/// unless enabled in the config
+pub struct CacheBus {
+    id: Uuid,
+    channel: String,
+    client: Option<redis::Client>,
+    conn: OnceCell<MultiplexedConnection>,
+}
+
+impl CacheBus {
+    fn new() -> Self {
+        let config = Instance::current().config().filter(|v| v.cache_bus.enabled);
+        let client = config.and_then(|config| {
+            let url = config
+                .cache_bus
+                .url
+                .as_deref()
+                .unwrap_or(&config.persistence.redis_connection);
+            redis::Client::open(url)
+                .map_err(|err| log::error!("invalid cache bus url: {}", err))
+                .ok()
+        });
+        Self {
+            id: Uuid::new_v4(),
+            channel: config
+                .map(|v| v.cache_bus.channel.clone())
+                .unwrap_or_default(),
+            client,
+            conn: OnceCell::new(),
+        }
+    }
+
+    /// True if messages are actually sent to other instances
+    pub fn is_enabled(&self) -> bool {
+        self.client.is_some()
+    }
+
+    /// Tell other instances to drop these keys from their caches. Failures are only
+    /// logged, since the write that caused them already happened
+    pub async fn publish<K: AsRef<str>>(&self, keys: &[K]) {
+        if keys.is_empty() {
+            return;
+        }
+        let keys = keys.iter().map(|v| v.as_ref().to_owned()).collect();
+        self.send(BusEvent::Invalidate { keys }, "cache invalidation")
+            .await;
+    }
+
+    /// Tell other instances to switch to a rotated bot token. Failures are only logged,
+    /// this instance already switched
+    pub async fn publish_token(&self, token: &str) {
+        let token = token.to_owned();
+        self.send(BusEvent::RotateToken { token }, "token rotation")
+            .await;
+    }
+
+    async fn send(&self, event: BusEvent, what: &str) {
+        let Some(client) = self.client.as_ref() else {
+            return;
+        };
+        if let Err(err) = self.try_send(client, event).await {
+            log::warn!("failed to publish {}: {}", what, err);
+            err.record_stats();
+        }
+    }
+
+    async fn try_send(&self, client: &redis::Client, event: BusEvent) -> Result<()> {
+        let message = serde_json::to_string(&BusMessage {
+            from: self.id,
+            event,
+        })?;
+        let mut conn = self
+            .conn
+            .get_or_try_init(|| async {
+                Ok::<_, BotError>(client.get_multiplexed_async_connection().await?)
+            })
+            .await?
+            .clone();
+        conn.publish::<_, _, ()>(&self.channel, message).await?;
+        Ok(())
+    }
+
+    /// Handle messages published by other instances. Runs forever, resubscribing if the
+    /// connection drops
+    pub async fn run(&self) {
+        let Some(client) = self.client.as_ref() else {
+            return;
+        };
+        loop {
+            if let Err(err) = self.listen(client).await {
+                log::warn!("cache bus disconnected: {}", err);
+                err.record_stats();
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(CACHE_BUS_RETRY)).await;
+        }
+    }
+
+    async fn listen(&self, client: &redis::Client) -> Result<()> {
+        let mut pubsub = client.get_async_pubsub().await?;
+        pubsub.subscribe(&self.channel).await?;
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let payload: String = message.get_payload()?;
+            let message = match serde_json::from_str::<BusMessage>(&payload) {
+                Ok(message) => message,
+                Err(err) => {
+                    log::warn!("invalid cache bus message: {}", err);
+                    continue;
+                }
+            };
+            if message.from == self.id {
+                continue;
+            }
+            match message.event {
+                BusEvent::Invalidate { keys } if !keys.is_empty() => {
+                    REDIS
+                        .pipe(|p| {
+                            for key in keys.iter() {
+                                p.del(key).ignore();
+                            }
+                            p
+                        })
+                        .await?;
+                }
+                BusEvent::Invalidate { .. } => (),
+                BusEvent::RotateToken { token } => {
+                    if let Err(err) = TG.receive_token(token).await {
+                        log::warn!("failed to switch to rotated token: {}", err);
+                        err.record_stats();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Helper trait intended to be used as an extension trait for caching ORM
 /// types
 #[async_trait]
@@ -530,6 +726,7 @@ where
         REDIS
             .pipe(|q| q.set(r, st).expire(r, expire.num_seconds()))
             .await?;
+        CACHE_BUS.publish(&[r]).await;
         Ok(self.into_active_model())
     }
 
@@ -559,6 +756,7 @@ where
         REDIS
             .pipe(|q| q.set(r, st).expire(r, expire.num_seconds()))
             .await?;
+        CACHE_BUS.publish(&[r]).await;
         let o =
             v.1.into_iter()
                 .map(|v| v.into_active_model())
@@ -598,6 +796,7 @@ where
         REDIS
             .pipe(|q| q.set(r, st).expire(r, expire.num_seconds()))
             .await?;
+        CACHE_BUS.publish(&[r]).await;
         let o = v.1.map(|v| v.into_active_model());
         Ok((v.0.into_active_model(), o))
     }
@@ -619,11 +818,8 @@ where
 
 #[cfg(test)]
 pub async fn mock(c: MockRedisConnection) {
-    use crate::statics::REDIS_BACKEND;
-
-    REDIS_BACKEND
-        .try_insert(RedisPool::new_mock(c).await.unwrap())
-        .map_err(|_| BotError::generic("redis already initilized"))
+    Instance::current()
+        .set_redis(RedisPool::new_mock(c).await.unwrap())
         .unwrap();
 }
 
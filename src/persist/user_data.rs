@@ -0,0 +1,259 @@
+//! Registry of every table that stores data about a user, keyed by the column holding the
+//! user's id. Data access requests are answered from this list, so any new table holding
+//! user ids should be added here. Data kept only in redis is registered the same way in
+//! USER_KEYS.
+
+use crate::persist::admin::{
+    actions, approvals, authorized, ban_signals, fbans, fedadmin, federations, gbans, warns,
+};
+use crate::persist::core::{audit_log, chat_members, flair, payments, roles, rules_acks, users};
+use crate::statics::{DB, REDIS};
+use crate::util::error::Result;
+use futures::{future::BoxFuture, FutureExt};
+use redis::AsyncCommands;
+use sea_orm::{
+    ColumnTrait, ConnectionTrait, EntityTrait, FromQueryResult, JsonValue, QueryFilter, Statement,
+};
+use sea_query::{Alias, ColumnRef, Expr, Query, QueryStatementBuilder};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+type RowFetcher = fn(i64) -> BoxFuture<'static, Result<Vec<Value>>>;
+
+/// A table and how to fetch the rows in it belonging to a user
+pub struct UserTable {
+    pub name: &'static str,
+    pub fetch: RowFetcher,
+}
+
+async fn find_rows<E>(column: E::Column, user: i64) -> Result<Vec<Value>>
+where
+    E: EntityTrait,
+    E::Model: Serialize,
+{
+    let rows = E::find().filter(column.eq(user)).all(*DB).await?;
+    let rows = rows
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<serde_json::Result<Vec<Value>>>()?;
+    Ok(rows)
+}
+
+/// Like find_rows, for tables whose entity is private to a module
+async fn find_raw_rows(table: &'static str, column: &'static str, user: i64) -> Result<Vec<Value>> {
+    let query = Query::select()
+        .column(ColumnRef::Asterisk)
+        .from(Alias::new(table))
+        .and_where(Expr::col(Alias::new(column)).eq(user))
+        .to_owned();
+    let backend = DB.get_database_backend();
+    let (query, params) = query.build_any(&*backend.get_query_builder());
+    let rows = JsonValue::find_by_statement(Statement::from_sql_and_values(backend, query, params))
+        .all(*DB)
+        .await?;
+    Ok(rows)
+}
+
+pub static USER_TABLES: &[UserTable] = &[
+    UserTable {
+        name: "users",
+        fetch: |u| find_rows::<users::Entity>(users::Column::UserId, u).boxed(),
+    },
+    UserTable {
+        name: "chat_members",
+        fetch: |u| find_rows::<chat_members::Entity>(chat_members::Column::UserId, u).boxed(),
+    },
+    UserTable {
+        name: "approvals",
+        fetch: |u| find_rows::<approvals::Entity>(approvals::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "captcha_auth",
+        fetch: |u| find_rows::<authorized::Entity>(authorized::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "warns",
+        fetch: |u| find_rows::<warns::Entity>(warns::Column::UserId, u).boxed(),
+    },
+    UserTable {
+        name: "actions",
+        fetch: |u| find_rows::<actions::Entity>(actions::Column::UserId, u).boxed(),
+    },
+    UserTable {
+        name: "gbans",
+        fetch: |u| find_rows::<gbans::Entity>(gbans::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "fbans",
+        fetch: |u| find_rows::<fbans::Entity>(fbans::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "ban_signals",
+        fetch: |u| find_rows::<ban_signals::Entity>(ban_signals::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "fedadmins",
+        fetch: |u| find_rows::<fedadmin::Entity>(fedadmin::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "federations",
+        fetch: |u| find_rows::<federations::Entity>(federations::Column::Owner, u).boxed(),
+    },
+    UserTable {
+        name: "rules_acks",
+        fetch: |u| find_rows::<rules_acks::Entity>(rules_acks::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "flair",
+        fetch: |u| find_rows::<flair::Entity>(flair::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "roles",
+        fetch: |u| find_rows::<roles::Entity>(roles::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "audit_log_issued",
+        fetch: |u| find_rows::<audit_log::Entity>(audit_log::Column::Issuer, u).boxed(),
+    },
+    UserTable {
+        name: "audit_log_targeted",
+        fetch: |u| find_rows::<audit_log::Entity>(audit_log::Column::Target, u).boxed(),
+    },
+    UserTable {
+        name: "payments",
+        fetch: |u| find_rows::<payments::Entity>(payments::Column::User, u).boxed(),
+    },
+    UserTable {
+        name: "giveaway_entries",
+        fetch: |u| find_raw_rows("giveaway_entries", "user", u).boxed(),
+    },
+];
+
+/// How a user is stored in a family of redis keys
+pub enum KeyKind {
+    /// sorted sets with the user's id as a member, like per chat scores
+    ZsetMember,
+    /// lists of json objects with the user's id in a `user` field
+    JsonList,
+}
+
+/// A family of redis keys holding data about a user that never makes it into a table
+pub struct UserKeys {
+    pub name: &'static str,
+    /// SCAN pattern matching every key in the family
+    pub pattern: &'static str,
+    pub kind: KeyKind,
+}
+
+pub static USER_KEYS: &[UserKeys] = &[
+    UserKeys {
+        name: "quiz_scores",
+        pattern: "quizscore:*",
+        kind: KeyKind::ZsetMember,
+    },
+    UserKeys {
+        name: "llm_recent_messages",
+        pattern: "llmrecent:*",
+        kind: KeyKind::JsonList,
+    },
+];
+
+async fn scan_keys(pattern: &'static str) -> Result<Vec<String>> {
+    REDIS
+        .query(|mut q| async move {
+            let mut iter: redis::AsyncIter<String> = q.scan_match(pattern).await?;
+            let mut keys = Vec::new();
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+            Ok(keys)
+        })
+        .await
+}
+
+/// Entries of a json list belonging to a user, along with their raw value for LREM
+async fn list_entries(key: &str, user: i64) -> Result<Vec<(String, Value)>> {
+    let entries: Vec<String> = REDIS.sq(|q| q.lrange(key, 0, -1)).await?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|raw| {
+            let value = serde_json::from_str::<Value>(&raw).ok()?;
+            let owner = value.get("user").and_then(|v| v.as_i64());
+            (owner == Some(user)).then_some((raw, value))
+        })
+        .collect())
+}
+
+impl UserKeys {
+    /// Everything stored about a user in this family of keys, one entry per key
+    pub async fn export(&self, user: i64) -> Result<Vec<Value>> {
+        let mut out = Vec::new();
+        for key in scan_keys(self.pattern).await? {
+            match self.kind {
+                KeyKind::ZsetMember => {
+                    let score: Option<f64> = REDIS.sq(|q| q.zscore(&key, user)).await?;
+                    if let Some(score) = score {
+                        out.push(json!({ "key": key, "score": score }));
+                    }
+                }
+                KeyKind::JsonList => {
+                    let entries = list_entries(&key, user).await?;
+                    if !entries.is_empty() {
+                        let entries = entries.into_iter().map(|(_, v)| v).collect::<Vec<Value>>();
+                        out.push(json!({ "key": key, "entries": entries }));
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Remove everything stored about a user from this family of keys
+    pub async fn forget(&self, user: i64) -> Result<()> {
+        for key in scan_keys(self.pattern).await? {
+            match self.kind {
+                KeyKind::ZsetMember => {
+                    let _: i64 = REDIS.sq(|q| q.zrem(&key, user)).await?;
+                }
+                KeyKind::JsonList => {
+                    let entries = list_entries(&key, user).await?;
+                    if !entries.is_empty() {
+                        REDIS
+                            .pipe(|p| {
+                                for (raw, _) in entries.iter() {
+                                    p.lrem(&key, 0, raw).ignore();
+                                }
+                                p
+                            })
+                            .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Collect everything stored about a user into a single json document, with one key
+/// per table or family of redis keys
+pub async fn export_user_data(user: i64) -> Result<Value> {
+    let mut out = Map::new();
+    for table in USER_TABLES {
+        let rows = (table.fetch)(user).await?;
+        out.insert(table.name.to_owned(), Value::Array(rows));
+    }
+    for keys in USER_KEYS {
+        let entries = keys.export(user).await?;
+        out.insert(keys.name.to_owned(), Value::Array(entries));
+    }
+    Ok(Value::Object(out))
+}
+
+/// Remove everything stored about a user in redis. Rows in USER_TABLES are left alone,
+/// since some of them (bans, warns) have to outlive an erasure request
+pub async fn forget_user_keys(user: i64) -> Result<()> {
+    for keys in USER_KEYS {
+        keys.forget(user).await?;
+    }
+    Ok(())
+}
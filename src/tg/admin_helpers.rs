@@ -4,7 +4,7 @@
 //! this module depends on the `static` module for access to the database, redis,
 //! and telegram client.
 
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
 
 use crate::{
     persist::{
@@ -12,14 +12,15 @@ use crate::{
             actions::{self, ActionType},
             approvals, warns,
         },
-        core::{dialogs, users},
+        core::{dialogs, pending_deletes, users},
         redis::{
             default_cache_query, CachedQuery, CachedQueryTrait, RedisCache, RedisStr, ToRedisStr,
         },
     },
-    statics::{CONFIG, DB, ME, REDIS, TG},
+    statics::{spawn, CONFIG, DB, ME, REDIS, TG},
     util::{
         error::{BotError, Fail, Result, SpeakErr},
+        scheduler::{register_job, schedule},
         string::{get_chat_lang, AlignCharBoundry, Speak},
     },
 };
@@ -38,25 +39,45 @@ use macros::{entity_fmt, lang_fmt};
 use redis::AsyncCommands;
 use reqwest::Response;
 use sea_orm::{
-    sea_query::OnConflict, ActiveValue::NotSet, ActiveValue::Set, ColumnTrait, EntityTrait,
-    IntoActiveModel, ModelTrait, PaginatorTrait, QueryFilter,
+    sea_query::{Expr, OnConflict},
+    ActiveValue::NotSet,
+    ActiveValue::Set,
+    ColumnTrait, EntityTrait, IntoActiveModel, ModelTrait, PaginatorTrait, QueryFilter, QueryOrder,
+    QuerySelect,
 };
 
+use tokio::io::AsyncWriteExt;
 use uuid::Uuid;
 
 use super::{
+    bridges::{bridged_author, is_bridged_id},
     button::OnPush,
-    command::{ArgSlice, Context, Entities, EntityArg, PopSlice},
-    dialog::{dialog_or_default, get_dialog_key},
+    command::{ArgSlice, Context, Entities, EntityArg, Nickname, PopSlice},
+    dialog::{dialog_or_default, get_dialog, get_dialog_key},
+    events::{publish, ModEvent},
+    exemptions::get_message_exemption,
     markdown::MarkupType,
-    permissions::{GetCachedAdmins, IsAdmin, IsGroupAdmin},
+    permissions::{GetCachedAdmins, IsAdmin},
     user::{get_user_username, GetUser, Username},
+    webhooks::{post_event, ChatEvent},
 };
 
 lazy_static! {
     static ref VECDEQUE: Entities<'static> = VecDeque::new();
 }
 
+/// Scheduler job kind for deleting a message later
+const DELETE_JOB: &str = "deletemessage";
+
+/// Seconds between sweeps for deletions the scheduler lost track of
+const DELETE_SWEEP_SECONDS: u64 = 60;
+
+/// Seconds a deletion has to be overdue before the sweep handles it instead of the scheduler
+const DELETE_SWEEP_GRACE: i64 = 30;
+
+/// Most overdue deletions handled per sweep
+const DELETE_SWEEP_BATCH: u64 = 500;
+
 #[inline(always)]
 fn get_chat_key(chat: i64) -> String {
     format!("gcch:{}", chat)
@@ -78,7 +99,7 @@ impl GetChat for i64 {
                 if let Some(chat) = chat {
                     Ok(chat.get()?)
                 } else {
-                    let c = TG.client.get_chat(*self).await?;
+                    let c = TG.client().get_chat(*self).await?;
                     q.set(&key, c.to_redis()?).await?;
                     q.expire(&key, 15).await?;
                     Ok(c)
@@ -130,7 +151,13 @@ pub trait UpdateHelpers {
     /// update is a 'chat left' or 'chat joined' event we simplify it by parsing to a
     /// UserChanged type
     fn user_event(&self) -> Option<UserChanged<'_>>;
-    async fn should_moderate(&self) -> Option<&'_ Message>;
+
+    /// Get the status change if this update is the bot being added to a group
+    fn bot_added(&self) -> Option<&'_ ChatMemberUpdated>;
+
+    /// Get the message in this update if automatic moderation should act on it, according
+    /// to the policy in the exemptions module
+    async fn moderated_message(&self) -> Option<&'_ Message>;
 }
 
 #[async_trait]
@@ -174,53 +201,75 @@ impl UpdateHelpers for UpdateExt {
         }
     }
 
-    async fn should_moderate(&self) -> Option<&'_ Message> {
+    fn bot_added(&'_ self) -> Option<&'_ ChatMemberUpdated> {
+        let UpdateExt::MyChatMember(member) = self else {
+            return None;
+        };
+        if is_dm(member.get_chat()) {
+            return None;
+        }
+        let old_left = matches!(
+            member.get_old_chat_member(),
+            ChatMember::ChatMemberLeft(_) | ChatMember::ChatMemberBanned(_)
+        );
+        let new_member = matches!(
+            member.get_new_chat_member(),
+            ChatMember::ChatMemberMember(_) | ChatMember::ChatMemberAdministrator(_)
+        );
+        (old_left && new_member).then_some(member)
+    }
+
+    async fn moderated_message(&self) -> Option<&'_ Message> {
         match self {
-            UpdateExt::Message(ref message) | UpdateExt::EditedMessage(ref message) => {
-                if message.is_group_admin().await.unwrap_or(false) {
-                    return None;
-                }
-                let chat = message.get_chat();
-                if let Some(ref sender_chat) = message.sender_chat {
-                    if is_approved(chat, sender_chat.id).await.unwrap_or(false) {
-                        return None;
-                    }
-                } else if let Some(ref user) = message.from {
-                    if is_approved(chat, user.id).await.unwrap_or(false) {
-                        return None;
-                    }
-                }
-                if let Some(ref fullchat) = message.sender_chat {
-                    match fullchat.id.get_chat_cached().await {
-                        Ok(fullchat) => {
-                            if fullchat.linked_chat_id != Some(message.chat.id)
-                                && Some(message.chat.id)
-                                    != message.sender_chat.as_ref().map(|v| v.id)
-                            {
-                                Some(message)
-                            } else {
-                                None
-                            }
-                        }
-                        Err(err) => {
-                            err.record_stats();
-                            Some(message)
-                        }
-                    }
-                } else {
-                    Some(message)
+            UpdateExt::EditedMessage(ref message) => match should_moderate_edit(message).await {
+                Ok(true) => exempt_or_message(message).await,
+                Ok(false) => None,
+                Err(err) => {
+                    err.record_stats();
+                    None
                 }
-            }
+            },
+            UpdateExt::Message(ref message) => exempt_or_message(message).await,
             _ => None,
         }
     }
 }
 
+/// Returns the message unless its sender is exempt from automatic moderation
+async fn exempt_or_message(message: &Message) -> Option<&'_ Message> {
+    match get_message_exemption(message).await {
+        Ok(None) => Some(message),
+        Ok(Some(_)) => None,
+        Err(err) => {
+            err.record_stats();
+            Some(message)
+        }
+    }
+}
+
+/// Check if an edit should be moderated, the chat must have edit moderation on and the
+/// message can't be older than the configured cap
+async fn should_moderate_edit(message: &Message) -> Result<bool> {
+    if !dialog_or_default(message.get_chat()).await?.moderate_edits {
+        return Ok(false);
+    }
+    let max_age = CONFIG.moderation.edit_max_age_minutes;
+    if max_age == 0 {
+        return Ok(true);
+    }
+    let edited = message
+        .get_edit_date()
+        .unwrap_or_else(|| Utc::now().timestamp());
+    Ok(edited - message.get_date() <= max_age * 60)
+}
+
 /// Trait for telegram objects that can be deleted after a delay.
 /// Meant to be used as an extension trait
 #[async_trait]
 pub trait DeleteAfterTime {
-    /// Delete the object after the specified duration
+    /// Delete the object after the specified duration. The deletion is stored in the
+    /// database and queued in the persistent scheduler, so it still happens if the bot
+    /// restarts or redis is flushed before then
     fn delete_after_time(&self, duration: Duration);
     async fn delete(&self) -> Result<()>;
 }
@@ -231,10 +280,19 @@ impl DeleteAfterTime for Message {
         let chat_id = self.get_chat().get_id();
         let message_id = self.get_message_id();
 
-        tokio::spawn(async move {
+        spawn(async move {
+            let Err(err) = delete_later(chat_id, message_id, duration).await else {
+                return Ok(());
+            };
+            // without the database, the message is still deleted unless we restart
+            log::warn!(
+                "failed to queue message deletion, waiting in memory: {}",
+                err
+            );
+            err.record_stats();
             tokio::time::sleep(duration.to_std()?).await;
             if let Err(err) = TG
-                .client
+                .client()
                 .build_delete_message(chat_id, message_id)
                 .build()
                 .await
@@ -247,7 +305,7 @@ impl DeleteAfterTime for Message {
     }
 
     async fn delete(&self) -> Result<()> {
-        TG.client
+        TG.client()
             .build_delete_message(self.get_chat().get_id(), self.get_message_id())
             .build()
             .await?;
@@ -298,77 +356,984 @@ pub fn is_dm(chat: &Chat) -> bool {
     chat.get_tg_type() == "private"
 }
 
-pub fn is_dm_info(chat: &ChatFullInfo) -> bool {
-    chat.get_tg_type() == "private"
+pub fn is_dm_info(chat: &ChatFullInfo) -> bool {
+    chat.get_tg_type() == "private"
+}
+
+/// Gets the redis key string for caching admin actins
+fn get_action_key(user: i64, chat: i64) -> String {
+    format!("act:{}:{}", user, chat)
+}
+
+/// Gets the redis key string for caching warns
+fn get_warns_key(user: i64, chat: i64) -> String {
+    format!("warns:{}:{}", user, chat)
+}
+
+/// Kicks a user from the specified chat. This is implemented
+// by banning then immmediately unbanning
+pub async fn kick(user: i64, chat: i64) -> Result<()> {
+    TG.client()
+        .build_ban_chat_member(chat, user)
+        .build()
+        .await?;
+    TG.client()
+        .build_unban_chat_member(chat, user)
+        .build()
+        .await?;
+    publish(ModEvent::UserKicked { chat, user });
+    Ok(())
+}
+
+/// Kicks the sender of a given message from the chat
+pub async fn kick_message(message: &Message) -> Result<()> {
+    if let Some(from) = message.get_from() {
+        TG.client()
+            .build_ban_chat_member(message.get_chat().get_id(), from.get_id())
+            .build()
+            .await?;
+        TG.client()
+            .build_unban_chat_member(message.get_chat().get_id(), from.get_id())
+            .build()
+            .await?;
+        publish(ModEvent::UserKicked {
+            chat: message.get_chat().get_id(),
+            user: from.get_id(),
+        });
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn get_history_key(chat: i64, user: i64) -> String {
+    format!("mhist:{}:{}", chat, user)
+}
+
+/// Seconds a message id is kept in a user's history. Telegram only lets bots delete
+/// messages for 48 hours
+const HISTORY_SECONDS: i64 = 48 * 60 * 60;
+
+/// Remember the id of a message in its sender's history, so it can be deleted if the
+/// sender is removed silently. Only the most recent messages of each user are kept
+pub async fn record_message_history(message: &Message) -> Result<()> {
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let max = CONFIG.moderation.silent_history;
+    if max == 0 || message.get_sender_chat().is_some() || is_dm(message.get_chat()) {
+        return Ok(());
+    }
+    let key = get_history_key(message.get_chat().get_id(), user.get_id());
+    REDIS
+        .pipe(|q| {
+            q.lpush(&key, message.get_message_id())
+                .ignore()
+                .ltrim(&key, 0, max as isize - 1)
+                .ignore()
+                .expire(&key, HISTORY_SECONDS)
+                .ignore()
+        })
+        .await?;
+    Ok(())
+}
+
+/// Delete the recent messages of a user in a chat and forget them
+pub async fn delete_message_history(chat: i64, user: i64) -> Result<()> {
+    let key = get_history_key(chat, user);
+    let (messages,): (Vec<i64>,) = REDIS
+        .pipe(|p| {
+            p.atomic();
+            p.lrange(&key, 0, -1).del(&key).ignore()
+        })
+        .await?;
+    TG.delete_messages_queued(chat, &messages, |_| async { Ok(()) })
+        .await
+}
+
+/// Parse a std::chrono::Duration from a human readable string (5m, 4d, etc)
+pub fn parse_duration_str(arg: &str, chat: i64, reply: i64) -> Result<Option<Duration>> {
+    let end = arg.align_char_boundry(arg.len() - 1);
+    let head = &arg[0..end];
+    let tail = &arg[end..];
+    log::info!("head {} tail {}", head, tail);
+    let head = match str::parse::<i64>(head) {
+        Err(_) => return Err(BotError::speak("Enter a number", chat, Some(reply))),
+        Ok(res) => res,
+    };
+    let res = match tail {
+        "m" => Duration::try_minutes(head),
+        "h" => Duration::try_hours(head),
+        "d" => Duration::try_days(head),
+        _ => return Err(BotError::speak("Invalid time spec", chat, Some(reply))),
+    }
+    .ok_or_else(|| BotError::speak("time out of range", chat, Some(reply)))?;
+
+    let res = if res.num_seconds() < 30 {
+        Duration::try_seconds(30).unwrap()
+    } else {
+        res
+    };
+
+    Ok(Some(res))
+}
+/// Sets the duration after which warns expire for the provided chat
+pub async fn set_warn_time(chat: &Chat, time: Option<i64>) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: Set(time),
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::WarnTime)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets or clears how long a user has to go without a new warn before all their warns
+/// in the provided chat reset
+pub async fn set_warn_decay(chat: &Chat, decay: Option<i64>) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: Set(decay),
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::WarnDecay)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets the number of warns until an action is triggered for the provided chat
+pub async fn set_warn_limit(chat: &Chat, limit: i32) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: Set(limit),
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::WarnLimit)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets the action to be applied when the warn count is exceeeded, parsing
+/// it from a string
+pub async fn set_warn_mode(chat: &Chat, mode: &str) -> Result<()> {
+    let chat_id = chat.get_id();
+    let mode = match mode {
+        "mute" => Ok(ActionType::Mute),
+        "ban" => Ok(ActionType::Ban),
+        "shame" => Ok(ActionType::Shame),
+        _ => chat.fail(format!("Invalid mode {}", mode)),
+    }?;
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: Set(mode),
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::ActionType)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets or clears the channel that moderation events for the provided chat are posted to
+pub async fn set_log_channel(chat: &Chat, channel: Option<i64>) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: Set(channel),
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::LogChannel)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets the number of joins or leaves per minute above which individual welcome and
+/// goodbye messages are replaced with a summary. 0 disables suppression
+pub async fn set_greet_flood_limit(chat: &Chat, limit: i32) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: Set(limit),
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::GreetFloodLimit)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets the default duration of mutes in the provided chat, used when no duration is
+/// specified. None makes mutes permanent
+pub async fn set_mute_time(chat: &Chat, time: Option<i64>) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: Set(time),
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::MuteTime)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets the default duration of bans in the provided chat, used when no duration is
+/// specified. None makes bans permanent
+pub async fn set_ban_time(chat: &Chat, time: Option<i64>) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: Set(time),
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::BanTime)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets whether new members in the provided chat stay muted until they agree to the
+/// rules using the button on the welcome message
+pub async fn set_rules_gate(chat: &Chat, enabled: bool) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: Set(enabled),
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::RulesGate)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets whether the previous welcome, or goodbye if goodbye is true, is deleted when a
+/// new one is sent in the provided chat
+pub async fn set_clean_greeting(chat: &Chat, goodbye: bool, enabled: bool) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: if goodbye { NotSet } else { Set(enabled) },
+        clean_goodbye: if goodbye { Set(enabled) } else { NotSet },
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+    let column = if goodbye {
+        dialogs::Column::CleanGoodbye
+    } else {
+        dialogs::Column::CleanWelcome
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(column)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets whether notes triggered in the provided chat are sent in dm instead of the chat
+pub async fn set_private_notes(chat: &Chat, enabled: bool) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: Set(enabled),
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::PrivateNotes)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets whether globally banned users are banned in the provided chat
+pub async fn set_enforce_gbans(chat: &Chat, enabled: bool) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: Set(enabled),
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::EnforceGbans)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets whether edited messages in the provided chat are checked by locks and blocklists
+pub async fn set_moderate_edits(chat: &Chat, enabled: bool) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: Set(enabled),
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::ModerateEdits)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Sets how long responses to admin commands, help, and errors stay in the provided chat
+/// before being deleted. None keeps them forever
+pub async fn set_response_ttl(chat: &Chat, seconds: Option<i64>) -> Result<()> {
+    let chat_id = chat.get_id();
+
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: Set(seconds),
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
+
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::ResponseTtl)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+
+    model.cache(key).await?;
+    Ok(())
 }
 
-/// Gets the redis key string for caching admin actins
-fn get_action_key(user: i64, chat: i64) -> String {
-    format!("act:{}:{}", user, chat)
-}
+/// Sets the nickname the bot answers to in the provided chat. None removes it
+pub async fn set_nickname(chat: &Chat, nickname: Option<&Nickname>) -> Result<()> {
+    let nickname = nickname.map(serde_json::to_value).transpose()?;
+    let chat_id = chat.get_id();
 
-/// Gets the redis key string for caching warns
-fn get_warns_key(user: i64, chat: i64) -> String {
-    format!("warns:{}:{}", user, chat)
-}
+    let model = dialogs::ActiveModel {
+        chat_id: Set(chat_id),
+        language: NotSet,
+        chat_type: Set(chat.get_tg_type().to_owned()),
+        warn_limit: NotSet,
+        action_type: NotSet,
+        warn_time: NotSet,
+        can_send_messages: NotSet,
+        can_send_audio: NotSet,
+        can_send_video: NotSet,
+        can_send_photo: NotSet,
+        can_send_document: NotSet,
+        can_send_video_note: NotSet,
+        can_send_voice_note: NotSet,
+        can_send_poll: NotSet,
+        can_send_other: NotSet,
+        federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: Set(nickname),
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
+    };
 
-/// Kicks a user from the specified chat. This is implemented
-// by banning then immmediately unbanning
-pub async fn kick(user: i64, chat: i64) -> Result<()> {
-    TG.client()
-        .build_ban_chat_member(chat, user)
-        .build()
-        .await?;
-    TG.client()
-        .build_unban_chat_member(chat, user)
-        .build()
+    let key = get_dialog_key(chat_id);
+    let model = dialogs::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(dialogs::Column::ChatId)
+                .update_column(dialogs::Column::Nickname)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
         .await?;
-    Ok(())
-}
 
-/// Kicks the sender of a given message from the chat
-pub async fn kick_message(message: &Message) -> Result<()> {
-    if let Some(from) = message.get_from() {
-        TG.client()
-            .build_ban_chat_member(message.get_chat().get_id(), from.get_id())
-            .build()
-            .await?;
-        TG.client()
-            .build_unban_chat_member(message.get_chat().get_id(), from.get_id())
-            .build()
-            .await?;
-    }
+    model.cache(key).await?;
     Ok(())
 }
 
-/// Parse a std::chrono::Duration from a human readable string (5m, 4d, etc)
-pub fn parse_duration_str(arg: &str, chat: i64, reply: i64) -> Result<Option<Duration>> {
-    let end = arg.align_char_boundry(arg.len() - 1);
-    let head = &arg[0..end];
-    let tail = &arg[end..];
-    log::info!("head {} tail {}", head, tail);
-    let head = match str::parse::<i64>(head) {
-        Err(_) => return Err(BotError::speak("Enter a number", chat, Some(reply))),
-        Ok(res) => res,
-    };
-    let res = match tail {
-        "m" => Duration::try_minutes(head),
-        "h" => Duration::try_hours(head),
-        "d" => Duration::try_days(head),
-        _ => return Err(BotError::speak("Invalid time spec", chat, Some(reply))),
-    }
-    .ok_or_else(|| BotError::speak("time out of range", chat, Some(reply)))?;
-
-    let res = if res.num_seconds() < 30 {
-        Duration::try_seconds(30).unwrap()
-    } else {
-        res
-    };
-
-    Ok(Some(res))
+/// Gets the admins of the provided chat who opted out of being pinged by reports
+pub async fn get_report_optouts(chat: &Chat) -> Result<BTreeSet<i64>> {
+    let optouts = get_dialog(chat)
+        .await?
+        .and_then(|v| v.report_optouts)
+        .map(serde_json::from_value)
+        .transpose()?;
+    Ok(optouts.unwrap_or_default())
 }
-/// Sets the duration after which warns expire for the provided chat
-pub async fn set_warn_time(chat: &Chat, time: Option<i64>) -> Result<()> {
+
+/// Sets the admins of the provided chat who opted out of being pinged by reports
+pub async fn set_report_optouts(chat: &Chat, optouts: &BTreeSet<i64>) -> Result<()> {
+    let optouts = serde_json::to_value(optouts)?;
     let chat_id = chat.get_id();
 
     let model = dialogs::ActiveModel {
@@ -377,7 +1342,7 @@ pub async fn set_warn_time(chat: &Chat, time: Option<i64>) -> Result<()> {
         chat_type: Set(chat.get_tg_type().to_owned()),
         warn_limit: NotSet,
         action_type: NotSet,
-        warn_time: Set(time),
+        warn_time: NotSet,
         can_send_messages: NotSet,
         can_send_audio: NotSet,
         can_send_video: NotSet,
@@ -388,13 +1353,31 @@ pub async fn set_warn_time(chat: &Chat, time: Option<i64>) -> Result<()> {
         can_send_poll: NotSet,
         can_send_other: NotSet,
         federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: Set(Some(optouts)),
     };
 
     let key = get_dialog_key(chat_id);
     let model = dialogs::Entity::insert(model)
         .on_conflict(
             OnConflict::column(dialogs::Column::ChatId)
-                .update_column(dialogs::Column::WarnTime)
+                .update_column(dialogs::Column::ReportOptouts)
                 .to_owned(),
         )
         .exec_with_returning(*DB)
@@ -404,15 +1387,20 @@ pub async fn set_warn_time(chat: &Chat, time: Option<i64>) -> Result<()> {
     Ok(())
 }
 
-/// Sets the number of warns until an action is triggered for the provided chat
-pub async fn set_warn_limit(chat: &Chat, limit: i32) -> Result<()> {
+/// Sets how many commands per minute the provided chat and each of its users can send.
+/// None falls back to the config default and 0 disables the limit
+pub async fn set_rate_limits(
+    chat: &Chat,
+    chat_limit: Option<i32>,
+    user_limit: Option<i32>,
+) -> Result<()> {
     let chat_id = chat.get_id();
 
     let model = dialogs::ActiveModel {
         chat_id: Set(chat_id),
         language: NotSet,
         chat_type: Set(chat.get_tg_type().to_owned()),
-        warn_limit: Set(limit),
+        warn_limit: NotSet,
         action_type: NotSet,
         warn_time: NotSet,
         can_send_messages: NotSet,
@@ -425,13 +1413,34 @@ pub async fn set_warn_limit(chat: &Chat, limit: i32) -> Result<()> {
         can_send_poll: NotSet,
         can_send_other: NotSet,
         federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: Set(chat_limit),
+        ratelimit_user: Set(user_limit),
+        connected_chat: NotSet,
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
     };
 
     let key = get_dialog_key(chat_id);
     let model = dialogs::Entity::insert(model)
         .on_conflict(
             OnConflict::column(dialogs::Column::ChatId)
-                .update_column(dialogs::Column::WarnLimit)
+                .update_columns([
+                    dialogs::Column::RatelimitChat,
+                    dialogs::Column::RatelimitUser,
+                ])
                 .to_owned(),
         )
         .exec_with_returning(*DB)
@@ -441,23 +1450,16 @@ pub async fn set_warn_limit(chat: &Chat, limit: i32) -> Result<()> {
     Ok(())
 }
 
-/// Sets the action to be applied when the warn count is exceeeded, parsing
-/// it from a string
-pub async fn set_warn_mode(chat: &Chat, mode: &str) -> Result<()> {
+/// Set the group a private chat's commands are sent to, or None to disconnect
+pub async fn set_connection(chat: &Chat, connected: Option<i64>) -> Result<()> {
     let chat_id = chat.get_id();
-    let mode = match mode {
-        "mute" => Ok(ActionType::Mute),
-        "ban" => Ok(ActionType::Ban),
-        "shame" => Ok(ActionType::Shame),
-        _ => chat.fail(format!("Invalid mode {}", mode)),
-    }?;
 
     let model = dialogs::ActiveModel {
         chat_id: Set(chat_id),
         language: NotSet,
         chat_type: Set(chat.get_tg_type().to_owned()),
         warn_limit: NotSet,
-        action_type: Set(mode),
+        action_type: NotSet,
         warn_time: NotSet,
         can_send_messages: NotSet,
         can_send_audio: NotSet,
@@ -469,13 +1471,31 @@ pub async fn set_warn_mode(chat: &Chat, mode: &str) -> Result<()> {
         can_send_poll: NotSet,
         can_send_other: NotSet,
         federation: NotSet,
+        log_channel: NotSet,
+        greet_flood_limit: NotSet,
+        mute_time: NotSet,
+        ban_time: NotSet,
+        rules_gate: NotSet,
+        moderate_edits: NotSet,
+        response_ttl: NotSet,
+        nickname: NotSet,
+        ratelimit_chat: NotSet,
+        ratelimit_user: NotSet,
+        connected_chat: Set(connected),
+        template_applied: NotSet,
+        clean_welcome: NotSet,
+        clean_goodbye: NotSet,
+        private_notes: NotSet,
+        warn_decay: NotSet,
+        enforce_gbans: NotSet,
+        report_optouts: NotSet,
     };
 
     let key = get_dialog_key(chat_id);
     let model = dialogs::Entity::insert(model)
         .on_conflict(
             OnConflict::column(dialogs::Column::ChatId)
-                .update_column(dialogs::Column::ActionType)
+                .update_column(dialogs::Column::ConnectedChat)
                 .to_owned(),
         )
         .exec_with_returning(*DB)
@@ -485,6 +1505,136 @@ pub async fn set_warn_mode(chat: &Chat, mode: &str) -> Result<()> {
     Ok(())
 }
 
+/// Get the nickname the bot answers to in a chat, if one was set
+pub async fn get_nickname(chat: &Chat) -> Result<Option<Nickname>> {
+    let nickname = get_dialog(chat)
+        .await?
+        .and_then(|v| v.nickname)
+        .map(serde_json::from_value)
+        .transpose()?;
+    Ok(nickname)
+}
+
+/// Delete a message after a delay. The deletion is stored in the pending_deletes table
+/// and queued in the persistent scheduler to run on time. If the scheduler loses it, for
+/// example because redis was flushed, run_delete_sweep deletes the message from the table
+pub async fn delete_later(chat: i64, message_id: i64, after: Duration) -> Result<()> {
+    let due = Utc::now() + after;
+    pending_deletes::Entity::insert(pending_deletes::ActiveModel {
+        chat: Set(chat),
+        message_id: Set(message_id),
+        due: Set(due),
+    })
+    .on_conflict(
+        OnConflict::columns([
+            pending_deletes::Column::Chat,
+            pending_deletes::Column::MessageId,
+        ])
+        .update_column(pending_deletes::Column::Due)
+        .to_owned(),
+    )
+    .exec(*DB)
+    .await?;
+    if let Err(err) = schedule(DELETE_JOB, &(chat, message_id), due).await {
+        log::warn!(
+            "failed to schedule message deletion, leaving it to the sweep: {}",
+            err
+        );
+        err.record_stats();
+    }
+    Ok(())
+}
+
+/// Remove a deletion queued with delete_later from the table. Returns false if it was
+/// already removed, so the sweep doesn't delete a message the scheduler got to first
+async fn claim_pending(chat: i64, message_id: i64) -> Result<bool> {
+    let claimed = pending_deletes::Entity::delete_many()
+        .filter(
+            pending_deletes::Column::Chat
+                .eq(chat)
+                .and(pending_deletes::Column::MessageId.eq(message_id)),
+        )
+        .exec(*DB)
+        .await?;
+    Ok(claimed.rows_affected > 0)
+}
+
+/// Register the scheduler handler for delete_later. Called once at startup
+pub fn register_delete_job() {
+    register_job(DELETE_JOB, |payload| async move {
+        let (chat, message_id): (i64, i64) = serde_json::from_value(payload)?;
+        // jobs queued before the table existed have no row, delete those messages anyway
+        claim_pending(chat, message_id).await?;
+        TG.client()
+            .build_delete_message(chat, message_id)
+            .build()
+            .await?;
+        Ok(())
+    });
+}
+
+/// Delete messages from delete_later that are overdue, in case the scheduler lost them
+async fn sweep_pending_deletes() -> Result<()> {
+    let overdue = Utc::now() - Duration::try_seconds(DELETE_SWEEP_GRACE).unwrap();
+    let pending = pending_deletes::Entity::find()
+        .filter(pending_deletes::Column::Due.lt(overdue))
+        .order_by_asc(pending_deletes::Column::Due)
+        .limit(DELETE_SWEEP_BATCH)
+        .all(*DB)
+        .await?;
+    for pending in pending {
+        if !claim_pending(pending.chat, pending.message_id).await? {
+            continue;
+        }
+        if let Err(err) = TG
+            .client()
+            .build_delete_message(pending.chat, pending.message_id)
+            .build()
+            .await
+        {
+            BotError::from(err).record_stats();
+        }
+    }
+    Ok(())
+}
+
+/// Periodically delete messages from delete_later that the scheduler didn't get to.
+/// Runs forever
+pub async fn run_delete_sweep() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(DELETE_SWEEP_SECONDS));
+    loop {
+        interval.tick().await;
+        if let Err(err) = sweep_pending_deletes().await {
+            log::warn!("failed to sweep pending deletions: {}", err);
+            err.record_stats();
+        }
+    }
+}
+
+/// Queue a bot response for deletion if its chat set a lifetime for responses
+pub async fn auto_delete_response(message: Option<&Message>) -> Result<()> {
+    let Some(message) = message else {
+        return Ok(());
+    };
+    if let Some(ttl) = get_dialog(message.get_chat())
+        .await?
+        .and_then(|v| v.response_ttl)
+    {
+        let after = Duration::try_seconds(ttl).unwrap_or_else(Duration::zero);
+        delete_later(message.get_chat().get_id(), message.get_message_id(), after).await?;
+    }
+    Ok(())
+}
+
+/// Post a message to the log channel configured for a chat. Does nothing if no
+/// log channel is set
+pub async fn post_log<T: AsRef<str> + Send + Sync>(chat: &Chat, text: T) -> Result<()> {
+    if let Some(channel) = get_dialog(chat).await?.and_then(|v| v.log_channel) {
+        channel.speak(text).await?;
+    }
+    Ok(())
+}
+
 /// Gets pending permissions to be applied to a user. This map onto telegram's built-in
 /// restrictions with the addition of a 'ban' permission.
 pub async fn get_action(chat: &Chat, user: &User) -> Result<Option<actions::Model>> {
@@ -601,10 +1751,81 @@ pub async fn get_warns_count(message: &Message, user: i64) -> Result<i32> {
     }
 }
 
+/// Push back the expiry of a user's warns so that they all expire once the user goes the
+/// chat's decay time without a new warn. Warns that already expire sooner are left alone
+async fn decay_warns(chat: i64, user: i64, decay: Duration) -> Result<()> {
+    let expires = Utc::now() + decay;
+    warns::Entity::update_many()
+        .col_expr(warns::Column::Expires, Expr::value(expires))
+        .filter(
+            warns::Column::ChatId
+                .eq(chat)
+                .and(warns::Column::UserId.eq(user))
+                .and(
+                    warns::Column::Expires
+                        .is_null()
+                        .or(warns::Column::Expires.gt(expires)),
+                ),
+        )
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_warns_key(user, chat)]).await?;
+    Ok(())
+}
+
+/// Delete expired warns and pending actions along with their cached copies
+pub async fn sweep_expired() -> Result<()> {
+    let now = Utc::now();
+    let warns = warns::Entity::find()
+        .filter(warns::Column::Expires.lt(now))
+        .all(*DB)
+        .await?;
+    let actions = actions::Entity::find()
+        .filter(actions::Column::Expires.lt(now))
+        .all(*DB)
+        .await?;
+    let keys = warns
+        .iter()
+        .map(|v| get_warns_key(v.user_id, v.chat_id))
+        .chain(actions.iter().map(|v| get_action_key(v.user_id, v.chat_id)))
+        .collect::<Vec<String>>();
+    warns::Entity::delete_many()
+        .filter(warns::Column::Id.is_in(warns.iter().map(|v| v.id)))
+        .exec(*DB)
+        .await?;
+    for action in actions.iter() {
+        actions::Entity::delete_by_id((action.user_id, action.chat_id))
+            .exec(*DB)
+            .await?;
+    }
+    if !keys.is_empty() {
+        REDIS.invalidate(&keys).await?;
+    }
+    log::info!(
+        "swept {} expired warns and {} expired actions",
+        warns.len(),
+        actions.len()
+    );
+    Ok(())
+}
+
+/// Periodically sweep expired warns and pending actions. Runs forever
+pub async fn run_warn_sweep() {
+    let minutes = CONFIG.moderation.warn_sweep_minutes.max(1);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(minutes * 60));
+    loop {
+        interval.tick().await;
+        if let Err(err) = sweep_expired().await {
+            log::warn!("failed to sweep expired warns: {}", err);
+            err.record_stats();
+        }
+    }
+}
+
 /// Removes all warns from a user in a chat
 pub async fn clear_warns(chat: &Chat, user: i64) -> Result<()> {
     let key = get_warns_key(user, chat.get_id());
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     warns::Entity::delete_many()
         .filter(
             warns::Column::ChatId
@@ -628,6 +1849,8 @@ pub async fn insert_user(user: &User) -> Result<users::Model> {
         first_name: Set(user.get_first_name().to_owned()),
         last_name: Set(user.get_last_name().map(|v| v.to_owned())),
         is_bot: Set(user.get_is_bot()),
+        optout_mentions: NotSet,
+        optout_stats: NotSet,
     })
     .on_conflict(
         OnConflict::column(users::Column::UserId)
@@ -678,7 +1901,7 @@ pub async fn unapprove(chat: &Chat, user: i64) -> Result<()> {
 
     let key = get_approval_key(chat, user);
 
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     Ok(())
 }
 
@@ -769,12 +1992,20 @@ fn merge_permissions(
         new = new.set_can_send_other_messages(p);
     }
 
+    if let Some(p) = permissions.get_can_add_web_page_previews() {
+        new = new.set_can_add_web_page_previews(p);
+    }
+
+    if let Some(p) = permissions.get_can_invite_users() {
+        new = new.set_can_invite_users(p);
+    }
+
     new
 }
 
 /// Sets the default permissions for the current chat
 pub async fn change_chat_permissions(chat: &Chat, permissions: &ChatPermissions) -> Result<()> {
-    let current_perms = TG.client.get_chat(chat.get_id()).await?;
+    let current_perms = TG.client().get_chat(chat.get_id()).await?;
     let mut new = ChatPermissionsBuilder::new();
     let old = current_perms
         .get_permissions()
@@ -782,7 +2013,7 @@ pub async fn change_chat_permissions(chat: &Chat, permissions: &ChatPermissions)
     new = merge_permissions(old, new);
     new = merge_permissions(permissions, new);
     let new = new.build();
-    TG.client
+    TG.client()
         .build_set_chat_permissions(chat.get_id(), &new)
         .use_independent_chat_permissions(true)
         .build()
@@ -799,7 +2030,8 @@ pub async fn ban_message(message: &Message, duration: Option<Duration>) -> Resul
             .build()
             .await?;
     } else if let Some(user) = message.get_from() {
-        if let Some(duration) = duration.and_then(|v| Utc::now().checked_add_signed(v)) {
+        let until = duration.and_then(|v| Utc::now().checked_add_signed(v));
+        if let Some(duration) = until {
             TG.client()
                 .build_ban_chat_member(message.get_chat().get_id(), user.get_id())
                 .until_date(duration.timestamp())
@@ -811,6 +2043,11 @@ pub async fn ban_message(message: &Message, duration: Option<Duration>) -> Resul
                 .build()
                 .await?;
         }
+        publish(ModEvent::UserBanned {
+            chat: message.get_chat().get_id(),
+            user: user.get_id(),
+            until,
+        });
     }
     Ok(())
 }
@@ -865,6 +2102,19 @@ impl Context {
         Ok(())
     }
 
+    /// Get the chat's configured duration for a mute or ban, used when an admin doesn't
+    /// specify one
+    pub async fn default_duration(&self, action: ActionType) -> Result<Option<Duration>> {
+        let time = get_dialog(self.try_get()?.chat)
+            .await?
+            .and_then(|v| match action {
+                ActionType::Mute => v.mute_time,
+                ActionType::Ban => v.ban_time,
+                _ => None,
+            });
+        Ok(time.and_then(Duration::try_seconds))
+    }
+
     /// Parse an std::chrono::Duration from a argument list
     pub fn parse_duration(&self, args: &Option<ArgSlice<'_>>) -> Result<Option<Duration>> {
         if let Some(args) = args {
@@ -938,6 +2188,10 @@ impl Context {
                 .build_unban_chat_member(self.try_get()?.chat.get_id(), user)
                 .build()
                 .await?;
+            publish(ModEvent::UserUnbanned {
+                chat: self.try_get()?.chat.get_id(),
+                user,
+            });
         }
         Ok(())
     }
@@ -947,6 +2201,10 @@ impl Context {
     pub async fn warn_mute(&self, user: i64, count: i32, duration: Option<Duration>) -> Result<()> {
         log::info!("warn_mute");
         let message = self.message()?;
+        let duration = match duration {
+            Some(duration) => Some(duration),
+            None => self.default_duration(ActionType::Mute).await?,
+        };
         self.mute(user, self.try_get()?.chat, duration).await?;
 
         let mention = user.mention().await?;
@@ -1025,7 +2283,7 @@ impl Context {
             user.cached_name().await?,
             chat.name_humanreadable()
         );
-        let old = TG.client.get_chat(chat.get_id()).await?;
+        let old = TG.client().get_chat(chat.get_id()).await?;
         let old = old.permissions.unwrap_or_else(|| {
             ChatPermissionsBuilder::new()
                 .set_can_send_messages(false)
@@ -1058,6 +2316,10 @@ impl Context {
 
         self.change_permissions_chat(user, chat, &new.build(), None)
             .await?;
+        publish(ModEvent::UserUnmuted {
+            chat: chat.get_id(),
+            user,
+        });
         Ok(())
     }
 
@@ -1079,6 +2341,11 @@ impl Context {
 
         self.change_permissions_chat(user, chat, &permissions, duration)
             .await?;
+        publish(ModEvent::UserMuted {
+            chat: chat.get_id(),
+            user,
+            until: duration.and_then(|v| Utc::now().checked_add_signed(v)),
+        });
         Ok(())
     }
 
@@ -1130,10 +2397,11 @@ impl Context {
     pub async fn change_permissions_message(
         &self,
         permissions: ChatPermissions,
+        default: Option<Duration>,
     ) -> Result<Option<i64>> {
         let me = self.clone();
         self.action_user(|ctx, user, args| async move {
-            let duration = ctx.parse_duration(&args)?;
+            let duration = ctx.parse_duration(&args)?.or(default);
             me.change_permissions(user, &permissions, duration).await?;
 
             Ok(())
@@ -1225,14 +2493,18 @@ impl Context {
             message.get_reply_to_message().and_then(|v| v.get_from()),
             message.get_reply_to_message(),
         ) {
+            let user = match bridged_author(message).await? {
+                Some(author) => author.get_id(),
+                None => user.get_id(),
+            };
             action(
                 self,
-                Some(user.get_id()),
+                Some(user),
                 args.map(|a| a.as_slice()),
                 ActionMessage::Reply(message),
             )
             .await?;
-            Ok(Some(user.get_id()))
+            Ok(Some(user))
         } else {
             match entities.front() {
                 Some(EntityArg::Mention(name)) => {
@@ -1314,8 +2586,23 @@ impl Context {
             dialog.warn_limit,
         )
         .await?;
+        if let Some(decay) = dialog.warn_decay.and_then(Duration::try_seconds) {
+            decay_warns(message.get_chat().get_id(), user, decay).await?;
+        }
 
-        if count >= dialog.warn_limit {
+        if count >= dialog.warn_limit && is_bridged_id(user) {
+            // bridged authors aren't telegram users, so they can't be muted or banned
+            let name = user.mention().await?;
+            message
+                .reply_fmt(entity_fmt!(
+                    self,
+                    "warnbridged",
+                    name,
+                    count.to_string(),
+                    dialog.warn_limit.to_string()
+                ))
+                .await?;
+        } else if count >= dialog.warn_limit {
             match dialog.action_type {
                 actions::ActionType::Mute => self.warn_mute(user, count, duration).await,
                 actions::ActionType::Ban => self.warn_ban(user, count, duration).await,
@@ -1367,26 +2654,26 @@ impl Context {
                             res.delete(*DB).await?;
                             REDIS.sq(|q| q.srem(&key, st)).await?;
                         }
-                        TG.client
+                        TG.client()
                             .build_edit_message_reply_markup()
                             .message_id(message.get_message_id())
                             .chat_id(chat.get_id())
                             .build()
                             .await?;
-                        TG.client
+                        TG.client()
                             .build_edit_message_text("Warn removed")
                             .message_id(message.get_message_id())
                             .chat_id(chat.get_id())
                             .build()
                             .await?;
-                        TG.client
+                        TG.client()
                             .build_answer_callback_query(cb.get_id())
                             .build()
                             .await?;
 
                         Ok(true)
                     } else {
-                        TG.client
+                        TG.client()
                             .build_answer_callback_query(cb.get_id())
                             .show_alert(true)
                             .text("User is not admin")
@@ -1413,6 +2700,10 @@ impl Context {
     pub async fn warn_ban(&self, user: i64, count: i32, duration: Option<Duration>) -> Result<()> {
         log::info!("warn_ban");
         let message = self.message()?;
+        let duration = match duration {
+            Some(duration) => Some(duration),
+            None => self.default_duration(ActionType::Ban).await?,
+        };
         self.ban(user, duration, true).await?;
         message
             .reply_fmt(entity_fmt!(
@@ -1425,6 +2716,46 @@ impl Context {
         Ok(())
     }
 
+    /// Records the message in this update in its sender's history, see record_message_history
+    pub async fn record_message_history(&self) -> Result<()> {
+        if let UpdateExt::Message(ref message) = self.update() {
+            record_message_history(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the command message along with the recent messages of a user, after a
+    /// silent action against them
+    async fn silent_cleanup(&self, user: i64) -> Result<()> {
+        let message = self.message()?;
+        message.delete().await?;
+        delete_message_history(message.get_chat().get_id(), user).await
+    }
+
+    /// Bans a user like ban, without announcing it and deleting their recent messages and
+    /// the command that banned them
+    pub async fn ban_silent(&self, user: i64, duration: Option<Duration>) -> Result<()> {
+        self.ban(user, duration, true).await?;
+        self.silent_cleanup(user).await
+    }
+
+    /// Mutes a user without announcing it, deleting their recent messages and the command
+    /// that muted them
+    pub async fn mute_silent(&self, user: i64, duration: Option<Duration>) -> Result<()> {
+        self.mute(user, self.message()?.get_chat(), duration)
+            .await
+            .silent()
+            .await?;
+        self.silent_cleanup(user).await
+    }
+
+    /// Kicks a user without announcing it, deleting their recent messages and the command
+    /// that kicked them
+    pub async fn kick_silent(&self, user: i64) -> Result<()> {
+        kick(user, self.message()?.get_chat().get_id()).await?;
+        self.silent_cleanup(user).await
+    }
+
     /// Bans a user in the given chat (from message), transparently handling anonymous channels.
     /// if a duration is specified. the ban will be lifted
     pub async fn ban(&self, user: i64, duration: Option<Duration>, silent: bool) -> Result<()> {
@@ -1462,7 +2793,8 @@ impl Context {
 
         if silent { err.silent().await } else { err }?;
 
-        if let Some(duration) = duration.and_then(|v| Utc::now().checked_add_signed(v)) {
+        let until = duration.and_then(|v| Utc::now().checked_add_signed(v));
+        if let Some(duration) = until {
             TG.client()
                 .build_ban_chat_member(message.get_chat().get_id(), user)
                 .until_date(duration.timestamp())
@@ -1474,6 +2806,11 @@ impl Context {
                 .build()
                 .await?;
         }
+        publish(ModEvent::UserBanned {
+            chat: message.get_chat().get_id(),
+            user,
+            until,
+        });
 
         let mention = user.mention().await?;
 
@@ -1522,6 +2859,21 @@ pub async fn warn_user(
                 .scard(&key)
         })
         .await?;
+    post_event(
+        chat_id,
+        ChatEvent::Warn {
+            user,
+            reason: model.reason.clone(),
+            count: count as i32,
+        },
+    )
+    .await?;
+    publish(ModEvent::UserWarned {
+        chat: chat_id,
+        user,
+        reason: model.reason.clone(),
+        count: count as i32,
+    });
 
     Ok((count as i32, Some(model)))
 }
@@ -1602,7 +2954,11 @@ pub trait FileGetter {
 #[async_trait]
 impl FileGetter for Document {
     async fn get_bytes(&self) -> Result<Bytes> {
-        let file = TG.client.build_get_file(self.get_file_id()).build().await?;
+        let file = TG
+            .client()
+            .build_get_file(self.get_file_id())
+            .build()
+            .await?;
         let path = file
             .get_file_path()
             .ok_or_else(|| BotError::Generic("Document file path missing".to_owned()))?;
@@ -1611,7 +2967,11 @@ impl FileGetter for Document {
     }
 
     async fn get_text(&self) -> Result<String> {
-        let file = TG.client.build_get_file(self.get_file_id()).build().await?;
+        let file = TG
+            .client()
+            .build_get_file(self.get_file_id())
+            .build()
+            .await?;
         let path = file
             .get_file_path()
             .ok_or_else(|| BotError::Generic("Docuemnt file path missing".to_owned()))?;
@@ -1620,7 +2980,7 @@ impl FileGetter for Document {
 }
 
 async fn get_file_body(path: &str) -> Result<Response> {
-    let path = format!("https://api.telegram.org/file/bot{}/{}", TG.token, path);
+    let path = format!("https://api.telegram.org/file/bot{}/{}", TG.token(), path);
     let body = reqwest::get(path).await.map_err(|err| err.without_url())?;
     Ok(body)
 }
@@ -1641,6 +3001,22 @@ pub async fn get_file_text(path: &str) -> Result<String> {
     Ok(text)
 }
 
+/// Download a file by id to disk a chunk at a time, so large files are never held in
+/// memory all at once
+pub async fn download_file(file_id: &str, dest: &std::path::Path) -> Result<()> {
+    let file = TG.client().build_get_file(file_id).build().await?;
+    let path = file
+        .get_file_path()
+        .ok_or_else(|| BotError::Generic("Document file path missing".to_owned()))?;
+    let mut body = get_file_body(path).await?;
+    let mut out = tokio::fs::File::create(dest).await?;
+    while let Some(chunk) = body.chunk().await? {
+        out.write_all(&chunk).await?;
+    }
+    out.flush().await?;
+    Ok(())
+}
+
 /// Sets the 'pending' flag on a stored action. Pending actions are applied the next time a user is seen
 /// actions without pending set are ignored
 pub async fn update_actions_pending(chat: &Chat, user: &User, pending: bool) -> Result<()> {
@@ -0,0 +1,98 @@
+//! Per-chat aliases for commands, so admins can give a command a name their members are
+//! used to. Aliases are resolved while parsing commands, so modules never see them
+
+use std::collections::HashMap;
+
+use chrono::Duration;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+
+use crate::persist::core::command_aliases;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::util::error::Result;
+
+/// Longest name an alias can have
+pub const MAX_ALIAS_LEN: usize = 32;
+
+#[inline(always)]
+fn get_aliases_key(chat: i64) -> String {
+    format!("calias:{}", chat)
+}
+
+/// Check that an alias could be typed as a command. Aliases are case insensitive and
+/// stored in lowercase
+pub fn parse_alias(name: &str) -> Option<String> {
+    let name = name.trim().to_lowercase();
+    (!name.is_empty()
+        && name.len() <= MAX_ALIAS_LEN
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_'))
+    .then_some(name)
+}
+
+/// Get every alias in a chat mapped to the command it runs
+pub async fn get_aliases(chat: i64) -> Result<HashMap<String, String>> {
+    let aliases = default_cache_query(
+        |_, _| async move {
+            let aliases = command_aliases::Entity::find()
+                .filter(command_aliases::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?
+                .into_iter()
+                .map(|v| (v.alias, v.command))
+                .collect::<HashMap<String, String>>();
+            Ok(Some(aliases))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_aliases_key(chat), &())
+    .await?;
+    Ok(aliases.unwrap_or_default())
+}
+
+/// Save an alias, replacing the command of an existing one with the same name
+pub async fn set_alias(chat: i64, alias: &str, command: &str) -> Result<()> {
+    command_aliases::Entity::insert(
+        command_aliases::Model {
+            chat,
+            alias: alias.to_owned(),
+            command: command.to_owned(),
+        }
+        .into_active_model(),
+    )
+    .on_conflict(
+        OnConflict::columns([
+            command_aliases::Column::Chat,
+            command_aliases::Column::Alias,
+        ])
+        .update_column(command_aliases::Column::Command)
+        .to_owned(),
+    )
+    .exec_without_returning(*DB)
+    .await?;
+    REDIS.invalidate(&[&get_aliases_key(chat)]).await?;
+    Ok(())
+}
+
+/// Remove an alias, returning false if it didn't exist
+pub async fn remove_alias(chat: i64, alias: &str) -> Result<bool> {
+    let res = command_aliases::Entity::delete_by_id((chat, alias.to_owned()))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_aliases_key(chat)]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn alias_names() {
+        assert_eq!(parse_alias("WarnBan"), Some("warnban".to_owned()));
+        assert_eq!(parse_alias("warn_ban2"), Some("warn_ban2".to_owned()));
+        assert_eq!(parse_alias("warn-ban"), None);
+        assert_eq!(parse_alias(""), None);
+        assert_eq!(parse_alias(&"a".repeat(MAX_ALIAS_LEN + 1)), None);
+    }
+}
@@ -0,0 +1,190 @@
+//! Opt-in sharing of ban signals between federations. Federations that publish have each
+//! fban recorded as a user id and category, without the reason or the federation it came
+//! from. Federations that consume can look up how many others flagged a user, as an
+//! advisory risk score only.
+
+use std::collections::BTreeMap;
+
+use chrono::{Duration, Utc};
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::persist::admin::ban_signals::{self, SignalCategory};
+use crate::persist::admin::{fbans, fed_sharing};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::util::error::Result;
+
+/// Signals from other federations about a single user
+#[derive(Debug, Default)]
+pub struct RiskScore {
+    /// number of federations that flagged the user
+    pub sources: usize,
+    pub categories: BTreeMap<SignalCategory, usize>,
+}
+
+impl RiskScore {
+    /// Categories with counts, most common first, "spam: 2, raid: 1"
+    pub fn describe(&self) -> String {
+        let mut categories = self.categories.iter().collect::<Vec<_>>();
+        categories.sort_by(|a, b| b.1.cmp(a.1));
+        categories
+            .into_iter()
+            .map(|(category, count)| format!("{}: {}", category.get_name(), count))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+}
+
+#[inline(always)]
+fn get_sharing_key(fed: &Uuid) -> String {
+    format!("fshare:{}", fed)
+}
+
+#[inline(always)]
+fn get_lookup_key(fed: &Uuid, hour: i64) -> String {
+    format!("frisk:{}:{}", fed, hour)
+}
+
+/// Salted hash identifying a federation in the signal table without storing its id
+fn signal_source(fed: &Uuid) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(CONFIG.federations.signal_salt.as_bytes());
+    hasher.update(fed.as_bytes());
+    hex::encode(&hasher.finalize()[..16])
+}
+
+/// Get a federation's sharing settings, None if it never opted in to anything
+pub async fn get_sharing(fed: &Uuid) -> Result<Option<fed_sharing::Model>> {
+    let key = get_sharing_key(fed);
+    let fed = *fed;
+    default_cache_query(
+        |_, _| async move {
+            let res = fed_sharing::Entity::find_by_id(fed).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await
+}
+
+/// Change whether a federation publishes signals or consumes risk scores. Leaving either
+/// as None keeps the current setting. Stopping publishing retracts everything the
+/// federation published
+pub async fn set_sharing(
+    fed: &Uuid,
+    publish: Option<bool>,
+    consume: Option<bool>,
+) -> Result<fed_sharing::Model> {
+    let model = fed_sharing::ActiveModel {
+        fed_id: Set(*fed),
+        publish: publish.map(Set).unwrap_or(NotSet),
+        consume: consume.map(Set).unwrap_or(NotSet),
+    };
+    let mut columns = Vec::new();
+    if publish.is_some() {
+        columns.push(fed_sharing::Column::Publish);
+    }
+    if consume.is_some() {
+        columns.push(fed_sharing::Column::Consume);
+    }
+    let model = fed_sharing::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(fed_sharing::Column::FedId)
+                .update_columns(columns)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+    if publish == Some(false) {
+        ban_signals::Entity::delete_many()
+            .filter(ban_signals::Column::Source.eq(signal_source(fed)))
+            .exec(*DB)
+            .await?;
+    }
+    model.cache(get_sharing_key(fed)).await?;
+    Ok(model)
+}
+
+/// Publish an fban as a signal if its federation opted in
+pub async fn publish_signal(fban: &fbans::Model) -> Result<()> {
+    if !get_sharing(&fban.federation)
+        .await?
+        .map(|v| v.publish)
+        .unwrap_or(false)
+    {
+        return Ok(());
+    }
+    let model = ban_signals::ActiveModel {
+        source: Set(signal_source(&fban.federation)),
+        user: Set(fban.user),
+        category: Set(SignalCategory::from_reason(fban.reason.as_deref())),
+        created: Set(Utc::now()),
+    };
+    ban_signals::Entity::insert(model)
+        .on_conflict(
+            OnConflict::columns([ban_signals::Column::Source, ban_signals::Column::User])
+                .update_columns([ban_signals::Column::Category, ban_signals::Column::Created])
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+    Ok(())
+}
+
+/// Remove the signal a federation published for a user, if any
+pub async fn retract_signal(fed: &Uuid, user: i64) -> Result<()> {
+    ban_signals::Entity::delete_by_id((signal_source(fed), user))
+        .exec(*DB)
+        .await?;
+    Ok(())
+}
+
+/// Count a risk lookup against a federation's hourly limit, returning false if the
+/// limit was already reached
+pub async fn try_risk_lookup(fed: &Uuid) -> Result<bool> {
+    let key = get_lookup_key(fed, Utc::now().timestamp() / 3600);
+    let (count, _): (u64, bool) = REDIS.pipe(|q| q.incr(&key, 1).expire(&key, 3600)).await?;
+    Ok(count <= CONFIG.federations.risk_lookups_per_hour)
+}
+
+/// Get the signals other federations published about a user. The asking federation's own
+/// signal is left out
+pub async fn get_risk_score(fed: &Uuid, user: i64) -> Result<RiskScore> {
+    let signals = ban_signals::Entity::find()
+        .filter(ban_signals::Column::User.eq(user))
+        .filter(ban_signals::Column::Source.ne(signal_source(fed)))
+        .all(*DB)
+        .await?;
+    let mut score = RiskScore {
+        sources: signals.len(),
+        ..Default::default()
+    };
+    for signal in signals {
+        *score.categories.entry(signal.category).or_insert(0) += 1;
+    }
+    Ok(score)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn category_from_reason() {
+        assert_eq!(
+            SignalCategory::from_reason(Some("crypto #Scam bot")),
+            SignalCategory::Scam
+        );
+        assert_eq!(
+            SignalCategory::from_reason(Some("spam")),
+            SignalCategory::Other
+        );
+        assert_eq!(SignalCategory::from_reason(None), SignalCategory::Other);
+    }
+}
@@ -0,0 +1,176 @@
+//! Relay bots that bridge other chat services (Matrix, Discord, IRC) into a chat post
+//! everyone's messages as themselves, with the real author's name in a prefix. Chats can
+//! register these bots along with a regex for the prefix, so warns and stats apply to the
+//! person who wrote the message instead of the bridge.
+//!
+//! Bridged authors don't have telegram accounts, so each one gets a stable id above any
+//! real telegram user id, derived from the bridge and the author's name. They are cached
+//! as users like anyone else, but can't be banned or muted since telegram doesn't know them
+
+use botapi::gen_types::{Message, User, UserBuilder};
+use chrono::Duration;
+use dashmap::DashMap;
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use sha2::{Digest, Sha256};
+
+use crate::persist::core::bridge_bots;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::util::error::{BotError, Result};
+
+use super::user::record_cache_user;
+
+/// Prefix used by most bridges when no pattern is given, "[name] text" or "<name>: text"
+pub const DEFAULT_PATTERN: &str = r"^[\[<](?P<name>[^\]>\n]{1,64})[\]>]:?\s*";
+
+/// Telegram user ids have at most 52 significant bits, ids for bridged authors start above
+const BRIDGED_ID_BASE: i64 = 1 << 53;
+
+const MAX_PATTERN_SIZE: usize = 1 << 16;
+
+lazy_static! {
+    static ref PATTERNS: DashMap<String, Regex> = DashMap::new();
+}
+
+#[inline(always)]
+fn get_bridge_key(chat: i64, bot: i64) -> String {
+    format!("bridge:{}:{}", chat, bot)
+}
+
+/// Compile a prefix pattern, checking that it captures the author's name
+pub fn compile_pattern(pattern: &str) -> Result<Regex> {
+    if let Some(regex) = PATTERNS.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = RegexBuilder::new(pattern)
+        .size_limit(MAX_PATTERN_SIZE)
+        .build()
+        .map_err(BotError::generic)?;
+    if !regex.capture_names().any(|v| v == Some("name")) {
+        return Err(BotError::generic("bridge patterns need a group named name"));
+    }
+    PATTERNS.insert(pattern.to_owned(), regex.clone());
+    Ok(regex)
+}
+
+/// Get the id used for an author relayed by a bridge bot
+pub fn bridged_id(bot: i64, name: &str) -> i64 {
+    let hash = Sha256::digest(format!("{}:{}", bot, name).as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    BRIDGED_ID_BASE | (i64::from_le_bytes(bytes) & (BRIDGED_ID_BASE - 1))
+}
+
+/// Returns true if a user id belongs to a bridged author rather than a telegram user
+pub fn is_bridged_id(user: i64) -> bool {
+    user >= BRIDGED_ID_BASE
+}
+
+/// Get a chat's settings for a bridge bot, if the bot is registered as one
+pub async fn get_bridge(chat: i64, bot: i64) -> Result<Option<bridge_bots::Model>> {
+    default_cache_query(
+        |_, _| async move {
+            let res = bridge_bots::Entity::find_by_id((chat, bot))
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_bridge_key(chat, bot), &())
+    .await
+}
+
+/// Register a bridge bot in a chat, or change its pattern
+pub async fn set_bridge(chat: i64, bot: i64, pattern: &str) -> Result<()> {
+    compile_pattern(pattern)?;
+    let model = bridge_bots::Entity::insert(bridge_bots::ActiveModel {
+        chat: Set(chat),
+        bot: Set(bot),
+        pattern: Set(pattern.to_owned()),
+    })
+    .on_conflict(
+        OnConflict::columns([bridge_bots::Column::Chat, bridge_bots::Column::Bot])
+            .update_column(bridge_bots::Column::Pattern)
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_bridge_key(chat, bot)).await?;
+    Ok(())
+}
+
+/// Stop treating a bot as a bridge, returning false if it wasn't one
+pub async fn delete_bridge(chat: i64, bot: i64) -> Result<bool> {
+    let res = bridge_bots::Entity::delete_by_id((chat, bot))
+        .exec(*DB)
+        .await?;
+    let key = get_bridge_key(chat, bot);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+/// List the bridge bots registered in a chat
+pub async fn get_bridges(chat: i64) -> Result<Vec<bridge_bots::Model>> {
+    let res = bridge_bots::Entity::find()
+        .filter(bridge_bots::Column::Chat.eq(chat))
+        .all(*DB)
+        .await?;
+    Ok(res)
+}
+
+fn parse_author(regex: &Regex, text: &str) -> Option<String> {
+    regex
+        .captures(text)
+        .and_then(|v| v.name("name"))
+        .map(|v| v.as_str().trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+/// Get the real author of a message relayed by a bridge bot. Returns None if the message
+/// wasn't sent by a registered bridge or the prefix doesn't match
+pub async fn bridged_author(message: &Message) -> Result<Option<User>> {
+    let Some(bot) = message.get_from().filter(|v| v.get_is_bot()) else {
+        return Ok(None);
+    };
+    let Some(bridge) = get_bridge(message.get_chat().get_id(), bot.get_id()).await? else {
+        return Ok(None);
+    };
+    let Some(text) = message.get_text().or_else(|| message.get_caption()) else {
+        return Ok(None);
+    };
+    let regex = compile_pattern(&bridge.pattern)?;
+    let Some(name) = parse_author(&regex, text) else {
+        return Ok(None);
+    };
+    let user = UserBuilder::new(bridged_id(bot.get_id(), &name), false, name).build();
+    record_cache_user(&user).await?;
+    Ok(Some(user))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_pattern() {
+        let regex = compile_pattern(DEFAULT_PATTERN).unwrap();
+        assert_eq!(parse_author(&regex, "[alice] hi").as_deref(), Some("alice"));
+        assert_eq!(parse_author(&regex, "<bob>: hi").as_deref(), Some("bob"));
+        assert_eq!(parse_author(&regex, "no prefix"), None);
+        assert!(compile_pattern("^(?P<user>.+):").is_err());
+    }
+
+    #[test]
+    fn ids_never_collide_with_telegram() {
+        let id = bridged_id(1234, "alice");
+        assert!(is_bridged_id(id));
+        assert_eq!(id, bridged_id(1234, "alice"));
+        assert_ne!(id, bridged_id(1234, "bob"));
+        assert!(!is_bridged_id((1 << 52) - 1));
+    }
+}
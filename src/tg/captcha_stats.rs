@@ -0,0 +1,187 @@
+//! Counters for captcha outcomes per chat and globally. Outcomes are also counted over a
+//! short window so a spike in failed captchas, usually a bot raid, can be reported to
+//! the chat's log channel
+
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use macros::lang_fmt;
+use redis::AsyncCommands;
+
+use crate::persist::metrics::CAPTCHA_EVENTS;
+use crate::statics::{CONFIG, REDIS};
+use crate::util::error::Result;
+use crate::util::string::get_chat_lang;
+
+use super::admin_helpers::post_log;
+use super::user::GetChat;
+
+const GLOBAL_KEY: &str = "cstats:global";
+
+/// Outcome of a single captcha
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptchaEvent {
+    Presented,
+    Solved,
+    Failed,
+    Timeout,
+}
+
+impl CaptchaEvent {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Presented => "presented",
+            Self::Solved => "solved",
+            Self::Failed => "failed",
+            Self::Timeout => "timeout",
+        }
+    }
+}
+
+/// Captcha outcome totals for a chat or for all chats
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CaptchaStats {
+    pub presented: u64,
+    pub solved: u64,
+    pub failed: u64,
+    pub timeout: u64,
+}
+
+impl From<HashMap<String, u64>> for CaptchaStats {
+    fn from(value: HashMap<String, u64>) -> Self {
+        let get = |event: CaptchaEvent| value.get(event.get_name()).copied().unwrap_or(0);
+        Self {
+            presented: get(CaptchaEvent::Presented),
+            solved: get(CaptchaEvent::Solved),
+            failed: get(CaptchaEvent::Failed),
+            timeout: get(CaptchaEvent::Timeout),
+        }
+    }
+}
+
+#[inline(always)]
+fn stats_key(chat: i64) -> String {
+    format!("cstats:{}", chat)
+}
+
+#[inline(always)]
+fn window_key(chat: i64, window: u64) -> String {
+    format!("cwin:{}:{}", chat, window)
+}
+
+#[inline(always)]
+fn alert_key(chat: i64, window: u64) -> String {
+    format!("calert:{}:{}", chat, window)
+}
+
+fn window_seconds() -> u64 {
+    (CONFIG.captcha_alerts.window_minutes.max(1) * 60) as u64
+}
+
+fn current_window() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_secs() / window_seconds())
+        .unwrap_or(0)
+}
+
+/// Returns true if enough captchas were failed in a window to be considered a raid
+fn is_spike(presented: u64, failures: u64, min_presented: u64, failure_rate: f64) -> bool {
+    presented >= min_presented.max(1) && failures as f64 / presented as f64 >= failure_rate
+}
+
+/// Count a captcha outcome for a chat, alerting the log channel if failures spike
+pub async fn record_captcha_event(chat: i64, event: CaptchaEvent) -> Result<()> {
+    CAPTCHA_EVENTS.with_label_values(&[event.get_name()]).inc();
+    let key = stats_key(chat);
+    let window = current_window();
+    let wkey = window_key(chat, window);
+    let name = event.get_name();
+    let (window_stats,): (HashMap<String, u64>,) = REDIS
+        .pipe(|q| {
+            q.hincr(&key, name, 1)
+                .ignore()
+                .hincr(GLOBAL_KEY, name, 1)
+                .ignore()
+                .hincr(&wkey, name, 1)
+                .ignore()
+                .expire(&wkey, (window_seconds() * 2) as i64)
+                .ignore()
+                .hgetall(&wkey)
+        })
+        .await?;
+
+    if matches!(event, CaptchaEvent::Failed | CaptchaEvent::Timeout) {
+        let stats = CaptchaStats::from(window_stats);
+        let failures = stats.failed + stats.timeout;
+        if is_spike(
+            stats.presented,
+            failures,
+            CONFIG.captcha_alerts.min_presented,
+            CONFIG.captcha_alerts.failure_rate,
+        ) {
+            let akey = alert_key(chat, window);
+            let (first,): (bool,) = REDIS
+                .pipe(|q| {
+                    q.set_nx(&akey, true)
+                        .expire(&akey, window_seconds() as i64)
+                        .ignore()
+                })
+                .await?;
+            if first {
+                if let Some(c) = chat.get_chat().await? {
+                    let lang = get_chat_lang(chat).await?;
+                    post_log(
+                        &c,
+                        lang_fmt!(
+                            lang,
+                            "captcharaid",
+                            failures,
+                            stats.presented,
+                            CONFIG.captcha_alerts.window_minutes
+                        ),
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get captcha outcome totals for a chat
+pub async fn get_captcha_stats(chat: i64) -> Result<CaptchaStats> {
+    let key = stats_key(chat);
+    let stats: HashMap<String, u64> = REDIS.sq(|q| q.hgetall(&key)).await?;
+    Ok(stats.into())
+}
+
+/// Get captcha outcome totals across all chats
+pub async fn get_global_captcha_stats() -> Result<CaptchaStats> {
+    let stats: HashMap<String, u64> = REDIS.sq(|q| q.hgetall(GLOBAL_KEY)).await?;
+    Ok(stats.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spike_threshold() {
+        assert!(!is_spike(5, 5, 10, 0.5));
+        assert!(!is_spike(20, 9, 10, 0.5));
+        assert!(is_spike(20, 10, 10, 0.5));
+        assert!(!is_spike(0, 0, 0, 0.0));
+    }
+
+    #[test]
+    fn stats_from_hash() {
+        let mut map = HashMap::new();
+        map.insert("presented".to_owned(), 4);
+        map.insert("timeout".to_owned(), 1);
+        let stats = CaptchaStats::from(map);
+        assert_eq!(stats.presented, 4);
+        assert_eq!(stats.solved, 0);
+        assert_eq!(stats.timeout, 1);
+    }
+}
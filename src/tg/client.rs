@@ -3,19 +3,32 @@
 //! command handler as well. Due to rust async limitations with the borrow checker this type
 //! is most useful from a static context only
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use super::{
-    admin_helpers::is_dm,
+    admin_helpers::{auto_delete_response, is_dm},
     button::InlineKeyboardBuilder,
-    command::{Context, TextArgs},
+    command::{Context, OwnedTextArgs, TextArgs},
+    connection::{is_redirected, origin_of, with_connection},
     dialog::{dialog_from_update, Conversation, ConversationState},
+    inline::answer_inline_query,
+    listener::listen,
+    payments::handle_payment_update,
     permissions::*,
-    user::RecordUser,
+    ratelimit::is_rate_limited,
+    template::ChatTemplate,
+    user::{GetChat, RecordUser},
 };
 use crate::{
-    metadata::{markdownify, Metadata},
+    metadata::{
+        markdownify, CommandLevel, CommandPolicy, Metadata, Module, UpdateKind, API_VERSION,
+        MIN_API_VERSION,
+    },
     modules,
+    persist::{
+        metrics::{count_update, meter_module},
+        redis::CACHE_BUS,
+    },
     tg::{
         admin_helpers::IntoChatUser,
         command::{post_deep_link, PopSlice},
@@ -28,16 +41,17 @@ use crate::{
     },
 };
 use crate::{
-    statics::{CONFIG, ME, TG},
-    util::error::Result,
+    statics::{module_enabled, spawn, CONFIG, ME, SHARDS, TG},
+    util::error::{BotError, Result},
     util::string::get_chat_lang,
 };
+use arc_swap::ArcSwap;
 use botapi::{
     bot::{ApiError, Bot, BotBuilder},
-    ext::{BotUrl, LongPoller, Webhook},
+    ext::LongPoller,
     gen_types::{
-        CallbackQuery, InlineKeyboardButton, InlineKeyboardButtonBuilder,
-        LinkPreviewOptionsBuilder, Message, ReplyParametersBuilder, UpdateExt,
+        CallbackQuery, Chat, InlineKeyboardButton, InlineKeyboardButtonBuilder,
+        LinkPreviewOptionsBuilder, Message, ReplyParametersBuilder, UpdateExt, User,
     },
 };
 use convert_case::Case;
@@ -45,30 +59,84 @@ use convert_case::Casing;
 use dashmap::DashMap;
 use futures::{future::BoxFuture, Future, StreamExt};
 use macros::{lang_fmt, message_fmt};
+use sea_orm_migration::MigrationTrait;
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
+use tokio::sync::Notify;
 
 static INVALID: &str = "invalid";
 
+/// Most modules listed on one page of the help menu
+const HELP_PAGE_SIZE: usize = 12;
+
+/// Most messages deleted with a single deleteMessages call
+const DELETE_BATCH: usize = 100;
+
+/// Pause between deleteMessages calls to the same chat, to stay clear of flood limits
+const DELETE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
 /// List of module info for populating bot help
 #[derive(Debug)]
 pub struct MetadataCollection(HashMap<String, Arc<Metadata>>);
 
 impl MetadataCollection {
-    fn get_module_text(&self, module: &str) -> String {
+    /// Iterate over the metadata for all loaded modules
+    pub fn iter(&self) -> impl Iterator<Item = &'_ Metadata> {
+        self.0.values().map(|v| v.as_ref())
+    }
+
+    /// Get the name of the module providing a command, if any
+    pub fn module_for_command(&self, command: &str) -> Option<&'_ str> {
+        self.0
+            .values()
+            .find(|v| v.commands.contains_key(command))
+            .map(|v| v.name.as_str())
+    }
+
+    /// Get who a command is intended for, or None if no module provides it
+    pub fn command_level(&self, command: &str) -> Option<CommandLevel> {
+        self.0
+            .values()
+            .find(|v| v.commands.contains_key(command))
+            .map(|v| v.command_level(command))
+    }
+
+    /// Get the rights and cooldown required to use a command, if it declared any
+    pub fn command_policy(&self, command: &str) -> Option<&'_ CommandPolicy> {
+        self.0
+            .values()
+            .find(|v| v.commands.contains_key(command))
+            .and_then(|v| v.command_policy(command))
+    }
+
+    fn get_module_text(&self, module: &str, viewer: HelpViewer) -> String {
         self.0
             .get(module)
             .map(|v| {
                 let helps = v
                     .commands
                     .iter()
-                    .map(|(c, h)| format!("/{}: {}", c, markdownify(h)))
+                    .filter(|(c, _)| viewer.can_see(v.command_level(c)))
+                    .map(|(c, h)| match v.command_level(c) {
+                        CommandLevel::User => format!("/{}: {}", c, markdownify(h)),
+                        CommandLevel::Admin => {
+                            format!("/{}: {} [_admin only]", c, markdownify(h))
+                        }
+                        CommandLevel::Owner => {
+                            format!("/{}: {} [_owner only]", c, markdownify(h))
+                        }
+                    })
                     .collect::<Vec<String>>()
                     .join("\n");
 
+                let description = match v.long_help {
+                    Some(ref long_help) => format!("{}\n\n{}", v.description, long_help),
+                    None => v.description.clone(),
+                };
                 if !v.commands.is_empty() {
-                    format!("[*{}]:\n{}\n\nCommands:\n{}", v.name, v.description, helps)
+                    format!("[*{}]:\n{}\n\nCommands:\n{}", v.name, description, helps)
                 } else {
-                    format!("[*{}]\n{}", v.name, v.description)
+                    format!("[*{}]\n{}", v.name, description)
                 }
             })
             .unwrap_or_else(|| INVALID.to_owned())
@@ -78,6 +146,7 @@ impl MetadataCollection {
         &self,
         message: &Message,
         current: Option<String>,
+        viewer: HelpViewer,
     ) -> Result<Conversation> {
         let me = ME.get().unwrap();
 
@@ -94,69 +163,235 @@ impl MetadataCollection {
         )?;
 
         let start = state.get_start()?.state_id;
-        self.0.iter().for_each(|(_, n)| {
-            let s = state.add_state(self.get_module_text(&n.name));
-            state.add_transition(start, s, n.name.to_lowercase(), n.name.to_case(Case::Title));
-            state.add_transition(s, start, "back", "Back");
-            n.sections.iter().for_each(|(sub, content)| {
-                let sb = state.add_state(content);
-                state.add_transition(s, sb, sub.to_lowercase(), sub.to_case(Case::Title));
-                state.add_transition(sb, s, "back", "Back");
-            });
-        });
+        let mut categories: BTreeMap<&str, Vec<&Metadata>> = BTreeMap::new();
+        for module in self.iter() {
+            categories
+                .entry(module.category())
+                .or_default()
+                .push(module);
+        }
+
+        // module and category names to the state showing them, for jumping straight there
+        let mut targets = HashMap::new();
+        for (category, mut modules) in categories {
+            modules.sort_by(|a, b| a.name.cmp(&b.name));
+            let chunks = modules.chunks(HELP_PAGE_SIZE).collect::<Vec<_>>();
+            let pages = chunks
+                .iter()
+                .enumerate()
+                .map(|(page, _)| {
+                    state.add_state(lang_fmt!(
+                        lang,
+                        "helpcategory",
+                        category,
+                        page + 1,
+                        chunks.len()
+                    ))
+                })
+                .collect::<Vec<_>>();
+            state.add_transition(
+                start,
+                pages[0],
+                category.to_lowercase(),
+                category.to_owned(),
+            );
+            targets.insert(category.to_lowercase(), pages[0]);
+            for (idx, (page, chunk)) in pages.iter().zip(chunks).enumerate() {
+                state.add_transition(*page, start, "back", "Back");
+                if idx > 0 {
+                    state.add_transition(*page, pages[idx - 1], "prev", "<");
+                }
+                if let Some(next) = pages.get(idx + 1) {
+                    state.add_transition(*page, *next, "next", ">");
+                }
+                for n in chunk {
+                    let s = state.add_state(self.get_module_text(&n.name, viewer));
+                    state.add_transition(
+                        *page,
+                        s,
+                        n.name.to_lowercase(),
+                        n.name.to_case(Case::Title),
+                    );
+                    state.add_transition(s, *page, "back", "Back");
+                    targets.insert(n.name.to_lowercase(), s);
+                    n.sections.iter().for_each(|(sub, content)| {
+                        let sb = state.add_state(content);
+                        state.add_transition(s, sb, sub.to_lowercase(), sub.to_case(Case::Title));
+                        state.add_transition(sb, s, "back", "Back");
+                    });
+                }
+            }
+        }
 
         let conversation = state.build();
         conversation.write_self().await?;
         if let Some(mut current) = current {
             for (module, v) in self.0.iter() {
-                // log::info!("checking {:?}", v.commands);
                 if v.commands.contains_key(&current) {
                     current = module.to_lowercase();
                     break;
                 }
             }
-            conversation.transition(current).await?;
+            let target = targets
+                .get(&current)
+                .ok_or_else(|| BotError::conversation_err("invalid choice"))?;
+            conversation.write_key(*target).await?;
         }
         Ok(conversation)
     }
 }
 
+/// Privileges of the user viewing help. Admin commands are only hidden if we know
+/// which chat the user is asking about, otherwise they are just marked
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct HelpViewer {
+    level: CommandLevel,
+    in_chat: bool,
+}
+
+impl HelpViewer {
+    /// Get the privileges of a user, checking the cached admin list of the chat they
+    /// came from if known
+    async fn get(user: &User, origin: Option<i64>) -> Result<Self> {
+        if CONFIG.admin.sudo_users.contains(&user.get_id())
+            || CONFIG.admin.support_users.contains(&user.get_id())
+        {
+            return Ok(Self {
+                level: CommandLevel::Owner,
+                in_chat: origin.is_some(),
+            });
+        }
+        let chat = if let Some(origin) = origin {
+            origin.get_chat().await?
+        } else {
+            None
+        };
+        let viewer = if let Some(chat) = chat {
+            let level = if chat.is_user_admin(user.get_id()).await?.is_some() {
+                CommandLevel::Admin
+            } else {
+                CommandLevel::User
+            };
+            Self {
+                level,
+                in_chat: true,
+            }
+        } else {
+            Self {
+                level: CommandLevel::User,
+                in_chat: false,
+            }
+        };
+        Ok(viewer)
+    }
+
+    fn can_see(&self, command: CommandLevel) -> bool {
+        match command {
+            CommandLevel::User => true,
+            CommandLevel::Admin => !self.in_chat || self.level >= CommandLevel::Admin,
+            CommandLevel::Owner => self.level == CommandLevel::Owner,
+        }
+    }
+}
+
+/// Help arguments stored behind a deep link along with the chat help was requested from
+#[derive(Serialize)]
+struct HelpLink<'a> {
+    args: &'a TextArgs<'a>,
+    chat: i64,
+}
+
+/// Owned version of HelpLink, retrieved when the deep link is opened
+#[derive(Deserialize)]
+pub struct OwnedHelpLink {
+    pub args: OwnedTextArgs,
+    pub chat: i64,
+}
+
 pub type UpdateCallback =
     Arc<dyn for<'b> Fn(&'b Context) -> BoxFuture<'b, Result<()>> + Send + Sync>;
 
-/// wrapper around a function that is called once for every update received by the bot
-pub struct UpdateHandler(Option<UpdateCallback>);
+/// Name resource usage of the custom update handler is recorded under
+const EXTERNAL_MODULE: &str = "external";
 
-impl UpdateHandler {
-    pub(crate) async fn handle_update(&self, ctx: &Context) {
-        if let Some(ref custom) = self.0 {
-            if let Err(err) = custom(ctx).await {
-                log::warn!("failed to process update from custom handler {:?}", err);
-                err.record_stats();
-                match err.get_message().await {
-                    Err(err) => {
-                        log::warn!("failed to send error message: {}, what the FLOOP", err);
-                        err.record_stats();
-                    }
-                    Ok(v) => {
-                        if !v {
-                            if let Some(chat) = ctx.chat() {
-                                if let Err(err) = chat.reply(err.to_string()).await {
-                                    log::warn!("triple fault! {}", err);
-                                }
-                            }
+/// A module registered with DijkstraOpts::register_module, along with its metadata
+#[derive(Clone)]
+pub(crate) struct ExternalModule {
+    metadata: Arc<Metadata>,
+    module: Arc<dyn Module>,
+}
 
-                            log::warn!("handle_update custom error: {}", err);
-                        }
+/// wrapper around a function that is called once for every update received by the bot,
+/// and the external modules registered with the bot
+pub struct UpdateHandler {
+    custom: Option<UpdateCallback>,
+    modules: Vec<ExternalModule>,
+}
+
+/// Commands sent from a connected private chat are only handled by the module owning the
+/// command, other modules would mistake them for messages sent in the group
+pub(crate) fn receives_redirected(ctx: &Context, metadata: &Metadata) -> bool {
+    !is_redirected()
+        || ctx
+            .cmd()
+            .map(|v| metadata.commands.contains_key(v.cmd))
+            .unwrap_or(false)
+}
+
+/// Log an error returned by a custom handler or external module and tell the user about it
+async fn report_handler_error(name: &str, ctx: &Context, err: BotError) {
+    log::warn!("failed to process update from {} {:?}", name, err);
+    err.record_stats();
+    match err.get_message().await {
+        Err(err) => {
+            log::warn!("failed to send error message: {}, what the FLOOP", err);
+            err.record_stats();
+        }
+        Ok(v) => {
+            if !v {
+                // commands sent from a connected private chat report errors there
+                let origin = ctx.message().ok().and_then(origin_of);
+                let chat = origin.as_ref().map(|v| v.get_chat()).or_else(|| ctx.chat());
+                if let Some(chat) = chat {
+                    if let Err(err) = chat.reply(err.to_string()).await {
+                        log::warn!("triple fault! {}", err);
                     }
                 }
+
+                log::warn!("handle_update {} error: {}", name, err);
+            }
+        }
+    }
+}
+
+impl UpdateHandler {
+    pub(crate) async fn handle_update(&self, ctx: &Context) {
+        if let Some(ref custom) = self.custom {
+            if let Err(err) = meter_module(EXTERNAL_MODULE, custom(ctx)).await {
+                report_handler_error(EXTERNAL_MODULE, ctx, err).await;
+            }
+        }
+        let kind = UpdateKind::of(ctx.update());
+        for external in self.modules.iter() {
+            let name = &external.metadata.name;
+            if !module_enabled(name)
+                || !external.metadata.updates.contains(kind)
+                || !receives_redirected(ctx, &external.metadata)
+            {
+                continue;
+            }
+            if let Err(err) = meter_module(name, external.module.handle_update(ctx)).await {
+                report_handler_error(name, ctx, err).await;
             }
         }
     }
 
     /// Construct a new update handler without a contained function. This handler does nothing.
     pub fn new() -> Self {
-        Self(None)
+        Self {
+            custom: None,
+            modules: Vec::new(),
+        }
     }
 
     /// Set the update handler function
@@ -164,13 +399,89 @@ impl UpdateHandler {
     where
         F: for<'b> Fn(&'b Context) -> BoxFuture<'b, Result<()>> + Send + Sync + 'static,
     {
-        self.0 = Some(Arc::new(func));
+        self.custom = Some(Arc::new(func));
         self
     }
 
+    /// Add an external module, called for updates after the custom handler
+    pub(crate) fn module<T: Module>(mut self, module: T) -> Self {
+        self.modules.push(ExternalModule {
+            metadata: Arc::new(module.metadata()),
+            module: Arc::new(module),
+        });
+        self
+    }
+
+    /// Drop external modules built against an API version this bot can't load. Called
+    /// once at startup, before any module is started
+    pub(crate) fn retain_compatible(&mut self) {
+        self.modules.retain(|v| {
+            let compatible = v.metadata.is_compatible();
+            if !compatible {
+                log::error!(
+                    "refusing to load module {}: built against api version {}, supported {}-{}",
+                    v.metadata.name,
+                    v.metadata.api_version,
+                    MIN_API_VERSION,
+                    API_VERSION
+                );
+            }
+            compatible
+        });
+    }
+
     /// returns true if the UpdateHandler contains a function
     pub fn has_handler(&self) -> bool {
-        self.0.is_some()
+        self.custom.is_some()
+    }
+
+    /// Help menu entries of the enabled external modules
+    pub(crate) fn get_metadata(&self) -> impl Iterator<Item = Metadata> + '_ {
+        self.modules
+            .iter()
+            .filter(|v| module_enabled(&v.metadata.name))
+            .map(|v| (*v.metadata).clone())
+    }
+
+    /// Migrations of every external module, enabled or not, so disabling a module never
+    /// leaves the schema behind
+    pub(crate) fn get_migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        self.modules
+            .iter()
+            .flat_map(|v| v.module.get_migrations())
+            .collect()
+    }
+
+    /// Register jobs and inline providers and start background tasks of the enabled
+    /// external modules
+    pub(crate) fn start_modules(&self) {
+        for external in self
+            .modules
+            .iter()
+            .filter(|v| module_enabled(&v.metadata.name))
+        {
+            external.module.register_jobs();
+            external.module.register_inline();
+            external.module.spawn_tasks();
+        }
+    }
+
+    /// Apply the default settings template to a new chat for each enabled external module
+    pub(crate) async fn apply_template(&self, chat: &Chat, template: &ChatTemplate) {
+        for external in self
+            .modules
+            .iter()
+            .filter(|v| module_enabled(&v.metadata.name))
+        {
+            if let Err(err) = external.module.apply_template(chat, template).await {
+                log::warn!(
+                    "failed to apply {} template: {}",
+                    external.metadata.name,
+                    err
+                );
+                err.record_stats();
+            }
+        }
     }
 }
 
@@ -182,7 +493,10 @@ impl Default for UpdateHandler {
 
 impl Clone for UpdateHandler {
     fn clone(&self) -> Self {
-        Self(self.0.clone())
+        Self {
+            custom: self.custom.clone(),
+            modules: self.modules.clone(),
+        }
     }
 }
 
@@ -196,20 +510,63 @@ impl std::fmt::Debug for UpdateHandler {
 /// Modular telegram client with long polling and webhook support
 #[derive(Debug)]
 pub struct TgClient {
-    pub client: Bot,
+    client: Arc<ArcSwap<Bot>>,
     pub modules: Arc<MetadataCollection>,
-    pub token: String,
+    token: Arc<ArcSwap<String>>,
+    rotated: Arc<Notify>,
     pub button_events: Arc<DashMap<String, SingleCb<CallbackQuery, Result<()>>>>,
     pub button_repeat: Arc<DashMap<String, MultiCb<CallbackQuery, Result<bool>>>>,
+    /// one lock per chat so deletes queue up instead of running in parallel
+    delete_queues: Arc<DashMap<i64, Arc<tokio::sync::Mutex<()>>>>,
     handler: UpdateHandler,
 }
 
+fn build_bot(token: &str) -> Bot {
+    BotBuilder::new(token.to_owned())
+        .unwrap()
+        .auto_wait(true)
+        .build()
+}
+
+/// Returns true if telegram rejected the bot token
+fn is_unauthorized(err: &BotError) -> bool {
+    match err {
+        BotError::ApiError(err) => err
+            .get_response()
+            .map(|v| v.error_code == Some(401))
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Periodically checks that telegram still accepts the bot token, switching to the
+/// backup token if it was revoked
+pub async fn run_token_check() {
+    if CONFIG.backup_bot_token.is_none() {
+        return;
+    }
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if let Err(err) = TG.client().get_me().await {
+            let err = BotError::from(err);
+            if is_unauthorized(&err) {
+                if let Err(err) = TG.failover().await {
+                    log::warn!("failed to switch to backup token: {}", err);
+                    err.record_stats();
+                }
+            }
+        }
+    }
+}
+
 /// Helper function to show the interactive help menu.
 pub(crate) async fn show_help<'a>(
     ctx: &Context,
     message: &Message,
     helps: Arc<MetadataCollection>,
     args_raw: &'a TextArgs<'a>,
+    origin: Option<i64>,
 ) -> Result<bool> {
     if !should_ignore_chat(message.get_chat().get_id()).await? {
         let lang = get_chat_lang(message.get_chat().get_id()).await?;
@@ -226,7 +583,11 @@ pub(crate) async fn show_help<'a>(
         if is_dm(message.get_chat()) {
             let me = ME.get().unwrap();
 
-            let conv = match helps.get_conversation(message, param.clone()).await {
+            let user = message
+                .get_from()
+                .ok_or_else(|| message.fail_err("User does not exist"))?;
+            let viewer = HelpViewer::get(user, origin).await?;
+            let conv = match helps.get_conversation(message, param.clone(), viewer).await {
                 Ok(v) => v,
                 Err(_) => {
                     message
@@ -283,7 +644,11 @@ pub(crate) async fn show_help<'a>(
                 .build()
                 .await?;
         } else {
-            let url = post_deep_link(args_raw, help_key).await?;
+            let link = HelpLink {
+                args: args_raw,
+                chat: message.get_chat().get_id(),
+            };
+            let url = post_deep_link(link, help_key).await?;
             let mut button = InlineKeyboardBuilder::default();
 
             button.button(
@@ -291,13 +656,16 @@ pub(crate) async fn show_help<'a>(
                     .set_url(url)
                     .build(),
             );
-            message_fmt!(ctx, "dmhelp")
+            let sent = message_fmt!(ctx, "dmhelp")
                 .reply_markup(&botapi::gen_types::EReplyMarkup::InlineKeyboardMarkup(
                     button.build(),
                 ))
                 .reply_parameters(&ReplyParametersBuilder::new(message.get_message_id()).build())
                 .build()
                 .await?;
+            if let Err(err) = auto_delete_response(Some(&sent)).await {
+                err.record_stats();
+            }
         }
     }
 
@@ -309,6 +677,21 @@ pub fn help_key(key: &str) -> String {
     format!("gethelp:{}", key)
 }
 
+/// With sharding enabled, record this process as the owner of a button callback so presses
+/// of the button are routed back to it
+fn claim_callback(data: &str) {
+    if !SHARDS.is_enabled() {
+        return;
+    }
+    let data = data.to_owned();
+    spawn(async move {
+        if let Err(err) = SHARDS.claim_callback(&data).await {
+            log::warn!("failed to claim button callback: {}", err);
+            err.record_stats();
+        }
+    });
+}
+
 impl TgClient {
     /// Register a button callback to be called when the corresponding callback button sends an update
     /// This callback will only fire once and be removed afterwards
@@ -318,6 +701,7 @@ impl TgClient {
         Fut: Future<Output = Result<()>> + Send + 'static,
     {
         if let Some(data) = button.get_callback_data() {
+            claim_callback(data);
             self.button_events
                 .insert(data.to_owned(), SingleCb::new(func));
         }
@@ -331,35 +715,29 @@ impl TgClient {
         Fut: Future<Output = Result<bool>> + Send + 'static,
     {
         if let Some(data) = button.get_callback_data() {
+            claim_callback(data);
             self.button_repeat
                 .insert(data.to_owned(), MultiCb::new(func));
         }
     }
 
+    /// Register jobs and start background tasks of external modules. Called once at startup
+    pub(crate) fn start_external_modules(&self) {
+        self.handler.start_modules();
+    }
+
+    /// Apply the default settings template to a new chat for external modules
+    pub(crate) async fn apply_external_template(&self, chat: &Chat, template: &ChatTemplate) {
+        self.handler.apply_template(chat, template).await;
+    }
+
     /// Creates a new client from a bot api token
     pub fn connect<T>(token: T) -> Self
     where
         T: Into<String>,
     {
         let metadata = modules::get_metadata();
-        let metadata = MetadataCollection(
-            metadata
-                .into_iter()
-                .map(|v| (v.name.clone(), Arc::new(v)))
-                .collect(),
-        );
-        let token = token.into();
-        Self {
-            client: BotBuilder::new(token.clone())
-                .unwrap()
-                .auto_wait(true)
-                .build(),
-            token,
-            modules: Arc::new(metadata),
-            button_events: Arc::new(DashMap::new()),
-            button_repeat: Arc::new(DashMap::new()),
-            handler: UpdateHandler(None),
-        }
+        Self::connect_mod(token, metadata, UpdateHandler::new())
     }
 
     /// Creates a new client from a bot api token
@@ -375,25 +753,95 @@ impl TgClient {
         );
         let token = token.into();
         Self {
-            client: BotBuilder::new(token.clone())
-                .unwrap()
-                .auto_wait(true)
-                .build(),
-            token,
+            client: Arc::new(ArcSwap::from_pointee(build_bot(&token))),
+            token: Arc::new(ArcSwap::from_pointee(token)),
+            rotated: Arc::new(Notify::new()),
             modules: Arc::new(metadata),
             button_events: Arc::new(DashMap::new()),
             button_repeat: Arc::new(DashMap::new()),
+            delete_queues: Arc::new(DashMap::new()),
             handler,
         }
     }
 
+    /// Switches to a new bot api token without restarting, for example after the
+    /// token was regenerated with BotFather. The token is checked with getMe before
+    /// being used and must belong to the same bot. Updates are fetched again using the
+    /// new token, setting up the webhook again if webhooks are enabled. Other processes
+    /// are told about the new token over the cache bus
+    pub async fn rotate_token<T>(&self, token: T) -> Result<User>
+    where
+        T: Into<String>,
+    {
+        let token = token.into();
+        let me = self.swap_token(token.clone()).await?;
+        if SHARDS.is_enabled() && !CACHE_BUS.is_enabled() {
+            log::warn!("cache bus is disabled, workers will keep using the old token");
+        }
+        CACHE_BUS.publish_token(&token).await;
+        Ok(me)
+    }
+
+    /// Switches to a token rotated by another process, unless it is already in use.
+    /// Unlike rotate_token this does not publish the token again
+    pub(crate) async fn receive_token(&self, token: String) -> Result<()> {
+        if token != self.token().as_str() {
+            self.swap_token(token).await?;
+        }
+        Ok(())
+    }
+
+    async fn swap_token(&self, token: String) -> Result<User> {
+        let client = build_bot(&token);
+        let me = client.get_me().await?;
+        if let Some(current) = ME.get() {
+            if current.get_id() != me.get_id() {
+                return Err(BotError::generic("token belongs to a different bot"));
+            }
+        }
+        self.client.store(Arc::new(client));
+        self.token.store(Arc::new(token));
+        self.rotated.notify_one();
+        log::info!("rotated bot token for {}", me.get_id());
+        Ok(me)
+    }
+
+    /// Switches to the backup token from the config if one is set and not already in
+    /// use. Returns false if there was nothing to switch to
+    pub async fn failover(&self) -> Result<bool> {
+        match CONFIG.backup_bot_token {
+            Some(ref backup) if backup != self.token().as_str() => {
+                log::warn!("bot token was rejected, switching to backup token");
+                self.rotate_token(backup.as_str()).await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Calls getMe, switching to the backup token if telegram rejects the current one
+    pub async fn get_me_or_failover(&self) -> Result<User> {
+        match self.client().get_me().await {
+            Ok(me) => Ok(me),
+            Err(err) => {
+                let err = BotError::from(err);
+                if is_unauthorized(&err) && self.failover().await? {
+                    Ok(self.client().get_me().await?)
+                } else {
+                    Err(err)
+                }
+            }
+        }
+    }
+
     /// Processes a single update from telegram
     async fn handle_update(&self, update: std::result::Result<UpdateExt, ApiError>) {
+        count_update();
         let modules = Arc::clone(&self.modules);
         let callbacks = Arc::clone(&self.button_events);
         let repeats = Arc::clone(&self.button_repeat);
         let custom_handler = self.handler.clone();
-        tokio::spawn(async move {
+        spawn(async move {
             match update {
                 Ok(UpdateExt::CallbackQuery(callbackquery)) => {
                     if let Some(data) = callbackquery.get_data() {
@@ -444,8 +892,31 @@ impl TgClient {
                         err.record_stats();
                     }
 
-                    if let Err(err) =
-                        crate::modules::process_updates(update, modules, custom_handler).await
+                    if let Err(err) = handle_payment_update(&update).await {
+                        log::warn!("failed to handle payment: {}", err);
+                        err.record_stats();
+                    }
+
+                    match is_rate_limited(&update).await {
+                        Ok(true) => return,
+                        Ok(false) => (),
+                        Err(err) => {
+                            log::warn!("failed to check rate limit: {}", err);
+                            err.record_stats();
+                        }
+                    }
+
+                    if let UpdateExt::InlineQuery(ref query) = update {
+                        if let Err(err) = answer_inline_query(query).await {
+                            log::warn!("failed to answer inline query: {}", err);
+                            err.record_stats();
+                        }
+                    }
+
+                    if let Err(err) = with_connection(update, |update| {
+                        crate::modules::process_updates(update, modules, custom_handler)
+                    })
+                    .await
                     {
                         log::warn!("process updates error: {}", err);
                         err.record_stats()
@@ -458,6 +929,33 @@ impl TgClient {
         });
     }
 
+    /// Sends an update to the worker for its shard, or handles it here if sharding is off
+    /// or it is a press of a button registered in this process
+    async fn route_update(&self, update: std::result::Result<UpdateExt, ApiError>) {
+        match update {
+            Ok(update) if SHARDS.is_enabled() => match SHARDS.route(&update).await {
+                Ok(true) => (),
+                Ok(false) => self.handle_update(Ok(update)).await,
+                Err(err) => {
+                    // handling it out of order is better than losing it
+                    log::warn!("failed to route update, handling it here: {}", err);
+                    err.record_stats();
+                    self.handle_update(Ok(update)).await;
+                }
+            },
+            update => self.handle_update(update).await,
+        }
+    }
+
+    /// Handles updates routed to one shard by the process receiving them from telegram,
+    /// forever
+    pub async fn run_worker(&self, shard: u32) -> Result<()> {
+        log::info!("run worker {}", shard);
+        SHARDS
+            .consume(shard, |update| self.handle_update(Ok(update)))
+            .await
+    }
+
     /// Handles updates from telegram forever either using webhooks or long polling
     /// depending on toml config
     pub async fn run(&self) -> Result<()> {
@@ -484,56 +982,144 @@ impl TgClient {
             .map(|v| v.to_owned())
             .collect(),
         );
-        match CONFIG.webhook.enable_webhook {
-            false => {
-                self.client
-                    .build_delete_webhook()
-                    .drop_pending_updates(true) // TODO: change this
-                    .build()
-                    .await?;
-                LongPoller::new(&self.client, updates)
-                    .get_updates()
-                    .await
-                    .for_each_concurrent(
-                        None,
-                        |update| async move { self.handle_update(update).await },
-                    )
-                    .await
-            }
-            true => {
-                Webhook::new(
-                    &self.client,
-                    BotUrl::Host(CONFIG.webhook.webhook_url.to_owned()),
-                    false,
-                    CONFIG.webhook.listen.to_owned(),
-                    updates,
-                )
-                .get_updates()
-                .await?
-                .for_each_concurrent(
-                    None,
-                    |update| async move { self.handle_update(update).await },
-                )
-                .await
+        // updates queued while the bot was down are dropped on startup, but not the ones
+        // that arrive while switching to a rotated token
+        let mut restarted = false;
+        loop {
+            let client = self.client();
+            let updates = updates.clone();
+            let poll = async move {
+                match CONFIG.webhook.enable_webhook {
+                    false => {
+                        client
+                            .build_delete_webhook()
+                            .drop_pending_updates(!restarted)
+                            .build()
+                            .await?;
+                        LongPoller::new(&client, updates)
+                            .get_updates()
+                            .await
+                            .for_each_concurrent(None, |update| async move {
+                                self.route_update(update).await
+                            })
+                            .await
+                    }
+                    true => {
+                        let (updates, server) = listen(&client, updates).await?;
+                        let handle = updates.for_each_concurrent(None, |update| async move {
+                            self.route_update(Ok(update)).await
+                        });
+                        futures::join!(server, handle);
+                    }
+                }
+                Ok::<(), BotError>(())
+            };
+            tokio::select! {
+                res = poll => return res,
+                _ = self.rotated.notified() => {
+                    log::info!("restarting updates with rotated token");
+                    restarted = true;
+                }
             }
         }
-        Ok(())
     }
 
-    pub fn client(&self) -> &'_ Bot {
-        &self.client
+    /// Get the bot api client for the current token. Clients replaced by rotate_token
+    /// are dropped once the last request made with them finishes
+    pub fn client(&self) -> Arc<Bot> {
+        self.client.load_full()
+    }
+
+    /// Get the current bot api token
+    pub fn token(&self) -> Arc<String> {
+        self.token.load_full()
+    }
+
+    /// Delete messages in a chat in batches of DELETE_BATCH. Deletes in the same chat are
+    /// queued behind each other with a pause between batches, so large purges don't run into
+    /// flood limits. progress is called with the number of messages handled after each batch
+    pub async fn delete_messages_queued<F, Fut>(
+        &self,
+        chat: i64,
+        messages: &[i64],
+        mut progress: F,
+    ) -> Result<()>
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let queue = self
+            .delete_queues
+            .entry(chat)
+            .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(())))
+            .clone();
+        let res = async {
+            let _lock = queue.lock().await;
+            let mut done = 0;
+            for (i, batch) in messages.chunks(DELETE_BATCH).enumerate() {
+                if i > 0 {
+                    tokio::time::sleep(DELETE_INTERVAL).await;
+                }
+                self.client()
+                    .build_delete_messages(chat, &batch.to_vec())
+                    .build()
+                    .await?;
+                done += batch.len();
+                progress(done).await?;
+            }
+            Ok(())
+        }
+        .await;
+        // drop the lock once nobody else is waiting on it
+        self.delete_queues
+            .remove_if(&chat, |_, v| Arc::strong_count(v) <= 2);
+        res
     }
 }
 
 impl Clone for TgClient {
     fn clone(&self) -> Self {
         TgClient {
-            token: self.token.clone(),
-            client: self.client.clone(),
+            token: Arc::clone(&self.token),
+            client: Arc::clone(&self.client),
+            rotated: Arc::clone(&self.rotated),
             modules: Arc::clone(&self.modules),
             button_events: Arc::clone(&self.button_events),
             button_repeat: Arc::clone(&self.button_repeat),
-            handler: UpdateHandler(self.handler.0.clone()),
+            delete_queues: Arc::clone(&self.delete_queues),
+            handler: self.handler.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn help_visibility() {
+        let unknown = HelpViewer {
+            level: CommandLevel::User,
+            in_chat: false,
+        };
+        let member = HelpViewer {
+            level: CommandLevel::User,
+            in_chat: true,
+        };
+        let admin = HelpViewer {
+            level: CommandLevel::Admin,
+            in_chat: true,
+        };
+        let owner = HelpViewer {
+            level: CommandLevel::Owner,
+            in_chat: false,
+        };
+        assert!(unknown.can_see(CommandLevel::Admin));
+        assert!(!unknown.can_see(CommandLevel::Owner));
+        assert!(!member.can_see(CommandLevel::Admin));
+        assert!(member.can_see(CommandLevel::User));
+        assert!(admin.can_see(CommandLevel::Admin));
+        assert!(!admin.can_see(CommandLevel::Owner));
+        assert!(owner.can_see(CommandLevel::Owner));
+    }
+}
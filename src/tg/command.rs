@@ -1,18 +1,21 @@
 //! Utilities exposing a unified interface for parsing slash commands and their arguments
 //!
-//! Commands can be either a normal telegram slash command, or a command preceeded with a
-//! different character, currently "!". Command arguments are parsed using regex currently
-//! but in the near future will be switched to a context-free grammar
+//! Commands can be either a normal telegram slash command, or a command preceeded with one
+//! of the prefixes in the config, "!" by default. Chats can also give commands other names
+//! with aliases, which are resolved here so modules only see the real command name.
+//! Command arguments are parsed using regex currently but in the near future will be
+//! switched to a context-free grammar
 
-use crate::statics::{AT_HANDLE, USERNAME};
+use crate::statics::{BotLocal, AT_HANDLE, USERNAME};
 use crate::util::error::Fail;
 use crate::util::string::AlignCharBoundry;
 use crate::{
-    persist::redis::RedisStr,
-    statics::{CONFIG, REDIS},
+    metadata::CommandLevel,
+    persist::{audit::record_command, redis::RedisStr},
+    statics::{CONFIG, REDIS, TG},
     util::{
         error::{BotError, Result},
-        string::{get_chat_lang, Lang, Speak},
+        string::{get_chat_lang, get_string_overrides, Lang, Speak},
     },
 };
 use async_trait::async_trait;
@@ -26,29 +29,97 @@ use redis::AsyncCommands;
 use regex::Regex;
 use serde::Deserialize;
 use serde::{de::DeserializeOwned, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::SystemTime;
 use uuid::Uuid;
 use yoke::{Yoke, Yokeable};
 
 use super::admin_helpers::is_dm;
+use super::aliases::get_aliases;
 use super::{
-    admin_helpers::{ChatUser, IntoChatUser, UpdateHelpers},
+    admin_helpers::{auto_delete_response, get_nickname, ChatUser, IntoChatUser, UpdateHelpers},
     button::get_url,
     markdown::EntityMessage,
     permissions::{BotPermissions, IsGroupAdmin, NamedBotPermissions, NamedPermission},
 };
 
+// these depend on the config and username, so every hosted bot has its own
+static PREFIXES: BotLocal<Vec<String>> =
+    BotLocal::new(|| command_prefixes(&CONFIG.commands.prefixes));
+static PREFIX_PATTERN: BotLocal<String> = BotLocal::new(|| {
+    PREFIXES
+        .iter()
+        .map(|v| regex::escape(v))
+        .collect::<Vec<String>>()
+        .join("|")
+});
+static COMMOND: BotLocal<Regex> = BotLocal::new(|| {
+    Regex::new(&format!(
+        r#"^({})\w+(@{})?\s+.*"#,
+        *PREFIX_PATTERN, *USERNAME
+    ))
+    .unwrap()
+});
+static COMMOND_HEAD: BotLocal<Regex> = BotLocal::new(|| {
+    Regex::new(&format!(
+        r#"^({})\w+(@{}|\s|$)"#,
+        *PREFIX_PATTERN, *USERNAME
+    ))
+    .unwrap()
+});
+
 lazy_static! {
-    static ref COMMOND: Regex = Regex::new(&format!(r#"^(!|/)\w+(@{})?\s+.*"#, *USERNAME)).unwrap();
-    static ref COMMOND_HEAD: Regex =
-        Regex::new(&format!(r#"^(!|/)\w+(@{}|\s|$)"#, *USERNAME)).unwrap();
     static ref TOKENS: Regex = Regex::new(r#"([^\s"!/]+|"|^!|^/)"#).unwrap();
     static ref ARGS: Regex = Regex::new(r#"(".*"|[^"\s]+)"#).unwrap();
     static ref QUOTE: Regex = Regex::new(r#"".*""#).unwrap();
 }
 
+/// Every prefix a command can start with, longest first so a prefix starting with another
+/// prefix is matched whole
+fn command_prefixes(configured: &[String]) -> Vec<String> {
+    let mut prefixes = configured
+        .iter()
+        .filter(|v| !v.trim().is_empty())
+        .cloned()
+        .chain(["/".to_owned()])
+        .collect::<Vec<String>>();
+    prefixes.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+    prefixes.dedup();
+    prefixes
+}
+
+/// Strip the command prefix from text if it has one, for command names given as arguments
+pub fn trim_prefix(text: &str) -> &'_ str {
+    PREFIXES
+        .iter()
+        .find_map(|v| text.strip_prefix(v.as_str()))
+        .unwrap_or(text)
+}
+
+/// Returns true if text starts with a command, without parsing it
+pub fn is_command(text: &str) -> bool {
+    COMMOND_HEAD.is_match(text)
+}
+
+/// Split the command text starts with into its name, without the prefix or bot username,
+/// and the position its arguments start at
+fn split_head(text: &str) -> Option<(&'_ str, usize)> {
+    let caps = COMMOND_HEAD.captures(text)?;
+    let head = caps.get(0)?;
+    let prefix = caps.get(1)?;
+    let name = text[prefix.end()..head.end()]
+        .trim_end()
+        .trim_end_matches(&*AT_HANDLE);
+    Some((name, head.end()))
+}
+
+/// Get the name of the command text starts with, without parsing arguments. Aliases are
+/// not resolved
+pub fn command_name(text: &str) -> Option<&'_ str> {
+    split_head(text).map(|(name, _)| name)
+}
+
 pub enum InputType<'a> {
     Reply(&'a str, Option<&'a str>, &'a Message),
     Command(&'a str, Option<&'a str>, &'a Message),
@@ -291,9 +362,44 @@ pub struct Cmd<'a> {
     pub lang: &'a Lang,
 }
 
+/// A name the bot answers to in a chat instead of a /command, for example
+/// "Dij, ban him". Only trigger words set by the chat's admins are recognized
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Nickname {
+    pub name: String,
+    /// lowercase trigger words mapped to command names
+    pub triggers: HashMap<String, String>,
+}
+
+impl Nickname {
+    /// Split a message starting with the nickname into the command its trigger word
+    /// maps to and the remaining text
+    pub fn parse<'a>(&'a self, text: &'a str) -> Option<(&'a str, &'a str)> {
+        let head = text.get(..self.name.len())?;
+        if self.name.is_empty() || !head.eq_ignore_ascii_case(&self.name) {
+            return None;
+        }
+        let rest = &text[self.name.len()..];
+        if !rest.starts_with([',', ':']) && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let rest = rest.trim_start_matches([',', ':']).trim_start();
+        let (trigger, tail) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        self.triggers
+            .get(&trigger.to_lowercase())
+            .map(|cmd| (cmd.as_str(), tail))
+    }
+}
+
 pub struct StaticContext {
     pub update: UpdateExt,
     pub lang: Lang,
+    /// strings replaced by the chat's admins, keyed by string name
+    pub overrides: HashMap<String, String>,
+    /// nickname the bot answers to in this chat
+    pub nickname: Option<Nickname>,
+    /// lowercase aliases mapped to the commands they run, only loaded for commands
+    pub aliases: HashMap<String, String>,
 }
 
 /// Everything needed to interact with user messages. Contains command and arguments, the message
@@ -351,52 +457,56 @@ impl StaticContext {
                 .map_or_else(|| message.get_caption(), Some)
             {
                 log::info!("cmd {}", cmd);
-                if let Some(head) = COMMOND_HEAD.find(cmd) {
-                    let entities = if let Some(entities) = message.get_entities() {
-                        let mut entities = entities
-                            .iter()
-                            .filter(|p| {
-                                matches!(
-                                    p.get_tg_type(),
-                                    "hashtag" | "mention" | "url" | "text_mention" | "text_link"
-                                )
-                            })
-                            .collect::<Vec<&MessageEntity>>();
-                        entities.sort_by_key(|n| n.get_offset());
-                        entities
-                    } else {
-                        vec![]
-                    };
-                    let tail = &cmd[head.end()..].trim_start();
-
-                    let args = entities.iter().filter_map(|v| get_arg_type(message, v));
-
-                    let raw_args = ARGS
-                        .find_iter(tail)
-                        .map(|v| {
-                            if let Some(m) = QUOTE.find(v.as_str()) {
-                                TextArg::Quote(m.as_str().trim_matches(&['\"']))
-                            } else {
-                                TextArg::Arg(v.as_str())
-                            }
+                let (name, tail) = if let Some((name, end)) = split_head(cmd) {
+                    let name = self
+                        .aliases
+                        .get(&name.to_lowercase())
+                        .map(|v| v.as_str())
+                        .unwrap_or(name);
+                    (name, &cmd[end..])
+                } else if let Some(v) = self.nickname.as_ref().and_then(|v| v.parse(cmd)) {
+                    v
+                } else {
+                    return None;
+                };
+                let entities = if let Some(entities) = message.get_entities() {
+                    let mut entities = entities
+                        .iter()
+                        .filter(|p| {
+                            matches!(
+                                p.get_tg_type(),
+                                "hashtag" | "mention" | "url" | "text_mention" | "text_link"
+                            )
                         })
-                        .collect();
-                    let mut cb = 1;
-                    cb = head.as_str().align_char_boundry(cb);
-
-                    Some((
-                        (head.as_str()[cb..head.end()]
-                            .trim_end()
-                            .trim_end_matches(&*AT_HANDLE)),
-                        TextArgs {
-                            text: tail,
-                            args: raw_args,
-                        },
-                        args.collect(),
-                    ))
+                        .collect::<Vec<&MessageEntity>>();
+                    entities.sort_by_key(|n| n.get_offset());
+                    entities
                 } else {
-                    None
-                }
+                    vec![]
+                };
+                let tail = tail.trim_start();
+
+                let args = entities.iter().filter_map(|v| get_arg_type(message, v));
+
+                let raw_args = ARGS
+                    .find_iter(tail)
+                    .map(|v| {
+                        if let Some(m) = QUOTE.find(v.as_str()) {
+                            TextArg::Quote(m.as_str().trim_matches(&['\"']))
+                        } else {
+                            TextArg::Arg(v.as_str())
+                        }
+                    })
+                    .collect();
+
+                Some((
+                    name,
+                    TextArgs {
+                        text: tail,
+                        args: raw_args,
+                    },
+                    args.collect(),
+                ))
             } else {
                 None
             }
@@ -460,7 +570,7 @@ impl StaticContext {
     /// Get a context from an update. Returns none if one or more fields aren't present
     /// Currently only Message updates return Some
     pub async fn get_context(update: UpdateExt) -> Result<Arc<Self>> {
-        let lang = if let Some(chat) = match update {
+        let chat = match update {
             UpdateExt::Message(ref m) => Some(m.chat.id),
             UpdateExt::EditedMessage(ref m) => Some(m.chat.id),
             UpdateExt::CallbackQuery(ref m) => m.get_message().map(|m| {
@@ -471,13 +581,39 @@ impl StaticContext {
                 .id
             }),
             UpdateExt::ChatMember(ref m) => Some(m.chat.id),
+            UpdateExt::ChatJoinRequest(ref m) => Some(m.chat.id),
             _ => None,
-        } {
-            get_chat_lang(chat).await?
+        };
+        let (lang, overrides) = if let Some(chat) = chat {
+            (
+                get_chat_lang(chat).await?,
+                get_string_overrides(chat).await?,
+            )
         } else {
-            Lang::En
+            (Lang::En, HashMap::new())
         };
-        Ok(Arc::new(Self { update, lang }))
+        let nickname = match update {
+            UpdateExt::Message(ref m) => get_nickname(m.get_chat()).await?,
+            _ => None,
+        };
+        let aliases = match update {
+            UpdateExt::Message(ref m)
+                if m.get_text()
+                    .or_else(|| m.get_caption())
+                    .map(is_command)
+                    .unwrap_or(false) =>
+            {
+                get_aliases(m.get_chat().get_id()).await?
+            }
+            _ => HashMap::new(),
+        };
+        Ok(Arc::new(Self {
+            update,
+            lang,
+            overrides,
+            nickname,
+            aliases,
+        }))
     }
 }
 
@@ -571,12 +707,20 @@ impl IsGroupAdmin for Context {
     }
 
     /// Apply the mapper function to the permissions, if it returns false NamedPermissions,
-    /// return with error
+    /// return with error. Members of a role granted the current command pass without the
+    /// permissions
     async fn check_permissions<F>(&self, func: F) -> Result<()>
     where
         F: Fn(NamedBotPermissions) -> NamedPermission + Send,
     {
-        self.message()?.check_permissions(func).await
+        if !self.role_grants_command().await? {
+            self.message()?.check_permissions(func).await?;
+        }
+        if let Err(err) = record_command(self).await {
+            log::warn!("failed to record audit entry: {}", err);
+            err.record_stats();
+        }
+        Ok(())
     }
 }
 
@@ -602,29 +746,57 @@ impl Speak for Context {
     where
         T: AsRef<str> + Send + Sync,
     {
-        self.message()?.speak(message).await
+        let message = self.message()?.speak(message).await?;
+        self.auto_delete(&message).await;
+        Ok(message)
     }
 
     async fn speak_fmt(&self, messsage: EntityMessage) -> Result<Option<Message>> {
-        self.message()?.speak_fmt(messsage).await
+        let message = self.message()?.speak_fmt(messsage).await?;
+        self.auto_delete(&message).await;
+        Ok(message)
     }
 
     async fn reply_fmt(&self, messsage: EntityMessage) -> Result<Option<Message>> {
-        self.message()?.reply_fmt(messsage).await
+        let message = self.message()?.reply_fmt(messsage).await?;
+        self.auto_delete(&message).await;
+        Ok(message)
     }
 
     async fn reply<T>(&self, message: T) -> Result<Option<Message>>
     where
         T: AsRef<str> + Send + Sync,
     {
-        self.message()?.reply(message).await
+        let message = self.message()?.reply(message).await?;
+        self.auto_delete(&message).await;
+        Ok(message)
     }
 
     async fn force_reply<T>(&self, message: T, reply: i64) -> Result<Option<Message>>
     where
         T: AsRef<str> + Send + Sync,
     {
-        self.message()?.force_reply(message, reply).await
+        let message = self.message()?.force_reply(message, reply).await?;
+        self.auto_delete(&message).await;
+        Ok(message)
+    }
+}
+
+impl Context {
+    /// Responses to admin commands are mostly settings confirmations, so they follow the
+    /// chat's auto delete setting. Responses to user commands are left alone
+    async fn auto_delete(&self, message: &Option<Message>) {
+        let admin = self
+            .cmd()
+            .and_then(|v| TG.modules.command_level(v.cmd))
+            .map(|v| v >= CommandLevel::Admin)
+            .unwrap_or(false);
+        if admin {
+            if let Err(err) = auto_delete_response(message.as_ref()).await {
+                log::warn!("failed to queue response for deletion: {}", err);
+                err.record_stats();
+            }
+        }
     }
 }
 
@@ -634,8 +806,8 @@ impl UpdateHelpers for Context {
         self.update().user_event()
     }
 
-    async fn should_moderate(&self) -> Option<&'_ Message> {
-        self.update().should_moderate().await
+    async fn moderated_message(&self) -> Option<&'_ Message> {
+        self.update().moderated_message().await
     }
 }
 pub async fn post_deep_link<T, F>(value: T, key_func: F) -> Result<String>
@@ -680,7 +852,7 @@ where
 mod test {
     use botapi::gen_types::UserBuilder;
 
-    use crate::statics::{Args, Config, ARGS, CONFIG_BACKEND, ME};
+    use crate::statics::{Args, Config, Instance, ARGS, ME};
 
     use super::*;
 
@@ -697,7 +869,7 @@ mod test {
     fn default_context(text: String) -> Result<Context> {
         let message = default_message(text)?;
         ARGS.set(Args::default()).ok();
-        CONFIG_BACKEND.set(Config::default()).ok();
+        Instance::current().set_config(Config::default()).ok();
         ME.set(
             UserBuilder::new(0, true, "test".to_owned())
                 .set_username("testbot".to_owned())
@@ -707,6 +879,9 @@ mod test {
         let ctx = StaticContext {
             update: UpdateExt::Message(message),
             lang: Lang::En,
+            overrides: HashMap::new(),
+            nickname: None,
+            aliases: HashMap::new(),
         };
         let ctx = Arc::new(ctx);
         Ok(ctx.yoke())
@@ -770,6 +945,28 @@ mod test {
         }
     }
 
+    #[test]
+    fn prefixes() {
+        let configured = ["!", "!!", "/", " "].map(|v| v.to_owned());
+        assert_eq!(command_prefixes(&configured), vec!["!!", "!", "/"]);
+        assert_eq!(command_prefixes(&[]), vec!["/"]);
+    }
+
+    #[test]
+    fn nickname_parse() {
+        let nickname = Nickname {
+            name: "Dij".to_owned(),
+            triggers: [("yeet".to_owned(), "ban".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(nickname.parse("dij, yeet him"), Some(("ban", "him")));
+        assert_eq!(nickname.parse("Dij: YEET"), Some(("ban", "")));
+        assert_eq!(nickname.parse("Dij, kick him"), None);
+        assert_eq!(nickname.parse("Dijkstra yeet him"), None);
+        assert_eq!(nickname.parse("D"), None);
+    }
+
     async fn command_emoji() {
         let ctx = default_context("/😍🧋".to_owned()).unwrap();
 
@@ -0,0 +1,142 @@
+//! Remote administration from a private chat. An admin connects their private chat with the
+//! bot to a group, after which commands sent in the private chat are handled as if they were
+//! sent in the group. This works by handing modules a copy of the command message with its
+//! chat replaced by the group, so everything keyed on the message's chat, including every
+//! function in admin_helpers and permission checks, acts on the group without knowing about
+//! connections. Responses to the copy are sent back to the private chat instead of the group
+//!
+//! Only the module owning the command sees the copy, so locks, filters, and other modules
+//! reacting to every message in the group never see commands sent from a private chat
+
+use botapi::gen_types::{Chat, Message, UpdateExt, User};
+use futures::Future;
+use macros::lang_fmt;
+
+use crate::statics::TG;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Lang;
+
+use super::admin_helpers::{is_dm, set_connection};
+use super::command::command_name;
+use super::dialog::get_dialog;
+use super::permissions::GetCachedAdmins;
+use super::user::GetChat;
+
+/// Module providing the connection commands. Its commands are never redirected, so a
+/// connection can always be inspected or dropped from the private chat
+pub const CONNECTION_MODULE: &str = "Connections";
+
+tokio::task_local! {
+    /// the private message a command was sent in, while its copy is being handled as if it
+    /// was sent in the connected group
+    static ORIGIN: Message;
+}
+
+/// Get the group a private chat is connected to, if any
+pub async fn get_connection(chat: &Chat) -> Result<Option<i64>> {
+    let connected = get_dialog(chat).await?.and_then(|v| v.connected_chat);
+    Ok(connected)
+}
+
+/// Connect a user's private chat to a group they are an admin in, returning the group
+pub async fn connect(message: &Message, lang: &Lang, user: &User, chat: i64) -> Result<Chat> {
+    let group = match chat.get_chat().await? {
+        Some(group) if !is_dm(&group) => group,
+        _ => return message.fail(lang_fmt!(lang, "connectunknown", chat)),
+    };
+    if group.is_user_admin(user.get_id()).await?.is_none() {
+        return message.fail(lang_fmt!(lang, "connectnotadmin"));
+    }
+    // connections always belong to the private chat, even when made from the group
+    let dm = match user.get_id().get_chat().await? {
+        Some(dm) => dm,
+        None => return message.fail(lang_fmt!(lang, "connectstartfirst")),
+    };
+    set_connection(&dm, Some(group.get_id())).await?;
+    Ok(group)
+}
+
+/// Drop a private chat's connection, returning the group it was connected to
+pub async fn disconnect(chat: &Chat) -> Result<Option<i64>> {
+    let connected = get_connection(chat).await?;
+    if connected.is_some() {
+        set_connection(chat, None).await?;
+    }
+    Ok(connected)
+}
+
+/// If a message is a command sent in a private chat connected to a group, get a copy of it
+/// sent in the group. Connections of users who are no longer admins are dropped
+async fn redirect(message: &Message) -> Result<Option<Message>> {
+    if !is_dm(message.get_chat()) {
+        return Ok(None);
+    }
+    let Some(user) = message.get_from() else {
+        return Ok(None);
+    };
+    let Some(cmd) = message.get_text().and_then(command_name) else {
+        return Ok(None);
+    };
+    match TG.modules.module_for_command(cmd) {
+        Some(module) if module != CONNECTION_MODULE => (),
+        _ => return Ok(None),
+    }
+    let Some(connected) = get_connection(message.get_chat()).await? else {
+        return Ok(None);
+    };
+    let group = match connected.get_chat().await? {
+        Some(group) if group.is_user_admin(user.get_id()).await?.is_some() => group,
+        _ => {
+            log::info!("dropping connection of {} to {}", user.get_id(), connected);
+            set_connection(message.get_chat(), None).await?;
+            return Ok(None);
+        }
+    };
+    let mut redirected = message.clone();
+    redirected.chat = group.into();
+    Ok(Some(redirected))
+}
+
+/// Handle an update, first replacing commands sent from connected private chats with a copy
+/// sent in the connected group
+pub async fn with_connection<F, Fut>(update: UpdateExt, func: F) -> Result<()>
+where
+    F: FnOnce(UpdateExt) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    if let UpdateExt::Message(ref message) = update {
+        match redirect(message).await {
+            Ok(Some(redirected)) => {
+                let origin = message.clone();
+                return ORIGIN
+                    .scope(origin, func(UpdateExt::Message(redirected)))
+                    .await;
+            }
+            Ok(None) => (),
+            Err(err) => {
+                log::warn!("failed to check connection: {}", err);
+                err.record_stats();
+            }
+        }
+    }
+    func(update).await
+}
+
+/// Returns true if the update being handled by this task is a command sent from a
+/// connected private chat
+pub fn is_redirected() -> bool {
+    ORIGIN.try_with(|_| ()).is_ok()
+}
+
+/// Get the private message a message was copied from, if it is a redirected command.
+/// Used to send responses back to the private chat
+pub fn origin_of(message: &Message) -> Option<Message> {
+    ORIGIN
+        .try_with(|origin| {
+            let copied = origin.get_message_id() == message.get_message_id()
+                && origin.get_chat().get_id() != message.get_chat().get_id();
+            copied.then(|| origin.clone())
+        })
+        .ok()
+        .flatten()
+}
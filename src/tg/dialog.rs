@@ -14,7 +14,7 @@ use crate::util::error::Result;
 use ::redis::AsyncCommands;
 
 use botapi::gen_types::{
-    CallbackQuery, Chat, InlineKeyboardButtonBuilder, InlineKeyboardMarkup,
+    CallbackQuery, Chat, InlineKeyboardButton, InlineKeyboardButtonBuilder, InlineKeyboardMarkup,
     LinkPreviewOptionsBuilder, MaybeInaccessibleMessage, Message, UpdateExt,
 };
 use chrono::{DateTime, Duration, Utc};
@@ -31,7 +31,9 @@ use std::str::FromStr;
 use uuid::Uuid;
 
 use crate::persist::core::{chat_members, dialogs};
-use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisStr, ToRedisStr};
+use crate::persist::redis::{
+    default_cache_query, CachedQueryTrait, RedisCache, RedisStr, ToRedisStr,
+};
 use crate::statics::{CONFIG, DB, REDIS, TG};
 use crate::tg::button::OnPush;
 use crate::util::error::BotError;
@@ -39,12 +41,16 @@ use log::info;
 
 use std::sync::Arc;
 
-use super::admin_helpers::IntoChatUser;
+use super::admin_helpers::{IntoChatUser, UpdateHelpers, UserChanged};
 use super::button::InlineKeyboardBuilder;
 use super::command::Context;
 use super::markdown::MarkupBuilder;
 pub const TYPE_DIALOG: &str = "DialogDb";
 
+/// Triggerphrases of transitions used for navigating between states rather than choosing
+/// something, in the order their buttons are shown
+pub const NAV_TRIGGERS: [&str; 3] = ["prev", "back", "next"];
+
 #[inline(always)]
 fn get_conversation_key_prefix(chat: i64, user: i64, prefix: &str) -> String {
     format!("{}:{}:{}", prefix, chat, user)
@@ -89,7 +95,7 @@ where
 {
     if let Set(key) = model.chat_id {
         let key = get_dialog_key(key);
-        REDIS.sq(|q| q.del(&key)).await?;
+        REDIS.invalidate(&[&key]).await?;
     }
     dialogs::Entity::insert(model)
         .on_conflict(
@@ -186,6 +192,11 @@ fn get_member_key(user: i64) -> String {
     format!("mbr:{}", user)
 }
 
+#[inline(always)]
+fn get_joined_key(chat: i64, user: i64) -> String {
+    format!("mjoin:{}:{}", chat, user)
+}
+
 pub async fn update_chat(
     user: i64,
 ) -> Result<Box<dyn Iterator<Item = chat_members::ActiveModel> + Send>> {
@@ -217,6 +228,7 @@ pub async fn update_chat(
                 user_id: Set(user),
                 chat_id: Set(v),
                 banned_by_me: NotSet,
+                joined_at: NotSet,
             }
         })))
     }
@@ -235,6 +247,7 @@ pub async fn is_chat_member(user: i64, chat: i64) -> Result<bool> {
                 chat_id: Set(chat),
                 user_id: Set(user),
                 banned_by_me: NotSet,
+                joined_at: NotSet,
             };
             Ok(v.any(|p| p.eq(&model)))
         }
@@ -272,7 +285,12 @@ impl Context {
     pub async fn record_chat_member(&self) -> Result<()> {
         match self.update() {
             UpdateExt::ChatMember(member) => {
-                record_chat_member(member.get_from().get_id(), member.get_chat().get_id()).await
+                record_chat_member(member.get_from().get_id(), member.get_chat().get_id()).await?;
+                if let Some(UserChanged::UserJoined(member)) = self.update().user_event() {
+                    let user = member.get_new_chat_member().get_user();
+                    record_chat_join(user.get_id(), member.get_chat().get_id()).await?;
+                }
+                Ok(())
             }
             UpdateExt::Message(message) => {
                 if let Some(user) = message.get_from() {
@@ -297,6 +315,7 @@ pub async fn record_chat_member(user: i64, chat: i64) -> Result<()> {
             chat_id: Set(chat),
             user_id: Set(user),
             banned_by_me: NotSet,
+            joined_at: Set(Some(Utc::now())),
         })
         .on_conflict(
             OnConflict::columns([chat_members::Column::ChatId, chat_members::Column::UserId])
@@ -309,6 +328,47 @@ pub async fn record_chat_member(user: i64, chat: i64) -> Result<()> {
     Ok(())
 }
 
+/// Records that a user just joined a chat, restarting the time they have been in the chat
+/// if they left and came back
+pub async fn record_chat_join(user: i64, chat: i64) -> Result<()> {
+    let key = get_member_key(user);
+    REDIS
+        .pipe(|q| q.sadd(&key, chat).expire(&key, CONFIG.timing.cache_timeout))
+        .await?;
+    let model = chat_members::Entity::insert(chat_members::ActiveModel {
+        chat_id: Set(chat),
+        user_id: Set(user),
+        banned_by_me: NotSet,
+        joined_at: Set(Some(Utc::now())),
+    })
+    .on_conflict(
+        OnConflict::columns([chat_members::Column::ChatId, chat_members::Column::UserId])
+            .update_column(chat_members::Column::JoinedAt)
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_joined_key(chat, user)).await?;
+    Ok(())
+}
+
+/// Get when a user joined a chat. None if the user was never seen in the chat or was
+/// recorded before join times were tracked
+pub async fn get_joined_at(user: i64, chat: i64) -> Result<Option<DateTime<Utc>>> {
+    let member = default_cache_query(
+        |_, _| async move {
+            let res = chat_members::Entity::find_by_id((chat, user))
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_joined_key(chat, user), &())
+    .await?;
+    Ok(member.and_then(|v| v.joined_at))
+}
+
 /// Updates the list of known chat members with a banned status.
 pub async fn record_chat_member_banned(user: i64, chat: i64, banned: bool) -> Result<()> {
     let key = get_member_key(user);
@@ -321,6 +381,7 @@ pub async fn record_chat_member_banned(user: i64, chat: i64, banned: bool) -> Re
             chat_id: Set(chat),
             user_id: Set(user),
             banned_by_me: Set(banned),
+            joined_at: Set(Some(Utc::now())),
         })
         .on_conflict(
             OnConflict::columns([chat_members::Column::ChatId, chat_members::Column::UserId])
@@ -339,13 +400,14 @@ pub async fn record_chat_member_banned(user: i64, chat: i64, banned: bool) -> Re
 
 pub async fn reset_banned_chats(user: i64) -> Result<()> {
     let key = get_member_key(user);
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     chat_members::Entity::update_many()
         .filter(chat_members::Column::UserId.eq(user))
         .set(chat_members::ActiveModel {
             user_id: NotSet,
             chat_id: NotSet,
             banned_by_me: Set(false),
+            joined_at: NotSet,
         })
         .exec(*DB)
         .await?;
@@ -621,8 +683,31 @@ impl Conversation {
         Ok(())
     }
 
+    /// Make a button that moves this conversation along a transition when pushed
+    fn transition_button(&self, t: &FSMTransition, row_limit: usize) -> InlineKeyboardButton {
+        let b = InlineKeyboardButtonBuilder::new(t.name.clone())
+            .set_callback_data(Uuid::new_v4().to_string())
+            .build();
+        let trans = t.end_state.to_owned();
+        if let Some(newstate) = self.0.states.get(&t.end_state) {
+            let content = newstate.content.to_owned();
+            let me = self.clone();
+            b.on_push(move |callback| async move {
+                if let Err(err) = me
+                    .edit_button_transition(trans, content, &callback, row_limit)
+                    .await
+                {
+                    log::warn!("failed to transition: {}", err);
+                }
+                Ok(())
+            });
+        }
+        b
+    }
+
     /// convert this conversation into a button menu automatically
-    /// Returns markup to add to a message
+    /// Returns markup to add to a message. Transitions named in NAV_TRIGGERS are put
+    /// on their own row after the others
     pub fn get_current_markup(
         &self,
         row_limit: usize,
@@ -630,40 +715,31 @@ impl Conversation {
         let me = self.clone();
         async move {
             let state = me.get_current().await?;
-            let markup =
+            let (nav, choices): (Vec<_>, Vec<_>) =
                 me.0.transitions
                     .iter()
                     .filter(|(_, t)| t.start_state == state.state_id)
-                    .map(|((_, _), t)| {
-                        let b = InlineKeyboardButtonBuilder::new(t.name.clone())
-                            .set_callback_data(Uuid::new_v4().to_string())
-                            .build();
-                        let trans = t.end_state.to_owned();
-                        if let Some(newstate) = me.0.states.get(&t.end_state) {
-                            let content = newstate.content.to_owned();
-                            let me = me.clone();
-                            b.on_push(move |callback| async move {
-                                if let Err(err) = me
-                                    .edit_button_transition(trans, content, &callback, row_limit)
-                                    .await
-                                {
-                                    log::warn!("failed to transition: {}", err);
-                                }
-                                Ok(())
-                            });
-                        }
-                        b
-                    })
-                    .fold(&mut InlineKeyboardBuilder::default(), |builder, st| {
-                        if builder.row_len() < row_limit {
-                            builder.button(st)
-                        } else {
-                            builder.newline().button(st)
-                        }
-                    })
-                    .to_owned()
-                    .build();
-            Ok(markup)
+                    .partition(|((_, trigger), _)| NAV_TRIGGERS.contains(&trigger.as_str()));
+            let mut builder = choices
+                .into_iter()
+                .map(|(_, t)| me.transition_button(t, row_limit))
+                .fold(&mut InlineKeyboardBuilder::default(), |builder, st| {
+                    if builder.row_len() < row_limit {
+                        builder.button(st)
+                    } else {
+                        builder.newline().button(st)
+                    }
+                })
+                .to_owned();
+            if !nav.is_empty() && builder.row_len() > 0 {
+                builder.newline();
+            }
+            for trigger in NAV_TRIGGERS {
+                if let Some((_, t)) = nav.iter().find(|((_, v), _)| v == trigger) {
+                    builder.button(me.transition_button(t, row_limit));
+                }
+            }
+            Ok(builder.build())
         }
         .boxed()
     }
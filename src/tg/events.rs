@@ -0,0 +1,108 @@
+//! In process bus for moderation events. Moderation helpers in admin_helpers and
+//! federations publish an event after each action succeeds, so integrations embedding the
+//! bot can follow bans, warns, and fbans without parsing updates or command text. Events
+//! are only delivered to subscribers listening at the time, a subscriber falling more than
+//! EVENT_BUFFER events behind misses the oldest ones and gets RecvError::Lagged
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::statics::BotLocal;
+
+/// Most events kept for a subscriber that hasn't received them yet
+pub const EVENT_BUFFER: usize = 1024;
+
+static BUS: BotLocal<broadcast::Sender<ModEvent>> =
+    BotLocal::new(|| broadcast::channel(EVENT_BUFFER).0);
+
+/// A moderation action taken by the bot
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ModEvent {
+    UserBanned {
+        chat: i64,
+        user: i64,
+        /// when the ban is lifted, None for permanent bans
+        until: Option<DateTime<Utc>>,
+    },
+    UserUnbanned {
+        chat: i64,
+        user: i64,
+    },
+    UserMuted {
+        chat: i64,
+        user: i64,
+        until: Option<DateTime<Utc>>,
+    },
+    UserUnmuted {
+        chat: i64,
+        user: i64,
+    },
+    UserKicked {
+        chat: i64,
+        user: i64,
+    },
+    UserWarned {
+        chat: i64,
+        user: i64,
+        reason: Option<String>,
+        /// warns the user has in the chat, including this one
+        count: i32,
+    },
+    FbanAdded {
+        federation: Uuid,
+        user: i64,
+        reason: Option<String>,
+    },
+    FbanRemoved {
+        federation: Uuid,
+        user: i64,
+    },
+    GbanAdded {
+        user: i64,
+        reason: Option<String>,
+    },
+    GbanRemoved {
+        user: i64,
+    },
+    /// the bot was added to a chat
+    ChatJoined {
+        chat: i64,
+    },
+}
+
+/// Send an event to every current subscriber
+pub fn publish(event: ModEvent) {
+    // an error only means nobody is listening
+    let _ = BUS.send(event);
+}
+
+/// Receive every event published from now on
+pub fn subscribe() -> broadcast::Receiver<ModEvent> {
+    BUS.subscribe()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subscribers_receive_events() {
+        let mut first = subscribe();
+        let mut second = subscribe();
+        let event = ModEvent::UserKicked { chat: 1, user: 2 };
+        publish(event.clone());
+        assert_eq!(first.try_recv().unwrap(), event);
+        assert_eq!(second.try_recv().unwrap(), event);
+    }
+
+    #[test]
+    fn serialize_tagged() {
+        let event = ModEvent::GbanRemoved { user: 2 };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["event"], "gban_removed");
+        assert_eq!(json["user"], 2);
+    }
+}
@@ -0,0 +1,174 @@
+//! Central policy for who automatic moderation applies to. Locks, blocklists, and other
+//! modules acting on messages without an admin asking should consult this instead of
+//! checking admin status or approvals themselves.
+
+use std::collections::BTreeSet;
+
+use botapi::gen_types::{Chat, Message};
+use chrono::Duration;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+
+use crate::persist::core::ignored_bots;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, ME};
+use crate::util::error::Result;
+
+use super::admin_helpers::{is_approved, GetChat};
+use super::command::Context;
+use super::permissions::IsAdmin;
+
+/// Reason a sender is not subject to automatic moderation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Exemption {
+    /// the bot itself
+    Me,
+    Admin,
+    /// an admin posting anonymously as the group
+    AnonymousAdmin,
+    Approved,
+    /// the channel linked to the group, posts from it are forwarded automatically
+    LinkedChannel,
+    /// a bot listed as exempt by the bot owner
+    ExemptBot,
+    /// another moderation bot, ignored to avoid fighting with it
+    ModerationBot,
+}
+
+#[inline(always)]
+fn get_ignored_bot_key(chat: i64, bot: &str) -> String {
+    format!("ibot:{}:{}", chat, bot)
+}
+
+/// Check if messages from a bot should be ignored in a chat, either because it is on the
+/// bot owner's list of moderation bots or the chat added it
+pub async fn is_ignored_bot(chat: i64, username: &str) -> Result<bool> {
+    let bot = username.trim_start_matches('@').to_lowercase();
+    let key = get_ignored_bot_key(chat, &bot);
+    let name = bot.clone();
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = ignored_bots::Entity::find_by_id((chat, name))
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res
+        .map(|v| v.ignored)
+        .unwrap_or_else(|| CONFIG.moderation.ignored_bots.contains(&bot)))
+}
+
+/// Override whether a bot is ignored in a chat
+pub async fn set_ignored_bot(chat: i64, username: &str, ignored: bool) -> Result<()> {
+    let bot = username.trim_start_matches('@').to_lowercase();
+    let key = get_ignored_bot_key(chat, &bot);
+    let model = ignored_bots::ActiveModel {
+        chat: Set(chat),
+        bot: Set(bot),
+        ignored: Set(ignored),
+    };
+    let model = ignored_bots::Entity::insert(model)
+        .on_conflict(
+            OnConflict::columns([ignored_bots::Column::Chat, ignored_bots::Column::Bot])
+                .update_column(ignored_bots::Column::Ignored)
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+    model.cache(key).await?;
+    Ok(())
+}
+
+/// Get the usernames of all bots ignored in a chat, after applying the chat's overrides
+pub async fn get_ignored_bots(chat: i64) -> Result<BTreeSet<String>> {
+    let overrides = ignored_bots::Entity::find()
+        .filter(ignored_bots::Column::Chat.eq(chat))
+        .all(*DB)
+        .await?;
+    let mut bots = CONFIG
+        .moderation
+        .ignored_bots
+        .iter()
+        .map(|v| v.to_lowercase())
+        .collect::<BTreeSet<String>>();
+    for model in overrides {
+        if model.ignored {
+            bots.insert(model.bot);
+        } else {
+            bots.remove(&model.bot);
+        }
+    }
+    Ok(bots)
+}
+
+/// Returns true if a message was sent by a moderation bot that should be ignored
+pub async fn is_from_ignored_bot(message: &Message) -> Result<bool> {
+    match message.get_from() {
+        Some(user) if user.get_is_bot() => match user.get_username() {
+            Some(username) => is_ignored_bot(message.get_chat().get_id(), username).await,
+            None => Ok(false),
+        },
+        _ => Ok(false),
+    }
+}
+
+/// Check if a user, or a chat posting with sender_chat, is exempt from automatic
+/// moderation in a chat
+pub async fn get_exemption(chat: &Chat, sender: i64) -> Result<Option<Exemption>> {
+    if sender == ME.get().unwrap().get_id() {
+        return Ok(Some(Exemption::Me));
+    }
+    if sender == chat.get_id() {
+        return Ok(Some(Exemption::AnonymousAdmin));
+    }
+    if CONFIG.moderation.exempt_bots.contains(&sender) {
+        return Ok(Some(Exemption::ExemptBot));
+    }
+    if is_approved(chat, sender).await? {
+        return Ok(Some(Exemption::Approved));
+    }
+
+    // user ids are always positive, chat ids never are
+    if sender < 0 {
+        match sender.get_chat_cached().await {
+            Ok(sender) if sender.linked_chat_id == Some(chat.get_id()) => {
+                return Ok(Some(Exemption::LinkedChannel))
+            }
+            Ok(_) => (),
+            Err(err) => err.record_stats(),
+        }
+    } else if sender.is_admin(chat).await? {
+        return Ok(Some(Exemption::Admin));
+    }
+    Ok(None)
+}
+
+/// Check if the sender of a message is exempt from automatic moderation. Messages with
+/// no sender are never exempt
+pub async fn get_message_exemption(message: &Message) -> Result<Option<Exemption>> {
+    if is_from_ignored_bot(message).await? {
+        return Ok(Some(Exemption::ModerationBot));
+    }
+    let sender = message
+        .get_sender_chat()
+        .map(|v| v.get_id())
+        .or_else(|| message.get_from().map(|v| v.get_id()));
+    match sender {
+        Some(sender) => get_exemption(message.get_chat(), sender).await,
+        None => Ok(None),
+    }
+}
+
+impl Context {
+    /// Returns true if automatic moderation should act on this user (or sender chat)
+    /// in the current chat
+    pub async fn should_moderate(&self, user: i64) -> Result<bool> {
+        let chat = self.try_get()?.chat;
+        Ok(get_exemption(chat, user).await?.is_none())
+    }
+}
@@ -0,0 +1,373 @@
+//! Media blocked across a federation. Media is matched exactly by its file_unique_id, or
+//! loosely by a perceptual hash so recompressed or resized copies of a known scam image
+//! are still caught. Perceptual hashes are computed from the image itself or from the
+//! thumbnail of videos, animations, and stickers, and cached by file_unique_id so each
+//! file is only downloaded once
+
+use botapi::gen_types::{Message, PhotoSize};
+use bytes::Bytes;
+use chrono::Duration;
+use redis::AsyncCommands;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+use uuid::Uuid;
+
+use crate::persist::admin::fed_media;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS, TG};
+use crate::tg::admin_helpers::get_file;
+use crate::util::error::{BotError, Result};
+
+/// Prefix of fingerprints holding a perceptual hash instead of a file_unique_id
+const PHASH_PREFIX: &str = "phash:";
+
+/// Longest fingerprint accepted from a command argument
+const MAX_FINGERPRINT_LEN: usize = 64;
+
+/// Seconds a computed perceptual hash is cached. Files never change, so this only
+/// bounds memory use
+const HASH_CACHE_SECONDS: i64 = 7 * 24 * 60 * 60;
+
+/// Width of the grayscale image a difference hash is computed from, one more than the
+/// height so each row gives 8 comparisons
+pub const DHASH_WIDTH: usize = 9;
+
+/// Height of the grayscale image a difference hash is computed from
+pub const DHASH_HEIGHT: usize = 8;
+
+#[inline(always)]
+fn get_fed_media_key(fed: &Uuid) -> String {
+    format!("fmedia:{}", fed)
+}
+
+#[inline(always)]
+fn get_hash_key(unique_id: &str) -> String {
+    format!("mhash:{}", unique_id)
+}
+
+/// Format a perceptual hash as a fingerprint
+pub fn hash_fingerprint(hash: u64) -> String {
+    format!("{}{:016x}", PHASH_PREFIX, hash)
+}
+
+/// Get the perceptual hash from a fingerprint, None if it is a file_unique_id
+pub fn parse_hash(fingerprint: &str) -> Option<u64> {
+    fingerprint
+        .strip_prefix(PHASH_PREFIX)
+        .and_then(|v| u64::from_str_radix(v, 16).ok())
+}
+
+/// Check if a fingerprint given as a command argument is either a perceptual hash or
+/// looks like a file_unique_id
+pub fn is_valid_fingerprint(fingerprint: &str) -> bool {
+    if fingerprint.starts_with(PHASH_PREFIX) {
+        parse_hash(fingerprint).is_some()
+    } else {
+        !fingerprint.is_empty()
+            && fingerprint.len() <= MAX_FINGERPRINT_LEN
+            && fingerprint
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+}
+
+/// Compute a difference hash from a DHASH_WIDTH by DHASH_HEIGHT grayscale image stored
+/// row by row. Each bit is set if a pixel is darker than the pixel to its right
+pub fn dhash(pixels: &[u8; DHASH_WIDTH * DHASH_HEIGHT]) -> u64 {
+    let mut hash = 0u64;
+    for y in 0..DHASH_HEIGHT {
+        for x in 0..DHASH_WIDTH - 1 {
+            let i = y * DHASH_WIDTH + x;
+            hash = (hash << 1) | (pixels[i] < pixels[i + 1]) as u64;
+        }
+    }
+    hash
+}
+
+/// Check if two perceptual hashes are close enough to be the same image
+pub fn is_similar(a: u64, b: u64) -> bool {
+    (a ^ b).count_ones() <= CONFIG.federations.media_hash_distance
+}
+
+/// Get the file_unique_id of every file in a message. Photos return every size so
+/// reposts at a different resolution still match
+pub fn unique_ids(message: &Message) -> Vec<String> {
+    let mut ids = Vec::new();
+    if let Some(photo) = message.get_photo() {
+        ids.extend(photo.iter().map(|v| v.get_file_unique_id().to_owned()));
+    }
+    if let Some(video) = message.get_video() {
+        ids.push(video.get_file_unique_id().to_owned());
+    }
+    if let Some(animation) = message.get_animation() {
+        ids.push(animation.get_file_unique_id().to_owned());
+    }
+    if let Some(document) = message.get_document() {
+        ids.push(document.get_file_unique_id().to_owned());
+    }
+    if let Some(sticker) = message.get_sticker() {
+        ids.push(sticker.get_file_unique_id().to_owned());
+    }
+    if let Some(video_note) = message.get_video_note() {
+        ids.push(video_note.get_file_unique_id().to_owned());
+    }
+    ids.dedup();
+    ids
+}
+
+fn fits(size: Option<i64>) -> bool {
+    size.map(|v| v <= CONFIG.federations.media_hash_max_size)
+        .unwrap_or(true)
+}
+
+fn thumbnail_source(thumbnail: Option<&PhotoSize>) -> Option<(String, String)> {
+    thumbnail.filter(|v| fits(v.get_file_size())).map(|v| {
+        (
+            v.get_file_id().to_owned(),
+            v.get_file_unique_id().to_owned(),
+        )
+    })
+}
+
+/// Get the file id and file_unique_id of the image in a message a perceptual hash is
+/// computed from
+fn hash_source(message: &Message) -> Option<(String, String)> {
+    if let Some(photo) = message.get_photo() {
+        photo
+            .iter()
+            .rev()
+            .find(|v| fits(v.get_file_size()))
+            .map(|v| {
+                (
+                    v.get_file_id().to_owned(),
+                    v.get_file_unique_id().to_owned(),
+                )
+            })
+    } else if let Some(video) = message.get_video() {
+        thumbnail_source(video.get_thumbnail())
+    } else if let Some(animation) = message.get_animation() {
+        thumbnail_source(animation.get_thumbnail())
+    } else if let Some(sticker) = message.get_sticker() {
+        thumbnail_source(sticker.get_thumbnail())
+    } else if let Some(video_note) = message.get_video_note() {
+        thumbnail_source(video_note.get_thumbnail())
+    } else if let Some(document) = message.get_document() {
+        let image = document
+            .get_mime_type()
+            .map(|v| v.starts_with("image/"))
+            .unwrap_or(false);
+        if image && fits(document.get_file_size()) {
+            Some((
+                document.get_file_id().to_owned(),
+                document.get_file_unique_id().to_owned(),
+            ))
+        } else {
+            thumbnail_source(document.get_thumbnail())
+        }
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "media-hash")]
+async fn decode(bytes: Bytes) -> Result<u64> {
+    use image::imageops::FilterType;
+
+    tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory(&bytes)
+            .map_err(|err| BotError::generic(format!("failed to decode image: {}", err)))?;
+        let pixels = image
+            .resize_exact(
+                DHASH_WIDTH as u32,
+                DHASH_HEIGHT as u32,
+                FilterType::Triangle,
+            )
+            .to_luma8()
+            .into_raw();
+        let pixels: [u8; DHASH_WIDTH * DHASH_HEIGHT] = pixels
+            .try_into()
+            .map_err(|_| BotError::generic("resized image has the wrong size"))?;
+        Ok(dhash(&pixels))
+    })
+    .await?
+}
+
+#[cfg(not(feature = "media-hash"))]
+async fn decode(_: Bytes) -> Result<u64> {
+    Err(BotError::generic("built without the media-hash feature"))
+}
+
+/// Get the perceptual hash of the image in a message, or of the thumbnail for other
+/// media. Returns None if the bot was built without the media-hash feature, the message
+/// has nothing to hash, or the image is too large or can't be decoded
+pub async fn media_hash(message: &Message) -> Result<Option<u64>> {
+    if !cfg!(feature = "media-hash") {
+        return Ok(None);
+    }
+    let Some((file_id, unique_id)) = hash_source(message) else {
+        return Ok(None);
+    };
+    let key = get_hash_key(&unique_id);
+    let cached: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    if let Some(cached) = cached {
+        // empty means the image was already found to be undecodable
+        return Ok(u64::from_str_radix(&cached, 16).ok());
+    }
+    let file = TG.client().build_get_file(&file_id).build().await?;
+    if !fits(file.get_file_size()) {
+        return Ok(None);
+    }
+    let path = file
+        .get_file_path()
+        .ok_or_else(|| BotError::Generic("File path missing".to_owned()))?;
+    let bytes = get_file(path).await?;
+    if bytes.len() as i64 > CONFIG.federations.media_hash_max_size {
+        return Ok(None);
+    }
+    let hash = match decode(bytes).await {
+        Ok(hash) => Some(hash),
+        Err(err) => {
+            log::warn!("failed to hash media {}: {}", unique_id, err);
+            None
+        }
+    };
+    let value = hash.map(|v| format!("{:016x}", v)).unwrap_or_default();
+    REDIS
+        .pipe(|q| {
+            q.set(&key, &value)
+                .expire(&key, HASH_CACHE_SECONDS)
+                .ignore()
+        })
+        .await?;
+    Ok(hash)
+}
+
+/// Get every fingerprint a message's media can be blocked by
+pub async fn message_fingerprints(message: &Message) -> Result<Vec<String>> {
+    let mut fingerprints = unique_ids(message);
+    if let Some(hash) = media_hash(message).await? {
+        fingerprints.push(hash_fingerprint(hash));
+    }
+    Ok(fingerprints)
+}
+
+/// Get the fingerprints of all media blocked in a federation
+pub async fn get_fed_media(fed: &Uuid) -> Result<Vec<String>> {
+    let fed = *fed;
+    default_cache_query(
+        |_, _| async move {
+            let res = fed_media::Entity::find()
+                .filter(fed_media::Column::FedId.eq(fed))
+                .all(*DB)
+                .await?
+                .into_iter()
+                .map(|v| v.fingerprint)
+                .collect::<Vec<String>>();
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_fed_media_key(&fed), &())
+    .await
+}
+
+/// Block media in a federation by fingerprint. Fingerprints that are already blocked
+/// are skipped
+pub async fn add_fed_media(fed: &Uuid, fingerprints: &[String], added_by: i64) -> Result<()> {
+    let models = fingerprints
+        .iter()
+        .map(|fingerprint| {
+            fed_media::Model {
+                fed_id: *fed,
+                fingerprint: fingerprint.clone(),
+                added_by,
+            }
+            .into_active_model()
+        })
+        .collect::<Vec<_>>();
+    if models.is_empty() {
+        return Ok(());
+    }
+    fed_media::Entity::insert_many(models)
+        .on_conflict(
+            OnConflict::columns([fed_media::Column::FedId, fed_media::Column::Fingerprint])
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_fed_media_key(fed)]).await?;
+    Ok(())
+}
+
+/// Unblock media in a federation, returning the number of fingerprints removed
+pub async fn remove_fed_media(fed: &Uuid, fingerprints: &[String]) -> Result<u64> {
+    let res = fed_media::Entity::delete_many()
+        .filter(
+            fed_media::Column::FedId
+                .eq(*fed)
+                .and(fed_media::Column::Fingerprint.is_in(fingerprints.iter().cloned())),
+        )
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_fed_media_key(fed)]).await?;
+    Ok(res.rows_affected)
+}
+
+/// Check a message's media against a federation's blocklist, returning the fingerprint
+/// that matched. Messages are only downloaded for hashing if the federation blocks
+/// anything by perceptual hash
+pub async fn blocked_media(fed: &Uuid, message: &Message) -> Result<Option<String>> {
+    let blocked = get_fed_media(fed).await?;
+    if blocked.is_empty() {
+        return Ok(None);
+    }
+    if let Some(id) = unique_ids(message)
+        .into_iter()
+        .find(|id| blocked.contains(id))
+    {
+        return Ok(Some(id));
+    }
+    let hashes = blocked
+        .iter()
+        .filter_map(|v| parse_hash(v).map(|hash| (v, hash)))
+        .collect::<Vec<(&String, u64)>>();
+    if hashes.is_empty() {
+        return Ok(None);
+    }
+    let Some(hash) = media_hash(message).await? else {
+        return Ok(None);
+    };
+    Ok(hashes
+        .into_iter()
+        .find(|(_, blocked)| is_similar(hash, *blocked))
+        .map(|(fingerprint, _)| fingerprint.clone()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn difference_hash() {
+        let gradient: [u8; DHASH_WIDTH * DHASH_HEIGHT] =
+            std::array::from_fn(|i| ((i % DHASH_WIDTH) * 20) as u8);
+        assert_eq!(dhash(&gradient), u64::MAX);
+        let mut edited = gradient;
+        edited[DHASH_WIDTH + 1] = 0;
+        assert_eq!((dhash(&gradient) ^ dhash(&edited)).count_ones(), 1);
+        assert_eq!(dhash(&[7; DHASH_WIDTH * DHASH_HEIGHT]), 0);
+    }
+
+    #[test]
+    fn fingerprints() {
+        let fingerprint = hash_fingerprint(0xdeadbeef);
+        assert_eq!(fingerprint, "phash:00000000deadbeef");
+        assert_eq!(parse_hash(&fingerprint), Some(0xdeadbeef));
+        assert_eq!(parse_hash("AQADbq4xG7y"), None);
+        assert!(is_valid_fingerprint(&fingerprint));
+        assert!(is_valid_fingerprint("AQADbq4xG7y-_1"));
+        assert!(!is_valid_fingerprint("phash:nothex"));
+        assert!(!is_valid_fingerprint("has space"));
+    }
+}
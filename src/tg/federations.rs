@@ -1,14 +1,18 @@
 //! Helpers for managing federations, subscribable ban lists
 
 use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::ops::DerefMut;
 
 use crate::{
     persist::{
         admin::{fbans, fedadmin, federations, gbans},
         core::{chat_members, dialogs, users},
-        redis::{default_cache_query, CachedQueryTrait, RedisCache, RedisStr, ToRedisStr},
+        metrics::record_fed_cache_check,
+        redis::{
+            default_cache_query, CachedQueryTrait, RedisCache, RedisStr, ToRedisStr, CACHE_BUS,
+        },
     },
-    statics::{BAN_GOVERNER, CONFIG, DB, REDIS, TG},
+    statics::{spawn, BAN_GOVERNER, CONFIG, DB, REDIS, TG},
     util::error::{BotError, Fail, Result, SpeakErr},
     util::string::Speak,
 };
@@ -21,12 +25,12 @@ use botapi::gen_types::{
 use chrono::Duration;
 
 use macros::{entity_fmt, lang_fmt};
-use redis::AsyncCommands;
+use redis::{AsyncCommands, Script};
 
 use sea_orm::{
     sea_query::OnConflict, ActiveValue::NotSet, ActiveValue::Set, ColumnTrait, ConnectionTrait,
-    EntityTrait, FromQueryResult, IntoActiveModel, JoinType, ModelTrait, QueryFilter, QuerySelect,
-    Statement,
+    EntityTrait, FromQueryResult, IntoActiveModel, JoinType, ModelTrait, Order, QueryFilter,
+    QueryOrder, QuerySelect, Statement,
 };
 use sea_query::{
     Alias, ColumnRef, CommonTableExpression, Expr, Query, QueryStatementBuilder, UnionType,
@@ -36,9 +40,14 @@ use uuid::Uuid;
 
 use super::{
     admin_helpers::insert_user,
+    ban_signals::{publish_signal, retract_signal},
     button::{InlineKeyboardBuilder, OnPush},
     command::Context,
-    dialog::{get_user_banned_chats, record_chat_member_banned, reset_banned_chats, upsert_dialog},
+    dialog::{
+        get_dialog, get_user_banned_chats, record_chat_member_banned, reset_banned_chats,
+        upsert_dialog,
+    },
+    events::{publish, ModEvent},
     markdown::MarkupType,
     user::{GetUser, Username},
 };
@@ -68,7 +77,7 @@ fn get_fban_key(fban: &Uuid) -> String {
 }
 
 #[inline(always)]
-fn get_gban_key(user: i64) -> String {
+pub(crate) fn get_gban_key(user: i64) -> String {
     format!("gban:{}", user)
 }
 
@@ -272,6 +281,7 @@ pub async fn create_federation(ctx: &Context, federation: federations::Model) ->
                         .expire(&key, CONFIG.timing.cache_timeout))
                 })
                 .await?;
+            CACHE_BUS.publish(&[&key]).await;
         }
     }
     Ok(())
@@ -288,7 +298,7 @@ pub async fn subfed(fed: &Uuid, sub: &Uuid) -> Result<federations::Model> {
     .await?;
 
     let key = get_fed_key(model.owner);
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     try_update_fban_cache(model.owner).await?;
     Ok(model)
 }
@@ -306,15 +316,134 @@ pub async fn update_fed(owner: i64, newname: String) -> Result<federations::Mode
         .exec_with_returning(*DB)
         .await?;
 
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     model
         .pop()
         .ok_or_else(|| BotError::Generic("no fed".to_owned()))
 }
 
+/// How a single user's entry in one cached fban set changes. Sets that aren't cached are
+/// left alone so a partial set is never created, and entries are only removed if they
+/// still point at the removed fban so a concurrent fban isn't lost
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FbanSetAction {
+    /// the user was fbanned, point their entry at the new fban
+    Set(Uuid),
+    /// the user was unfbanned and no other fban of theirs reaches this set
+    Remove(Uuid),
+    /// the user was unfbanned but another fban of theirs still reaches this set, drop the
+    /// set to be rebuilt rather than guess which fban the entry should point at
+    Invalidate(Uuid),
+}
+
+/// Applies FbanSetActions for one user. KEYS are fban sets, ARGV[1] is the user, followed
+/// by an operation and fban id for each key
+const FBAN_SET_SCRIPT: &str = r#"
+    local user = ARGV[1]
+    for i, key in ipairs(KEYS) do
+        local op = ARGV[i * 2]
+        local fban = ARGV[i * 2 + 1]
+        if redis.call("exists", key) == 1 then
+            if op == "set" then
+                redis.call("hset", key, user, fban)
+            elseif redis.call("hget", key, user) == fban then
+                if op == "remove" then
+                    redis.call("hdel", key, user)
+                else
+                    redis.call("del", key)
+                end
+            end
+        end
+    end
+    return 0
+"#;
+
+impl FbanSetAction {
+    fn get_op(&self) -> (&'static str, &Uuid) {
+        match self {
+            Self::Set(fban) => ("set", fban),
+            Self::Remove(fban) => ("remove", fban),
+            Self::Invalidate(fban) => ("invalidate", fban),
+        }
+    }
+}
+
+/// Get a federation and every federation subscribed to it, directly or through other
+/// subscriptions. These are the federations whose fban sets include its fbans
+async fn get_subscribers(fed: &Uuid) -> Result<Vec<federations::Model>> {
+    let mut found = federations::Entity::find_by_id(*fed).all(*DB).await?;
+    let mut frontier = vec![*fed];
+    while !frontier.is_empty() {
+        let subs = federations::Entity::find()
+            .filter(federations::Column::Subscribed.is_in(frontier))
+            .all(*DB)
+            .await?
+            .into_iter()
+            .filter(|v| !found.iter().any(|f| f.fed_id == v.fed_id))
+            .collect::<Vec<federations::Model>>();
+        frontier = subs.iter().map(|v| v.fed_id).collect();
+        found.extend(subs);
+    }
+    Ok(found)
+}
+
+/// Actions adding a new fban to the sets of every federation subscribed to its federation
+fn fban_actions(subscribers: &[Uuid], fban: Uuid) -> Vec<(Uuid, FbanSetAction)> {
+    subscribers
+        .iter()
+        .map(|fed| (*fed, FbanSetAction::Set(fban)))
+        .collect()
+}
+
+/// Actions removing a deleted fban from the sets of every federation subscribed to its
+/// federation. `chains` holds each of those federations with its subscription chain and
+/// `remaining` the user's other fbans keyed by federation, read after the delete
+fn unfban_actions(
+    chains: &[(Uuid, Vec<Uuid>)],
+    removed: Uuid,
+    remaining: &HashMap<Uuid, Uuid>,
+) -> Vec<(Uuid, FbanSetAction)> {
+    chains
+        .iter()
+        .map(|(fed, chain)| {
+            let action = if chain.iter().any(|v| remaining.contains_key(v)) {
+                FbanSetAction::Invalidate(removed)
+            } else {
+                FbanSetAction::Remove(removed)
+            };
+            (*fed, action)
+        })
+        .collect()
+}
+
+/// Apply changes to one user's entry in several fban sets in a single atomic step
+async fn update_fban_sets(user: i64, actions: &[(Uuid, FbanSetAction)]) -> Result<()> {
+    if actions.is_empty() {
+        return Ok(());
+    }
+    let script = Script::new(FBAN_SET_SCRIPT);
+    let mut invocation = script.prepare_invoke();
+    invocation.arg(user);
+    let keys = actions
+        .iter()
+        .map(|(fed, _)| get_fban_set_key(fed))
+        .collect::<Vec<String>>();
+    for ((_, action), key) in actions.iter().zip(keys.iter()) {
+        let (op, fban) = action.get_op();
+        invocation.key(key).arg(op).arg(fban.to_redis()?);
+    }
+    REDIS
+        .query(|mut q| async move {
+            let _: () = invocation.invoke_async(q.deref_mut()).await?;
+            Ok(())
+        })
+        .await?;
+    CACHE_BUS.publish(&keys).await;
+    Ok(())
+}
+
 pub async fn fban_user(fban: fbans::Model, user: &User) -> Result<()> {
     let key = get_fban_key(&fban.fban_id);
-    let setkey = get_fban_set_key(&fban.federation);
     insert_user(user).await?;
     let model = fbans::Entity::insert(fban.into_active_model())
         .on_conflict(
@@ -325,10 +454,38 @@ pub async fn fban_user(fban: fbans::Model, user: &User) -> Result<()> {
         .exec_with_returning(*DB)
         .await?;
     model.cache(&key).await?;
-    REDIS.sq(|q| q.del(&setkey)).await?; //TODO: less drastic
+    let subscribers = get_subscribers(&model.federation)
+        .await?
+        .into_iter()
+        .map(|v| v.fed_id)
+        .collect::<Vec<Uuid>>();
+    update_fban_sets(model.user, &fban_actions(&subscribers, model.fban_id)).await?;
+    publish_signal(&model).await?;
+    publish(ModEvent::FbanAdded {
+        federation: model.federation,
+        user: model.user,
+        reason: model.reason.clone(),
+    });
     Ok(())
 }
 
+/// Update the cached fban sets affected by an fban that was just deleted
+async fn remove_from_fban_sets(fban: &fbans::Model) -> Result<()> {
+    let remaining = fbans::Entity::find()
+        .filter(fbans::Column::User.eq(fban.user))
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| (v.federation, v.fban_id))
+        .collect::<HashMap<Uuid, Uuid>>();
+    let mut chains = Vec::new();
+    for fed in get_subscribers(&fban.federation).await? {
+        chains.push((fed.fed_id, get_subscription_chain(&fed).await?));
+    }
+    let actions = unfban_actions(&chains, fban.fban_id, &remaining);
+    update_fban_sets(fban.user, &actions).await
+}
+
 pub async fn get_fed(user: i64) -> Result<Option<federations::Model>> {
     let key = get_fed_key(user);
     for _ in 0..4 {
@@ -378,6 +535,10 @@ pub async fn gban_user(fban: gbans::Model, metadata: User) -> Result<()> {
         )
         .exec_with_returning(*DB)
         .await?;
+    publish(ModEvent::GbanAdded {
+        user: model.user,
+        reason: model.reason.clone(),
+    });
     model.join_single(&key, Some(user)).await?;
     Ok(())
 }
@@ -397,7 +558,7 @@ async fn get_fbanned_chats(fed: &Uuid, user: i64) -> Result<impl Iterator<Item =
 
 async fn iter_unfban_user(user: i64, fed: &Uuid) -> Result<()> {
     for chat in get_fbanned_chats(fed, user).await? {
-        TG.client
+        TG.client()
             .build_unban_chat_member(chat, user)
             .only_if_banned(true)
             .build()
@@ -419,7 +580,7 @@ pub async fn fstat(user: i64) -> Result<impl Iterator<Item = (fbans::Model, fede
 
 async fn iter_unban_user(user: i64) -> Result<()> {
     for chat in get_user_banned_chats(user).await? {
-        TG.client
+        TG.client()
             .build_unban_chat_member(chat, user)
             .only_if_banned(true)
             .build()
@@ -455,6 +616,8 @@ pub async fn is_user_gbanned(user: i64) -> Result<Option<(gbans::Model, users::M
                 first_name: "".to_owned(),
                 last_name: None,
                 is_bot: false,
+                optout_mentions: false,
+                optout_stats: false,
             }),
         )
     }))
@@ -481,7 +644,7 @@ pub async fn fpromote(fed: Uuid, user: i64) -> Result<()> {
     )
     .exec(*DB)
     .await?;
-    REDIS.sq(|q| q.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     Ok(())
 }
 
@@ -501,6 +664,7 @@ pub async fn refresh_fedadmin_cache(fed: &Uuid) -> Result<()> {
             p
         })
         .await?;
+    CACHE_BUS.publish(&[&key]).await;
     Ok(())
 }
 
@@ -524,7 +688,7 @@ pub async fn join_fed(chat: &Chat, fed: &Uuid) -> Result<()> {
     model.federation = Set(Some(*fed));
     upsert_dialog(*DB, model).await?;
 
-    REDIS.sq(|p| p.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
     // try_update_fed_cache(chat.get_id()).await?;
     Ok(())
 }
@@ -537,6 +701,7 @@ pub async fn try_update_fed_cache(chat: i64) -> Result<()> {
         .await?;
     log::info!("try_update_fed_cache {}", feds.len());
 
+    let mut keys = vec![get_fed_chat_key(chat)];
     REDIS
         .try_pipe(|p| {
             let key = get_fed_chat_key(chat);
@@ -550,11 +715,13 @@ pub async fn try_update_fed_cache(chat: i64) -> Result<()> {
                     let key = get_fed_key(fed.owner);
                     p.set(&key, Some(fed).to_redis()?)
                         .expire(&key, CONFIG.timing.cache_timeout);
+                    keys.push(key);
                 }
             }
             Ok(p)
         })
         .await?;
+    CACHE_BUS.publish(&keys).await;
     Ok(())
 }
 
@@ -599,6 +766,7 @@ pub async fn try_update_fban_cache(user: i64) -> Result<()> {
     let fbans = get_fbans_for_user_with_chats(user).await?;
 
     log::info!("update fban cache {}", fbans.len());
+    let mut keys = Vec::new();
     REDIS
         .try_pipe(|p| {
             p.atomic();
@@ -634,6 +802,7 @@ pub async fn try_update_fban_cache(user: i64) -> Result<()> {
                 let fed_key = get_fed_key(federation_model.owner);
                 p.set(&fed_key, Some(&federation_model).to_redis()?)
                     .expire(&fed_key, CONFIG.timing.cache_timeout);
+                keys.push(fed_key);
                 if let (Some(fban_id), Some(federation), Some(user), Some(chat_id)) =
                     (fban_id, federation, user, chat_id)
                 {
@@ -651,6 +820,7 @@ pub async fn try_update_fban_cache(user: i64) -> Result<()> {
 
                     p.set(&fban_key, fbans.to_redis()?)
                         .expire(&fban_key, CONFIG.timing.cache_timeout);
+                    keys.push(fban_key);
 
                     if let Some((cache, _)) = fban_cache.get_mut(&federation_model.fed_id) {
                         cache.insert((user, fbans.fban_id));
@@ -665,6 +835,7 @@ pub async fn try_update_fban_cache(user: i64) -> Result<()> {
             for (chat, _) in members.iter() {
                 let key = get_fed_chat_key(*chat);
                 p.del(&key);
+                keys.push(key);
             }
 
             for (chat, fed) in members {
@@ -678,6 +849,7 @@ pub async fn try_update_fban_cache(user: i64) -> Result<()> {
             for (fed, (fbans, subscribed)) in fban_cache.iter() {
                 log::info!("updating subscribed {:?}", subscribed);
                 let key = get_fban_set_key(fed);
+                keys.push(key.clone());
                 p.hset(&key, true, true);
                 for (user, fban) in fbans {
                     p.hset(&key, user, fban.to_redis()?);
@@ -708,20 +880,191 @@ pub async fn try_update_fban_cache(user: i64) -> Result<()> {
             Ok(p)
         })
         .await?;
+    CACHE_BUS.publish(&keys).await;
 
     Ok(())
 }
 
+/// Entries compared and entries found inconsistent in one pass over a cache
+type CheckResult = (u64, u64);
+
+/// Get a federation and every federation it is subscribed to, stopping at cycles
+async fn get_subscription_chain(fed: &federations::Model) -> Result<Vec<Uuid>> {
+    let mut chain = vec![fed.fed_id];
+    let mut sub = fed.subscribed;
+    while let Some(s) = sub {
+        if chain.contains(&s) {
+            break;
+        }
+        chain.push(s);
+        sub = federations::Entity::find_by_id(s)
+            .one(*DB)
+            .await?
+            .and_then(|v| v.subscribed);
+    }
+    Ok(chain)
+}
+
+/// Compare a cached fban set with the fbans the database has for the federation and its
+/// subscriptions. A user has drifted if the cache is missing them, lists them when the
+/// database doesn't, or points at an fban that isn't theirs
+fn fban_set_drift(
+    expected: &HashMap<i64, HashSet<Uuid>>,
+    cached: &HashMap<i64, Uuid>,
+) -> CheckResult {
+    let users = expected
+        .keys()
+        .chain(cached.keys())
+        .collect::<HashSet<&i64>>();
+    let checked = users.len() as u64;
+    let drifted = users
+        .into_iter()
+        .filter(|user| match (expected.get(user), cached.get(user)) {
+            (Some(fbans), Some(fban)) => !fbans.contains(fban),
+            _ => true,
+        })
+        .count() as u64;
+    (checked, drifted)
+}
+
+async fn check_fed_cache(sample: u64) -> Result<CheckResult> {
+    let feds = federations::Entity::find()
+        .order_by(Expr::cust("RANDOM()"), Order::Asc)
+        .limit(sample)
+        .all(*DB)
+        .await?;
+    let (mut checked, mut drifted) = (0, 0);
+    for fed in feds {
+        let key = get_fed_key(fed.owner);
+        let cached: Option<RedisStr> = REDIS.sq(|q| q.get(&key)).await?;
+        let Some(cached) = cached else {
+            continue;
+        };
+        checked += 1;
+        let cached = cached.get::<Option<federations::Model>>().ok().flatten();
+        if cached.as_ref() != Some(&fed) {
+            drifted += 1;
+            REDIS.invalidate(&[&key]).await?;
+        }
+    }
+    Ok((checked, drifted))
+}
+
+async fn check_fed_chat_cache(sample: u64) -> Result<CheckResult> {
+    let chats = dialogs::Entity::find()
+        .order_by(Expr::cust("RANDOM()"), Order::Asc)
+        .limit(sample)
+        .all(*DB)
+        .await?;
+    let (mut checked, mut drifted) = (0, 0);
+    for chat in chats {
+        let key = get_fed_chat_key(chat.chat_id);
+        let (exists, member): (bool, Option<RedisStr>) = REDIS
+            .pipe(|p| p.exists(&key).hget(&key, chat.chat_id))
+            .await?;
+        if !exists {
+            continue;
+        }
+        checked += 1;
+        if member.and_then(|v| v.get::<Uuid>().ok()) != chat.federation {
+            drifted += 1;
+            REDIS.invalidate(&[&key]).await?;
+        }
+    }
+    Ok((checked, drifted))
+}
+
+async fn check_fban_cache(sample: u64) -> Result<CheckResult> {
+    let feds = federations::Entity::find()
+        .order_by(Expr::cust("RANDOM()"), Order::Asc)
+        .limit(sample)
+        .all(*DB)
+        .await?;
+    let (mut checked, mut drifted) = (0, 0);
+    for fed in feds {
+        let key = get_fban_set_key(&fed.fed_id);
+        let (exists, cached): (bool, HashMap<String, RedisStr>) =
+            REDIS.pipe(|p| p.exists(&key).hgetall(&key)).await?;
+        if !exists {
+            continue;
+        }
+        // the "true" field only marks the set as loaded and never parses as a user
+        let cached = cached
+            .into_iter()
+            .filter_map(|(user, fban)| Some((user.parse::<i64>().ok()?, fban.get::<Uuid>().ok()?)))
+            .collect::<HashMap<i64, Uuid>>();
+
+        let chain = get_subscription_chain(&fed).await?;
+        let mut expected = HashMap::<i64, HashSet<Uuid>>::new();
+        for fban in fbans::Entity::find()
+            .filter(fbans::Column::Federation.is_in(chain))
+            .all(*DB)
+            .await?
+        {
+            expected.entry(fban.user).or_default().insert(fban.fban_id);
+        }
+
+        let (c, d) = fban_set_drift(&expected, &cached);
+        checked += c;
+        drifted += d;
+        if d > 0 {
+            REDIS.invalidate(&[&key]).await?;
+        }
+    }
+    Ok((checked, drifted))
+}
+
+/// Validate a random sample of the federation caches against the database. Entries that
+/// drifted are dropped so they are rebuilt from the database on next use
+pub async fn check_fed_caches() -> Result<()> {
+    let sample = CONFIG.federations.cache_check_sample;
+    let (checked, drifted) = check_fed_cache(sample).await?;
+    record_fed_cache_check("fed", checked, drifted);
+    let (checked, drifted) = check_fed_chat_cache(sample).await?;
+    record_fed_cache_check("fed_chat", checked, drifted);
+    let (checked, drifted) = check_fban_cache(sample).await?;
+    record_fed_cache_check("fban_set", checked, drifted);
+    if drifted > 0 {
+        log::warn!(
+            "repaired {} of {} sampled fban cache entries",
+            drifted,
+            checked
+        );
+    }
+    Ok(())
+}
+
+/// Periodically check the federation caches for drift. Runs forever unless checking is
+/// disabled in the config
+pub async fn run_cache_check() {
+    let minutes = CONFIG.federations.cache_check_minutes;
+    if minutes == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(minutes * 60));
+    loop {
+        interval.tick().await;
+        if let Err(err) = check_fed_caches().await {
+            log::warn!("failed to check federation caches: {}", err);
+            err.record_stats();
+        }
+    }
+}
+
 impl Context {
-    pub async fn unfban(&self, user: i64, fed: &Uuid) -> Result<()> {
+    pub async fn unfban(&self, user: i64) -> Result<()> {
         let v = self.try_get()?;
         let chat = v.chat;
-        let key = get_fban_set_key(fed);
         if let Some(fban) = is_user_fbanned(user, chat.get_id(), self.message()?.message_id).await?
         {
             iter_unfban_user(user, &fban.federation).await?;
-            fban.delete(*DB).await?;
-            REDIS.sq(|q| q.del(&key)).await?;
+            fban.clone().delete(*DB).await?;
+            remove_from_fban_sets(&fban).await?;
+            retract_signal(&fban.federation, user).await?;
+            publish(ModEvent::FbanRemoved {
+                federation: fban.federation,
+                user,
+            });
             self.reply_fmt(entity_fmt!(self, "unfban", user.mention().await?))
                 .await?;
         } else {
@@ -755,7 +1098,7 @@ impl Context {
             let lang = *self.lang();
             confirm.on_push_multi(move |callback| async move {
                 if callback.get_from().get_id() != user {
-                    TG.client
+                    TG.client()
                         .build_answer_callback_query(callback.get_id())
                         .show_alert(true)
                         .text(&lang_fmt!(lang, "fpromotenotauth"))
@@ -764,13 +1107,13 @@ impl Context {
                     return Ok(false);
                 }
                 if let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() {
-                    TG.client
+                    TG.client()
                         .build_delete_message(chat, message.get_message_id())
                         .build()
                         .await?;
                     match fpromote(fed.fed_id, user).await {
                         Ok(_) => {
-                            TG.client
+                            TG.client()
                                 .build_answer_callback_query(callback.get_id())
                                 .show_alert(true)
                                 .text(&lang_fmt!(lang, "fpromoted"))
@@ -778,7 +1121,7 @@ impl Context {
                                 .await
                         }
                         Err(err) => {
-                            TG.client
+                            TG.client()
                                 .build_answer_callback_query(callback.get_id())
                                 .show_alert(true)
                                 .text(&lang_fmt!(lang, "failfpromote", err))
@@ -793,7 +1136,7 @@ impl Context {
 
             cancel.on_push_multi(move |callback| async move {
                 if callback.get_from().get_id() != me {
-                    TG.client
+                    TG.client()
                         .build_answer_callback_query(callback.get_id())
                         .show_alert(true)
                         .text("You are not the fed owner")
@@ -802,13 +1145,13 @@ impl Context {
                     return Ok(false);
                 }
                 if let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() {
-                    TG.client
+                    TG.client()
                         .build_edit_message_text("Fpromote has been canceled")
                         .message_id(message.get_message_id())
                         .chat_id(chat)
                         .build()
                         .await?;
-                    TG.client
+                    TG.client()
                         .build_edit_message_reply_markup()
                         .reply_markup(&InlineKeyboardMarkup::default())
                         .message_id(message.get_message_id())
@@ -816,7 +1159,7 @@ impl Context {
                         .build()
                         .await?;
                 }
-                TG.client
+                TG.client()
                     .build_answer_callback_query(callback.get_id())
                     .build()
                     .await?;
@@ -851,8 +1194,9 @@ impl Context {
 
         let delete = gbans::Entity::delete_by_id(user).exec(*DB).await?;
         if delete.rows_affected > 0 {
-            REDIS.sq(|q| q.del(&key)).await?;
-            tokio::spawn(async move { iter_unban_user(user).await.log() });
+            REDIS.invalidate(&[&key]).await?;
+            publish(ModEvent::GbanRemoved { user });
+            spawn(async move { iter_unban_user(user).await.log() });
 
             Ok(())
         } else {
@@ -874,11 +1218,16 @@ impl Context {
     }
 
     async fn single_gban(&self, user: i64) -> Result<()> {
-        let chat = self.try_get()?.chat.get_id();
-        if let Some((gban, user)) = is_user_gbanned(user).await? {
+        let chat = self.try_get()?.chat;
+        let enforce = get_dialog(chat)
+            .await?
+            .map(|v| v.enforce_gbans)
+            .unwrap_or(true);
+        let chat = chat.get_id();
+        if let Some((gban, user)) = is_user_gbanned(user).await?.filter(|_| enforce) {
             record_chat_member_banned(user.user_id, chat, true).await?;
 
-            TG.client
+            TG.client()
                 .build_ban_chat_member(chat, user.user_id)
                 .build()
                 .await?;
@@ -891,7 +1240,7 @@ impl Context {
         }
 
         if let Some(model) = is_user_fbanned(user, chat, self.message()?.message_id).await? {
-            TG.client
+            TG.client()
                 .build_ban_chat_member(chat, model.user)
                 .build()
                 .await?;
@@ -905,3 +1254,159 @@ impl Context {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fban_drift() {
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut expected = HashMap::new();
+        expected.insert(1, HashSet::from([a]));
+        expected.insert(2, HashSet::from([b]));
+        let mut cached = HashMap::new();
+        cached.insert(1, a);
+        cached.insert(2, b);
+        assert_eq!(fban_set_drift(&expected, &cached), (2, 0));
+
+        cached.insert(2, a);
+        cached.insert(3, b);
+        expected.insert(4, HashSet::from([a]));
+        assert_eq!(fban_set_drift(&expected, &cached), (4, 3));
+    }
+
+    /// Federations s, b, and c where s is subscribed to b and b is subscribed to c
+    struct Feds([Uuid; 3]);
+
+    impl Feds {
+        fn chain(&self, fed: Uuid) -> Vec<Uuid> {
+            let start = self.0.iter().position(|v| *v == fed).unwrap();
+            self.0[start..].to_vec()
+        }
+
+        fn subscribers(&self, fed: Uuid) -> Vec<Uuid> {
+            self.0
+                .iter()
+                .copied()
+                .filter(|v| self.chain(*v).contains(&fed))
+                .collect()
+        }
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Op {
+        Fban(Uuid),
+        Unfban(Uuid),
+    }
+
+    /// Stand-in for FBAN_SET_SCRIPT over one user's entry in each cached set. Sets missing
+    /// from the map aren't cached
+    fn apply(sets: &mut HashMap<Uuid, Option<Uuid>>, actions: &[(Uuid, FbanSetAction)]) {
+        for (fed, action) in actions {
+            let Some(entry) = sets.get_mut(fed) else {
+                continue;
+            };
+            match *action {
+                FbanSetAction::Set(fban) => *entry = Some(fban),
+                FbanSetAction::Remove(fban) if *entry == Some(fban) => *entry = None,
+                FbanSetAction::Invalidate(fban) if *entry == Some(fban) => {
+                    sets.remove(fed);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Run one step of an operation against the database and cache. Step 0 writes the
+    /// database, step 1 works out the cache actions from the database as it is now, and
+    /// step 2 applies them
+    fn step(
+        feds: &Feds,
+        db: &mut HashMap<Uuid, Uuid>,
+        sets: &mut HashMap<Uuid, Option<Uuid>>,
+        op: Op,
+        step: usize,
+        state: &mut (Option<Uuid>, Vec<(Uuid, FbanSetAction)>),
+    ) {
+        match (op, step) {
+            (Op::Fban(fed), 0) => state.0 = Some(*db.entry(fed).or_insert_with(Uuid::new_v4)),
+            (Op::Unfban(fed), 0) => state.0 = db.remove(&fed),
+            (Op::Fban(fed), 1) => state.1 = fban_actions(&feds.subscribers(fed), state.0.unwrap()),
+            (Op::Unfban(fed), 1) => {
+                if let Some(removed) = state.0 {
+                    let chains = feds
+                        .subscribers(fed)
+                        .into_iter()
+                        .map(|v| (v, feds.chain(v)))
+                        .collect::<Vec<(Uuid, Vec<Uuid>)>>();
+                    state.1 = unfban_actions(&chains, removed, db);
+                }
+            }
+            _ => apply(sets, &state.1),
+        }
+    }
+
+    /// Checks the update protocol against a model of FBAN_SET_SCRIPT, the script itself
+    /// isn't run since tests only have a mock redis
+    #[test]
+    fn model_concurrent_fban_set_updates() {
+        let feds = Feds([Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()]);
+        let ops = feds
+            .0
+            .iter()
+            .flat_map(|v| [Op::Fban(*v), Op::Unfban(*v)])
+            .collect::<Vec<Op>>();
+        for a in ops.iter().copied() {
+            for b in ops.iter().copied() {
+                // fbanning and unfbanning the same user in the same federation at once
+                // has no single right answer
+                if matches!((a, b), (Op::Fban(x), Op::Unfban(y)) | (Op::Unfban(x), Op::Fban(y)) if x == y)
+                {
+                    continue;
+                }
+                // every interleaving of the three steps of each operation
+                for order in (0u32..64).filter(|v| v.count_ones() == 3) {
+                    let mut db = HashMap::new();
+                    db.insert(feds.0[1], Uuid::new_v4());
+                    db.insert(feds.0[2], Uuid::new_v4());
+                    // a rebuilt set points at the last fban along the chain
+                    let mut sets = feds
+                        .0
+                        .iter()
+                        .map(|v| {
+                            (
+                                *v,
+                                feds.chain(*v).iter().rev().find_map(|f| db.get(f).copied()),
+                            )
+                        })
+                        .collect::<HashMap<Uuid, Option<Uuid>>>();
+
+                    let (mut sa, mut sb) = ((None, vec![]), (None, vec![]));
+                    let (mut na, mut nb) = (0, 0);
+                    for i in 0..6 {
+                        if order & (1 << i) != 0 {
+                            step(&feds, &mut db, &mut sets, a, na, &mut sa);
+                            na += 1;
+                        } else {
+                            step(&feds, &mut db, &mut sets, b, nb, &mut sb);
+                            nb += 1;
+                        }
+                    }
+
+                    for (fed, entry) in sets.iter() {
+                        let valid = feds
+                            .chain(*fed)
+                            .iter()
+                            .filter_map(|v| db.get(v).copied())
+                            .collect::<Vec<Uuid>>();
+                        match entry {
+                            Some(fban) => assert!(valid.contains(fban)),
+                            None => assert!(valid.is_empty()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+//! Cosmetic flair earned by being active in a chat. The flair module decides when a user
+//! earns a new flair, this is only storage so flair can be shown in /info and the
+//! {flair} filling
+
+use chrono::Duration;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+
+use crate::persist::core::flair;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::util::error::Result;
+
+#[inline(always)]
+fn get_flair_key(chat: i64, user: i64) -> String {
+    format!("flair:{}:{}", chat, user)
+}
+
+/// Get the saved flair and message count of a user in a chat
+pub async fn get_flair_model(chat: i64, user: i64) -> Result<Option<flair::Model>> {
+    default_cache_query(
+        |_, _| async move {
+            let res = flair::Entity::find_by_id((chat, user)).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_flair_key(chat, user), &())
+    .await
+}
+
+/// Get the flair of a user in a chat, if they earned one
+pub async fn get_flair(chat: i64, user: i64) -> Result<Option<String>> {
+    Ok(get_flair_model(chat, user).await?.and_then(|v| v.title))
+}
+
+/// Save the flair a user earned along with their message count when they earned it
+pub async fn set_flair(chat: i64, user: i64, messages: i64, title: Option<String>) -> Result<()> {
+    let model = flair::Entity::insert(
+        flair::Model {
+            chat,
+            user,
+            messages,
+            title,
+        }
+        .into_active_model(),
+    )
+    .on_conflict(
+        OnConflict::columns([flair::Column::Chat, flair::Column::User])
+            .update_columns([flair::Column::Messages, flair::Column::Title])
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_flair_key(chat, user)).await?;
+    Ok(())
+}
+
+/// Remove the flair of every user in a chat
+pub async fn delete_chat_flair(chat: i64) -> Result<()> {
+    let keys = flair::Entity::find()
+        .filter(flair::Column::Chat.eq(chat))
+        .all(*DB)
+        .await?
+        .into_iter()
+        .map(|v| get_flair_key(chat, v.user))
+        .collect::<Vec<String>>();
+    flair::Entity::delete_many()
+        .filter(flair::Column::Chat.eq(chat))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&keys).await?;
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! Syncs remote ban lists into the global bans. Every configured source is fetched on an
+//! interval and its bans are merged into the gbans table, tagged with the source's name so
+//! that bans dropped from the list are lifted again. Gbans issued by hand are never touched
+
+use std::collections::HashSet;
+use std::time::Duration as StdDuration;
+
+use lazy_static::lazy_static;
+use sea_orm::{
+    sea_query::OnConflict, ActiveValue::Set, ColumnTrait, EntityTrait, QueryFilter, QuerySelect,
+};
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::persist::admin::gbans;
+use crate::persist::core::users;
+use crate::statics::{GbanSource, GbanSourceFormat, CONFIG, DB, REDIS};
+use crate::util::error::Result;
+
+use super::events::{publish, ModEvent};
+use super::federations::get_gban_key;
+
+const FETCH_TIMEOUT: StdDuration = StdDuration::from_secs(60);
+
+/// Rows inserted or deleted per query
+const BATCH_SIZE: usize = 1000;
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(FETCH_TIMEOUT)
+        .build()
+        .expect("failed to build gban sync client");
+}
+
+/// A ban as listed by a remote source
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum RemoteBan {
+    Id(i64),
+    Ban {
+        #[serde(alias = "user", alias = "user_id")]
+        id: i64,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+impl RemoteBan {
+    fn into_parts(self) -> (i64, Option<String>) {
+        match self {
+            Self::Id(id) => (id, None),
+            Self::Ban { id, reason } => (id, reason.filter(|v| !v.is_empty())),
+        }
+    }
+}
+
+/// Fetch every ban currently on a source's list
+async fn fetch(source: &GbanSource) -> Result<Vec<(i64, Option<String>)>> {
+    let request = match source.format {
+        GbanSourceFormat::SpamWatch => {
+            let url = format!("{}/banlist", source.url.trim_end_matches('/'));
+            CLIENT
+                .get(url)
+                .bearer_auth(source.token.as_deref().unwrap_or_default())
+        }
+        GbanSourceFormat::Json => CLIENT.get(&source.url),
+    };
+    let bans: Vec<RemoteBan> = request
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .map_err(|err| err.without_url())?;
+    Ok(bans.into_iter().map(|v| v.into_parts()).collect())
+}
+
+/// Merge one source's list into the gbans table, returning how many bans were added and
+/// lifted
+async fn sync_source(source: &GbanSource) -> Result<(usize, usize)> {
+    let fetched = fetch(source).await?;
+    let listed = fetched.iter().map(|(v, _)| *v).collect::<HashSet<i64>>();
+    let existing: HashSet<i64> = gbans::Entity::find()
+        .select_only()
+        .column(gbans::Column::User)
+        .filter(gbans::Column::Source.eq(source.name.as_str()))
+        .into_tuple()
+        .all(*DB)
+        .await?
+        .into_iter()
+        .collect();
+
+    let added = fetched
+        .into_iter()
+        .filter(|(user, _)| !existing.contains(user))
+        .collect::<Vec<(i64, Option<String>)>>();
+    let lifted = existing.difference(&listed).copied().collect::<Vec<i64>>();
+
+    for batch in added.chunks(BATCH_SIZE) {
+        // gbans reference users, most listed users have never been seen by the bot
+        users::Entity::insert_many(batch.iter().map(|(user, _)| users::ActiveModel {
+            user_id: Set(*user),
+            first_name: Set(String::new()),
+            last_name: Set(None),
+            username: Set(None),
+            is_bot: Set(false),
+            optout_mentions: Set(false),
+            optout_stats: Set(false),
+        }))
+        .on_conflict(
+            OnConflict::column(users::Column::UserId)
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+        gbans::Entity::insert_many(batch.iter().map(|(user, reason)| gbans::ActiveModel {
+            user: Set(*user),
+            id: Set(Uuid::new_v4()),
+            reason: Set(reason.clone()),
+            source: Set(Some(source.name.clone())),
+        }))
+        .on_conflict(
+            OnConflict::column(gbans::Column::User)
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+        let keys = batch
+            .iter()
+            .map(|(user, _)| get_gban_key(*user))
+            .collect::<Vec<String>>();
+        REDIS.invalidate(&keys).await?;
+    }
+    for batch in lifted.chunks(BATCH_SIZE) {
+        gbans::Entity::delete_many()
+            .filter(gbans::Column::Source.eq(source.name.as_str()))
+            .filter(gbans::Column::User.is_in(batch.iter().copied()))
+            .exec(*DB)
+            .await?;
+        let keys = batch
+            .iter()
+            .map(|user| get_gban_key(*user))
+            .collect::<Vec<String>>();
+        REDIS.invalidate(&keys).await?;
+    }
+
+    for (user, reason) in added.iter() {
+        publish(ModEvent::GbanAdded {
+            user: *user,
+            reason: reason.clone(),
+        });
+    }
+    for user in lifted.iter() {
+        publish(ModEvent::GbanRemoved { user: *user });
+    }
+    Ok((added.len(), lifted.len()))
+}
+
+/// Sync every configured source once. A source that fails is skipped until the next sync
+pub async fn sync_sources() {
+    for source in CONFIG.gbans.sources.iter() {
+        match sync_source(source).await {
+            Ok((added, lifted)) => log::info!(
+                "synced gban source {}: {} added, {} lifted",
+                source.name,
+                added,
+                lifted
+            ),
+            Err(err) => {
+                log::warn!("failed to sync gban source {}: {}", source.name, err);
+                err.record_stats();
+            }
+        }
+    }
+}
+
+/// Periodically sync the remote ban lists. Runs forever unless no sources are configured
+pub async fn run_sync() {
+    if CONFIG.gbans.sources.is_empty() {
+        return;
+    }
+    let minutes = CONFIG.gbans.sync_minutes.max(1);
+    let mut interval = tokio::time::interval(StdDuration::from_secs(minutes * 60));
+    loop {
+        interval.tick().await;
+        sync_sources().await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn remote_formats() {
+        let bans: Vec<RemoteBan> = serde_json::from_str(
+            r#"[1, {"id": 2, "reason": "spam", "admin": 7, "date": 0}, {"user": 3}]"#,
+        )
+        .unwrap();
+        let bans = bans
+            .into_iter()
+            .map(|v| v.into_parts())
+            .collect::<Vec<(i64, Option<String>)>>();
+        assert_eq!(
+            bans,
+            vec![(1, None), (2, Some("spam".to_owned())), (3, None)]
+        );
+    }
+}
@@ -5,14 +5,15 @@ use crate::persist::core::media::SendMediaReply;
 use crate::persist::redis::{
     default_cache_query, CachedQueryTrait, RedisCache, RedisStr, ToRedisStr,
 };
-use crate::statics::{ME, TG};
+use crate::statics::{spawn, ME, TG};
 use crate::util::error::BotError;
-use crate::util::string::{should_ignore_chat, Speak};
+use crate::util::scheduler::{register_job, schedule};
+use crate::util::string::{get_chat_lang, should_ignore_chat, Speak};
 use crate::{
     langs::Lang,
     persist::{
         admin::{authorized, captchastate},
-        core::{media::MediaType, welcomes},
+        core::{media::MediaType, rules_acks, welcomes},
     },
     statics::{CONFIG, DB, REDIS},
     util::error::Result,
@@ -20,12 +21,12 @@ use crate::{
 use base64::engine::general_purpose;
 use base64::Engine;
 use botapi::gen_types::{
-    CallbackQuery, Chat, ChatMemberUpdated, EReplyMarkup, InlineKeyboardButton,
-    InlineKeyboardButtonBuilder, MaybeInaccessibleMessage, Message, MessageEntity,
-    ReplyParametersBuilder, UpdateExt, User,
+    CallbackQuery, Chat, ChatJoinRequest, ChatMemberUpdated, EReplyMarkup, InlineKeyboardButton,
+    InlineKeyboardButtonBuilder, InlineKeyboardMarkup, MaybeInaccessibleMessage, Message,
+    MessageEntity, ReplyParametersBuilder, UpdateExt, User,
 };
 use captcha::gen;
-use chrono::Duration;
+use chrono::{DateTime, Duration, Utc};
 use futures::FutureExt;
 use macros::lang_fmt;
 use rand::seq::SliceRandom;
@@ -39,7 +40,9 @@ use uuid::Uuid;
 
 use super::admin_helpers::{kick, DeleteAfterTime, UpdateHelpers, UserChanged};
 use super::button::{get_url, InlineKeyboardBuilder, OnPush};
+use super::captcha_stats::{record_captcha_event, CaptchaEvent};
 use super::command::Context;
+use super::dialog::dialog_or_default;
 use super::markdown::get_markup_for_buttons;
 use super::notes::handle_transition;
 use super::permissions::{IsAdmin, IsGroupAdmin};
@@ -49,6 +52,66 @@ pub(crate) fn auth_key(chat: i64) -> String {
     format!("cauth:{}", chat)
 }
 
+/// Scheduler job kind for declining join requests whose captcha was never solved
+const JOIN_TIMEOUT_JOB: &str = "captchajoin";
+
+/// Minutes a join request waits for its captcha if the chat has no captcha kick time
+const JOIN_CAPTCHA_MINUTES: i64 = 10;
+
+#[inline(always)]
+fn join_request_key(chat: i64, user: i64) -> String {
+    format!("cjoin:{}:{}", chat, user)
+}
+
+/// Approves a user's join request if it is waiting on a captcha. Returns false if
+/// there was no pending join request
+async fn approve_join_request(user: i64, chat: i64) -> Result<bool> {
+    let key = join_request_key(chat, user);
+    let pending: i64 = REDIS.sq(|q| q.del(&key)).await?;
+    if pending == 1 {
+        TG.client()
+            .build_approve_chat_join_request(chat, user)
+            .build()
+            .await?;
+    }
+    Ok(pending == 1)
+}
+
+/// Declines a user's join request if it is waiting on a captcha. Returns false if
+/// there was no pending join request
+async fn decline_join_request(user: i64, chat: i64) -> Result<bool> {
+    let key = join_request_key(chat, user);
+    let pending: i64 = REDIS.sq(|q| q.del(&key)).await?;
+    if pending == 1 {
+        TG.client()
+            .build_decline_chat_join_request(chat, user)
+            .build()
+            .await?;
+    }
+    Ok(pending == 1)
+}
+
+/// Removes a user who failed the captcha, declining their join request instead if
+/// they haven't joined yet
+async fn reject_captcha(user: i64, chat: i64) -> Result<()> {
+    if !decline_join_request(user, chat).await? {
+        kick(user, chat).await?;
+    }
+    Ok(())
+}
+
+/// Register the scheduler handler declining join requests after the captcha times out.
+/// Called once at startup
+pub fn register_join_request_jobs() {
+    register_job(JOIN_TIMEOUT_JOB, |payload| async move {
+        let (chat, user): (i64, i64) = serde_json::from_value(payload)?;
+        if decline_join_request(user, chat).await? {
+            record_captcha_event(chat, CaptchaEvent::Timeout).await?;
+        }
+        Ok(())
+    });
+}
+
 /// Loads the cache of users that already completed the captcha from db to redis
 pub async fn update_auth_cache(chat: i64) -> Result<()> {
     let key = auth_key(chat);
@@ -102,8 +165,13 @@ fn captcha_state_key(chat: &Chat) -> String {
 pub async fn get_captcha_config(
     message: &ChatMemberUpdated,
 ) -> Result<Option<captchastate::Model>> {
-    let key = captcha_state_key(message.get_chat());
-    let chat = message.get_chat().get_id();
+    get_chat_captcha_config(message.get_chat()).await
+}
+
+/// Gets the captcha configuration for a chat, returns None if captcha is disabled
+pub async fn get_chat_captcha_config(chat: &Chat) -> Result<Option<captchastate::Model>> {
+    let key = captcha_state_key(chat);
+    let chat = chat.get_id();
     let res = default_cache_query(
         |_, _| async move {
             let res = captchastate::Entity::find_by_id(chat).one(*DB).await?;
@@ -116,6 +184,42 @@ pub async fn get_captcha_config(
     Ok(res)
 }
 
+/// Enables captcha for a chat with the given mode and kick timeout, or disables it if
+/// None. Unlike the captcha commands this does not check permissions or reply
+pub async fn set_chat_captcha(
+    chat: &Chat,
+    config: Option<(CaptchaType, Option<i64>)>,
+) -> Result<()> {
+    let key = captcha_state_key(chat);
+    if let Some((captcha_type, kick_time)) = config {
+        let model = captchastate::ActiveModel {
+            chat: Set(chat.get_id()),
+            captcha_type: Set(captcha_type),
+            kick_time: Set(kick_time),
+            captcha_text: NotSet,
+            timeout_mute: NotSet,
+        };
+        let model = captchastate::Entity::insert(model)
+            .on_conflict(
+                OnConflict::column(captchastate::Column::Chat)
+                    .update_columns([
+                        captchastate::Column::CaptchaType,
+                        captchastate::Column::KickTime,
+                    ])
+                    .to_owned(),
+            )
+            .exec_with_returning(*DB)
+            .await?;
+        model.cache(key).await?;
+    } else {
+        captchastate::Entity::delete_by_id(chat.get_id())
+            .exec(*DB)
+            .await?;
+        REDIS.invalidate(&[&key]).await?;
+    }
+    Ok(())
+}
+
 pub(crate) async fn goodbye_members(
     ctx: &Context,
     model: welcomes::Model,
@@ -129,7 +233,7 @@ pub(crate) async fn goodbye_members(
         lang_fmt!(lang, "defaultgoodbye")
     };
 
-    SendMediaReply::new(ctx, model.goodbye_media_type.unwrap_or(MediaType::Text))
+    let sent = SendMediaReply::new(ctx, model.goodbye_media_type.unwrap_or(MediaType::Text))
         .button_callback(|_, _| async move { Ok(()) }.boxed())
         .text(Some(text))
         .media_id(model.goodbye_media_id)
@@ -137,10 +241,281 @@ pub(crate) async fn goodbye_members(
         .buttons(buttons)
         .send_media()
         .await?;
+    if let Some(chat) = ctx.chat() {
+        clean_greeting(chat, true, sent).await?;
+    }
+    Ok(())
+}
+
+#[inline(always)]
+fn last_greeting_key(chat: i64, goodbye: bool) -> String {
+    format!("lastgreet:{}:{}", chat, goodbye)
+}
+
+/// Remember the welcome or goodbye just sent, deleting the previous one if the chat
+/// has /cleanwelcome or /cleangoodbye on
+async fn clean_greeting(chat: &Chat, goodbye: bool, sent: Option<Message>) -> Result<()> {
+    let Some(sent) = sent else {
+        return Ok(());
+    };
+    let dialog = dialog_or_default(chat).await?;
+    let clean = if goodbye {
+        dialog.clean_goodbye
+    } else {
+        dialog.clean_welcome
+    };
+    if !clean {
+        return Ok(());
+    }
+    let key = last_greeting_key(chat.get_id(), goodbye);
+    let previous: Option<i64> = REDIS.sq(|q| q.getset(&key, sent.get_message_id())).await?;
+    if let Some(previous) = previous {
+        // admins may have deleted it already, or it is too old for bots to delete
+        if let Err(err) = TG
+            .client()
+            .build_delete_message(chat.get_id(), previous)
+            .build()
+            .await
+        {
+            log::info!("failed to clean greeting in {}: {}", chat.get_id(), err);
+        }
+    }
+    Ok(())
+}
+
+/// How long individual greetings stay suppressed after a mass join or leave before
+/// a summary is posted
+const MASS_EVENT_SUMMARY_MINUTES: i64 = 5;
+
+#[inline(always)]
+fn mass_event_counter(chat: i64, joined: bool, minute: i64) -> String {
+    format!("gflood:{}:{}:{}", chat, joined, minute)
+}
+
+#[inline(always)]
+fn mass_event_suppressed(chat: i64, joined: bool) -> String {
+    format!("gsupp:{}:{}", chat, joined)
+}
+
+/// Count a join or leave, returning true if the chat is seeing more joins or leaves
+/// per minute than its configured limit. Suppressed greetings are counted and replaced
+/// with a single summary posted once things calm down
+async fn suppress_mass_event(userchanged: &UserChanged<'_>) -> Result<bool> {
+    let (upd, joined) = match userchanged {
+        UserChanged::UserJoined(upd) => (upd, true),
+        UserChanged::UserLeft(upd) => (upd, false),
+    };
+    let chat = upd.get_chat();
+    let limit = dialog_or_default(chat).await?.greet_flood_limit;
+    if limit <= 0 {
+        return Ok(false);
+    }
+    let minute = chrono::Utc::now().timestamp() / 60;
+    let key = mass_event_counter(chat.get_id(), joined, minute);
+    let (count,): (i64,) = REDIS
+        .pipe(|q| {
+            q.incr(&key, 1)
+                .expire(&key, Duration::try_minutes(2).unwrap().num_seconds())
+                .ignore()
+        })
+        .await?;
+    let skey = mass_event_suppressed(chat.get_id(), joined);
+    let suppressing: bool = REDIS.sq(|q| q.exists(&skey)).await?;
+    if count <= limit as i64 && !suppressing {
+        return Ok(false);
+    }
+
+    let suppressed: i64 = REDIS.sq(|q| q.incr(&skey, 1)).await?;
+    if suppressed == 1 {
+        let chat = chat.clone();
+        spawn(async move {
+            let window = Duration::try_minutes(MASS_EVENT_SUMMARY_MINUTES).unwrap();
+            sleep(window.to_std()?).await;
+            let skey = mass_event_suppressed(chat.get_id(), joined);
+            let (count,): (Option<i64>,) = REDIS.pipe(|q| q.get(&skey).del(&skey).ignore()).await?;
+            if let Some(count) = count {
+                let lang = get_chat_lang(chat.get_id()).await?;
+                let text = if joined {
+                    lang_fmt!(lang, "massjoined", count, MASS_EVENT_SUMMARY_MINUTES)
+                } else {
+                    lang_fmt!(lang, "massleft", count, MASS_EVENT_SUMMARY_MINUTES)
+                };
+                chat.speak(text).await?;
+            }
+            Ok::<(), BotError>(())
+        });
+    }
+    Ok(true)
+}
+
+#[inline(always)]
+fn rules_ack_key(chat: i64, user: i64) -> String {
+    format!("rack:{}:{}", chat, user)
+}
+
+/// Set while a user is muted waiting to agree to the rules, so solving the captcha
+/// doesn't unmute them early
+#[inline(always)]
+fn rules_pending_key(chat: i64, user: i64) -> String {
+    format!("rpend:{}:{}", chat, user)
+}
+
+/// Gets the time a user agreed to a chat's rules, None if they never did
+pub async fn get_rules_ack(chat: i64, user: i64) -> Result<Option<DateTime<Utc>>> {
+    let key = rules_ack_key(chat, user);
+    let res = default_cache_query(
+        |_, _| async move {
+            let res = rules_acks::Entity::find_by_id((chat, user))
+                .one(*DB)
+                .await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.map(|v| v.acknowledged))
+}
+
+/// Records that a user agreed to a chat's rules. Only the first agreement is kept
+pub async fn acknowledge_rules(chat: i64, user: i64) -> Result<()> {
+    let model = rules_acks::ActiveModel {
+        chat: Set(chat),
+        user: Set(user),
+        acknowledged: Set(Utc::now()),
+    };
+    rules_acks::Entity::insert(model)
+        .on_conflict(
+            OnConflict::columns([rules_acks::Column::Chat, rules_acks::Column::User])
+                .do_nothing()
+                .to_owned(),
+        )
+        .exec_without_returning(*DB)
+        .await?;
+    let (key, pending) = (rules_ack_key(chat, user), rules_pending_key(chat, user));
+    REDIS.invalidate(&[&key, &pending]).await?;
     Ok(())
 }
 
+/// Returns true if a user joining a chat has to agree to the rules before speaking
+async fn needs_rules_ack(chat: &Chat, user: &User) -> Result<bool> {
+    if !dialog_or_default(chat).await?.rules_gate
+        || user.get_id() == ME.get().unwrap().get_id()
+        || user.is_admin(chat).await?
+    {
+        return Ok(false);
+    }
+    Ok(get_rules_ack(chat.get_id(), user.get_id()).await?.is_none())
+}
+
+/// Mutes a new member and builds the button they press to agree to the rules. If the
+/// chat also has a captcha the user is only unmuted once both are done
+async fn rules_ack_button(
+    ctx: &Context,
+    upd: &ChatMemberUpdated,
+    captcha: bool,
+) -> Result<InlineKeyboardButton> {
+    let chat = upd.get_chat().to_owned();
+    let user = upd.get_from().get_id();
+    ctx.mute(user, &chat, None).await?;
+    let key = rules_pending_key(chat.get_id(), user);
+    REDIS
+        .pipe(|q| {
+            q.set(&key, true)
+                .expire(&key, Duration::try_days(1).unwrap().num_seconds())
+        })
+        .await?;
+
+    let button = InlineKeyboardButtonBuilder::new(lang_fmt!(ctx, "rulesagree"))
+        .set_callback_data(Uuid::new_v4().to_string())
+        .build();
+    let ctx = ctx.clone();
+    button.on_push_multi(move |callback| {
+        let ctx = ctx.clone();
+        let chat = chat.clone();
+        async move {
+            if callback.get_from().get_id() != user {
+                TG.client()
+                    .build_answer_callback_query(callback.get_id())
+                    .text(&lang_fmt!(ctx, "rulesagreenotyou"))
+                    .show_alert(true)
+                    .build()
+                    .await?;
+                return Ok(false);
+            }
+            acknowledge_rules(chat.get_id(), user).await?;
+            let text = if captcha && !user_is_authorized(chat.get_id(), user).await? {
+                lang_fmt!(ctx, "rulesagreedcaptcha")
+            } else {
+                ctx.unmute(user, &chat).await?;
+                lang_fmt!(ctx, "rulesagreed")
+            };
+            TG.client()
+                .build_answer_callback_query(callback.get_id())
+                .text(&text)
+                .build()
+                .await?;
+            Ok(true)
+        }
+    });
+    Ok(button)
+}
+
 /// Handle sending a welcome message along with a text captcha
+/// Get a chat's welcome settings along with the entities and buttons for the welcome and
+/// goodbye messages
+pub(crate) async fn get_welcome(
+    chat_id: i64,
+) -> Result<
+    Option<(
+        welcomes::Model,
+        Vec<MessageEntity>,
+        Vec<MessageEntity>,
+        Option<InlineKeyboardBuilder>,
+        Option<InlineKeyboardBuilder>,
+    )>,
+> {
+    let key = format!("welcome:{}", chat_id);
+
+    let v: Option<RedisStr> = REDIS.sq(|q| q.get(&key)).await?;
+    if let Some(v) = v {
+        Ok(v.get()?)
+    } else {
+        let res = welcomes::get_filters_join(welcomes::Column::Chat.eq(chat_id)).await?;
+        log::info!("should_welcome cache miss {:?}", res);
+        let res = res
+            .into_iter()
+            .map(|(model, (entity, goodbye, button, gb_button))| {
+                (
+                    model,
+                    entity
+                        .into_iter()
+                        .map(|e| e.get())
+                        .map(|(e, u)| e.to_entity(u))
+                        .collect(),
+                    goodbye
+                        .into_iter()
+                        .map(|e| e.get())
+                        .map(|(e, u)| e.to_entity(u))
+                        .collect(),
+                    get_markup_for_buttons(button.into_iter().collect()),
+                    get_markup_for_buttons(gb_button.into_iter().collect()),
+                )
+            })
+            .next();
+
+        if let Some(ref map) = res {
+            REDIS
+                .try_pipe(|p| {
+                    Ok(p.set(&key, map.to_redis()?)
+                        .expire(&key, CONFIG.timing.cache_timeout))
+                })
+                .await?;
+        }
+        Ok(res)
+    }
+}
+
 pub(crate) async fn welcome_members(
     ctx: &Context,
     upd: &ChatMemberUpdated,
@@ -175,12 +550,16 @@ pub(crate) async fn welcome_members(
         b.button(button);
     }
 
-    SendMediaReply::new(ctx, model.media_type.unwrap_or(MediaType::Text))
+    if needs_rules_ack(upd.get_chat(), upd.get_from()).await? {
+        b.button(rules_ack_button(ctx, upd, captcha.is_some()).await?);
+    }
+
+    let sent = SendMediaReply::new(ctx, model.media_type.unwrap_or(MediaType::Text))
         .button_callback(move |note, button| {
             let c = c.clone();
             async move {
                 button.on_push(move |b| async move {
-                    TG.client
+                    TG.client()
                         .build_answer_callback_query(b.get_id())
                         .build()
                         .await?;
@@ -199,10 +578,62 @@ pub(crate) async fn welcome_members(
         .buttons(extra_buttons)
         .send_media()
         .await?;
+    clean_greeting(upd.get_chat(), false, sent).await?;
 
     Ok(())
 }
 
+/// Send the current welcome to the chat a command was used in as if the user running it had
+/// just joined. Captcha and rules buttons are left out since nobody is joining
+pub(crate) async fn preview_welcome(ctx: &Context, lang: &Lang) -> Result<()> {
+    let message = ctx.message()?;
+    let chat = message.get_chat().get_id();
+    let (text, media_id, media_type, entities, buttons) = match get_welcome(chat).await? {
+        Some((model, entities, _, buttons, _)) => (
+            model.text,
+            model.media_id,
+            model.media_type,
+            entities,
+            buttons,
+        ),
+        None => (None, None, None, vec![], None),
+    };
+    let text = if let Some(text) = text {
+        text
+    } else {
+        lang_fmt!(lang, "defaultwelcome")
+    };
+    let c = ctx.clone();
+    SendMediaReply::new(ctx, media_type.unwrap_or(MediaType::Text))
+        .button_callback(move |note, button| {
+            let c = c.clone();
+            async move {
+                button.on_push(move |b| async move {
+                    TG.client()
+                        .build_answer_callback_query(b.get_id())
+                        .build()
+                        .await?;
+
+                    handle_transition(&c, chat, note, b).await?;
+                    Ok(())
+                });
+
+                Ok(())
+            }
+            .boxed()
+        })
+        .text(Some(text))
+        .media_id(media_id)
+        .extra_entities(entities)
+        .buttons(buttons)
+        .send_media_reply()
+        .await?;
+    Ok(())
+}
+
+/// Number of wrong answers offered alongside the correct one
+const CAPTCHA_DECOYS: usize = 8;
+
 fn build_captcha_sync() -> (String, Vec<u8>, Vec<char>) {
     let captcha = gen(captcha::Difficulty::Hard);
 
@@ -251,24 +682,15 @@ async fn incorrect_tries(callback: &CallbackQuery, incorrect_chat: i64) -> Resul
     Ok(count)
 }
 
-/// Generates a series of "incorrect" captcha answers, pushing them as InlineKeyboardButton
+/// Pushes an "incorrect" captcha answer with the given label as InlineKeyboardButton
 /// onto a Vec of buttons
 fn insert_incorrect(
     ctx: &Context,
     res: &mut Vec<InlineKeyboardButton>,
-    correct: &str,
-    supported: &Vec<char>,
+    label: String,
     unmute_chat: i64,
 ) {
-    let mut rng = thread_rng();
-    let mut s = String::with_capacity(correct.len());
-    for _ in correct.chars() {
-        if let Some(ch) = supported.choose(&mut rng) {
-            s.push(*ch);
-        }
-    }
-    drop(rng);
-    let s = InlineKeyboardButtonBuilder::new(s)
+    let s = InlineKeyboardButtonBuilder::new(label)
         .set_callback_data(Uuid::new_v4().to_string())
         .build();
     let ctx = ctx.clone();
@@ -278,7 +700,7 @@ fn insert_incorrect(
             if let Some(MaybeInaccessibleMessage::Message(message)) = callback.get_message() {
                 let count = 3 - incorrect_tries(&callback, unmute_chat).await?;
                 if count > 0 {
-                    TG.client
+                    TG.client()
                         .build_answer_callback_query(callback.get_id())
                         .show_alert(true)
                         .text(&lang_fmt!(ctx, "incorrect", count))
@@ -286,13 +708,14 @@ fn insert_incorrect(
                         .await?;
                     Ok(false)
                 } else {
-                    TG.client
+                    TG.client()
                         .build_answer_callback_query(callback.get_id())
                         .show_alert(true)
                         .text(&lang_fmt!(ctx, "notries"))
                         .build()
                         .await?;
-                    kick(callback.get_from().get_id(), unmute_chat).await?;
+                    reject_captcha(callback.get_from().get_id(), unmute_chat).await?;
+                    record_captcha_event(unmute_chat, CaptchaEvent::Failed).await?;
                     if let Some(chat) = unmute_chat.get_chat().await? {
                         message
                             .reply(lang_fmt!(ctx, "notrieskickchat", chat.name_humanreadable()))
@@ -300,7 +723,7 @@ fn insert_incorrect(
                     } else {
                         message.reply(lang_fmt!(ctx, "notrieskick")).await?;
                     }
-                    TG.client
+                    TG.client()
                         .build_delete_message(message.get_chat().get_id(), message.get_message_id())
                         .build()
                         .await?;
@@ -323,22 +746,21 @@ async fn get_invite_link(chat: &Chat) -> Result<Option<String>> {
 
 fn get_choices(
     correct: String,
-    supported: &Vec<char>,
-    times: usize,
+    incorrect: Vec<String>,
     unmute_chat: Chat,
     ctx: &Context,
 ) -> Vec<InlineKeyboardButton> {
     let mut rng = thread_rng();
-    let mut res = Vec::<InlineKeyboardButton>::with_capacity(times);
-    let pos = rng.gen_range(0..=times);
+    let mut res = Vec::<InlineKeyboardButton>::with_capacity(incorrect.len() + 1);
+    let pos = rng.gen_range(0..=incorrect.len());
     drop(rng);
-    //log::info!("selected captcha correct pos {}", pos);
     let incorrect_chat = unmute_chat.get_id();
-    for _ in 0..pos {
-        insert_incorrect(ctx, &mut res, correct.as_str(), supported, incorrect_chat);
+    let mut incorrect = incorrect.into_iter();
+    for label in incorrect.by_ref().take(pos) {
+        insert_incorrect(ctx, &mut res, label, incorrect_chat);
     }
 
-    let correct_button = InlineKeyboardButtonBuilder::new(correct.clone())
+    let correct_button = InlineKeyboardButtonBuilder::new(correct)
         .set_callback_data(Uuid::new_v4().to_string())
         .build();
     let c = ctx.clone();
@@ -354,23 +776,9 @@ fn get_choices(
                 );
 
                 let button = button.build();
-
-                TG.client()
-                    .build_edit_message_caption()
-                    .caption(&lang_fmt!(c, "correctchoice"))
-                    .message_id(message.get_message_id())
-                    .chat_id(message.get_chat().get_id())
-                    .reply_markup(&button)
-                    .build()
-                    .await?;
+                edit_solved(&message, &lang_fmt!(c, "correctchoice"), Some(&button)).await?;
             } else {
-                TG.client()
-                    .build_edit_message_caption()
-                    .caption(&lang_fmt!(c, "correctchoice"))
-                    .message_id(message.get_message_id())
-                    .chat_id(message.get_chat().get_id())
-                    .build()
-                    .await?;
+                edit_solved(&message, &lang_fmt!(c, "correctchoice"), None).await?;
             }
             c.authorize_user(callback.get_from().get_id(), &unmute_chat)
                 .await?;
@@ -385,38 +793,166 @@ fn get_choices(
     });
     res.push(correct_button);
 
-    for _ in (pos + 1)..times {
-        insert_incorrect(ctx, &mut res, correct.as_str(), supported, incorrect_chat);
+    for label in incorrect {
+        insert_incorrect(ctx, &mut res, label, incorrect_chat);
     }
     res
 }
 
-/// Sends a "text" captcha to the specified chat
+/// Sends the captcha for unmute_chat in reply to a message, a math question or a text image
+/// depending on the chat's captcha mode
 pub async fn send_captcha<'a>(message: &Message, unmute_chat: Chat, ctx: &Context) -> Result<()> {
-    let (correct, bytes, supported) = build_captcha_sync();
+    let mode = get_chat_captcha_config(&unmute_chat)
+        .await?
+        .map(|v| v.captcha_type)
+        .unwrap_or(CaptchaType::Text);
+    send_private_captcha(
+        message.get_chat().get_id(),
+        Some(message.get_message_id()),
+        unmute_chat,
+        &mode,
+        ctx,
+    )
+    .await
+}
+
+/// Sends a captcha with multiple choice answers for unmute_chat to a chat, optionally as a
+/// reply. Button captchas don't have answers, so they are sent as text captchas
+async fn send_private_captcha(
+    chat: i64,
+    reply: Option<i64>,
+    unmute_chat: Chat,
+    mode: &CaptchaType,
+    ctx: &Context,
+) -> Result<()> {
+    match mode {
+        CaptchaType::Math => send_captcha_math(chat, reply, unmute_chat, ctx).await,
+        CaptchaType::Text | CaptchaType::Button => {
+            send_captcha_photo(chat, reply, unmute_chat, ctx).await
+        }
+    }
+}
+
+/// Lays out captcha answers in rows of three
+fn choices_markup(choices: Vec<InlineKeyboardButton>) -> EReplyMarkup {
     let mut builder = InlineKeyboardBuilder::default();
-    for (i, choice) in get_choices(correct, &supported, 9, unmute_chat, ctx)
-        .into_iter()
-        .enumerate()
-    {
+    for (i, choice) in choices.into_iter().enumerate() {
         builder.button(choice);
 
         if i % 3 == 2 {
             builder.newline();
         }
     }
-    TG.client()
-        .build_send_photo(
-            message.get_chat().get_id(),
-            botapi::gen_types::FileData::Bytes(bytes),
-        )
-        .caption(&lang_fmt!(ctx, "captchawarning"))
-        .reply_parameters(&ReplyParametersBuilder::new(message.get_message_id()).build())
-        .reply_markup(&botapi::gen_types::EReplyMarkup::InlineKeyboardMarkup(
-            builder.build(),
-        ))
-        .build()
-        .await?;
+    EReplyMarkup::InlineKeyboardMarkup(builder.build())
+}
+
+/// Replaces the text of a solved captcha, or its caption if it was an image
+async fn edit_solved(
+    message: &Message,
+    text: &str,
+    markup: Option<&InlineKeyboardMarkup>,
+) -> Result<()> {
+    let (chat, message_id) = (message.get_chat().get_id(), message.get_message_id());
+    let client = TG.client();
+    if message.get_photo().is_some() {
+        let mut edit = client
+            .build_edit_message_caption()
+            .caption(text)
+            .message_id(message_id)
+            .chat_id(chat);
+        if let Some(markup) = markup {
+            edit = edit.reply_markup(markup);
+        }
+        edit.build().await?;
+    } else {
+        let mut edit = client
+            .build_edit_message_text(text)
+            .message_id(message_id)
+            .chat_id(chat);
+        if let Some(markup) = markup {
+            edit = edit.reply_markup(markup);
+        }
+        edit.build().await?;
+    }
+    Ok(())
+}
+
+/// Generates wrong answers for a text captcha from the characters the captcha can contain
+fn text_decoys(correct: &str, supported: &[char]) -> Vec<String> {
+    let mut rng = thread_rng();
+    (0..CAPTCHA_DECOYS)
+        .map(|_| {
+            correct
+                .chars()
+                .filter_map(|_| supported.choose(&mut rng))
+                .collect()
+        })
+        .collect()
+}
+
+/// Generates an arithmetic question, its answer, and distinct wrong answers close enough
+/// to the real one that they can't be ruled out without solving it
+fn build_math_captcha() -> (String, String, Vec<String>) {
+    let mut rng = thread_rng();
+    let (a, b) = (rng.gen_range(2..=12i64), rng.gen_range(2..=12i64));
+    let (question, answer) = match rng.gen_range(0..3) {
+        0 => (format!("{} + {}", a, b), a + b),
+        1 => (format!("{} - {}", a.max(b), a.min(b)), a.max(b) - a.min(b)),
+        _ => (format!("{} × {}", a, b), a * b),
+    };
+    let mut decoys = Vec::with_capacity(CAPTCHA_DECOYS);
+    while decoys.len() < CAPTCHA_DECOYS {
+        let decoy = answer + rng.gen_range(-10..=10);
+        if decoy >= 0 && decoy != answer && !decoys.contains(&decoy) {
+            decoys.push(decoy);
+        }
+    }
+    let decoys = decoys.into_iter().map(|v| v.to_string()).collect();
+    (question, answer.to_string(), decoys)
+}
+
+/// Sends a "text" captcha for unmute_chat to a chat, optionally as a reply
+async fn send_captcha_photo(
+    chat: i64,
+    reply: Option<i64>,
+    unmute_chat: Chat,
+    ctx: &Context,
+) -> Result<()> {
+    let (correct, bytes, supported) = build_captcha_sync();
+    let decoys = text_decoys(&correct, &supported);
+    let markup = choices_markup(get_choices(correct, decoys, unmute_chat, ctx));
+    let caption = lang_fmt!(ctx, "captchawarning");
+    let reply = reply.map(|v| ReplyParametersBuilder::new(v).build());
+    let client = TG.client();
+    let mut photo = client
+        .build_send_photo(chat, botapi::gen_types::FileData::Bytes(bytes))
+        .caption(&caption)
+        .reply_markup(&markup);
+    if let Some(ref reply) = reply {
+        photo = photo.reply_parameters(reply);
+    }
+    photo.build().await?;
+
+    Ok(())
+}
+
+/// Sends a "math" captcha for unmute_chat to a chat, optionally as a reply
+async fn send_captcha_math(
+    chat: i64,
+    reply: Option<i64>,
+    unmute_chat: Chat,
+    ctx: &Context,
+) -> Result<()> {
+    let (question, answer, decoys) = build_math_captcha();
+    let markup = choices_markup(get_choices(answer, decoys, unmute_chat, ctx));
+    let text = lang_fmt!(ctx, "mathcaptcha", question);
+    let reply = reply.map(|v| ReplyParametersBuilder::new(v).build());
+    let client = TG.client();
+    let mut message = client.build_send_message(chat, &text).reply_markup(&markup);
+    if let Some(ref reply) = reply {
+        message = message.reply_parameters(reply);
+    }
+    message.build().await?;
 
     Ok(())
 }
@@ -440,6 +976,10 @@ async fn button_captcha<'a>(
     });
     let mut button = InlineKeyboardBuilder::default();
     button.button(unmute_button);
+    let text = captcha
+        .captcha_text
+        .clone()
+        .unwrap_or_else(|| lang_fmt!(ctx, "pushunmute"));
     if let Some(welcome) = welcome {
         welcome_members(
             ctx,
@@ -453,7 +993,7 @@ async fn button_captcha<'a>(
         .await?;
     } else if !should_ignore_chat(upd.get_chat().get_id()).await? {
         TG.client()
-            .build_send_message(upd.get_chat().get_id(), &lang_fmt!(ctx, "pushunmute"))
+            .build_send_message(upd.get_chat().get_id(), &text)
             .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(button.build()))
             .build()
             .await?
@@ -490,9 +1030,13 @@ async fn send_captcha_chooser(
     if let Some(welcome) = welcome {
         welcome_members(ctx, upd, welcome, entities, buttons, lang, Some(catpcha)).await?;
     } else {
+        let text = catpcha
+            .captcha_text
+            .clone()
+            .unwrap_or_else(|| lang_fmt!(ctx, "solvecaptcha"));
         let nm = TG
             .client()
-            .build_send_message(chat.get_id(), &lang_fmt!(ctx, "solvecaptcha"))
+            .build_send_message(chat.get_id(), &text)
             .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(button.build()))
             .build()
             .await?;
@@ -512,6 +1056,73 @@ impl Context {
         }
     }
 
+    /// Holds a join request until the user solves a captcha sent to their DM. The
+    /// request is approved on success and declined on failure or timeout
+    async fn join_request_captcha(&self, req: &ChatJoinRequest) -> Result<()> {
+        let chat = req.get_chat();
+        let user = req.get_from();
+        let Some(config) = get_chat_captcha_config(chat).await? else {
+            return Ok(());
+        };
+        if user_is_authorized(chat.get_id(), user.get_id()).await? {
+            TG.client()
+                .build_approve_chat_join_request(chat.get_id(), user.get_id())
+                .build()
+                .await?;
+            return Ok(());
+        }
+
+        let timeout = config
+            .kick_time
+            .and_then(Duration::try_seconds)
+            .unwrap_or_else(|| Duration::try_minutes(JOIN_CAPTCHA_MINUTES).unwrap());
+        let key = join_request_key(chat.get_id(), user.get_id());
+        REDIS
+            .pipe(|q| q.set(&key, true).expire(&key, timeout.num_seconds() * 2))
+            .await?;
+        record_captcha_event(chat.get_id(), CaptchaEvent::Presented).await?;
+        schedule(
+            JOIN_TIMEOUT_JOB,
+            &(chat.get_id(), user.get_id()),
+            Utc::now() + timeout,
+        )
+        .await?;
+
+        let dm = req.get_user_chat_id();
+        match config.captcha_type {
+            CaptchaType::Text | CaptchaType::Math => {
+                send_private_captcha(dm, None, chat.clone(), &config.captcha_type, self).await?
+            }
+            CaptchaType::Button => {
+                let approve = InlineKeyboardButtonBuilder::new(lang_fmt!(self, "pressjoin"))
+                    .set_callback_data(Uuid::new_v4().to_string())
+                    .build();
+                let (bctx, bchat) = (self.clone(), chat.clone());
+                approve.on_push(|callback| async move {
+                    bctx.authorize_user(callback.get_from().get_id(), &bchat)
+                        .await?;
+                    TG.client()
+                        .build_answer_callback_query(callback.get_id())
+                        .text(&lang_fmt!(bctx, "joinapproved", bchat.name_humanreadable()))
+                        .build()
+                        .await?;
+                    Ok(())
+                });
+                let mut button = InlineKeyboardBuilder::default();
+                button.button(approve);
+                TG.client()
+                    .build_send_message(
+                        dm,
+                        &lang_fmt!(self, "joincaptcha", chat.name_humanreadable()),
+                    )
+                    .reply_markup(&EReplyMarkup::InlineKeyboardMarkup(button.build()))
+                    .build()
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
     async fn check_members<'a>(
         &self,
         config: &captchastate::Model,
@@ -537,22 +1148,32 @@ impl Context {
                             .expire(&key, Duration::try_minutes(10).unwrap().num_seconds())
                     })
                     .await?;
+                record_captcha_event(chat.get_id(), CaptchaEvent::Presented).await?;
                 if let Some(kicktime) = config.kick_time {
                     let chatid = chat.get_id();
                     let userid = user.get_id();
-                    tokio::spawn(async move {
+                    let mute = config.timeout_mute;
+                    spawn(async move {
                         let kicktime = Duration::try_seconds(kicktime)
                             .unwrap_or_else(|| Duration::try_minutes(5).unwrap());
                         sleep(kicktime.to_std()?).await;
 
                         if !user_is_authorized(chatid, userid).await? {
-                            kick(userid, chatid).await?;
+                            if mute {
+                                // the mute from joining is already stored in actions, so
+                                // it only needs to stop the captcha from being solved
+                                let key = get_captcha_auth_key(userid, chatid);
+                                REDIS.sq(|q| q.del(&key)).await?;
+                            } else {
+                                kick(userid, chatid).await?;
+                            }
+                            record_captcha_event(chatid, CaptchaEvent::Timeout).await?;
                         }
                         Ok::<(), BotError>(())
                     });
                 }
                 match config.captcha_type {
-                    CaptchaType::Text => {
+                    CaptchaType::Text | CaptchaType::Math => {
                         send_captcha_chooser(
                             self,
                             message,
@@ -596,6 +1217,9 @@ impl Context {
         );
         if let Some(userchanged) = self.update().user_event() {
             if welcome.enabled {
+                if suppress_mass_event(&userchanged).await? {
+                    return Ok(());
+                }
                 match userchanged {
                     UserChanged::UserJoined(member) => {
                         welcome_members(
@@ -620,6 +1244,9 @@ impl Context {
 
     /// Send a captcha, welcome, or both to a user entering a chat
     pub async fn greeter_handle_update(&self) -> Result<()> {
+        if let UpdateExt::ChatJoinRequest(ref req) = self.update() {
+            return self.join_request_captcha(req).await;
+        }
         if let UpdateExt::ChatMember(ref upd) = self.update() {
             log::info!("chat_member update");
             match (
@@ -661,6 +1288,7 @@ impl Context {
             captcha_type: NotSet,
             kick_time: NotSet,
             captcha_text: NotSet,
+            timeout_mute: NotSet,
         };
         let model = captchastate::Entity::insert(model)
             .on_conflict(
@@ -685,7 +1313,7 @@ impl Context {
             .exec(*DB)
             .await?;
 
-        REDIS.sq(|q| q.del(&key)).await?;
+        REDIS.invalidate(&[&key]).await?;
         message.reply(lang_fmt!(self, "disabledcaptcha")).await?;
         Ok(())
     }
@@ -701,6 +1329,7 @@ impl Context {
             captcha_type: NotSet,
             kick_time: Set(kick),
             captcha_text: NotSet,
+            timeout_mute: NotSet,
         };
 
         let key = captcha_state_key(message.get_chat());
@@ -719,6 +1348,7 @@ impl Context {
             captcha_type: Set(mode),
             kick_time: NotSet,
             captcha_text: NotSet,
+            timeout_mute: NotSet,
         };
 
         let key = captcha_state_key(message.get_chat());
@@ -733,6 +1363,63 @@ impl Context {
         Ok(())
     }
 
+    /// Sets the text sent to new members with the captcha for the current chat. None for
+    /// the default text
+    pub async fn captchatext(&self, text: Option<String>) -> Result<()> {
+        let message = self.message()?;
+        self.check_permissions(|p| p.can_change_info).await?;
+        let model = captchastate::ActiveModel {
+            chat: Set(message.get_chat().get_id()),
+            captcha_type: NotSet,
+            kick_time: NotSet,
+            captcha_text: Set(text),
+            timeout_mute: NotSet,
+        };
+
+        let key = captcha_state_key(message.get_chat());
+        if let Ok(model) = captchastate::Entity::update(model).exec(*DB).await {
+            let reply = if model.captcha_text.is_some() {
+                lang_fmt!(self, "captchatextset")
+            } else {
+                lang_fmt!(self, "captchatextreset")
+            };
+            model.cache(key).await?;
+            message.reply(reply).await?;
+        } else {
+            message.reply(lang_fmt!(self, "captchanotenabled")).await?;
+        }
+        Ok(())
+    }
+
+    /// Sets whether users who don't solve the captcha in time are kept muted instead of
+    /// being kicked
+    pub async fn captchaaction(&self, mute: bool) -> Result<()> {
+        let message = self.message()?;
+        self.check_permissions(|p| p.can_change_info.and(p.can_restrict_members))
+            .await?;
+        let model = captchastate::ActiveModel {
+            chat: Set(message.get_chat().get_id()),
+            captcha_type: NotSet,
+            kick_time: NotSet,
+            captcha_text: NotSet,
+            timeout_mute: Set(mute),
+        };
+
+        let key = captcha_state_key(message.get_chat());
+        if let Ok(model) = captchastate::Entity::update(model).exec(*DB).await {
+            let reply = if model.timeout_mute {
+                lang_fmt!(self, "captchaactionmute")
+            } else {
+                lang_fmt!(self, "captchaactionkick")
+            };
+            model.cache(key).await?;
+            message.reply(reply).await?;
+        } else {
+            message.reply(lang_fmt!(self, "captchanotenabled")).await?;
+        }
+        Ok(())
+    }
+
     async fn should_welcome(
         &self,
         upd: &ChatMemberUpdated,
@@ -745,48 +1432,7 @@ impl Context {
             Option<InlineKeyboardBuilder>,
         )>,
     > {
-        let chat = upd.get_chat();
-        let key = format!("welcome:{}", chat.get_id());
-        let chat_id = chat.get_id();
-
-        let v: Option<RedisStr> = REDIS.sq(|q| q.get(&key)).await?;
-        let res = if let Some(v) = v {
-            Ok(v.get()?)
-        } else {
-            let res = welcomes::get_filters_join(welcomes::Column::Chat.eq(chat_id)).await?;
-            log::info!("should_welcome cache miss {:?}", res);
-            let res = res
-                .into_iter()
-                .map(|(model, (entity, goodbye, button, gb_button))| {
-                    (
-                        model,
-                        entity
-                            .into_iter()
-                            .map(|e| e.get())
-                            .map(|(e, u)| e.to_entity(u))
-                            .collect(),
-                        goodbye
-                            .into_iter()
-                            .map(|e| e.get())
-                            .map(|(e, u)| e.to_entity(u))
-                            .collect(),
-                        get_markup_for_buttons(button.into_iter().collect()),
-                        get_markup_for_buttons(gb_button.into_iter().collect()),
-                    )
-                })
-                .next();
-
-            if let Some(ref map) = res {
-                REDIS
-                    .try_pipe(|p| {
-                        Ok(p.set(&key, map.to_redis()?)
-                            .expire(&key, CONFIG.timing.cache_timeout))
-                    })
-                    .await?;
-            }
-            Ok(res)
-        };
-
+        let res = get_welcome(upd.get_chat().get_id()).await;
         log::info!("should_welcome {:?}", res);
         res
     }
@@ -804,13 +1450,36 @@ impl Context {
                 user,
             };
 
-            self.unmute(user, unmute_chat).await?;
+            let pending = rules_pending_key(unmute_chat.get_id(), user);
+            if !approve_join_request(user, unmute_chat.get_id()).await?
+                && !REDIS.sq(|q| q.exists(&pending)).await?
+            {
+                self.unmute(user, unmute_chat).await?;
+            }
             authorized::Entity::insert(model.into_active_model())
                 .on_conflict(OnConflict::new().do_nothing().to_owned())
                 .exec(*DB)
                 .await?;
+            record_captcha_event(unmute_chat.get_id(), CaptchaEvent::Solved).await?;
         }
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn math_captcha_decoys() {
+        for _ in 0..100 {
+            let (_, answer, decoys) = build_math_captcha();
+            assert_eq!(decoys.len(), CAPTCHA_DECOYS);
+            assert!(!decoys.contains(&answer));
+            for (i, decoy) in decoys.iter().enumerate() {
+                assert!(!decoys[i + 1..].contains(decoy));
+            }
+        }
+    }
+}
@@ -1,15 +1,27 @@
 use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 
+use botapi::bot::Part;
 use botapi::gen_types::{
-    EReplyMarkup, InlineKeyboardButtonBuilder, MaybeInaccessibleMessage, UpdateExt,
+    EReplyMarkup, FileData, InlineKeyboardButtonBuilder, MaybeInaccessibleMessage, UpdateExt,
 };
-use chrono::Duration;
+use chrono::{Duration, Utc};
 use futures::{future::BoxFuture, Future, FutureExt};
 use macros::lang_fmt;
 use redis::AsyncCommands;
-use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, TransactionTrait};
+use sea_orm::{
+    ColumnTrait, EntityTrait, IntoActiveModel, PaginatorTrait, QueryFilter, TransactionTrait,
+};
 use sea_query::OnConflict;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{
+        DeserializeOwned, DeserializeSeed, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor,
+    },
+    Deserialize, Deserializer, Serialize,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
 use uuid::Uuid;
 
 use crate::{
@@ -24,19 +36,31 @@ use crate::{
     statics::{CONFIG, DB, ME, REDIS, TG},
     util::{
         error::{BotError, Result},
-        string::Speak,
+        scheduler::{cancel, register_job, schedule},
+        string::{get_chat_lang, Speak},
     },
 };
 
 use super::{
-    admin_helpers::is_dm,
+    admin_helpers::{download_file, is_dm},
     button::{InlineKeyboardBuilder, OnPush},
     command::Context,
-    markdown::EntityMessage,
+    markdown::{EntityMessage, Escape},
+    user::{GetChat, Username},
 };
 
+/// Version of the backup format written by /export. Bump this when a module changes the
+/// layout of its section and handle the old layout in ModuleHelpers::upgrade_export
+pub const EXPORT_VERSION: u32 = 1;
+
+/// Version assumed for backups without one, like the ones made by Rose bot
+pub const ROSE_EXPORT_VERSION: u32 = 0;
+
 #[derive(Serialize, Deserialize)]
 pub struct RoseExport {
+    /// written before data so streaming imports know the version before any sections
+    #[serde(default)]
+    pub version: u32,
     pub bot_id: i64,
     pub data: HashMap<String, serde_json::Value>,
 }
@@ -51,12 +75,581 @@ impl RoseExport {
     pub fn new() -> Self {
         let bot_id = ME.get().unwrap().get_id();
         Self {
+            version: EXPORT_VERSION,
             bot_id,
             data: HashMap::new(),
         }
     }
 }
 
+/// Job kind used to resume imports interrupted by a restart
+const IMPORT_JOB: &str = "streamimport";
+
+/// How long an import can go without finishing a section before it is assumed dead and
+/// started again from its job
+const IMPORT_RESUME_AFTER: i64 = 600;
+
+/// An import of a backup file, stored in the job queue until it finishes so it can be
+/// resumed if the bot restarts partway through
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportJob {
+    pub id: Uuid,
+    pub chat: i64,
+    pub file_id: String,
+    /// Message edited to show progress, if one could be sent
+    pub progress: Option<i64>,
+}
+
+/// Outcome of an import, by section
+#[derive(Default, Debug)]
+pub struct ImportReport {
+    pub imported: Vec<String>,
+    pub unsupported: Vec<String>,
+    pub failed: Vec<(String, String)>,
+    /// Set if the file stopped parsing partway through
+    pub parse_error: Option<String>,
+}
+
+#[inline(always)]
+fn get_import_key(id: &Uuid) -> String {
+    format!("import:{}", id)
+}
+
+fn get_import_path(id: &Uuid) -> PathBuf {
+    std::env::temp_dir().join(format!("import-{}.json", id))
+}
+
+/// A section of an export along with the version of the export it came from
+type Section = (String, u32, serde_json::Value);
+
+/// Visits the top level of an export, handing off the data map to SectionsVisitor
+struct ExportVisitor(mpsc::Sender<Section>);
+
+/// Visits the data map of an export, sending each section as soon as it is parsed
+struct SectionsVisitor(mpsc::Sender<Section>, u32);
+
+impl<'de> Visitor<'de> for ExportVisitor {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an export object")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let mut version = ROSE_EXPORT_VERSION;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == "version" {
+                version = map.next_value()?;
+            } else if key == "data" {
+                map.next_value_seed(SectionsVisitor(self.0.clone(), version))?;
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for SectionsVisitor {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> std::result::Result<(), D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for SectionsVisitor {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("a map of sections")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> std::result::Result<(), A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some((name, value)) = map.next_entry::<String, serde_json::Value>()? {
+            self.0
+                .blocking_send((name, self.1, value))
+                .map_err(|_| A::Error::custom("import stopped"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse an export file one section at a time. Blocks, so must be run with spawn_blocking
+fn parse_sections(path: &Path, sections: mpsc::Sender<Section>) -> Result<()> {
+    let file = BufReader::new(std::fs::File::open(path)?);
+    let mut deserializer = serde_json::Deserializer::from_reader(file);
+    deserializer.deserialize_map(ExportVisitor(sections))?;
+    deserializer.end()?;
+    Ok(())
+}
+
+async fn edit_import_progress(job: &ImportJob, text: &str) -> Result<()> {
+    if let Some(progress) = job.progress {
+        TG.client()
+            .build_edit_message_text(text)
+            .message_id(progress)
+            .chat_id(job.chat)
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+/// Import each section of a downloaded export as it is parsed. Sections already imported
+/// by an earlier attempt at the same job are skipped
+async fn import_sections(job: &ImportJob, checkpoint: &mut Uuid) -> Result<ImportReport> {
+    let lang = get_chat_lang(job.chat).await?;
+    let key = get_import_key(&job.id);
+    let path = get_import_path(&job.id);
+    download_file(&job.file_id, &path).await?;
+
+    let (tx, mut rx) = mpsc::channel(1);
+    let parser = tokio::task::spawn_blocking(move || parse_sections(&path, tx));
+    let mut report = ImportReport::default();
+    while let Some((name, version, value)) = rx.recv().await {
+        let done: bool = REDIS.sq(|q| q.sismember(&key, &name)).await?;
+        if done {
+            report.imported.push(name);
+            continue;
+        }
+        edit_import_progress(
+            job,
+            &lang_fmt!(lang, "importprogress", name, report.imported.len()),
+        )
+        .await?;
+        match crate::modules::import_section(job.chat, &name, version, value).await {
+            Ok(true) => {
+                REDIS
+                    .pipe(|q| {
+                        q.sadd(&key, &name)
+                            .ignore()
+                            .expire(&key, IMPORT_RESUME_AFTER * 6)
+                            .ignore()
+                    })
+                    .await?;
+                report.imported.push(name);
+            }
+            Ok(false) => report.unsupported.push(name),
+            Err(err) => {
+                log::warn!("failed to import section {}: {}", name, err);
+                report.failed.push((name, err.to_string()));
+            }
+        }
+
+        // push back the resume job, since this import is still making progress
+        cancel(checkpoint).await?;
+        *checkpoint = schedule(
+            IMPORT_JOB,
+            job,
+            Utc::now() + Duration::try_seconds(IMPORT_RESUME_AFTER).unwrap(),
+        )
+        .await?;
+    }
+
+    if let Err(err) = parser.await? {
+        report.parse_error = Some(err.to_string());
+    }
+    Ok(report)
+}
+
+async fn report_import(job: &ImportJob, report: ImportReport) -> Result<()> {
+    let lang = get_chat_lang(job.chat).await?;
+    let name = job
+        .chat
+        .get_chat()
+        .await?
+        .map(|v| v.name_humanreadable().into_owned())
+        .unwrap_or_else(|| job.chat.to_string());
+    let taint = taint::Entity::find()
+        .filter(taint::Column::Chat.eq(job.chat))
+        .count(*DB)
+        .await?;
+    let mut text = if taint == 0 {
+        lang_fmt!(lang, "imported", name)
+    } else {
+        lang_fmt!(lang, "taintdetected", name)
+    };
+    if !report.imported.is_empty() {
+        text.push_str(&lang_fmt!(
+            lang,
+            "importsections",
+            report.imported.join(", ")
+        ));
+    }
+    if !report.unsupported.is_empty() {
+        text.push_str(&lang_fmt!(
+            lang,
+            "importunsupported",
+            report.unsupported.join(", ")
+        ));
+    }
+    for (section, err) in report.failed {
+        text.push_str(&lang_fmt!(lang, "importfailed", section, err.escape(false)));
+    }
+    if let Some(err) = report.parse_error {
+        text.push_str(&lang_fmt!(lang, "importparseerror", err.escape(false)));
+    }
+    if job.progress.is_some() {
+        edit_import_progress(job, &text).await?;
+    } else {
+        job.chat.speak(text).await?;
+    }
+    Ok(())
+}
+
+/// Run an import job to completion. A resume job is kept in the queue while it runs, so
+/// an import cut short by a restart starts again and skips the sections already done
+pub async fn run_import(job: ImportJob) -> Result<()> {
+    let mut checkpoint = schedule(
+        IMPORT_JOB,
+        &job,
+        Utc::now() + Duration::try_seconds(IMPORT_RESUME_AFTER).unwrap(),
+    )
+    .await?;
+    let res = import_sections(&job, &mut checkpoint).await;
+    cancel(&checkpoint).await?;
+    if let Err(err) = tokio::fs::remove_file(get_import_path(&job.id)).await {
+        log::warn!("failed to remove import file: {}", err);
+    }
+    let key = get_import_key(&job.id);
+    REDIS.sq(|q| q.del(&key)).await?;
+    report_import(&job, res?).await
+}
+
+/// Start importing a backup document sent to a chat, editing a progress message as each
+/// section is imported
+pub async fn start_import(chat: i64, file_id: String) -> Result<()> {
+    let lang = get_chat_lang(chat).await?;
+    let progress = chat.speak(lang_fmt!(lang, "importstarted")).await?;
+    let job = ImportJob {
+        id: Uuid::new_v4(),
+        chat,
+        file_id,
+        progress: progress.map(|v| v.get_message_id()),
+    };
+    run_import(job).await
+}
+
+/// Register the handler that resumes interrupted imports
+pub fn register_import_job() {
+    register_job(IMPORT_JOB, |payload| async move {
+        let job: ImportJob = serde_json::from_value(payload)?;
+        log::info!("resuming import {} for {}", job.id, job.chat);
+        run_import(job).await
+    });
+}
+
+/// File format of a flat list like Rose bot's fban exports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListFormat {
+    /// One json object per line, or a json array of objects
+    Json,
+    /// Comma separated values with a header row
+    Csv,
+}
+
+impl ListFormat {
+    pub fn from_arg(arg: &str) -> Option<Self> {
+        match arg.to_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+/// A row of a list that can be exported and imported as either json or csv
+pub trait ListRow: Serialize + DeserializeOwned + Send + 'static {
+    /// Header row written at the top of each csv file
+    const CSV_HEADER: &'static str;
+
+    /// Fields of this row in the same order as the header
+    fn to_csv(&self) -> Vec<String>;
+
+    /// Read a row from its csv fields, returning None if the fields are invalid
+    fn from_csv(fields: Vec<String>) -> Option<Self>;
+}
+
+/// Quote a csv field if it contains anything that would break the row
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Split a single csv line into fields, handling quoted fields and escaped quotes
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Builds a list export, uploading a new document each time `chunk` rows are written so
+/// large lists stay well under telegram's upload limit
+pub struct ChunkedUpload<T: ListRow> {
+    chat: i64,
+    name: String,
+    format: ListFormat,
+    chunk: usize,
+    buf: String,
+    rows: usize,
+    parts: usize,
+    row: PhantomData<T>,
+}
+
+impl<T: ListRow> ChunkedUpload<T> {
+    pub fn new(chat: i64, name: &str, format: ListFormat, chunk: usize) -> Self {
+        Self {
+            chat,
+            name: name.to_owned(),
+            format,
+            chunk,
+            buf: String::new(),
+            rows: 0,
+            parts: 0,
+            row: PhantomData,
+        }
+    }
+
+    pub async fn push(&mut self, row: &T) -> Result<()> {
+        if self.rows == 0 && self.format == ListFormat::Csv {
+            self.buf.push_str(T::CSV_HEADER);
+            self.buf.push('\n');
+        }
+        match self.format {
+            ListFormat::Json => self.buf.push_str(&serde_json::to_string(row)?),
+            ListFormat::Csv => self.buf.push_str(
+                &row.to_csv()
+                    .iter()
+                    .map(|v| csv_field(v))
+                    .collect::<Vec<String>>()
+                    .join(","),
+            ),
+        }
+        self.buf.push('\n');
+        self.rows += 1;
+        if self.rows >= self.chunk {
+            self.upload().await?;
+        }
+        Ok(())
+    }
+
+    async fn upload(&mut self) -> Result<()> {
+        self.parts += 1;
+        let name = if self.parts == 1 {
+            format!("{}.{}", self.name, self.format.extension())
+        } else {
+            format!("{}-{}.{}", self.name, self.parts, self.format.extension())
+        };
+        let text = std::mem::take(&mut self.buf);
+        self.rows = 0;
+        TG.client()
+            .build_send_document(self.chat, FileData::Part(Part::text(text).file_name(name)))
+            .build()
+            .await?;
+        Ok(())
+    }
+
+    /// Upload any rows left over, returning the number of documents sent
+    pub async fn finish(mut self) -> Result<usize> {
+        if self.rows > 0 {
+            self.upload().await?;
+        }
+        Ok(self.parts)
+    }
+}
+
+/// A row read from an imported list along with its line or item number. The row is None
+/// if it could not be read
+pub type ListItem<T> = (usize, Option<T>);
+
+/// Visits a json array of rows, sending them in batches as they are parsed
+struct ListVisitor<'a, T> {
+    out: &'a mut ListBatcher<T>,
+}
+
+impl<'de, 'a, T: ListRow> Visitor<'de> for ListVisitor<'a, T> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an array of rows")
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<(), A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        while let Some(value) = seq.next_element::<serde_json::Value>()? {
+            self.out
+                .push(serde_json::from_value(value).ok())
+                .map_err(|_| A::Error::custom("import stopped"))?;
+        }
+        Ok(())
+    }
+}
+
+struct ListBatcher<T> {
+    tx: mpsc::Sender<Vec<ListItem<T>>>,
+    batch: Vec<ListItem<T>>,
+    size: usize,
+    count: usize,
+}
+
+impl<T> ListBatcher<T> {
+    fn push(&mut self, row: Option<T>) -> Result<()> {
+        self.count += 1;
+        self.batch.push((self.count, row));
+        if self.batch.len() >= self.size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if !self.batch.is_empty() {
+            self.tx
+                .blocking_send(std::mem::take(&mut self.batch))
+                .map_err(|_| BotError::Generic("import stopped".to_owned()))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse a list file in batches, guessing the format from the first character. Blocks, so
+/// must be run with spawn_blocking
+fn parse_list<T: ListRow>(path: &Path, out: &mut ListBatcher<T>) -> Result<()> {
+    let mut file = BufReader::new(std::fs::File::open(path)?);
+    let first = loop {
+        let buf = file.fill_buf()?;
+        let Some(pos) = buf.iter().position(|v| !v.is_ascii_whitespace()) else {
+            if buf.is_empty() {
+                return Ok(());
+            }
+            let len = buf.len();
+            file.consume(len);
+            continue;
+        };
+        let first = buf[pos];
+        file.consume(pos);
+        break first;
+    };
+    match first {
+        b'[' => {
+            let mut deserializer = serde_json::Deserializer::from_reader(file);
+            deserializer.deserialize_seq(ListVisitor { out: &mut *out })?;
+            deserializer.end()?;
+        }
+        b'{' => {
+            for value in
+                serde_json::Deserializer::from_reader(file).into_iter::<serde_json::Value>()
+            {
+                out.push(serde_json::from_value(value?).ok())?;
+            }
+        }
+        _ => {
+            for (i, line) in file.lines().enumerate() {
+                let line = line?;
+                let line = line.trim();
+                if line.is_empty() {
+                    out.count += 1;
+                    continue;
+                }
+                match T::from_csv(split_csv_line(line)) {
+                    // allow a header row
+                    None if i == 0 => out.count += 1,
+                    row => out.push(row)?,
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+/// A json or csv list being imported in batches. Batches are parsed as they are read, so
+/// lists of any size can be imported without holding the whole file in memory
+pub struct ListImport<T> {
+    path: PathBuf,
+    rx: mpsc::Receiver<Vec<ListItem<T>>>,
+    parser: JoinHandle<Result<()>>,
+}
+
+impl<T: ListRow> ListImport<T> {
+    /// Download a list document and start parsing it in batches of `size` rows
+    pub async fn start(file_id: &str, size: usize) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("list-{}", Uuid::new_v4()));
+        if let Err(err) = download_file(file_id, &path).await {
+            if let Err(err) = tokio::fs::remove_file(&path).await {
+                log::warn!("failed to remove list file: {}", err);
+            }
+            return Err(err);
+        }
+        let (tx, rx) = mpsc::channel(1);
+        let parse_path = path.clone();
+        let parser = tokio::task::spawn_blocking(move || {
+            let mut out = ListBatcher {
+                tx,
+                batch: Vec::with_capacity(size),
+                size,
+                count: 0,
+            };
+            parse_list::<T>(&parse_path, &mut out)
+        });
+        Ok(Self { path, rx, parser })
+    }
+
+    /// Get the next batch of rows, or None once the file has been read
+    pub async fn next(&mut self) -> Option<Vec<ListItem<T>>> {
+        self.rx.recv().await
+    }
+
+    /// Wait for the parser to stop, returning an error if the file stopped parsing partway
+    pub async fn finish(mut self) -> Result<()> {
+        self.rx.close();
+        (&mut self.parser).await?
+    }
+}
+
+impl<T> Drop for ListImport<T> {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            log::warn!("failed to remove list file: {}", err);
+        }
+    }
+}
+
 #[inline(always)]
 fn get_taint_key(media_id: &str) -> String {
     format!("tt:{}", media_id)
@@ -100,7 +693,7 @@ pub async fn set_taint(model: taint::Model) -> Result<()> {
         .exec_without_returning(*DB)
         .await?;
     if res > 0 {
-        REDIS.sq(|q| q.del(&key)).await?;
+        REDIS.invalidate(&[&key]).await?;
     }
     Ok(())
 }
@@ -133,7 +726,7 @@ pub async fn set_taint_vec(media_id: Vec<taint::Model>) -> Result<()> {
             if res > 0 {
                 for key in existing {
                     let k = get_taint_key(&key.media_id);
-                    REDIS.sq(|q| q.del(&k)).await?;
+                    REDIS.invalidate(&[&k]).await?;
                 }
             }
 
@@ -152,7 +745,7 @@ pub async fn remove_taint(taint: &str) -> Result<()> {
         .await?;
 
     let key = get_taint_key(taint);
-    REDIS.sq(|p| p.del(&key)).await?;
+    REDIS.invalidate(&[&key]).await?;
 
     Ok(())
 }
@@ -221,7 +814,7 @@ impl Context {
                             log::info!("handle taint {} {}", taint.media_id, new_media_id);
 
                             cb(&taint, new_media_id).await?;
-                            REDIS.sq(|q| q.del(&key)).await?;
+                            REDIS.invalidate(&[&key]).await?;
                             remove_taint(&taint.media_id).await?;
                             return Ok(());
                         }
@@ -292,7 +885,7 @@ impl Context {
             let taintmessage = lang_fmt!(self, "taintforward", media_type);
             replace.on_push(move |c| async move {
                 if let Some(MaybeInaccessibleMessage::Message(message)) = c.get_message() {
-                    TG.client
+                    TG.client()
                         .build_edit_message_text(&taintmessage)
                         .message_id(message.get_message_id())
                         .chat_id(message.get_chat().get_id())
@@ -322,7 +915,7 @@ impl Context {
 
             delete.on_push(|c| async move {
                 if let Some(MaybeInaccessibleMessage::Message(message)) = c.get_message() {
-                    TG.client
+                    TG.client()
                         .build_delete_message(message.get_chat().get_id(), message.get_message_id())
                         .build()
                         .await?;
@@ -346,3 +939,41 @@ impl Context {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_roundtrip() {
+        let fields = ["1", "plain", "with, comma", "with \"quotes\""];
+        let line = fields.iter().map(|v| csv_field(v)).collect::<Vec<String>>();
+        assert_eq!(line[2], "\"with, comma\"");
+        assert_eq!(split_csv_line(&line.join(",")), fields);
+        assert_eq!(split_csv_line("1,,"), vec!["1", "", ""]);
+    }
+
+    #[test]
+    fn section_versions() {
+        let versions = |text: &str| {
+            let path = std::env::temp_dir().join(format!("import-test-{}.json", Uuid::new_v4()));
+            std::fs::write(&path, text).unwrap();
+            let (tx, mut rx) = mpsc::channel(8);
+            parse_sections(&path, tx).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let mut out = Vec::new();
+            while let Ok((name, version, _)) = rx.try_recv() {
+                out.push((name, version));
+            }
+            out
+        };
+        assert_eq!(
+            versions(r#"{"version": 1, "bot_id": 1, "data": {"rules": {}}}"#),
+            vec![("rules".to_owned(), 1)]
+        );
+        assert_eq!(
+            versions(r#"{"bot_id": 1, "data": {"notes": {}}}"#),
+            vec![("notes".to_owned(), ROSE_EXPORT_VERSION)]
+        );
+    }
+}
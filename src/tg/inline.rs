@@ -0,0 +1,146 @@
+//! Inline queries. Telegram only accepts one answer for each query, so modules don't answer
+//! queries themselves. Instead they register a provider that returns results, and the
+//! results of every provider are sent in a single answer. A provider registered with a
+//! keyword only gets queries starting with that keyword, for example "notes rules", and
+//! providers without one get every query that doesn't start with a keyword
+
+use std::sync::Arc;
+
+use botapi::gen_types::{InlineQuery, InlineQueryResult, User};
+use dashmap::DashMap;
+use futures::{future::BoxFuture, Future, FutureExt};
+
+use crate::statics::{module_enabled, BotLocal, TG};
+use crate::util::error::Result;
+use crate::util::string::{get_chat_lang, Lang};
+
+/// Most results telegram accepts in one answer
+pub const MAX_RESULTS: usize = 50;
+
+type InlineProviderFn = Arc<
+    dyn Fn(Arc<InlineContext>) -> BoxFuture<'static, Result<Vec<InlineQueryResult>>> + Send + Sync,
+>;
+
+struct InlineProvider {
+    keyword: Option<String>,
+    func: InlineProviderFn,
+}
+
+static PROVIDERS: BotLocal<DashMap<String, InlineProvider>> = BotLocal::new(DashMap::new);
+
+/// Everything a provider needs to answer an inline query
+pub struct InlineContext {
+    query: InlineQuery,
+    text: String,
+    lang: Lang,
+}
+
+impl InlineContext {
+    /// The inline query itself
+    pub fn query(&self) -> &'_ InlineQuery {
+        &self.query
+    }
+
+    /// Text of the query, without the provider's keyword if it has one
+    pub fn text(&self) -> &'_ str {
+        &self.text
+    }
+
+    /// The user typing the query
+    pub fn user(&self) -> &'_ User {
+        self.query.get_from()
+    }
+
+    /// Language of the user's private chat with the bot
+    pub fn lang(&self) -> &'_ Lang {
+        &self.lang
+    }
+}
+
+/// Register a module's inline provider, optionally only for queries starting with a
+/// keyword. Registering the same module twice replaces the previous provider
+pub fn register_inline_provider<F, Fut>(module: &str, keyword: Option<&str>, func: F)
+where
+    F: Fn(Arc<InlineContext>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<InlineQueryResult>>> + Send + 'static,
+{
+    PROVIDERS.insert(
+        module.to_owned(),
+        InlineProvider {
+            keyword: keyword.map(|v| v.to_lowercase()),
+            func: Arc::new(move |ctx| func(ctx).boxed()),
+        },
+    );
+}
+
+/// Split the first word off a query, returning it lowercased along with the rest
+fn split_keyword(query: &str) -> (String, &'_ str) {
+    let query = query.trim();
+    let (keyword, rest) = query.split_once(char::is_whitespace).unwrap_or((query, ""));
+    (keyword.to_lowercase(), rest.trim_start())
+}
+
+/// Answer an inline query with the results of every provider it is meant for. Providers
+/// that fail are logged and left out instead of failing the whole answer
+pub async fn answer_inline_query(query: &InlineQuery) -> Result<()> {
+    let (keyword, rest) = split_keyword(query.get_query());
+    let keyed = PROVIDERS
+        .iter()
+        .any(|v| v.keyword.as_deref() == Some(keyword.as_str()) && module_enabled(v.key()));
+    let providers = PROVIDERS
+        .iter()
+        .filter(|v| module_enabled(v.key()))
+        .filter(|v| {
+            if keyed {
+                v.keyword.as_deref() == Some(keyword.as_str())
+            } else {
+                v.keyword.is_none()
+            }
+        })
+        .map(|v| (v.key().to_owned(), Arc::clone(&v.func)))
+        .collect::<Vec<_>>();
+    if providers.is_empty() {
+        return Ok(());
+    }
+
+    let ctx = Arc::new(InlineContext {
+        text: if keyed {
+            rest.to_owned()
+        } else {
+            query.get_query().trim().to_owned()
+        },
+        lang: get_chat_lang(query.get_from().get_id()).await?,
+        query: query.to_owned(),
+    });
+    let answers =
+        futures::future::join_all(providers.iter().map(|(_, func)| func(Arc::clone(&ctx)))).await;
+    let mut results = Vec::new();
+    for ((module, _), answer) in providers.iter().zip(answers) {
+        match answer {
+            Ok(answer) => results.extend(answer),
+            Err(err) => {
+                log::warn!("inline provider {} failed: {}", module, err);
+                err.record_stats();
+            }
+        }
+    }
+    results.truncate(MAX_RESULTS);
+    TG.client()
+        .build_answer_inline_query(query.get_id(), &results)
+        .is_personal(true)
+        .build()
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn keyword_split() {
+        assert_eq!(split_keyword("Notes  rules"), ("notes".to_owned(), "rules"));
+        assert_eq!(split_keyword(" cat "), ("cat".to_owned(), ""));
+        assert_eq!(split_keyword(""), ("".to_owned(), ""));
+    }
+}
@@ -0,0 +1,157 @@
+//! Receives updates from telegram over a webhook instead of long polling. The listener
+//! speaks plain http and is meant to sit behind a reverse proxy or load balancer that
+//! terminates tls. Every request must carry the secret token registered with setWebhook
+//! and arrive on the configured path, anything else is rejected before its body is read
+
+use botapi::bot::Bot;
+use botapi::gen_types::{Update, UpdateExt};
+use futures::channel::mpsc;
+use futures::future::BoxFuture;
+use futures::{FutureExt, SinkExt, Stream};
+use std::sync::Arc;
+use warp::http::StatusCode;
+use warp::path::FullPath;
+use warp::reject::Reject;
+use warp::{Filter, Rejection, Reply};
+
+use crate::statics::CONFIG;
+use crate::util::error::{BotError, Result};
+
+/// Header telegram puts the secret token in
+pub const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Largest update body accepted
+const MAX_BODY: u64 = 1 << 20;
+
+/// Updates waiting to be handled before telegram is made to wait
+const QUEUE: usize = 1024;
+
+/// A request turned away before its body was read
+#[derive(Debug)]
+struct Denied(StatusCode);
+
+impl Reject for Denied {}
+
+/// Compare a received secret to the expected one in constant time
+fn check_secret(expected: &str, got: Option<&str>) -> bool {
+    match got {
+        Some(got) if got.len() == expected.len() => {
+            got.bytes()
+                .zip(expected.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                == 0
+        }
+        _ => false,
+    }
+}
+
+/// Get the path updates are accepted on, from the config or the public webhook url
+fn get_path() -> Result<String> {
+    if let Some(ref path) = CONFIG.webhook.path {
+        return Ok(path.to_owned());
+    }
+    let url = reqwest::Url::parse(&CONFIG.webhook.webhook_url).map_err(BotError::generic)?;
+    Ok(url.path().to_owned())
+}
+
+/// Get the secret token telegram sends with every update. It has to be configured since
+/// instances behind a load balancer share one webhook, and a secret made up on start would
+/// only be known to whichever instance registered the webhook last
+fn get_secret() -> Result<String> {
+    let secret = CONFIG
+        .webhook
+        .secret_token
+        .as_deref()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| BotError::generic("webhook.secret_token must be set to use a webhook"))?;
+    if secret.len() > 256
+        || !secret
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(BotError::generic(
+            "webhook.secret_token can only contain A-Z, a-z, 0-9, _ and -, up to 256 characters",
+        ));
+    }
+    Ok(secret.to_owned())
+}
+
+/// Turn requests away with the status they were denied with, other rejections are left to
+/// warp
+async fn recover_denied(err: Rejection) -> std::result::Result<impl Reply, Rejection> {
+    match err.find::<Denied>() {
+        Some(Denied(status)) => Ok(warp::reply::with_status(warp::reply(), *status)),
+        None => Err(err),
+    }
+}
+
+/// Register the webhook with telegram and start listening. Returns the received updates
+/// and the server, which stops listening when dropped
+pub async fn listen(
+    client: &Bot,
+    allowed_updates: Option<Vec<String>>,
+) -> Result<(impl Stream<Item = UpdateExt>, BoxFuture<'static, ()>)> {
+    let secret = Arc::new(get_secret()?);
+    let path = Arc::new(get_path()?);
+
+    let mut request = client
+        .build_set_webhook(&CONFIG.webhook.webhook_url)
+        .secret_token(&secret);
+    if let Some(ref allowed) = allowed_updates {
+        request = request.allowed_updates(allowed);
+    }
+    if let Some(max) = CONFIG.webhook.max_connections {
+        request = request.max_connections(max);
+    }
+    request.build().await?;
+
+    let (tx, rx) = mpsc::channel(QUEUE);
+    // check where the request was sent and who sent it before reading the body
+    let authorized = warp::path::full()
+        .and(warp::header::optional::<String>(SECRET_HEADER))
+        .and_then(move |full: FullPath, token: Option<String>| {
+            let (path, secret) = (Arc::clone(&path), Arc::clone(&secret));
+            async move {
+                if full.as_str() != path.as_str() {
+                    Err(warp::reject::custom(Denied(StatusCode::NOT_FOUND)))
+                } else if !check_secret(&secret, token.as_deref()) {
+                    Err(warp::reject::custom(Denied(StatusCode::UNAUTHORIZED)))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one();
+    let route = warp::post()
+        .and(authorized)
+        .and(warp::body::content_length_limit(MAX_BODY))
+        .and(warp::body::json::<Update>())
+        .then(move |update: Update| {
+            let mut tx = tx.clone();
+            async move {
+                match tx.send(UpdateExt::from(update)).await {
+                    Ok(()) => StatusCode::OK,
+                    Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+                }
+            }
+        })
+        .map(|status| warp::reply::with_status(warp::reply(), status))
+        .recover(recover_denied);
+
+    log::info!("listening for updates on {}", CONFIG.webhook.listen);
+    let server = warp::serve(route).run(CONFIG.webhook.listen).boxed();
+    Ok((rx, server))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn secret_check() {
+        assert!(check_secret("secret", Some("secret")));
+        assert!(!check_secret("secret", Some("secreT")));
+        assert!(!check_secret("secret", Some("secret2")));
+        assert!(!check_secret("secret", None));
+    }
+}
@@ -0,0 +1,100 @@
+//! Completions from an OpenAI compatible chat api. Everything sent to the endpoint passes
+//! through redact first, so user ids and usernames never leave the bot even if they were
+//! typed into a message
+
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::statics::CONFIG;
+use crate::util::error::{BotError, Result};
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::new();
+    static ref ID_REGEX: Regex = Regex::new(r"-?\b\d{6,}\b").unwrap();
+    static ref USERNAME_REGEX: Regex = Regex::new(r"@[A-Za-z][A-Za-z0-9_]{3,31}\b").unwrap();
+    static ref TG_LINK_REGEX: Regex =
+        Regex::new(r"(?i)\b(?:https?://)?(?:t\.me|telegram\.me)/[A-Za-z0-9_+/]+").unwrap();
+}
+
+#[derive(Deserialize)]
+struct CompletionMessage {
+    content: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CompletionChoice {
+    message: CompletionMessage,
+}
+
+#[derive(Deserialize)]
+struct CompletionResponse {
+    choices: Vec<CompletionChoice>,
+}
+
+/// Replace anything that could identify a telegram account: numeric ids, usernames, and
+/// t.me links
+pub fn redact(text: &str) -> String {
+    let text = TG_LINK_REGEX.replace_all(text, "[link]");
+    let text = USERNAME_REGEX.replace_all(&text, "@[user]");
+    ID_REGEX.replace_all(&text, "[id]").into_owned()
+}
+
+async fn request(system: &str, prompt: &str) -> Result<String> {
+    let url = format!("{}/chat/completions", CONFIG.llm.url.trim_end_matches('/'));
+    let mut req = CLIENT.post(url).json(&json!({
+        "model": CONFIG.llm.model,
+        "messages": [
+            { "role": "system", "content": system },
+            { "role": "user", "content": prompt },
+        ],
+    }));
+    if let Some(key) = CONFIG.llm.api_key.as_ref() {
+        req = req.bearer_auth(key);
+    }
+    let res: CompletionResponse = req
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .map_err(|err| err.without_url())?;
+    res.choices
+        .into_iter()
+        .next()
+        .and_then(|v| v.message.content)
+        .ok_or_else(|| BotError::generic("completion missing from response"))
+}
+
+/// Ask the endpoint to respond to a prompt following the system instructions. The prompt
+/// is redacted and cut to llm.max_prompt_chars, keeping the end since that is the most
+/// recent part of a conversation
+pub async fn complete(system: &str, prompt: &str) -> Result<String> {
+    let prompt = redact(prompt);
+    let skip = prompt
+        .chars()
+        .count()
+        .saturating_sub(CONFIG.llm.max_prompt_chars);
+    let prompt = prompt.chars().skip(skip).collect::<String>();
+    let timeout = Duration::from_secs(CONFIG.llm.timeout_seconds);
+    tokio::time::timeout(timeout, request(system, &prompt))
+        .await
+        .map_err(|_| BotError::generic("completion took too long"))?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redacts_ids() {
+        assert_eq!(
+            redact("ban @spam_bot (id 123456789) from -1001234567890, see t.me/joinchat/abc"),
+            "ban @[user] (id [id]) from [id], see [link]"
+        );
+        assert_eq!(redact("it costs 12345 coins"), "it costs 12345 coins");
+    }
+}
@@ -0,0 +1,53 @@
+//! Global maintenance mode. While it is on every module command is answered with a
+//! maintenance notice instead of running, except for owner commands and commands sent by
+//! sudo users. Updates that are not commands are handled as usual.
+
+use macros::lang_fmt;
+use redis::AsyncCommands;
+
+use crate::metadata::CommandLevel;
+use crate::statics::{CONFIG, REDIS};
+use crate::util::error::Result;
+use crate::util::string::Speak;
+
+use super::client::MetadataCollection;
+use super::command::{Cmd, Context};
+
+const MAINTENANCE_KEY: &str = "maintenance";
+
+/// Check if maintenance mode is on, falling back to the config value if it was never
+/// toggled
+pub async fn is_maintenance() -> Result<bool> {
+    let enabled: Option<bool> = REDIS.sq(|q| q.get(MAINTENANCE_KEY)).await?;
+    Ok(enabled.unwrap_or(CONFIG.admin.maintenance))
+}
+
+/// Turn maintenance mode on or off for every instance sharing this redis
+pub async fn set_maintenance(enabled: bool) -> Result<()> {
+    REDIS.sq(|q| q.set(MAINTENANCE_KEY, enabled)).await?;
+    Ok(())
+}
+
+/// Answer a command with the maintenance notice if maintenance mode is on. Returns true
+/// if the update was handled here and should not be passed to modules
+pub async fn maintenance_middleware(ctx: &Context, helps: &MetadataCollection) -> Result<bool> {
+    let Some(&Cmd { cmd, message, .. }) = ctx.cmd() else {
+        return Ok(false);
+    };
+    match helps.command_level(cmd) {
+        None | Some(CommandLevel::Owner) => return Ok(false),
+        Some(_) => (),
+    }
+    if message
+        .get_from()
+        .map(|v| CONFIG.admin.sudo_users.contains(&v.get_id()))
+        .unwrap_or(false)
+    {
+        return Ok(false);
+    }
+    if !is_maintenance().await? {
+        return Ok(false);
+    }
+    message.reply(lang_fmt!(ctx, "maintenance")).await?;
+    Ok(true)
+}
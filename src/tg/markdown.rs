@@ -6,10 +6,12 @@ use crate::persist::core::button;
 use crate::statics::TG;
 use crate::util::error::{BotError, Result};
 use crate::util::string::AlignCharBoundry;
+use botapi::bot::Bot;
 use botapi::gen_methods::CallSendMessage;
 use botapi::gen_types::{
-    Chat, EReplyMarkup, InlineKeyboardButton, InlineKeyboardButtonBuilder, MessageEntity,
-    MessageEntityBuilder, User,
+    Chat, EReplyMarkup, InlineKeyboardButton, InlineKeyboardButtonBuilder, InlineQueryResult,
+    InlineQueryResultArticleBuilder, InputMessageContent, InputTextMessageContentBuilder,
+    MessageEntity, MessageEntityBuilder, User,
 };
 use futures::future::BoxFuture;
 use futures::FutureExt;
@@ -26,14 +28,68 @@ use std::sync::Arc;
 use thiserror::Error;
 use uuid::Uuid;
 
-/// Custom error type for murkdown parse failure. TODO: add additional context here
+/// Custom error type for murkdown parse failure. Errors from the parser itself carry no
+/// context, the position and snippet are added once the failing token is known
 #[derive(Debug, Error, Default)]
-pub struct DefaultParseErr {}
+pub struct DefaultParseErr {
+    /// character position parsing failed at
+    pub pos: Option<usize>,
+    /// what was found at pos
+    pub found: String,
+    /// tokens that could have closed what was left open before pos. Best effort, since
+    /// the parser doesn't expose what it expected
+    pub expected: Vec<&'static str>,
+    /// the line parsing failed on with a caret under pos
+    pub snippet: String,
+}
+
+/// Characters of context shown on either side of a parse error
+const SNIPPET_CONTEXT: usize = 20;
+
+impl DefaultParseErr {
+    fn at(text: &str, pos: usize, expected: Vec<&'static str>) -> Self {
+        let chars = text.chars().collect::<Vec<char>>();
+        let found = chars
+            .get(pos)
+            .map(|v| format!("'{}'", v))
+            .unwrap_or_else(|| "end of text".to_owned());
+        let pos = pos.min(chars.len());
+        let line_start = chars[..pos]
+            .iter()
+            .rposition(|v| *v == '\n')
+            .map(|v| v + 1)
+            .unwrap_or(0);
+        let line_end = chars[pos..]
+            .iter()
+            .position(|v| *v == '\n')
+            .map(|v| v + pos)
+            .unwrap_or(chars.len());
+        let start = line_start.max(pos.saturating_sub(SNIPPET_CONTEXT));
+        let end = line_end.min(pos + SNIPPET_CONTEXT);
+        let snippet = format!(
+            "{}\n{}^",
+            chars[start..end].iter().collect::<String>(),
+            " ".repeat(pos - start)
+        );
+        Self {
+            pos: Some(pos),
+            found,
+            expected,
+            snippet,
+        }
+    }
+}
 
 impl Display for DefaultParseErr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("default parse error")?;
-        Ok(())
+        let Some(pos) = self.pos else {
+            return f.write_str("default parse error");
+        };
+        write!(f, "unexpected {} at character {}", self.found, pos + 1)?;
+        if !self.expected.is_empty() {
+            write!(f, ", expected {}", self.expected.join(" or "))?;
+        }
+        write!(f, "\n{}", self.snippet)
     }
 }
 
@@ -273,6 +329,7 @@ use parser::{Parser, Token};
 use super::admin_helpers::{is_dm, ChatUser};
 use super::button::InlineKeyboardBuilder;
 use super::command::post_deep_link;
+use super::flair::get_flair;
 use super::user::Username;
 
 #[derive(Debug)]
@@ -288,6 +345,10 @@ pub struct Lexer {
     escape: bool,
     code: Option<MonoMode>,
     rawbuf: String,
+    /// whitespace trimmed from the start of the input
+    trimmed: usize,
+    /// position of the last character of each token
+    positions: Vec<usize>,
 }
 
 fn is_valid(token: char, header: bool) -> bool {
@@ -319,9 +380,22 @@ impl Lexer {
             escape: false,
             code: None,
             rawbuf: String::new(),
+            trimmed: input.chars().take_while(|v| v.is_whitespace()).count(),
+            positions: Vec::new(),
         }
     }
 
+    /// Get the character position in the original input a token starts at. Only valid
+    /// after next_token
+    fn position(&self, idx: usize, token: &Token) -> usize {
+        let end = self.positions.get(idx).copied().unwrap_or(self.s.len());
+        let start = match token {
+            Token::Str(s) | Token::Whitespace(s) => (end + 1).saturating_sub(s.chars().count()),
+            _ => end,
+        };
+        start + self.trimmed
+    }
+
     fn next_token(&mut self) -> Vec<Token> {
         let mut output = if self.header {
             vec![Token::Start]
@@ -329,7 +403,11 @@ impl Lexer {
             Vec::new()
         };
         let mut idx = 0;
+        let mut last = 0;
         while let Some(char) = self.s.get(idx) {
+            // tokens pushed while handling the last character end there
+            self.positions.resize(output.len(), last);
+            last = idx;
             // log::info!("parsing {} {}", idx, char);
             if self.code.is_some() {
                 if *char == ']' && idx > 0 && self.s.get(idx - 1) != Some(&'\\') {
@@ -475,12 +553,58 @@ impl Lexer {
             };
             idx += 1;
         }
+        self.positions.resize(output.len(), last);
+        self.positions.push(self.s.len());
         output.push(Token::Eof);
 
         output
     }
 }
 
+/// Tokenize and parse murkdown. On failure the error points to the token the parser
+/// gave up at
+fn parse_murkdown(text: &str, header: bool) -> std::result::Result<FilterCommond, DefaultParseErr> {
+    let mut parser = Parser::new();
+    let mut lexer = Lexer::new(text, header);
+    let tokens = lexer.next_token();
+    let mut open: Vec<&'static str> = Vec::new();
+    let mut after_bracket = false;
+    for (idx, token) in tokens.into_iter().enumerate() {
+        let pos = lexer.position(idx, &token);
+        let closes = match token {
+            Token::LSBracket => Some("]"),
+            Token::LTBracket => Some(">"),
+            Token::LCurly => Some("}"),
+            Token::LParen => Some(")"),
+            _ => None,
+        };
+        let is_close = matches!(
+            token,
+            Token::RSBracket | Token::RTBracket | Token::RCurly | Token::RParen
+        );
+        let is_paren = matches!(token, Token::LParen);
+        let brackets = matches!(token, Token::RSBracket | Token::RTBracket);
+        if parser.parse(token).is_err() {
+            let mut expected = Vec::new();
+            // links and buttons need a (url) right after the closing bracket
+            if after_bracket && !is_paren {
+                expected.push("(");
+            }
+            expected.extend(open.last().copied());
+            return Err(DefaultParseErr::at(text, pos, expected));
+        }
+        if let Some(close) = closes {
+            open.push(close);
+        } else if is_close {
+            open.pop();
+        }
+        after_bracket = brackets;
+    }
+    parser
+        .end_of_input()
+        .map_err(|_| DefaultParseErr::at(text, text.chars().count(), open))
+}
+
 pub type ButtonFn = Arc<
     dyn for<'b> Fn(String, &'b InlineKeyboardButton) -> BoxFuture<'b, Result<()>> + Send + Sync,
 >;
@@ -730,10 +854,12 @@ impl MarkupBuilder {
                 //a               log::info!("diff {} offset {}", self.diff, self.offset);
                 match (span, self.chatuser.as_ref()) {
                     (TgSpan::Code(code), _) => {
+                        size += code.encode_utf16().count() as i64;
                         self.code(&code);
                     }
 
                     (TgSpan::Pre((lang, code)), _) => {
+                        size += code.encode_utf16().count() as i64;
                         self.pre(&code, lang, None);
                     }
                     (TgSpan::Italic(s), _) => {
@@ -822,6 +948,14 @@ impl MarkupBuilder {
                                 size += id.encode_utf16().count() as i64;
                                 self.text_internal(&id);
                             }
+                            "flair" => {
+                                let flair =
+                                    get_flair(chatuser.chat.get_id(), chatuser.user.get_id())
+                                        .await?
+                                        .unwrap_or_default();
+                                size += flair.encode_utf16().count() as i64;
+                                self.text_internal(&flair);
+                            }
                             "rules" => {
                                 self.rules().await?;
                             }
@@ -990,12 +1124,7 @@ impl MarkupBuilder {
         {
             existing.extend_from_slice(existingnew);
         }
-        let mut parser = Parser::new();
-        let mut tokenizer = Lexer::new(text, false);
-        for token in tokenizer.next_token() {
-            parser.parse(token)?;
-        }
-        let res = parser.end_of_input()?;
+        let res = parse_murkdown(text, false)?;
         self.parse_tgspan(res.body).await?;
         if let Some(ref existing) = self.existing_entities {
             self.entities.extend_from_slice(existing.as_slice());
@@ -1019,19 +1148,23 @@ impl MarkupBuilder {
         self
     }
 
-    pub fn build(&self) -> (&'_ str, &'_ Vec<MessageEntity>) {
+    /// Fix any entities telegram would reject, logging if something had to change
+    fn normalize(&mut self) {
+        let corrections = normalize_entities(&self.text, &mut self.entities);
+        if corrections > 0 {
+            log::warn!("corrected {} invalid message entities", corrections);
+        }
+    }
+
+    pub fn build(&mut self) -> (&'_ str, &'_ Vec<MessageEntity>) {
+        self.normalize();
         (&self.text, &self.entities)
     }
 
     pub async fn build_murkdown<'a>(
         mut self,
     ) -> Result<(String, Vec<MessageEntity>, InlineKeyboardBuilder)> {
-        let mut parser = Parser::new();
-        let mut tokenizer = Lexer::new(&self.text, self.enabled_header);
-        for token in tokenizer.next_token() {
-            parser.parse(token)?;
-        }
-        let res = parser.end_of_input()?;
+        let res = parse_murkdown(&self.text, self.enabled_header)?;
         self.offset = 0;
         self.text.clear();
         self.parse_tgspan(res.body).await?;
@@ -1043,17 +1176,13 @@ impl MarkupBuilder {
         if let Some(ref existing) = self.existing_entities {
             self.entities.extend_from_slice(existing.as_slice());
         }
+        self.normalize();
 
         Ok((self.text, self.entities, self.buttons))
     }
 
     async fn nofail_internal(&mut self) -> Result<()> {
-        let mut parser = Parser::new();
-        let mut tokenizer = Lexer::new(&self.text, self.enabled_header);
-        for token in tokenizer.next_token() {
-            parser.parse(token)?;
-        }
-        let res = parser.end_of_input()?;
+        let res = parse_murkdown(&self.text, self.enabled_header)?;
         self.offset = 0;
         self.text.clear();
         self.parse_tgspan(res.body).await?;
@@ -1065,6 +1194,7 @@ impl MarkupBuilder {
         if let Some(ref existing) = self.existing_entities {
             self.entities.extend_from_slice(existing.as_slice());
         }
+        self.normalize();
 
         Ok(())
     }
@@ -1358,6 +1488,28 @@ impl MarkupBuilder {
         self
     }
 
+    /// Like build_filter, but fails instead of returning partial output if the murkdown
+    /// is invalid
+    pub async fn try_build_filter(
+        mut self,
+    ) -> Result<(
+        String,
+        Vec<MessageEntity>,
+        InlineKeyboardBuilder,
+        Option<Header>,
+        BTreeSet<String>,
+    )> {
+        self.enabled_header = true;
+        self.nofail_internal().await?;
+        Ok((
+            self.text,
+            self.entities,
+            self.buttons,
+            self.header,
+            self.fillings,
+        ))
+    }
+
     pub async fn build_filter(
         mut self,
     ) -> (
@@ -1388,6 +1540,72 @@ impl MarkupBuilder {
     }
 }
 
+/// Entities telegram doesn't allow to contain other entities
+const LEAF_ENTITIES: [&str; 2] = ["pre", "code"];
+
+/// Fix entities telegram would reject or render wrong. Entities are clipped to the text,
+/// sorted, deduplicated, and cut so they never partially overlap. Entities inside pre or
+/// code and blockquotes inside blockquotes are removed. Returns the number of entities
+/// changed or removed
+pub fn normalize_entities(text: &str, entities: &mut Vec<MessageEntity>) -> usize {
+    let len = text.encode_utf16().count() as i64;
+    let mut corrections = 0;
+    entities.retain_mut(|entity| {
+        let start = entity.get_offset().max(0);
+        let end = (entity.get_offset() + entity.get_length()).min(len);
+        if end <= start {
+            corrections += 1;
+            return false;
+        }
+        if start != entity.get_offset() || end - start != entity.get_length() {
+            corrections += 1;
+            entity.set_offset(start);
+            entity.set_length(end - start);
+        }
+        true
+    });
+    // outer entities come before the ones they contain
+    entities.sort_by_key(|v| (v.get_offset(), -v.get_length()));
+
+    let mut res: Vec<MessageEntity> = Vec::with_capacity(entities.len());
+    // end and type of each entity containing the current one
+    let mut open: Vec<(i64, String)> = Vec::new();
+    for mut entity in entities.drain(..) {
+        let start = entity.get_offset();
+        open.retain(|(end, _)| *end > start);
+        let leaf = open
+            .iter()
+            .any(|(_, t)| LEAF_ENTITIES.contains(&t.as_str()));
+        let quote =
+            entity.get_tg_type() == "blockquote" && open.iter().any(|(_, t)| t == "blockquote");
+        if leaf || quote {
+            corrections += 1;
+            continue;
+        }
+        if let Some(end) = open.iter().map(|(end, _)| *end).min() {
+            if start + entity.get_length() > end {
+                corrections += 1;
+                entity.set_length(end - start);
+            }
+        }
+        let duplicate = res
+            .iter()
+            .rev()
+            .take_while(|v| v.get_offset() == start)
+            .any(|v| {
+                v.get_length() == entity.get_length() && v.get_tg_type() == entity.get_tg_type()
+            });
+        if duplicate {
+            corrections += 1;
+            continue;
+        }
+        open.push((start + entity.get_length(), entity.get_tg_type().to_owned()));
+        res.push(entity);
+    }
+    *entities = res;
+    corrections
+}
+
 lazy_static! {
     static ref FILLER_REGEX: Regex = Regex::new(r"\{\w*\}").unwrap();
 }
@@ -1487,7 +1705,13 @@ pub async fn retro_fillings<'a>(
             "id" => {
                 let id = chatuser.user.get_id().to_string();
                 (Cow::Owned(id), None)
-            } // TODO: handle rules filler
+            }
+            "flair" => {
+                let flair = get_flair(chatuser.chat.get_id(), chatuser.user.get_id())
+                    .await?
+                    .unwrap_or_default();
+                (Cow::Owned(flair), None)
+            }
             s => {
                 let s = format!("{{{}}}", s);
                 (Cow::Owned(s), None)
@@ -1634,6 +1858,7 @@ pub struct EntityMessage {
     pub chat: i64,
     pub reply_markup: Option<EReplyMarkup>,
     pub disable_murkdown: bool,
+    client: Arc<Bot>,
 }
 
 impl EntityMessage {
@@ -1643,6 +1868,7 @@ impl EntityMessage {
             chat,
             reply_markup: None,
             disable_murkdown: false,
+            client: TG.client(),
         }
     }
 
@@ -1655,6 +1881,7 @@ impl EntityMessage {
             chat,
             reply_markup: None,
             disable_murkdown: false,
+            client: TG.client(),
         };
 
         s.builder.text(text);
@@ -1674,7 +1901,7 @@ impl EntityMessage {
     pub async fn call(&mut self) -> CallSendMessage<'_, i64> {
         if self.disable_murkdown {
             self.builder.build_murkdown_nofail_ref().await;
-            let call = TG
+            let call = self
                 .client
                 .build_send_message(self.chat, &self.builder.text)
                 .entities(&self.builder.entities);
@@ -1686,7 +1913,7 @@ impl EntityMessage {
         } else {
             let (text, entities, buttons) = self.builder.build_murkdown_nofail_ref().await;
             log::info!("call {} {}", text, self.reply_markup.is_some());
-            let call = TG
+            let call = self
                 .client
                 .build_send_message(self.chat, text)
                 .entities(entities);
@@ -1703,14 +1930,59 @@ impl EntityMessage {
     pub fn textentities(&self) -> (&'_ str, &'_ Vec<MessageEntity>) {
         (&self.builder.text, &self.builder.entities)
     }
+
+    /// Build an inline query result that sends this message when chosen, with buttons
+    /// from reply_markup or the murkdown if there are any
+    pub async fn inline_article<T>(
+        &mut self,
+        title: T,
+        description: Option<String>,
+    ) -> InlineQueryResult
+    where
+        T: Into<String>,
+    {
+        let (text, entities, buttons) = if self.disable_murkdown {
+            self.builder.build_murkdown_nofail_ref().await;
+            (
+                self.builder.text.clone(),
+                self.builder.entities.clone(),
+                None,
+            )
+        } else {
+            let (text, entities, buttons) = self.builder.build_murkdown_nofail_ref().await;
+            (text.clone(), entities.clone(), buttons.cloned())
+        };
+        let buttons = match self.reply_markup.clone().or(buttons) {
+            Some(EReplyMarkup::InlineKeyboardMarkup(buttons)) => Some(buttons),
+            _ => None,
+        };
+        let content = InputMessageContent::InputTextMessageContent(
+            InputTextMessageContentBuilder::new(text)
+                .set_entities(entities)
+                .build(),
+        );
+        let mut article =
+            InlineQueryResultArticleBuilder::new(Uuid::new_v4().to_string(), title.into(), content);
+        if let Some(description) = description {
+            article = article.set_description(description);
+        }
+        if let Some(buttons) = buttons {
+            article = article.set_reply_markup(buttons);
+        }
+        InlineQueryResult::InlineQueryResultArticle(article.build())
+    }
 }
 
+#[cfg(test)]
 #[allow(dead_code, unused_imports)]
 mod test {
     use std::borrow::Cow;
 
     use botapi::gen_types::{ChatBuilder, UserBuilder};
     use futures::executor::block_on;
+    use once_cell::sync::Lazy;
+    use proptest::prelude::*;
+    use proptest::sample::select;
 
     use super::*;
     const MARKDOWN_TEST: &str = "what
@@ -1728,6 +2000,109 @@ mod test {
 
     const ESCAPE: &str = "\\[ thing";
 
+    /// Words for generated text, most with characters wider than one UTF-16 unit
+    const WORDS: [&str; 7] = ["hello", "🥟", "héllo", "日本語", "a🥟b", "👨‍👩‍👧", "x"];
+
+    const STYLES: [(&str, &str); 6] = [
+        ("[*", "bold"),
+        ("[_", "italic"),
+        ("[~", "strikethrough"),
+        ("[__", "underline"),
+        ("[||", "spoiler"),
+        ("[`", "code"),
+    ];
+
+    const FILLINGS: [&str; 4] = ["mention", "username", "first", "chatname"];
+
+    /// Number of random inputs each property test checks
+    const CASES: u32 = 256;
+
+    /// Runtime for property tests calling async code
+    static RUNTIME: Lazy<tokio::runtime::Runtime> = Lazy::new(|| {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    });
+
+    /// Type and text of the entities some murkdown should produce
+    type Expected = Vec<(&'static str, String)>;
+
+    fn word() -> impl Strategy<Value = &'static str> {
+        select(WORDS.to_vec())
+    }
+
+    /// A piece of murkdown along with the entities it should produce
+    fn murkdown_part() -> impl Strategy<Value = (String, Expected)> {
+        // code can't contain anything, so only nest inside the other styles
+        let outer = || select(STYLES[..5].to_vec());
+        prop_oneof![
+            word().prop_map(|word| (word.to_owned(), vec![])),
+            select(FILLINGS.to_vec()).prop_map(|filling| (format!("{{{}}}", filling), vec![])),
+            word().prop_map(|word| (
+                format!("[{}](https://example.com)", word),
+                vec![("text_link", word.to_owned())]
+            )),
+            (outer(), word()).prop_map(|((open, t), word)| (
+                format!("{}{}]", open, word),
+                vec![(t, word.to_owned())]
+            )),
+            (outer(), word(), select(STYLES.to_vec()), word()).prop_map(
+                |((open, t), word, (inner_open, inner_t), inner)| (
+                    format!("{}{} {}{}]]", open, word, inner_open, inner),
+                    vec![
+                        (inner_t, inner.to_owned()),
+                        (t, format!("{} {}", word, inner))
+                    ]
+                )
+            ),
+        ]
+    }
+
+    /// Murkdown along with the entities it should produce
+    fn murkdown() -> impl Strategy<Value = (String, Expected)> {
+        proptest::collection::vec(murkdown_part(), 1..12).prop_map(|parts| {
+            let (text, expected): (Vec<String>, Vec<Expected>) = parts.into_iter().unzip();
+            (text.join(" "), expected.into_iter().flatten().collect())
+        })
+    }
+
+    /// Plain markdown made of words with wide characters
+    fn markdown() -> impl Strategy<Value = String> {
+        let part = prop_oneof![
+            word().prop_map(|word| word.to_owned()),
+            word().prop_map(|word| format!("**{}**", word)),
+            word().prop_map(|word| format!("*{}*", word)),
+            word().prop_map(|word| format!("`{}`", word)),
+            word().prop_map(|word| format!("[{}](https://example.com)", word)),
+        ];
+        proptest::collection::vec(part, 1..12).prop_map(|parts| parts.join(" "))
+    }
+
+    /// Check each entity is a valid UTF-16 range over the text, returning the type and
+    /// text of each
+    fn entity_texts(text: &str, entities: &[MessageEntity]) -> Vec<(String, String)> {
+        let utf16 = text.encode_utf16().collect_vec();
+        entities
+            .iter()
+            .map(|entity| {
+                let start = entity.get_offset();
+                let end = start + entity.get_length();
+                assert!(
+                    start >= 0 && start < end && end as usize <= utf16.len(),
+                    "entity {}..{} out of bounds in {:?}",
+                    start,
+                    end,
+                    text
+                );
+                // splitting a surrogate pair leaves invalid utf16
+                let covered = String::from_utf16(&utf16[start as usize..end as usize])
+                    .unwrap_or_else(|_| panic!("entity {}..{} splits a character", start, end));
+                (entity.get_tg_type().to_owned(), covered)
+            })
+            .collect()
+    }
+
     fn test_parse(markdown: &str) -> FilterCommond {
         let mut parser = Parser::new();
         let mut tokenizer = Lexer::new(markdown, false);
@@ -1749,6 +2124,45 @@ mod test {
         parser.end_of_input().unwrap();
     }
 
+    #[test]
+    fn parse_error_position() {
+        let err = parse_murkdown("hello [*world", false).err().unwrap();
+        assert_eq!(err.pos, Some(13));
+        assert_eq!(err.expected, vec!["]"]);
+        let err = parse_murkdown("a [link] b", false).err().unwrap();
+        assert_eq!(err.pos, Some(8));
+        assert_eq!(err.expected, vec!["("]);
+        let err = DefaultParseErr::at("ab\ncd", 4, vec![]);
+        assert_eq!(err.snippet, "cd\n ^");
+        assert_eq!(err.found, "'d'");
+    }
+
+    #[test]
+    fn normalize_overlap() {
+        let entity = |offset, length, t: &str| {
+            MessageEntityBuilder::new(offset, length)
+                .set_type(t.to_owned())
+                .build()
+        };
+        let mut entities = vec![
+            entity(6, 10, "italic"),
+            entity(0, 8, "bold"),
+            entity(0, 8, "bold"),
+            entity(20, 5, "bold"),
+        ];
+        assert_eq!(normalize_entities("hello world", &mut entities), 4);
+        let spans = entities
+            .iter()
+            .map(|v| (v.get_offset(), v.get_length()))
+            .collect_vec();
+        assert_eq!(spans, vec![(0, 8), (6, 2)]);
+
+        let mut entities = vec![entity(1, 2, "bold"), entity(0, 5, "code")];
+        assert_eq!(normalize_entities("hello", &mut entities), 1);
+        assert_eq!(entities.len(), 1);
+        assert_eq!(entities[0].get_tg_type(), "code");
+    }
+
     #[test]
     fn tokenize_test() {
         let mut tokenizer = Lexer::new(MARKDOWN_SIMPLE, false);
@@ -1912,6 +2326,51 @@ mod test {
         }
     }
 
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+        #[test]
+        fn murkdown_utf16_ranges((murkdown, expected) in murkdown()) {
+            let chat = ChatBuilder::new(0)
+                .set_title("日本 🥟 chat".to_owned())
+                .build();
+            let user = UserBuilder::new(1, false, "🥟 dumpling".to_owned()).build();
+            let chatuser = ChatUser {
+                chat: &chat,
+                user: &user,
+            };
+            let (text, entities) = RUNTIME.block_on(async {
+                let (text, entities, mut buttons) = MarkupBuilder::new(None)
+                    .set_text(murkdown.clone())
+                    .filling(false)
+                    .header(false)
+                    .build_murkdown()
+                    .await
+                    .unwrap();
+                retro_fillings(text, entities, Some(&mut buttons), &chatuser)
+                    .await
+                    .unwrap()
+            });
+            let covered = entity_texts(&text, &entities);
+            for (t, word) in expected {
+                prop_assert!(
+                    covered.iter().any(|(ct, cw)| ct == t && *cw == word),
+                    "no {} entity over {:?} for {:?}",
+                    t,
+                    word,
+                    murkdown
+                );
+            }
+        }
+
+        #[test]
+        fn markdown_utf16_ranges(markdown in markdown()) {
+            let mut builder = MarkupBuilder::from_markdown(&markdown, None);
+            let (text, entities) = builder.build();
+            entity_texts(text, entities);
+        }
+    }
+
     #[tokio::test]
     async fn parse_help() {
         let test = r#"
@@ -0,0 +1,250 @@
+//! Linter for murkdown. Finds mistakes that would otherwise be silently rendered as plain
+//! text or broken buttons, along with the character they start at so they can be shown to
+//! whoever wrote the text
+
+use std::fmt::Display;
+
+use crate::util::error::BotError;
+
+use super::markdown::MarkupBuilder;
+
+/// Fillings replaced with information about the chat or user
+pub const KNOWN_FILLINGS: [&str; 8] = [
+    "username", "first", "last", "mention", "chatname", "id", "rules", "flair",
+];
+
+/// Most entities telegram allows in a single message
+pub const MAX_ENTITIES: usize = 100;
+
+/// Url schemes telegram accepts for url buttons
+const BUTTON_SCHEMES: [&str; 3] = ["http", "https", "tg"];
+
+/// A single problem found by the linter
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LintKind {
+    /// An opening bracket that is never closed
+    Unclosed(char),
+    /// A closing bracket with nothing to close
+    Unopened(char),
+    /// A filling that isn't in KNOWN_FILLINGS
+    UnknownFilling(String),
+    /// A button pointing to something other than a url or #note
+    InvalidButtonUrl(String),
+    /// More entities than telegram will accept, with the number found
+    TooManyEntities(usize),
+    /// The text could not be parsed for some other reason
+    ParseError,
+}
+
+/// A problem and the character position (not byte) it was found at, if it has one
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lint {
+    pub pos: Option<usize>,
+    pub kind: LintKind,
+}
+
+impl Lint {
+    fn at(pos: usize, kind: LintKind) -> Self {
+        Self {
+            pos: Some(pos),
+            kind,
+        }
+    }
+}
+
+impl Display for LintKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unclosed(c) => write!(f, "{} is never closed", c),
+            Self::Unopened(c) => write!(f, "{} closes nothing", c),
+            Self::UnknownFilling(name) => write!(f, "unknown filling {{{}}}", name),
+            Self::InvalidButtonUrl(url) => write!(f, "button has invalid url \"{}\"", url),
+            Self::TooManyEntities(count) => write!(
+                f,
+                "{} formatted sections, telegram only allows {}",
+                count, MAX_ENTITIES
+            ),
+            Self::ParseError => f.write_str("text could not be parsed"),
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // positions are shown counting from one
+        if let Some(pos) = self.pos {
+            write!(f, "character {}: {}", pos + 1, self.kind)
+        } else {
+            self.kind.fmt(f)
+        }
+    }
+}
+
+fn opening(close: char) -> char {
+    match close {
+        ']' => '[',
+        ')' => '(',
+        '}' => '{',
+        _ => '<',
+    }
+}
+
+/// Find the next character that isn't escaped with a backslash
+fn find_unescaped(chars: &[char], start: usize, c: char) -> Option<usize> {
+    let mut idx = start;
+    while let Some(v) = chars.get(idx) {
+        if *v == '\\' {
+            idx += 2;
+            continue;
+        }
+        if *v == c {
+            return Some(idx);
+        }
+        idx += 1;
+    }
+    None
+}
+
+fn valid_button_url(url: &str) -> bool {
+    if url.starts_with('#') && url.len() > 1 {
+        return true;
+    }
+    reqwest::Url::parse(url)
+        .map(|v| BUTTON_SCHEMES.contains(&v.scheme()))
+        .unwrap_or(false)
+}
+
+/// Check text for problems that can be found without parsing it. This doesn't need
+/// async and is cheap enough to run on every edit
+pub fn lint_syntax(text: &str) -> Vec<Lint> {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut stack: Vec<(char, usize)> = Vec::new();
+    let mut lints = Vec::new();
+    let mut idx = 0;
+    while let Some(c) = chars.get(idx) {
+        match c {
+            '\\' => {
+                idx += 2;
+                continue;
+            }
+            // code is never formatted, so skip to the end of it
+            '[' if chars.get(idx + 1) == Some(&'`') => {
+                if let Some(end) = chars[idx + 2..]
+                    .iter()
+                    .enumerate()
+                    .position(|(i, v)| *v == ']' && chars[idx + 1 + i] != '\\')
+                {
+                    idx += end + 3;
+                    continue;
+                }
+                lints.push(Lint::at(idx, LintKind::Unclosed('[')));
+                break;
+            }
+            '[' | '(' | '{' | '<' => stack.push((*c, idx)),
+            ']' | ')' | '}' | '>' => {
+                let open = opening(*c);
+                if let Some(depth) = stack.iter().rposition(|(v, _)| *v == open) {
+                    // anything opened after the matching bracket was never closed
+                    for (v, pos) in stack.drain(depth + 1..) {
+                        lints.push(Lint::at(pos, LintKind::Unclosed(v)));
+                    }
+                    let (_, start) = stack.pop().unwrap();
+                    if *c == '}' {
+                        let name = chars[start + 1..idx].iter().collect::<String>();
+                        let name = name.trim();
+                        if !name.is_empty() && !KNOWN_FILLINGS.contains(&name) {
+                            let kind = LintKind::UnknownFilling(name.to_owned());
+                            lints.push(Lint::at(start, kind));
+                        }
+                    }
+                    if *c == '>' && chars.get(idx + 1) == Some(&'(') {
+                        if let Some(end) = find_unescaped(&chars, idx + 2, ')') {
+                            let url = chars[idx + 2..end].iter().collect::<String>();
+                            let url = url.trim();
+                            if !valid_button_url(url) {
+                                lints.push(Lint::at(
+                                    idx + 2,
+                                    LintKind::InvalidButtonUrl(url.to_owned()),
+                                ));
+                            }
+                        }
+                    }
+                } else {
+                    lints.push(Lint::at(idx, LintKind::Unopened(*c)));
+                }
+            }
+            _ => (),
+        }
+        idx += 1;
+    }
+    lints.extend(
+        stack
+            .into_iter()
+            .map(|(c, pos)| Lint::at(pos, LintKind::Unclosed(c))),
+    );
+    lints.sort_by_key(|v| v.pos);
+    lints
+}
+
+/// Check text for all known problems, including ones only found by parsing it the same
+/// way it would be parsed before sending. Returns an empty list if nothing is wrong
+pub async fn lint(text: &str) -> Vec<Lint> {
+    let mut lints = lint_syntax(text);
+    let res = MarkupBuilder::new(None)
+        .set_text(text.to_owned())
+        .filling(false)
+        .header(false)
+        .build_murkdown()
+        .await;
+    match res {
+        Ok((_, entities, _)) if entities.len() > MAX_ENTITIES => lints.push(Lint {
+            pos: None,
+            kind: LintKind::TooManyEntities(entities.len()),
+        }),
+        Ok(_) => (),
+        // syntax problems already explain why parsing failed
+        Err(err) if lints.is_empty() => lints.push(Lint {
+            pos: match err {
+                BotError::MurkdownError(err) => err.pos,
+                _ => None,
+            },
+            kind: LintKind::ParseError,
+        }),
+        Err(_) => (),
+    }
+    lints
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lint_brackets() {
+        assert!(lint_syntax("[*bold] and [_italic] <button>(https://example.com)").is_empty());
+        assert_eq!(
+            lint_syntax("hello [*world"),
+            vec![Lint::at(6, LintKind::Unclosed('['))]
+        );
+        assert_eq!(
+            lint_syntax("oops]"),
+            vec![Lint::at(4, LintKind::Unopened(']'))]
+        );
+        assert!(lint_syntax("\\[escaped [`code [ <`]").is_empty());
+    }
+
+    #[test]
+    fn lint_fillings_buttons() {
+        assert_eq!(
+            lint_syntax("hi {mention} {nope}"),
+            vec![Lint::at(13, LintKind::UnknownFilling("nope".to_owned()))]
+        );
+        assert_eq!(
+            lint_syntax("<note>(#rules) <bad>(notaurl)"),
+            vec![Lint::at(
+                21,
+                LintKind::InvalidButtonUrl("notaurl".to_owned())
+            )]
+        );
+    }
+}
@@ -1,13 +1,43 @@
 pub mod admin_helpers;
+pub mod aliases;
+pub mod ban_signals;
+pub mod bridges;
 pub mod button;
+pub mod captcha_stats;
 pub mod client;
 pub mod command;
+pub mod connection;
 pub mod dialog;
+pub mod events;
+pub mod exemptions;
+pub mod fed_media;
 pub mod federations;
+pub mod flair;
+pub mod gbansync;
 pub mod greetings;
 pub mod import_export;
+pub mod inline;
+pub mod listener;
+pub mod llm;
+pub mod maintenance;
 pub mod markdown;
+pub mod mdlint;
 pub mod notes;
+pub mod optout;
+pub mod ocr;
+pub mod payments;
 pub mod permissions;
+#[cfg(feature = "dylib-plugins")]
+pub mod plugin;
+pub mod policy;
+pub mod quotas;
+#[cfg(feature = "quote")]
+pub mod quote;
+pub mod ratelimit;
+pub mod roles;
 pub mod rosemd;
+pub mod sharding;
+pub mod template;
+pub mod translate;
 pub mod user;
+pub mod webhooks;
@@ -105,7 +105,7 @@ pub async fn clear_notes(chat: i64) -> Result<()> {
                 .filter(entity::Column::Id.is_in(ids))
                 .exec(tx)
                 .await?;
-            REDIS.sq(|q| q.del(key)).await?;
+            REDIS.invalidate(&[key]).await?;
             Ok(())
         }
         .boxed()
@@ -197,7 +197,7 @@ pub fn handle_transition(
                         async move {
                             log::info!("next notes: {}", note);
                             button.on_push(move |b| async move {
-                                TG.client
+                                TG.client()
                                     .build_answer_callback_query(b.get_id())
                                     .build()
                                     .await?;
@@ -0,0 +1,145 @@
+//! Text extraction from images for blocklists. Images are read by an external service
+//! or a local tesseract binary, and the text found in each image is cached by its
+//! file_unique_id so reposts of the same spam image are only read once. Matching against
+//! the cached text still happens on every message, so blocklist changes apply right away
+
+use std::time::Duration;
+
+use botapi::gen_types::Message;
+use bytes::Bytes;
+use redis::AsyncCommands;
+
+use crate::statics::{CONFIG, REDIS, TG};
+use crate::tg::admin_helpers::get_file;
+use crate::util::error::{BotError, Result};
+
+fn get_ocr_key(unique_id: &str) -> String {
+    format!("ocr:{}", unique_id)
+}
+
+fn fits(size: Option<i64>) -> bool {
+    size.map(|v| v <= CONFIG.ocr.max_file_size).unwrap_or(true)
+}
+
+/// Get the file id and file_unique_id of the largest image in a message that is still
+/// under the size limit
+fn image_source(message: &Message) -> Option<(String, String)> {
+    if let Some(photo) = message.get_photo() {
+        photo
+            .iter()
+            .rev()
+            .find(|v| fits(v.get_file_size()))
+            .map(|v| {
+                (
+                    v.get_file_id().to_owned(),
+                    v.get_file_unique_id().to_owned(),
+                )
+            })
+    } else if let Some(document) = message.get_document() {
+        let image = document
+            .get_mime_type()
+            .map(|v| v.starts_with("image/"))
+            .unwrap_or(false);
+        if image && fits(document.get_file_size()) {
+            Some((
+                document.get_file_id().to_owned(),
+                document.get_file_unique_id().to_owned(),
+            ))
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+async fn extract(bytes: Bytes) -> Result<String> {
+    if let Some(url) = CONFIG.ocr.service_url.as_ref() {
+        let text = reqwest::Client::new()
+            .post(url)
+            .body(bytes)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        Ok(text)
+    } else {
+        tesseract(bytes).await
+    }
+}
+
+#[cfg(feature = "tesseract")]
+async fn tesseract(bytes: Bytes) -> Result<String> {
+    use std::process::Stdio;
+    use tokio::io::AsyncWriteExt;
+    use tokio::process::Command;
+
+    let mut child = Command::new(&CONFIG.ocr.tesseract_path)
+        .args(["stdin", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&bytes).await?;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(BotError::generic(format!(
+            "tesseract exited with {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(not(feature = "tesseract"))]
+async fn tesseract(_: Bytes) -> Result<String> {
+    Err(BotError::generic(
+        "no ocr service_url set and built without the tesseract feature",
+    ))
+}
+
+/// Get the text in a message's photo or image document. Returns None if ocr is disabled,
+/// the message has no image, the image is too large, or no text was found
+pub async fn image_text(message: &Message) -> Result<Option<String>> {
+    if !CONFIG.ocr.enabled {
+        return Ok(None);
+    }
+    let Some((file_id, unique_id)) = image_source(message) else {
+        return Ok(None);
+    };
+    let key = get_ocr_key(&unique_id);
+    let cached: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    let text = if let Some(cached) = cached {
+        cached
+    } else {
+        let file = TG.client().build_get_file(&file_id).build().await?;
+        if !fits(file.get_file_size()) {
+            return Ok(None);
+        }
+        let path = file
+            .get_file_path()
+            .ok_or_else(|| BotError::Generic("File path missing".to_owned()))?;
+        let bytes = get_file(path).await?;
+        if bytes.len() as i64 > CONFIG.ocr.max_file_size {
+            return Ok(None);
+        }
+        let timeout = Duration::from_secs(CONFIG.ocr.timeout_seconds);
+        let text = tokio::time::timeout(timeout, extract(bytes))
+            .await
+            .map_err(|_| BotError::generic("ocr took too long"))??;
+        let text = text.trim().to_owned();
+        REDIS
+            .pipe(|q| {
+                q.set(&key, &text)
+                    .expire(&key, CONFIG.ocr.cache_seconds)
+                    .ignore()
+            })
+            .await?;
+        text
+    };
+    Ok(Some(text).filter(|v| !v.is_empty()))
+}
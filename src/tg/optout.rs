@@ -0,0 +1,140 @@
+//! Per user opt-outs from mass mentions and activity statistics. These apply in every chat
+//! and are stored with the user, so anything that pings or counts users in bulk should
+//! check them first
+
+use botapi::gen_types::User;
+use chrono::Duration;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::EntityTrait;
+
+use crate::persist::core::users;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{CONFIG, DB};
+use crate::util::error::Result;
+
+/// Something a user can opt out of
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptOut {
+    /// being pinged by tagall or role mentions
+    Mentions,
+    /// having activity counted in digests or archived
+    Stats,
+}
+
+impl OptOut {
+    pub const ALL: [OptOut; 2] = [OptOut::Mentions, OptOut::Stats];
+
+    /// Parse an opt-out from its name as used in /optout
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mentions" | "pings" => Some(Self::Mentions),
+            "stats" | "analytics" => Some(Self::Stats),
+            _ => None,
+        }
+    }
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Self::Mentions => "mentions",
+            Self::Stats => "stats",
+        }
+    }
+
+    fn get_column(&self) -> users::Column {
+        match self {
+            Self::Mentions => users::Column::OptoutMentions,
+            Self::Stats => users::Column::OptoutStats,
+        }
+    }
+}
+
+/// A user's current opt-outs
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OptOuts {
+    pub mentions: bool,
+    pub stats: bool,
+}
+
+impl OptOuts {
+    pub fn get(&self, kind: OptOut) -> bool {
+        match kind {
+            OptOut::Mentions => self.mentions,
+            OptOut::Stats => self.stats,
+        }
+    }
+}
+
+impl From<users::Model> for OptOuts {
+    fn from(value: users::Model) -> Self {
+        Self {
+            mentions: value.optout_mentions,
+            stats: value.optout_stats,
+        }
+    }
+}
+
+#[inline(always)]
+fn get_optout_key(user: i64) -> String {
+    format!("optout:{}", user)
+}
+
+/// Get what a user opted out of. Users the bot has no record of haven't opted out of anything
+pub async fn get_optouts(user: i64) -> Result<OptOuts> {
+    let model = default_cache_query(
+        |_, _| async move {
+            let res = users::Entity::find_by_id(user).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_optout_key(user), &())
+    .await?;
+    Ok(model.map(OptOuts::from).unwrap_or_default())
+}
+
+/// Returns true if a user opted out of something
+pub async fn is_opted_out(user: i64, kind: OptOut) -> Result<bool> {
+    Ok(get_optouts(user).await?.get(kind))
+}
+
+/// Opt a user in or out of something, returning their updated opt-outs
+pub async fn set_optout(user: &User, kind: OptOut, value: bool) -> Result<OptOuts> {
+    let mut model = users::ActiveModel {
+        user_id: Set(user.get_id()),
+        first_name: Set(user.get_first_name().to_owned()),
+        last_name: Set(user.get_last_name().map(|v| v.to_owned())),
+        username: Set(user.get_username().map(|v| v.to_owned())),
+        is_bot: Set(user.get_is_bot()),
+        optout_mentions: NotSet,
+        optout_stats: NotSet,
+    };
+    match kind {
+        OptOut::Mentions => model.optout_mentions = Set(value),
+        OptOut::Stats => model.optout_stats = Set(value),
+    }
+    let model = users::Entity::insert(model)
+        .on_conflict(
+            OnConflict::column(users::Column::UserId)
+                .update_column(kind.get_column())
+                .to_owned(),
+        )
+        .exec_with_returning(*DB)
+        .await?;
+    model.clone().cache(get_optout_key(user.get_id())).await?;
+    Ok(model.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn optout_names() {
+        for kind in OptOut::ALL {
+            assert_eq!(OptOut::from_name(kind.get_name()), Some(kind));
+        }
+        assert_eq!(OptOut::from_name("pings"), Some(OptOut::Mentions));
+        assert_eq!(OptOut::from_name("everything"), None);
+    }
+}
@@ -0,0 +1,181 @@
+//! Telegram Stars payments. Modules offering paid features register a handler for a
+//! product kind at startup and send invoices with send_invoice. Pre-checkout queries are
+//! approved only for invoices the bot sent and can still fulfil, and every successful
+//! payment is recorded in the payments table before being dispatched to its handler
+
+use std::sync::Arc;
+
+use botapi::gen_types::{
+    LabeledPriceBuilder, Message, PreCheckoutQuery, SuccessfulPayment, UpdateExt,
+};
+use chrono::Utc;
+use dashmap::DashMap;
+use futures::{future::BoxFuture, Future, FutureExt};
+use redis::AsyncCommands;
+use sea_orm::ActiveValue::{NotSet, Set};
+use sea_orm::{EntityTrait, IntoActiveModel};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::persist::core::payments;
+use crate::persist::redis::RedisStr;
+use crate::statics::{BotLocal, CONFIG, DB, REDIS, TG};
+use crate::util::error::{BotError, Result};
+
+/// Currency code for Telegram Stars
+pub const STARS: &str = "XTR";
+
+type PaymentHandler = Arc<dyn Fn(payments::Model) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+static HANDLERS: BotLocal<DashMap<String, PaymentHandler>> = BotLocal::new(DashMap::new);
+
+/// An invoice waiting to be paid
+#[derive(Serialize, Deserialize, Debug)]
+struct PendingInvoice {
+    kind: String,
+    chat: i64,
+    data: serde_json::Value,
+}
+
+#[inline(always)]
+fn get_invoice_key(id: &str) -> String {
+    format!("invoice:{}", id)
+}
+
+/// Register a handler called after each successful payment for a product kind.
+/// Registering the same kind twice replaces the previous handler
+pub fn register_product<F, Fut>(kind: &str, func: F)
+where
+    F: Fn(payments::Model) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    HANDLERS.insert(kind.to_owned(), Arc::new(move |v| func(v).boxed()));
+}
+
+/// Send an invoice for a product priced in stars. data is passed back to the product's
+/// handler once paid. Unpaid invoices stop being accepted after the cache timeout
+pub async fn send_invoice<T: Serialize>(
+    chat: i64,
+    kind: &str,
+    title: &str,
+    description: &str,
+    stars: i64,
+    data: &T,
+) -> Result<Message> {
+    if !HANDLERS.contains_key(kind) {
+        return Err(BotError::generic(format!(
+            "no handler for product {}",
+            kind
+        )));
+    }
+    let id = Uuid::new_v4().to_string();
+    let pending = RedisStr::new(&PendingInvoice {
+        kind: kind.to_owned(),
+        chat,
+        data: serde_json::to_value(data)?,
+    })?;
+    let key = get_invoice_key(&id);
+    REDIS
+        .pipe(|q| {
+            q.set(&key, pending)
+                .expire(&key, CONFIG.timing.cache_timeout)
+                .ignore()
+        })
+        .await?;
+    let prices = vec![LabeledPriceBuilder::new(title.to_owned(), stars).build()];
+    let message = TG
+        .client()
+        .build_send_invoice(chat, title, description, &id, STARS, &prices)
+        .build()
+        .await?;
+    Ok(message)
+}
+
+async fn get_pending(id: &str) -> Result<Option<PendingInvoice>> {
+    let key = get_invoice_key(id);
+    let pending: Option<RedisStr> = REDIS.sq(|q| q.get(&key)).await?;
+    Ok(pending.map(|v| v.get()).transpose()?)
+}
+
+async fn pre_checkout(query: &PreCheckoutQuery) -> Result<()> {
+    let ok = match get_pending(query.get_invoice_payload()).await? {
+        Some(pending) => HANDLERS.contains_key(&pending.kind),
+        None => false,
+    };
+    let client = TG.client();
+    let answer = client.build_answer_pre_checkout_query(query.get_id(), ok);
+    if ok {
+        answer.build().await?;
+    } else {
+        answer
+            .error_message("This invoice has expired, please request a new one")
+            .build()
+            .await?;
+    }
+    Ok(())
+}
+
+async fn successful_payment(message: &Message, payment: &SuccessfulPayment) -> Result<()> {
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let id = payment.get_invoice_payload();
+    let pending = get_pending(id).await?;
+    let (kind, chat, data) = match pending {
+        Some(pending) => (pending.kind, pending.chat, pending.data),
+        None => (
+            String::new(),
+            message.get_chat().get_id(),
+            serde_json::Value::Null,
+        ),
+    };
+    let model = payments::Entity::insert(payments::ActiveModel {
+        id: NotSet,
+        user: Set(user.get_id()),
+        chat: Set(chat),
+        kind: Set(kind),
+        data: Set(data),
+        currency: Set(payment.get_currency().to_owned()),
+        amount: Set(payment.get_total_amount()),
+        charge_id: Set(payment.get_telegram_payment_charge_id().to_owned()),
+        refunded: Set(false),
+        created: Set(Utc::now()),
+    })
+    .exec_with_returning(*DB)
+    .await?;
+    let key = get_invoice_key(id);
+    REDIS.invalidate(&[&key]).await?;
+
+    match HANDLERS.get(&model.kind).map(|v| Arc::clone(v.value())) {
+        Some(handler) => handler(model).await,
+        None => {
+            // the invoice expired between checkout and payment, the user shouldn't lose stars
+            log::warn!("payment {} has no product, refunding", model.id);
+            refund(model).await
+        }
+    }
+}
+
+/// Refund a stars payment and mark it refunded in the payments table
+pub async fn refund(payment: payments::Model) -> Result<()> {
+    TG.client()
+        .build_refund_star_payment(payment.user, &payment.charge_id)
+        .build()
+        .await?;
+    let mut model = payment.into_active_model();
+    model.refunded = Set(true);
+    payments::Entity::update(model).exec(*DB).await?;
+    Ok(())
+}
+
+/// Answer pre-checkout queries and dispatch successful payments to their products
+pub async fn handle_payment_update(update: &UpdateExt) -> Result<()> {
+    match update {
+        UpdateExt::PreCheckoutQuery(ref query) => pre_checkout(query).await,
+        UpdateExt::Message(ref message) => match message.get_successful_payment() {
+            Some(payment) => successful_payment(message, payment).await,
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
@@ -9,7 +9,7 @@ use crate::{
         core::dialogs,
         redis::{RedisStr, ToRedisStr},
     },
-    statics::{CONFIG, DB, REDIS, TG},
+    statics::{spawn, CONFIG, DB, REDIS, TG},
     util::string::get_chat_lang,
     util::{
         error::{BotError, Fail, Result},
@@ -27,11 +27,13 @@ use tokio::{sync::mpsc, time::sleep};
 use uuid::Uuid;
 
 use super::{
-    admin_helpers::{is_group_or_die, is_self_admin},
+    admin_helpers::{is_group_or_die, is_self_admin, UpdateHelpers},
     button::{InlineKeyboardBuilder, OnPush},
     command::Context,
     dialog::upsert_dialog,
+    events::{publish, ModEvent},
     markdown::EntityMessage,
+    template::apply_template,
     user::{GetUser, Username},
 };
 use itertools::Itertools;
@@ -338,7 +340,7 @@ where
         .build();
     let timer_out = out.clone();
 
-    tokio::spawn(async move {
+    spawn(async move {
         sleep(Duration::try_minutes(1).unwrap().to_std()?).await;
         timer_out.send(None).await?;
         Ok::<(), BotError>(())
@@ -372,19 +374,19 @@ where
         let sudo = perm.is_sudo.is_granted();
         let p = func(perm);
         if p.is_granted() || sudo {
-            TG.client
+            TG.client()
                 .build_answer_callback_query(cb.get_id())
                 .build()
                 .await?;
             if let Some(MaybeInaccessibleMessage::Message(message)) = cb.get_message() {
-                TG.client
+                TG.client()
                     .build_delete_message(message.get_chat().get_id(), message.get_message_id())
                     .build()
                     .await?;
             }
             return Ok(());
         }
-        TG.client
+        TG.client()
             .build_answer_callback_query(cb.get_id())
             .text(&lang_fmt!(lang, "channeldenied"))
             .show_alert(true)
@@ -642,6 +644,16 @@ pub async fn update_self_admin(update: &UpdateExt) -> Result<()> {
                     REDIS.sq(|q| q.hdel(&key, user_id)).await?;
                 }
             }
+            if update.bot_added().is_some() {
+                publish(ModEvent::ChatJoined {
+                    chat: member.get_chat().get_id(),
+                });
+                // a broken template shouldn't keep the bot from tracking its own status
+                if let Err(err) = apply_template(member.get_chat()).await {
+                    log::warn!("failed to apply template: {}", err);
+                    err.record_stats();
+                }
+            }
         }
         UpdateExt::ChatMember(member) => {
             let key = get_chat_admin_cache_key(member.get_chat().get_id());
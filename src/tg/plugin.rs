@@ -0,0 +1,418 @@
+//! Modules loaded at startup from shared libraries, so modules can be added without
+//! recompiling the bot. Only built with the dylib-plugins feature
+//!
+//! A plugin is a cdylib exporting PLUGIN_ENTRY, a function returning a PluginVTable. Rust
+//! plugins implement Plugin and use export_plugin! to generate it. Everything crossing the
+//! library boundary is either a repr(C) struct or json, so plugins don't need to be built
+//! with the same compiler as the bot. Plugins see commands and messages through
+//! PluginUpdate and can reply with PluginResponse
+//!
+//! Buffers are always freed by the side that allocated them. Panics are caught inside the
+//! plugin and reported with STATUS_PANIC, after which the plugin is no longer called.
+//! Plugins are never unloaded
+
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use libloading::{Library, Symbol};
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{Metadata, Module, UpdateKind, UpdateKinds};
+use crate::util::error::{BotError, Result};
+use crate::util::string::Speak;
+
+use super::command::Context;
+
+/// Version of the structs in this file. Plugins reporting a different version are refused
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// Symbol every plugin exports, an extern "C" fn() -> *const PluginVTable
+pub const PLUGIN_ENTRY: &[u8] = b"dijkstra_plugin\0";
+
+/// The call succeeded, the output is json
+pub const STATUS_OK: i32 = 0;
+/// The plugin returned an error, the output is the error message
+pub const STATUS_ERROR: i32 = 1;
+/// The plugin panicked, the output is empty
+pub const STATUS_PANIC: i32 = 2;
+
+/// Bytes allocated by one side of the plugin boundary, returned to it to be freed
+#[repr(C)]
+pub struct PluginBytes {
+    ptr: *mut u8,
+    len: usize,
+    cap: usize,
+}
+
+impl PluginBytes {
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        let mut bytes = std::mem::ManuallyDrop::new(bytes);
+        Self {
+            ptr: bytes.as_mut_ptr(),
+            len: bytes.len(),
+            cap: bytes.capacity(),
+        }
+    }
+
+    /// # Safety
+    /// Must have been created by from_vec in the same library
+    unsafe fn into_vec(self) -> Vec<u8> {
+        Vec::from_raw_parts(self.ptr, self.len, self.cap)
+    }
+
+    /// # Safety
+    /// ptr must point to len initialized bytes
+    unsafe fn as_slice(&self) -> &[u8] {
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// Functions exported by a plugin
+#[repr(C)]
+pub struct PluginVTable {
+    /// PLUGIN_ABI_VERSION the plugin was built with
+    pub abi_version: u32,
+    /// Write the json encoded PluginManifest to the output
+    pub manifest: extern "C" fn(out: *mut PluginBytes) -> i32,
+    /// Handle a json encoded PluginUpdate, writing a json encoded PluginResponse
+    pub handle_update: extern "C" fn(update: *const u8, len: usize, out: *mut PluginBytes) -> i32,
+    /// Free an output written by the plugin
+    pub free: extern "C" fn(bytes: PluginBytes),
+}
+
+/// Name, help, and version of a plugin
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PluginManifest {
+    pub name: String,
+    pub description: String,
+    #[serde(default)]
+    pub category: Option<String>,
+    /// help text for each command
+    #[serde(default)]
+    pub commands: HashMap<String, String>,
+    #[serde(default)]
+    pub version: Option<String>,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub source: Option<String>,
+    /// crate::metadata::API_VERSION the plugin was written for
+    pub api_version: u32,
+}
+
+impl From<PluginManifest> for Metadata {
+    fn from(manifest: PluginManifest) -> Self {
+        let mut metadata = Metadata::new(manifest.name, manifest.description, None)
+            .set_api_version(manifest.api_version);
+        metadata.commands = manifest.commands;
+        metadata.category = manifest.category;
+        metadata.version = manifest.version;
+        metadata.author = manifest.author;
+        metadata.source = manifest.source;
+        metadata.updates = UpdateKinds::declared(&[UpdateKind::Message]);
+        metadata
+    }
+}
+
+/// A message sent in a chat with the bot
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PluginUpdate {
+    pub chat: i64,
+    pub user: Option<i64>,
+    pub message_id: i64,
+    pub text: Option<String>,
+    /// command without the slash, if the message is a command
+    pub command: Option<String>,
+    /// text after the command
+    pub args: Option<String>,
+}
+
+impl PluginUpdate {
+    fn from_context(ctx: &Context) -> Option<Self> {
+        let message = ctx.message().ok()?;
+        let cmd = ctx.cmd();
+        Some(Self {
+            chat: message.get_chat().get_id(),
+            user: message.get_from().map(|v| v.get_id()),
+            message_id: message.get_message_id(),
+            text: message.get_text().map(|v| v.to_owned()),
+            command: cmd.map(|v| v.cmd.to_owned()),
+            args: cmd.map(|v| v.args.text.to_owned()),
+        })
+    }
+}
+
+/// What the bot should do after a plugin handled an update
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PluginResponse {
+    /// messages sent in reply to the update
+    #[serde(default)]
+    pub replies: Vec<String>,
+}
+
+/// A module in a plugin. Exported with export_plugin!
+pub trait Plugin: Send + Sync + 'static {
+    fn manifest(&self) -> PluginManifest;
+
+    /// Called for every message, errors are reported to the chat
+    fn handle_update(&self, update: PluginUpdate) -> std::result::Result<PluginResponse, String>;
+}
+
+/// Generate the PLUGIN_ENTRY export for a type implementing Plugin and Default. Used once
+/// in a cdylib crate
+#[macro_export]
+macro_rules! export_plugin {
+    ($plugin:ty) => {
+        fn __dijkstra_plugin() -> &'static $plugin {
+            static PLUGIN: ::std::sync::OnceLock<$plugin> = ::std::sync::OnceLock::new();
+            PLUGIN.get_or_init(<$plugin as ::std::default::Default>::default)
+        }
+
+        extern "C" fn __dijkstra_plugin_manifest(out: *mut $crate::tg::plugin::PluginBytes) -> i32 {
+            unsafe { $crate::tg::plugin::guest_manifest(__dijkstra_plugin, out) }
+        }
+
+        extern "C" fn __dijkstra_plugin_handle_update(
+            update: *const u8,
+            len: usize,
+            out: *mut $crate::tg::plugin::PluginBytes,
+        ) -> i32 {
+            unsafe { $crate::tg::plugin::guest_handle_update(__dijkstra_plugin, update, len, out) }
+        }
+
+        #[no_mangle]
+        pub extern "C" fn dijkstra_plugin() -> *const $crate::tg::plugin::PluginVTable {
+            static VTABLE: $crate::tg::plugin::PluginVTable = $crate::tg::plugin::PluginVTable {
+                abi_version: $crate::tg::plugin::PLUGIN_ABI_VERSION,
+                manifest: __dijkstra_plugin_manifest,
+                handle_update: __dijkstra_plugin_handle_update,
+                free: $crate::tg::plugin::guest_free,
+            };
+            &VTABLE
+        }
+    };
+}
+
+/// Run a call inside a plugin, catching panics before they reach the boundary
+///
+/// # Safety
+/// out must be valid for writes
+unsafe fn guest_call<F>(out: *mut PluginBytes, func: F) -> i32
+where
+    F: FnOnce() -> std::result::Result<Vec<u8>, String>,
+{
+    let (status, bytes) = match std::panic::catch_unwind(AssertUnwindSafe(func)) {
+        Ok(Ok(bytes)) => (STATUS_OK, bytes),
+        Ok(Err(err)) => (STATUS_ERROR, err.into_bytes()),
+        Err(_) => (STATUS_PANIC, Vec::new()),
+    };
+    out.write(PluginBytes::from_vec(bytes));
+    status
+}
+
+/// Plugin side of PluginVTable::manifest, used by export_plugin!
+///
+/// # Safety
+/// out must be valid for writes
+#[doc(hidden)]
+pub unsafe fn guest_manifest<T: Plugin>(plugin: fn() -> &'static T, out: *mut PluginBytes) -> i32 {
+    guest_call(out, || {
+        serde_json::to_vec(&plugin().manifest()).map_err(|err| err.to_string())
+    })
+}
+
+/// Plugin side of PluginVTable::handle_update, used by export_plugin!
+///
+/// # Safety
+/// update must point to len initialized bytes and out must be valid for writes
+#[doc(hidden)]
+pub unsafe fn guest_handle_update<T: Plugin>(
+    plugin: fn() -> &'static T,
+    update: *const u8,
+    len: usize,
+    out: *mut PluginBytes,
+) -> i32 {
+    guest_call(out, || {
+        let update = std::slice::from_raw_parts(update, len);
+        let update = serde_json::from_slice(update).map_err(|err| err.to_string())?;
+        let response = plugin().handle_update(update)?;
+        serde_json::to_vec(&response).map_err(|err| err.to_string())
+    })
+}
+
+/// Plugin side of PluginVTable::free, used by export_plugin!
+#[doc(hidden)]
+pub extern "C" fn guest_free(bytes: PluginBytes) {
+    drop(unsafe { bytes.into_vec() });
+}
+
+/// Call a plugin function, copying its output and handing the buffer back to the plugin
+fn host_call<F>(vtable: &PluginVTable, func: F) -> (i32, Vec<u8>)
+where
+    F: FnOnce(*mut PluginBytes) -> i32,
+{
+    let mut out = PluginBytes::from_vec(Vec::new());
+    let status = func(&mut out);
+    let bytes = unsafe { out.as_slice() }.to_vec();
+    (vtable.free)(out);
+    (status, bytes)
+}
+
+/// A plugin loaded from a shared library, registered like any other external module
+pub struct DylibModule {
+    vtable: &'static PluginVTable,
+    metadata: Metadata,
+    /// set once the plugin panics, it isn't called again after that
+    poisoned: AtomicBool,
+}
+
+impl DylibModule {
+    /// Load a plugin, checking that it was built with the same PLUGIN_ABI_VERSION
+    pub fn load(path: &Path) -> Result<Self> {
+        let library = unsafe { Library::new(path) }
+            .map_err(|err| BotError::generic(format!("failed to load plugin: {}", err)))?;
+        // plugins are never unloaded, so everything they export lives forever
+        let library: &'static Library = Box::leak(Box::new(library));
+        let entry: Symbol<extern "C" fn() -> *const PluginVTable> =
+            unsafe { library.get(PLUGIN_ENTRY) }
+                .map_err(|err| BotError::generic(format!("plugin has no entry point: {}", err)))?;
+        let vtable = unsafe { entry().as_ref() }
+            .ok_or_else(|| BotError::generic("plugin returned no vtable"))?;
+        if vtable.abi_version != PLUGIN_ABI_VERSION {
+            return Err(BotError::generic(format!(
+                "plugin built with abi version {}, expected {}",
+                vtable.abi_version, PLUGIN_ABI_VERSION
+            )));
+        }
+        let (status, bytes) = host_call(vtable, |out| (vtable.manifest)(out));
+        if status != STATUS_OK {
+            return Err(BotError::generic(format!(
+                "plugin failed to describe itself: {}",
+                String::from_utf8_lossy(&bytes)
+            )));
+        }
+        let manifest: PluginManifest = serde_json::from_slice(&bytes)?;
+        Ok(Self {
+            vtable,
+            metadata: manifest.into(),
+            poisoned: AtomicBool::new(false),
+        })
+    }
+}
+
+#[async_trait]
+impl Module for DylibModule {
+    fn metadata(&self) -> Metadata {
+        self.metadata.clone()
+    }
+
+    async fn handle_update(&self, ctx: &Context) -> Result<()> {
+        if self.poisoned.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let Some(update) = PluginUpdate::from_context(ctx) else {
+            return Ok(());
+        };
+        let update = serde_json::to_vec(&update)?;
+        let vtable = self.vtable;
+        let (status, bytes) = tokio::task::spawn_blocking(move || {
+            host_call(vtable, |out| {
+                (vtable.handle_update)(update.as_ptr(), update.len(), out)
+            })
+        })
+        .await?;
+        match status {
+            STATUS_OK => {
+                let response: PluginResponse = serde_json::from_slice(&bytes)?;
+                for reply in response.replies {
+                    ctx.reply(reply).await?;
+                }
+                Ok(())
+            }
+            STATUS_ERROR => Err(BotError::generic(String::from_utf8_lossy(&bytes))),
+            _ => {
+                log::error!("plugin {} panicked, disabling it", self.metadata.name);
+                self.poisoned.store(true, Ordering::Relaxed);
+                Err(BotError::generic(format!(
+                    "plugin {} crashed",
+                    self.metadata.name
+                )))
+            }
+        }
+    }
+}
+
+/// Load every shared library in a directory as a plugin. Plugins that fail to load are
+/// logged and skipped
+pub fn load_plugins(dir: &Path) -> Vec<DylibModule> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            log::warn!("failed to read plugin dir {}: {}", dir.display(), err);
+            return Vec::new();
+        }
+    };
+    entries
+        .filter_map(|v| v.ok().map(|v| v.path()))
+        .filter(|v| {
+            v.extension()
+                .map(|v| v == std::env::consts::DLL_EXTENSION)
+                .unwrap_or(false)
+        })
+        .filter_map(|path| match DylibModule::load(&path) {
+            Ok(plugin) => {
+                log::info!(
+                    "loaded plugin {} from {}",
+                    plugin.metadata.name,
+                    path.display()
+                );
+                Some(plugin)
+            }
+            Err(err) => {
+                log::error!("refusing to load plugin {}: {}", path.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn guest_call_status() {
+        let vtable = PluginVTable {
+            abi_version: PLUGIN_ABI_VERSION,
+            manifest: {
+                extern "C" fn manifest(_: *mut PluginBytes) -> i32 {
+                    STATUS_OK
+                }
+                manifest
+            },
+            handle_update: {
+                extern "C" fn handle_update(_: *const u8, _: usize, _: *mut PluginBytes) -> i32 {
+                    STATUS_OK
+                }
+                handle_update
+            },
+            free: guest_free,
+        };
+        let (status, bytes) = host_call(&vtable, |out| unsafe {
+            guest_call(out, || Ok(b"ok".to_vec()))
+        });
+        assert_eq!((status, bytes.as_slice()), (STATUS_OK, b"ok".as_slice()));
+        let (status, bytes) = host_call(&vtable, |out| unsafe {
+            guest_call(out, || Err("failed".to_owned()))
+        });
+        assert_eq!(
+            (status, bytes.as_slice()),
+            (STATUS_ERROR, b"failed".as_slice())
+        );
+        let (status, _) = host_call(&vtable, |out| unsafe { guest_call(out, || panic!("oops")) });
+        assert_eq!(status, STATUS_PANIC);
+    }
+}
@@ -0,0 +1,138 @@
+//! Enforces the rights and cooldowns commands declare in metadata!, see CommandPolicy.
+//! Commands that fail a check are answered with a refusal and never reach their module,
+//! so modules declaring a policy don't need to check permissions themselves
+
+use botapi::gen_types::ChatMember;
+use macros::lang_fmt;
+use redis::AsyncCommands;
+
+use crate::metadata::{CommandPolicy, CommandRight};
+use crate::statics::{CONFIG, REDIS};
+use crate::util::error::{Fail, Result};
+
+use super::client::MetadataCollection;
+use super::command::{Cmd, Context};
+use super::federations::{get_fed, is_fedadmin, is_fedmember};
+use super::permissions::{GetCachedAdmins, IsGroupAdmin, NamedBotPermissions, NamedPermission};
+use super::user::Username;
+
+#[inline(always)]
+fn get_cooldown_key(cmd: &str, chat: i64, user: i64) -> String {
+    format!("cmdcd:{}:{}:{}", cmd, chat, user)
+}
+
+/// Get the admin right a CommandRight maps to, None for rights that aren't admin rights
+fn admin_right(right: CommandRight, p: &NamedBotPermissions) -> Option<NamedPermission> {
+    match right {
+        CommandRight::CanRestrict => Some(p.can_restrict_members.clone()),
+        CommandRight::CanDelete => Some(p.can_delete_messages.clone()),
+        CommandRight::CanPin => Some(p.can_pin_messages.clone()),
+        CommandRight::CanChangeInfo => Some(p.can_change_info.clone()),
+        CommandRight::CanPromote => Some(p.can_promote_members.clone()),
+        CommandRight::Owner | CommandRight::FedAdmin => None,
+    }
+}
+
+/// Fail unless the sender of a command has every right in the policy
+async fn check_rights(ctx: &Context, cmd: &str, policy: &CommandPolicy) -> Result<()> {
+    let message = ctx.message()?;
+    let chat = message.get_chat();
+    let requires = policy.requires.clone();
+    if requires
+        .iter()
+        .any(|v| !matches!(v, CommandRight::Owner | CommandRight::FedAdmin))
+    {
+        ctx.check_permissions(move |p| {
+            // never empty, there is at least one admin right
+            requires
+                .iter()
+                .filter_map(|v| admin_right(*v, &p))
+                .reduce(|acc, v| acc.and(v))
+                .unwrap_or_else(|| p.can_manage_chat.clone())
+        })
+        .await?;
+    }
+
+    let user = message
+        .get_from()
+        .filter(|_| message.get_sender_chat().is_none());
+    if policy.requires.contains(&CommandRight::Owner) {
+        let owner = match user {
+            Some(user) => matches!(
+                chat.is_user_admin(user.get_id()).await?,
+                Some(ChatMember::ChatMemberOwner(_))
+            ),
+            None => false,
+        };
+        if !owner {
+            return ctx.fail(lang_fmt!(ctx, "policyowner", cmd));
+        }
+    }
+    if policy.requires.contains(&CommandRight::FedAdmin) {
+        let Some(fed) = is_fedmember(chat.get_id()).await? else {
+            return ctx.fail(lang_fmt!(ctx, "notinfed", chat.name_humanreadable()));
+        };
+        let Some(user) = user else {
+            return ctx.fail(lang_fmt!(ctx, "anonfed"));
+        };
+        let owner = get_fed(user.get_id()).await?.map(|v| v.fed_id) == Some(fed);
+        if !owner
+            && !is_fedadmin(user.get_id(), &fed).await?
+            && !CONFIG.admin.support_users.contains(&user.get_id())
+        {
+            return ctx.fail(lang_fmt!(ctx, "notfedadmin", fed.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Fail if the sender used a command again before its cooldown ran out, otherwise start
+/// the cooldown
+async fn check_cooldown(ctx: &Context, cmd: &str, cooldown: u64) -> Result<()> {
+    let message = ctx.message()?;
+    let Some(user) = message.get_from() else {
+        return Ok(());
+    };
+    let key = get_cooldown_key(cmd, message.get_chat().get_id(), user.get_id());
+    let cooldown = cooldown as i64;
+    let (first,): (bool,) = REDIS
+        .pipe(|q| q.set_nx(&key, true).expire(&key, cooldown).ignore())
+        .await?;
+    if !first {
+        let remaining: i64 = REDIS.sq(|q| q.ttl(&key)).await?;
+        return ctx.fail(lang_fmt!(ctx, "policycooldown", cmd, remaining.max(1)));
+    }
+    Ok(())
+}
+
+/// Check a command against the policy it declared. Returns true if the command was
+/// refused and should not be passed to modules
+pub async fn policy_middleware(ctx: &Context, helps: &MetadataCollection) -> Result<bool> {
+    let Some(&Cmd { cmd, message, .. }) = ctx.cmd() else {
+        return Ok(false);
+    };
+    let Some(policy) = helps.command_policy(cmd) else {
+        return Ok(false);
+    };
+    if message
+        .get_from()
+        .map(|v| CONFIG.admin.sudo_users.contains(&v.get_id()))
+        .unwrap_or(false)
+    {
+        return Ok(false);
+    }
+    let mut res = check_rights(ctx, cmd, policy).await;
+    if let (Ok(()), Some(cooldown)) = (&res, policy.cooldown) {
+        res = check_cooldown(ctx, cmd, cooldown).await;
+    }
+    match res {
+        Ok(()) => Ok(false),
+        Err(err) => {
+            if err.get_message().await? {
+                Ok(true)
+            } else {
+                Err(err)
+            }
+        }
+    }
+}
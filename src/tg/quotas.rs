@@ -0,0 +1,137 @@
+//! Per chat limits on stored content, so a single chat can't grow the database without
+//! bound on deployments with little storage. Limits are set in the quotas section of the
+//! config and checked when something is saved. Chats in exempt_chats are never limited
+
+use botapi::gen_types::Message;
+use macros::lang_fmt;
+
+use crate::statics::CONFIG;
+use crate::util::error::{Fail, Result};
+use crate::util::string::Lang;
+
+/// Kinds of content with a limit on how many a chat can store
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quota {
+    Notes,
+    Filters,
+    Blocklists,
+    Scripts,
+    CustomCommands,
+    Automations,
+    Roles,
+    Aliases,
+    FedMedia,
+}
+
+impl Quota {
+    /// Most items allowed in a chat, 0 meaning unlimited
+    fn limit(self) -> u64 {
+        match self {
+            Self::Notes => CONFIG.quotas.max_notes,
+            Self::Filters => CONFIG.quotas.max_filters,
+            Self::Blocklists => CONFIG.quotas.max_blocklists,
+            Self::Scripts => CONFIG.quotas.max_scripts,
+            Self::CustomCommands => CONFIG.quotas.max_custom_commands,
+            Self::Automations => CONFIG.quotas.max_automations,
+            Self::Roles => CONFIG.quotas.max_roles,
+            Self::Aliases => CONFIG.quotas.max_aliases,
+            Self::FedMedia => CONFIG.quotas.max_fed_media,
+        }
+    }
+}
+
+/// Returns true if a chat is never limited
+pub fn is_exempt(chat: i64) -> bool {
+    CONFIG.quotas.exempt_chats.contains(&chat)
+}
+
+/// Returns true if adding items to the existing ones would go over a limit
+fn exceeds(limit: u64, existing: u64, adding: u64) -> bool {
+    limit != 0 && existing + adding > limit
+}
+
+/// Fail if adding items to a chat that already stores `existing` would go over its quota.
+/// Items being replaced should not be counted in `existing`
+pub fn check_quota(
+    message: &Message,
+    lang: &Lang,
+    quota: Quota,
+    existing: u64,
+    adding: u64,
+) -> Result<()> {
+    let limit = quota.limit();
+    if is_exempt(message.get_chat().get_id()) || !exceeds(limit, existing, adding) {
+        return Ok(());
+    }
+    match quota {
+        Quota::Notes => message.fail(lang_fmt!(lang, "quotanotes", limit)),
+        Quota::Filters => message.fail(lang_fmt!(lang, "quotafilters", limit)),
+        Quota::Blocklists => message.fail(lang_fmt!(lang, "quotablocklists", limit)),
+        Quota::Scripts => message.fail(lang_fmt!(lang, "quotascripts", limit)),
+        Quota::CustomCommands => message.fail(lang_fmt!(lang, "quotacustomcmds", limit)),
+        Quota::Automations => message.fail(lang_fmt!(lang, "quotaautomations", limit)),
+        Quota::Roles => message.fail(lang_fmt!(lang, "quotaroles", limit)),
+        Quota::Aliases => message.fail(lang_fmt!(lang, "quotaaliases", limit)),
+        Quota::FedMedia => message.fail(lang_fmt!(lang, "quotafedmedia", limit)),
+    }
+}
+
+/// Fail if the text of a note is longer than allowed
+pub fn check_note_size(message: &Message, lang: &Lang, text: &str) -> Result<()> {
+    let limit = CONFIG.quotas.max_note_chars as u64;
+    let size = text.chars().count() as u64;
+    if is_exempt(message.get_chat().get_id()) || !exceeds(limit, 0, size) {
+        return Ok(());
+    }
+    message.fail(lang_fmt!(lang, "quotanotesize", limit))
+}
+
+/// Fail if a chat script is longer than allowed
+pub fn check_script_size(message: &Message, lang: &Lang, script: &str) -> Result<()> {
+    let limit = CONFIG.quotas.max_script_chars as u64;
+    let size = script.chars().count() as u64;
+    if is_exempt(message.get_chat().get_id()) || !exceeds(limit, 0, size) {
+        return Ok(());
+    }
+    message.fail(lang_fmt!(lang, "quotascriptsize", limit))
+}
+
+/// Size in bytes of the largest media in a message, if telegram reported one
+fn media_size(message: &Message) -> Option<i64> {
+    if let Some(photo) = message.get_photo() {
+        photo.iter().filter_map(|v| v.get_file_size()).max()
+    } else if let Some(sticker) = message.get_sticker() {
+        sticker.get_file_size()
+    } else if let Some(document) = message.get_document() {
+        document.get_file_size()
+    } else if let Some(video) = message.get_video() {
+        video.get_file_size()
+    } else if let Some(audio) = message.get_audio() {
+        audio.get_file_size()
+    } else {
+        None
+    }
+}
+
+/// Fail if the media a welcome or goodbye is being set from is larger than allowed
+pub fn check_welcome_media(message: &Message, media: &Message, lang: &Lang) -> Result<()> {
+    let limit = CONFIG.quotas.max_welcome_media_size.max(0) as u64;
+    let size = media_size(media).unwrap_or(0).max(0) as u64;
+    if is_exempt(message.get_chat().get_id()) || !exceeds(limit, 0, size) {
+        return Ok(());
+    }
+    message.fail(lang_fmt!(lang, "quotawelcomemedia", limit / 1024))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quota_exceeds() {
+        assert!(!exceeds(0, 1000, 1));
+        assert!(!exceeds(10, 9, 1));
+        assert!(exceeds(10, 10, 1));
+        assert!(exceeds(10, 0, 11));
+    }
+}
@@ -0,0 +1,335 @@
+//! Server side rendering of quoted messages into stickers. A quote is drawn as a chat
+//! bubble with the sender's name, a placeholder avatar, and the message text with its
+//! formatting, then encoded as a 512 pixel wide webp. Rendering runs on the blocking
+//! thread pool with a limit on concurrent renders and on how long each can take
+
+use std::io::Cursor;
+use std::time::Duration;
+
+use ab_glyph::{FontVec, PxScale};
+use image::{ImageFormat, Rgba, RgbaImage};
+use imageproc::drawing::{
+    draw_filled_circle_mut, draw_filled_rect_mut, draw_line_segment_mut, draw_text_mut, text_size,
+};
+use imageproc::rect::Rect;
+use once_cell::sync::OnceCell;
+use tokio::sync::Semaphore;
+
+use crate::statics::{BotLocal, Instance, CONFIG};
+use crate::util::error::{BotError, Result};
+
+const WIDTH: u32 = 512;
+const MAX_HEIGHT: u32 = 512;
+const PADDING: i32 = 8;
+const INNER_PADDING: i32 = 14;
+const AVATAR: i32 = 56;
+const CORNER: i32 = 18;
+const NAME_SCALE: f32 = 26.0;
+const TEXT_SCALE: f32 = 24.0;
+const LINE_HEIGHT: i32 = 30;
+
+const BUBBLE: Rgba<u8> = Rgba([30, 30, 36, 255]);
+const TEXT: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// Accent colors for names and avatars, picked from the sender's name
+const PALETTE: [Rgba<u8>; 7] = [
+    Rgba([255, 130, 110, 255]),
+    Rgba([250, 180, 80, 255]),
+    Rgba([180, 130, 240, 255]),
+    Rgba([120, 200, 100, 255]),
+    Rgba([80, 200, 210, 255]),
+    Rgba([90, 160, 240, 255]),
+    Rgba([240, 120, 170, 255]),
+];
+
+static RENDERING: BotLocal<Semaphore> =
+    BotLocal::new(|| Semaphore::new(CONFIG.quote.max_concurrent.max(1)));
+
+static FONT: BotLocal<OnceCell<FontVec>> = BotLocal::new(OnceCell::new);
+
+/// Formatting applied to a run of quoted text
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Style {
+    #[default]
+    Plain,
+    Bold,
+    Italic,
+    Code,
+    Link,
+    Strike,
+    Underline,
+}
+
+impl Style {
+    /// Get the style for a telegram MessageEntity type, None if it doesn't change how
+    /// text looks
+    pub fn from_entity(kind: &str) -> Option<Self> {
+        match kind {
+            "bold" => Some(Self::Bold),
+            "italic" => Some(Self::Italic),
+            "code" | "pre" => Some(Self::Code),
+            "url" | "text_link" | "mention" | "text_mention" | "hashtag" | "cashtag" | "email" => {
+                Some(Self::Link)
+            }
+            "strikethrough" => Some(Self::Strike),
+            "underline" => Some(Self::Underline),
+            _ => None,
+        }
+    }
+
+    fn color(&self) -> Rgba<u8> {
+        match self {
+            Self::Link => Rgba([110, 180, 245, 255]),
+            Self::Code => Rgba([150, 200, 120, 255]),
+            Self::Italic => Rgba([205, 205, 215, 255]),
+            _ => TEXT,
+        }
+    }
+}
+
+/// A message to render as a quote
+pub struct Quote {
+    pub name: String,
+    pub text: String,
+    /// offset, length, and style of formatted text, in utf-16 code units like telegram
+    pub entities: Vec<(usize, usize, Style)>,
+}
+
+/// A piece of text placed on a line of the quote
+struct Placed {
+    text: String,
+    style: Style,
+    x: i32,
+    line: i32,
+}
+
+/// Split text into runs with a single style each
+fn styled_runs(text: &str, entities: &[(usize, usize, Style)]) -> Vec<(String, Style)> {
+    let mut runs: Vec<(String, Style)> = Vec::new();
+    let mut pos = 0;
+    for c in text.chars() {
+        let style = entities
+            .iter()
+            .rev()
+            .find(|(offset, length, _)| pos >= *offset && pos < offset + length)
+            .map(|v| v.2)
+            .unwrap_or_default();
+        match runs.last_mut() {
+            Some((run, s)) if *s == style => run.push(c),
+            _ => runs.push((c.to_string(), style)),
+        }
+        pos += c.len_utf16();
+    }
+    runs
+}
+
+/// Wrap styled runs into lines no wider than max_width. Returns the placed words and
+/// the number of lines used, stopping at max_lines
+fn layout(
+    font: &FontVec,
+    runs: &[(String, Style)],
+    max_width: i32,
+    max_lines: i32,
+) -> (Vec<Placed>, i32) {
+    let mut placed = Vec::new();
+    let (mut x, mut line) = (0, 0);
+    for (run, style) in runs {
+        for word in run.split_inclusive(char::is_whitespace) {
+            let newline = word.ends_with('\n');
+            let word = word.trim_end_matches('\n');
+            let (width, _) = text_size(PxScale::from(TEXT_SCALE), font, word);
+            let width = width as i32;
+            if x > 0 && x + width > max_width {
+                x = 0;
+                line += 1;
+            }
+            if line >= max_lines {
+                if let Some(last) = placed.last_mut() {
+                    last.text.push('…');
+                }
+                return (placed, max_lines);
+            }
+            placed.push(Placed {
+                text: word.to_owned(),
+                style: *style,
+                x,
+                line,
+            });
+            x += width;
+            if newline {
+                x = 0;
+                line += 1;
+            }
+        }
+    }
+    (placed, line + 1)
+}
+
+fn rounded_rect(img: &mut RgbaImage, x: i32, y: i32, w: i32, h: i32, color: Rgba<u8>) {
+    let r = CORNER.min(w / 2).min(h / 2);
+    draw_filled_rect_mut(
+        img,
+        Rect::at(x + r, y).of_size((w - 2 * r).max(1) as u32, h as u32),
+        color,
+    );
+    draw_filled_rect_mut(
+        img,
+        Rect::at(x, y + r).of_size(w as u32, (h - 2 * r).max(1) as u32),
+        color,
+    );
+    for (cx, cy) in [
+        (x + r, y + r),
+        (x + w - r - 1, y + r),
+        (x + r, y + h - r - 1),
+        (x + w - r - 1, y + h - r - 1),
+    ] {
+        draw_filled_circle_mut(img, (cx, cy), r, color);
+    }
+}
+
+fn load_font() -> Result<&'static FontVec> {
+    FONT.get_or_try_init(|| {
+        let data = std::fs::read(&CONFIG.quote.font_path)?;
+        FontVec::try_from_vec(data).map_err(BotError::generic)
+    })
+}
+
+fn render_sync(font: &FontVec, quote: &Quote) -> Result<Vec<u8>> {
+    let accent = PALETTE[quote.name.bytes().map(usize::from).sum::<usize>() % PALETTE.len()];
+    let bubble_x = PADDING + AVATAR + PADDING;
+    let max_text = WIDTH as i32 - bubble_x - PADDING - 2 * INNER_PADDING;
+    let header = INNER_PADDING + LINE_HEIGHT;
+    let max_lines = (MAX_HEIGHT as i32 - 2 * PADDING - header - INNER_PADDING) / LINE_HEIGHT;
+
+    let runs = styled_runs(&quote.text, &quote.entities);
+    let (placed, lines) = layout(font, &runs, max_text, max_lines);
+
+    let name: String = quote.name.chars().take(40).collect();
+    let (name_width, _) = text_size(PxScale::from(NAME_SCALE), font, &name);
+    let text_width = placed
+        .iter()
+        .map(|v| v.x + text_size(PxScale::from(TEXT_SCALE), font, &v.text).0 as i32)
+        .max()
+        .unwrap_or(0);
+    let bubble_w = (text_width.max(name_width as i32).min(max_text)) + 2 * INNER_PADDING;
+    let bubble_h = header + lines * LINE_HEIGHT + INNER_PADDING;
+    let height = (bubble_h.max(AVATAR) + 2 * PADDING).min(MAX_HEIGHT as i32);
+
+    let mut img = RgbaImage::new(WIDTH, height as u32);
+    draw_filled_circle_mut(
+        &mut img,
+        (PADDING + AVATAR / 2, height - PADDING - AVATAR / 2),
+        AVATAR / 2,
+        accent,
+    );
+    if let Some(initial) = name.chars().next() {
+        let initial = initial.to_uppercase().to_string();
+        let (w, h) = text_size(PxScale::from(NAME_SCALE), font, &initial);
+        draw_text_mut(
+            &mut img,
+            TEXT,
+            PADDING + (AVATAR - w as i32) / 2,
+            height - PADDING - (AVATAR + h as i32) / 2,
+            PxScale::from(NAME_SCALE),
+            font,
+            &initial,
+        );
+    }
+
+    rounded_rect(&mut img, bubble_x, PADDING, bubble_w, bubble_h, BUBBLE);
+    let left = bubble_x + INNER_PADDING;
+    draw_text_mut(
+        &mut img,
+        accent,
+        left,
+        PADDING + INNER_PADDING,
+        PxScale::from(NAME_SCALE),
+        font,
+        &name,
+    );
+    for word in placed {
+        let x = left + word.x;
+        let y = PADDING + header + word.line * LINE_HEIGHT;
+        let color = word.style.color();
+        draw_text_mut(
+            &mut img,
+            color,
+            x,
+            y,
+            PxScale::from(TEXT_SCALE),
+            font,
+            &word.text,
+        );
+        let (w, h) = text_size(PxScale::from(TEXT_SCALE), font, word.text.trim_end());
+        match word.style {
+            Style::Bold => draw_text_mut(
+                &mut img,
+                color,
+                x + 1,
+                y,
+                PxScale::from(TEXT_SCALE),
+                font,
+                &word.text,
+            ),
+            Style::Strike => {
+                let mid = (y + h as i32 / 2) as f32;
+                draw_line_segment_mut(
+                    &mut img,
+                    (x as f32, mid),
+                    ((x + w as i32) as f32, mid),
+                    color,
+                )
+            }
+            Style::Underline => {
+                let bottom = (y + h as i32 + 2) as f32;
+                draw_line_segment_mut(
+                    &mut img,
+                    (x as f32, bottom),
+                    ((x + w as i32) as f32, bottom),
+                    color,
+                )
+            }
+            _ => (),
+        }
+    }
+
+    let mut bytes = Vec::new();
+    img.write_to(&mut Cursor::new(&mut bytes), ImageFormat::WebP)
+        .map_err(BotError::generic)?;
+    Ok(bytes)
+}
+
+/// Render a quote as a webp sticker, waiting for a free render slot first
+pub async fn render_quote(quote: Quote) -> Result<Vec<u8>> {
+    let permit = RENDERING.acquire().await.map_err(BotError::generic)?;
+    let timeout = Duration::from_secs(CONFIG.quote.timeout_seconds);
+    // the permit moves into the render so a slot stays taken until rendering really stops,
+    // even if we stop waiting for it
+    let instance = Instance::current();
+    let render = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        instance.enter(|| render_sync(load_font()?, &quote))
+    });
+    match tokio::time::timeout(timeout, render).await {
+        Ok(res) => res?,
+        Err(_) => Err(BotError::generic("quote took too long to render")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runs_follow_utf16_offsets() {
+        let runs = styled_runs("🧋 bold plain", &[(3, 4, Style::Bold)]);
+        assert_eq!(
+            runs,
+            vec![
+                ("🧋 ".to_owned(), Style::Plain),
+                ("bold".to_owned(), Style::Bold),
+                (" plain".to_owned(), Style::Plain),
+            ]
+        );
+    }
+}
@@ -0,0 +1,134 @@
+//! Token bucket rate limiting for commands, checked before updates reach any module.
+//! Each chat and each user within a chat gets a bucket that refills over a minute, so
+//! short bursts are allowed but floods of commands are dropped instead of answered.
+//! Only commands are limited so locks, blocklists, and other moderation still see every
+//! message. Admins are never limited
+
+use std::time::{Duration, Instant};
+
+use botapi::gen_types::{Message, UpdateExt};
+use dashmap::DashMap;
+
+use crate::statics::{BotLocal, CONFIG};
+use crate::util::error::Result;
+
+use super::command::is_command;
+use super::dialog::get_dialog;
+use super::permissions::IsGroupAdmin;
+
+/// Buckets untouched for this long are full again and can be forgotten
+const BUCKET_IDLE: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            tokens: f64::MAX,
+            updated: now,
+        }
+    }
+
+    /// Refill the bucket for the time passed and take a token if there is one
+    fn take(&mut self, per_minute: u32, now: Instant) -> bool {
+        let capacity = per_minute as f64;
+        let refill = now.duration_since(self.updated).as_secs_f64() * capacity / 60.0;
+        self.tokens = (self.tokens + refill).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+static CHAT_BUCKETS: BotLocal<DashMap<i64, Bucket>> = BotLocal::new(DashMap::new);
+static USER_BUCKETS: BotLocal<DashMap<(i64, i64), Bucket>> = BotLocal::new(DashMap::new);
+
+/// Get the limits for a chat as (chat, user) commands per minute, 0 meaning unlimited
+pub async fn get_rate_limits(message: &Message) -> Result<(u32, u32)> {
+    let dialog = get_dialog(message.get_chat()).await?;
+    let chat = dialog
+        .as_ref()
+        .and_then(|v| v.ratelimit_chat)
+        .map(|v| v.max(0) as u32)
+        .unwrap_or(CONFIG.ratelimit.chat_per_minute);
+    let user = dialog
+        .as_ref()
+        .and_then(|v| v.ratelimit_user)
+        .map(|v| v.max(0) as u32)
+        .unwrap_or(CONFIG.ratelimit.user_per_minute);
+    Ok((chat, user))
+}
+
+fn take<K>(buckets: &DashMap<K, Bucket>, key: K, per_minute: u32, now: Instant) -> bool
+where
+    K: std::hash::Hash + Eq,
+{
+    per_minute == 0
+        || buckets
+            .entry(key)
+            .or_insert_with(|| Bucket::new(now))
+            .take(per_minute, now)
+}
+
+/// Returns true if an update should be dropped instead of handled. Users are checked
+/// before chats so one user's flood doesn't use up the chat's limit
+pub async fn is_rate_limited(update: &UpdateExt) -> Result<bool> {
+    let message = match update {
+        UpdateExt::Message(message) => message,
+        _ => return Ok(false),
+    };
+    let (Some(text), Some(user)) = (
+        message.get_text().or_else(|| message.get_caption()),
+        message.get_from(),
+    ) else {
+        return Ok(false);
+    };
+    if !is_command(text) {
+        return Ok(false);
+    }
+    let (chat_limit, user_limit) = get_rate_limits(message).await?;
+    let chat = message.get_chat().get_id();
+    let now = Instant::now();
+    let allowed = take(&USER_BUCKETS, (chat, user.get_id()), user_limit, now)
+        && take(&CHAT_BUCKETS, chat, chat_limit, now);
+    if allowed {
+        return Ok(false);
+    }
+    // only look up admins once limited, since it is slower than the buckets
+    Ok(!message.is_group_admin().await?)
+}
+
+/// Periodically forget buckets that have refilled, so memory use follows active chats
+pub async fn run_cleanup() {
+    let mut interval = tokio::time::interval(BUCKET_IDLE);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        CHAT_BUCKETS.retain(|_, v| now.duration_since(v.updated) < BUCKET_IDLE);
+        USER_BUCKETS.retain(|_, v| now.duration_since(v.updated) < BUCKET_IDLE);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn bucket_refill() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(start);
+        for _ in 0..6 {
+            assert!(bucket.take(6, start));
+        }
+        assert!(!bucket.take(6, start));
+        assert!(bucket.take(6, start + Duration::from_secs(10)));
+        assert!(!bucket.take(6, start + Duration::from_secs(10)));
+    }
+}
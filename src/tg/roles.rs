@@ -0,0 +1,246 @@
+//! Chat-local roles. A role is a named group of members that can be mentioned all at once
+//! with @name and used to restrict who can run custom commands. Roles can also be granted
+//! bot commands, letting their members use those commands without being admins. A role
+//! exists while it has members or granted commands
+
+use std::collections::BTreeMap;
+
+use chrono::Duration;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+
+use crate::persist::core::{role_commands, roles};
+use crate::persist::redis::{default_cache_query, CachedQueryTrait};
+use crate::statics::{CONFIG, DB, REDIS};
+use crate::util::error::Result;
+
+use super::command::Context;
+
+/// Longest name a role can have
+pub const MAX_ROLE_LEN: usize = 32;
+
+/// Commands that manage roles, which can't be granted to a role since its members could
+/// then grant themselves anything
+pub const UNGRANTABLE: [&str; 4] = ["role", "roleadd", "roledel", "rmrole"];
+
+#[inline(always)]
+fn get_roles_key(chat: i64) -> String {
+    format!("roles:{}", chat)
+}
+
+#[inline(always)]
+fn get_role_commands_key(chat: i64) -> String {
+    format!("rolecmds:{}", chat)
+}
+
+/// Strip the leading @ from a role name and check that it could be mentioned. Role names
+/// are case insensitive and stored in lowercase
+pub fn parse_role_name(name: &str) -> Option<String> {
+    let name = name.trim().trim_start_matches('@').to_lowercase();
+    (!name.is_empty()
+        && name.len() <= MAX_ROLE_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+    .then_some(name)
+}
+
+/// Get every role in a chat along with its members
+pub async fn get_roles(chat: i64) -> Result<BTreeMap<String, Vec<i64>>> {
+    let roles = default_cache_query(
+        |_, _| async move {
+            let mut roles = BTreeMap::<String, Vec<i64>>::new();
+            for role in roles::Entity::find()
+                .filter(roles::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?
+            {
+                roles.entry(role.name).or_default().push(role.user);
+            }
+            Ok(Some(roles))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_roles_key(chat), &())
+    .await?;
+    Ok(roles.unwrap_or_default())
+}
+
+/// Get the members of a single role, empty if the role doesn't exist
+pub async fn get_role_members(chat: i64, role: &str) -> Result<Vec<i64>> {
+    Ok(get_roles(chat).await?.remove(role).unwrap_or_default())
+}
+
+/// Returns true if a user is a member of a role
+pub async fn has_role(chat: i64, user: i64, role: &str) -> Result<bool> {
+    Ok(get_roles(chat)
+        .await?
+        .get(role)
+        .map(|v| v.contains(&user))
+        .unwrap_or(false))
+}
+
+/// Add a user to a role, creating the role if this is its first member
+pub async fn add_role_member(chat: i64, user: i64, role: &str) -> Result<()> {
+    roles::Entity::insert(
+        roles::Model {
+            chat,
+            name: role.to_owned(),
+            user,
+        }
+        .into_active_model(),
+    )
+    .on_conflict(
+        OnConflict::columns([
+            roles::Column::Chat,
+            roles::Column::Name,
+            roles::Column::User,
+        ])
+        .do_nothing()
+        .to_owned(),
+    )
+    .exec_without_returning(*DB)
+    .await?;
+    REDIS.invalidate(&[&get_roles_key(chat)]).await?;
+    Ok(())
+}
+
+/// Remove a user from a role, returning false if they weren't a member
+pub async fn remove_role_member(chat: i64, user: i64, role: &str) -> Result<bool> {
+    let res = roles::Entity::delete_by_id((chat, role.to_owned(), user))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_roles_key(chat)]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+/// Remove every member and granted command from a role, returning false if the role
+/// didn't exist
+pub async fn delete_role(chat: i64, role: &str) -> Result<bool> {
+    let members = roles::Entity::delete_many()
+        .filter(roles::Column::Chat.eq(chat))
+        .filter(roles::Column::Name.eq(role))
+        .exec(*DB)
+        .await?;
+    let commands = role_commands::Entity::delete_many()
+        .filter(role_commands::Column::Chat.eq(chat))
+        .filter(role_commands::Column::Role.eq(role))
+        .exec(*DB)
+        .await?;
+    REDIS
+        .invalidate(&[&get_roles_key(chat), &get_role_commands_key(chat)])
+        .await?;
+    Ok(members.rows_affected > 0 || commands.rows_affected > 0)
+}
+
+/// Get the commands granted to each role in a chat
+pub async fn get_role_commands(chat: i64) -> Result<BTreeMap<String, Vec<String>>> {
+    let commands = default_cache_query(
+        |_, _| async move {
+            let mut commands = BTreeMap::<String, Vec<String>>::new();
+            for grant in role_commands::Entity::find()
+                .filter(role_commands::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?
+            {
+                commands.entry(grant.role).or_default().push(grant.command);
+            }
+            Ok(Some(commands))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_role_commands_key(chat), &())
+    .await?;
+    Ok(commands.unwrap_or_default())
+}
+
+/// Let members of a role use a command
+pub async fn grant_command(chat: i64, role: &str, command: &str) -> Result<()> {
+    role_commands::Entity::insert(
+        role_commands::Model {
+            chat,
+            role: role.to_owned(),
+            command: command.to_owned(),
+        }
+        .into_active_model(),
+    )
+    .on_conflict(
+        OnConflict::columns([
+            role_commands::Column::Chat,
+            role_commands::Column::Role,
+            role_commands::Column::Command,
+        ])
+        .do_nothing()
+        .to_owned(),
+    )
+    .exec_without_returning(*DB)
+    .await?;
+    REDIS.invalidate(&[&get_role_commands_key(chat)]).await?;
+    Ok(())
+}
+
+/// Take a command away from a role, returning false if it wasn't granted
+pub async fn revoke_command(chat: i64, role: &str, command: &str) -> Result<bool> {
+    let res = role_commands::Entity::delete_by_id((chat, role.to_owned(), command.to_owned()))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[&get_role_commands_key(chat)]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+/// Returns true if a user is in a role that was granted a command
+pub async fn role_grants_command(chat: i64, user: i64, command: &str) -> Result<bool> {
+    if UNGRANTABLE.contains(&command) {
+        return Ok(false);
+    }
+    let commands = get_role_commands(chat).await?;
+    let granted = commands
+        .iter()
+        .filter(|(_, commands)| commands.iter().any(|v| v == command))
+        .map(|(role, _)| role)
+        .collect::<Vec<&String>>();
+    if granted.is_empty() {
+        return Ok(false);
+    }
+    let roles = get_roles(chat).await?;
+    Ok(granted
+        .into_iter()
+        .any(|role| roles.get(role).map(|v| v.contains(&user)).unwrap_or(false)))
+}
+
+impl Context {
+    /// Returns true if a user is a member of a role in the chat of this update
+    pub async fn has_role(&self, user: i64, role: &str) -> Result<bool> {
+        match self.chat() {
+            Some(chat) => has_role(chat.get_id(), user, role).await,
+            None => Ok(false),
+        }
+    }
+
+    /// Returns true if the sender of this command is in a role that was granted it
+    pub async fn role_grants_command(&self) -> Result<bool> {
+        let Some(cmd) = self.cmd() else {
+            return Ok(false);
+        };
+        let message = cmd.message;
+        let Some(user) = message.get_from() else {
+            return Ok(false);
+        };
+        if message.get_sender_chat().is_some() {
+            return Ok(false);
+        }
+        role_grants_command(message.get_chat().get_id(), user.get_id(), cmd.cmd).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn role_names() {
+        assert_eq!(parse_role_name("@Helpers"), Some("helpers".to_owned()));
+        assert_eq!(parse_role_name("mods_2"), Some("mods_2".to_owned()));
+        assert_eq!(parse_role_name("@"), None);
+        assert_eq!(parse_role_name("help ers"), None);
+        assert_eq!(parse_role_name(&"a".repeat(MAX_ROLE_LEN + 1)), None);
+    }
+}
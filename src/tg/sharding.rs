@@ -0,0 +1,284 @@
+//! Opt-in sharding of update processing between processes, for bots too large for one.
+//! The process receiving updates from telegram routes each one into a redis stream picked
+//! by its chat id, and worker processes started with --worker each consume one stream.
+//! Every update for a chat goes to the same worker, so per-chat state like conversations
+//! stays in one process. Button callbacks are kept in memory by whichever process sent the
+//! button, which isn't always the worker for the chat the button is in, for example buttons
+//! sent by scheduled jobs in the routing process or sent to a user's private chat. The
+//! process registering a callback records itself as its owner in redis, and presses of the
+//! button are routed to the owner instead of by chat
+//!
+//! Updates are acknowledged once handed to the handler, so an update being handled when a
+//! worker stops is lost, like one being handled when a single process stops. Updates routed
+//! while a worker is down wait in its stream, up to the configured length
+
+use std::future::Future;
+use std::time::Duration;
+
+use botapi::gen_types::{MaybeInaccessibleMessage, UpdateExt};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamMaxlen, StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use tokio::sync::OnceCell;
+
+use crate::statics::{Instance, ARGS};
+use crate::util::error::{BotError, Result};
+
+/// Consumer group every worker reads its stream with
+const GROUP: &str = "workers";
+
+/// Field of a stream entry containing the json encoded update
+const FIELD: &str = "update";
+
+/// Most updates read from a stream at once
+const BATCH: usize = 100;
+
+/// Milliseconds to wait for new updates before reading again
+const BLOCK_MS: usize = 5000;
+
+/// Seconds to wait before reconnecting after losing a stream
+const RETRY: u64 = 5;
+
+/// Seconds the owner of a button callback is remembered
+const OWNER_SECONDS: u64 = 60 * 60 * 24 * 7;
+
+/// Owner recorded for callbacks registered in the process receiving updates
+const ROUTER: i64 = -1;
+
+/// Pick the shard for a chat or user id. The same id always gets the same shard as long
+/// as the number of shards doesn't change
+pub fn shard_of(id: i64, shards: u32) -> u32 {
+    id.rem_euclid(shards.max(1) as i64) as u32
+}
+
+/// Get the id an update is routed by. This is the chat for anything that happens in a
+/// chat and the user for inline queries and payments. Updates with neither all go to
+/// the first shard
+pub fn routing_id(update: &UpdateExt) -> Option<i64> {
+    match update {
+        UpdateExt::Message(ref m)
+        | UpdateExt::EditedMessage(ref m)
+        | UpdateExt::ChannelPost(ref m)
+        | UpdateExt::EditedChannelPost(ref m) => Some(m.get_chat().get_id()),
+        UpdateExt::CallbackQuery(ref q) => Some(match q.get_message() {
+            Some(MaybeInaccessibleMessage::Message(m)) => m.get_chat().get_id(),
+            Some(MaybeInaccessibleMessage::InaccessibleMessage(m)) => m.get_chat().get_id(),
+            None => q.get_from().get_id(),
+        }),
+        UpdateExt::ChatMember(ref m) | UpdateExt::MyChatMember(ref m) => {
+            Some(m.get_chat().get_id())
+        }
+        UpdateExt::ChatJoinRequest(ref r) => Some(r.get_chat().get_id()),
+        UpdateExt::InlineQuery(ref q) => Some(q.get_from().get_id()),
+        UpdateExt::ChosenInlineResult(ref r) => Some(r.get_from().get_id()),
+        UpdateExt::PreCheckoutQuery(ref q) => Some(q.get_from().get_id()),
+        _ => None,
+    }
+}
+
+/// Routes updates into per shard redis streams and reads them back out in workers. Does
+/// nothing unless enabled in the config
+pub struct UpdateShards {
+    shards: u32,
+    stream: String,
+    max_len: usize,
+    client: Option<redis::Client>,
+    conn: OnceCell<MultiplexedConnection>,
+}
+
+impl UpdateShards {
+    pub(crate) fn new() -> Self {
+        let config = Instance::current().config().filter(|v| v.sharding.enabled);
+        let client = config.and_then(|config| {
+            let url = config
+                .sharding
+                .url
+                .as_deref()
+                .unwrap_or(&config.persistence.redis_connection);
+            redis::Client::open(url)
+                .map_err(|err| log::error!("invalid sharding url: {}", err))
+                .ok()
+        });
+        Self {
+            shards: config.map(|v| v.sharding.shards.max(1)).unwrap_or(1),
+            stream: config
+                .map(|v| v.sharding.stream.clone())
+                .unwrap_or_default(),
+            max_len: config.map(|v| v.sharding.max_len).unwrap_or_default(),
+            client,
+            conn: OnceCell::new(),
+        }
+    }
+
+    /// Returns true if updates should be routed to workers instead of handled here
+    pub fn is_enabled(&self) -> bool {
+        self.client.is_some()
+    }
+
+    fn stream_key(&self, shard: u32) -> String {
+        format!("{}:{}", self.stream, shard)
+    }
+
+    fn owner_key(&self, data: &str) -> String {
+        format!("{}:owner:{}", self.stream, data)
+    }
+
+    async fn conn(&self, client: &redis::Client) -> Result<MultiplexedConnection> {
+        let conn = self
+            .conn
+            .get_or_try_init(|| async {
+                Ok::<_, BotError>(client.get_multiplexed_async_connection().await?)
+            })
+            .await?;
+        Ok(conn.clone())
+    }
+
+    /// Record this process as the owner of a button callback, so presses of the button
+    /// are routed here
+    pub async fn claim_callback(&self, data: &str) -> Result<()> {
+        let Some(client) = self.client.as_ref() else {
+            return Ok(());
+        };
+        let owner = ARGS
+            .get()
+            .and_then(|v| v.worker)
+            .map(i64::from)
+            .unwrap_or(ROUTER);
+        self.conn(client)
+            .await?
+            .set_ex::<_, _, ()>(self.owner_key(data), owner, OWNER_SECONDS)
+            .await?;
+        Ok(())
+    }
+
+    /// Get the shard an update goes to, None if it belongs to the routing process. Button
+    /// presses go to the owner of the button's callback if it has one
+    async fn destination(
+        &self,
+        conn: &mut MultiplexedConnection,
+        update: &UpdateExt,
+    ) -> Result<Option<u32>> {
+        if let UpdateExt::CallbackQuery(ref query) = update {
+            if let Some(data) = query.get_data() {
+                let owner: Option<i64> = conn.get(self.owner_key(data)).await?;
+                match owner {
+                    Some(ROUTER) => return Ok(None),
+                    Some(shard) if (0..self.shards as i64).contains(&shard) => {
+                        return Ok(Some(shard as u32))
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(Some(shard_of(routing_id(update).unwrap_or(0), self.shards)))
+    }
+
+    /// Add an update to the stream of the shard it belongs to. Returns false if the update
+    /// belongs to this process and should be handled here instead
+    pub async fn route(&self, update: &UpdateExt) -> Result<bool> {
+        let Some(client) = self.client.as_ref() else {
+            return Err(BotError::generic("sharding is not enabled"));
+        };
+        let mut conn = self.conn(client).await?;
+        let Some(shard) = self.destination(&mut conn, update).await? else {
+            return Ok(false);
+        };
+        let body = serde_json::to_string(update)?;
+        conn.xadd_maxlen::<_, _, _, _, ()>(
+            self.stream_key(shard),
+            StreamMaxlen::Approx(self.max_len),
+            "*",
+            &[(FIELD, body)],
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Handle the updates routed to a shard. Runs forever, reconnecting if the connection
+    /// drops
+    pub async fn consume<F, Fut>(&self, shard: u32, handle: F) -> Result<()>
+    where
+        F: Fn(UpdateExt) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let Some(client) = self.client.as_ref() else {
+            return Err(BotError::generic("sharding is not enabled"));
+        };
+        if shard >= self.shards {
+            return Err(BotError::Generic(format!(
+                "worker {} is out of range, there are {} shards",
+                shard, self.shards
+            )));
+        }
+        loop {
+            if let Err(err) = self.read(client, shard, &handle).await {
+                log::warn!("lost update stream for shard {}: {}", shard, err);
+                err.record_stats();
+            }
+            tokio::time::sleep(Duration::from_secs(RETRY)).await;
+        }
+    }
+
+    async fn read<F, Fut>(&self, client: &redis::Client, shard: u32, handle: &F) -> Result<()>
+    where
+        F: Fn(UpdateExt) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        // blocking reads hold up everything else on a connection, so workers get their own
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        let key = self.stream_key(shard);
+        let created: redis::RedisResult<()> = conn.xgroup_create_mkstream(&key, GROUP, "0").await;
+        if let Err(err) = created {
+            if err.code() != Some("BUSYGROUP") {
+                return Err(err.into());
+            }
+        }
+        let consumer = format!("worker-{}", shard);
+        let options = StreamReadOptions::default()
+            .group(GROUP, &consumer)
+            .count(BATCH)
+            .block(BLOCK_MS);
+        // updates read before a restart but never acknowledged come first
+        let mut pending = true;
+        loop {
+            let id = if pending { "0" } else { ">" };
+            let reply: StreamReadReply = conn.xread_options(&[&key], &[id], &options).await?;
+            let entries = reply
+                .keys
+                .into_iter()
+                .flat_map(|v| v.ids)
+                .collect::<Vec<_>>();
+            if pending && entries.is_empty() {
+                pending = false;
+                continue;
+            }
+            for entry in entries {
+                match entry
+                    .get::<String>(FIELD)
+                    .map(|v| serde_json::from_str::<UpdateExt>(&v))
+                {
+                    Some(Ok(update)) => handle(update).await,
+                    Some(Err(err)) => log::warn!("invalid update in {}: {}", key, err),
+                    None => log::warn!("update missing from {} entry {}", key, entry.id),
+                }
+                conn.xack::<_, _, _, ()>(&key, GROUP, &[&entry.id]).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shards() {
+        for id in [-1001234567890, -1, 0, 1, 42, i64::MAX, i64::MIN] {
+            let shard = shard_of(id, 4);
+            assert!(shard < 4);
+            assert_eq!(shard, shard_of(id, 4));
+        }
+        assert_eq!(shard_of(-1001234567890, 1), 0);
+        assert_eq!(shard_of(7, 0), 0);
+    }
+}
@@ -0,0 +1,121 @@
+//! Default settings applied once to chats the bot is added to. The template file named in
+//! the config has a section per module, and each module reads its own section in
+//! ModuleHelpers::apply_template. Whether a chat was set up is recorded in its dialog, so
+//! the template is never applied twice, even if the bot leaves and is added back
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use botapi::gen_types::Chat;
+use sea_orm::sea_query::Expr;
+use sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::persist::core::dialogs;
+use crate::statics::{module_enabled, BotLocal, CONFIG, DB, REDIS, TG};
+use crate::util::error::{BotError, Result};
+
+use super::dialog::get_dialog_key;
+
+static TEMPLATE: BotLocal<Option<ChatTemplate>> = BotLocal::new(load_configured);
+
+/// Settings applied to new chats, one section per module
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(transparent)]
+pub struct ChatTemplate(HashMap<String, serde_json::Value>);
+
+impl ChatTemplate {
+    /// Load a template from a json file, or a toml file for any other extension
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Err(BotError::generic(format!(
+                "template {} does not exist",
+                path.display()
+            )));
+        }
+        if path.extension().map(|v| v == "json").unwrap_or(false) {
+            let file = std::fs::File::open(path)?;
+            Ok(serde_json::from_reader(file)?)
+        } else {
+            confy::load_path(path).map_err(|err| BotError::generic(err.to_string()))
+        }
+    }
+
+    /// Parse a module's section of the template, if present
+    pub fn section<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>> {
+        self.0
+            .get(name)
+            .map(|v| serde_json::from_value(v.clone()))
+            .transpose()
+            .map_err(|err| err.into())
+    }
+}
+
+fn load_configured() -> Option<ChatTemplate> {
+    let path = CONFIG.template.path.as_ref()?;
+    match ChatTemplate::load(path) {
+        Ok(template) => Some(template),
+        Err(err) => {
+            log::warn!("failed to load chat template: {}", err);
+            None
+        }
+    }
+}
+
+/// Mark a chat as set up, returning false if it already was
+async fn claim(chat: &Chat) -> Result<bool> {
+    let claimed = dialogs::Entity::update_many()
+        .col_expr(dialogs::Column::TemplateApplied, Expr::value(true))
+        .filter(dialogs::Column::ChatId.eq(chat.get_id()))
+        .filter(dialogs::Column::TemplateApplied.eq(false))
+        .exec(*DB)
+        .await?;
+    REDIS.invalidate(&[get_dialog_key(chat.get_id())]).await?;
+    Ok(claimed.rows_affected > 0)
+}
+
+/// Apply the configured template to a chat the bot was just added to. Does nothing if
+/// templates are turned off or the chat was already set up. The chat's dialog must exist
+pub async fn apply_template(chat: &Chat) -> Result<()> {
+    if !CONFIG.template.enabled {
+        return Ok(());
+    }
+    let Some(template) = TEMPLATE.as_ref() else {
+        return Ok(());
+    };
+    if !claim(chat).await? {
+        return Ok(());
+    }
+    log::info!("applying template to {}", chat.get_id());
+    for module in TG.modules.iter().filter(|v| module_enabled(&v.name)) {
+        if let Some(state) = module.state.as_ref() {
+            if let Err(err) = state.apply_template(chat, template).await {
+                log::warn!("failed to apply {} template: {}", module.name, err);
+                err.record_stats();
+            }
+        }
+    }
+    TG.apply_external_template(chat, template).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Section {
+        locked: Vec<String>,
+    }
+
+    #[test]
+    fn parse_section() {
+        let template: ChatTemplate =
+            serde_json::from_str(r#"{"locks": {"locked": ["url", "sticker"]}}"#).unwrap();
+        let locks: Section = template.section("locks").unwrap().unwrap();
+        assert_eq!(locks.locked, vec!["url", "sticker"]);
+        assert!(template.section::<Section>("welcome").unwrap().is_none());
+    }
+}
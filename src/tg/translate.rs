@@ -0,0 +1,230 @@
+//! Message translation through an external backend. Formatting is kept by converting a
+//! message's entities to html, which both supported backends translate around, and sending
+//! the result back with telegram's html parse mode. Translations are cached by target
+//! language and source html, so the same message is only translated once
+
+use std::time::Duration;
+
+use botapi::gen_types::MessageEntity;
+use lazy_static::lazy_static;
+use redis::AsyncCommands;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::statics::{TranslateBackend, CONFIG, REDIS};
+use crate::util::error::{BotError, Result};
+
+use super::markdown::normalize_entities;
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::new();
+    static ref TAG_REGEX: Regex = Regex::new(r"<[^>]*>").unwrap();
+}
+
+#[derive(Deserialize)]
+struct LibreResponse {
+    #[serde(rename = "translatedText")]
+    translated_text: String,
+}
+
+#[derive(Deserialize)]
+struct DeepLTranslation {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct DeepLResponse {
+    translations: Vec<DeepLTranslation>,
+}
+
+fn get_translate_key(lang: &str, html: &str) -> String {
+    let hash = hex::encode(Sha256::digest(html.as_bytes()));
+    format!("tl:{}:{}", lang, hash)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Opening and closing html tags for an entity, None for entities without formatting
+fn entity_tags(entity: &MessageEntity) -> Option<(String, &'static str)> {
+    let tags = match entity.get_tg_type() {
+        "bold" => ("<b>".to_owned(), "</b>"),
+        "italic" => ("<i>".to_owned(), "</i>"),
+        "underline" => ("<u>".to_owned(), "</u>"),
+        "strikethrough" => ("<s>".to_owned(), "</s>"),
+        "spoiler" => ("<tg-spoiler>".to_owned(), "</tg-spoiler>"),
+        "code" => ("<code>".to_owned(), "</code>"),
+        "pre" => ("<pre>".to_owned(), "</pre>"),
+        "blockquote" => ("<blockquote>".to_owned(), "</blockquote>"),
+        "text_link" => (
+            format!("<a href=\"{}\">", escape_html(entity.get_url()?)),
+            "</a>",
+        ),
+        _ => return None,
+    };
+    Some(tags)
+}
+
+/// Convert text and its entities to telegram flavored html
+pub fn to_html(text: &str, entities: Option<&Vec<MessageEntity>>) -> String {
+    let mut entities = entities.cloned().unwrap_or_default();
+    entities.retain(|v| entity_tags(v).is_some());
+    normalize_entities(text, &mut entities);
+
+    let units = text.encode_utf16().collect::<Vec<u16>>();
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<(i64, &'static str)> = Vec::new();
+    let mut entities = entities.iter().peekable();
+    let mut start = 0;
+    for pos in 0..=units.len() as i64 {
+        let closing = open.last().map(|(end, _)| *end == pos).unwrap_or(false);
+        let opening = entities
+            .peek()
+            .map(|v| v.get_offset() == pos)
+            .unwrap_or(false);
+        if !closing && !opening {
+            continue;
+        }
+        out.push_str(&escape_html(&String::from_utf16_lossy(
+            &units[start..pos as usize],
+        )));
+        start = pos as usize;
+        while let Some((_, close)) = open.last().filter(|(end, _)| *end == pos) {
+            out.push_str(close);
+            open.pop();
+        }
+        while let Some(entity) = entities.next_if(|v| v.get_offset() == pos) {
+            if let Some((tag, close)) = entity_tags(entity) {
+                out.push_str(&tag);
+                open.push((pos + entity.get_length(), close));
+            }
+        }
+    }
+    out.push_str(&escape_html(&String::from_utf16_lossy(&units[start..])));
+    out
+}
+
+/// Remove html formatting, for when telegram rejects a translation's html
+pub fn strip_html(html: &str) -> String {
+    TAG_REGEX
+        .replace_all(html, "")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+async fn request(lang: &str, html: &str) -> Result<String> {
+    let text = match CONFIG.translate.backend {
+        TranslateBackend::LibreTranslate => {
+            let url = format!("{}/translate", CONFIG.translate.url.trim_end_matches('/'));
+            let res: LibreResponse = CLIENT
+                .post(url)
+                .json(&json!({
+                    "q": html,
+                    "source": "auto",
+                    "target": lang,
+                    "format": "html",
+                    "api_key": CONFIG.translate.api_key,
+                }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+                .map_err(|err| err.without_url())?;
+            res.translated_text
+        }
+        TranslateBackend::DeepL => {
+            let url = format!(
+                "{}/v2/translate",
+                CONFIG.translate.url.trim_end_matches('/')
+            );
+            let res: DeepLResponse = CLIENT
+                .post(url)
+                .header(
+                    reqwest::header::AUTHORIZATION,
+                    format!(
+                        "DeepL-Auth-Key {}",
+                        CONFIG.translate.api_key.as_deref().unwrap_or_default()
+                    ),
+                )
+                .json(&json!({
+                    "text": [html],
+                    "target_lang": lang.to_uppercase(),
+                    "tag_handling": "html",
+                }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+                .map_err(|err| err.without_url())?;
+            res.translations
+                .into_iter()
+                .next()
+                .map(|v| v.text)
+                .ok_or_else(|| BotError::generic("translation missing from response"))?
+        }
+    };
+    Ok(text)
+}
+
+/// Translate telegram flavored html to a language, given as a two letter code
+pub async fn translate(lang: &str, html: &str) -> Result<String> {
+    let key = get_translate_key(lang, html);
+    let cached: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    if let Some(cached) = cached {
+        return Ok(cached);
+    }
+    let timeout = Duration::from_secs(CONFIG.translate.timeout_seconds);
+    let text = tokio::time::timeout(timeout, request(lang, html))
+        .await
+        .map_err(|_| BotError::generic("translation took too long"))??;
+    REDIS
+        .pipe(|q| {
+            q.set(&key, &text)
+                .expire(&key, CONFIG.translate.cache_seconds)
+                .ignore()
+        })
+        .await?;
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use botapi::gen_types::MessageEntityBuilder;
+
+    fn entity(kind: &str, offset: i64, length: i64) -> MessageEntity {
+        MessageEntityBuilder::new(offset, length)
+            .set_type(kind.to_owned())
+            .build()
+    }
+
+    #[test]
+    fn html_nesting() {
+        let entities = vec![
+            entity("bold", 0, 11),
+            entity("italic", 6, 5),
+            entity("mention", 12, 4),
+        ];
+        assert_eq!(
+            to_html("hello world @bob <3", Some(&entities)),
+            "<b>hello <i>world</i></b> @bob &lt;3"
+        );
+        assert_eq!(strip_html("<b>a &lt;3</b>"), "a <3");
+    }
+
+    #[test]
+    fn html_utf16_offsets() {
+        let entities = vec![entity("bold", 3, 2)];
+        assert_eq!(to_html("🙂 ok", Some(&entities)), "🙂 <b>ok</b>");
+    }
+}
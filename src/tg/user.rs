@@ -11,6 +11,7 @@ use async_trait::async_trait;
 use botapi::gen_types::{Chat, MessageOrigin, UpdateExt, User};
 use redis::AsyncCommands;
 
+use super::bridges::is_bridged_id;
 use super::markdown::{Escape, Markup, MarkupType};
 
 fn get_user_cache_key(user: i64) -> String {
@@ -25,6 +26,10 @@ fn get_chat_cache_key(chat: i64) -> String {
     format!("chat:{}", chat)
 }
 
+fn get_bio_key(user: i64) -> String {
+    format!("ubio:{}", user)
+}
+
 /// Get the user for this bot. This function just caches the getMe telegram API call
 pub async fn get_me() -> Result<User> {
     let me_key = "user_me";
@@ -97,6 +102,33 @@ pub async fn record_cache_update(update: &UpdateExt) -> Result<()> {
     Ok(())
 }
 
+/// Get a user's bio, cached for as long as profiles go without being rechecked by the
+/// profile blocklist. Bios can't always be fetched, for example if the user hid it from
+/// the bot
+pub async fn get_bio(user: i64) -> Result<Option<String>> {
+    let key = get_bio_key(user);
+    let cached: Option<String> = REDIS.sq(|q| q.get(&key)).await?;
+    if let Some(bio) = cached {
+        return Ok(Some(bio).filter(|v| !v.is_empty()));
+    }
+    let bio = match TG.client().build_get_chat(user).build().await {
+        Ok(chat) => chat.get_bio().map(|v| v.to_owned()),
+        Err(err) => {
+            log::debug!("failed to get bio of {}: {}", user, err);
+            None
+        }
+    };
+    let value = bio.clone().unwrap_or_default();
+    REDIS
+        .pipe(|q| {
+            q.set(&key, &value)
+                .expire(&key, CONFIG.moderation.profile_recheck_minutes.max(1) * 60)
+                .ignore()
+        })
+        .await?;
+    Ok(bio)
+}
+
 /// get a cached user by id
 pub async fn get_user(user: i64) -> Result<Option<User>> {
     let key = get_user_cache_key(user);
@@ -216,6 +248,8 @@ impl From<&User> for crate::persist::core::users::Model {
             last_name: user.get_last_name().map(|v| v.to_owned()),
             username: user.get_username().map(|v| v.to_owned()),
             is_bot: user.get_is_bot(),
+            optout_mentions: false,
+            optout_stats: false,
         }
     }
 }
@@ -235,7 +269,11 @@ impl GetUser for i64 {
 
     async fn cached_name<'a>(&'a self) -> Result<Cow<'a, str>> {
         let res = if let Some(user) = self.get_cached_user().await? {
-            Cow::Owned(user.name_humanreadable().into_owned())
+            if is_bridged_id(*self) {
+                Cow::Owned(user.get_first_name().to_owned())
+            } else {
+                Cow::Owned(user.name_humanreadable().into_owned())
+            }
         } else {
             Cow::Owned(self.to_string())
         };
@@ -244,6 +282,10 @@ impl GetUser for i64 {
 
     async fn mention(&self) -> Result<Markup<String>> {
         let res = if let Some(user) = self.get_cached_user().await? {
+            if is_bridged_id(*self) {
+                // telegram has no account to link a bridged author to
+                return Ok(MarkupType::Text.text(user.get_first_name().to_owned()));
+            }
             let name = user
                 .get_username()
                 .map(|v| format!("@{}", v))
@@ -0,0 +1,197 @@
+//! Outgoing per-chat webhooks. Chat admins can register a url that is sent a json POST
+//! for some chat events, so bridges and ticket systems can follow a chat without access to
+//! the database. Each body is signed with a secret unique to the chat, sent in the
+//! X-Dijkstra-Signature header as sha256=<hex hmac of the body>
+
+use std::time::Duration as StdDuration;
+
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::ActiveValue::Set;
+use sea_orm::EntityTrait;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::persist::core::chat_webhooks;
+use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisCache};
+use crate::statics::{spawn, CONFIG, DB, REDIS};
+use crate::util::error::{BotError, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header containing the signature of a webhook body
+pub const SIGNATURE_HEADER: &str = "X-Dijkstra-Signature";
+
+const DELIVERY_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+lazy_static! {
+    static ref CLIENT: reqwest::Client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("failed to build webhook client");
+}
+
+/// An event sent to a chat's webhook
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ChatEvent {
+    NewMember {
+        user: i64,
+        name: String,
+    },
+    Warn {
+        user: i64,
+        reason: Option<String>,
+        count: i32,
+    },
+    NoteTrigger {
+        name: String,
+        user: Option<i64>,
+    },
+}
+
+#[derive(Serialize)]
+struct Delivery<'a> {
+    chat: i64,
+    time: i64,
+    #[serde(flatten)]
+    event: &'a ChatEvent,
+}
+
+#[inline(always)]
+fn get_webhook_key(chat: i64) -> String {
+    format!("chook:{}", chat)
+}
+
+/// Create a new random signing secret
+pub fn new_secret() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Check that a url can be used as a webhook. Only https is allowed so events and
+/// signatures are never sent in the clear
+pub fn validate_url(url: &str) -> Result<()> {
+    let parsed = reqwest::Url::parse(url).map_err(BotError::generic)?;
+    if parsed.scheme() != "https" || parsed.host_str().is_none() {
+        return Err(BotError::generic("webhook urls must use https"));
+    }
+    Ok(())
+}
+
+/// Get the webhook set for a chat, if any
+pub async fn get_webhook(chat: i64) -> Result<Option<chat_webhooks::Model>> {
+    default_cache_query(
+        |_, _| async move {
+            let res = chat_webhooks::Entity::find_by_id(chat).one(*DB).await?;
+            Ok(res)
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&get_webhook_key(chat), &())
+    .await
+}
+
+/// Set or replace a chat's webhook
+pub async fn set_webhook(chat: i64, url: String, secret: String) -> Result<()> {
+    validate_url(&url)?;
+    let model = chat_webhooks::Entity::insert(chat_webhooks::ActiveModel {
+        chat: Set(chat),
+        url: Set(url),
+        secret: Set(secret),
+    })
+    .on_conflict(
+        OnConflict::column(chat_webhooks::Column::Chat)
+            .update_columns([chat_webhooks::Column::Url, chat_webhooks::Column::Secret])
+            .to_owned(),
+    )
+    .exec_with_returning(*DB)
+    .await?;
+    model.cache(get_webhook_key(chat)).await?;
+    Ok(())
+}
+
+/// Remove a chat's webhook, returning false if there wasn't one
+pub async fn delete_webhook(chat: i64) -> Result<bool> {
+    let res = chat_webhooks::Entity::delete_by_id(chat).exec(*DB).await?;
+    let key = get_webhook_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(res.rows_affected > 0)
+}
+
+async fn deliver(hook: chat_webhooks::Model, body: Vec<u8>) -> Result<()> {
+    CLIENT
+        .post(&hook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(&hook.secret, &body))
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// Send an event to a chat's webhook if it has one. Delivery happens in the background
+/// and failures are only logged, a broken webhook never blocks handling an update
+pub async fn post_event(chat: i64, event: ChatEvent) -> Result<()> {
+    let Some(hook) = get_webhook(chat).await? else {
+        return Ok(());
+    };
+    let body = serde_json::to_vec(&Delivery {
+        chat,
+        time: Utc::now().timestamp(),
+        event: &event,
+    })?;
+    spawn(async move {
+        if let Err(err) = deliver(hook, body).await {
+            log::warn!("failed to deliver webhook for {}: {}", chat, err);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn event_body() {
+        let event = ChatEvent::Warn {
+            user: 42,
+            reason: None,
+            count: 1,
+        };
+        let body = serde_json::to_value(Delivery {
+            chat: -100,
+            time: 0,
+            event: &event,
+        })
+        .unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "chat": -100,
+                "time": 0,
+                "event": "warn",
+                "user": 42,
+                "reason": null,
+                "count": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn only_https() {
+        assert!(validate_url("https://example.com/hook").is_ok());
+        assert!(validate_url("http://example.com/hook").is_err());
+        assert!(validate_url("not a url").is_err());
+    }
+}
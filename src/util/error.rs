@@ -6,7 +6,9 @@
 //! sending formatted errors to the user via telegram
 use std::time::SystemTimeError;
 
+use crate::tg::admin_helpers::auto_delete_response;
 use crate::tg::command::Context;
+use crate::tg::connection::origin_of;
 use crate::tg::markdown::DefaultParseErr;
 use async_trait::async_trait;
 use bb8::RunError;
@@ -213,7 +215,7 @@ impl Fail for Context {
 
     fn fail_err<T: AsRef<str>>(&self, message: T) -> BotError {
         match self.message() {
-            Ok(get) => BotError::speak(message.as_ref(), get.chat.get_id(), Some(get.message_id)),
+            Ok(get) => get.fail_err(message),
             Err(err) => err,
         }
     }
@@ -225,10 +227,13 @@ impl Fail for Message {
     }
 
     fn fail_err<T: AsRef<str>>(&self, message: T) -> BotError {
+        // errors about commands sent from a connected private chat are sent back there
+        let origin = origin_of(self);
+        let reply = origin.as_ref().unwrap_or(self);
         BotError::speak(
             message.as_ref(),
-            self.get_chat().get_id(),
-            Some(self.message_id),
+            reply.get_chat().get_id(),
+            Some(reply.message_id),
         )
     }
 }
@@ -374,6 +379,7 @@ impl BotError {
 
     /// record this error using prometheus error counters. Counters used depend on error
     pub fn record_stats(&self) {
+        crate::persist::metrics::HANDLER_ERRORS.inc();
         if let Self::ApiError(ref error) = self {
             if let Some(error) = error.get_response() {
                 log::warn!(
@@ -415,11 +421,14 @@ impl BotError {
             Self::Speak {
                 say, chat, message, ..
             } => {
-                if let Some(message) = message {
-                    chat.force_reply(say, *message).await?;
+                let sent = if let Some(message) = message {
+                    chat.force_reply(say, *message).await?
                 } else {
                     log::warn!("attempted to speak error without reply-to message");
-                    chat.speak(say).await?;
+                    chat.speak(say).await?
+                };
+                if let Err(err) = auto_delete_response(sent.as_ref()).await {
+                    err.record_stats();
                 }
                 Ok(true)
             }
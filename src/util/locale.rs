@@ -0,0 +1,215 @@
+//! Locale-aware formatting for numbers, dates, and durations used as arguments to
+//! lang_fmt and entity_fmt. Formatting rules are picked from the chat's resolved language,
+//! unit names for durations come from the strings files like any other text.
+
+use chrono::{DateTime, FixedOffset, Utc};
+use macros::lang_fmt;
+
+use crate::langs::Lang;
+
+/// Separators and date layout for a single language
+struct LocaleFormat {
+    group: &'static str,
+    /// Group digits as 12,34,567 instead of 1,234,567
+    indian_grouping: bool,
+    datetime: &'static str,
+}
+
+const DEFAULT_FORMAT: LocaleFormat = LocaleFormat {
+    group: ",",
+    indian_grouping: false,
+    datetime: "%b %-d %Y %H:%M UTC",
+};
+
+fn get_format(lang: &Lang) -> LocaleFormat {
+    match lang {
+        Lang::Bn | Lang::Hi | Lang::Ta => LocaleFormat {
+            group: ",",
+            indian_grouping: true,
+            datetime: "%d/%m/%Y %H:%M UTC",
+        },
+        Lang::Es => LocaleFormat {
+            group: ".",
+            indian_grouping: false,
+            datetime: "%d/%m/%Y %H:%M UTC",
+        },
+        Lang::Uk => LocaleFormat {
+            group: "\u{a0}",
+            indian_grouping: false,
+            datetime: "%d.%m.%Y %H:%M UTC",
+        },
+        Lang::Fa => LocaleFormat {
+            group: "\u{66c}",
+            indian_grouping: false,
+            datetime: "%Y/%m/%d %H:%M UTC",
+        },
+        Lang::Ja | Lang::ZhTw => LocaleFormat {
+            group: ",",
+            indian_grouping: false,
+            datetime: "%Y/%m/%d %H:%M UTC",
+        },
+        Lang::Ko => LocaleFormat {
+            group: ",",
+            indian_grouping: false,
+            datetime: "%Y. %m. %d. %H:%M UTC",
+        },
+        _ => DEFAULT_FORMAT,
+    }
+}
+
+/// Insert group separators into a string of digits
+fn group_digits(digits: &str, format: &LocaleFormat) -> String {
+    let mut groups = Vec::new();
+    let mut rest = digits;
+    let mut size = 3;
+    while rest.len() > size {
+        let (head, tail) = rest.split_at(rest.len() - size);
+        groups.push(tail);
+        rest = head;
+        if format.indian_grouping {
+            size = 2;
+        }
+    }
+    groups.push(rest);
+    groups.reverse();
+    groups.join(format.group)
+}
+
+/// Types that can be formatted for display in a specific language
+pub trait Localize {
+    fn localize(&self, lang: &Lang) -> String;
+}
+
+macro_rules! localize_int {
+    ($($t:ty),*) => {
+        $(
+            impl Localize for $t {
+                fn localize(&self, lang: &Lang) -> String {
+                    let digits = self.unsigned_abs().to_string();
+                    let grouped = group_digits(&digits, &get_format(lang));
+                    if *self < 0 as $t {
+                        format!("-{}", grouped)
+                    } else {
+                        grouped
+                    }
+                }
+            }
+        )*
+    };
+}
+
+localize_int!(i32, i64, isize);
+
+macro_rules! localize_uint {
+    ($($t:ty),*) => {
+        $(
+            impl Localize for $t {
+                fn localize(&self, lang: &Lang) -> String {
+                    group_digits(&self.to_string(), &get_format(lang))
+                }
+            }
+        )*
+    };
+}
+
+localize_uint!(u32, u64, usize);
+
+impl Localize for DateTime<Utc> {
+    fn localize(&self, lang: &Lang) -> String {
+        self.format(get_format(lang).datetime).to_string()
+    }
+}
+
+impl Localize for chrono::Duration {
+    fn localize(&self, lang: &Lang) -> String {
+        let seconds = self.num_seconds().max(0) as u64;
+        std::time::Duration::from_secs(seconds).localize(lang)
+    }
+}
+
+impl Localize for std::time::Duration {
+    /// Durations are shown using at most the two largest nonzero units, "1 day 3 hours"
+    fn localize(&self, lang: &Lang) -> String {
+        let total = self.as_secs();
+        let units = [
+            (total / 86400, 0),
+            ((total % 86400) / 3600, 1),
+            ((total % 3600) / 60, 2),
+            (total % 60, 3),
+        ];
+        let parts = units
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .take(2)
+            .map(|(count, unit)| {
+                let count = count.localize(lang);
+                match unit {
+                    0 => lang_fmt!(lang, "durationdays", count),
+                    1 => lang_fmt!(lang, "durationhours", count),
+                    2 => lang_fmt!(lang, "durationminutes", count),
+                    _ => lang_fmt!(lang, "durationseconds", count),
+                }
+            })
+            .collect::<Vec<String>>();
+        if parts.is_empty() {
+            lang_fmt!(lang, "durationseconds", 0)
+        } else {
+            parts.join(" ")
+        }
+    }
+}
+
+/// Parse a UTC offset like "UTC+2", "+05:30", or "utc" into minutes east of UTC
+pub fn parse_utc_offset(text: &str) -> Option<i32> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("UTC")
+        .or_else(|| text.strip_prefix("utc"))
+        .or_else(|| text.strip_prefix("GMT"))
+        .unwrap_or(text);
+    if text.is_empty() {
+        return Some(0);
+    }
+    let (sign, text) = if let Some(rest) = text.strip_prefix('+') {
+        (1, rest)
+    } else if let Some(rest) = text.strip_prefix('-') {
+        (-1, rest)
+    } else {
+        return None;
+    };
+    let (hours, minutes) = text.split_once(':').unwrap_or((text, "0"));
+    let hours: i32 = hours.parse().ok()?;
+    let minutes: i32 = minutes.parse().ok()?;
+    if !(0..=14).contains(&hours) || !(0..60).contains(&minutes) {
+        return None;
+    }
+    Some(sign * (hours * 60 + minutes))
+}
+
+/// Get the timezone for a UTC offset in minutes, UTC if the offset is out of range
+pub fn utc_offset_timezone(offset: i32) -> FixedOffset {
+    FixedOffset::east_opt(offset * 60).unwrap_or_else(|| FixedOffset::east_opt(0).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn digit_grouping() {
+        assert_eq!(1234567_i64.localize(&Lang::En), "1,234,567");
+        assert_eq!((-1234_i64).localize(&Lang::En), "-1,234");
+        assert_eq!(999_u64.localize(&Lang::Es), "999");
+        assert_eq!(1234567_u64.localize(&Lang::Es), "1.234.567");
+        assert_eq!(1234567_u64.localize(&Lang::Hi), "12,34,567");
+    }
+
+    #[test]
+    fn offsets() {
+        assert_eq!(parse_utc_offset("UTC"), Some(0));
+        assert_eq!(parse_utc_offset("UTC+2"), Some(120));
+        assert_eq!(parse_utc_offset("-05:30"), Some(-330));
+        assert_eq!(parse_utc_offset("+15"), None);
+        assert_eq!(parse_utc_offset("Europe/Berlin"), None);
+    }
+}
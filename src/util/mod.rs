@@ -3,5 +3,8 @@ pub mod callback;
 pub mod error;
 //pub mod filter;
 pub mod glob;
+pub mod locale;
+pub mod scheduler;
 pub mod scripting;
 pub mod string;
+pub mod tasks;
@@ -0,0 +1,130 @@
+//! Persistent scheduler for one-off jobs. Jobs are stored in redis so they survive
+//! restarts, and are dispatched by kind to handlers registered by modules at startup.
+//! A job that needs to repeat should schedule its next run from its handler
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures::{future::BoxFuture, Future, FutureExt};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::statics::{spawn, BotLocal, REDIS};
+use crate::util::error::Result;
+
+const JOBS_KEY: &str = "sched:jobs";
+const DUE_KEY: &str = "sched:due";
+
+/// Maximum number of due jobs claimed per poll
+const BATCH_SIZE: isize = 100;
+
+type JobHandler = Arc<dyn Fn(serde_json::Value) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+static HANDLERS: BotLocal<DashMap<String, JobHandler>> = BotLocal::new(DashMap::new);
+
+/// A job waiting to run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: Uuid,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub due: i64,
+}
+
+/// Register a handler for all jobs of a given kind. Registering the same kind twice
+/// replaces the previous handler
+pub fn register_job<F, Fut>(kind: &str, func: F)
+where
+    F: Fn(serde_json::Value) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    HANDLERS.insert(kind.to_owned(), Arc::new(move |v| func(v).boxed()));
+}
+
+/// Schedule a job to run at a given time, returning its id for cancellation
+pub async fn schedule<T: Serialize>(kind: &str, payload: &T, at: DateTime<Utc>) -> Result<Uuid> {
+    let job = Job {
+        id: Uuid::new_v4(),
+        kind: kind.to_owned(),
+        payload: serde_json::to_value(payload)?,
+        due: at.timestamp(),
+    };
+    let id = job.id.to_string();
+    let value = serde_json::to_string(&job)?;
+    REDIS
+        .pipe(|q| {
+            q.hset(JOBS_KEY, &id, value)
+                .ignore()
+                .zadd(DUE_KEY, &id, job.due)
+                .ignore()
+        })
+        .await?;
+    Ok(job.id)
+}
+
+/// Cancel a scheduled job. Does nothing if the job already ran
+pub async fn cancel(id: &Uuid) -> Result<()> {
+    let id = id.to_string();
+    REDIS
+        .pipe(|q| q.zrem(DUE_KEY, &id).ignore().hdel(JOBS_KEY, &id).ignore())
+        .await?;
+    Ok(())
+}
+
+/// Remove a job from the queue, returning it only if this call was the one to remove it.
+/// This keeps a job from running twice if more than one instance is polling
+async fn claim(id: &str) -> Result<Option<Job>> {
+    let (removed, job): (i64, Option<String>) = REDIS
+        .pipe(|q| {
+            q.zrem(DUE_KEY, id)
+                .hget(JOBS_KEY, id)
+                .hdel(JOBS_KEY, id)
+                .ignore()
+        })
+        .await?;
+    if removed == 0 {
+        return Ok(None);
+    }
+    Ok(job.and_then(|v| serde_json::from_str(&v).ok()))
+}
+
+async fn poll() -> Result<()> {
+    let now = Utc::now().timestamp();
+    let due: Vec<String> = REDIS
+        .sq(|q| q.zrangebyscore_limit(DUE_KEY, "-inf", now, 0, BATCH_SIZE))
+        .await?;
+    for id in due {
+        let Some(job) = claim(&id).await? else {
+            continue;
+        };
+        let Some(handler) = HANDLERS.get(&job.kind).map(|v| Arc::clone(v.value())) else {
+            log::warn!(
+                "no handler for job kind {}, dropping job {}",
+                job.kind,
+                job.id
+            );
+            continue;
+        };
+        spawn(async move {
+            if let Err(err) = handler(job.payload).await {
+                log::warn!("scheduled job {} ({}) failed: {}", job.id, job.kind, err);
+                err.record_stats();
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Run due jobs as they come up. Runs forever
+pub async fn run_scheduler() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if let Err(err) = poll().await {
+            log::warn!("failed to poll scheduled jobs: {}", err);
+            err.record_stats();
+        }
+    }
+}
@@ -1,15 +1,16 @@
-use std::{marker::PhantomData, thread::LocalKey};
+use std::{cell::RefCell, collections::VecDeque, marker::PhantomData, thread::LocalKey};
 
 use botapi::gen_types::rhai_helpers::setup_all_rhai;
 use lazy_static::lazy_static;
 use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
 use rhai::plugin::*;
 use rhai::{export_module, exported_module, Dynamic, Engine, FnPtr, FuncArgs, Scope, AST};
 use threadpool::ThreadPool;
 use tokio::sync::mpsc;
 
 use crate::persist::admin::actions::ActionType;
-use crate::statics::CONFIG;
+use crate::statics::{Instance, CONFIG};
 
 use super::error::{BotError, Result};
 
@@ -17,21 +18,62 @@ lazy_static! {
     pub static ref COMPUTE_TP: ThreadPool = ThreadPool::new(CONFIG.compute_threads);
 }
 
+/// Longest string a chat script can build, in bytes
+const MAX_HOOK_STRING: usize = 64 * 1024;
+
+/// Largest array or object map a chat script can build
+const MAX_HOOK_COLLECTION: usize = 1024;
+
+/// Largest compiled size allowed for regexes built by scripts
+const MAX_PATTERN_SIZE: usize = 1 << 16;
+
+/// Compiled script regexes kept on each thread
+const MAX_CACHED_PATTERNS: usize = 64;
+
 thread_local! {
+    /// Recently used script regexes, least recently used first. Invalid patterns are
+    /// cached as None so they aren't compiled again
+    static PATTERNS: RefCell<VecDeque<(String, Option<Regex>)>> = const { RefCell::new(VecDeque::new()) };
+
     /// Thread local rhai engine preloaded with telegram api types
     pub static RHAI_ENGINE: Lazy<Engine> = Lazy::new(|| {
-        let mut engine = Engine::new();
-        engine.on_print(|_| ());
-        engine.on_debug(|_, _, _| ());
+        let mut engine = tg_engine();
         terminate_on_progress(&mut engine, 1024);
-        setup_all_rhai(&mut engine);
-        let tg_api = exported_module!(tg_api);
-        let action = exported_module!(action);
-        engine.register_global_module(tg_api.into());
-        engine.register_static_module("ModAction" ,action.into());
-        engine.register_type_with_name::<ModAction>("ModAction");
         engine
     });
+
+    /// Thread local engine for scripts saved by chat admins. Operations are capped by the
+    /// config of the bot running the script and data sizes are limited so a script can't
+    /// exhaust memory
+    pub static HOOK_ENGINE: Lazy<Engine> = Lazy::new(|| {
+        let mut engine = tg_engine();
+        engine.on_progress(|p| {
+            if p >= CONFIG.scripting.max_operations {
+                Some(Dynamic::UNIT)
+            } else {
+                None
+            }
+        });
+        engine.set_max_string_size(MAX_HOOK_STRING);
+        engine.set_max_array_size(MAX_HOOK_COLLECTION);
+        engine.set_max_map_size(MAX_HOOK_COLLECTION);
+        engine.set_max_call_levels(32);
+        engine
+    });
+}
+
+/// Create an engine preloaded with telegram api types and helpers
+fn tg_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.on_print(|_| ());
+    engine.on_debug(|_, _, _| ());
+    setup_all_rhai(&mut engine);
+    let tg_api = exported_module!(tg_api);
+    let action = exported_module!(action);
+    engine.register_global_module(tg_api.into());
+    engine.register_static_module("ModAction", action.into());
+    engine.register_type_with_name::<ModAction>("ModAction");
+    engine
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
@@ -155,6 +197,28 @@ mod action {
         format!("{:?}", my_enum)
     }
 }
+/// Compile a regex for a script, reusing recently compiled patterns
+fn script_regex(pattern: &str) -> Option<Regex> {
+    PATTERNS.with(|patterns| {
+        let mut patterns = patterns.borrow_mut();
+        if let Some(idx) = patterns.iter().position(|(p, _)| p == pattern) {
+            let entry = patterns.remove(idx)?;
+            let regex = entry.1.clone();
+            patterns.push_back(entry);
+            return regex;
+        }
+        let regex = RegexBuilder::new(pattern)
+            .size_limit(MAX_PATTERN_SIZE)
+            .build()
+            .ok();
+        if patterns.len() >= MAX_CACHED_PATTERNS {
+            patterns.pop_front();
+        }
+        patterns.push_back((pattern.to_owned(), regex.clone()));
+        regex
+    })
+}
+
 #[export_module]
 mod tg_api {
     use crate::util::glob::WildMatch;
@@ -163,6 +227,14 @@ mod tg_api {
     pub fn glob(value: &str, matches: &str) -> bool {
         WildMatch::new(value).matches(matches)
     }
+
+    /// Returns true if the pattern matches anywhere in the text. Invalid or oversized
+    /// patterns never match
+    pub fn regex(pattern: &str, text: &str) -> bool {
+        super::script_regex(pattern)
+            .map(|r| r.is_match(text))
+            .unwrap_or(false)
+    }
 }
 
 pub trait EngineGetter: Send {
@@ -406,8 +478,11 @@ where
         A: FuncArgs + Send + Sync + 'static,
     {
         let (tx, mut rx) = mpsc::channel(1);
+        let instance = Instance::current();
         COMPUTE_TP.execute(move || {
-            let res = self.run();
+            // the compute threads are shared by every bot, so scripts run as part of the
+            // bot that posted them
+            let res = instance.enter(|| self.run());
             if let Err(err) = tx.blocking_send(res) {
                 log::warn!("failed to send script result: {}", err);
             }
@@ -451,6 +526,28 @@ mod tests {
         assert!(r.is_err());
     }
 
+    #[test]
+    fn regex_helper() {
+        let r: bool = ManagedRhai::new(
+            r#"regex("c[o0]in", "free botc0in")"#.to_owned(),
+            &RHAI_ENGINE,
+        )
+        .run()
+        .unwrap();
+
+        assert!(r);
+    }
+
+    #[test]
+    fn regex_cache_bounded() {
+        for i in 0..MAX_CACHED_PATTERNS * 2 {
+            assert!(script_regex(&format!("a{}", i)).is_some());
+        }
+        assert!(script_regex("(").is_none());
+        assert!(script_regex("\\w{1000}{1000}").is_none());
+        PATTERNS.with(|p| assert_eq!(p.borrow().len(), MAX_CACHED_PATTERNS));
+    }
+
     #[tokio::test]
     async fn post() {
         let r: i64 = ManagedRhai::new("1+1".to_owned(), Engine::new())
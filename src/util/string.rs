@@ -3,10 +3,13 @@
 //! and ratelimiting to work
 
 pub use crate::langs::*;
-use crate::persist::core::dialogs;
+use crate::persist::core::{dialogs, string_overrides};
+use crate::persist::metrics::count_module_message;
 use crate::persist::redis::{default_cache_query, CachedQueryTrait, RedisStr};
 use crate::statics::{CHAT_GOVERNER, CONFIG, DB, REDIS, TG};
 use crate::tg::admin_helpers::IntoChatUser;
+use crate::tg::command::{Context, StaticContext};
+use crate::tg::connection::origin_of;
 use crate::tg::markdown::{EntityMessage, MarkupBuilder};
 use crate::util::error::Result;
 use async_trait::async_trait;
@@ -15,10 +18,12 @@ use botapi::gen_types::{
     Chat, EReplyMarkup, FileData, LinkPreviewOptionsBuilder, Message, ReplyParametersBuilder,
 };
 use chrono::Duration;
-use redis::Script;
+use redis::{AsyncCommands, Script};
 use sea_orm::sea_query::OnConflict;
 use sea_orm::ActiveValue::Set;
-use sea_orm::{EntityTrait, IntoActiveModel};
+use sea_orm::{ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter};
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::ops::DerefMut;
 
 /// Returns false if ratelimiting is triggered. This function should be called before
@@ -53,7 +58,11 @@ pub async fn should_ignore_chat(chat: i64) -> Result<bool> {
         .await?;
 
     CHAT_GOVERNER.until_key_ready(&chat).await;
-    Ok(count >= CONFIG.timing.antifloodwait_count)
+    let ignore = count >= CONFIG.timing.antifloodwait_count;
+    if !ignore {
+        count_module_message();
+    }
+    Ok(ignore)
 }
 
 /// Sets a redis key that causes all official methods of sending messages to suspend
@@ -105,7 +114,11 @@ impl Speak for i64 {
                 let bytes = FileData::Part(
                     Part::text(message.as_ref().to_owned()).file_name("message.txt"),
                 );
-                let message = TG.client.build_send_document(*self, bytes).build().await?;
+                let message = TG
+                    .client()
+                    .build_send_document(*self, bytes)
+                    .build()
+                    .await?;
                 return Ok(Some(message));
             }
 
@@ -189,7 +202,11 @@ impl Speak for i64 {
                 let bytes = FileData::Part(
                     Part::text(message.as_ref().to_owned()).file_name("message.txt"),
                 );
-                let message = TG.client.build_send_document(*self, bytes).build().await?;
+                let message = TG
+                    .client()
+                    .build_send_document(*self, bytes)
+                    .build()
+                    .await?;
                 return Ok(Some(message));
             }
 
@@ -227,13 +244,16 @@ impl Speak for Message {
     where
         T: AsRef<str> + Send + Sync,
     {
+        if let Some(origin) = origin_of(self) {
+            return origin.speak(message).await;
+        }
         if !should_ignore_chat(self.get_chat().get_id()).await? {
             if message.as_ref().len() > 4096 {
                 let bytes = FileData::Part(
                     Part::text(message.as_ref().to_owned()).file_name("message.txt"),
                 );
                 let message = TG
-                    .client
+                    .client()
                     .build_send_document(self.get_chat().get_id(), bytes)
                     .build()
                     .await?;
@@ -268,6 +288,10 @@ impl Speak for Message {
     }
 
     async fn speak_fmt(&self, mut message: EntityMessage) -> Result<Option<Message>> {
+        if let Some(origin) = origin_of(self) {
+            message.chat = origin.get_chat().get_id();
+            return origin.speak_fmt(message).await;
+        }
         if !should_ignore_chat(self.get_chat().get_id()).await? {
             Ok(Some(
                 message
@@ -287,6 +311,10 @@ impl Speak for Message {
     }
 
     async fn reply_fmt(&self, mut message: EntityMessage) -> Result<Option<Message>> {
+        if let Some(origin) = origin_of(self) {
+            message.chat = origin.get_chat().get_id();
+            return origin.reply_fmt(message).await;
+        }
         if !should_ignore_chat(self.get_chat().get_id()).await? {
             Ok(Some(
                 message
@@ -310,6 +338,9 @@ impl Speak for Message {
     where
         T: AsRef<str> + Send + Sync,
     {
+        if let Some(origin) = origin_of(self) {
+            return origin.reply(message).await;
+        }
         if !should_ignore_chat(self.get_chat().get_id()).await? {
             if message.as_ref().len() > 4096 {
                 let bytes = FileData::Part(
@@ -317,7 +348,7 @@ impl Speak for Message {
                 );
 
                 let message = TG
-                    .client
+                    .client()
                     .build_send_document(self.get_chat().get_id(), bytes)
                     .reply_parameters(&ReplyParametersBuilder::new(self.get_message_id()).build())
                     .build()
@@ -356,6 +387,10 @@ impl Speak for Message {
     where
         T: AsRef<str> + Send + Sync,
     {
+        // the message being replied to is in the group, so answer the private message
+        if let Some(origin) = origin_of(self) {
+            return origin.reply(message).await;
+        }
         if !should_ignore_chat(self.get_chat().get_id()).await? {
             if message.as_ref().len() > 4096 {
                 let bytes = FileData::Part(
@@ -363,7 +398,7 @@ impl Speak for Message {
                 );
 
                 let message = TG
-                    .client
+                    .client()
                     .build_send_document(self.get_chat().get_id(), bytes)
                     .reply_parameters(&ReplyParametersBuilder::new(self.get_message_id()).build())
                     .build()
@@ -537,6 +572,122 @@ pub async fn set_chat_lang(chat: &Chat, lang: Lang) -> Result<()> {
     Ok(())
 }
 
+/// Source of per-chat replacements for locale strings. lang_fmt and entity_fmt check this
+/// before falling back to the locale files
+pub trait StringOverrides {
+    fn string_override(&self, key: &str) -> Option<&str>;
+}
+
+impl StringOverrides for Lang {
+    fn string_override(&self, _: &str) -> Option<&str> {
+        None
+    }
+}
+
+impl StringOverrides for StaticContext {
+    fn string_override(&self, key: &str) -> Option<&str> {
+        self.overrides.get(key).map(|v| v.as_str())
+    }
+}
+
+impl StringOverrides for Context {
+    fn string_override(&self, key: &str) -> Option<&str> {
+        self.get_static().string_override(key)
+    }
+}
+
+/// Fill the {} placeholders of an overridden string at runtime, following the same escaping
+/// rules as format!
+pub fn format_override(template: &str, args: &[&dyn Display]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('}')) => {
+                chars.next();
+                if let Some(arg) = args.next() {
+                    out.push_str(&arg.to_string());
+                }
+            }
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Number of {} placeholders in a string, not counting escaped braces
+pub fn count_placeholders(template: &str) -> usize {
+    template
+        .replace("{{", "")
+        .replace("}}", "")
+        .matches("{}")
+        .count()
+}
+
+fn get_overrides_key(chat: i64) -> String {
+    format!("sovr:{}", chat)
+}
+
+/// Gets every string overridden in a chat, keyed by string name
+pub async fn get_string_overrides(chat: i64) -> Result<HashMap<String, String>> {
+    let key = get_overrides_key(chat);
+    let res = default_cache_query(
+        |_, _| async move {
+            let overrides = string_overrides::Entity::find()
+                .filter(string_overrides::Column::Chat.eq(chat))
+                .all(*DB)
+                .await?
+                .into_iter()
+                .map(|v| (v.key, v.text))
+                .collect::<HashMap<String, String>>();
+            Ok(Some(overrides))
+        },
+        Duration::try_seconds(CONFIG.timing.cache_timeout).unwrap(),
+    )
+    .query(&key, &())
+    .await?;
+    Ok(res.unwrap_or_default())
+}
+
+/// Replaces a locale string in a chat. The caller is responsible for checking that the
+/// key exists and the placeholders match
+pub async fn set_string_override(chat: i64, name: &str, text: &str) -> Result<()> {
+    let model = string_overrides::ActiveModel {
+        chat: Set(chat),
+        key: Set(name.to_owned()),
+        text: Set(text.to_owned()),
+    };
+    string_overrides::Entity::insert(model)
+        .on_conflict(
+            OnConflict::columns([
+                string_overrides::Column::Chat,
+                string_overrides::Column::Key,
+            ])
+            .update_column(string_overrides::Column::Text)
+            .to_owned(),
+        )
+        .exec(*DB)
+        .await?;
+    let key = get_overrides_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(())
+}
+
+/// Removes an overridden string from a chat, returning false if it wasn't overridden
+pub async fn clear_string_override(chat: i64, name: &str) -> Result<bool> {
+    let res = string_overrides::Entity::delete_by_id((chat, name.to_owned()))
+        .exec(*DB)
+        .await?;
+    let key = get_overrides_key(chat);
+    REDIS.invalidate(&[&key]).await?;
+    Ok(res.rows_affected > 0)
+}
+
 pub trait AlignCharBoundry {
     fn align_char_boundry(&self, idx: usize) -> usize;
 }
@@ -558,7 +709,18 @@ impl AlignCharBoundry for String {
 
 #[cfg(test)]
 mod test {
-    use super::AlignCharBoundry;
+    use super::{count_placeholders, format_override, AlignCharBoundry};
+
+    #[test]
+    fn override_placeholders() {
+        assert_eq!(count_placeholders("{} warned {}, {{mention}}"), 2);
+        assert_eq!(count_placeholders("{{}}"), 0);
+        assert_eq!(
+            format_override("{} warned {} {{mention}}", &[&"admin", &3]),
+            "admin warned 3 {mention}"
+        );
+        assert_eq!(format_override("too few {} {}", &[&1]), "too few 1 ");
+    }
 
     #[test]
     fn align_cyrillic_shit() {
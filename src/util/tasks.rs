@@ -0,0 +1,205 @@
+//! Supervised long-running background tasks. Tasks are registered by name along with a
+//! restart policy and start running once the bot starts. A task that returns an error or
+//! panics is restarted after a backoff that doubles with every failure in a row, and each
+//! task gets its own prometheus metrics for running state, failures, and restarts
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::persist::metrics::{TASK_FAILURES, TASK_RESTARTS, TASK_RUNNING};
+use crate::statics::spawn;
+use crate::util::error::Result;
+
+type TaskFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type TaskFactory = Arc<dyn Fn() -> TaskFuture + Send + Sync>;
+
+/// When a task is started again after it stops
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Restart {
+    /// Restart whenever the task stops, even if it finished successfully
+    Always,
+    /// Restart only if the task returned an error or panicked
+    OnFailure,
+    /// Never restart the task
+    Never,
+}
+
+/// How and how quickly a task is restarted
+#[derive(Clone, Copy, Debug)]
+pub struct RestartPolicy {
+    pub restart: Restart,
+    /// Delay before the first restart, doubled after each restart in a row
+    pub initial_backoff: Duration,
+    /// Longest delay between restarts. A task that ran at least this long before stopping
+    /// is considered healthy again and starts over from the initial backoff
+    pub max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    fn new(restart: Restart) -> Self {
+        Self {
+            restart,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+        }
+    }
+
+    /// Restart the task whenever it stops
+    pub fn always() -> Self {
+        Self::new(Restart::Always)
+    }
+
+    /// Restart the task only if it fails
+    pub fn on_failure() -> Self {
+        Self::new(Restart::OnFailure)
+    }
+
+    /// Run the task once
+    pub fn never() -> Self {
+        Self::new(Restart::Never)
+    }
+
+    /// Set the initial and longest delay between restarts
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    fn should_restart(&self, failed: bool) -> bool {
+        match self.restart {
+            Restart::Always => true,
+            Restart::OnFailure => failed,
+            Restart::Never => false,
+        }
+    }
+
+    /// Get the delay before the next restart given the delay before the previous one
+    fn next_backoff(&self, previous: Option<Duration>) -> Duration {
+        previous
+            .map(|v| v.saturating_mul(2).min(self.max_backoff))
+            .unwrap_or(self.initial_backoff)
+    }
+}
+
+struct Task {
+    name: &'static str,
+    policy: RestartPolicy,
+    factory: TaskFactory,
+}
+
+/// Named background tasks run under supervision. Tasks registered before the registry is
+/// started wait for it, tasks registered afterwards start right away
+pub struct TaskRegistry {
+    pending: Mutex<Vec<Task>>,
+    started: AtomicBool,
+}
+
+impl TaskRegistry {
+    pub(crate) fn new() -> Self {
+        Self {
+            pending: Mutex::new(Vec::new()),
+            started: AtomicBool::new(false),
+        }
+    }
+
+    /// Register a background task. The factory is called to create the task every time it
+    /// is (re)started, so it should capture anything the task needs to pick up where it
+    /// left off
+    pub fn register<F, Fut>(&self, name: &'static str, policy: RestartPolicy, factory: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let task = Task {
+            name,
+            policy,
+            factory: Arc::new(move || Box::pin(factory())),
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if self.started.load(Ordering::Acquire) {
+            spawn(supervise(task));
+        } else {
+            pending.push(task);
+        }
+    }
+
+    /// Start every task registered so far
+    pub fn start(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        self.started.store(true, Ordering::Release);
+        for task in pending.drain(..) {
+            spawn(supervise(task));
+        }
+    }
+}
+
+/// Run a task, restarting it according to its policy until it stops for good
+async fn supervise(task: Task) {
+    let name = task.name;
+    let running = TASK_RUNNING.with_label_values(&[name]);
+    let mut backoff = None;
+    loop {
+        let started = Instant::now();
+        running.set(1);
+        // spawned separately so a panic in the task doesn't take down the supervisor
+        let res = spawn((task.factory)()).await;
+        running.set(0);
+        let failed = match res {
+            Ok(Ok(())) => {
+                log::debug!("background task {} finished", name);
+                false
+            }
+            Ok(Err(err)) => {
+                log::error!("background task {} failed: {}", name, err);
+                err.record_stats();
+                true
+            }
+            Err(err) => {
+                log::error!("background task {} panicked: {}", name, err);
+                true
+            }
+        };
+        if failed {
+            TASK_FAILURES.with_label_values(&[name]).inc();
+        }
+        if !task.policy.should_restart(failed) {
+            break;
+        }
+        if started.elapsed() >= task.policy.max_backoff {
+            backoff = None;
+        }
+        let delay = task.policy.next_backoff(backoff);
+        backoff = Some(delay);
+        log::warn!("restarting background task {} in {:?}", name, delay);
+        tokio::time::sleep(delay).await;
+        TASK_RESTARTS.with_label_values(&[name]).inc();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy =
+            RestartPolicy::on_failure().backoff(Duration::from_secs(1), Duration::from_secs(5));
+        let mut delay = None;
+        let delays = (0..5)
+            .map(|_| {
+                let next = policy.next_backoff(delay);
+                delay = Some(next);
+                next.as_secs()
+            })
+            .collect::<Vec<u64>>();
+        assert_eq!(delays, vec![1, 2, 4, 5, 5]);
+        assert!(policy.should_restart(true));
+        assert!(!policy.should_restart(false));
+        assert!(RestartPolicy::always().should_restart(false));
+        assert!(!RestartPolicy::never().should_restart(true));
+    }
+}